@@ -0,0 +1,57 @@
+//! The connections the listeners accept, served by the same handlers
+//! whether they are plain TCP or TLS terminated here
+//!
+//! The handlers read and write them as any [ClientStream], and look through
+//! them at the TCP connection they are carried over for its addresses, the
+//! process of a local client and, on a hot upgrade, the socket to hand over.
+
+use std::fmt::Debug;
+use std::io::Result;
+use std::net::SocketAddr;
+
+use socks5::stream::{ClientStream, Peekable};
+use tokio::net::TcpStream;
+
+pub(crate) trait Accepted: ClientStream + Debug {
+    /// The TCP connection it is carried over
+    fn socket(&self) -> &TcpStream;
+
+    /// Whether what is relayed is what the socket carries, which a new
+    /// process can then go on relaying. Not so once TLS is terminated, its
+    /// session being of this process.
+    fn is_plain(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.socket().peer_addr()
+    }
+
+    #[inline]
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket().local_addr()
+    }
+}
+
+impl Accepted for TcpStream {
+    #[inline]
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+
+    fn is_plain(&self) -> bool {
+        true
+    }
+}
+
+impl<S: Accepted> Accepted for Peekable<S> {
+    #[inline]
+    fn socket(&self) -> &TcpStream {
+        self.get_ref().socket()
+    }
+
+    fn is_plain(&self) -> bool {
+        self.get_ref().is_plain()
+    }
+}
@@ -0,0 +1,411 @@
+//! Management API of a running instance, JSON over HTTP on localhost
+//!
+//! | Method | Path                  | Description                               |
+//! |--------|-----------------------|-------------------------------------------|
+//! | GET    | `/connections`        | Active connections                        |
+//! | GET    | `/conntrack`          | Tracked connections and their sockets     |
+//! | DELETE | `/conntrack/<client>` | Kill the connection of a client addr      |
+//! | GET    | `/traffic`            | Traffic per destination                   |
+//! | GET    | `/rules`              | Rules in use                              |
+//! | PUT    | `/rules`              | Replace the rules, e.g. `["MATCH,PROXY"]` |
+//! | POST   | `/config/validate`    | Dry-run reload, the rules it would apply  |
+//! | POST   | `/config/reload`      | Re-read the configuration file            |
+//! | POST   | `/config/confirm`     | Keep what the last reload applied         |
+//! | POST   | `/shutdown`           | Stop the proxy                            |
+//! | GET    | `/dns`                | Name resolution counters per family       |
+//! | GET    | `/dns/queries`        | Names resolved, one JSON per line         |
+//! | GET    | `/capture`            | The pcap file tunnel packets go to        |
+//! | POST   | `/capture`            | Record them, e.g. `{"path": "tun.pcap"}`  |
+//! | DELETE | `/capture`            | Stop recording                            |
+//! | GET    | `/metrics`            | Prometheus metrics, `prometheus` feature  |
+//! | GET    | `/status`             | Uptime, counters and the active profile   |
+//! | GET    | `/logs`               | Recent events, oldest first               |
+//! | GET    | `/profiles`           | Profiles and the active one               |
+//! | GET    | `/events`             | Live session events, one JSON per line    |
+//! | PUT    | `/profiles`           | Switch profile, e.g. `{"name": "home"}`   |
+//! | GET    | `/users`              | Users who may authenticate                |
+//! | POST   | `/users`              | Add one, `{"name": .., "password": ..}`   |
+//! | DELETE | `/users/<name>`       | Remove a user                             |
+//! | GET    | `/ui`                 | Web dashboard, `web-ui` feature           |
+//!
+//! With `confirm_window` in `[admin]`, a reload is rolled back when
+//! `/config/confirm` does not follow within it, the time left is in
+//! `/status`.
+//!
+//! The dashboard itself is served without the token, it asks for it and
+//! sends it along with the API requests it makes.
+//!
+//! `/events` keeps the response open and streams a [ConnectionEvent] as
+//! each session opens, relays bytes or closes. `/dns/queries` does the
+//! same with a [DnsLogEvent] per name resolved, after the recent ones.
+//! `nstream tail` prints either stream.
+
+use std::convert::Infallible;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use nstream_core::Rule;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::AdminConfig;
+use crate::dnslog::DnsLogEvent;
+use crate::session::ConnectionEvent;
+use crate::state::AppState;
+use crate::users::UserRequest;
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    resp
+}
+
+/// The body of every error response
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct ErrorBody {
+    pub(crate) error: String,
+}
+
+#[inline]
+fn error_response(status: StatusCode, msg: &str) -> Response<Body> {
+    json_response(status, &ErrorBody { error: msg.to_string() })
+}
+
+/// The body of `POST /capture`
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct CaptureRequest {
+    pub(crate) path: PathBuf,
+}
+
+/// Where tunnel packets are being recorded, nowhere when `path` is null
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct CaptureStatus {
+    pub(crate) path: Option<PathBuf>,
+}
+
+/// Served by `GET /status`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct Status {
+    pub(crate) version: String,
+    pub(crate) uptime_secs: u64,
+    pub(crate) active_sessions: usize,
+    /// Accepted since startup
+    pub(crate) connections: u64,
+    /// UDP associations holding their socket pair
+    pub(crate) udp_associations: u64,
+    pub(crate) draining: bool,
+    pub(crate) profile: Option<String>,
+    /// Seconds left to confirm the last reload before it is rolled back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reload_confirm_secs: Option<u64>,
+}
+
+/// The body of `PUT /profiles`, a null `name` goes back to the `rules` of
+/// the configuration file
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ProfileRequest {
+    pub(crate) name: Option<String>,
+}
+
+/// The profiles of the configuration file, `active` is null when the
+/// `rules` are in use
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct ProfilesStatus {
+    pub(crate) active: Option<String>,
+    pub(crate) available: Vec<String>,
+}
+
+fn profiles_status(state: &AppState) -> ProfilesStatus {
+    ProfilesStatus { active: state.profile(), available: state.profile_names() }
+}
+
+/// The dashboard, by path
+#[cfg(feature = "web-ui")]
+const UI_ASSETS: [(&str, &str, &str); 3] = [
+    ("/ui", "text/html; charset=utf-8", include_str!("../ui/index.html")),
+    ("/ui/app.js", "text/javascript; charset=utf-8", include_str!("../ui/app.js")),
+    ("/ui/style.css", "text/css; charset=utf-8", include_str!("../ui/style.css")),
+];
+
+#[cfg(feature = "web-ui")]
+fn ui_asset(req: &Request<Body>) -> Option<Response<Body>> {
+    if req.method() != Method::GET {
+        return None;
+    }
+    let (_, content_type, body) = UI_ASSETS.iter().find(|(path, ..)| *path == req.uri().path())?;
+    let mut resp = Response::new(Body::from(*body));
+    resp.headers_mut().insert(CONTENT_TYPE, content_type.parse().unwrap());
+    Some(resp)
+}
+
+fn authorized(req: &Request<Body>, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Bearer "))
+        .is_some_and(|val| val == token)
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    state: Arc<AppState>,
+    token: Option<Arc<String>>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    #[cfg(feature = "web-ui")]
+    if let Some(resp) = ui_asset(&req) {
+        return Ok(resp);
+    }
+    if !authorized(&req, token.as_deref().map(String::as_str)) {
+        return Ok(error_response(StatusCode::UNAUTHORIZED, "Missing or invalid token"));
+    }
+
+    let resp = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/connections") => json_response(StatusCode::OK, &state.named_sessions()),
+        (&Method::GET, "/conntrack") => json_response(StatusCode::OK, &state.conntrack.list()),
+        (&Method::DELETE, path) if path.starts_with("/conntrack/") => {
+            match path["/conntrack/".len()..].parse() {
+                Ok(client) if state.conntrack.kill(&client) => {
+                    state.log.push(format!("Killed the connection of {}", client));
+                    json_response(StatusCode::ACCEPTED, &json!({}))
+                }
+                Ok(_) => error_response(StatusCode::NOT_FOUND, "No such connection"),
+                Err(_) => error_response(StatusCode::BAD_REQUEST, "Invalid client address"),
+            }
+        }
+        (&Method::GET, "/traffic") => json_response(StatusCode::OK, &state.sessions.traffic()),
+        (&Method::GET, "/rules") => json_response(StatusCode::OK, &state.rules()),
+        (&Method::PUT, "/rules") => {
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+            };
+            match serde_json::from_slice::<Vec<Rule>>(&body) {
+                Ok(rules) => {
+                    state.set_rules(rules);
+                    state.log.push(String::from("Rules replaced"));
+                    json_response(StatusCode::OK, &state.rules())
+                }
+                Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::POST, "/config/validate") => match state.stage_config() {
+            Ok((_, rules)) => json_response(StatusCode::OK, &rules),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                error_response(StatusCode::CONFLICT, &e.to_string())
+            }
+            Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()),
+        },
+        (&Method::POST, "/config/reload") => match state.reload_config() {
+            Ok((_, confirm_window)) => {
+                state.log.push(match confirm_window {
+                    Some(window) => {
+                        format!("Configuration reloaded, to confirm within {:?}", window)
+                    }
+                    None => String::from("Configuration reloaded"),
+                });
+                json_response(StatusCode::OK, &state.rules())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                error_response(StatusCode::CONFLICT, &e.to_string())
+            }
+            Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()),
+        },
+        (&Method::POST, "/config/confirm") => match state.confirm_reload() {
+            true => {
+                state.log.push(String::from("Reload confirmed"));
+                json_response(StatusCode::OK, &json!({}))
+            }
+            false => error_response(StatusCode::CONFLICT, "No reload to confirm"),
+        },
+        #[cfg(feature = "prometheus")]
+        (&Method::GET, "/metrics") => {
+            let mut resp =
+                Response::new(Body::from(state.metrics.render_prometheus(&state.sessions)));
+            resp.headers_mut().insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+            resp
+        }
+        (&Method::POST, "/shutdown") => {
+            state.request_shutdown();
+            json_response(StatusCode::ACCEPTED, &json!({}))
+        }
+        (&Method::GET, "/dns") => json_response(StatusCode::OK, &state.metrics.dns()),
+        (&Method::GET, "/status") => {
+            let status = Status {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                uptime_secs: state.uptime().as_secs(),
+                active_sessions: state.sessions.active().len(),
+                connections: state.metrics.connections(),
+                udp_associations: state.metrics.udp_associations(),
+                draining: state.draining(),
+                profile: state.profile(),
+                reload_confirm_secs: state.reload_confirm_left().map(|left| left.as_secs()),
+            };
+            json_response(StatusCode::OK, &status)
+        }
+        (&Method::GET, "/events") => {
+            let mut events = state.sessions.subscribe();
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                loop {
+                    let event = match events.recv().await {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(missed)) => ConnectionEvent::Lagged { missed },
+                        Err(RecvError::Closed) => break,
+                    };
+                    let mut line = serde_json::to_vec(&event).unwrap_or_default();
+                    line.push(b'\n');
+                    /* Until the client goes away */
+                    if sender.send_data(line.into()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            let mut resp = Response::new(body);
+            resp.headers_mut().insert(CONTENT_TYPE, "application/x-ndjson".parse().unwrap());
+            resp
+        }
+        (&Method::GET, "/dns/queries") => {
+            let (recent, mut queries) = state.dns_log.subscribe();
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                let mut recent = recent.into_iter();
+                loop {
+                    let event = match recent.next() {
+                        Some(query) => DnsLogEvent::Query(query),
+                        None => match queries.recv().await {
+                            Ok(query) => DnsLogEvent::Query(query),
+                            Err(RecvError::Lagged(missed)) => DnsLogEvent::Lagged { missed },
+                            Err(RecvError::Closed) => break,
+                        },
+                    };
+                    let mut line = serde_json::to_vec(&event).unwrap_or_default();
+                    line.push(b'\n');
+                    /* Until the client goes away */
+                    if sender.send_data(line.into()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            let mut resp = Response::new(body);
+            resp.headers_mut().insert(CONTENT_TYPE, "application/x-ndjson".parse().unwrap());
+            resp
+        }
+        (&Method::GET, "/logs") => json_response(StatusCode::OK, &state.log.recent()),
+        (&Method::GET, "/profiles") => json_response(StatusCode::OK, &profiles_status(&state)),
+        (&Method::PUT, "/profiles") => {
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+            };
+            match serde_json::from_slice::<ProfileRequest>(&body) {
+                Ok(ProfileRequest { name }) => match state.switch_profile(name.as_deref()) {
+                    Ok(()) => {
+                        state.log.push(match name {
+                            Some(name) => format!("Switched to profile {}", name),
+                            None => String::from("Switched back to the configured rules"),
+                        });
+                        json_response(StatusCode::OK, &profiles_status(&state))
+                    }
+                    Err(e) => error_response(StatusCode::NOT_FOUND, &e.to_string()),
+                },
+                Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::GET, "/users") => json_response(StatusCode::OK, &state.users.list()),
+        (&Method::POST, "/users") => {
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+            };
+            match serde_json::from_slice::<UserRequest>(&body) {
+                Ok(UserRequest { name, password }) => match state.users.add(&name, &password) {
+                    Ok(()) => {
+                        state.log.push(format!("Set the password of user {}", name));
+                        json_response(StatusCode::OK, &state.users.list())
+                    }
+                    Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                        error_response(StatusCode::BAD_REQUEST, &e.to_string())
+                    }
+                    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+                },
+                Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::DELETE, path) if path.starts_with("/users/") => {
+            match state.users.remove(&path["/users/".len()..]) {
+                Ok(true) => {
+                    state.log.push(format!("Removed user {}", &path["/users/".len()..]));
+                    json_response(StatusCode::OK, &json!({}))
+                }
+                Ok(false) => error_response(StatusCode::NOT_FOUND, "No such user"),
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+            }
+        }
+        (_, "/capture") if state.tun_capture().is_none() => {
+            error_response(StatusCode::CONFLICT, "No tunnel interface")
+        }
+        (&Method::GET, "/capture") => {
+            let path = state.tun_capture().and_then(|capture| capture.path());
+            json_response(StatusCode::OK, &CaptureStatus { path })
+        }
+        (&Method::POST, "/capture") => {
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+            };
+            match serde_json::from_slice::<CaptureRequest>(&body) {
+                Ok(CaptureRequest { path }) => match state.tun_capture().unwrap().start(&path) {
+                    Ok(()) => json_response(StatusCode::OK, &CaptureStatus { path: Some(path) }),
+                    Err(e) => error_response(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()),
+                },
+                Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::DELETE, "/capture") => match state.tun_capture().unwrap().stop() {
+            Ok(_) => json_response(StatusCode::OK, &CaptureStatus { path: None }),
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+        },
+        (
+            _,
+            "/connections" | "/conntrack" | "/traffic" | "/rules" | "/config/validate"
+            | "/config/reload" | "/config/confirm" | "/shutdown" | "/dns" | "/dns/queries"
+            | "/capture" | "/status" | "/logs" | "/profiles" | "/users",
+        ) => error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(resp)
+}
+
+/// Serve the management API until the process exits
+pub(crate) async fn serve(admin_config: AdminConfig, state: Arc<AppState>) -> Result<()> {
+    if !admin_config.listen.ip().is_loopback() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Management API must listen on localhost, not {}", admin_config.listen),
+        ));
+    }
+    let token = admin_config.token.map(Arc::new);
+    let make_svc = make_service_fn(move |_conn| {
+        let state = state.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle_request(req, state.clone(), token.clone())
+            }))
+        }
+    });
+    let server = Server::try_bind(&admin_config.listen).map_err(Error::other)?.serve(make_svc);
+    println!("Management API on http://{}", server.local_addr());
+    server.await.map_err(Error::other)
+}
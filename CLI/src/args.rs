@@ -0,0 +1,184 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use nstream_core::{ByteSize, HumanDuration};
+
+use crate::config::ConfigOverride;
+
+/// Address family preference
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum IpPreference {
+    V4,
+    V6,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Commands {
+    /// Run the proxy, as without a subcommand
+    Run,
+    /// Shut down the nstream of the pidfile, as Ctrl + C does, then kill it
+    /// should it still run after 30s
+    Stop,
+    /// Run the proxy and print its socks5:// URIs as QR codes for mobile clients
+    Share,
+    /// Point the system proxy at a SOCKS server or a PAC file, or turn it
+    /// off, without running the proxy
+    Proxy(ProxyArgs),
+    /// Print the status of the running nstream from the management API
+    /// `[admin]` of the configuration file sets
+    Status,
+    /// Print the LAN and external IPv4 and IPv6 addresses of this host, as
+    /// the proxy finds them at startup
+    Ip,
+    /// Print the JSON Schema of the configuration file and of the management
+    /// API, then exit
+    Schema,
+    /// Open concurrent connections to a running nstream, each doing a
+    /// handshake, a CONNECT and a payload echo, then report latency
+    /// percentiles and failures. Exits with 1 when any connection failed.
+    Loadgen(LoadgenArgs),
+    /// Print a live stream of the running nstream, one JSON per line, from
+    /// the management API `[admin]` of the configuration file sets
+    Tail(TailArgs),
+    /// Knock with the key of `[knock]` of the configuration file on the
+    /// nstream at SERVER, which then lets this host connect for a while
+    Knock(KnockArgs),
+    /// Validate the configuration file and print its rules normalized, then
+    /// exit with 1 on errors, e.g. in CI before deploying it
+    Check(CheckArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct ProxyArgs {
+    #[command(subcommand)]
+    pub(crate) state: ProxyState,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum ProxyState {
+    /// Use the SOCKS server at ADDR, or the PAC file at `--pac`
+    On(ProxyOnArgs),
+    /// Use no proxy, whichever was set
+    Off,
+}
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct ProxyOnArgs {
+    /// `ip:port` of the SOCKS server, e.g. one nstream printed it listens on
+    #[arg(required_unless_present = "pac")]
+    pub(crate) addr: Option<SocketAddr>,
+    /// Username of the SOCKS server, which it is used without otherwise
+    #[arg(long, requires = "password")]
+    pub(crate) user: Option<String>,
+    #[arg(long, requires = "user")]
+    pub(crate) password: Option<String>,
+    /// URL of a PAC file to use instead of a fixed SOCKS server
+    #[arg(long, conflicts_with_all = ["addr", "user"])]
+    pub(crate) pac: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct CheckArgs {
+    /// Also connect to the upstreams reached over TCP
+    #[arg(long)]
+    pub(crate) probe: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct KnockArgs {
+    /// `host:port` the `[knock]` of the server listens on, e.g.
+    /// `vpn.example:62201`
+    pub(crate) server: String,
+}
+
+/// The streams of the management API
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum TailStream {
+    /// Sessions opening, relaying bytes and closing
+    Events,
+    /// Names resolved, with the rule each answer matches
+    Dns,
+}
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct TailArgs {
+    #[arg(value_enum)]
+    pub(crate) stream: TailStream,
+}
+
+#[derive(Debug, clap::Args)]
+pub(crate) struct LoadgenArgs {
+    /// Address of the nstream to load
+    pub(crate) target: SocketAddr,
+    /// Connections opened at once
+    #[arg(short = 'n', long, default_value_t = 100)]
+    pub(crate) connections: usize,
+    /// Bytes echoed per connection, e.g. "64KiB"
+    #[arg(long, default_value = "4KiB")]
+    pub(crate) payload: ByteSize,
+    /// Where the echo server the connections CONNECT to listens, which the
+    /// target must be able to reach
+    #[arg(long, default_value = "127.0.0.1:0")]
+    pub(crate) echo_listen: SocketAddr,
+    #[arg(long)]
+    pub(crate) user: Option<String>,
+    #[arg(long)]
+    pub(crate) password: Option<String>,
+    /// Time each connection gets to go through, e.g. "10s"
+    #[arg(long, default_value = "10s")]
+    pub(crate) timeout: HumanDuration,
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "nstream", version, about)]
+pub(crate) struct Args {
+    #[command(subcommand)]
+    pub(crate) command: Option<Commands>,
+    /// Address family of the listener the system proxy points at
+    #[arg(long, value_enum, default_value_t = IpPreference::V6)]
+    pub(crate) prefer: IpPreference,
+    /// Path of the TOML configuration file
+    #[arg(short, long)]
+    pub(crate) config: Option<PathBuf>,
+    /// Override a key of the configuration file, e.g.
+    /// `--set socket.keepalive=30s`, after the `NSTREAM__SECTION__KEY`
+    /// environment variables
+    #[arg(long = "set", value_name = "SECTION.KEY=VALUE")]
+    pub(crate) overrides: Vec<ConfigOverride>,
+    /// Run the proxy in the background, detached from the terminal
+    #[arg(long)]
+    pub(crate) daemon: bool,
+    /// Where the pid of the proxy is written for `nstream stop`,
+    /// `nstream.pid` of the temporary directory by default with `--daemon`
+    #[arg(long, value_name = "PATH")]
+    pub(crate) pidfile: Option<PathBuf>,
+    /// Where the output goes with `--daemon`, appended to, discarded by
+    /// default
+    #[arg(long, value_name = "PATH", requires = "daemon")]
+    pub(crate) log_file: Option<PathBuf>,
+    /// Unix socket for hot upgrades, a new process started with the same
+    /// path takes over the listeners and connections of the running one
+    #[arg(long, value_name = "PATH")]
+    pub(crate) upgrade_socket: Option<PathBuf>,
+    /// Name of the credentials generated when clients must authenticate,
+    /// random when omitted
+    #[arg(long, value_name = "NAME")]
+    pub(crate) user: Option<String>,
+    /// Start without checking privileges, ports, the tunnel driver, the
+    /// GeoIP database, STUN and the system proxy first
+    #[arg(long)]
+    pub(crate) skip_preflight: bool,
+    /// Also serve HTTP CONNECT on the SOCKS port, so that applications only
+    /// need the one proxy port whichever protocol they speak
+    #[arg(long)]
+    pub(crate) single_port: bool,
+    /// Print every relayed UDP datagram, only in builds with the
+    /// `trace-log` feature
+    #[arg(long)]
+    pub(crate) trace: bool,
+    /// Print the changes to the tunnel interface, the routes, the system
+    /// proxy and the firewall instead of making them
+    #[arg(long, global = true)]
+    pub(crate) dry_run: bool,
+}
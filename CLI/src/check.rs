@@ -0,0 +1,231 @@
+//! `nstream check`, the configuration file validated as the proxy would
+//! take it, for deployments to catch mistakes before a restart does
+//!
+//! Besides what loading it checks already, the rule lists are searched for
+//! rules an earlier one leaves nothing to match, and `GEOIP` rules for
+//! codes of no country. The sections read at startup are checked as they
+//! would be then, and the upstreams resolved, or connected to as well with
+//! `--probe`. The rules are printed normalized along the way. Errors make
+//! the exit status 1, warnings alone leave it 0.
+
+use std::path::Path;
+use std::time::Duration;
+
+use nstream_core::{shadowed_rules, Rule, RuleAction, RuleMatcher};
+use tokio::net::{lookup_host, TcpStream};
+
+use crate::args::CheckArgs;
+use crate::config::{Config, ConfigOverride};
+
+/// How long `--probe` waits for each upstream to answer
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct Report {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    fn error(&mut self, msg: String) {
+        self.errors.push(msg);
+    }
+
+    fn warn(&mut self, msg: String) {
+        self.warnings.push(msg);
+    }
+}
+
+/// Check the configuration file at `path`, or that of the default
+/// location, printing what is wrong with it. Returns whether nothing is.
+pub(crate) async fn run(
+    args: &CheckArgs,
+    path: Option<&Path>,
+    overrides: &[ConfigOverride],
+) -> bool {
+    let config = match Config::load(path, overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return false;
+        }
+    };
+    let mut report = Report::default();
+    check_rules(&config, &mut report);
+    check_sections(&config, &mut report).await;
+    check_upstreams(&config, args.probe, &mut report).await;
+
+    for warning in report.warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+    for error in report.errors.iter() {
+        eprintln!("error: {}", error);
+    }
+    println!("{} errors, {} warnings", report.errors.len(), report.warnings.len());
+    report.errors.is_empty()
+}
+
+/// Every rule list of `config` by where it is in the file
+fn rule_lists(config: &Config) -> Vec<(String, &[Rule])> {
+    let mut lists = vec![(String::from("rules"), config.rules.as_slice())];
+    for (name, rules) in config.profiles.iter() {
+        lists.push((format!("profiles.{}", name), rules));
+    }
+    if let Some(ssh) = config.ssh.as_ref() {
+        lists.push((String::from("ssh.rules"), &ssh.rules));
+    }
+    for (name, policy) in config.auth.iter().flat_map(|auth| auth.users.iter()) {
+        lists.push((format!("auth.users.{}.rules", name), &policy.rules));
+    }
+    lists
+}
+
+fn check_rules(config: &Config, report: &mut Report) {
+    let mut geoip = false;
+    for (name, rules) in rule_lists(config) {
+        if rules.is_empty() {
+            continue;
+        }
+        println!("{}", name);
+        for (i, rule) in rules.iter().enumerate() {
+            println!("{:>4}  {}", i + 1, rule);
+        }
+        for (i, by) in shadowed_rules(rules) {
+            let msg = format!(
+                "{} {}: {} never matches, rule {} {} takes all it would",
+                name,
+                i + 1,
+                rules[i],
+                by + 1,
+                rules[by]
+            );
+            /* Dead, but deciding as intended either way */
+            match rules[i].action == rules[by].action {
+                true => report.warn(msg),
+                false => report.error(msg),
+            }
+        }
+        for (i, rule) in rules.iter().enumerate() {
+            if let RuleMatcher::GeoIp(iso_code) = &rule.matcher {
+                geoip = true;
+                if !nstream_core::is_country_code(iso_code) {
+                    report.error(format!("{} {}: {} names no country", name, i + 1, rule));
+                }
+            }
+        }
+    }
+    if geoip {
+        if let Err(e) = nstream_core::probe_geoip_database() {
+            report
+                .error(format!("GEOIP rules never match, the GeoIP database is unreadable: {}", e));
+        }
+    }
+}
+
+/// The checks startup makes of the sections only read then
+async fn check_sections(config: &Config, report: &mut Report) {
+    if let Some(admin) = config.admin.as_ref() {
+        if !admin.listen.ip().is_loopback() {
+            report.error(format!("admin: listen {} is not on localhost", admin.listen));
+        }
+    }
+    if config.socket.keepalive.as_ref().is_some_and(|keepalive| keepalive.as_duration().is_zero()) {
+        report.error(String::from("socket: keepalive of 0 fails to be applied to any connection"));
+    }
+    if config.socket.fast_open.is_some() && !cfg!(target_os = "linux") {
+        report.warn(String::from("socket: fast_open is only honoured on Linux"));
+    }
+    match config.socket.udp_datagram_size {
+        Some(0) => report.error(String::from("socket: udp_datagram_size of 0 relays nothing")),
+        Some(size) if size > u16::MAX as usize => report.warn(format!(
+            "socket: udp_datagram_size {} is only sent over IPv6 as jumbograms",
+            size
+        )),
+        _ => {}
+    }
+    if let Some(geoip) = config.geoip.as_ref() {
+        match geoip.policy() {
+            Ok(policy) if policy.action() == RuleAction::Proxy => {
+                if config.ssh.is_none() && config.wireguard.is_none() {
+                    report.error(String::from(
+                        "geoip: action proxy without [ssh] or [wireguard] refuses all it acts on",
+                    ));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => report.error(e.to_string()),
+        }
+        if let Err(e) = nstream_core::probe_geoip_database() {
+            report.error(format!("geoip: the GeoIP database is unreadable: {}", e));
+        }
+    }
+    #[cfg(feature = "wireguard")]
+    if let Some(wireguard) = config.wireguard.as_ref() {
+        if let Err(e) = wireguard.to_config().await {
+            report.error(e.to_string());
+        }
+    }
+    #[cfg(feature = "ssh")]
+    if let Some(Err(e)) = config.ssh.as_ref().map(|ssh| ssh.to_config()) {
+        report.error(e.to_string());
+    }
+    #[cfg(feature = "tls")]
+    if let Some(Err(e)) = config.tls.as_ref().map(crate::tls::TlsTerminator::new) {
+        report.error(e.to_string());
+    }
+    #[cfg(feature = "tls-psk")]
+    if let Some(Err(e)) = config.tls_psk.as_ref().map(crate::tls_psk::TlsPskAcceptor::new) {
+        report.error(e.to_string());
+    }
+    #[cfg(feature = "knock")]
+    if let Some(Err(e)) = config.knock.as_ref().map(|knock| knock.key()) {
+        report.error(e.to_string());
+    }
+    /* Which keep the proxy from starting */
+    let missing_features = [
+        (config.wireguard.is_some() && !cfg!(feature = "wireguard"), "wireguard", "wireguard"),
+        (config.ssh.is_some() && !cfg!(feature = "ssh"), "ssh", "ssh"),
+        (config.tls.is_some() && !cfg!(feature = "tls"), "tls", "tls"),
+        (config.tls_psk.is_some() && !cfg!(feature = "tls-psk"), "tls_psk", "tls-psk"),
+        (config.knock.is_some() && !cfg!(feature = "knock"), "knock", "knock"),
+    ];
+    for (_, section, feature) in missing_features.iter().filter(|(missing, ..)| *missing) {
+        report.error(format!(
+            "[{}] is configured, this build is without the {} feature",
+            section, feature
+        ));
+    }
+}
+
+/// Resolve the servers the outbounds go through, and connect to those over
+/// TCP with `probe`. The WireGuard peer only answers handshakes.
+async fn check_upstreams(config: &Config, probe: bool, report: &mut Report) {
+    let upstreams = [
+        config.wireguard.as_ref().map(|wireguard| ("wireguard", &wireguard.peer.endpoint, false)),
+        config.ssh.as_ref().map(|ssh| ("ssh", &ssh.server, true)),
+    ];
+    for (section, upstream, tcp) in upstreams.into_iter().flatten() {
+        let addr = match lookup_host(upstream.as_str()).await.map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            Ok(None) => {
+                report.error(format!("{}: No address for {}", section, upstream));
+                continue;
+            }
+            Err(e) => {
+                report.error(format!("{}: {}: {}", section, upstream, e));
+                continue;
+            }
+        };
+        if !probe || !tcp {
+            continue;
+        }
+        match tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => println!("{} {} ({}) is reachable", section, upstream, addr),
+            Ok(Err(e)) => report.error(format!("{}: {} ({}): {}", section, upstream, addr, e)),
+            Err(_) => report.error(format!(
+                "{}: {} ({}) did not answer within {:?}",
+                section, upstream, addr, PROBE_TIMEOUT
+            )),
+        }
+    }
+}
@@ -14,48 +14,91 @@
 
 use std::io::Result;
 #[cfg(target_os = "macos")]
-use std::process::{Command, ExitStatus, Stdio};
-#[cfg(target_os = "macos")]
-use std::{ffi::OsStr, net::SocketAddr};
+use std::net::SocketAddr;
 
 #[cfg(target_os = "macos")]
 pub(crate) const NETWORK_SERVICE: &'static str = "Wi-Fi";
 
+/// The option of networksetup reading what the option `set` changes, e.g.
+/// `-getwebproxy` for `-setwebproxystate`
 #[cfg(target_os = "macos")]
-#[inline]
-fn exec_networksetup<S: AsRef<OsStr>>(args: &[S]) -> Result<ExitStatus> {
-    let mut cmd_networksetup = Command::new("networksetup");
-    let mut cmd_networksetup = cmd_networksetup.stdout(Stdio::null()).stderr(Stdio::null());
-    for arg in args {
-        cmd_networksetup = cmd_networksetup.arg(arg);
+fn getter_of(set: &str) -> String {
+    match set.trim_start_matches("-set").trim_end_matches("state") {
+        "autoproxy" => String::from("-getautoproxyurl"),
+        setting => format!("-get{}", setting),
     }
-    cmd_networksetup.status()
+}
+
+/// Run networksetup through [crate::ops], `secret` masked in the log
+#[cfg(target_os = "macos")]
+fn exec_networksetup_hiding(args: &[&str], secret: Option<&str>) -> Result<()> {
+    let get = getter_of(args[0]);
+    let probe = ("networksetup", &[get.as_str(), NETWORK_SERVICE][..]);
+    crate::ops::run_hiding("networksetup", args, None, secret, probe).map(drop)
 }
 
 #[cfg(target_os = "macos")]
-#[allow(unused_variables)]
+#[inline]
+fn exec_networksetup(args: &[&str]) -> Result<()> {
+    exec_networksetup_hiding(args, None)
+}
+
+/// Use the SOCKS server at `socket_addr`, authenticating with the username
+/// and password of `credentials` if any
+#[cfg(target_os = "macos")]
 #[allow(dead_code)]
-pub(crate) fn open_socks5_proxy(socket_addr: SocketAddr, usr: &str, pwd: &str) -> Result<()> {
-    assert!(exec_networksetup(&[
-        "-setsocksfirewallproxy",
-        NETWORK_SERVICE,
-        &socket_addr.ip().to_string(),
-        &socket_addr.port().to_string(),
-        "on",
-        usr,
-        pwd
-    ])?
-    .success());
-
-    assert!(exec_networksetup(&["-setwebproxystate", NETWORK_SERVICE, "off"])?.success());
-    assert!(exec_networksetup(&["-setsecurewebproxystate", NETWORK_SERVICE, "off"])?.success());
-    assert!(exec_networksetup(&["-setsocksfirewallproxystate", NETWORK_SERVICE, "on"])?.success());
+pub(crate) fn open_socks5_proxy(
+    socket_addr: SocketAddr,
+    credentials: Option<(&str, &str)>,
+) -> Result<()> {
+    let (ip, port) = (socket_addr.ip().to_string(), socket_addr.port().to_string());
+    let mut args = vec!["-setsocksfirewallproxy", NETWORK_SERVICE, &ip, &port];
+    match credentials {
+        Some((usr, pwd)) => args.extend(["on", usr, pwd]),
+        None => args.push("off"),
+    }
+    exec_networksetup_hiding(&args, credentials.map(|(_, pwd)| pwd))?;
+
+    exec_networksetup(&["-setwebproxystate", NETWORK_SERVICE, "off"])?;
+    exec_networksetup(&["-setsecurewebproxystate", NETWORK_SERVICE, "off"])?;
+    exec_networksetup(&["-setsocksfirewallproxystate", NETWORK_SERVICE, "on"])?;
     Ok(())
 }
 
+/// Write the current SOCKS proxy state back, which tells whether the
+/// proxy settings can be changed without changing them
+#[cfg(target_os = "macos")]
+pub(crate) fn probe_socks5_proxy() -> Result<()> {
+    let output = crate::ops::query("networksetup", &["-getsocksfirewallproxy", NETWORK_SERVICE])
+        .map_err(|_| {
+            std::io::Error::other(format!("No network service named {}", NETWORK_SERVICE))
+        })?;
+    let enabled = output.lines().any(|line| line == "Enabled: Yes");
+    let state = if enabled { "on" } else { "off" };
+    exec_networksetup(&["-setsocksfirewallproxystate", NETWORK_SERVICE, state])
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+}
+
 #[cfg(target_os = "macos")]
 #[allow(dead_code)]
 pub(crate) fn close_socks5_proxy() -> Result<()> {
-    assert!(exec_networksetup(&["-setsocksfirewallproxystate", NETWORK_SERVICE, "off"])?.success());
+    exec_networksetup(&["-setsocksfirewallproxystate", NETWORK_SERVICE, "off"])?;
+    Ok(())
+}
+
+/// Use the PAC file at `url` rather than a fixed proxy
+#[cfg(target_os = "macos")]
+pub(crate) fn open_pac_proxy(url: &str) -> Result<()> {
+    exec_networksetup(&["-setautoproxyurl", NETWORK_SERVICE, url])?;
+    exec_networksetup(&["-setwebproxystate", NETWORK_SERVICE, "off"])?;
+    exec_networksetup(&["-setsecurewebproxystate", NETWORK_SERVICE, "off"])?;
+    exec_networksetup(&["-setsocksfirewallproxystate", NETWORK_SERVICE, "off"])?;
+    exec_networksetup(&["-setautoproxystate", NETWORK_SERVICE, "on"])?;
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn close_pac_proxy() -> Result<()> {
+    exec_networksetup(&["-setautoproxystate", NETWORK_SERVICE, "off"])?;
     Ok(())
 }
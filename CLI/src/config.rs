@@ -0,0 +1,956 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use nstream_core::{
+    ByteRate, ByteSize, CaptureFilter, CountryMode, CountryPolicy, DialConfig, FakeIpPool,
+    FamilyPreference, HumanDuration, IpCidr, Ipv6Source, OutboundBind, RetryPolicy, Rule,
+    RuleAction, SocketOptions, UdpBufferOptions, DEFAULT_FAKE_IP_RANGE, DEFAULT_FAKE_IP_TTL,
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use socks5::protocol::ClientMatch;
+
+/// Settings of the management API
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct AdminConfig {
+    /// Must be a loopback address
+    pub(crate) listen: SocketAddr,
+    /// When set, requests must carry `Authorization: Bearer <token>`
+    pub(crate) token: Option<String>,
+    /// When set, e.g. `"2m"`, a reload is rolled back unless confirmed by
+    /// `POST /config/confirm` within it, so that rules locking the client
+    /// of a remote host out undo themselves
+    #[schemars(with = "Option<String>")]
+    pub(crate) confirm_window: Option<HumanDuration>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 9090)),
+            token: None,
+            confirm_window: None,
+        }
+    }
+}
+
+/// Settings of the PAC file server, which is set as the system proxy
+/// instead of the SOCKS proxy when configured
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct PacConfig {
+    pub(crate) listen: SocketAddr,
+}
+
+impl Default for PacConfig {
+    fn default() -> Self {
+        Self { listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 9091)) }
+    }
+}
+
+impl PacConfig {
+    #[inline]
+    pub(crate) fn url(&self) -> String {
+        format!("http://{}{}", self.listen, crate::pac::PAC_PATH)
+    }
+}
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_GREETING_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_MAX_HANDSHAKES: usize = 512;
+const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REVERSE_DNS_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_REVERSE_DNS_TTL: Duration = Duration::from_secs(3600);
+const DEFAULT_WARM_START_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// Overrides of the [SocketOptions] defaults
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SocketConfig {
+    /// `TCP_NODELAY` on accepted and outbound connections, on by default
+    /// for interactive traffic not to wait on Nagle's algorithm
+    pub(crate) nodelay: Option<bool>,
+    /// Idle time before the first keepalive probe and between the next
+    /// ones, none are sent by default
+    #[schemars(with = "Option<String>")]
+    pub(crate) keepalive: Option<HumanDuration>,
+    /// `SO_REUSEPORT` on the listeners
+    pub(crate) reuse_port: Option<bool>,
+    /// TCP Fast Open, the queue length of pending TFO requests on the
+    /// listeners, Linux only
+    pub(crate) fast_open: Option<u32>,
+    /// From accepting a connection until its request has been read
+    #[schemars(with = "Option<String>")]
+    pub(crate) handshake_timeout: Option<HumanDuration>,
+    /// From accepting a connection until its first byte and SOCKS5 greeting
+    /// have been read, at most `handshake_timeout`
+    #[schemars(with = "Option<String>")]
+    pub(crate) greeting_timeout: Option<HumanDuration>,
+    /// Connections in their handshake at once, those accepted past it are
+    /// closed right away
+    pub(crate) max_handshakes: Option<usize>,
+    /// How long a UDP association may relay nothing before it is closed
+    #[schemars(with = "Option<String>")]
+    pub(crate) udp_idle_timeout: Option<HumanDuration>,
+    /// Whether UDP datagrams must come from the client IP, or its IP and port
+    pub(crate) udp_client_match: Option<UdpClientMatch>,
+    /// The largest datagram payload a UDP association relays, 65535 by
+    /// default. Longer ones are truncated on receipt, and those too long
+    /// for the socket they are sent over dropped.
+    pub(crate) udp_datagram_size: Option<usize>,
+    /// `SO_RCVBUF` of the sockets of the UDP associations
+    #[schemars(with = "Option<String>")]
+    pub(crate) udp_recv_buffer: Option<ByteSize>,
+    /// `SO_SNDBUF` of the sockets of the UDP associations
+    #[schemars(with = "Option<String>")]
+    pub(crate) udp_send_buffer: Option<ByteSize>,
+    /// How long shutdown waits for connections to finish before aborting them
+    #[schemars(with = "Option<String>")]
+    pub(crate) drain_timeout: Option<HumanDuration>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UdpClientMatch {
+    Ip,
+    IpPort,
+}
+
+impl From<UdpClientMatch> for ClientMatch {
+    fn from(value: UdpClientMatch) -> Self {
+        match value {
+            UdpClientMatch::Ip => Self::Ip,
+            UdpClientMatch::IpPort => Self::IpPort,
+        }
+    }
+}
+
+impl SocketConfig {
+    #[inline]
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout.map_or(DEFAULT_HANDSHAKE_TIMEOUT, Into::into)
+    }
+
+    #[inline]
+    pub(crate) fn greeting_timeout(&self) -> Duration {
+        let greeting_timeout = self.greeting_timeout.map_or(DEFAULT_GREETING_TIMEOUT, Into::into);
+        greeting_timeout.min(self.handshake_timeout())
+    }
+
+    #[inline]
+    pub(crate) fn max_handshakes(&self) -> usize {
+        self.max_handshakes.unwrap_or(DEFAULT_MAX_HANDSHAKES)
+    }
+
+    #[inline]
+    pub(crate) fn udp_idle_timeout(&self) -> Duration {
+        self.udp_idle_timeout.map_or(DEFAULT_UDP_IDLE_TIMEOUT, Into::into)
+    }
+
+    #[inline]
+    pub(crate) fn udp_client_match(&self) -> ClientMatch {
+        self.udp_client_match.map(Into::into).unwrap_or_default()
+    }
+
+    #[inline]
+    pub(crate) fn drain_timeout(&self) -> Duration {
+        self.drain_timeout.map_or(DEFAULT_DRAIN_TIMEOUT, Into::into)
+    }
+
+    pub(crate) fn udp_buffers(&self) -> UdpBufferOptions {
+        let mut buffers = UdpBufferOptions::default();
+        if let Some(datagram_size) = self.udp_datagram_size {
+            buffers.datagram_size = datagram_size;
+        }
+        buffers.recv_buffer = self.udp_recv_buffer.map(|size| size.as_u64() as usize);
+        buffers.send_buffer = self.udp_send_buffer.map(|size| size.as_u64() as usize);
+        buffers
+    }
+
+    pub(crate) fn to_sockopts(&self) -> SocketOptions {
+        let mut sockopts = SocketOptions::default();
+        if self.nodelay.is_some() {
+            sockopts.nodelay = self.nodelay;
+        }
+        sockopts.keepalive = self.keepalive.map(Into::into);
+        sockopts.reuse_port = self.reuse_port;
+        sockopts.fast_open = self.fast_open;
+        sockopts
+    }
+}
+
+/// Retries of failed connections to destinations, see [RetryPolicy]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RetrySection {
+    /// Attempts in all, 1 by default for no retry
+    pub(crate) max_attempts: Option<u32>,
+    /// Before the first retry, doubled for each next one, 200ms by default.
+    /// The wait is random between half of it and all of it.
+    #[schemars(with = "Option<String>")]
+    pub(crate) backoff: Option<HumanDuration>,
+    /// The cap on the doubled backoff, 2s by default
+    #[schemars(with = "Option<String>")]
+    pub(crate) max_backoff: Option<HumanDuration>,
+    /// How long the attempts to connect to a destination may take in all,
+    /// unlimited by default
+    #[schemars(with = "Option<String>")]
+    pub(crate) budget: Option<HumanDuration>,
+}
+
+impl RetrySection {
+    fn to_policy(&self) -> RetryPolicy {
+        let mut policy = RetryPolicy::default();
+        if let Some(max_attempts) = self.max_attempts {
+            policy.max_attempts = max_attempts;
+        }
+        if let Some(backoff) = self.backoff {
+            policy.initial_backoff = backoff.into();
+        }
+        if let Some(max_backoff) = self.max_backoff {
+            policy.max_backoff = max_backoff.into();
+        }
+        policy.budget = self.budget.map(Into::into);
+        policy
+    }
+}
+
+/// Overrides of the [DialConfig] defaults
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DialSection {
+    #[schemars(with = "Option<String>")]
+    pub(crate) attempt_delay: Option<HumanDuration>,
+    /// `"system"`, `"temporary"` or an IPv6 prefix such as `"2001:db8:1::/64"`
+    #[schemars(with = "Option<String>")]
+    pub(crate) ipv6_source: Option<Ipv6Source>,
+    /// `"prefer_v6"`, `"prefer_v4"` or `"fastest"`, which address family is
+    /// tried first for destination names
+    #[schemars(with = "Option<String>")]
+    pub(crate) family_preference: Option<FamilyPreference>,
+    /// The interface, e.g. `"en0"`, or the source address connections and
+    /// relayed datagrams leave through, so that they do not loop back into
+    /// a tunnel routing everything
+    #[schemars(with = "Option<String>")]
+    pub(crate) bind: Option<OutboundBind>,
+    /// Timeouts, resets and unreachable hosts or networks are tried again,
+    /// refusals are not
+    pub(crate) retry: RetrySection,
+}
+
+impl DialSection {
+    pub(crate) fn to_dial_config(&self, sockopts: SocketOptions) -> DialConfig {
+        let mut dial_config = DialConfig { sockopts, ..Default::default() };
+        if let Some(attempt_delay) = self.attempt_delay {
+            dial_config.attempt_delay = attempt_delay.into();
+        }
+        if let Some(ipv6_source) = self.ipv6_source {
+            dial_config.ipv6_source = ipv6_source;
+        }
+        if let Some(family_preference) = self.family_preference {
+            dial_config.family_preference = family_preference;
+        }
+        dial_config.bind = self.bind;
+        dial_config.retry = self.retry.to_policy();
+        dial_config
+    }
+}
+
+/// Protocol trace settings, the clients to trace are picked by the
+/// `NSTREAM_TRACE` environment variable
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TraceSection {
+    /// Only trace the connections and datagrams matching this tcpdump-like
+    /// expression, e.g. `"udp and dst port 53"`
+    #[schemars(with = "Option<String>")]
+    pub(crate) filter: Option<CaptureFilter>,
+}
+
+/// Settings of the tunnel interface
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TunSection {
+    /// The path MTU toward `[wireguard]`, `[ssh]` or else the STUN servers,
+    /// less what the tunnel wraps packets in, by default
+    pub(crate) mtu: Option<u16>,
+    /// Lower the MSS of the TCP SYNs crossing the interface to what its MTU
+    /// carries, on by default
+    pub(crate) mss_clamp: Option<bool>,
+}
+
+/// Warm starts, see [crate::hints]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct WarmStartSection {
+    /// Where the external addresses are saved, `nstream-addresses.json` of
+    /// the temporary directory by default
+    pub(crate) path: Option<PathBuf>,
+    /// How long after they were found they are taken at startup, 10m by
+    /// default
+    #[schemars(with = "Option<String>")]
+    pub(crate) max_age: Option<HumanDuration>,
+}
+
+impl WarmStartSection {
+    pub(crate) fn path(&self) -> PathBuf {
+        self.path.to_owned().unwrap_or_else(|| std::env::temp_dir().join("nstream-addresses.json"))
+    }
+
+    #[inline]
+    pub(crate) fn max_age(&self) -> Duration {
+        self.max_age.map_or(DEFAULT_WARM_START_MAX_AGE, Into::into)
+    }
+}
+
+/// Firewall rules letting only loopback and the `allow` subnets reach the
+/// proxy ports, through pf on macOS and nftables on Linux
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FirewallConfig {
+    /// e.g. `["192.168.1.0/24", "fd00::/8"]`
+    #[schemars(with = "Vec<String>")]
+    pub(crate) allow: Vec<IpCidr>,
+}
+
+/// Sends all IPv4 traffic to the tunnel interface until exit, but that to
+/// the servers of the outbounds, the STUN servers and the LAN subnets, see
+/// [crate::routes]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct GlobalConfig {
+    /// More subnets reached through the original gateway, e.g.
+    /// `["203.0.113.0/24"]`
+    #[schemars(with = "Vec<String>")]
+    pub(crate) bypass: Vec<IpCidr>,
+}
+
+/// Answers DNS queries relayed over UDP with addresses from `range`, and
+/// connects to the domain a fake address stands for instead of the address
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FakeIpConfig {
+    /// `"198.18.0.0/15"` by default
+    #[schemars(with = "Option<String>")]
+    pub(crate) range: Option<IpCidr>,
+    /// How long an address stands for its domain since it was last handed out
+    #[schemars(with = "Option<String>")]
+    pub(crate) ttl: Option<HumanDuration>,
+}
+
+impl FakeIpConfig {
+    pub(crate) fn pool(&self) -> Result<FakeIpPool> {
+        let range = self.range.unwrap_or_else(|| DEFAULT_FAKE_IP_RANGE.parse().unwrap());
+        let ttl = self.ttl.map_or(DEFAULT_FAKE_IP_TTL, Into::into);
+        FakeIpPool::new(range, ttl).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+}
+
+/// Refuses the destinations located in some countries, or only reaches them
+/// through `[ssh]` or `[wireguard]`. Names are resolved first, the
+/// destination being acted on when any of its addresses is. UDP datagrams
+/// it acts on are dropped. Only read at startup.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct GeoIpConfig {
+    /// `block` acts on the `countries`, `allow` on all others
+    pub(crate) mode: GeoIpMode,
+    /// ISO 3166-1 codes, e.g. `["KP", "IR"]`
+    pub(crate) countries: Vec<String>,
+    /// `reject` by default, or `proxy`
+    pub(crate) action: GeoIpAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GeoIpMode {
+    #[default]
+    Block,
+    Allow,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GeoIpAction {
+    #[default]
+    Reject,
+    Proxy,
+}
+
+impl GeoIpConfig {
+    pub(crate) fn policy(&self) -> Result<CountryPolicy> {
+        let mode = match self.mode {
+            GeoIpMode::Block => CountryMode::Block,
+            GeoIpMode::Allow => CountryMode::Allow,
+        };
+        let action = match self.action {
+            GeoIpAction::Reject => RuleAction::Reject,
+            GeoIpAction::Proxy => RuleAction::Proxy,
+        };
+        CountryPolicy::new(mode, self.countries.to_owned(), action)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("geoip: {}", e)))
+    }
+}
+
+/// Shows the host names of IP destinations in the management API, which
+/// takes a reverse lookup per destination
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ReverseDnsConfig {
+    /// How long a lookup may take before it is given up on
+    #[schemars(with = "Option<String>")]
+    pub(crate) timeout: Option<HumanDuration>,
+    /// How long names, and their absence, are cached
+    #[schemars(with = "Option<String>")]
+    pub(crate) ttl: Option<HumanDuration>,
+}
+
+impl ReverseDnsConfig {
+    #[inline]
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout.map_or(DEFAULT_REVERSE_DNS_TIMEOUT, Into::into)
+    }
+
+    #[inline]
+    pub(crate) fn ttl(&self) -> Duration {
+        self.ttl.map_or(DEFAULT_REVERSE_DNS_TTL, Into::into)
+    }
+}
+
+/// What a user may reach and how fast
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct UserPolicy {
+    /// Destinations these rules REJECT are refused, e.g.
+    /// `["DOMAIN-SUFFIX,internal.example,REJECT"]`
+    #[schemars(with = "Vec<String>")]
+    pub(crate) rules: Vec<Rule>,
+    /// Cap on the bytes relayed per second by all the sessions of the user,
+    /// e.g. `"1MB/s"`
+    #[schemars(with = "Option<String>")]
+    pub(crate) rate: Option<ByteRate>,
+}
+
+/// Clients must authenticate with a name and password as soon as there is
+/// a user, but those of the `trusted` subnets, see [crate::users]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthConfig {
+    /// File of `name:hash` lines, the hashes being argon2 PHC strings, which
+    /// users added through the management API are saved to. They are only
+    /// kept in memory without one.
+    pub(crate) file: Option<PathBuf>,
+    /// Grant UDP ASSOCIATE to authenticated users, localhost and trusted
+    /// clients only, so that a listener reachable from elsewhere is no open relay
+    pub(crate) strict_udp: bool,
+    /// Clients connecting from these subnets may skip authentication, e.g.
+    /// `["192.168.1.0/24"]`. They can still log in to have the policy of a
+    /// user applied.
+    #[schemars(with = "Vec<String>")]
+    pub(crate) trusted: Vec<IpCidr>,
+    /// How many decisions of the user rules are cached, 4096 by default and
+    /// 0 to match the rules for every connection. The hit rate exported by
+    /// `/metrics` tells whether it fits the destinations seen.
+    pub(crate) decision_cache: Option<usize>,
+    /// Policies by user name
+    pub(crate) users: BTreeMap<String, UserPolicy>,
+}
+
+/// Shorter keys are guessable, longer ones are of no use to the TLS-PSK
+/// suites
+#[cfg(any(feature = "tls-psk", feature = "knock"))]
+const KEY_LEN: std::ops::RangeInclusive<usize> = 16..=64;
+
+/// A key of `[tls_psk]` or `[knock]` in `hex` as bytes, of a length within
+/// [KEY_LEN]
+#[cfg(any(feature = "tls-psk", feature = "knock"))]
+pub(crate) fn parse_key(hex: &str) -> std::result::Result<Vec<u8>, String> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(String::from("Not an even number of hex digits"));
+    }
+    let key = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    if !KEY_LEN.contains(&key.len()) {
+        return Err(format!(
+            "{} bytes, expected {} to {}",
+            key.len(),
+            KEY_LEN.start(),
+            KEY_LEN.end()
+        ));
+    }
+    Ok(key)
+}
+
+/// Single-packet authorization in front of the SOCKS and `[tls_psk]`
+/// listeners, which then turn away all but loopback and the addresses that
+/// knocked lately, e.g. with `nstream knock`. Needs a build with the
+/// `knock` feature, and is only read at startup.
+#[derive(Clone, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct KnockSection {
+    /// Of the UDP socket taking the knocks, also where `nstream knock`
+    /// sends them to on the server it is given
+    pub(crate) listen: SocketAddr,
+    /// In hex, 16 to 64 bytes, e.g. as `openssl rand -hex 32` prints it
+    pub(crate) key: String,
+    /// How long an address may connect for after knocking, 30s by default
+    #[schemars(with = "Option<String>")]
+    pub(crate) window: Option<HumanDuration>,
+}
+
+impl Default for KnockSection {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 62201)),
+            key: String::new(),
+            window: None,
+        }
+    }
+}
+
+/// Without the key
+impl std::fmt::Debug for KnockSection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KnockSection")
+            .field("listen", &self.listen)
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "knock")]
+impl KnockSection {
+    pub(crate) fn key(&self) -> Result<Vec<u8>> {
+        parse_key(&self.key)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("knock: key: {}", e)))
+    }
+}
+
+/// Clients of the SOCKS listeners speak SOCKS over TLS, see [crate::tls].
+/// Needs a build with the `tls` feature, and is only read at startup.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TlsSection {
+    /// PEM file of the certificate of the listeners, followed by its chain
+    pub(crate) cert: PathBuf,
+    /// PEM file of its private key
+    pub(crate) key: PathBuf,
+}
+
+/// A device of the `[tls_psk]` listener, by its identity
+#[derive(Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TlsPskIdentity {
+    /// In hex, 16 to 64 bytes, e.g. as `openssl rand -hex 32` prints it
+    pub(crate) key: String,
+    /// Whose policy of `[auth.users]` the device is given, it needs no
+    /// password
+    pub(crate) user: String,
+}
+
+/// Without the key
+impl std::fmt::Debug for TlsPskIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsPskIdentity").field("user", &self.user).finish_non_exhaustive()
+    }
+}
+
+/// A listener of SOCKS over TLS for devices authenticated by a pre-shared
+/// key rather than a certificate, see [crate::tls_psk]. Needs a build with
+/// the `tls-psk` feature, and is only read at startup.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TlsPskSection {
+    pub(crate) listen: SocketAddr,
+    pub(crate) identities: BTreeMap<String, TlsPskIdentity>,
+}
+
+impl Default for TlsPskSection {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 1443)),
+            identities: BTreeMap::new(),
+        }
+    }
+}
+
+/// The peer of the `[wireguard]` tunnel
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct WireGuardPeerSection {
+    /// In base64, as `wg pubkey` prints it
+    pub(crate) public_key: String,
+    pub(crate) preshared_key: Option<String>,
+    /// `host:port`, resolved at startup
+    pub(crate) endpoint: String,
+    /// The destinations connected to through the tunnel, e.g.
+    /// `["10.7.0.0/24"]`
+    #[schemars(with = "Vec<String>")]
+    pub(crate) allowed_ips: Vec<IpCidr>,
+    /// e.g. `"25s"` behind a NAT, no keepalives are sent without traffic
+    /// otherwise
+    #[schemars(with = "Option<String>")]
+    pub(crate) persistent_keepalive: Option<HumanDuration>,
+}
+
+/// Connects to the destinations within the `allowed_ips` of the peer
+/// through a WireGuard tunnel run in process, without an interface of the
+/// system. Needs a build with the `wireguard` feature, and is only read at
+/// startup.
+#[derive(Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct WireGuardSection {
+    /// In base64, as `wg genkey` prints it
+    pub(crate) private_key: String,
+    /// Of this end of the tunnel, one per address family at most, e.g.
+    /// `["10.7.0.2"]`
+    pub(crate) address: Vec<IpAddr>,
+    /// 1420 by default
+    pub(crate) mtu: Option<usize>,
+    pub(crate) peer: WireGuardPeerSection,
+}
+
+/// Without the private key
+impl std::fmt::Debug for WireGuardSection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WireGuardSection")
+            .field("address", &self.address)
+            .field("mtu", &self.mtu)
+            .field("peer", &self.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "wireguard")]
+impl WireGuardSection {
+    pub(crate) async fn to_config(&self) -> Result<nstream_core::WireGuardConfig> {
+        use nstream_core::{WireGuardConfig, WireGuardKey, WireGuardPeer};
+
+        let invalid = |e: String| Error::new(ErrorKind::InvalidInput, format!("wireguard: {}", e));
+        let key = |key: &str| key.parse::<WireGuardKey>().map_err(invalid);
+        let endpoint =
+            tokio::net::lookup_host(&self.peer.endpoint).await?.next().ok_or_else(|| {
+                invalid(format!("No address for the endpoint {}", self.peer.endpoint))
+            })?;
+        let peer = WireGuardPeer {
+            public_key: key(&self.peer.public_key)?,
+            preshared_key: self.peer.preshared_key.as_deref().map(key).transpose()?,
+            endpoint,
+            allowed_ips: self.peer.allowed_ips.to_owned(),
+            persistent_keepalive: self.peer.persistent_keepalive.map(Into::into),
+        };
+        let mut config =
+            WireGuardConfig::new(key(&self.private_key)?, self.address.to_owned(), peer);
+        if let Some(mtu) = self.mtu {
+            config.mtu = mtu;
+        }
+        Ok(config)
+    }
+}
+
+/// Connects to the destinations the `rules` send to PROXY through the
+/// direct-tcpip channels of an SSH server, as `ssh -W` does. Needs a build
+/// with the `ssh` feature, and is only read at startup.
+#[derive(Clone, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SshSection {
+    /// `host:port` of the server
+    pub(crate) server: String,
+    pub(crate) user: String,
+    /// Logged in with unless `identity_file` is set
+    pub(crate) password: Option<String>,
+    /// An Ed25519 key without passphrase, as `ssh-keygen -t ed25519`
+    /// writes it
+    pub(crate) identity_file: Option<PathBuf>,
+    /// The fingerprint of the key of the server as `ssh-keygen -l` prints
+    /// it, or the public key itself
+    pub(crate) host_key: String,
+    /// PROXY through the server, DIRECT past it and REJECT refused, every
+    /// destination goes through the server without rules
+    #[schemars(with = "Vec<String>")]
+    pub(crate) rules: Vec<Rule>,
+}
+
+/// Without the password
+impl std::fmt::Debug for SshSection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SshSection")
+            .field("server", &self.server)
+            .field("user", &self.user)
+            .field("identity_file", &self.identity_file)
+            .field("host_key", &self.host_key)
+            .field("rules", &self.rules)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl SshSection {
+    pub(crate) fn to_config(&self) -> Result<nstream_core::SshConfig> {
+        use nstream_core::{SshAuth, SshConfig, SshPrivateKey};
+
+        let invalid = |e: String| Error::new(ErrorKind::InvalidInput, format!("ssh: {}", e));
+        let auth = match (&self.password, &self.identity_file) {
+            (None, Some(path)) => SshAuth::Key(
+                SshPrivateKey::from_openssh(&std::fs::read_to_string(path)?)
+                    .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?,
+            ),
+            (Some(password), None) => SshAuth::Password(password.to_owned()),
+            _ => return Err(invalid("Either password or identity_file must be set".into())),
+        };
+        let host_key = self.host_key.parse().map_err(invalid)?;
+        Ok(SshConfig::new(&self.server, &self.user, auth, host_key))
+    }
+}
+
+/// The TOML configuration file, e.g.
+///
+/// ```toml
+/// rules = ["BUNDLE-ID,com.tinyspeck.slackmacgap,DIRECT", "GEOIP,CN,DIRECT,log-sample=100", "MATCH,PROXY"]
+/// log_blocked = true
+///
+/// [socket]
+/// nodelay = true
+/// keepalive = "30s"
+/// reuse_port = true
+/// fast_open = 256
+/// handshake_timeout = "10s"
+/// greeting_timeout = "3s"
+/// max_handshakes = 512
+/// udp_idle_timeout = "2m"
+/// udp_client_match = "ip_port"
+/// udp_datagram_size = 1472
+/// udp_recv_buffer = "4MiB"
+/// udp_send_buffer = "1MiB"
+/// drain_timeout = "10s"
+///
+/// [dial]
+/// attempt_delay = "250ms"
+/// ipv6_source = "temporary"
+/// family_preference = "fastest"
+/// bind = "en0"
+///
+/// [dial.retry]
+/// max_attempts = 3
+/// backoff = "200ms"
+/// budget = "10s"
+///
+/// [trace]
+/// filter = "tcp and dst port 443"
+///
+/// [tun]
+/// mtu = 1400
+///
+/// [warm_start]
+/// max_age = "10m"
+///
+/// [admin]
+/// listen = "127.0.0.1:9090"
+/// token = "secret"
+/// confirm_window = "2m"
+///
+/// [pac]
+/// listen = "127.0.0.1:9091"
+///
+/// [firewall]
+/// allow = ["192.168.1.0/24"]
+///
+/// [global]
+/// bypass = ["203.0.113.0/24"]
+///
+/// [fake_ip]
+/// range = "198.18.0.0/15"
+/// ttl = "10m"
+///
+/// [geoip]
+/// mode = "block"
+/// countries = ["KP", "IR"]
+/// action = "reject"
+///
+/// [reverse_dns]
+/// timeout = "2s"
+/// ttl = "1h"
+///
+/// [auth]
+/// file = "/etc/nstream/users"
+/// strict_udp = true
+/// trusted = ["192.168.1.0/24"]
+/// decision_cache = 4096
+///
+/// [auth.users.guest]
+/// rules = ["IP-CIDR,192.168.0.0/16,REJECT"]
+/// rate = "1MB/s"
+///
+/// [wireguard]
+/// private_key = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+/// address = ["10.7.0.2"]
+///
+/// [wireguard.peer]
+/// public_key = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+/// endpoint = "vpn.example:51820"
+/// allowed_ips = ["10.7.0.0/24"]
+/// persistent_keepalive = "25s"
+///
+/// [ssh]
+/// server = "bastion.example:22"
+/// user = "nstream"
+/// identity_file = "/etc/nstream/id_ed25519"
+/// host_key = "SHA256:2OapaW2JfJBtRMZB5gcnr3OR03njWiUKky/QKS9xOaU"
+/// rules = ["DOMAIN-SUFFIX,corp.example,PROXY", "MATCH,DIRECT"]
+///
+/// [tls]
+/// cert = "/etc/nstream/fullchain.pem"
+/// key = "/etc/nstream/privkey.pem"
+///
+/// [tls_psk]
+/// listen = "0.0.0.0:1443"
+///
+/// [tls_psk.identities.thermostat-1]
+/// key = "6f0c3e2b9a4d5871c2e4f60a1b3d5c7e"
+/// user = "guest"
+///
+/// [knock]
+/// key = "0b7d2f6e41a9c3581e6d4b2a9f7c0e35"
+/// window = "1m"
+///
+/// [profiles]
+/// home = ["MATCH,DIRECT"]
+/// ```
+///
+/// Any key can be overridden by the environment, then by `--set`, e.g.
+/// `NSTREAM__DIAL__ATTEMPT_DELAY=100ms` or `--set dial.attempt_delay=100ms`.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub(crate) admin: Option<AdminConfig>,
+    pub(crate) pac: Option<PacConfig>,
+    pub(crate) firewall: Option<FirewallConfig>,
+    pub(crate) global: Option<GlobalConfig>,
+    pub(crate) fake_ip: Option<FakeIpConfig>,
+    pub(crate) geoip: Option<GeoIpConfig>,
+    pub(crate) reverse_dns: Option<ReverseDnsConfig>,
+    pub(crate) auth: Option<AuthConfig>,
+    pub(crate) wireguard: Option<WireGuardSection>,
+    pub(crate) ssh: Option<SshSection>,
+    pub(crate) tls: Option<TlsSection>,
+    pub(crate) tls_psk: Option<TlsPskSection>,
+    pub(crate) knock: Option<KnockSection>,
+    pub(crate) warm_start: Option<WarmStartSection>,
+    /// Applied by the PAC file, but the `PROCESS-NAME`, `PROCESS-PATH` and
+    /// `BUNDLE-ID` rules, which the proxy applies to the clients on this
+    /// host: DIRECT connects past `[ssh]` and `[wireguard]`, REJECT refuses
+    #[schemars(with = "Vec<String>")]
+    pub(crate) rules: Vec<Rule>,
+    /// Log each connection a rule refuses along with the rule, to the
+    /// output and `/logs`, they are only counted otherwise
+    pub(crate) log_blocked: bool,
+    /// Named rule sets the management API can switch to instead of `rules`
+    #[schemars(with = "BTreeMap<String, Vec<String>>")]
+    pub(crate) profiles: BTreeMap<String, Vec<Rule>>,
+    pub(crate) socket: SocketConfig,
+    pub(crate) dial: DialSection,
+    pub(crate) trace: TraceSection,
+    pub(crate) tun: TunSection,
+}
+
+/// Prefix of the environment variables overriding keys, the rest of the
+/// name being the key path separated by `__`, e.g. `NSTREAM__SOCKET__KEEPALIVE`
+const ENV_PREFIX: &str = "NSTREAM__";
+
+/// An override of a key of the configuration file, given as
+/// `section.key=value` where the value is a TOML value, or a string when it
+/// does not parse as one
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConfigOverride {
+    path: Vec<String>,
+    value: toml::Value,
+}
+
+/// `raw` as a TOML value, e.g. `30`, `true` or `["MATCH,PROXY"]`
+fn parse_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_owned()))
+}
+
+impl FromStr for ConfigOverride {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (path, raw) =
+            s.split_once('=').ok_or_else(|| format!("{}, expected section.key=value", s))?;
+        let path = path.trim().split('.').map(str::to_owned).collect::<Vec<_>>();
+        if path.iter().any(String::is_empty) {
+            return Err(format!("{}: Empty key", s));
+        }
+        Ok(Self { path, value: parse_value(raw.trim()) })
+    }
+}
+
+impl ConfigOverride {
+    /// Those given by the `NSTREAM__*` environment variables, in the order
+    /// of their names
+    pub(crate) fn from_env() -> Vec<Self> {
+        let mut vars = std::env::vars()
+            .filter_map(|(name, raw)| {
+                let path = name.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase();
+                let path = path.split("__").map(str::to_owned).collect::<Vec<_>>();
+                (!path.iter().any(String::is_empty))
+                    .then(|| (name, Self { path, value: parse_value(&raw) }))
+            })
+            .collect::<Vec<_>>();
+        vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+        vars.into_iter().map(|(_, config_override)| config_override).collect()
+    }
+
+    fn apply(&self, table: &mut toml::Table) -> Result<()> {
+        let (key, sections) = self.path.split_last().unwrap();
+        let mut table = table;
+        for section in sections {
+            let entry =
+                table.entry(section.to_owned()).or_insert_with(|| toml::Table::new().into());
+            table = entry.as_table_mut().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{}: Not a section", self.path.join(".")),
+                )
+            })?;
+        }
+        table.insert(key.to_owned(), self.value.to_owned());
+        Ok(())
+    }
+}
+
+impl Config {
+    /// The file at `path`, if any, with the keys overridden by the
+    /// environment, then by `overrides`. Errors name the file, and the
+    /// offending key along with its line when nothing is overridden.
+    pub(crate) fn load(path: Option<&Path>, overrides: &[ConfigOverride]) -> Result<Self> {
+        let overrides = [ConfigOverride::from_env(), overrides.to_vec()].concat();
+        let name = path.map_or(String::from("configuration"), |path| path.display().to_string());
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::new(ErrorKind::InvalidData, format!("{}: {}", name, e))
+        };
+        let content = match path {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => String::new(),
+        };
+        if overrides.is_empty() {
+            return toml::from_str(&content).map_err(|e| invalid(&e));
+        }
+        let mut table = toml::from_str::<toml::Table>(&content).map_err(|e| invalid(&e))?;
+        for config_override in overrides.iter() {
+            config_override.apply(&mut table)?;
+        }
+        toml::Value::Table(table).try_into().map_err(|e| invalid(&e))
+    }
+}
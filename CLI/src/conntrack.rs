@@ -0,0 +1,158 @@
+//! Connection tracking
+//!
+//! Every relayed connection is tracked by the client address of its TCP
+//! connection, which for UDP ASSOCIATE is the control connection. An entry
+//! lists the sockets held on behalf of the client, and when it was last
+//! active, so that idle UDP associations can be expired and any connection
+//! can be killed from the management API.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::state::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug)]
+struct Entry {
+    session_id: u64,
+    protocol: Protocol,
+    /// Local addresses of the sockets relaying for the client
+    sockets: Vec<SocketAddr>,
+    /// Seconds since the UNIX epoch
+    started_at: u64,
+    last_active: Instant,
+    bytes_sent: u64,
+    bytes_received: u64,
+    killed: Arc<Notify>,
+}
+
+/// A tracked connection, as listed by the management API
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub(crate) struct Conn {
+    pub(crate) client: SocketAddr,
+    pub(crate) session_id: u64,
+    pub(crate) protocol: Protocol,
+    pub(crate) sockets: Vec<SocketAddr>,
+    pub(crate) started_at: u64,
+    pub(crate) idle_secs: u64,
+    /// Only counted as the datagrams of UDP associations are relayed, TCP
+    /// sessions are accounted in the traffic totals once closed
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ConnTrack {
+    entries: DashMap<SocketAddr, Entry>,
+}
+
+impl ConnTrack {
+    /// Start tracking the connection of `client`, the returned [Notify] is
+    /// signaled when it is to be killed
+    pub(crate) fn track(
+        &self,
+        client: SocketAddr,
+        session_id: u64,
+        protocol: Protocol,
+        sockets: Vec<SocketAddr>,
+    ) -> Arc<Notify> {
+        let killed = Arc::new(Notify::new());
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let entry = Entry {
+            session_id,
+            protocol,
+            sockets,
+            started_at,
+            last_active: Instant::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            killed: killed.clone(),
+        };
+        self.entries.insert(client, entry);
+        killed
+    }
+
+    #[inline]
+    pub(crate) fn untrack(&self, client: &SocketAddr) {
+        self.entries.remove(client);
+    }
+
+    /// Record activity of the connection of `client`
+    pub(crate) fn touch(&self, client: &SocketAddr, bytes_sent: u64, bytes_received: u64) {
+        if let Some(mut entry) = self.entries.get_mut(client) {
+            entry.last_active = Instant::now();
+            entry.bytes_sent += bytes_sent;
+            entry.bytes_received += bytes_received;
+        }
+    }
+
+    /// Ask the connection of `client` to stop, returns whether there is one
+    pub(crate) fn kill(&self, client: &SocketAddr) -> bool {
+        match self.entries.get(client) {
+            Some(entry) => {
+                /* Stores a permit, so it is not missed by a relay that is
+                 * not waiting at the moment */
+                entry.killed.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Kill the UDP associations idle for longer than `idle_timeout`,
+    /// returns how many
+    pub(crate) fn expire_idle_udp(&self, idle_timeout: Duration) -> usize {
+        let mut expired = 0;
+        for entry in self.entries.iter() {
+            if entry.protocol == Protocol::Udp && entry.last_active.elapsed() > idle_timeout {
+                entry.killed.notify_one();
+                expired += 1;
+            }
+        }
+        expired
+    }
+
+    pub(crate) fn list(&self) -> Vec<Conn> {
+        let mut conns = self
+            .entries
+            .iter()
+            .map(|entry| Conn {
+                client: *entry.key(),
+                session_id: entry.session_id,
+                protocol: entry.protocol,
+                sockets: entry.sockets.to_owned(),
+                started_at: entry.started_at,
+                idle_secs: entry.last_active.elapsed().as_secs(),
+                bytes_sent: entry.bytes_sent,
+                bytes_received: entry.bytes_received,
+            })
+            .collect::<Vec<_>>();
+        conns.sort_by_key(|conn| conn.session_id);
+        conns
+    }
+}
+
+/// Expire idle UDP associations until the process exits
+pub(crate) async fn expire_loop(state: Arc<AppState>) {
+    let idle_timeout = state.udp_idle_timeout();
+    let mut interval = tokio::time::interval((idle_timeout / 4).max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        let expired = state.conntrack.expire_idle_udp(idle_timeout);
+        if expired > 0 {
+            println!("Expired {} idle UDP associations", expired);
+        }
+    }
+}
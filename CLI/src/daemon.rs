@@ -0,0 +1,139 @@
+//! Background mode, `--daemon`, and the pidfile `nstream stop` finds the
+//! running instance by
+//!
+//! The process forks before the runtime starts, the parent exiting at once
+//! and the child leaving the terminal for a session of its own, its output
+//! going to `--log-file`. The working directory is kept, relative paths of
+//! the command line and the configuration file stay valid.
+//!
+//! `nstream stop` sends SIGTERM, which shuts the proxy down as Ctrl + C
+//! does. Should it still run after [STOP_TIMEOUT], it is killed and the
+//! system proxy settings and firewall rules it would have removed are
+//! removed in its place. The tunnel interface goes away with its process.
+
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// How long `nstream stop` waits for the connections to drain and the
+/// process to exit before killing it
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `nstream.pid` of the temporary directory
+pub(crate) fn default_pidfile() -> PathBuf {
+    std::env::temp_dir().join("nstream.pid")
+}
+
+fn check(ret: libc::c_int) -> Result<libc::c_int> {
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Go on in a child process detached from the terminal, its standard
+/// output and error appended to `log_file` or discarded. The parent prints
+/// the pid of the child and exits. Only to be called while the process has
+/// a single thread.
+pub(crate) fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    let log = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = OpenOptions::new().read(true).open("/dev/null")?;
+    match check(unsafe { libc::fork() })? {
+        0 => {}
+        pid => {
+            println!("nstream runs in the background as {}", pid);
+            std::process::exit(0)
+        }
+    }
+    check(unsafe { libc::setsid() })?;
+    unsafe {
+        check(libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO))?;
+        check(libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO))?;
+        check(libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO))?;
+    }
+    Ok(())
+}
+
+/// Whether a process `pid` exists, which may not be ours to signal
+fn is_running(pid: libc::pid_t) -> bool {
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// The pid `path` holds, None without such a file
+fn read_pid(path: &Path) -> Result<Option<libc::pid_t>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => {
+            content.trim().parse().map(Some).map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write the pid of this process to `path`, failing if another instance
+/// running holds it unless `takeover` tells this one takes its place
+pub(crate) fn write_pidfile(path: &Path, takeover: bool) -> Result<()> {
+    if let Some(pid) = read_pid(path).ok().flatten() {
+        if !takeover && pid != std::process::id() as libc::pid_t && is_running(pid) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("nstream already runs as {}, see {}", pid, path.display()),
+            ));
+        }
+    }
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, format!("{}\n", std::process::id()))?;
+    std::fs::rename(tmp_path, path)
+}
+
+/// Remove `path` if it still holds the pid of this process
+pub(crate) fn remove_pidfile(path: &Path) {
+    if read_pid(path).ok().flatten() == Some(std::process::id() as libc::pid_t) {
+        if let Err(e) = std::fs::remove_file(path) {
+            eprintln!("Removing {} failed; error: {:?}", path.display(), e);
+        }
+    }
+}
+
+/// `nstream stop`, shut the instance of `pidfile` down
+pub(crate) async fn stop(pidfile: &Path, config: &Config) -> Result<()> {
+    let pid = read_pid(pidfile)?.ok_or_else(|| {
+        Error::new(ErrorKind::NotFound, format!("No pidfile at {}", pidfile.display()))
+    })?;
+    if !is_running(pid) {
+        std::fs::remove_file(pidfile)?;
+        return Err(Error::new(ErrorKind::NotFound, format!("nstream {} is not running", pid)));
+    }
+    check(unsafe { libc::kill(pid, libc::SIGTERM) })?;
+    let mut waited = Duration::ZERO;
+    while is_running(pid) && waited < STOP_TIMEOUT {
+        tokio::time::sleep(STOP_POLL_INTERVAL).await;
+        waited += STOP_POLL_INTERVAL;
+    }
+    if !is_running(pid) {
+        println!("Stopped nstream {}", pid);
+        return Ok(());
+    }
+    check(unsafe { libc::kill(pid, libc::SIGKILL) })?;
+    println!("Killed nstream {}, still running after {:?}", pid, STOP_TIMEOUT);
+    /* What its shutdown would have undone */
+    crate::cmd::close_pac_proxy()?;
+    crate::cmd::close_socks5_proxy()?;
+    if config.firewall.is_some() {
+        crate::firewall::Firewall::remove_leftover()?;
+    }
+    std::fs::remove_file(pidfile)
+}
@@ -0,0 +1,119 @@
+//! Names resolved on behalf of clients, served by the management API
+//!
+//! Each query is recorded along with what answered it, how long it took
+//! and the rule in use its answer matches, so that why a destination was
+//! sent directly or through the proxy can be followed from the name on.
+//! The last [CAPACITY] queries are kept for `GET /dns/queries` to start
+//! with, the later ones are streamed as they come, `nstream tail dns`
+//! prints them.
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+const CAPACITY: usize = 200;
+
+/// What answered a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Resolver {
+    /// `getaddrinfo`, for the destinations dialed by name
+    System,
+    /// `[fake_ip]`, for the queries relayed over UDP
+    FakeIp,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub(crate) struct DnsQuery {
+    /// Seconds since the UNIX epoch
+    pub(crate) at: u64,
+    pub(crate) domain: String,
+    /// Of the connection or UDP association the query was made for
+    pub(crate) client: SocketAddr,
+    pub(crate) resolver: Resolver,
+    /// Those tried first come first, none for a failed query
+    pub(crate) answers: Vec<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    pub(crate) latency_us: u64,
+    /// The first rule in use matching the domain along with the first
+    /// answer, as written in the configuration file, None when no rule does
+    pub(crate) rule: Option<String>,
+    /// What the rule says, `PROXY` without one
+    pub(crate) action: String,
+}
+
+impl DnsQuery {
+    pub(crate) fn new(
+        domain: &str,
+        client: SocketAddr,
+        resolver: Resolver,
+        answers: std::result::Result<Vec<IpAddr>, String>,
+        latency: Duration,
+    ) -> Self {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let (answers, error) = match answers {
+            Ok(answers) => (answers, None),
+            Err(e) => (vec![], Some(e)),
+        };
+        Self {
+            at,
+            domain: domain.to_owned(),
+            client,
+            resolver,
+            answers,
+            error,
+            latency_us: latency.as_micros() as u64,
+            rule: None,
+            action: nstream_core::RuleAction::Proxy.to_string(),
+        }
+    }
+}
+
+/// Streamed by `GET /dns/queries`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum DnsLogEvent {
+    Query(DnsQuery),
+    /// Queries the subscriber missed for falling behind
+    Lagged {
+        missed: u64,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) struct DnsLog {
+    recent: Mutex<VecDeque<DnsQuery>>,
+    queries: broadcast::Sender<DnsQuery>,
+}
+
+impl Default for DnsLog {
+    fn default() -> Self {
+        Self { recent: Mutex::default(), queries: broadcast::channel(CAPACITY).0 }
+    }
+}
+
+impl DnsLog {
+    pub(crate) fn push(&self, query: DnsQuery) {
+        /* Sent with the lock held, for subscribe to tell which are recent */
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(query.clone());
+        /* Fails only when nobody is subscribed */
+        let _ = self.queries.send(query);
+    }
+
+    /// The queries kept, oldest first, along with the later ones from now
+    /// on, none of them missing or twice in both
+    pub(crate) fn subscribe(&self) -> (Vec<DnsQuery>, broadcast::Receiver<DnsQuery>) {
+        let recent = self.recent.lock().unwrap();
+        (recent.iter().cloned().collect(), self.queries.subscribe())
+    }
+}
@@ -0,0 +1,42 @@
+//! Recent events of the running instance, served by the management API
+//!
+//! Only the last [CAPACITY] entries are kept in memory, this is for a quick
+//! look from the dashboard rather than a replacement for the output of the
+//! process.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+const CAPACITY: usize = 500;
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub(crate) struct LogEntry {
+    /// Seconds since the UNIX epoch
+    pub(crate) at: u64,
+    pub(crate) message: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct EventLog {
+    entries: Mutex<VecDeque<LogEntry>>,
+}
+
+impl EventLog {
+    pub(crate) fn push(&self, message: String) {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(LogEntry { at, message });
+    }
+
+    /// The entries kept, oldest first
+    pub(crate) fn recent(&self) -> Vec<LogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,124 @@
+//! Firewall rules restricting who can reach the proxy ports
+//!
+//! Binding to a LAN address exposes the proxy to the whole network, so with
+//! `[firewall]` configured only loopback and the listed subnets may connect
+//! to the listeners. The rules are loaded into a pf anchor on macOS and into
+//! an nftables table of their own on Linux, so that removing them leaves the
+//! rest of the firewall as it was. UDP relays are left out: their ports are
+//! picked per association, and they only take datagrams from its client.
+
+use std::io::{Error, ErrorKind, Result};
+
+use nstream_core::IpCidr;
+
+use crate::ops::run;
+
+/// Evaluated by the stock pf.conf of macOS, which loads `com.apple/*`
+#[cfg(target_os = "macos")]
+const PF_ANCHOR: &str = "com.apple/nstream";
+
+#[cfg(target_os = "linux")]
+const NFT_TABLE: &str = "nstream";
+
+/// The rules of the anchor
+#[cfg(target_os = "macos")]
+const PF_ANCHOR_PROBE: (&str, &[&str]) = ("pfctl", &["-a", PF_ANCHOR, "-s", "rules"]);
+/// Whether pf is enabled
+#[cfg(target_os = "macos")]
+const PF_INFO_PROBE: (&str, &[&str]) = ("pfctl", &["-s", "Running"]);
+
+#[cfg(target_os = "linux")]
+const NFT_TABLE_PROBE: (&str, &[&str]) = ("nft", &["list", "table", "inet", NFT_TABLE]);
+
+#[inline]
+fn join<T: ToString>(items: &[T]) -> String {
+    items.iter().map(T::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Only let loopback and `allow` reach TCP `ports`
+#[cfg(target_os = "macos")]
+fn pf_ruleset(ports: &[u16], allow: &[IpCidr]) -> String {
+    let ports = join(ports);
+    let mut ruleset = format!("pass in quick on lo0 proto tcp to any port {{ {} }}\n", ports);
+    if !allow.is_empty() {
+        ruleset += &format!("table <nstream_allow> const {{ {} }}\n", join(allow));
+        ruleset +=
+            &format!("pass in quick proto tcp from <nstream_allow> to any port {{ {} }}\n", ports);
+    }
+    ruleset += &format!("block drop in quick proto tcp to any port {{ {} }}\n", ports);
+    ruleset
+}
+
+/// Same as [pf_ruleset], replacing the table left by a previous run
+#[cfg(target_os = "linux")]
+fn nft_ruleset(ports: &[u16], allow: &[IpCidr]) -> String {
+    let ports = join(ports);
+    let (v4, v6): (Vec<&IpCidr>, Vec<&IpCidr>) =
+        allow.iter().partition(|cidr| cidr.addr().is_ipv4());
+    let mut chain = format!("\t\tiif \"lo\" tcp dport {{ {} }} accept\n", ports);
+    if !v4.is_empty() {
+        chain += &format!("\t\tip saddr {{ {} }} tcp dport {{ {} }} accept\n", join(&v4), ports);
+    }
+    if !v6.is_empty() {
+        chain += &format!("\t\tip6 saddr {{ {} }} tcp dport {{ {} }} accept\n", join(&v6), ports);
+    }
+    chain += &format!("\t\ttcp dport {{ {} }} drop\n", ports);
+    format!(
+        "table inet {0}\ndelete table inet {0}\ntable inet {0} {{\n\tchain input {{\n\t\ttype filter hook input priority filter; policy accept;\n{1}\t}}\n}}\n",
+        NFT_TABLE, chain
+    )
+}
+
+/// Loads the rules and enables pf, returns the token to release it with
+#[cfg(target_os = "macos")]
+fn pf_apply(ports: &[u16], allow: &[IpCidr]) -> Result<Option<String>> {
+    let ruleset = pf_ruleset(ports, allow);
+    run("pfctl", &["-a", PF_ANCHOR, "-f", "-"], Some(&ruleset), PF_ANCHOR_PROBE)?;
+    let printed = run("pfctl", &["-E"], None, PF_INFO_PROBE)?;
+    /* The token is printed as `Token : 1234`, on stderr */
+    Ok(printed.lines().find_map(|line| line.strip_prefix("Token : ")).map(str::to_owned))
+}
+
+#[cfg(target_os = "macos")]
+fn pf_remove(pf_token: Option<String>) -> Result<()> {
+    run("pfctl", &["-a", PF_ANCHOR, "-F", "all"], None, PF_ANCHOR_PROBE)?;
+    if let Some(pf_token) = pf_token {
+        run("pfctl", &["-X", &pf_token], None, PF_INFO_PROBE)?;
+    }
+    Ok(())
+}
+
+/// Rules in force until [Firewall::remove]
+#[derive(Debug)]
+pub(crate) struct Firewall {
+    /// Handed out by `pfctl -E`, pf stays enabled while others hold one
+    pf_token: Option<String>,
+}
+
+impl Firewall {
+    /// Only let loopback and `allow` reach TCP `ports`
+    pub(crate) fn apply(ports: &[u16], allow: &[IpCidr]) -> Result<Self> {
+        #[cfg(target_os = "macos")]
+        return pf_apply(ports, allow).map(|pf_token| Self { pf_token });
+        #[cfg(target_os = "linux")]
+        return run("nft", &["-f", "-"], Some(&nft_ruleset(ports, allow)), NFT_TABLE_PROBE)
+            .map(|_| Self { pf_token: None });
+        #[allow(unreachable_code)]
+        Err(Error::new(ErrorKind::Unsupported, "No supported firewall on this system"))
+    }
+
+    pub(crate) fn remove(self) -> Result<()> {
+        #[cfg(target_os = "macos")]
+        return pf_remove(self.pf_token);
+        #[cfg(target_os = "linux")]
+        return run("nft", &["delete", "table", "inet", NFT_TABLE], None, NFT_TABLE_PROBE)
+            .map(drop);
+        #[allow(unreachable_code)]
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Remove the rules a process that could not remove them left
+    pub(crate) fn remove_leftover() -> Result<()> {
+        Self { pf_token: None }.remove()
+    }
+}
@@ -0,0 +1,113 @@
+//! Warm starts, the external addresses STUN found saved for a restart to
+//! start with instead of waiting for the STUN servers again
+//!
+//! The file `[warm_start]` points at holds the addresses along with when
+//! they were found. A restart within `max_age` of that takes them at once
+//! and only looks the LAN addresses up, then queries STUN in the background
+//! and saves what it finds. Where the STUN servers are slow or blocked this
+//! saves the whole discovery timeout.
+
+use std::io::{ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nstream_core::{discover_addresses, discover_lan_addresses, Addresses};
+use serde::{Deserialize, Serialize};
+
+use crate::config::WarmStartSection;
+
+/// The external addresses STUN found, as saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct AddressHints {
+    ext_v4: Option<SocketAddr>,
+    ext_v6: Option<SocketAddr>,
+    /// Seconds since the Unix epoch
+    found_at: u64,
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl AddressHints {
+    fn of(addrs: &Addresses) -> Self {
+        Self { ext_v4: addrs.ext_v4, ext_v6: addrs.ext_v6, found_at: unix_secs() }
+    }
+
+    /// Those saved at `path`, None when there are none or they are older
+    /// than `max_age`
+    fn load(path: &Path, max_age: Duration) -> Option<Self> {
+        let content = match std::fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                eprintln!("Reading {} failed; error: {:?}", path.display(), e);
+                return None;
+            }
+        };
+        let hints = serde_json::from_slice::<Self>(&content).ok()?;
+        let age = unix_secs().checked_sub(hints.found_at)?;
+        (age <= max_age.as_secs()).then_some(hints)
+    }
+
+    /// Replace the file at `path` with these
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(tmp_path, path)
+    }
+}
+
+/// Save the external addresses of `addrs` to `path`. Nothing found is no
+/// hint, the ones saved before stay until too old.
+fn save_found(path: &Path, addrs: &Addresses) {
+    if addrs.ext_v4.is_none() && addrs.ext_v6.is_none() {
+        return;
+    }
+    if let Err(e) = AddressHints::of(addrs).save(path) {
+        eprintln!("Saving {} failed; error: {:?}", path.display(), e);
+    }
+}
+
+/// Look the external addresses up again, then save them to `path` and
+/// tell if they changed from `hints`
+async fn revalidate(path: PathBuf, hints: AddressHints, timeout: Duration) {
+    let addrs = discover_addresses(timeout).await;
+    if (addrs.ext_v4, addrs.ext_v6) != (hints.ext_v4, hints.ext_v6) {
+        println!(
+            "External addresses are {:?} and {:?}, not those of the last run",
+            addrs.ext_v4, addrs.ext_v6
+        );
+    }
+    save_found(&path, &addrs);
+}
+
+/// The addresses of this host as [discover_addresses] finds them, the
+/// external ones of the last run being taken at once while recent enough
+/// when `warm_start` is set
+pub(crate) async fn discover_addresses_warm(
+    warm_start: Option<&WarmStartSection>,
+    timeout: Duration,
+) -> Addresses {
+    let Some(warm_start) = warm_start else {
+        return discover_addresses(timeout).await;
+    };
+    let path = warm_start.path();
+    match AddressHints::load(&path, warm_start.max_age()) {
+        Some(hints) => {
+            println!(
+                "External addresses of {}s ago, checking them in the background",
+                unix_secs().saturating_sub(hints.found_at)
+            );
+            tokio::spawn(revalidate(path, hints, timeout));
+            let lan = discover_lan_addresses(timeout).await;
+            Addresses { ext_v4: hints.ext_v4, ext_v6: hints.ext_v6, ..lan }
+        }
+        None => {
+            let addrs = discover_addresses(timeout).await;
+            save_found(&path, &addrs);
+            addrs
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! HTTP CONNECT on the SOCKS port
+//!
+//! With `--single-port` a connection starting with an ASCII letter is an
+//! HTTP proxy request rather than a SOCKS one. Only CONNECT is served, the
+//! tunnel then carries whatever the client sends, TLS most of the time.
+
+use std::io::{Error, ErrorKind, Result};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use socks5::protocol::Address;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest request head read before giving up on it
+const MAX_HEAD_LEN: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// The first bytes of the requests told apart from SOCKS ones, every
+/// method starts with an uppercase letter
+pub(crate) const FIRST_BYTES: std::ops::RangeInclusive<u8> = b'A'..=b'Z';
+
+/// Read the request head up to its empty line, byte by byte so that what
+/// the client sends right after it stays in the stream for the tunnel
+async fn read_head<R>(r: &mut R) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut head = Vec::with_capacity(512);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_HEAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "HTTP request head too long"));
+        }
+        head.push(r.read_u8().await?);
+    }
+    Ok(head)
+}
+
+/// The name and password of a `Proxy-Authorization: Basic` header
+fn basic_credentials(value: &[u8]) -> Option<(String, String)> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_owned(), password.to_owned()))
+}
+
+/// Read a CONNECT request and return the destination it names, along with
+/// the Basic credentials of the client if any. Any other method fails with
+/// [ErrorKind::Unsupported]
+pub(crate) async fn read_connect<R>(r: &mut R) -> Result<(Address, Option<(String, String)>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let head = read_head(r).await?;
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return Err(ErrorKind::UnexpectedEof.into()),
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+    }
+    match req.method {
+        Some("CONNECT") => {}
+        method => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Unsupported HTTP method: {:?}", method),
+            ))
+        }
+    }
+    let credentials = req
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Proxy-Authorization"))
+        .and_then(|header| basic_credentials(header.value));
+    /* The authority form, "host:port" */
+    let target = req.path.unwrap_or_default();
+    let addr = Address::try_from(target.to_string()).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("Invalid CONNECT target {:?}: {}", target, e))
+    })?;
+    Ok((addr, credentials))
+}
+
+/// Write a response without a body, closing the connection unless the
+/// tunnel is established
+pub(crate) async fn respond<W>(w: &mut W, status: u16, reason: &str) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let extra = match status {
+        405 => "Allow: CONNECT\r\n",
+        407 => "Proxy-Authenticate: Basic realm=\"nstream\"\r\n",
+        _ => "",
+    };
+    let resp = match status {
+        200 => format!("HTTP/1.1 200 {}\r\n\r\n", reason),
+        _ => format!(
+            "HTTP/1.1 {} {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status, reason, extra
+        ),
+    };
+    w.write_all(resp.as_bytes()).await
+}
+
+/// The status answering a request that could not be read
+pub(crate) fn status_of_request_error(e: &Error) -> (u16, &'static str) {
+    match e.kind() {
+        ErrorKind::Unsupported => (405, "Method Not Allowed"),
+        ErrorKind::TimedOut => (408, "Request Timeout"),
+        _ => (400, "Bad Request"),
+    }
+}
+
+/// The status answering a CONNECT whose destination could not be reached
+pub(crate) fn status_of_dial_error(e: &Error) -> (u16, &'static str) {
+    if matches!(socks5::Socks5Error::of(e), Some(socks5::Socks5Error::Blocked { .. })) {
+        return (403, "Forbidden");
+    }
+    match e.kind() {
+        ErrorKind::TimedOut => (504, "Gateway Timeout"),
+        _ => (502, "Bad Gateway"),
+    }
+}
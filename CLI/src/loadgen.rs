@@ -0,0 +1,171 @@
+//! Load generator, `nstream loadgen`
+//!
+//! Opens concurrent connections to a running nstream, each of which
+//! negotiates, CONNECTs to an echo server started here and has a payload
+//! echoed back, then reports the latency percentiles and the failures.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use socks5::protocol::{
+    AuthMethod, HandshakeRequest, HandshakeResponse, ReplyField, ReplyResponse, TellRequest,
+    UsernamePasswordAuth, UsernamePasswordAuthResult,
+};
+use socks5::Socks5Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::args::LoadgenArgs;
+
+/// Timings of a connection that went through
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// From connecting to the reply to CONNECT
+    handshake: Duration,
+    /// From sending the payload to having received it back
+    echo: Duration,
+}
+
+async fn serve_echo(echo_listener: TcpListener) -> Result<()> {
+    loop {
+        let (mut tcp_stream, _) = echo_listener.accept().await?;
+        tokio::spawn(async move {
+            let (mut r, mut w) = tcp_stream.split();
+            tokio::io::copy(&mut r, &mut w).await
+        });
+    }
+}
+
+async fn probe(
+    target: SocketAddr,
+    echo_addr: SocketAddr,
+    credentials: Option<(Arc<str>, Arc<str>)>,
+    payload: Arc<[u8]>,
+) -> Result<Sample> {
+    let started = Instant::now();
+    let mut tcp_stream = TcpStream::connect(target).await?;
+    let method = match credentials {
+        Some(_) => AuthMethod::UsernameOrPassword,
+        None => AuthMethod::NoAuthenticationRequired,
+    };
+    HandshakeRequest::new(vec![method.clone()]).write_to(&mut tcp_stream).await?;
+    if HandshakeResponse::from(&mut tcp_stream).await?.method() != method {
+        return Err(Error::new(ErrorKind::PermissionDenied, "No acceptable method"));
+    }
+    if let Some((usr, pwd)) = credentials {
+        UsernamePasswordAuth::new(&usr, &pwd).write_to(&mut tcp_stream).await?;
+        if UsernamePasswordAuthResult::from(&mut tcp_stream).await?
+            != UsernamePasswordAuthResult::Succeeded
+        {
+            return Err(Socks5Error::AuthFailed.into());
+        }
+    }
+    TellRequest::connect(echo_addr).write_to(&mut tcp_stream).await?;
+    let rep = ReplyResponse::from(&mut tcp_stream).await?.rep();
+    if rep != ReplyField::Succeeded {
+        return Err(Error::new(ErrorKind::ConnectionRefused, format!("Replied {:?}", rep)));
+    }
+    let handshake = started.elapsed();
+
+    let started = Instant::now();
+    let mut echoed = vec![0u8; payload.len()];
+    let (mut r, mut w) = tcp_stream.split();
+    tokio::try_join!(w.write_all(&payload), r.read_exact(&mut echoed))?;
+    if *echoed != *payload {
+        return Err(Error::new(ErrorKind::InvalidData, "Payload echoed back altered"));
+    }
+    Ok(Sample { handshake, echo: started.elapsed() })
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    samples: Vec<Sample>,
+    /// Number of connections per reason they failed for
+    failures: BTreeMap<String, usize>,
+    elapsed: Duration,
+}
+
+/// The `p`th percentile of `durations`, which must be sorted
+fn percentile(durations: &[Duration], p: f64) -> Duration {
+    match durations.len() {
+        0 => Duration::ZERO,
+        len => durations[((len - 1) as f64 * p / 100.0).round() as usize],
+    }
+}
+
+fn print_percentiles(name: &str, durations: impl Iterator<Item = Duration>) {
+    let mut durations = durations.collect::<Vec<_>>();
+    durations.sort_unstable();
+    println!(
+        "  {:<10} p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+        name,
+        percentile(&durations, 50.0),
+        percentile(&durations, 90.0),
+        percentile(&durations, 99.0),
+        durations.last().copied().unwrap_or_default()
+    );
+}
+
+impl Report {
+    #[inline]
+    pub(crate) fn failed(&self) -> usize {
+        self.failures.values().sum()
+    }
+
+    pub(crate) fn print(&self) {
+        println!(
+            "{} connections in {:?}: {} succeeded, {} failed",
+            self.samples.len() + self.failed(),
+            self.elapsed,
+            self.samples.len(),
+            self.failed()
+        );
+        print_percentiles("handshake", self.samples.iter().map(|sample| sample.handshake));
+        print_percentiles("echo", self.samples.iter().map(|sample| sample.echo));
+        for (reason, count) in self.failures.iter() {
+            println!("  {} failed: {}", count, reason);
+        }
+    }
+}
+
+/// Run every connection of `args` at once, until done or timed out
+pub(crate) async fn run(args: &LoadgenArgs) -> Result<Report> {
+    let echo_listener = TcpListener::bind(args.echo_listen).await?;
+    let echo_addr = echo_listener.local_addr()?;
+    let echo_server = tokio::spawn(serve_echo(echo_listener));
+
+    let credentials = match (&args.user, &args.password) {
+        (Some(usr), Some(pwd)) => Some((Arc::<str>::from(usr.as_str()), Arc::from(pwd.as_str()))),
+        (None, None) => None,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "--user and --password go together")),
+    };
+    let payload = (0..args.payload.as_u64()).map(|i| (i % 251) as u8).collect::<Arc<[u8]>>();
+    let timeout = args.timeout.as_duration();
+
+    let started = Instant::now();
+    let mut probes = JoinSet::new();
+    for _ in 0..args.connections {
+        let (credentials, payload) = (credentials.clone(), payload.clone());
+        let probe = probe(args.target, echo_addr, credentials, payload);
+        probes.spawn(async move {
+            tokio::time::timeout(timeout, probe)
+                .await
+                .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "Timed out")))
+        });
+    }
+    let mut report = Report::default();
+    while let Some(ret) = probes.join_next().await {
+        match ret.map_err(Error::other).and_then(|ret| ret) {
+            Ok(sample) => report.samples.push(sample),
+            Err(e) => *report.failures.entry(e.to_string()).or_default() += 1,
+        }
+    }
+    report.elapsed = started.elapsed();
+    echo_server.abort();
+    Ok(report)
+}
@@ -1,213 +1,1877 @@
+mod accepted;
+mod admin;
+mod args;
+mod check;
 mod cmd;
+mod config;
+mod conntrack;
+mod daemon;
+mod dnslog;
+mod eventlog;
+mod firewall;
+mod hints;
+mod http;
+mod loadgen;
+mod metrics;
+mod ops;
+mod pac;
+mod preflight;
+mod rdns;
+mod routes;
+mod schema;
+mod session;
+mod share;
+mod state;
+mod status;
+mod tail;
+mod tasks;
+mod tls;
+#[cfg(feature = "tls-psk")]
+mod tls_psk;
+mod upgrade;
+mod users;
 
 use core::net::{Ipv6Addr, SocketAddr};
+use std::collections::HashSet;
 use std::error::Error;
-use std::net::{Ipv4Addr, SocketAddrV6};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, SocketAddrV6};
+use std::ops::ControlFlow;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use advanced_random_string::{charset, random_string};
+use clap::Parser;
+use socks5::dispatch::Dispatcher;
 use socks5::protocol::{
-    Address, AuthMethod, Command, HandshakeRequest, HandshakeResponse, ReplyField, ReplyResponse,
-    TellRequest, UdpPacket,
+    Address, AuthMethod, Command, ExpectedClient, HandshakeRequest, HandshakeResponse, ReplyField,
+    ReplyResponse, TellRequest, UdpPacket, UsernamePasswordAuth, UsernamePasswordAuthResult,
+    MAX_UDP_HEAD_LEN,
 };
-use socks5::{exchange_data, wait_closed};
+use socks5::socks4::{Socks4Command, Socks4Reply, Socks4ReplyCode, Socks4Request, SOCKS4_VERSION};
+use socks5::stream::{Peek, Peekable, Shutdown};
+use socks5::trace::Tracer;
+use socks5::udp_pool::{PooledUdpSocket, UdpPoolKey};
+use socks5::{wait_closed, with_deadline, Socks5Error, SOCKS_VERSION};
 
 use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
 use tokio::signal;
 use tokio::sync::Mutex;
+use tokio::time::Instant;
 
+use crate::accepted::Accepted;
+use crate::args::{Args, Commands, IpPreference, ProxyState};
+use crate::config::{AdminConfig, Config};
+use crate::conntrack::Protocol;
+use crate::dnslog::{DnsQuery, Resolver};
+use crate::metrics::Metrics;
+use crate::session::Session;
+use crate::state::AppState;
+use crate::tls::{TlsStream, TlsTerminator};
+use crate::upgrade::relay_session;
+use crate::users::RateLimiter;
+
+use nstream_core::overhead::{payload_mtu, Carrier, TransportKind};
 use nstream_core::{
-    seeval, what_is_my_extip_v4addr, what_is_my_extip_v6addr, what_is_my_lanip_v4addr,
-    what_is_my_lanip_v6addr, Tun, VTun, VTunConfig,
+    discover_addresses, happy_eyeballs_connect, seeval, trace_println, DialConfig, Flow, FlowProto,
+    SocketOptions, Tun, VTun, VTunConfig,
 };
 
-async fn register_graceful_shutdown() {
-    let close_socks5_proxy_and_exit = || {
-        crate::cmd::close_socks5_proxy().unwrap();
-        std::process::exit(0)
+/// How long looking up the addresses of this host may hold startup up
+const ADDRESS_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// `pac` tells whether the system proxy is set to the PAC file, `pidfile`
+/// is removed last
+async fn register_graceful_shutdown(state: Arc<AppState>, pac: bool, pidfile: Option<PathBuf>) {
+    let sigterm = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => sigterm.recv().await,
+            Err(err) => {
+                eprintln!("Unable to listen for SIGTERM: {}", err);
+                std::future::pending().await
+            }
+        }
+    };
+    tokio::select! {
+        ret = signal::ctrl_c() => match ret {
+            Ok(()) => println!(" (Received Ctrl + C)"),
+            Err(err) => {
+                eprintln!("Unable to listen for shutdown signal: {}", err);
+                // we also shut down in case of error
+            }
+        },
+        _ = sigterm => println!(" (Received SIGTERM)"),
+        _ = state.shutdown_requested() => {
+            println!(" (Shutdown requested by the management API)");
+        }
+    }
+    close_system_proxy(pac);
+    println!("Waiting for {} connections to finish", state.tasks.running());
+    let aborted = state.drain().await;
+    if aborted > 0 {
+        println!("Aborted {} connections still open after {:?}", aborted, state.drain_timeout());
+    }
+    undo_system_changes(&state, pidfile.as_deref());
+    std::process::exit(0)
+}
+
+/// Unset the system proxy, set to the PAC file if `pac`. Left set, the rest
+/// of the cleanup still has to happen.
+fn close_system_proxy(pac: bool) {
+    let closed = match pac {
+        true => crate::cmd::close_pac_proxy(),
+        false => crate::cmd::close_socks5_proxy(),
+    };
+    if let Err(e) = closed {
+        eprintln!("Unsetting the system proxy failed; error: {:?}", e);
+    }
+}
+
+/// Remove the tunnel interface, routes, firewall rules and `pidfile` that
+/// startup set up, whichever it got to
+fn undo_system_changes(state: &AppState, pidfile: Option<&Path>) {
+    destroy_vtun(state);
+    if let Some(firewall) = state.take_firewall() {
+        if let Err(e) = firewall.remove() {
+            eprintln!("Removing firewall rules failed; error: {:?}", e);
+        }
+    }
+    if let Some(pidfile) = pidfile {
+        crate::daemon::remove_pidfile(pidfile);
+    }
+}
+
+/// Remove the tunnel interface along with its addresses and routes, which
+/// [std::process::exit] would leave to the kernel otherwise
+fn destroy_vtun(state: &AppState) {
+    if let Some(routes) = state.take_routes() {
+        if let Err(e) = routes.remove() {
+            eprintln!("Restoring the routing table failed; error: {:?}", e);
+        }
+    }
+    if let Some(vtun) = state.take_vtun() {
+        let ifname = vtun.ifname().unwrap_or_default();
+        let what = format!("destroy tunnel interface {}", ifname);
+        if let Err(e) = crate::ops::change(what, interfaces, || vtun.destroy()) {
+            eprintln!("Unconfiguring tunnel interface {} failed; error: {:?}", ifname, e);
+        }
+    }
+}
+
+/// The network interfaces of the system, what creating and destroying the
+/// tunnel interface changes
+fn interfaces() -> std::io::Result<String> {
+    #[cfg(target_os = "macos")]
+    return crate::ops::query("ifconfig", &["-l"]);
+    #[cfg(target_os = "linux")]
+    return crate::ops::query("ip", &["-brief", "link"]);
+    #[allow(unreachable_code)]
+    Err(std::io::Error::from(ErrorKind::Unsupported))
+}
+
+/// The tracer of a connection from `peer_addr`, which is held back until the
+/// request tells whether the connection matches the trace filter
+fn tracer_for(peer_addr: SocketAddr, state: &AppState) -> Tracer {
+    match (state.traces(peer_addr.ip()), state.trace_filter()) {
+        (false, _) => Tracer::disabled(),
+        (true, None) => Tracer::new(peer_addr, true),
+        (true, Some(_)) => Tracer::pending(peer_addr),
+    }
+}
+
+/// The flow from `client` to `destination`, for matching the trace filter
+fn flow_to(proto: FlowProto, client: SocketAddr, destination: &Address) -> Flow {
+    match destination {
+        Address::IP(socket_addr) => Flow::new(proto, client).with_dst_addr(*socket_addr),
+        Address::Domain(name, port) => Flow::new(proto, client).with_dst_domain(name, *port),
+    }
+}
+
+/// Decide a pending tracer once the request tells what the client asked for
+fn resolve_tracer(tracer: &Tracer, state: &AppState, flow: &Flow) {
+    if let Some(filter) = state.trace_filter() {
+        tracer.resolve(filter.may_match(flow));
+    }
+}
+
+/// Record a session for the established `proxy_tcp_stream` and relay it,
+/// whether the client asked for it over SOCKS5 or SOCKS4, at the rate
+/// `limiter` allows
+async fn relay_established<S: Accepted>(
+    destination: &Address,
+    command: &str,
+    proxy_tcp_stream: &mut TcpStream,
+    tcp_stream: &mut S,
+    limiter: Option<&RateLimiter>,
+    state: &AppState,
+) -> std::io::Result<()> {
+    #[cfg(feature = "prometheus")]
+    if let Some(iso_code) = nstream_core::iso_code_of(proxy_tcp_stream.peer_addr()?.ip()) {
+        state.metrics.inc_country(&iso_code);
+    }
+    let client = tcp_stream.peer_addr()?;
+    let sampled = state.sample_log(destination);
+    let destination = destination.to_string();
+    if sampled {
+        state.log.push(format!("{} {} to {}", command, client, destination));
+    }
+    let session_id = state.sessions.open(client, destination, command, "direct");
+    let sockets = vec![tcp_stream.local_addr()?, proxy_tcp_stream.local_addr()?];
+    let killed = state.conntrack.track(client, session_id, Protocol::Tcp, sockets);
+    let relay_ret = tokio::select! {
+        relay_ret = relay_session(state, session_id, proxy_tcp_stream, tcp_stream, limiter) => relay_ret,
+        _ = killed.notified() => {
+            state.sessions.close(session_id, 0, 0);
+            Ok(false)
+        }
     };
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            println!(" (Received Ctrl + C)");
-            close_socks5_proxy_and_exit()
+    state.conntrack.untrack(&client);
+    if relay_ret? {
+        /* Handed over to a new process, which now owns the connections */
+        return Ok(());
+    }
+    tcp_stream.shutdown().await
+}
+
+/// Connect to `addr` for the client of `tcp_stream`, names are resolved
+/// with both address families in flight
+async fn dial(
+    addr: &Address,
+    tcp_stream: &TcpStream,
+    dial_config: &DialConfig,
+    state: &AppState,
+) -> std::io::Result<TcpStream> {
+    let addr = state.unfake(addr);
+    let client = tcp_stream.peer_addr()?;
+    /* By the country of its addresses, whatever the other rules say */
+    let mut resolved = None;
+    if let Some(policy) = state.country_policy() {
+        let addrs = match &addr {
+            Address::IP(socket_addr) => vec![*socket_addr],
+            Address::Domain(name, port) => {
+                state.resolve(name, *port, dial_config.family_preference, client).await?
+            }
+        };
+        if let Some(rule) = policy.rule_for(addrs.iter().map(SocketAddr::ip)) {
+            state.metrics.inc_country_rule(&rule);
+            if rule.action == nstream_core::RuleAction::Reject {
+                return Err(state.block(client, &addr, &rule));
+            }
+            return match dial_outbound(&addr, &addrs, state).await {
+                Some(ret) => {
+                    let destination = addr.to_string();
+                    state.log.push(format!(
+                        "Sent {} to {} through the outbound by {}",
+                        client, destination, rule
+                    ));
+                    ret
+                }
+                /* Never directly */
+                None => Err(state.block(client, &addr, &rule)),
+            };
+        }
+        resolved = Some(addrs);
+    }
+    if let Some((_, rule)) = state.app_rule(tcp_stream).await {
+        match rule.action {
+            nstream_core::RuleAction::Reject => return Err(state.block(client, &addr, &rule)),
+            /* Past the outbounds */
+            nstream_core::RuleAction::Direct => {
+                return dial_direct(&addr, resolved, client, dial_config, state).await
+            }
+            nstream_core::RuleAction::Proxy => {}
+        }
+    }
+    #[cfg(feature = "ssh")]
+    if let Some((ssh, router)) = state.ssh() {
+        let (name, ip) = match &addr {
+            Address::IP(socket_addr) => (None, Some(socket_addr.ip())),
+            Address::Domain(name, _) => (Some(name.as_str()), None),
+        };
+        match router.matched_rule(name, ip) {
+            Some(rule) if rule.action == nstream_core::RuleAction::Reject => {
+                return Err(state.block(client, &addr, rule));
+            }
+            Some(rule) if rule.action == nstream_core::RuleAction::Direct => {}
+            _ => {
+                return match &addr {
+                    Address::IP(socket_addr) => {
+                        ssh.connect(&socket_addr.ip().to_string(), socket_addr.port()).await
+                    }
+                    Address::Domain(name, port) => ssh.connect(name, *port).await,
+                };
+            }
+        }
+    }
+    #[cfg(feature = "wireguard")]
+    if let Some(wireguard) = state.wireguard() {
+        return dial_wireguard(&addr, resolved, client, dial_config, state, wireguard).await;
+    }
+    dial_direct(&addr, resolved, client, dial_config, state).await
+}
+
+/// Through `[ssh]`, else `[wireguard]`, whatever their rules say. None
+/// without either.
+#[cfg_attr(not(any(feature = "ssh", feature = "wireguard")), allow(unused_variables))]
+async fn dial_outbound(
+    addr: &Address,
+    addrs: &[SocketAddr],
+    state: &AppState,
+) -> Option<std::io::Result<TcpStream>> {
+    #[cfg(feature = "ssh")]
+    if let Some((ssh, _)) = state.ssh() {
+        return Some(match addr {
+            Address::IP(socket_addr) => {
+                ssh.connect(&socket_addr.ip().to_string(), socket_addr.port()).await
+            }
+            Address::Domain(name, port) => ssh.connect(name, *port).await,
+        });
+    }
+    #[cfg(feature = "wireguard")]
+    if let Some(wireguard) = state.wireguard() {
+        return Some(wireguard.connect(addrs).await);
+    }
+    None
+}
+
+/// Names are resolved for `client` as [nstream_core::connect_host] does,
+/// the queries being logged, unless `resolved` has their addresses already
+async fn dial_direct(
+    addr: &Address,
+    resolved: Option<Vec<SocketAddr>>,
+    client: SocketAddr,
+    dial_config: &DialConfig,
+    state: &AppState,
+) -> std::io::Result<TcpStream> {
+    match addr {
+        Address::IP(socket_addr) => happy_eyeballs_connect(&[*socket_addr], dial_config).await,
+        Address::Domain(name, port) => {
+            let addrs = match resolved {
+                Some(addrs) => addrs,
+                None => state.resolve(name, *port, dial_config.family_preference, client).await?,
+            };
+            let dial_config = DialConfig { prefer_ipv6: addrs[0].is_ipv6(), ..*dial_config };
+            happy_eyeballs_connect(&addrs, &dial_config).await
         }
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
-            // we also shut down in case of error
-            close_socks5_proxy_and_exit()
+    }
+}
+
+/// Through the tunnel when an address of the destination is within the
+/// allowed IPs of the peer, directly otherwise
+#[cfg(feature = "wireguard")]
+async fn dial_wireguard(
+    addr: &Address,
+    resolved: Option<Vec<SocketAddr>>,
+    client: SocketAddr,
+    dial_config: &DialConfig,
+    state: &AppState,
+    wireguard: &nstream_core::WireGuard,
+) -> std::io::Result<TcpStream> {
+    let addrs = match (addr, resolved) {
+        (_, Some(addrs)) => addrs,
+        (Address::IP(socket_addr), None) => vec![*socket_addr],
+        (Address::Domain(name, port), None) => {
+            state.resolve(name, *port, dial_config.family_preference, client).await?
         }
+    };
+    if addrs.iter().any(|addr| wireguard.routes(addr.ip())) {
+        return wireguard.connect(&addrs).await;
+    }
+    let dial_config = DialConfig { prefer_ipv6: addrs[0].is_ipv6(), ..*dial_config };
+    happy_eyeballs_connect(&addrs, &dial_config).await
+}
+
+/// What `[global]` keeps out of the tunnel: the servers the outbounds and
+/// STUN queries go to, the LAN subnets and the `bypass` subnets
+async fn global_bypass(
+    global_config: &crate::config::GlobalConfig,
+    config: &Config,
+) -> std::io::Result<Vec<nstream_core::IpCidr>> {
+    let mut servers =
+        nstream_core::CoreContext::global().stun_servers().map(|addr| addr.ip()).to_vec();
+    let endpoints = [
+        config.wireguard.as_ref().map(|wireguard| wireguard.peer.endpoint.as_str()),
+        config.ssh.as_ref().map(|ssh| ssh.server.as_str()),
+    ];
+    for endpoint in endpoints.into_iter().flatten() {
+        servers.extend(lookup_host(endpoint).await?.map(|addr| addr.ip()));
     }
+    let mut bypass = crate::routes::host_routes(servers);
+    bypass.extend(
+        crate::routes::LAN_SUBNETS.iter().map(|cidr| cidr.parse::<nstream_core::IpCidr>().unwrap()),
+    );
+    bypass.extend(global_config.bypass.iter().copied());
+    Ok(bypass)
+}
+
+/// Each probe of the path MTU waits as long for an ICMP error
+const PATH_MTU_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The MTU of the tunnel interface, the path MTU toward the upstream less
+/// what it wraps packets in unless `[tun]` sets one. An upstream over TCP
+/// wraps none the path sees, its stream being segmented anew.
+async fn tun_mtu(config: &Config) -> u16 {
+    if let Some(mtu) = config.tun.mtu {
+        return mtu;
+    }
+    let (upstream, carrier) = match (config.wireguard.as_ref(), config.ssh.as_ref()) {
+        (Some(wireguard), _) => (Some(wireguard.peer.endpoint.as_str()), Some(Carrier::WireGuard)),
+        (None, Some(ssh)) => (Some(ssh.server.as_str()), None),
+        (None, None) => (None, None),
+    };
+    let dest = match upstream {
+        Some(upstream) => lookup_host(upstream).await.ok().and_then(|mut addrs| addrs.next()),
+        None => Some(nstream_core::CoreContext::global().stun_servers()[0]),
+    };
+    /* Assuming the larger IPv6 header of an unknown address */
+    let payload = |path_mtu: u16, dest: Option<SocketAddr>| match carrier {
+        Some(carrier) => {
+            let ipv6 = !dest.is_some_and(|dest| dest.is_ipv4());
+            payload_mtu(path_mtu, TransportKind::new(ipv6, carrier))
+        }
+        None => path_mtu,
+    };
+    let Some(dest) = dest else {
+        eprintln!(
+            "No address for {}, the tunnel MTU is left to the default",
+            upstream.unwrap_or_default()
+        );
+        return payload(nstream_core::MTU_PLATEAUS[0], None);
+    };
+    let path_mtu = match nstream_core::probe_path_mtu(dest, PATH_MTU_PROBE_TIMEOUT).await {
+        Ok(path_mtu) => {
+            println!("Path MTU toward {} is {}", dest, path_mtu);
+            path_mtu
+        }
+        Err(e) => {
+            eprintln!("Path MTU toward {} unknown; error: {:?}", dest, e);
+            nstream_core::MTU_PLATEAUS[0]
+        }
+    };
+    /* The interface has an IPv6 address, which needs this much */
+    payload(path_mtu, Some(dest)).max(nstream_core::MIN_PATH_MTU)
+}
+
+/// Bring the tunnel of `section` up before the first connection is dialed
+#[cfg(feature = "wireguard")]
+async fn start_wireguard(
+    section: &crate::config::WireGuardSection,
+    state: &AppState,
+) -> std::io::Result<()> {
+    let config = section.to_config().await?;
+    println!("WireGuard peer {} reaches {:?}", config.peer.endpoint, config.peer.allowed_ips);
+    state.set_wireguard(nstream_core::WireGuard::start(config).await?);
+    Ok(())
+}
+
+/// Refused rather than ignored, the destinations it should reach would be
+/// connected to directly
+#[cfg(not(feature = "wireguard"))]
+async fn start_wireguard(
+    _section: &crate::config::WireGuardSection,
+    _state: &AppState,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "[wireguard] is configured, this build is without the wireguard feature",
+    ))
+}
+
+/// Check `section` before the first connection is dialed, the server is
+/// only logged in to then
+#[cfg(feature = "ssh")]
+fn start_ssh(section: &crate::config::SshSection, state: &AppState) -> std::io::Result<()> {
+    let config = section.to_config()?;
+    println!("SSH server {} reached as {}", config.server, config.user);
+    let router = nstream_core::Router::new(section.rules.to_owned());
+    state.set_ssh(nstream_core::Ssh::new(config), router);
+    Ok(())
+}
+
+/// Take the knocks of `section` until exit, the listeners turning away the
+/// addresses that did not knock from then on
+#[cfg(feature = "knock")]
+async fn start_knock(
+    section: &crate::config::KnockSection,
+    state: &Arc<AppState>,
+) -> std::io::Result<()> {
+    let window = section.window.map(Into::into).unwrap_or(nstream_core::DEFAULT_KNOCK_WINDOW);
+    state.set_knock(nstream_core::KnockGate::new(&section.key()?, window));
+    let udp_socket = UdpSocket::bind(section.listen).await?;
+    println!("Knocks taken on {}, each letting its address in for {:?}", section.listen, window);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; nstream_core::KNOCK_LEN + 1];
+        loop {
+            let (n, from) = match udp_socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Knock listener stopped; error: {:?}", e);
+                    break;
+                }
+            };
+            let Some(gate) = state.knock() else { break };
+            match gate.knock(&buf[..n], from.ip()) {
+                Ok(()) => println!("Knock from {}", from.ip()),
+                /* Stale or replayed, the key holders are worth telling */
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                    eprintln!("Knock from {} ignored; error: {}", from, e)
+                }
+                /* Scanners, left unanswered */
+                Err(_) => {}
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Refused rather than ignored, the listeners would be open to all
+#[cfg(not(feature = "knock"))]
+async fn start_knock(
+    _section: &crate::config::KnockSection,
+    _state: &Arc<AppState>,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "[knock] is configured, this build is without the knock feature",
+    ))
+}
+
+/// Knock on `server` with the key of `[knock]`
+#[cfg(feature = "knock")]
+async fn send_knock(section: &crate::config::KnockSection, server: &str) -> std::io::Result<()> {
+    let key = section.key()?;
+    let dest = lookup_host(server).await?.next().ok_or_else(|| {
+        std::io::Error::new(ErrorKind::NotFound, format!("No address for {}", server))
+    })?;
+    nstream_core::send_knock(dest, &key).await?;
+    println!("Knocked on {}", dest);
+    Ok(())
+}
+
+#[cfg(not(feature = "knock"))]
+async fn send_knock(_section: &crate::config::KnockSection, _server: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(ErrorKind::Unsupported, "This build is without the knock feature"))
 }
 
-async fn impl_connect(
-    tellreq_addr: &SocketAddr,
-    tcp_stream: &mut TcpStream,
+/// Refused rather than ignored, the destinations it should reach would be
+/// connected to directly
+#[cfg(not(feature = "ssh"))]
+fn start_ssh(_section: &crate::config::SshSection, _state: &AppState) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "[ssh] is configured, this build is without the ssh feature",
+    ))
+}
+
+/// `user` is the name the client authenticated with, if any
+async fn impl_connect<S: Accepted>(
+    tellreq_addr: &Address,
+    tcp_stream: &mut S,
+    dial_config: &DialConfig,
+    tracer: &Tracer,
+    user: Option<&str>,
+    state: &AppState,
 ) -> std::io::Result<()> {
-    let proxy_tcp_stream_ret = TcpStream::connect(tellreq_addr).await;
+    if let Some(rule) = user.and_then(|user| state.users.blocked_by(user, tellreq_addr)) {
+        let e = state.block(tcp_stream.peer_addr()?, tellreq_addr, &rule);
+        let rep_resp = ReplyResponse::failed((&e).into());
+        tracer.send(&rep_resp);
+        rep_resp.respond_with(tcp_stream).await?;
+        return tcp_stream.shutdown().await;
+    }
+    let limiter = user.and_then(|user| state.users.limiter(user));
+    let proxy_tcp_stream_ret = dial(tellreq_addr, tcp_stream.socket(), dial_config, state).await;
     let rep: ReplyField = (&proxy_tcp_stream_ret).into();
     let rep_resp = ReplyResponse::new(rep, Address::default());
+    tracer.send(&rep_resp);
     rep_resp.respond_with(tcp_stream).await?;
-    if rep_resp.rep() == ReplyField::Succeeded {
-        let mut proxy_tcp_stream = proxy_tcp_stream_ret.unwrap();
-        exchange_data(&mut proxy_tcp_stream, tcp_stream).await?;
-    } else {
-        drop(proxy_tcp_stream_ret);
+    match proxy_tcp_stream_ret {
+        Ok(mut proxy_tcp_stream) if rep_resp.rep() == ReplyField::Succeeded => {
+            relay_established(
+                tellreq_addr,
+                "CONNECT",
+                &mut proxy_tcp_stream,
+                tcp_stream,
+                limiter.as_deref(),
+                state,
+            )
+            .await
+        }
+        _ => tcp_stream.shutdown().await,
+    }
+}
+
+async fn impl_socks4_connect<S: Accepted>(
+    req_addr: &Address,
+    tcp_stream: &mut S,
+    dial_config: &DialConfig,
+    tracer: &Tracer,
+    state: &AppState,
+) -> std::io::Result<()> {
+    let proxy_tcp_stream_ret = dial(req_addr, tcp_stream.socket(), dial_config, state).await;
+    let reply = Socks4Reply::new(
+        (&proxy_tcp_stream_ret).into(),
+        SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+    );
+    tracer.send(&reply);
+    reply.respond_with(tcp_stream).await?;
+    match proxy_tcp_stream_ret {
+        Ok(mut proxy_tcp_stream) => {
+            relay_established(req_addr, "CONNECT", &mut proxy_tcp_stream, tcp_stream, None, state)
+                .await
+        }
+        Err(_) => tcp_stream.shutdown().await,
+    }
+}
+
+/// SOCKS4 BIND: listen for a single inbound connection from the
+/// destination, announcing the listen address and then the peer address
+async fn impl_socks4_bind<S: Accepted>(
+    req_addr: &Address,
+    tcp_stream: &mut S,
+    tracer: &Tracer,
+    state: &AppState,
+) -> std::io::Result<()> {
+    let reject = |tracer: &Tracer| {
+        let reply = Socks4Reply::new(
+            Socks4ReplyCode::Rejected,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        );
+        tracer.send(&reply);
+        reply
+    };
+    let listen_ip = match tcp_stream.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) => ip,
+        std::net::IpAddr::V6(ip) => ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED),
+    };
+    let tcp_listener = match TcpListener::bind(SocketAddrV4::new(listen_ip, 0)).await {
+        Ok(tcp_listener) => tcp_listener,
+        Err(e) => {
+            reject(tracer).respond_with(tcp_stream).await?;
+            tcp_stream.shutdown().await?;
+            return Err(e);
+        }
+    };
+    let reply = Socks4Reply::new(
+        Socks4ReplyCode::Granted,
+        SocketAddrV4::new(listen_ip, tcp_listener.local_addr()?.port()),
+    );
+    tracer.send(&reply);
+    reply.respond_with(tcp_stream).await?;
+
+    let (mut inbound_tcp_stream, inbound_addr) = tokio::select! {
+        ret = tcp_listener.accept() => ret?,
+        _ = wait_closed(tcp_stream) => return Ok(()),
+    };
+    let expected_ip = match req_addr {
+        Address::IP(socket_addr) => Some(socket_addr.ip()),
+        Address::Domain(..) => lookup_host(req_addr.to_string())
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .map(|a| a.ip()),
+    };
+    let inbound_ip = inbound_addr.ip().to_canonical();
+    if expected_ip.is_some_and(|ip| !ip.is_unspecified() && ip != inbound_ip) {
+        /* Only the destination named in the request may connect back */
+        reject(tracer).respond_with(tcp_stream).await?;
+        return tcp_stream.shutdown().await;
+    }
+    let inbound_v4addr = match inbound_ip {
+        std::net::IpAddr::V4(ip) => SocketAddrV4::new(ip, inbound_addr.port()),
+        std::net::IpAddr::V6(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, inbound_addr.port()),
+    };
+    let reply = Socks4Reply::new(Socks4ReplyCode::Granted, inbound_v4addr);
+    tracer.send(&reply);
+    reply.respond_with(tcp_stream).await?;
+    relay_established(req_addr, "BIND", &mut inbound_tcp_stream, tcp_stream, None, state).await
+}
+
+/// SOCKS4 and SOCKS4a clients skip the method negotiation, the request
+/// comes first
+async fn handle_socks4<S: Accepted>(mut tcp_stream: S, ctx: ConnContext) -> std::io::Result<()> {
+    let ConnContext { dial_config, tracer, state, deadline, authenticated, .. } = ctx;
+    let req =
+        with_deadline(deadline, Socks4Request::from(&mut tcp_stream)).await.inspect_err(|_| {
+            state.metrics.inc_handshake_failures();
+        })?;
+    seeval!(&req);
+    tracer.recv(&req);
+    let client = tcp_stream.peer_addr()?;
+    if authenticated.is_none() && state.users.required_from(client.ip()) {
+        /* A USERID is no password */
+        state.metrics.inc_auth_failures();
+        let reply = Socks4Reply::new(
+            Socks4ReplyCode::Rejected,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        );
+        tracer.send(&reply);
+        reply.respond_with(&mut tcp_stream).await?;
+        return tcp_stream.shutdown().await;
+    }
+    resolve_tracer(&tracer, &state, &flow_to(FlowProto::Tcp, client, &req.addr()));
+
+    let tasks = state.tasks.clone();
+    match req.cmd() {
+        Socks4Command::Connect => {
+            tasks.spawn(format!("SOCKS4 CONNECT from {}", client), async move {
+                impl_socks4_connect(&req.addr(), &mut tcp_stream, &dial_config, &tracer, &state)
+                    .await
+            });
+        }
+        Socks4Command::Bind => {
+            tasks.spawn(format!("SOCKS4 BIND from {}", client), async move {
+                impl_socks4_bind(&req.addr(), &mut tcp_stream, &tracer, &state).await
+            });
+        }
+    }
+    Ok(())
+}
+
+/// HTTP CONNECT, served on the SOCKS port with `--single-port`
+async fn handle_http<S: Accepted>(mut tcp_stream: S, ctx: ConnContext) -> std::io::Result<()> {
+    let ConnContext { dial_config, tracer, state, deadline, authenticated, .. } = ctx;
+    let (req_addr, credentials) =
+        match with_deadline(deadline, crate::http::read_connect(&mut tcp_stream)).await {
+            Ok(req) => req,
+            Err(e) => {
+                state.metrics.inc_handshake_failures();
+                let (status, reason) = crate::http::status_of_request_error(&e);
+                crate::http::respond(&mut tcp_stream, status, reason).await?;
+                tcp_stream.shutdown().await?;
+                return Err(e);
+            }
+        };
+    seeval!(&req_addr);
+    let client = tcp_stream.peer_addr()?;
+    let user = match credentials {
+        _ if authenticated.is_some() => authenticated,
+        Some((name, password)) if state.users.verify(&name, &password).await => Some(name),
+        _ if state.users.required_from(client.ip()) => {
+            state.metrics.inc_auth_failures();
+            crate::http::respond(&mut tcp_stream, 407, "Proxy Authentication Required").await?;
+            return tcp_stream.shutdown().await;
+        }
+        _ => None,
+    };
+    if let Some(rule) = user.as_deref().and_then(|user| state.users.blocked_by(user, &req_addr)) {
+        let e = state.block(client, &req_addr, &rule);
+        let (status, reason) = crate::http::status_of_dial_error(&e);
+        crate::http::respond(&mut tcp_stream, status, reason).await?;
+        return tcp_stream.shutdown().await;
+    }
+    let limiter = user.as_deref().and_then(|user| state.users.limiter(user));
+    resolve_tracer(&tracer, &state, &flow_to(FlowProto::Tcp, client, &req_addr));
+
+    state.tasks.clone().spawn(format!("HTTP CONNECT from {}", client), async move {
+        match dial(&req_addr, tcp_stream.socket(), &dial_config, &state).await {
+            Ok(mut proxy_tcp_stream) => {
+                crate::http::respond(&mut tcp_stream, 200, "Connection Established").await?;
+                relay_established(
+                    &req_addr,
+                    "CONNECT",
+                    &mut proxy_tcp_stream,
+                    &mut tcp_stream,
+                    limiter.as_deref(),
+                    &state,
+                )
+                .await
+            }
+            Err(e) => {
+                let (status, reason) = crate::http::status_of_dial_error(&e);
+                crate::http::respond(&mut tcp_stream, status, reason).await?;
+                tcp_stream.shutdown().await
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Resume a CONNECT session taken over from the previous process
+async fn resume_session(
+    session: Session,
+    client: std::net::TcpStream,
+    upstream: std::net::TcpStream,
+    state: Arc<AppState>,
+) -> std::io::Result<()> {
+    client.set_nonblocking(true)?;
+    upstream.set_nonblocking(true)?;
+    let mut tcp_stream = TcpStream::from_std(client)?;
+    let mut proxy_tcp_stream = TcpStream::from_std(upstream)?;
+    let session_id = session.id;
+    state.sessions.restore(session);
+    /* The user, and so the rate cap, of the session is not handed over */
+    if relay_session(&state, session_id, &mut proxy_tcp_stream, &mut tcp_stream, None).await? {
+        return Ok(());
     }
     tcp_stream.shutdown().await?;
     Ok(())
 }
 
-async fn impl_udp_associate(
-    tellreq_addr: &SocketAddr,
-    tcp_stream: &mut TcpStream,
+/// Where to send a datagram for `addr`, domain names are resolved and the
+/// address family of `udp_sock` preferred
+async fn udp_destination(addr: &Address, udp_sock: &UdpSocket) -> std::io::Result<SocketAddr> {
+    let resolved = match addr {
+        Address::IP(socket_addr) => return Ok(*socket_addr),
+        Address::Domain(..) => lookup_host(addr.to_string()).await?.collect::<Vec<_>>(),
+    };
+    let is_ipv4 = udp_sock.local_addr()?.is_ipv4();
+    resolved
+        .iter()
+        .find(|socket_addr| socket_addr.is_ipv4() == is_ipv4)
+        .or(resolved.first())
+        .copied()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No address resolved"))
+}
+
+/// The key in the UDP socket pool of the sockets `dial_config` binds
+fn udp_pool_key(dial_config: &DialConfig) -> UdpPoolKey {
+    match &dial_config.bind {
+        Some(nstream_core::OutboundBind::Interface(name)) => {
+            UdpPoolKey { interface: Some(name.as_str().to_owned()), ..UdpPoolKey::any(false) }
+        }
+        Some(nstream_core::OutboundBind::Address(ip)) => {
+            UdpPoolKey { interface: None, local_ip: *ip }
+        }
+        None => UdpPoolKey::any(false),
+    }
+}
+
+/// The socket pair of a UDP association, kept for the whole association and
+/// counted in the metrics until it is released
+struct UdpAssociation<'a> {
+    /// Where the client sends its datagrams
+    client_side: UdpSocket,
+    /// Where datagrams to and from destinations go, leased from the pool
+    remote_side: PooledUdpSocket,
+    metrics: &'a Metrics,
+}
+
+impl<'a> UdpAssociation<'a> {
+    fn new((client_side, remote_side): (UdpSocket, PooledUdpSocket), metrics: &'a Metrics) -> Self {
+        metrics.inc_udp_associations();
+        Self { client_side, remote_side, metrics }
+    }
+
+    /// Release the port of the client and give the remote socket back
+    /// right away, instead of once the control connection is shut down
+    fn close(self) {
+        drop(self)
+    }
+}
+
+impl Drop for UdpAssociation<'_> {
+    /* Also when the task is aborted, e.g. past the drain timeout */
+    fn drop(&mut self) {
+        self.metrics.dec_udp_associations();
+    }
+}
+
+/// `tellreq_addr` is where the client will send its datagrams from, each
+/// datagram names its own destination. `user` is the name the client
+/// authenticated with, if any.
+async fn impl_udp_associate<S: Accepted>(
+    tellreq_addr: &Address,
+    tcp_stream: &mut S,
+    dial_config: &DialConfig,
+    tracer: &Tracer,
+    user: Option<&str>,
+    state: &AppState,
 ) -> std::io::Result<()> {
+    let control_addr = tcp_stream.peer_addr()?;
+    if !state.users.allows_udp(user, control_addr.ip()) {
+        state.log.push(format!("Refused UDP ASSOCIATE from {}", control_addr));
+        let rep_resp = ReplyResponse::failed(ReplyField::ConnectionNotAllowedByRuleSet);
+        tracer.send(&rep_resp);
+        rep_resp.respond_with(tcp_stream).await?;
+        return tcp_stream.shutdown().await;
+    }
     let listen_ip = tcp_stream.local_addr()?.ip();
-    let (from_udp_sock, to_udp_sock) = UdpPacket::new_exchange(listen_ip).await?;
-    let connect_ret = (&to_udp_sock).connect(tellreq_addr).await;
-    let rep: ReplyField = (&connect_ret).into();
+    let mut client = ExpectedClient::new(tellreq_addr, control_addr.ip(), state.udp_client_match());
+    seeval!(&client);
+    let buffers = state.udp_buffers();
+    let client_side = UdpSocket::bind(SocketAddr::new(listen_ip, 0)).await?;
+    buffers.apply_to_udp(&client_side)?;
+    let remote_side = state.udp_pool.acquire_with(udp_pool_key(dial_config), || {
+        let udp_sock = dial_config.udp_socket()?;
+        buffers.apply_to_udp(&udp_sock)?;
+        Ok(udp_sock)
+    })?;
+    let association = UdpAssociation::new((client_side, remote_side), &state.metrics);
+    let (from_udp_sock, to_udp_sock) = (&association.client_side, &association.remote_side);
+    /* Datagrams too long for a socket are dropped, not relayed in pieces */
+    let (client_max, remote_max) =
+        (buffers.max_payload(from_udp_sock)?, buffers.max_payload(to_udp_sock)?);
+    let mut client_buf = vec![0u8; buffers.datagram_size + MAX_UDP_HEAD_LEN];
+    let mut back_buf = vec![0u8; buffers.datagram_size];
+    /* Printed for the first only, the others are counted */
+    let oversize_logged = AtomicBool::new(false);
+    let mut country_logged = HashSet::new();
 
-    let rep_resp = ReplyResponse::new(rep, from_udp_sock.local_addr()?.into());
+    let rep_resp = ReplyResponse::succeeded(from_udp_sock.local_addr()?);
+    tracer.send(&rep_resp);
     rep_resp.respond_with(tcp_stream).await?;
 
-    let mut udp_associate_ret = Ok(());
     let incoming_addr = Arc::new(Mutex::new(from_udp_sock.local_addr()?));
+    let (mut bytes_sent, mut bytes_received) = (0u64, 0u64);
 
-    if rep_resp.rep() == ReplyField::Succeeded {
-        let _ret = loop {
-            tokio::select! {
-                _ret = async {
-                    let (udp_req, from_addr) = UdpPacket::from(&from_udp_sock).await?;
-                    *incoming_addr.lock().await = from_addr;
-
-                    let send_data = udp_req.data();
-                    seeval!(&send_data);
-                    println!("String(send_data) >>> {}", String::from_utf8_lossy(&send_data));
-                    (&to_udp_sock).send(&send_data).await?;
-                    Ok::<_, std::io::Error>(())
-                } => {
-                    if _ret.is_err() {
-                        break _ret;
+    let session_id =
+        state.sessions.open(control_addr, String::from("*"), "UDP ASSOCIATE", "direct");
+    state.log.push(format!("UDP ASSOCIATE {}", control_addr));
+    let sockets = vec![from_udp_sock.local_addr()?, to_udp_sock.local_addr()?];
+    let killed = state.conntrack.track(control_addr, session_id, Protocol::Udp, sockets);
+    /* The trace filter selects datagrams by their destination */
+    let traced = |dst: &Address| {
+        tracer.enabled()
+            && state
+                .trace_filter()
+                .is_none_or(|filter| filter.may_match(&flow_to(FlowProto::Udp, control_addr, dst)))
+    };
+    /* Created once, so that it cancels the association whichever
+     * datagram is in flight when the control connection ends */
+    let mut cancelled = Box::pin(async {
+        tokio::select! {
+            _ = wait_closed(tcp_stream) => {}
+            _ = killed.notified() => {}
+        }
+    });
+    let ret = loop {
+        tokio::select! {
+            _ret = async {
+                let (udp_req, from_addr) = UdpPacket::from_client_in(from_udp_sock, &mut client, &mut client_buf, |e, from_addr| {
+                    /* Counted, a stray sender printing each one would flood the log */
+                    state.metrics.inc_udp_dropped();
+                    trace_println!("Dropped datagram from {}; error: {}", from_addr, e);
+                    ControlFlow::Continue(())
+                }).await?;
+                if traced(&udp_req.addr()) {
+                    tracer.recv(&udp_req);
+                }
+                *incoming_addr.lock().await = from_addr;
+
+                let send_data = udp_req.data();
+                trace_println!(
+                    "UDP {} -> {} >>> {}",
+                    from_addr,
+                    udp_req.addr().to_string(),
+                    String::from_utf8_lossy(&send_data)
+                );
+                /* DNS queries are answered with fake IPs right away */
+                let started = Instant::now();
+                let fake_answer = state
+                    .fake_ip()
+                    .filter(|_| udp_req.addr().port() == 53)
+                    .and_then(|fake_ip| fake_ip.answer_query(&send_data));
+                if let Some(answer) = fake_answer {
+                    state.log_dns(DnsQuery::new(
+                        &answer.domain,
+                        from_addr,
+                        Resolver::FakeIp,
+                        Ok(answer.ip.into_iter().collect()),
+                        started.elapsed(),
+                    ));
+                    let udp_resp = UdpPacket::new(0, udp_req.addr(), answer.response);
+                    if traced(&udp_resp.addr()) {
+                        tracer.send(&udp_resp);
                     }
-                },
-                _ret = async {
-                    let mut back_data = [0u8; u16::MAX as usize];
-                    let len = (&to_udp_sock).recv(&mut back_data).await?;
-                    let back_data = &back_data[..len];
-                    seeval!(back_data);
-                    println!("String(back_data) >>> {}", String::from_utf8_lossy(back_data));
-
-                    let from_addr = *incoming_addr.lock().await;
-
-                    let udp_resp = UdpPacket::new(0, tellreq_addr.clone().into(), back_data.to_vec());
-                    let udp_resp_bytes = udp_resp.as_socks_bytes();
-                    seeval!(udp_resp_bytes);
-                    println!();
-
-                    from_udp_sock.send_to(&udp_resp_bytes, from_addr).await?;
-                    Ok::<_, std::io::Error>(())
-                }  => {
-                    if _ret.is_err() {
-                        break _ret;
+                    from_udp_sock.send_to(&udp_resp.as_socks_bytes()?, from_addr).await?;
+                    return Ok(());
+                }
+                if send_data.len() > remote_max {
+                    state.metrics.inc_udp_dropped();
+                    if !oversize_logged.swap(true, Ordering::Relaxed) {
+                        eprintln!(
+                            "Dropped datagram to {}; error: {} bytes, past {}",
+                            udp_req.addr().to_string(),
+                            send_data.len(),
+                            remote_max
+                        );
                     }
-                },
-                _ = wait_closed(tcp_stream) => {
-                    break Ok::<_, std::io::Error>(())
+                    return Ok(());
                 }
-            };
+                let to_addr = udp_destination(&state.unfake(&udp_req.addr()), to_udp_sock).await;
+                /* Not even PROXY ones, there is no outbound for them */
+                let country_rule = to_addr.as_ref().ok().and_then(|to_addr| {
+                    let rule = state.country_policy()?.rule_for([to_addr.ip()])?;
+                    Some((to_addr.ip(), rule))
+                });
+                if let Some((ip, rule)) = country_rule {
+                    state.metrics.inc_country_rule(&rule);
+                    state.metrics.inc_udp_dropped();
+                    /* Counted by the rule metrics, printed once for each destination */
+                    if country_logged.insert(ip) {
+                        eprintln!("Dropped datagram to {}; error: {}", udp_req.addr().to_string(), rule);
+                    }
+                    return Ok(());
+                }
+                match to_addr {
+                    Ok(to_addr) => {
+                        let len = to_udp_sock.send_to_peer(&send_data, to_addr).await? as u64;
+                        bytes_sent += len;
+                        state.conntrack.touch(&control_addr, len, 0);
+                        state.sessions.relayed(session_id, len, 0);
+                    }
+                    Err(e) => {
+                        state.metrics.inc_udp_dropped();
+                        eprintln!("Dropped datagram to {}; error: {:?}", udp_req.addr().to_string(), e);
+                    }
+                }
+                Ok::<_, std::io::Error>(())
+            } => {
+                if _ret.is_err() {
+                    break _ret;
+                }
+            },
+            _ret = async {
+                /* The socket may have been leased before, and anyone may
+                 * send to it, only replies of destinations are relayed */
+                let (len, back_addr) = to_udp_sock
+                    .recv_from_peer(&mut back_buf, |_| state.metrics.inc_udp_dropped())
+                    .await?;
+                let back_data = &back_buf[..len];
+                bytes_received += len as u64;
+                state.conntrack.touch(&control_addr, 0, len as u64);
+                state.sessions.relayed(session_id, 0, len as u64);
+
+                let from_addr = *incoming_addr.lock().await;
+                trace_println!(
+                    "UDP {} <- {} >>> {}",
+                    from_addr,
+                    back_addr,
+                    String::from_utf8_lossy(back_data)
+                );
+
+                let udp_resp = UdpPacket::new(0, back_addr.into(), back_data.to_vec());
+                if traced(&udp_resp.addr()) {
+                    tracer.send(&udp_resp);
+                }
+                let udp_resp_bytes = udp_resp.as_socks_bytes()?;
+                if udp_resp_bytes.len() > client_max {
+                    state.metrics.inc_udp_dropped();
+                    if !oversize_logged.swap(true, Ordering::Relaxed) {
+                        eprintln!(
+                            "Dropped datagram from {}; error: {} bytes with its header, past {}",
+                            back_addr,
+                            udp_resp_bytes.len(),
+                            client_max
+                        );
+                    }
+                    return Ok(());
+                }
+
+                from_udp_sock.send_to(&udp_resp_bytes, from_addr).await?;
+                Ok::<_, std::io::Error>(())
+            }  => {
+                if _ret.is_err() {
+                    break _ret;
+                }
+            },
+            _ = &mut cancelled => {
+                break Ok::<_, std::io::Error>(())
+            }
+        };
+    };
+    association.close();
+    state.conntrack.untrack(&control_addr);
+    state.sessions.close(session_id, bytes_sent, bytes_received);
+
+    /* Borrowing the control connection, which is shut down next */
+    drop(cancelled);
+    tcp_stream.shutdown().await?;
+    ret
+}
+
+async fn handle_socks5<S: Accepted>(mut tcp_stream: S, ctx: ConnContext) -> std::io::Result<()> {
+    let ConnContext { dial_config, tracer, state, deadline, greeting_deadline, authenticated } =
+        ctx;
+    let hreq = match with_deadline(greeting_deadline, HandshakeRequest::from(&mut tcp_stream)).await
+    {
+        Ok(hreq) => hreq,
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            /* A greeting without any method, none of them can be acceptable */
+            state.metrics.inc_handshake_failures();
+            let hresp = HandshakeResponse::new(AuthMethod::NoAcceptableMethods);
+            tracer.send(&hresp);
+            hresp.write_to(&mut tcp_stream).await?;
+            tcp_stream.shutdown().await?;
+            return Err(e);
+        }
+        Err(e) => {
+            state.metrics.inc_handshake_failures();
+            return Err(e);
+        }
+    };
+    seeval!(&hreq);
+    tracer.recv(&hreq);
+    let client = tcp_stream.peer_addr()?;
+    /* A trusted client may still log in, to be given the policy of a user */
+    let methods: &[AuthMethod] = match (state.users.required(), state.users.trusts(client.ip())) {
+        _ if authenticated.is_some() => &[AuthMethod::NoAuthenticationRequired],
+        (true, false) => &[AuthMethod::UsernameOrPassword],
+        (true, true) => &[AuthMethod::NoAuthenticationRequired, AuthMethod::UsernameOrPassword],
+        (false, _) => &[AuthMethod::NoAuthenticationRequired],
+    };
+    let hresp = HandshakeResponse::new(hreq.select_method(methods));
+    seeval!(&hresp);
+    tracer.send(&hresp);
+    if let Err(e) = hresp.write_to(&mut tcp_stream).await {
+        eprintln!("Failed to write handshake response; error: {:?}", e);
+    }
+    if hresp.method() == AuthMethod::NoAcceptableMethods {
+        state.metrics.inc_auth_failures();
+        tcp_stream.shutdown().await?;
+        return Ok(());
+    }
+    let mut user = authenticated;
+    if hresp.method() == AuthMethod::UsernameOrPassword {
+        let auth = with_deadline(deadline, UsernamePasswordAuth::from(&mut tcp_stream))
+            .await
+            .inspect_err(|_| {
+                state.metrics.inc_handshake_failures();
+            })?;
+        tracer.recv(&auth);
+        let auth_ret = match state.users.verify(&auth.uname(), &auth.passwd()).await {
+            true => UsernamePasswordAuthResult::Succeeded,
+            false => UsernamePasswordAuthResult::Failure,
         };
-        if let err @ Err(_) = _ret {
-            udp_associate_ret = err
+        tracer.send(&auth_ret);
+        tcp_stream.write_all(&auth_ret.as_bytes()).await?;
+        if auth_ret == UsernamePasswordAuthResult::Failure {
+            state.metrics.inc_auth_failures();
+            tcp_stream.shutdown().await?;
+            return Ok(());
         }
+        user = Some(auth.uname());
     }
 
-    tcp_stream.shutdown().await?;
-    udp_associate_ret
+    let tellreq = match with_deadline(deadline, TellRequest::from(&mut tcp_stream)).await {
+        Ok(tellreq) => tellreq,
+        Err(e) => {
+            state.metrics.inc_handshake_failures();
+            /* An unknown CMD or ATYP is answered as RFC 1928 asks */
+            if let Some(rep) = Socks5Error::of(&e).and_then(Socks5Error::reply_field) {
+                let rep_resp = ReplyResponse::failed(rep);
+                tracer.send(&rep_resp);
+                rep_resp.respond_with(&mut tcp_stream).await?;
+                tcp_stream.shutdown().await?;
+            }
+            return Err(e);
+        }
+    };
+    seeval!(&tellreq);
+    tracer.recv(&tellreq);
+    let flow = match tellreq.cmd() {
+        /* The request names the client, each datagram its destination */
+        Command::UdpAssociate => Flow::new(FlowProto::Udp, client),
+        Command::Connect | Command::Bind => flow_to(FlowProto::Tcp, client, &tellreq.addr()),
+    };
+    resolve_tracer(&tracer, &state, &flow);
+
+    seeval!(&tcp_stream);
+
+    let tasks = state.tasks.clone();
+    let label = format!("SOCKS5 {:?} from {}", tellreq.cmd(), client);
+    match tellreq.cmd() {
+        Command::Connect => {
+            tasks.spawn(label, async move {
+                impl_connect(
+                    &tellreq.addr(),
+                    &mut tcp_stream,
+                    &dial_config,
+                    &tracer,
+                    user.as_deref(),
+                    &state,
+                )
+                .await
+            });
+        }
+        Command::UdpAssociate => {
+            tasks.spawn(label, async move {
+                impl_udp_associate(
+                    &tellreq.addr(),
+                    &mut tcp_stream,
+                    &dial_config,
+                    &tracer,
+                    user.as_deref(),
+                    &state,
+                )
+                .await
+            });
+        }
+        Command::Bind => {
+            tasks.spawn(label, async move {
+                let rep_resp = ReplyResponse::failed(ReplyField::CommandNotSupported);
+                tracer.send(&rep_resp);
+                rep_resp.respond_with(&mut tcp_stream).await?;
+                tcp_stream.shutdown().await?;
+                Ok::<_, std::io::Error>(())
+            });
+        }
+    }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    tokio::spawn(async { register_graceful_shutdown().await });
+/// Handed to the protocol handlers along with each accepted connection
+#[derive(Clone)]
+struct ConnContext {
+    dial_config: DialConfig,
+    tracer: Tracer,
+    state: Arc<AppState>,
+    /// Until when the client may take to send its request
+    deadline: Instant,
+    /// Until when it may take to send its first byte and SOCKS5 greeting
+    greeting_deadline: Instant,
+    /// The user the client was authenticated as before the SOCKS handshake,
+    /// which asks for no credentials then
+    authenticated: Option<String>,
+}
 
-    let usr = Arc::new(random_string::generate(10, charset::BASE62));
-    let pwd = Arc::new(random_string::generate(10, charset::BASE62));
+/// Experimental, the request is only parsed and logged
+#[cfg(feature = "socks6")]
+async fn handle_socks6<S: Accepted>(mut tcp_stream: S, ctx: ConnContext) -> std::io::Result<()> {
+    let req = with_deadline(ctx.deadline, socks5::socks6::Socks6Request::from(&mut tcp_stream))
+        .await
+        .inspect_err(|_| {
+            ctx.state.metrics.inc_handshake_failures();
+        })?;
+    seeval!(&req);
+    tcp_stream.shutdown().await
+}
 
-    let my_extip_v6addr = what_is_my_extip_v6addr().await?;
-    seeval!(my_extip_v6addr);
-    let my_extip_v4addr = what_is_my_extip_v4addr().await?;
-    seeval!(my_extip_v4addr);
+/// `single_port` also serves HTTP CONNECT, told apart by its first byte,
+/// which must come within `greeting_timeout`
+fn version_dispatcher<S>(
+    greeting_timeout: Duration,
+    single_port: bool,
+) -> Dispatcher<ConnContext, S>
+where
+    S: Accepted + Peek,
+{
+    let mut dispatcher = Dispatcher::new();
+    dispatcher
+        .peek_timeout(greeting_timeout)
+        .register(SOCKS_VERSION, handle_socks5::<S>)
+        .register(SOCKS4_VERSION, handle_socks4::<S>)
+        .fallback(|_, ctx: ConnContext| async move {
+            ctx.state.metrics.inc_handshake_failures();
+            Ok(())
+        });
+    #[cfg(feature = "socks6")]
+    dispatcher.register(socks5::socks6::SOCKS6_VERSION, handle_socks6::<S>);
+    if single_port {
+        for first_byte in crate::http::FIRST_BYTES {
+            dispatcher.register(first_byte, handle_http::<S>);
+        }
+    }
+    dispatcher
+}
 
-    let my_lanip_v6addr =
-        what_is_my_lanip_v6addr().await.unwrap_or(Ipv6Addr::LOCALHOST.to_string());
-    seeval!(my_lanip_v6addr);
-    let my_lanip_v4addr =
-        what_is_my_lanip_v4addr().await.unwrap_or(Ipv4Addr::LOCALHOST.to_string());
-    seeval!(my_lanip_v4addr);
+/// The dispatchers of the connections accepted, by what they are carried
+/// over, the handlers being the same
+struct Dispatchers {
+    tcp: Dispatcher<ConnContext>,
+    /// Of the listeners with `[tls]`
+    tls: Dispatcher<ConnContext, Peekable<TlsStream>>,
+    #[cfg(feature = "tls-psk")]
+    tls_psk: Dispatcher<ConnContext, Peekable<crate::tls_psk::TlsStream>>,
+}
 
-    crate::cmd::close_socks5_proxy()?;
+impl Dispatchers {
+    fn new(greeting_timeout: Duration, single_port: bool) -> Self {
+        Self {
+            tcp: version_dispatcher(greeting_timeout, single_port),
+            tls: version_dispatcher(greeting_timeout, single_port),
+            #[cfg(feature = "tls-psk")]
+            tls_psk: version_dispatcher(greeting_timeout, single_port),
+        }
+    }
+}
 
-    let socks5_proxy_bind_addr =
-        SocketAddr::V6(SocketAddrV6::new((&my_lanip_v6addr).parse::<Ipv6Addr>().unwrap(), 0, 0, 0));
-    let tcp_listener = TcpListener::bind(socks5_proxy_bind_addr).await?;
-    let socks5_proxy_bind_addr = tcp_listener.local_addr()?;
-    crate::cmd::open_socks5_proxy(socks5_proxy_bind_addr, &usr, &pwd)?;
-    let vtun = VTun::new();
-    let vtun_config = VTunConfig {
-        mtu: Some(2000),
-        ipv4_addr: Some(Ipv4Addr::new(192, 168, 31, u8::MAX - 1)),
-        ipv6_addr: Some(format!("::ffff:192.168.31.{}", u8::MAX - 1).parse::<Ipv6Addr>().unwrap()),
-        netmask: Some(0xffffff00),
-    };
-    vtun.config_with(vtun_config)?;
-    seeval!(vtun.ifname());
-    seeval!(vtun.ifindex());
-    seeval!(vtun.mtu());
+/// `tls` terminates TLS on the connections accepted before they are
+/// dispatched
+async fn accept_loop(
+    tcp_listener: TcpListener,
+    tls: Option<TlsTerminator>,
+    sockopts: SocketOptions,
+    dial_config: DialConfig,
+    dispatchers: Arc<Dispatchers>,
+    state: Arc<AppState>,
+) {
+    let mut handoff = state.handoff_signal();
+    let mut draining = state.draining_signal();
+    loop {
+        let (tcp_stream, peer_addr) = tokio::select! {
+            ret = tcp_listener.accept() => match ret {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = handoff.wait_for(|handing_off| *handing_off) => break,
+            _ = draining.wait_for(|draining| *draining) => break,
+        };
+        if !state.knocked(peer_addr.ip()) {
+            state.metrics.inc_knock_refused();
+            tcp_stream.abort();
+            continue;
+        }
+        let Some(handshake_slot) = state.handshake_slot() else {
+            state.metrics.inc_handshakes_shed();
+            tcp_stream.abort();
+            continue;
+        };
+        state.metrics.inc_connections();
+        let tracer = tracer_for(peer_addr, &state);
+        if let Err(e) = sockopts.apply_to_stream(&tcp_stream) {
+            eprintln!("Failed to apply socket options to {}; error: {:?}", peer_addr, e);
+        }
 
-    while let Ok((mut tcp_stream, _)) = tcp_listener.accept().await {
-        let _usr = usr.clone();
-        let _pwd = pwd.clone();
+        let accepted_at = Instant::now();
+        let ctx = ConnContext {
+            dial_config,
+            tracer,
+            state: state.clone(),
+            deadline: accepted_at + state.handshake_timeout(),
+            greeting_deadline: accepted_at + state.greeting_timeout(),
+            authenticated: None,
+        };
+        let dispatchers = dispatchers.clone();
+        let tls = tls.clone();
+        state.tasks.spawn(format!("Connection from {}", peer_addr), async move {
+            /* Over once the request is read, the relay runs in a task of its own */
+            let _handshake_slot = handshake_slot;
+            let Some(tls) = tls else {
+                return dispatchers.tcp.dispatch(tcp_stream, ctx).await;
+            };
+            let tls_stream = match with_deadline(ctx.deadline, tls.accept(tcp_stream)).await {
+                Ok(tls_stream) => tls_stream,
+                Err(e) => {
+                    ctx.state.metrics.inc_handshake_failures();
+                    return Err(e);
+                }
+            };
+            dispatchers.tls.dispatch(Peekable::new(tls_stream), ctx).await
+        });
+    }
+}
 
-        tokio::spawn(async move {
-            let hreq = HandshakeRequest::from(&mut tcp_stream).await?;
-            seeval!(&hreq);
-            if hreq.methods().contains(&AuthMethod::NoAuthenticationRequired) {
-                // seeval!(hreq);
+/// Accept the devices of `[tls_psk]`, whose decrypted streams are
+/// dispatched as if accepted by [accept_loop], as the user of their identity
+#[cfg(feature = "tls-psk")]
+async fn tls_psk_accept_loop(
+    tcp_listener: TcpListener,
+    acceptor: crate::tls_psk::TlsPskAcceptor,
+    sockopts: SocketOptions,
+    dial_config: DialConfig,
+    dispatchers: Arc<Dispatchers>,
+    state: Arc<AppState>,
+) {
+    let mut handoff = state.handoff_signal();
+    let mut draining = state.draining_signal();
+    loop {
+        let (tcp_stream, peer_addr) = tokio::select! {
+            ret = tcp_listener.accept() => match ret {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = handoff.wait_for(|handing_off| *handing_off) => break,
+            _ = draining.wait_for(|draining| *draining) => break,
+        };
+        if !state.knocked(peer_addr.ip()) {
+            state.metrics.inc_knock_refused();
+            tcp_stream.abort();
+            continue;
+        }
+        let Some(handshake_slot) = state.handshake_slot() else {
+            state.metrics.inc_handshakes_shed();
+            tcp_stream.abort();
+            continue;
+        };
+        state.metrics.inc_connections();
+        if let Err(e) = sockopts.apply_to_stream(&tcp_stream) {
+            eprintln!("Failed to apply socket options to {}; error: {:?}", peer_addr, e);
+        }
+        let deadline = Instant::now() + state.handshake_timeout();
+        let greeting_deadline = Instant::now() + state.greeting_timeout();
+        let acceptor = acceptor.clone();
+        let dispatchers = dispatchers.clone();
+        let state = state.clone();
+        state.clone().tasks.spawn(format!("TLS-PSK connection from {}", peer_addr), async move {
+            let _handshake_slot = handshake_slot;
+            let (tls_stream, user) =
+                match with_deadline(deadline, acceptor.accept(tcp_stream)).await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        state.metrics.inc_handshake_failures();
+                        return Err(e);
+                    }
+                };
+            println!("TLS-PSK device from {} is {}", peer_addr, user);
+            let tracer = tracer_for(peer_addr, &state);
+            let ctx = ConnContext {
+                dial_config,
+                tracer,
+                state,
+                deadline,
+                greeting_deadline,
+                authenticated: Some(user),
+            };
+            dispatchers.tls_psk.dispatch(Peekable::new(tls_stream), ctx).await
+        });
+    }
+}
+
+/// Listen for the devices of `section` until shutdown
+#[cfg(feature = "tls-psk")]
+fn start_tls_psk(
+    section: &crate::config::TlsPskSection,
+    sockopts: SocketOptions,
+    dial_config: DialConfig,
+    dispatchers: Arc<Dispatchers>,
+    state: Arc<AppState>,
+) -> std::io::Result<()> {
+    let acceptor = crate::tls_psk::TlsPskAcceptor::new(section)?;
+    let tcp_listener = sockopts.bind_listener(section.listen)?;
+    println!(
+        "TLS-PSK listener on {} for {} devices",
+        tcp_listener.local_addr()?,
+        section.identities.len()
+    );
+    tokio::spawn(tls_psk_accept_loop(
+        tcp_listener,
+        acceptor,
+        sockopts,
+        dial_config,
+        dispatchers,
+        state,
+    ));
+    Ok(())
+}
+
+/// Refused rather than ignored, the devices would find nothing listening
+#[cfg(not(feature = "tls-psk"))]
+fn start_tls_psk(
+    _section: &crate::config::TlsPskSection,
+    _sockopts: SocketOptions,
+    _dial_config: DialConfig,
+    _dispatchers: Arc<Dispatchers>,
+    _state: Arc<AppState>,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "[tls_psk] is configured, this build is without the tls-psk feature",
+    ))
+}
+
+/// Bind the IPv6 and IPv4 listeners, sharing the same port when possible
+fn bind_dual_stack(
+    sockopts: &SocketOptions,
+    lanip_v6addr: Ipv6Addr,
+    lanip_v4addr: Ipv4Addr,
+) -> std::io::Result<Vec<TcpListener>> {
+    let mut tcp_listeners = vec![];
+    let mut port = 0;
+    match sockopts.bind_listener(SocketAddr::V6(SocketAddrV6::new(lanip_v6addr, 0, 0, 0))) {
+        Ok(tcp_listener) => {
+            port = tcp_listener.local_addr()?.port();
+            tcp_listeners.push(tcp_listener);
+        }
+        Err(e) => eprintln!("Failed to bind IPv6 listener on {}; error: {:?}", lanip_v6addr, e),
+    }
+    let v4_bind_ret = sockopts
+        .bind_listener(SocketAddr::V4(SocketAddrV4::new(lanip_v4addr, port)))
+        .or_else(|_| sockopts.bind_listener(SocketAddr::V4(SocketAddrV4::new(lanip_v4addr, 0))));
+    match v4_bind_ret {
+        Ok(tcp_listener) => tcp_listeners.push(tcp_listener),
+        Err(e) if tcp_listeners.is_empty() => return Err(e),
+        Err(e) => eprintln!("Failed to bind IPv4 listener on {}; error: {:?}", lanip_v4addr, e),
+    }
+    Ok(tcp_listeners)
+}
+
+/// The `[admin]` of the configuration file `args` point at, which the
+/// subcommands talking to the running instance go through
+fn admin_config(args: &Args) -> Result<AdminConfig, Box<dyn Error>> {
+    let config = Config::load(args.config.as_deref(), &args.overrides)?;
+    let admin_config = config.admin.ok_or_else(|| {
+        std::io::Error::new(ErrorKind::NotFound, "No [admin] in the configuration file")
+    })?;
+    Ok(admin_config)
+}
+
+/// `nstream ip`, the addresses of this host one per line, `-` for those
+/// that could not be found
+async fn print_addresses() {
+    let addrs = discover_addresses(ADDRESS_DISCOVERY_TIMEOUT).await;
+    let or_dash = |ip: Option<IpAddr>| ip.map_or_else(|| String::from("-"), |ip| ip.to_string());
+    println!("LAN IPv4       {}", or_dash(addrs.lan_v4.map(IpAddr::V4)));
+    println!("LAN IPv6       {}", or_dash(addrs.lan_v6.map(IpAddr::V6)));
+    println!("External IPv4  {}", or_dash(addrs.ext_v4.map(|addr| addr.ip())));
+    println!("External IPv6  {}", or_dash(addrs.ext_v6.map(|addr| addr.ip())));
+    match nstream_core::LanAddresses::enumerate() {
+        Ok(lan) => {
+            for addr in lan.addrs.iter() {
+                let ip = match addr.scope_id {
+                    0 => addr.ip.to_string(),
+                    _ => format!("{}%{}", addr.ip, addr.ifname),
+                };
+                println!("{:<14} {} ({})", addr.ifname, ip, addr.scope());
+            }
+        }
+        Err(e) => eprintln!("Listing the interface addresses failed; error: {:?}", e),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    crate::ops::set_dry_run(args.dry_run);
+    if args.daemon {
+        if !matches!(args.command, None | Some(Commands::Run) | Some(Commands::Share)) {
+            return Err("--daemon only runs the proxy in the background".into());
+        }
+        /* Before the runtime starts threads, which the child would not have */
+        crate::daemon::daemonize(args.log_file.as_deref())?;
+    }
+    tokio::runtime::Runtime::new()?.block_on(dispatch(args))
+}
+
+async fn dispatch(args: Args) -> Result<(), Box<dyn Error>> {
+    match &args.command {
+        Some(Commands::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&crate::schema::schema())?);
+            Ok(())
+        }
+        Some(Commands::Loadgen(loadgen_args)) => {
+            let report = crate::loadgen::run(loadgen_args).await?;
+            report.print();
+            std::process::exit(if report.failed() > 0 { 1 } else { 0 });
+        }
+        Some(Commands::Tail(tail_args)) => {
+            crate::tail::run(tail_args, &admin_config(&args)?).await?;
+            Ok(())
+        }
+        Some(Commands::Status) => {
+            crate::status::run(&admin_config(&args)?).await?;
+            Ok(())
+        }
+        Some(Commands::Check(check_args)) => {
+            let ok = crate::check::run(check_args, args.config.as_deref(), &args.overrides).await;
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        Some(Commands::Knock(knock_args)) => {
+            let config = Config::load(args.config.as_deref(), &args.overrides)?;
+            let knock = config.knock.ok_or_else(|| {
+                std::io::Error::new(ErrorKind::NotFound, "No [knock] in the configuration file")
+            })?;
+            send_knock(&knock, &knock_args.server).await?;
+            Ok(())
+        }
+        Some(Commands::Proxy(proxy_args)) => {
+            match &proxy_args.state {
+                ProxyState::On(on_args) => match (&on_args.pac, on_args.addr) {
+                    (Some(url), _) => crate::cmd::open_pac_proxy(url)?,
+                    (None, Some(addr)) => {
+                        let credentials = on_args.user.as_deref().zip(on_args.password.as_deref());
+                        crate::cmd::open_socks5_proxy(addr, credentials)?
+                    }
+                    (None, None) => unreachable!("ADDR is required without --pac"),
+                },
+                ProxyState::Off => {
+                    crate::cmd::close_pac_proxy()?;
+                    crate::cmd::close_socks5_proxy()?;
+                }
             }
-            let hresp = HandshakeResponse::new(AuthMethod::NoAuthenticationRequired);
-            seeval!(&hresp);
-            if let Err(e) = (&mut tcp_stream).write(&hresp.as_bytes()).await {
-                eprintln!("Failed to write handshake response; error: {:?}", e);
+            Ok(())
+        }
+        Some(Commands::Ip) => {
+            print_addresses().await;
+            Ok(())
+        }
+        Some(Commands::Stop) => {
+            let config = Config::load(args.config.as_deref(), &args.overrides)?;
+            let pidfile = args.pidfile.to_owned().unwrap_or_else(crate::daemon::default_pidfile);
+            crate::daemon::stop(&pidfile, &config).await?;
+            Ok(())
+        }
+        None | Some(Commands::Run) | Some(Commands::Share) => run(args).await,
+    }
+}
+
+/// Run the proxy until it is shut down
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    if args.trace && !cfg!(feature = "trace-log") {
+        eprintln!("--trace has no effect, this build is without the trace-log feature");
+    }
+    nstream_core::set_trace(args.trace);
+    let config = Config::load(args.config.as_deref(), &args.overrides)?;
+    let state =
+        Arc::new(AppState::new(args.config.to_owned(), args.overrides.to_owned(), &config)?);
+    let mut takeover = match args.upgrade_socket.to_owned() {
+        Some(path) => {
+            tokio::task::spawn_blocking(move || crate::upgrade::take_over(&path)).await??
+        }
+        None => None,
+    };
+    if !args.skip_preflight {
+        let report = crate::preflight::run(&config, takeover.is_some()).await;
+        report.print();
+        report.into_result()?;
+    }
+    let pidfile =
+        args.pidfile.to_owned().or_else(|| args.daemon.then(crate::daemon::default_pidfile));
+    if let Some(pidfile) = pidfile.as_ref() {
+        crate::daemon::write_pidfile(pidfile, takeover.is_some())?;
+    }
+    tokio::spawn(register_graceful_shutdown(state.clone(), config.pac.is_some(), pidfile.clone()));
+    tokio::spawn(crate::conntrack::expire_loop(state.clone()));
+    if let Some(admin_config) = config.admin.to_owned() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::admin::serve(admin_config, state).await {
+                eprintln!("Management API stopped; error: {:?}", e);
             }
+        });
+    }
 
-            let tellreq = TellRequest::from(&mut tcp_stream).await?;
-            seeval!(&tellreq);
-            let tellreq_addr = TryInto::<SocketAddr>::try_into(tellreq.addr())?;
-            seeval!(&tellreq_addr);
+    /* Only worth handing out when clients must authenticate, and accepted
+     * for the run then */
+    let credentials = match state.users.required() {
+        true => {
+            let usr = args
+                .user
+                .to_owned()
+                .unwrap_or_else(|| random_string::generate(10, charset::BASE62));
+            let pwd = random_string::generate(10, charset::BASE62);
+            state.users.add_for_run(&usr, &pwd)?;
+            Some((usr, pwd))
+        }
+        false => None,
+    };
+    let credentials = credentials.as_ref().map(|(usr, pwd)| (usr.as_str(), pwd.as_str()));
 
-            seeval!(&tcp_stream);
+    let my_addrs = crate::hints::discover_addresses_warm(
+        config.warm_start.as_ref(),
+        ADDRESS_DISCOVERY_TIMEOUT,
+    )
+    .await;
+    seeval!(my_addrs);
 
-            match tellreq.cmd() {
-                Command::Connect => {
-                    tokio::spawn(async move { impl_connect(&tellreq_addr, &mut tcp_stream).await });
-                }
-                Command::UdpAssociate => {
-                    tokio::spawn(async move {
-                        impl_udp_associate(&tellreq_addr, &mut tcp_stream).await
-                    });
+    let sockopts = config.socket.to_sockopts();
+    let dial_config = config.dial.to_dial_config(sockopts);
+    let tcp_listeners = match takeover.as_mut() {
+        Some(takeover) => {
+            println!("Took over from the previous process");
+            let mut tcp_listeners = vec![];
+            for tcp_listener in std::mem::take(&mut takeover.listeners) {
+                tcp_listener.set_nonblocking(true)?;
+                tcp_listeners.push(TcpListener::from_std(tcp_listener)?);
+            }
+            /* Nothing to listen on would leave the proxy without an address */
+            if tcp_listeners.is_empty() {
+                return Err(Box::new(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    "No listener handed over by the previous process",
+                )));
+            }
+            tcp_listeners
+        }
+        None => {
+            crate::cmd::close_socks5_proxy()?;
+            bind_dual_stack(
+                &sockopts,
+                my_addrs.lan_v6.unwrap_or(Ipv6Addr::LOCALHOST),
+                my_addrs.lan_v4.unwrap_or(Ipv4Addr::LOCALHOST),
+            )?
+        }
+    };
+    let mut listen_addrs = vec![];
+    for tcp_listener in tcp_listeners.iter() {
+        let listen_addr = tcp_listener.local_addr()?;
+        println!("Listening on socks5://{}", listen_addr);
+        if args.single_port {
+            println!("Listening on http://{}", listen_addr);
+        }
+        if matches!(args.command, Some(Commands::Share)) {
+            crate::share::print_share(&crate::share::socks5_uri(&listen_addr, credentials));
+        }
+        listen_addrs.push(listen_addr);
+        if args.upgrade_socket.is_some() {
+            state.register_listener(listen_addr, tcp_listener.as_fd().try_clone_to_owned()?);
+        }
+    }
+    if let Some(takeover) = takeover {
+        state.sessions.restore_traffic(takeover.traffic);
+        for (session, client, upstream) in takeover.sessions {
+            let label = format!("Resumed session {}", session.id);
+            state.tasks.spawn(label, resume_session(session, client, upstream, state.clone()));
+        }
+    }
+    if let Some(path) = args.upgrade_socket.to_owned() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            match crate::upgrade::serve_handoff(path, state.clone()).await {
+                Ok(()) => {
+                    destroy_vtun(&state);
+                    std::process::exit(0)
                 }
-                Command::Bind => {
-                    tokio::spawn(async move {
-                        let rep_resp =
-                            ReplyResponse::new(ReplyField::CommandNotSupported, Address::default());
-                        rep_resp.respond_with(&mut tcp_stream).await?;
-                        tcp_stream.shutdown().await?;
-                        Ok::<_, std::io::Error>(())
-                    });
+                Err(e) => {
+                    eprintln!("Hot upgrade failed; error: {:?}", e);
+                    if state.handing_off() {
+                        std::process::exit(1)
+                    }
                 }
             }
-            Ok::<_, std::io::Error>(())
         });
     }
+    let socks5_proxy_bind_addr = *listen_addrs
+        .iter()
+        .find(|addr| addr.is_ipv4() == (args.prefer == IpPreference::V4))
+        .unwrap_or(&listen_addrs[0]);
+    /* From setting the system proxy on, a failure to start puts back what
+     * was changed, as shutting down does */
+    let started = start_system_changes(
+        &args,
+        &config,
+        &state,
+        socks5_proxy_bind_addr,
+        &listen_addrs,
+        sockopts,
+        dial_config,
+        credentials,
+    )
+    .await;
+    let (dispatchers, tls) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            close_system_proxy(config.pac.is_some());
+            undo_system_changes(&state, pidfile.as_deref());
+            return Err(e);
+        }
+    };
+    let mut accept_tasks = vec![];
+    for tcp_listener in tcp_listeners {
+        accept_tasks.push(tokio::spawn(accept_loop(
+            tcp_listener,
+            tls.clone(),
+            sockopts,
+            dial_config,
+            dispatchers.clone(),
+            state.clone(),
+        )));
+    }
+    for accept_task in accept_tasks {
+        accept_task.await?;
+    }
+    if state.handing_off() || state.draining() {
+        /* The process exits once the new one has taken over, or once the
+         * connections have been drained */
+        std::future::pending::<()>().await;
+    }
 
     Ok(())
 }
+
+/// Set the system proxy, firewall rules, tunnel interface and routes up,
+/// then start what serves besides the listeners, stopping at the first
+/// failure
+#[allow(clippy::too_many_arguments)]
+async fn start_system_changes(
+    args: &Args,
+    config: &Config,
+    state: &Arc<AppState>,
+    socks5_proxy_bind_addr: SocketAddr,
+    listen_addrs: &[SocketAddr],
+    sockopts: SocketOptions,
+    dial_config: DialConfig,
+    credentials: Option<(&str, &str)>,
+) -> Result<(Arc<Dispatchers>, Option<TlsTerminator>), Box<dyn Error>> {
+    match config.pac.to_owned() {
+        Some(pac_config) => {
+            let url = pac_config.url();
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = crate::pac::serve(pac_config, state, socks5_proxy_bind_addr).await {
+                    eprintln!("PAC file server stopped; error: {:?}", e);
+                }
+            });
+            crate::cmd::open_pac_proxy(&url)?;
+        }
+        None => crate::cmd::open_socks5_proxy(socks5_proxy_bind_addr, credentials)?,
+    }
+    if let Some(firewall_config) = config.firewall.as_ref() {
+        let mut ports = listen_addrs.iter().map(SocketAddr::port).collect::<Vec<_>>();
+        ports.extend(config.pac.as_ref().map(|pac_config| pac_config.listen.port()));
+        ports.extend(config.tls_psk.as_ref().map(|tls_psk| tls_psk.listen.port()));
+        ports.sort_unstable();
+        ports.dedup();
+        /* On a hot upgrade the rules are left to the process taking over */
+        let firewall = crate::firewall::Firewall::apply(&ports, &firewall_config.allow)?;
+        println!("Firewall lets {:?} reach ports {:?}", firewall_config.allow, ports);
+        state.set_firewall(firewall);
+    }
+    if let Some(wireguard) = config.wireguard.as_ref() {
+        start_wireguard(wireguard, state).await?;
+    }
+    if let Some(ssh) = config.ssh.as_ref() {
+        start_ssh(ssh, state)?;
+    }
+    if let Some(knock) = config.knock.as_ref() {
+        start_knock(knock, state).await?;
+    }
+    let mtu = tun_mtu(config).await;
+    let vtun_config = VTunConfig {
+        mtu: Some(mtu),
+        ipv4_addr: Some(Ipv4Addr::new(192, 168, 31, u8::MAX - 1)),
+        ipv6_addr: Some(format!("::ffff:192.168.31.{}", u8::MAX - 1).parse::<Ipv6Addr>().unwrap()),
+        netmask: Some(0xffffff00),
+        label: Some(String::from("nstream")),
+    };
+    let vtun_label = vtun_config.label.to_owned().unwrap_or_default();
+    let what = format!(
+        "create tunnel interface [{}] with MTU {} and {:?}",
+        vtun_label, mtu, vtun_config.ipv4_addr
+    );
+    let vtun = crate::ops::change(what, interfaces, || {
+        let vtun = VTun::new()?;
+        vtun.config_with(vtun_config)?;
+        Ok(Some(vtun))
+    })?;
+    /* None on a dry run, the routes name the interface it would have been */
+    let ifname = match vtun {
+        Some(vtun) => {
+            if config.tun.mss_clamp.unwrap_or(true) {
+                vtun.clamp_mss_to(Some(mtu));
+            }
+            println!("Tunnel interface {} [{}]", vtun.ifname().unwrap_or_default(), vtun_label);
+            seeval!(vtun.ifname());
+            seeval!(vtun.ifindex());
+            seeval!(vtun.mtu());
+            let ifname = vtun.ifname().unwrap_or_default();
+            state.set_vtun(vtun);
+            ifname
+        }
+        None => String::from("utunN"),
+    };
+    if let Some(global_config) = config.global.as_ref() {
+        let bypass = global_bypass(global_config, config).await?;
+        let routes = crate::routes::Routes::install(&ifname, &bypass)?;
+        println!("Default route through {}, bypassing {:?}", ifname, bypass);
+        state.set_routes(routes);
+        if dial_config.bind.is_none() {
+            eprintln!("Without [dial] bind, direct connections go through {} as well", ifname);
+        }
+    }
+
+    let dispatchers = Arc::new(Dispatchers::new(state.greeting_timeout(), args.single_port));
+    if let Some(tls_psk) = config.tls_psk.as_ref() {
+        start_tls_psk(tls_psk, sockopts, dial_config, dispatchers.clone(), state.clone())?;
+    }
+    let tls = config.tls.as_ref().map(TlsTerminator::new).transpose()?;
+    if tls.is_some() {
+        println!("SOCKS over TLS on the listeners");
+    }
+    Ok((dispatchers, tls))
+}
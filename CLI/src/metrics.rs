@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use nstream_core::{DecisionStats, FamilyStats, ResolveStats, Rule};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Name resolution counters of one address family
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct FamilyDnsStats {
+    pub(crate) queries: u64,
+    /// Queries that failed or found no address
+    pub(crate) failures: u64,
+    pub(crate) mean_latency_ms: f64,
+}
+
+impl From<&FamilyStats> for FamilyDnsStats {
+    fn from(stats: &FamilyStats) -> Self {
+        Self {
+            queries: stats.queries(),
+            failures: stats.failures(),
+            mean_latency_ms: stats.mean_latency().as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+/// Served by `GET /dns`, e.g. to tell a broken IPv6 path by AAAA queries
+/// failing or taking much longer than A ones
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct DnsStats {
+    pub(crate) ipv4: FamilyDnsStats,
+    pub(crate) ipv6: FamilyDnsStats,
+}
+
+/// Process-wide counters, the byte counts and active sessions come from
+/// the session registry instead
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    connections: AtomicU64,
+    handshake_failures: AtomicU64,
+    auth_failures: AtomicU64,
+    /// Connections a rule refused before they were dialed
+    blocked: AtomicU64,
+    /// Connections from addresses that did not knock, see `[knock]`
+    knock_refused: AtomicU64,
+    /// Connections closed for `max_handshakes` being reached
+    handshakes_shed: AtomicU64,
+    udp_dropped: AtomicU64,
+    /// UDP associations holding their socket pair
+    udp_associations: AtomicU64,
+    /// Per-family name resolution counters
+    pub(crate) resolve: ResolveStats,
+    /// Hits and misses of the decisions cached for the user rules
+    pub(crate) decisions: DecisionStats,
+    /// CONNECT destinations per country ISO code, looked up only when
+    /// they are exported
+    #[cfg(feature = "prometheus")]
+    countries: Mutex<HashMap<String, u64>>,
+    /// Destinations `[geoip]` acted on, by the rule it applied
+    country_rules: Mutex<HashMap<String, u64>>,
+}
+
+impl Metrics {
+    #[inline]
+    pub(crate) fn inc_connections(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Accepted client connections
+    #[inline]
+    pub(crate) fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// A malformed greeting or request
+    #[inline]
+    pub(crate) fn inc_handshake_failures(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// No acceptable authentication method, or rejected credentials
+    #[inline]
+    pub(crate) fn inc_auth_failures(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn inc_blocked(&self) {
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn inc_knock_refused(&self) {
+        self.knock_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn inc_handshakes_shed(&self) {
+        self.handshakes_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A datagram with a malformed header or a nonzero FRAG, or from
+    /// another sender than the associated client
+    #[inline]
+    pub(crate) fn inc_udp_dropped(&self) {
+        self.udp_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn inc_udp_associations(&self) {
+        self.udp_associations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn dec_udp_associations(&self) {
+        self.udp_associations.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn udp_associations(&self) -> u64 {
+        self.udp_associations.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn dns(&self) -> DnsStats {
+        DnsStats { ipv4: self.resolve.v4().into(), ipv6: self.resolve.v6().into() }
+    }
+
+    /// A connection or datagram `rule` of `[geoip]` refused or sent
+    /// through the outbound
+    pub(crate) fn inc_country_rule(&self, rule: &Rule) {
+        *self.country_rules.lock().unwrap().entry(rule.to_string()).or_default() += 1;
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn inc_country(&self, iso_code: &str) {
+        *self.countries.lock().unwrap().entry(iso_code.to_string()).or_default() += 1;
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod prometheus {
+    use std::fmt::Write;
+    use std::sync::atomic::Ordering;
+
+    use super::Metrics;
+    use crate::session::Sessions;
+
+    fn write_metric(
+        out: &mut String,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: &[(String, u64)],
+    ) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    }
+
+    impl Metrics {
+        /// Render in the Prometheus text exposition format
+        pub(crate) fn render_prometheus(&self, sessions: &Sessions) -> String {
+            let traffic = sessions.traffic();
+            let (bytes_sent, bytes_received) =
+                traffic.values().fold((0, 0), |(sent, received), t| {
+                    (sent + t.bytes_sent, received + t.bytes_received)
+                });
+            let mut countries =
+                self.countries.lock().unwrap().clone().into_iter().collect::<Vec<_>>();
+            countries.sort();
+            let countries = countries
+                .into_iter()
+                .map(|(iso_code, count)| (format!("{{country=\"{}\"}}", iso_code), count))
+                .collect::<Vec<_>>();
+            let mut country_rules =
+                self.country_rules.lock().unwrap().clone().into_iter().collect::<Vec<_>>();
+            country_rules.sort();
+            let country_rules = country_rules
+                .into_iter()
+                .map(|(rule, count)| (format!("{{rule=\"{}\"}}", rule), count))
+                .collect::<Vec<_>>();
+
+            let single = |value: u64| [(String::new(), value)];
+            let per_family = |value: fn(&nstream_core::FamilyStats) -> u64| {
+                [
+                    (String::from("{family=\"ipv4\"}"), value(self.resolve.v4())),
+                    (String::from("{family=\"ipv6\"}"), value(self.resolve.v6())),
+                ]
+            };
+            let mut out = String::new();
+            write_metric(
+                &mut out,
+                "nstream_connections_total",
+                "counter",
+                "Accepted client connections.",
+                &single(self.connections.load(Ordering::Relaxed)),
+            );
+            write_metric(
+                &mut out,
+                "nstream_sessions_active",
+                "gauge",
+                "Sessions being relayed.",
+                &single(sessions.active().len() as u64),
+            );
+            write_metric(
+                &mut out,
+                "nstream_bytes_sent_total",
+                "counter",
+                "Bytes sent to destinations by closed sessions.",
+                &single(bytes_sent),
+            );
+            write_metric(
+                &mut out,
+                "nstream_bytes_received_total",
+                "counter",
+                "Bytes received from destinations by closed sessions.",
+                &single(bytes_received),
+            );
+            write_metric(
+                &mut out,
+                "nstream_handshake_failures_total",
+                "counter",
+                "Malformed greetings or requests.",
+                &single(self.handshake_failures.load(Ordering::Relaxed)),
+            );
+            write_metric(
+                &mut out,
+                "nstream_auth_failures_total",
+                "counter",
+                "Failed authentication negotiations.",
+                &single(self.auth_failures.load(Ordering::Relaxed)),
+            );
+            write_metric(
+                &mut out,
+                "nstream_blocked_total",
+                "counter",
+                "Connections refused by a rule without being dialed.",
+                &single(self.blocked.load(Ordering::Relaxed)),
+            );
+            write_metric(
+                &mut out,
+                "nstream_knock_refused_total",
+                "counter",
+                "Connections turned away for their address not having knocked.",
+                &single(self.knock_refused.load(Ordering::Relaxed)),
+            );
+            write_metric(
+                &mut out,
+                "nstream_handshakes_shed_total",
+                "counter",
+                "Connections closed at once for too many others being in their handshake.",
+                &single(self.handshakes_shed.load(Ordering::Relaxed)),
+            );
+            write_metric(
+                &mut out,
+                "nstream_udp_dropped_total",
+                "counter",
+                "UDP datagrams dropped for a malformed header, fragmentation or a foreign sender.",
+                &single(self.udp_dropped.load(Ordering::Relaxed)),
+            );
+            write_metric(
+                &mut out,
+                "nstream_udp_associations_active",
+                "gauge",
+                "UDP associations holding their socket pair.",
+                &single(self.udp_associations()),
+            );
+            write_metric(
+                &mut out,
+                "nstream_destinations_total",
+                "counter",
+                "CONNECT destinations per country.",
+                &countries,
+            );
+            write_metric(
+                &mut out,
+                "nstream_country_rules_total",
+                "counter",
+                "Connections and datagrams [geoip] refused or sent through the outbound, per rule.",
+                &country_rules,
+            );
+            write_metric(
+                &mut out,
+                "nstream_dns_queries_total",
+                "counter",
+                "Name resolution queries per address family.",
+                &per_family(|stats| stats.queries()),
+            );
+            write_metric(
+                &mut out,
+                "nstream_dns_failures_total",
+                "counter",
+                "Name resolution queries that failed or found no address, per address family.",
+                &per_family(|stats| stats.failures()),
+            );
+            write_metric(
+                &mut out,
+                "nstream_dns_latency_microseconds_total",
+                "counter",
+                "Time name resolution queries took, per address family.",
+                &per_family(|stats| stats.total_latency().as_micros() as u64),
+            );
+            write_metric(
+                &mut out,
+                "nstream_decision_cache_hits_total",
+                "counter",
+                "Connections whose user rules were decided from the cache.",
+                &single(self.decisions.hits()),
+            );
+            write_metric(
+                &mut out,
+                "nstream_decision_cache_misses_total",
+                "counter",
+                "Connections whose user rules were matched.",
+                &single(self.decisions.misses()),
+            );
+            out
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! The changes made to the system, all of them made here and logged
+//!
+//! Configuring the tunnel interface, adding routes, setting the system
+//! proxy and loading firewall rules take privileges and outlive the process
+//! when it dies unexpectedly. Each such change is logged as `ops:` lines,
+//! with what it is and the state it changes as read before and after, so
+//! that the output of a run tells what was done to a system and what to
+//! undo by hand.
+//!
+//! With `--dry-run` the changes are logged and not made, the state being
+//! read before only. What a change would have returned is taken to be
+//! empty: no pf token, no tunnel interface.
+
+use std::fmt::Display;
+use std::io::{Error, Result, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed)
+}
+
+pub(crate) fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// What `program` printed, its output then its errors, the latter in the
+/// error should it fail
+fn output_of(program: &str, args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::other(format!("{} {}: {}", program, args.join(" "), stderr.trim())));
+    }
+    Ok(String::from_utf8_lossy(&[output.stdout, output.stderr].concat()).into_owned())
+}
+
+/// Run `program`, which only reads the state of the system, also under
+/// `--dry-run`
+pub(crate) fn query(program: &str, args: &[&str]) -> Result<String> {
+    output_of(program, args, None)
+}
+
+/// `state` on a line of the log
+fn one_line(state: Result<String>) -> String {
+    match state {
+        Ok(state) => {
+            let lines = state.lines().map(str::trim).filter(|line| !line.is_empty());
+            match lines.collect::<Vec<_>>().join("; ") {
+                state if state.is_empty() => String::from("-"),
+                state => state,
+            }
+        }
+        Err(e) => format!("unknown, {}", e),
+    }
+}
+
+/// Make the change `what` describes by calling `change`, logging what
+/// `state` reads before and after. Under `--dry-run` it is logged only,
+/// `T::default()` standing for what it returns.
+pub(crate) fn change<T, S, C>(what: impl Display, state: S, change: C) -> Result<T>
+where
+    T: Default,
+    S: Fn() -> Result<String>,
+    C: FnOnce() -> Result<T>,
+{
+    if dry_run() {
+        println!("ops: would {}", what);
+        println!("ops:   now {}", one_line(state()));
+        return Ok(T::default());
+    }
+    println!("ops: {}", what);
+    println!("ops:   before {}", one_line(state()));
+    let ret = change();
+    match &ret {
+        Ok(_) => println!("ops:   after {}", one_line(state())),
+        Err(e) => println!("ops:   failed; error: {}", e),
+    }
+    ret
+}
+
+/// Run the privileged `program`, feeding it `input`, as a [change] whose
+/// state the read-only command `probe` prints. Returns what it printed,
+/// errors carry what it printed on failure.
+pub(crate) fn run(
+    program: &str,
+    args: &[&str],
+    input: Option<&str>,
+    probe: (&str, &[&str]),
+) -> Result<String> {
+    run_hiding(program, args, input, None, probe)
+}
+
+/// [run] with the argument `secret`, a password, masked in the log
+pub(crate) fn run_hiding(
+    program: &str,
+    args: &[&str],
+    input: Option<&str>,
+    secret: Option<&str>,
+    probe: (&str, &[&str]),
+) -> Result<String> {
+    let logged = args.iter().map(|arg| match secret == Some(*arg) {
+        true => "********",
+        false => arg,
+    });
+    let mut what = format!("run {} {}", program, logged.collect::<Vec<_>>().join(" "));
+    /* What it would have been fed, under the command */
+    for line in input.filter(|_| dry_run()).into_iter().flat_map(str::lines) {
+        what += &format!("\nops:     {}", line);
+    }
+    change(what, || query(probe.0, probe.1), || output_of(program, args, input))
+}
@@ -0,0 +1,55 @@
+//! Proxy auto-config file server
+//!
+//! Serves `/proxy.pac`, generated from the rules in use on every request so
+//! that rule changes through the management API or a reload take effect
+//! the next time the system fetches it.
+
+use std::convert::Infallible;
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use nstream_core::Router;
+
+use crate::config::PacConfig;
+use crate::state::AppState;
+
+pub(crate) const PAC_PATH: &str = "/proxy.pac";
+
+async fn handle_request(
+    req: Request<Body>,
+    state: Arc<AppState>,
+    socks_addr: SocketAddr,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if (req.method(), req.uri().path()) != (&Method::GET, PAC_PATH) {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+    let pac = Router::new(state.rules()).to_pac(socks_addr);
+    let mut resp = Response::new(Body::from(pac));
+    resp.headers_mut().insert(CONTENT_TYPE, "application/x-ns-proxy-autoconfig".parse().unwrap());
+    Ok(resp)
+}
+
+/// Serve the PAC file sending proxied destinations to `socks_addr`
+pub(crate) async fn serve(
+    pac_config: PacConfig,
+    state: Arc<AppState>,
+    socks_addr: SocketAddr,
+) -> Result<()> {
+    let make_svc = make_service_fn(move |_conn| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle_request(req, state.clone(), socks_addr)
+            }))
+        }
+    });
+    let server = Server::try_bind(&pac_config.listen).map_err(Error::other)?.serve(make_svc);
+    println!("PAC file on {}", pac_config.url());
+    server.await.map_err(Error::other)
+}
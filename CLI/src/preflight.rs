@@ -0,0 +1,176 @@
+//! Startup self-checks
+//!
+//! Everything the proxy needs from the system is checked up front, and all
+//! failures are reported at once along with how to fix them, rather than
+//! stopping at whichever happens to fail first. Only failed checks of
+//! [Severity::Error] keep it from starting.
+
+use std::fmt::Display;
+use std::io::{Error, ErrorKind, Result};
+use std::net::TcpListener;
+use std::time::Duration;
+
+use nstream_core::{
+    is_privileged, probe_geoip_database, probe_tun_support, what_is_my_extip_v4addr,
+    what_is_my_extip_v6addr,
+};
+
+use crate::config::Config;
+
+/// How long a STUN server may take to answer
+const STUN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a failed check means for starting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Severity {
+    /// Starting is refused
+    Error,
+    /// Worth fixing, but the proxy runs without it
+    Warning,
+}
+
+#[derive(Debug)]
+pub(crate) struct Check {
+    pub(crate) name: &'static str,
+    /// What went wrong, if anything
+    pub(crate) failure: Option<String>,
+    /// What to do about a failure
+    pub(crate) remedy: &'static str,
+    pub(crate) severity: Severity,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    pub(crate) checks: Vec<Check>,
+}
+
+impl Report {
+    fn push<E: Display>(
+        &mut self,
+        name: &'static str,
+        ret: std::result::Result<(), E>,
+        remedy: &'static str,
+    ) {
+        self.push_with(name, ret, remedy, Severity::Error)
+    }
+
+    fn push_with<E: Display>(
+        &mut self,
+        name: &'static str,
+        ret: std::result::Result<(), E>,
+        remedy: &'static str,
+        severity: Severity,
+    ) {
+        let failure = ret.err().map(|e| e.to_string());
+        self.checks.push(Check { name, failure, remedy, severity });
+    }
+
+    /// The failed checks of [Severity::Error]
+    pub(crate) fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks
+            .iter()
+            .filter(|check| check.failure.is_some() && check.severity == Severity::Error)
+    }
+
+    pub(crate) fn print(&self) {
+        println!("Pre-flight checks:");
+        for check in self.checks.iter() {
+            let label = match (&check.failure, check.severity) {
+                (None, _) => "[ok]  ",
+                (Some(_), Severity::Error) => "[FAIL]",
+                (Some(_), Severity::Warning) => "[warn]",
+            };
+            match &check.failure {
+                None => println!("  {} {}", label, check.name),
+                Some(failure) => {
+                    println!("  {} {}: {}", label, check.name, failure);
+                    println!("         {}", check.remedy);
+                }
+            }
+        }
+    }
+
+    /// An error counting the failed checks, if any
+    pub(crate) fn into_result(self) -> Result<()> {
+        match self.failures().count() {
+            0 => Ok(()),
+            n => Err(Error::other(format!(
+                "{} of {} pre-flight checks failed",
+                n,
+                self.checks.len()
+            ))),
+        }
+    }
+}
+
+async fn probe_stun<F, T>(query: F) -> std::result::Result<(), String>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    match tokio::time::timeout(STUN_TIMEOUT, query).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("No answer within {:?}", STUN_TIMEOUT)),
+    }
+}
+
+/// Run every check, `taking_over` skips those that the process being taken
+/// over is expected to fail, since it still holds the resources
+pub(crate) async fn run(config: &Config, taking_over: bool) -> Report {
+    let mut report = Report::default();
+
+    let privileged = match is_privileged() {
+        true => Ok(()),
+        false => Err(Error::from(ErrorKind::PermissionDenied)),
+    };
+    report.push("privileges", privileged, "Run as root, e.g. with sudo");
+
+    if let (Some(admin), false) = (&config.admin, taking_over) {
+        report.push(
+            "management API port",
+            TcpListener::bind(admin.listen).map(drop),
+            "Stop whatever listens on [admin] listen, or choose another port",
+        );
+    }
+    if let (Some(tls_psk), false) = (&config.tls_psk, taking_over) {
+        report.push(
+            "TLS-PSK port",
+            TcpListener::bind(tls_psk.listen).map(drop),
+            "Stop whatever listens on [tls_psk] listen, or choose another port",
+        );
+    }
+
+    report.push(
+        "tunnel interface",
+        probe_tun_support(),
+        "Tunnel interfaces need macOS utun, or /dev/net/tun on Linux",
+    );
+
+    report.push(
+        "GeoIP database",
+        probe_geoip_database(),
+        "Rebuild so that build.rs can download Country.mmdb",
+    );
+
+    let (stun_v4, stun_v6) =
+        tokio::join!(probe_stun(what_is_my_extip_v4addr()), probe_stun(what_is_my_extip_v6addr()));
+    let stun_remedy =
+        "Allow outbound UDP to port 3478, the external address is looked up with STUN";
+    /* A host of a single family is common, the address of the other one is
+     * looked up without it as well */
+    let stun_severity = match (&stun_v4, &stun_v6) {
+        (Err(_), Err(_)) => Severity::Error,
+        _ => Severity::Warning,
+    };
+    report.push_with("STUN over IPv4", stun_v4, stun_remedy, stun_severity);
+    report.push_with("STUN over IPv6", stun_v6, stun_remedy, stun_severity);
+
+    #[cfg(target_os = "macos")]
+    report.push(
+        "system proxy",
+        crate::cmd::probe_socks5_proxy(),
+        "Run as an administrator, and check that the Wi-Fi network service exists",
+    );
+
+    report
+}
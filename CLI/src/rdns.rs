@@ -0,0 +1,62 @@
+//! Host names of the destinations shown by the management API
+//!
+//! Destinations given as IP addresses are looked up in the background the
+//! first time they are shown, and their name is shown from the cache once
+//! known, so that listing connections never waits for DNS. Lookups that
+//! fail or time out are cached too, so an address is asked about at most
+//! once per TTL.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use nstream_core::reverse_lookup;
+
+/// Addresses cached before the expired entries are dropped
+const MAX_CACHED: usize = 4096;
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Pending,
+    /// None when the address has no name
+    Done(Option<String>, Instant),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ReverseNames {
+    timeout: Duration,
+    ttl: Duration,
+    cache: Arc<Mutex<HashMap<IpAddr, Entry>>>,
+}
+
+impl ReverseNames {
+    pub(crate) fn new(timeout: Duration, ttl: Duration) -> Self {
+        Self { timeout, ttl, cache: Arc::default() }
+    }
+
+    /// The name of `ip` when known, otherwise it is looked up for next time
+    pub(crate) fn name_of(&self, ip: IpAddr) -> Option<String> {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(&ip) {
+            Some(Entry::Pending) => return None,
+            Some(Entry::Done(name, expires)) if *expires > now => return name.to_owned(),
+            _ => {}
+        }
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, entry| !matches!(entry, Entry::Done(_, expires) if *expires <= now));
+            if cache.len() >= MAX_CACHED {
+                return None;
+            }
+        }
+        cache.insert(ip, Entry::Pending);
+
+        let (cache, timeout, ttl) = (self.cache.clone(), self.timeout, self.ttl);
+        tokio::spawn(async move {
+            let name = reverse_lookup(ip, timeout).await.ok();
+            cache.lock().unwrap().insert(ip, Entry::Done(name, Instant::now() + ttl));
+        });
+        None
+    }
+}
@@ -0,0 +1,138 @@
+//! Default-route takeover, the `[global]` section
+//!
+//! All IPv4 traffic is sent to the tunnel interface by two routes covering
+//! half of the address space each. Being more specific than the default
+//! route of the system, they win over it while leaving it in place, so
+//! that removing them restores the routing table as it was. The servers the
+//! proxy talks to itself, the LAN subnets and whatever else is bypassed
+//! keep going through the original gateway by routes of their own, which
+//! are removed along. IPv6 is left as it is.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr};
+
+use nstream_core::IpCidr;
+
+use crate::ops::run;
+
+/// Private and link-local networks, whose hosts are not to be reached
+/// through the tunnel
+pub(crate) const LAN_SUBNETS: [&str; 4] =
+    ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "169.254.0.0/16"];
+
+/// Together the whole IPv4 space, each more specific than a default route
+const TAKEOVER: [&str; 2] = ["0.0.0.0/1", "128.0.0.0/1"];
+
+/// Where a route sends packets
+#[derive(Debug)]
+enum Via<'a> {
+    Gateway(Ipv4Addr),
+    Interface(&'a str),
+}
+
+/// Add or replace the route to `dest`
+fn add(dest: &IpCidr, via: Via) -> Result<()> {
+    let dest = dest.to_string();
+    #[cfg(target_os = "macos")]
+    return {
+        let probe = ("route", &["-n", "get", &dest][..]);
+        /* A route left by a previous run would make the addition fail */
+        let _ = run("route", &["-n", "delete", "-net", &dest], None, probe);
+        match via {
+            Via::Gateway(gateway) => {
+                run("route", &["-n", "add", "-net", &dest, &gateway.to_string()], None, probe)
+            }
+            Via::Interface(ifname) => {
+                run("route", &["-n", "add", "-net", &dest, "-interface", ifname], None, probe)
+            }
+        }
+        .map(drop)
+    };
+    #[cfg(target_os = "linux")]
+    return {
+        let probe = ("ip", &["route", "show", &dest][..]);
+        match via {
+            Via::Gateway(gateway) => {
+                run("ip", &["route", "replace", &dest, "via", &gateway.to_string()], None, probe)
+            }
+            Via::Interface(ifname) => {
+                run("ip", &["route", "replace", &dest, "dev", ifname], None, probe)
+            }
+        }
+        .map(drop)
+    };
+    #[allow(unreachable_code)]
+    Err(Error::new(ErrorKind::Unsupported, "No supported routing table on this system"))
+}
+
+fn delete(dest: &IpCidr) -> Result<()> {
+    let dest = dest.to_string();
+    #[cfg(target_os = "macos")]
+    return run("route", &["-n", "delete", "-net", &dest], None, ("route", &["-n", "get", &dest]))
+        .map(drop);
+    #[cfg(target_os = "linux")]
+    return run("ip", &["route", "del", &dest], None, ("ip", &["route", "show", &dest])).map(drop);
+    #[allow(unreachable_code)]
+    Err(Error::from(ErrorKind::Unsupported))
+}
+
+/// Routes in force until [Routes::remove]
+#[derive(Debug)]
+pub(crate) struct Routes {
+    /// In the order they were added
+    added: Vec<IpCidr>,
+}
+
+impl Routes {
+    /// Route all IPv4 traffic to `ifname` but that to `bypass`, which keeps
+    /// going through the current default gateway
+    pub(crate) fn install(ifname: &str, bypass: &[IpCidr]) -> Result<Self> {
+        if ifname.is_empty() {
+            return Err(Error::new(ErrorKind::Unsupported, "The tunnel interface has no name"));
+        }
+        let gateway = nstream_core::default_gateway()?;
+        let mut unique = vec![];
+        for cidr in bypass.iter().filter(|cidr| cidr.addr().is_ipv4()) {
+            if !unique.contains(cidr) {
+                unique.push(*cidr);
+            }
+        }
+        /* The bypass routes first, the servers are never reached through
+         * the tunnel */
+        let routes = unique
+            .into_iter()
+            .map(|cidr| (cidr, Via::Gateway(gateway)))
+            .chain(TAKEOVER.iter().map(|cidr| (cidr.parse().unwrap(), Via::Interface(ifname))));
+        let mut installed = Self { added: vec![] };
+        for (dest, via) in routes {
+            if let Err(e) = add(&dest, via) {
+                let _ = installed.remove();
+                return Err(e);
+            }
+            installed.added.push(dest);
+        }
+        Ok(installed)
+    }
+
+    /// Remove the routes in the reverse order, the takeover ones first.
+    /// Returns the first error, once all of them were tried.
+    pub(crate) fn remove(self) -> Result<()> {
+        let mut ret = Ok(());
+        for dest in self.added.iter().rev() {
+            if let Err(e) = delete(dest) {
+                ret = ret.and(Err(e));
+            }
+        }
+        ret
+    }
+}
+
+/// Host routes to `addrs`, only the IPv4 ones are bypassed
+pub(crate) fn host_routes(addrs: impl IntoIterator<Item = IpAddr>) -> Vec<IpCidr> {
+    addrs
+        .into_iter()
+        .map(|addr| addr.to_canonical())
+        .filter(IpAddr::is_ipv4)
+        .map(|addr| IpCidr::new(addr, 32).unwrap())
+        .collect()
+}
@@ -0,0 +1,77 @@
+//! JSON Schema of the configuration file and of the management API, for
+//! GUIs and validation tools to build against
+//!
+//! Printed by `nstream schema`. Values that are parsed from strings, such as
+//! durations, rules and capture filters, are described as plain strings.
+
+use std::collections::{BTreeMap, HashMap};
+
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::admin::{
+    CaptureRequest, CaptureStatus, ErrorBody, ProfileRequest, ProfilesStatus, Status,
+};
+use crate::config::Config;
+use crate::conntrack::Conn;
+use crate::dnslog::DnsLogEvent;
+use crate::eventlog::LogEntry;
+use crate::metrics::DnsStats;
+use crate::session::{ConnectionEvent, Session, Traffic};
+use crate::users::{UserRequest, UserStatus};
+
+/// Rules as written in the configuration file, e.g. `"MATCH,PROXY"`
+type Rules = Vec<String>;
+
+#[derive(Serialize)]
+struct Endpoint {
+    request: Option<RootSchema>,
+    response: RootSchema,
+}
+
+impl Endpoint {
+    fn new<Resp: JsonSchema>() -> Self {
+        Self { request: None, response: schema_for!(Resp) }
+    }
+
+    fn with_request<Req: JsonSchema, Resp: JsonSchema>() -> Self {
+        Self { request: Some(schema_for!(Req)), response: schema_for!(Resp) }
+    }
+}
+
+/// The schema document, the API endpoints are keyed by method and path
+pub(crate) fn schema() -> Value {
+    let api = [
+        ("GET /connections", Endpoint::new::<Vec<Session>>()),
+        ("GET /conntrack", Endpoint::new::<Vec<Conn>>()),
+        ("DELETE /conntrack/<client>", Endpoint::new::<Map<String, Value>>()),
+        ("GET /traffic", Endpoint::new::<HashMap<String, Traffic>>()),
+        ("GET /rules", Endpoint::new::<Rules>()),
+        ("PUT /rules", Endpoint::with_request::<Rules, Rules>()),
+        ("POST /config/validate", Endpoint::new::<Rules>()),
+        ("POST /config/reload", Endpoint::new::<Rules>()),
+        ("POST /config/confirm", Endpoint::new::<Map<String, Value>>()),
+        ("POST /shutdown", Endpoint::new::<Map<String, Value>>()),
+        ("GET /dns", Endpoint::new::<DnsStats>()),
+        ("GET /capture", Endpoint::new::<CaptureStatus>()),
+        ("POST /capture", Endpoint::with_request::<CaptureRequest, CaptureStatus>()),
+        ("DELETE /capture", Endpoint::new::<CaptureStatus>()),
+        ("GET /status", Endpoint::new::<Status>()),
+        ("GET /logs", Endpoint::new::<Vec<LogEntry>>()),
+        ("GET /profiles", Endpoint::new::<ProfilesStatus>()),
+        ("PUT /profiles", Endpoint::with_request::<ProfileRequest, ProfilesStatus>()),
+        ("GET /users", Endpoint::new::<Vec<UserStatus>>()),
+        ("POST /users", Endpoint::with_request::<UserRequest, Vec<UserStatus>>()),
+        ("DELETE /users/<name>", Endpoint::new::<Map<String, Value>>()),
+        /* A stream of them, one per line */
+        ("GET /events", Endpoint::new::<ConnectionEvent>()),
+        ("GET /dns/queries", Endpoint::new::<DnsLogEvent>()),
+    ];
+    json!({
+        "config": schema_for!(Config),
+        "api": api.into_iter().collect::<BTreeMap<_, _>>(),
+        "error": schema_for!(ErrorBody),
+    })
+}
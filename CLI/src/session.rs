@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Events held for each subscriber, one that falls further behind misses
+/// the oldest
+const EVENT_CAPACITY: usize = 1024;
+
+/// A proxied connection in progress
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct Session {
+    pub(crate) id: u64,
+    pub(crate) peer: SocketAddr,
+    pub(crate) destination: String,
+    /// The host name of an IP `destination`, with `[reverse_dns]` configured
+    /// and once it has been looked up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) destination_name: Option<String>,
+    pub(crate) command: String,
+    /// Seconds since the UNIX epoch
+    pub(crate) started_at: u64,
+    /// Bytes relayed by a previous process, before a hot upgrade
+    #[serde(default)]
+    pub(crate) bytes_sent: u64,
+    #[serde(default)]
+    pub(crate) bytes_received: u64,
+}
+
+/// Traffic totals of one destination, over closed sessions
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct Traffic {
+    pub(crate) sessions: u64,
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+}
+
+/// Published live to the subscribers of [Sessions::subscribe]
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum ConnectionEvent {
+    Opened {
+        id: u64,
+        peer: SocketAddr,
+        destination: String,
+        command: String,
+        /// How the destination is reached, e.g. `direct`
+        route: String,
+    },
+    /// Bytes relayed since the previous event of the session
+    Bytes {
+        id: u64,
+        sent: u64,
+        received: u64,
+    },
+    Closed {
+        id: u64,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+    /// Events the subscriber missed for falling behind
+    Lagged {
+        missed: u64,
+    },
+}
+
+/// Registry of the active sessions and the per-destination traffic
+#[derive(Debug)]
+pub(crate) struct Sessions {
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, Session>>,
+    traffic: Mutex<HashMap<String, Traffic>>,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::default(),
+            active: Mutex::default(),
+            traffic: Mutex::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl Sessions {
+    /// Live events of the sessions from now on
+    #[inline]
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, event: ConnectionEvent) {
+        /* Fails only when nobody is subscribed */
+        let _ = self.events.send(event);
+    }
+
+    /// Register a new session reaching its destination by `route`, returns
+    /// its id
+    pub(crate) fn open(
+        &self,
+        peer: SocketAddr,
+        destination: String,
+        command: &str,
+        route: &str,
+    ) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let command = command.to_string();
+        let session = Session {
+            id,
+            peer,
+            destination,
+            destination_name: None,
+            command,
+            started_at,
+            bytes_sent: 0,
+            bytes_received: 0,
+        };
+        self.publish(ConnectionEvent::Opened {
+            id,
+            peer,
+            destination: session.destination.clone(),
+            command: session.command.clone(),
+            route: route.to_string(),
+        });
+        self.active.lock().unwrap().insert(id, session);
+        id
+    }
+
+    /// Report bytes relayed by a session, only when someone listens
+    #[inline]
+    pub(crate) fn relayed(&self, id: u64, sent: u64, received: u64) {
+        if self.events.receiver_count() > 0 {
+            self.publish(ConnectionEvent::Bytes { id, sent, received });
+        }
+    }
+
+    /// Unregister a session, accounting the bytes it sent to and received
+    /// from its destination
+    pub(crate) fn close(&self, id: u64, bytes_sent: u64, bytes_received: u64) {
+        let Some(session) = self.active.lock().unwrap().remove(&id) else {
+            return;
+        };
+        let (bytes_sent, bytes_received) =
+            (session.bytes_sent + bytes_sent, session.bytes_received + bytes_received);
+        self.publish(ConnectionEvent::Closed { id, bytes_sent, bytes_received });
+        let mut traffic = self.traffic.lock().unwrap();
+        let traffic = traffic.entry(session.destination).or_default();
+        traffic.sessions += 1;
+        traffic.bytes_sent += bytes_sent;
+        traffic.bytes_received += bytes_received;
+    }
+
+    /// Unregister a session without accounting it, for handing it over to
+    /// another process
+    pub(crate) fn take(&self, id: u64, bytes_sent: u64, bytes_received: u64) -> Option<Session> {
+        let mut session = self.active.lock().unwrap().remove(&id)?;
+        session.bytes_sent += bytes_sent;
+        session.bytes_received += bytes_received;
+        Some(session)
+    }
+
+    /// Register a session taken over from another process, keeping its id
+    pub(crate) fn restore(&self, session: Session) {
+        self.next_id.fetch_max(session.id + 1, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(session.id, session);
+    }
+
+    /// Merge the traffic totals taken over from another process
+    pub(crate) fn restore_traffic(&self, traffic: HashMap<String, Traffic>) {
+        let mut totals = self.traffic.lock().unwrap();
+        for (destination, traffic) in traffic {
+            let total = totals.entry(destination).or_default();
+            total.sessions += traffic.sessions;
+            total.bytes_sent += traffic.bytes_sent;
+            total.bytes_received += traffic.bytes_received;
+        }
+    }
+
+    pub(crate) fn active(&self) -> Vec<Session> {
+        let mut sessions = self.active.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    pub(crate) fn traffic(&self) -> HashMap<String, Traffic> {
+        self.traffic.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,679 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use nstream_core::{
+    CaptureFilter, CountryPolicy, FakeIpPool, FamilyPreference, LogSampler, Process, Router, Rule,
+    TunCapture, UdpBufferOptions, VTun,
+};
+use socks5::protocol::Address;
+use socks5::protocol::ClientMatch;
+use socks5::udp_pool::UdpSocketPool;
+use socks5::Socks5Error;
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::config::{AuthConfig, Config, ConfigOverride};
+use crate::conntrack::ConnTrack;
+use crate::dnslog::{DnsLog, DnsQuery, Resolver};
+use crate::eventlog::EventLog;
+use crate::firewall::Firewall;
+use crate::metrics::Metrics;
+use crate::rdns::ReverseNames;
+use crate::routes::Routes;
+use crate::session::{Session, Sessions};
+use crate::tasks::Tasks;
+use crate::upgrade::ParkedSession;
+use crate::users::Users;
+
+/// The rule sets of the configuration file and which one is in use
+#[derive(Debug, Clone, Default)]
+struct Profiles {
+    /// `rules` of the configuration file
+    base: Vec<Rule>,
+    named: BTreeMap<String, Vec<Rule>>,
+    /// None for the base rules, or rules replaced through the API
+    active: Option<String>,
+}
+
+/// What a reload replaces, put back as it was by a rollback
+#[derive(Debug)]
+struct Snapshot {
+    profiles: Profiles,
+    rules: Vec<Rule>,
+    auth: Option<AuthConfig>,
+}
+
+/// A reload to be confirmed before its window closes
+#[derive(Debug)]
+struct PendingReload {
+    /// Of the last reload, a later one restarts the window
+    id: u64,
+    deadline: Instant,
+    /// As of before the first reload not confirmed
+    previous: Snapshot,
+}
+
+/// The clients whose connections are traced, from the `NSTREAM_TRACE`
+/// environment variable, which is either `all` or a comma separated list
+/// of client IP addresses
+#[derive(Debug, Clone)]
+enum TraceClients {
+    None,
+    All,
+    Ips(Vec<IpAddr>),
+}
+
+impl TraceClients {
+    fn from_env() -> Self {
+        let val = match std::env::var("NSTREAM_TRACE") {
+            Ok(val) => val,
+            Err(_) => return Self::None,
+        };
+        let items = val.split(',').map(str::trim).collect::<Vec<_>>();
+        match items.contains(&"all") {
+            true => Self::All,
+            false => Self::Ips(items.into_iter().filter_map(|item| item.parse().ok()).collect()),
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::Ips(ips) => ips.contains(&ip),
+        }
+    }
+}
+
+/// State shared by the proxy and the management API
+#[derive(Debug)]
+pub(crate) struct AppState {
+    config_path: Option<PathBuf>,
+    /// Applied again on reload
+    overrides: Vec<ConfigOverride>,
+    started_at: Instant,
+    router: RwLock<Router>,
+    profiles: Mutex<Profiles>,
+    /// `[auth]` as last applied
+    auth: Mutex<Option<AuthConfig>>,
+    /// How long a reload has to be confirmed, `confirm_window` of `[admin]`
+    confirm_window: Option<Duration>,
+    reloads: AtomicU64,
+    pending_reload: Mutex<Option<PendingReload>>,
+    handshake_timeout: Duration,
+    greeting_timeout: Duration,
+    /// Permits of the connections in their handshake, `max_handshakes`
+    handshake_slots: Arc<Semaphore>,
+    udp_idle_timeout: Duration,
+    udp_client_match: ClientMatch,
+    udp_buffers: UdpBufferOptions,
+    drain_timeout: Duration,
+    /// `log_blocked` of the configuration file
+    log_blocked: bool,
+    /// Read once at startup
+    trace_clients: TraceClients,
+    trace_filter: Option<CaptureFilter>,
+    fake_ip: Option<FakeIpPool>,
+    /// That of `[geoip]`
+    country_policy: Option<CountryPolicy>,
+    reverse_names: Option<ReverseNames>,
+    pub(crate) sessions: Sessions,
+    pub(crate) conntrack: ConnTrack,
+    pub(crate) metrics: Metrics,
+    pub(crate) log: EventLog,
+    /// Which connections of rules with `log-sample` make it to `log`
+    log_sampler: LogSampler,
+    pub(crate) dns_log: DnsLog,
+    pub(crate) users: Users,
+    /// Spawned per connection
+    pub(crate) tasks: Tasks,
+    /// Outbound sockets of the UDP associations
+    pub(crate) udp_pool: UdpSocketPool,
+    shutdown: Notify,
+    /// Set once shutdown starts
+    draining: watch::Sender<bool>,
+    /// Set once a hot upgrade starts
+    handoff: watch::Sender<bool>,
+    listeners: Mutex<Vec<(SocketAddr, OwnedFd)>>,
+    parked: Mutex<Vec<ParkedSession>>,
+    /// Torn down on exit
+    vtun: Mutex<Option<VTun>>,
+    /// Removed on exit
+    firewall: Mutex<Option<Firewall>>,
+    /// Those of `[global]`, removed before the tunnel interface
+    routes: Mutex<Option<Routes>>,
+    /// The tunnel of `[wireguard]`, once up
+    #[cfg(feature = "wireguard")]
+    wireguard: std::sync::OnceLock<nstream_core::WireGuard>,
+    /// The server of `[ssh]` along with the rules of what goes through it
+    #[cfg(feature = "ssh")]
+    ssh: std::sync::OnceLock<(nstream_core::Ssh, Router)>,
+    /// The addresses let in by `[knock]`, once its socket is bound
+    #[cfg(feature = "knock")]
+    knock: std::sync::OnceLock<nstream_core::KnockGate>,
+}
+
+impl AppState {
+    pub(crate) fn new(
+        config_path: Option<PathBuf>,
+        overrides: Vec<ConfigOverride>,
+        config: &Config,
+    ) -> Result<Self> {
+        let metrics = Metrics::default();
+        let users = match &config.auth {
+            Some(auth) => Users::new(auth, metrics.decisions.clone())?,
+            None => Users::default(),
+        };
+        Ok(Self {
+            config_path,
+            overrides,
+            started_at: Instant::now(),
+            router: RwLock::new(Router::new(config.rules.to_owned())),
+            profiles: Mutex::new(Profiles {
+                base: config.rules.to_owned(),
+                named: config.profiles.to_owned(),
+                active: None,
+            }),
+            auth: Mutex::new(config.auth.to_owned()),
+            confirm_window: config
+                .admin
+                .as_ref()
+                .and_then(|admin| admin.confirm_window)
+                .map(Into::into),
+            reloads: AtomicU64::new(0),
+            pending_reload: Mutex::new(None),
+            handshake_timeout: config.socket.handshake_timeout(),
+            greeting_timeout: config.socket.greeting_timeout(),
+            handshake_slots: Arc::new(Semaphore::new(config.socket.max_handshakes())),
+            udp_idle_timeout: config.socket.udp_idle_timeout(),
+            udp_client_match: config.socket.udp_client_match(),
+            udp_buffers: config.socket.udp_buffers(),
+            drain_timeout: config.socket.drain_timeout(),
+            log_blocked: config.log_blocked,
+            trace_clients: TraceClients::from_env(),
+            trace_filter: config.trace.filter.to_owned(),
+            fake_ip: config.fake_ip.as_ref().map(|fake_ip| fake_ip.pool()).transpose()?,
+            country_policy: config.geoip.as_ref().map(|geoip| geoip.policy()).transpose()?,
+            reverse_names: config
+                .reverse_dns
+                .as_ref()
+                .map(|reverse_dns| ReverseNames::new(reverse_dns.timeout(), reverse_dns.ttl())),
+            sessions: Sessions::default(),
+            conntrack: ConnTrack::default(),
+            metrics,
+            log: EventLog::default(),
+            log_sampler: LogSampler::default(),
+            dns_log: DnsLog::default(),
+            users,
+            tasks: Tasks::default(),
+            udp_pool: UdpSocketPool::default(),
+            shutdown: Notify::new(),
+            draining: watch::channel(false).0,
+            handoff: watch::channel(false).0,
+            listeners: Mutex::new(vec![]),
+            parked: Mutex::new(vec![]),
+            vtun: Mutex::new(None),
+            firewall: Mutex::new(None),
+            routes: Mutex::new(None),
+            #[cfg(feature = "wireguard")]
+            wireguard: std::sync::OnceLock::new(),
+            #[cfg(feature = "ssh")]
+            ssh: std::sync::OnceLock::new(),
+            #[cfg(feature = "knock")]
+            knock: std::sync::OnceLock::new(),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn rules(&self) -> Vec<Rule> {
+        self.router.read().unwrap().rules()
+    }
+
+    /// The process on this host which opened `tcp_stream` and the first
+    /// process rule in use it matches, None without a match. The other
+    /// rules are left to the PAC file, the destinations reaching the proxy
+    /// being those sent to it.
+    pub(crate) async fn app_rule(
+        &self,
+        tcp_stream: &tokio::net::TcpStream,
+    ) -> Option<(Process, Rule)> {
+        let rules = self
+            .router
+            .read()
+            .unwrap()
+            .rules()
+            .into_iter()
+            .filter(Rule::is_process_rule)
+            .collect::<Vec<_>>();
+        if rules.is_empty() {
+            return None;
+        }
+        let (client, local) = (tcp_stream.peer_addr().ok()?, tcp_stream.local_addr().ok()?);
+        /* Walks the processes of the host */
+        let process = tokio::task::spawn_blocking(move || nstream_core::owner_of(client, local))
+            .await
+            .ok()?
+            .ok()?;
+        let rule = rules
+            .into_iter()
+            .find(|rule| rule.matches_process(None, None, None, Some(&process)))?;
+        Some((process, rule))
+    }
+
+    /// Count the connection of `client` to `addr` which `rule` refuses, and
+    /// log it with `log_blocked`. Returns the error to fail it with, whose
+    /// reply says a rule refused it.
+    pub(crate) fn block(&self, client: SocketAddr, addr: &Address, rule: &Rule) -> Error {
+        self.metrics.inc_blocked();
+        if self.log_blocked {
+            let message = format!("Blocked {} to {} by {}", client, addr.to_string(), rule);
+            println!("{}", message);
+            self.log.push(message);
+        }
+        Socks5Error::Blocked { rule: rule.to_string() }.into()
+    }
+
+    /// Resolve `name` for `client` as [nstream_core::resolve] does, the
+    /// query being logged
+    pub(crate) async fn resolve(
+        &self,
+        name: &str,
+        port: u16,
+        preference: FamilyPreference,
+        client: SocketAddr,
+    ) -> Result<Vec<SocketAddr>> {
+        let started = Instant::now();
+        let ret = nstream_core::resolve(name, port, preference, &self.metrics.resolve).await;
+        let answers = match &ret {
+            Ok(addrs) => Ok(addrs.iter().map(SocketAddr::ip).collect()),
+            Err(e) => Err(e.to_string()),
+        };
+        self.log_dns(DnsQuery::new(name, client, Resolver::System, answers, started.elapsed()));
+        ret
+    }
+
+    /// Whether the connection to `destination` is to be logged, as the
+    /// `log-sample` of the rule it matches tells
+    pub(crate) fn sample_log(&self, destination: &Address) -> bool {
+        let router = self.router.read().unwrap();
+        let rule = match destination {
+            Address::IP(socket_addr) => router.matched_rule(None, Some(socket_addr.ip())),
+            Address::Domain(name, _) => router.matched_rule(Some(name), None),
+        };
+        self.log_sampler.sample(rule)
+    }
+
+    /// Log `query` along with the rule its first answer matches
+    pub(crate) fn log_dns(&self, mut query: DnsQuery) {
+        /* Fake addresses stand for the domain, it is all there is to match */
+        let answer = query.answers.first().copied().filter(|_| query.resolver != Resolver::FakeIp);
+        if let Some(rule) = self.router.read().unwrap().matched_rule(Some(&query.domain), answer) {
+            query.rule = Some(rule.to_string());
+            query.action = rule.action.to_string();
+        }
+        self.dns_log.push(query);
+    }
+
+    /// Use `rules` instead of those of the active profile
+    pub(crate) fn set_rules(&self, rules: Vec<Rule>) {
+        self.profiles.lock().unwrap().active = None;
+        self.router.write().unwrap().set_rules(rules)
+    }
+
+    /// The profile in use, None for the `rules` of the configuration file
+    /// or rules replaced through the API
+    #[inline]
+    pub(crate) fn profile(&self) -> Option<String> {
+        self.profiles.lock().unwrap().active.to_owned()
+    }
+
+    #[inline]
+    pub(crate) fn profile_names(&self) -> Vec<String> {
+        self.profiles.lock().unwrap().named.keys().cloned().collect()
+    }
+
+    /// Use the rules of profile `name`, or the `rules` of the configuration
+    /// file for None
+    pub(crate) fn switch_profile(&self, name: Option<&str>) -> Result<()> {
+        let mut profiles = self.profiles.lock().unwrap();
+        let rules = match name {
+            Some(name) => profiles.named.get(name).ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("No profile named {}", name))
+            })?,
+            None => &profiles.base,
+        };
+        self.router.write().unwrap().set_rules(rules.to_owned());
+        profiles.active = name.map(str::to_string);
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    #[inline]
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    #[inline]
+    pub(crate) fn greeting_timeout(&self) -> Duration {
+        self.greeting_timeout
+    }
+
+    /// A slot for a connection to do its handshake in, held until then.
+    /// None when all of them are taken, as a flood of connections sending
+    /// nothing would have them.
+    pub(crate) fn handshake_slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.handshake_slots.clone().try_acquire_owned().ok()
+    }
+
+    #[inline]
+    pub(crate) fn udp_idle_timeout(&self) -> Duration {
+        self.udp_idle_timeout
+    }
+
+    #[inline]
+    pub(crate) fn udp_client_match(&self) -> ClientMatch {
+        self.udp_client_match
+    }
+
+    #[inline]
+    pub(crate) fn udp_buffers(&self) -> &UdpBufferOptions {
+        &self.udp_buffers
+    }
+
+    #[inline]
+    pub(crate) fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// Whether protocol tracing is enabled for connections from `ip`
+    #[inline]
+    pub(crate) fn traces(&self, ip: IpAddr) -> bool {
+        self.trace_clients.contains(ip)
+    }
+
+    #[inline]
+    pub(crate) fn trace_filter(&self) -> Option<&CaptureFilter> {
+        self.trace_filter.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn fake_ip(&self) -> Option<&FakeIpPool> {
+        self.fake_ip.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn country_policy(&self) -> Option<&CountryPolicy> {
+        self.country_policy.as_ref()
+    }
+
+    /// The active sessions, with the names of their destinations when known
+    pub(crate) fn named_sessions(&self) -> Vec<Session> {
+        let mut sessions = self.sessions.active();
+        let Some(reverse_names) = &self.reverse_names else {
+            return sessions;
+        };
+        for session in sessions.iter_mut() {
+            if let Ok(destination) = session.destination.parse::<SocketAddr>() {
+                session.destination_name = reverse_names.name_of(destination.ip());
+            }
+        }
+        sessions
+    }
+
+    /// `addr` with a fake IP replaced by the domain it stands for
+    pub(crate) fn unfake(&self, addr: &Address) -> Address {
+        if let (Some(fake_ip), Address::IP(socket_addr)) = (&self.fake_ip, addr) {
+            if let Some(domain) = fake_ip.domain_of(&socket_addr.ip()) {
+                return Address::Domain(domain, socket_addr.port());
+            }
+        }
+        addr.to_owned()
+    }
+
+    /// Re-read the configuration file and apply the settings that can
+    /// change at runtime, i.e. the rules and profiles, staying on the active
+    /// profile if it is still there, and the policies of the users
+    /// Load and check the configuration file without applying it, returns
+    /// it along with the rules a reload would put in use: those of the
+    /// active profile if the file still has it, the base ones otherwise
+    pub(crate) fn stage_config(&self) -> Result<(Config, Vec<Rule>)> {
+        let Some(config_path) = &self.config_path else {
+            return Err(Error::new(ErrorKind::NotFound, "No configuration file in use"));
+        };
+        let config = Config::load(Some(config_path), &self.overrides)?;
+        let rules = self
+            .profile()
+            .and_then(|name| config.profiles.get(&name))
+            .unwrap_or(&config.rules)
+            .to_owned();
+        Ok((config, rules))
+    }
+
+    /// Re-read the configuration file and apply it. With a confirm window,
+    /// the configuration as of before is restored unless [confirm_reload]
+    /// follows within it, which is returned along.
+    ///
+    /// [confirm_reload]: AppState::confirm_reload
+    pub(crate) fn reload_config(self: &Arc<Self>) -> Result<(Config, Option<Duration>)> {
+        let (config, _) = self.stage_config()?;
+        let previous = self.snapshot();
+        let active = {
+            let mut profiles = self.profiles.lock().unwrap();
+            profiles.base = config.rules.to_owned();
+            profiles.named = config.profiles.to_owned();
+            profiles.active.take().filter(|name| profiles.named.contains_key(name))
+        };
+        self.switch_profile(active.as_deref())?;
+        self.users.set_policies(config.auth.as_ref());
+        *self.auth.lock().unwrap() = config.auth.to_owned();
+
+        let Some(window) = self.confirm_window else {
+            return Ok((config, None));
+        };
+        let id = self.reloads.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut pending = self.pending_reload.lock().unwrap();
+            /* Back to the last confirmed configuration, not to another
+             * unconfirmed one */
+            let previous = pending.take().map_or(previous, |pending| pending.previous);
+            *pending = Some(PendingReload { id, deadline: Instant::now() + window, previous });
+        }
+        let state = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            state.roll_back(id);
+        });
+        Ok((config, Some(window)))
+    }
+
+    /// Keep what the pending reload applied, false without one
+    #[inline]
+    pub(crate) fn confirm_reload(&self) -> bool {
+        self.pending_reload.lock().unwrap().take().is_some()
+    }
+
+    /// The time left to confirm the pending reload, if any
+    pub(crate) fn reload_confirm_left(&self) -> Option<Duration> {
+        let pending = self.pending_reload.lock().unwrap();
+        pending.as_ref().map(|pending| pending.deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            profiles: self.profiles.lock().unwrap().clone(),
+            rules: self.rules(),
+            auth: self.auth.lock().unwrap().clone(),
+        }
+    }
+
+    /// Undo reload `id` unless it was confirmed or another came since.
+    /// Rules replaced or profiles switched since are undone along.
+    fn roll_back(&self, id: u64) {
+        let mut pending = self.pending_reload.lock().unwrap();
+        if !matches!(pending.as_ref(), Some(pending) if pending.id == id) {
+            return;
+        }
+        let Snapshot { profiles, rules, auth } = pending.take().unwrap().previous;
+        *self.profiles.lock().unwrap() = profiles;
+        self.router.write().unwrap().set_rules(rules);
+        self.users.set_policies(auth.as_ref());
+        *self.auth.lock().unwrap() = auth;
+        self.log.push(String::from("Reload not confirmed in time, rolled back"));
+        println!("Reload not confirmed in time, rolled back");
+    }
+
+    #[inline]
+    pub(crate) fn request_shutdown(&self) {
+        self.shutdown.notify_one()
+    }
+
+    #[inline]
+    pub(crate) async fn shutdown_requested(&self) {
+        self.shutdown.notified().await
+    }
+
+    #[inline]
+    pub(crate) fn draining_signal(&self) -> watch::Receiver<bool> {
+        self.draining.subscribe()
+    }
+
+    #[inline]
+    pub(crate) fn draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Stop accepting, then wait for the connections being served to finish
+    /// for up to the drain timeout, returns how many had to be aborted
+    pub(crate) async fn drain(&self) -> usize {
+        self.draining.send_replace(true);
+        self.tasks.shutdown(self.drain_timeout).await
+    }
+
+    #[inline]
+    pub(crate) fn handoff_signal(&self) -> watch::Receiver<bool> {
+        self.handoff.subscribe()
+    }
+
+    #[inline]
+    pub(crate) fn handing_off(&self) -> bool {
+        *self.handoff.borrow()
+    }
+
+    /// Stop accepting and pause the sessions for a hot upgrade
+    #[inline]
+    pub(crate) fn start_handoff(&self) {
+        self.handoff.send_replace(true);
+    }
+
+    /// Keep a listener around to hand it over on a hot upgrade
+    pub(crate) fn register_listener(&self, addr: SocketAddr, fd: OwnedFd) {
+        self.listeners.lock().unwrap().push((addr, fd))
+    }
+
+    #[inline]
+    pub(crate) fn take_listeners(&self) -> Vec<(SocketAddr, OwnedFd)> {
+        std::mem::take(&mut self.listeners.lock().unwrap())
+    }
+
+    #[inline]
+    pub(crate) fn park(&self, parked: ParkedSession) {
+        self.parked.lock().unwrap().push(parked)
+    }
+
+    #[inline]
+    pub(crate) fn take_parked(&self) -> Vec<ParkedSession> {
+        std::mem::take(&mut self.parked.lock().unwrap())
+    }
+
+    #[cfg(feature = "wireguard")]
+    #[inline]
+    pub(crate) fn set_wireguard(&self, wireguard: nstream_core::WireGuard) {
+        let _ = self.wireguard.set(wireguard);
+    }
+
+    #[cfg(feature = "wireguard")]
+    #[inline]
+    pub(crate) fn wireguard(&self) -> Option<&nstream_core::WireGuard> {
+        self.wireguard.get()
+    }
+
+    #[cfg(feature = "ssh")]
+    #[inline]
+    pub(crate) fn set_ssh(&self, ssh: nstream_core::Ssh, router: Router) {
+        let _ = self.ssh.set((ssh, router));
+    }
+
+    #[cfg(feature = "ssh")]
+    #[inline]
+    pub(crate) fn ssh(&self) -> Option<&(nstream_core::Ssh, Router)> {
+        self.ssh.get()
+    }
+
+    #[cfg(feature = "knock")]
+    #[inline]
+    pub(crate) fn set_knock(&self, gate: nstream_core::KnockGate) {
+        let _ = self.knock.set(gate);
+    }
+
+    #[cfg(feature = "knock")]
+    #[inline]
+    pub(crate) fn knock(&self) -> Option<&nstream_core::KnockGate> {
+        self.knock.get()
+    }
+
+    /// Whether a connection from `ip` gets past `[knock]`, those from
+    /// loopback always do
+    #[allow(unused_variables)]
+    pub(crate) fn knocked(&self, ip: IpAddr) -> bool {
+        #[cfg(feature = "knock")]
+        if let Some(gate) = self.knock() {
+            return ip.to_canonical().is_loopback() || gate.is_open(ip);
+        }
+        true
+    }
+
+    #[inline]
+    pub(crate) fn set_vtun(&self, vtun: VTun) {
+        self.vtun.lock().unwrap().replace(vtun);
+    }
+
+    #[inline]
+    pub(crate) fn take_vtun(&self) -> Option<VTun> {
+        self.vtun.lock().unwrap().take()
+    }
+
+    /// That of the tunnel interface, once it is up
+    #[inline]
+    pub(crate) fn tun_capture(&self) -> Option<TunCapture> {
+        self.vtun.lock().unwrap().as_ref().map(|vtun| vtun.capture().clone())
+    }
+
+    #[inline]
+    pub(crate) fn set_firewall(&self, firewall: Firewall) {
+        self.firewall.lock().unwrap().replace(firewall);
+    }
+
+    #[inline]
+    pub(crate) fn take_firewall(&self) -> Option<Firewall> {
+        self.firewall.lock().unwrap().take()
+    }
+
+    #[inline]
+    pub(crate) fn set_routes(&self, routes: Routes) {
+        self.routes.lock().unwrap().replace(routes);
+    }
+
+    #[inline]
+    pub(crate) fn take_routes(&self) -> Option<Routes> {
+        self.routes.lock().unwrap().take()
+    }
+}
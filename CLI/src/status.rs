@@ -0,0 +1,27 @@
+//! `nstream status`, the status of the running instance on the terminal
+
+use std::io::{Error, Result};
+
+use crate::admin::Status;
+use crate::config::AdminConfig;
+use crate::tail::admin_get;
+
+/// Print what `GET /status` of the running instance tells
+pub(crate) async fn run(admin_config: &AdminConfig) -> Result<()> {
+    let body = hyper::body::to_bytes(admin_get(admin_config, "/status").await?.into_body())
+        .await
+        .map_err(Error::other)?;
+    let status: Status = serde_json::from_slice(&body)?;
+    println!("nstream {}, up {}s", status.version, status.uptime_secs);
+    println!("Sessions          {}", status.active_sessions);
+    println!("Connections       {}", status.connections);
+    println!("UDP associations  {}", status.udp_associations);
+    println!("Profile           {}", status.profile.as_deref().unwrap_or("rules"));
+    if status.draining {
+        println!("Draining connections before exiting");
+    }
+    if let Some(left) = status.reload_confirm_secs {
+        println!("The last reload is rolled back unless confirmed within {}s", left);
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+//! `nstream tail`, a stream of the management API on the terminal
+
+use std::io::{Error, ErrorKind, Result};
+
+use hyper::body::HttpBody;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Request, Response};
+use tokio::io::AsyncWriteExt;
+
+use crate::args::{TailArgs, TailStream};
+use crate::config::AdminConfig;
+
+/// Copy the stream `args` picks to the standard output until the running
+/// instance closes it
+pub(crate) async fn run(args: &TailArgs, admin_config: &AdminConfig) -> Result<()> {
+    let path = match args.stream {
+        TailStream::Events => "/events",
+        TailStream::Dns => "/dns/queries",
+    };
+    let mut body = admin_get(admin_config, path).await?.into_body();
+    let mut stdout = tokio::io::stdout();
+    while let Some(chunk) = body.data().await {
+        stdout.write_all(&chunk.map_err(Error::other)?).await?;
+        stdout.flush().await?;
+    }
+    Ok(())
+}
+
+/// `GET path` of the management API of the running instance, failing
+/// unless it succeeds
+pub(crate) async fn admin_get(admin_config: &AdminConfig, path: &str) -> Result<Response<Body>> {
+    let mut req = Request::get(format!("http://{}{}", admin_config.listen, path));
+    if let Some(token) = &admin_config.token {
+        req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let req = req.body(Body::empty()).map_err(Error::other)?;
+    let resp = Client::new().request(req).await.map_err(|e| {
+        Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Management API on {}: {}", admin_config.listen, e),
+        )
+    })?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        return Err(Error::other(format!("{}: {}", status, String::from_utf8_lossy(&body))));
+    }
+    Ok(resp)
+}
@@ -0,0 +1,88 @@
+//! Registry of the tasks spawned per connection
+//!
+//! Tasks are held in a [JoinSet] rather than detached, so that shutdown can
+//! wait for them to finish, and a task that panics is logged along with its
+//! label instead of vanishing.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::{Id, JoinError, JoinSet};
+use tokio::time::Instant;
+
+#[derive(Debug, Default)]
+struct Inner {
+    set: JoinSet<()>,
+    labels: HashMap<Id, String>,
+}
+
+impl Inner {
+    fn finished(&mut self, ret: Result<(Id, ()), JoinError>) {
+        match ret {
+            Ok((id, ())) => {
+                self.labels.remove(&id);
+            }
+            Err(e) => {
+                let label = self.labels.remove(&e.id()).unwrap_or_default();
+                if e.is_panic() {
+                    eprintln!("Task {} panicked; error: {:?}", label, e);
+                }
+            }
+        }
+    }
+
+    /// Collect the tasks that have finished so far
+    fn reap(&mut self) {
+        while let Some(ret) = self.set.try_join_next_with_id() {
+            self.finished(ret);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tasks {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Tasks {
+    /// Spawn `fut` as a task named `label` in logs, its output is dropped
+    pub(crate) fn spawn<F>(&self, label: String, fut: F)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        inner.reap();
+        let abort_handle = inner.set.spawn(async move {
+            fut.await;
+        });
+        inner.labels.insert(abort_handle.id(), label);
+    }
+
+    /// How many tasks are still running
+    pub(crate) fn running(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.reap();
+        inner.set.len()
+    }
+
+    /// Wait up to `timeout` for the tasks to finish, then abort the rest,
+    /// returns how many were aborted
+    pub(crate) async fn shutdown(&self, timeout: Duration) -> usize {
+        let mut inner = std::mem::take(&mut *self.inner.lock().unwrap());
+        let deadline = Instant::now() + timeout;
+        while let Ok(Some(ret)) =
+            tokio::time::timeout_at(deadline, inner.set.join_next_with_id()).await
+        {
+            inner.finished(ret);
+        }
+        let aborted = inner.set.len();
+        inner.set.abort_all();
+        while let Some(ret) = inner.set.join_next_with_id().await {
+            inner.finished(ret);
+        }
+        aborted
+    }
+}
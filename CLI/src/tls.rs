@@ -0,0 +1,186 @@
+//! SOCKS over TLS on the SOCKS listeners, the `[tls]` section
+//!
+//! Clients which speak SOCKS over TLS connect to the same listeners, whose
+//! accepted connections complete a TLS handshake with the certificate of
+//! the section before the SOCKS one. The handshake is that of rustls, and
+//! the acceptor takes any stream: what is handed to it is no more bound to
+//! be a [tokio::net::TcpStream] than what it returns.
+//!
+//! Without the `tls` feature the section is refused at startup, the
+//! [TlsTerminator] of such a build cannot be made.
+
+use std::io::Result;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::config::TlsSection;
+
+#[cfg(feature = "tls")]
+mod rustls_acceptor {
+    use std::fs::File;
+    use std::io::{BufReader, Error, ErrorKind, Result};
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use socks5::stream::Shutdown;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio_rustls::rustls::{crypto, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::config::TlsSection;
+
+    fn invalid(e: impl std::fmt::Display) -> Error {
+        Error::new(ErrorKind::InvalidInput, format!("tls: {}", e))
+    }
+
+    fn open(path: &Path) -> Result<BufReader<File>> {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| Error::new(e.kind(), format!("tls: {}: {}", path.display(), e)))
+    }
+
+    /// The chain of `cert`, the certificate of the listeners first
+    fn load_certs(cert: &Path) -> Result<Vec<CertificateDer<'static>>> {
+        let certs = rustls_pemfile::certs(&mut open(cert)?).collect::<Result<Vec<_>>>()?;
+        match certs.is_empty() {
+            true => Err(invalid(format!("{}: No certificate", cert.display()))),
+            false => Ok(certs),
+        }
+    }
+
+    /// The first key of `key`, in PKCS #8, PKCS #1 or SEC1
+    fn load_key(key: &Path) -> Result<PrivateKeyDer<'static>> {
+        rustls_pemfile::private_key(&mut open(key)?)?
+            .ok_or_else(|| invalid(format!("{}: No private key", key.display())))
+    }
+
+    #[derive(Clone)]
+    pub(crate) struct TlsTerminator(TlsAcceptor);
+
+    impl TlsTerminator {
+        pub(crate) fn new(section: &TlsSection) -> Result<Self> {
+            let certs = load_certs(&section.cert)?;
+            let key = load_key(&section.key)?;
+            let provider = Arc::new(crypto::ring::default_provider());
+            let config = ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .map_err(invalid)?
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(invalid)?;
+            Ok(Self(TlsAcceptor::from(Arc::new(config))))
+        }
+
+        pub(crate) async fn accept<S>(&self, stream: S) -> Result<TlsStream<S>>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+        {
+            self.0.accept(stream).await.map(TlsStream)
+        }
+    }
+
+    /// The decrypted stream of a client
+    #[derive(Debug)]
+    pub(crate) struct TlsStream<S = TcpStream>(tokio_rustls::server::TlsStream<S>);
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        }
+
+        /// The close_notify alert, then the shutdown of the underlying stream
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+        }
+    }
+
+    /// Without a close_notify, the underlying stream aborted
+    impl<S: AsyncRead + Shutdown> Shutdown for TlsStream<S> {
+        fn abort(self) {
+            self.0.into_inner().0.abort()
+        }
+    }
+
+    impl crate::accepted::Accepted for TlsStream {
+        fn socket(&self) -> &TcpStream {
+            self.0.get_ref().0
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+pub(crate) use rustls_acceptor::TlsStream;
+#[cfg(feature = "tls")]
+use rustls_acceptor::TlsTerminator as Inner;
+
+/// Never accepted without the `tls` feature, only keeps the types of the
+/// listeners those of a build with it
+#[cfg(not(feature = "tls"))]
+pub(crate) type TlsStream<S = tokio::net::TcpStream> = S;
+
+/// What a build without the `tls` feature has of it, never made
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+enum Inner {}
+
+#[cfg(not(feature = "tls"))]
+impl Inner {
+    fn new(_section: &TlsSection) -> Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "[tls] is configured, this build is without the tls feature",
+        ))
+    }
+
+    async fn accept<S>(&self, _stream: S) -> Result<S> {
+        match *self {}
+    }
+}
+
+/// Completes the TLS handshakes of the clients of the SOCKS listeners
+#[derive(Clone)]
+pub(crate) struct TlsTerminator(Inner);
+
+impl std::fmt::Debug for TlsTerminator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsTerminator").finish_non_exhaustive()
+    }
+}
+
+impl TlsTerminator {
+    /// Loads the certificate and key of `section`
+    pub(crate) fn new(section: &TlsSection) -> Result<Self> {
+        Inner::new(section).map(Self)
+    }
+
+    /// The decrypted stream of `stream`, once its client completed the
+    /// handshake
+    pub(crate) async fn accept<S>(&self, stream: S) -> Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.0.accept(stream).await
+    }
+}
@@ -0,0 +1,239 @@
+//! SOCKS over TLS with pre-shared keys, the `[tls_psk]` section
+//!
+//! Devices which cannot manage certificates authenticate with a key shared
+//! with the proxy, picked by the identity they present. The identity maps
+//! to a user, whose policy in `[auth.users]` applies to the device, so the
+//! SOCKS handshake within asks for no credentials. rustls has no support
+//! for external pre-shared keys, the handshake is that of OpenSSL: TLS 1.3
+//! with a PSK of SHA-256 suites, or the PSK cipher suites of TLS 1.2 for
+//! older stacks.
+//!
+//! The decrypted stream is dispatched to the SOCKS handlers as any
+//! [crate::accepted::Accepted] connection.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use openssl::ex_data::Index;
+use openssl::ssl::{self, ErrorCode, Ssl, SslContext, SslMethod, SslStream, SslVersion};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::config::{parse_key, TlsPskSection};
+
+/// For the clients of TLS 1.2, ephemeral key exchanges first
+const TLS12_CIPHERS: &str = "ECDHE-PSK-CHACHA20-POLY1305:ECDHE-PSK-AES256-CBC-SHA384:\
+                             ECDHE-PSK-AES128-CBC-SHA256:PSK-CHACHA20-POLY1305:\
+                             PSK-AES256-GCM-SHA384:PSK-AES128-GCM-SHA256";
+
+/// A device, known by its identity
+struct Device {
+    key: Vec<u8>,
+    user: String,
+}
+
+/// Without the key
+impl std::fmt::Debug for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Device").field("user", &self.user).finish_non_exhaustive()
+    }
+}
+
+/// Completes the handshakes of the devices of a `[tls_psk]` section
+#[derive(Clone)]
+pub(crate) struct TlsPskAcceptor {
+    context: SslContext,
+    /// Where the key lookup leaves the user of the device, OpenSSL only
+    /// tells the identity of a TLS 1.2 handshake
+    user: Index<Ssl, String>,
+}
+
+impl std::fmt::Debug for TlsPskAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsPskAcceptor").finish_non_exhaustive()
+    }
+}
+
+impl TlsPskAcceptor {
+    pub(crate) fn new(section: &TlsPskSection) -> Result<Self> {
+        let invalid = |e: String| Error::new(ErrorKind::InvalidInput, format!("tls_psk: {}", e));
+        if section.identities.is_empty() {
+            return Err(invalid(String::from("No identities")));
+        }
+        let mut devices = HashMap::new();
+        for (identity, device) in section.identities.iter() {
+            let key =
+                parse_key(&device.key).map_err(|e| invalid(format!("{}: {}", identity, e)))?;
+            if device.user.is_empty() {
+                return Err(invalid(format!("{}: No user", identity)));
+            }
+            devices.insert(identity.to_owned(), Device { key, user: device.user.to_owned() });
+        }
+        let user = Ssl::new_ex_index::<String>().map_err(Error::other)?;
+
+        let mut builder = SslContext::builder(SslMethod::tls_server()).map_err(Error::other)?;
+        builder.set_min_proto_version(Some(SslVersion::TLS1_2)).map_err(Error::other)?;
+        builder.set_cipher_list(TLS12_CIPHERS).map_err(Error::other)?;
+        builder.set_psk_server_callback(move |ssl, identity, psk| {
+            /* Zero bytes for an unknown identity, which fails the handshake */
+            let device = identity
+                .and_then(|identity| std::str::from_utf8(identity).ok())
+                .and_then(|identity| devices.get(identity))
+                .filter(|device| device.key.len() <= psk.len());
+            Ok(device.map_or(0, |device| {
+                ssl.set_ex_data(user, device.user.to_owned());
+                psk[..device.key.len()].copy_from_slice(&device.key);
+                device.key.len()
+            }))
+        });
+        Ok(Self { context: builder.build(), user })
+    }
+
+    /// The decrypted stream of the device connected by `tcp_stream`, along
+    /// with the user its identity maps to
+    pub(crate) async fn accept(&self, tcp_stream: TcpStream) -> Result<(TlsStream, String)> {
+        let ssl = Ssl::new(&self.context).map_err(Error::other)?;
+        let ssl_stream = SslStream::new(ssl, Blocking { stream: tcp_stream, context: 0 })
+            .map_err(Error::other)?;
+        let mut tls_stream = TlsStream(ssl_stream);
+        std::future::poll_fn(|cx| {
+            tls_stream.with_context(cx, |ssl_stream| ssl_stream.accept().map_err(io_error))
+        })
+        .await?;
+        /* Not completed with a key other than that of a known identity */
+        let user = tls_stream
+            .0
+            .ssl()
+            .ex_data(self.user)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "No known identity"))?;
+        Ok((tls_stream, user))
+    }
+}
+
+/// Would-block errors as such, failures of the handshake as invalid data
+fn io_error(e: ssl::Error) -> Error {
+    match e.code() {
+        ErrorCode::WANT_READ | ErrorCode::WANT_WRITE => ErrorKind::WouldBlock.into(),
+        _ => e.into_io_error().unwrap_or_else(|e| Error::new(ErrorKind::InvalidData, e)),
+    }
+}
+
+/// A stream of the runtime as OpenSSL expects a blocking one to be, whose
+/// pending operations fail as would-block. Only polled within
+/// [TlsStream::with_context], which sets the context to wake the task by.
+#[derive(Debug)]
+struct Blocking {
+    stream: TcpStream,
+    /// The address of the [Context] of the current poll, an address rather
+    /// than a pointer for the stream to be [Send]
+    context: usize,
+}
+
+impl Blocking {
+    fn poll<T>(
+        &mut self,
+        f: impl FnOnce(Pin<&mut TcpStream>, &mut Context<'_>) -> Poll<Result<T>>,
+    ) -> Result<T> {
+        assert_ne!(self.context, 0, "Polled outside of TlsStream::with_context");
+        /* SAFETY: the context outlives the call of with_context that set it */
+        let cx = unsafe { &mut *(self.context as *mut Context<'_>) };
+        match f(Pin::new(&mut self.stream), cx) {
+            Poll::Ready(ret) => ret,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Read for Blocking {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.poll(|stream, cx| {
+            let mut buf = ReadBuf::new(buf);
+            stream.poll_read(cx, &mut buf).map_ok(|()| buf.filled().len())
+        })
+    }
+}
+
+impl Write for Blocking {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.poll(|stream, cx| stream.poll_write(cx, buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.poll(|stream, cx| stream.poll_flush(cx))
+    }
+}
+
+/// The decrypted stream of a device
+#[derive(Debug)]
+pub(crate) struct TlsStream(SslStream<Blocking>);
+
+/// Dropped on abort, the session cannot be told apart from its socket
+impl socks5::stream::Shutdown for TlsStream {}
+
+impl crate::accepted::Accepted for TlsStream {
+    fn socket(&self) -> &TcpStream {
+        &self.0.get_ref().stream
+    }
+}
+
+impl TlsStream {
+    /// `f` on the stream, which may poll the underlying one with `cx`
+    fn with_context<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(&mut SslStream<Blocking>) -> Result<T>,
+    ) -> Poll<Result<T>> {
+        self.0.get_mut().context = cx as *mut Context<'_> as usize;
+        let ret = f(&mut self.0);
+        self.0.get_mut().context = 0;
+        match ret {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Poll::Pending,
+            ret => Poll::Ready(ret),
+        }
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        self.get_mut().with_context(cx, |ssl_stream| {
+            let n = ssl_stream.read(buf.initialize_unfilled())?;
+            buf.advance(n);
+            Ok(())
+        })
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.get_mut().with_context(cx, |ssl_stream| ssl_stream.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().with_context(cx, |ssl_stream| ssl_stream.flush())
+    }
+
+    /// The close_notify alert, then the shutdown of the underlying stream
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let close_notify = this.with_context(cx, |ssl_stream| match ssl_stream.shutdown() {
+            Ok(_) => Ok(()),
+            Err(e) if e.code() == ErrorCode::ZERO_RETURN => Ok(()),
+            Err(e) => Err(io_error(e)),
+        });
+        match close_notify {
+            Poll::Ready(Ok(())) => {}
+            /* The device may be gone already */
+            Poll::Ready(Err(e)) if e.kind() == ErrorKind::NotConnected => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        Pin::new(&mut this.0.get_mut().stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,247 @@
+//! Hot upgrade without dropping connections
+//!
+//! A running process started with `--upgrade-socket <PATH>` listens on that
+//! Unix socket. A new process started with the same path connects to it and
+//! the old process then:
+//!
+//! 1. stops accepting and pauses its CONNECT sessions between two chunks,
+//!    so that no relayed data is buffered in the process
+//! 2. sends the listeners, then the client and upstream sockets of every
+//!    session along with its state, then the traffic totals
+//! 3. exits, leaving the system proxy settings in place
+//!
+//! Each message is a length-prefixed JSON document, the file descriptors
+//! are passed as `SCM_RIGHTS` ancillary data of its first byte.
+//! UDP ASSOCIATE sessions are not handed over, nor are those of clients
+//! over TLS, whose session the new process would not have.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nstream_core::fdpass::{recv_with_fds, send_with_fds};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixListener};
+use tokio::sync::watch;
+
+use crate::accepted::Accepted;
+use crate::session::{Session, Traffic};
+use crate::state::AppState;
+use crate::users::RateLimiter;
+
+/// How long to wait for the sessions to pause before handing over
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    /// Comes with the listener socket
+    Listener {
+        addr: SocketAddr,
+    },
+    /// Comes with the client and the upstream sockets
+    Session {
+        session: Session,
+    },
+    Traffic {
+        traffic: HashMap<String, Traffic>,
+    },
+    Done,
+}
+
+/// A session paused for a hot upgrade
+#[derive(Debug)]
+pub(crate) struct ParkedSession {
+    session: Session,
+    client: OwnedFd,
+    upstream: OwnedFd,
+}
+
+/// What the new process receives from the old one
+#[derive(Debug, Default)]
+pub(crate) struct Takeover {
+    pub(crate) listeners: Vec<std::net::TcpListener>,
+    pub(crate) sessions: Vec<(Session, std::net::TcpStream, std::net::TcpStream)>,
+    pub(crate) traffic: HashMap<String, Traffic>,
+}
+
+/// Copy from `r` to `w` until EOF, or until a hot upgrade is signaled,
+/// returns whether it is paused and the number of bytes copied.
+/// `on_copied` is told about every chunk copied, which `limiter` then
+/// holds the next one back for.
+async fn pump<R, W>(
+    r: &mut R,
+    w: &mut W,
+    mut handoff: watch::Receiver<bool>,
+    limiter: Option<&RateLimiter>,
+    on_copied: impl Fn(u64),
+) -> Result<(bool, u64)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    let mut copied = 0u64;
+    loop {
+        let len = tokio::select! {
+            ret = r.read(&mut buf) => ret?,
+            _ = handoff.wait_for(|handing_off| *handing_off) => return Ok((true, copied)),
+        };
+        if len == 0 {
+            w.shutdown().await?;
+            return Ok((false, copied));
+        }
+        w.write_all(&buf[..len]).await?;
+        copied += len as u64;
+        on_copied(len as u64);
+        if let Some(limiter) = limiter {
+            limiter.consume(len as u64).await;
+        }
+    }
+}
+
+/// Relay a CONNECT session until both sides are closed, or until it is
+/// parked for a hot upgrade. Returns whether it is parked, in which case
+/// the sockets must be left open. Both directions count against `limiter`.
+/// A `client` which is not [Accepted::is_plain] is closed on a hot upgrade.
+pub(crate) async fn relay_session<S: Accepted>(
+    state: &AppState,
+    id: u64,
+    upstream: &mut TcpStream,
+    client: &mut S,
+    limiter: Option<&RateLimiter>,
+) -> Result<bool> {
+    let relay_ret = {
+        let (mut client_r, mut client_w) = tokio::io::split(&mut *client);
+        let (mut upstream_r, mut upstream_w) = upstream.split();
+        tokio::try_join!(
+            pump(&mut client_r, &mut upstream_w, state.handoff_signal(), limiter, |len| {
+                state.sessions.relayed(id, len, 0)
+            }),
+            pump(&mut upstream_r, &mut client_w, state.handoff_signal(), limiter, |len| {
+                state.sessions.relayed(id, 0, len)
+            }),
+        )
+    };
+    let ((sent_paused, bytes_sent), (received_paused, bytes_received)) = match relay_ret {
+        Ok(ret) => ret,
+        Err(e) => {
+            state.sessions.close(id, 0, 0);
+            return Err(e);
+        }
+    };
+    if !(sent_paused || received_paused) || !client.is_plain() {
+        state.sessions.close(id, bytes_sent, bytes_received);
+        return Ok(false);
+    }
+    if let Some(session) = state.sessions.take(id, bytes_sent, bytes_received) {
+        state.park(ParkedSession {
+            session,
+            client: client.socket().as_fd().try_clone_to_owned()?,
+            upstream: upstream.as_fd().try_clone_to_owned()?,
+        });
+    }
+    Ok(true)
+}
+
+fn send_message(sock: &UnixStream, msg: &Message, fds: &[RawFd]) -> Result<()> {
+    let body = serde_json::to_vec(msg)?;
+    let mut data = (body.len() as u32).to_be_bytes().to_vec();
+    data.extend(body);
+    let len = send_with_fds(sock, &data, fds)?;
+    (&*sock).write_all(&data[len..])
+}
+
+fn recv_message(sock: &UnixStream) -> Result<(Message, Vec<OwnedFd>)> {
+    let mut len_buf = [0u8; 4];
+    let (len, fds) = recv_with_fds(sock, &mut len_buf)?;
+    if len == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Handoff aborted by the old process"));
+    }
+    (&*sock).read_exact(&mut len_buf[len..])?;
+    let mut body = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+    (&*sock).read_exact(&mut body)?;
+    Ok((serde_json::from_slice(&body)?, fds))
+}
+
+/// Wait for a new process on `path`, then hand everything over to it.
+/// Returns once the handoff is done, the caller is expected to exit.
+pub(crate) async fn serve_handoff(path: PathBuf, state: Arc<AppState>) -> Result<()> {
+    let _ = std::fs::remove_file(&path);
+    let unix_listener = UnixListener::bind(&path)?;
+    let (unix_stream, _) = unix_listener.accept().await?;
+    println!("Handing over to a new process");
+
+    state.start_handoff();
+    let deadline = Instant::now() + PAUSE_TIMEOUT;
+    while Instant::now() < deadline
+        && state.sessions.active().iter().any(|session| session.command == "CONNECT")
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let unix_stream = unix_stream.into_std()?;
+    unix_stream.set_nonblocking(false)?;
+    let listeners = state.take_listeners();
+    let parked = state.take_parked();
+    let traffic = state.sessions.traffic();
+    tokio::task::spawn_blocking(move || {
+        for (addr, fd) in listeners {
+            send_message(&unix_stream, &Message::Listener { addr }, &[fd.as_raw_fd()])?;
+        }
+        for ParkedSession { session, client, upstream } in parked {
+            let fds = [client.as_raw_fd(), upstream.as_raw_fd()];
+            send_message(&unix_stream, &Message::Session { session }, &fds)?;
+        }
+        send_message(&unix_stream, &Message::Traffic { traffic }, &[])?;
+        send_message(&unix_stream, &Message::Done, &[])
+    })
+    .await
+    .map_err(Error::other)?
+}
+
+/// Take over from the process listening on `path`, if there is one
+pub(crate) fn take_over(path: &Path) -> Result<Option<Takeover>> {
+    let unix_stream = match UnixStream::connect(path) {
+        Ok(unix_stream) => unix_stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut takeover = Takeover::default();
+    loop {
+        let (msg, mut fds) = recv_message(&unix_stream)?;
+        let expected_fds = match msg {
+            Message::Listener { .. } => 1,
+            Message::Session { .. } => 2,
+            Message::Traffic { .. } | Message::Done => 0,
+        };
+        if fds.len() != expected_fds {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Expected {} file descriptors, got {}", expected_fds, fds.len()),
+            ));
+        }
+        match msg {
+            Message::Listener { .. } => takeover.listeners.push(fds.remove(0).into()),
+            Message::Session { session } => {
+                let client = fds.remove(0).into();
+                let upstream = fds.remove(0).into();
+                takeover.sessions.push((session, client, upstream));
+            }
+            Message::Traffic { traffic } => takeover.traffic = traffic,
+            Message::Done => break,
+        }
+    }
+    Ok(Some(takeover))
+}
@@ -0,0 +1,355 @@
+//! Users authenticating with a name and password (RFC 1929)
+//!
+//! The credentials live in an htpasswd-style file, one `name:hash` line per
+//! user where the hash is an argon2 PHC string, which `POST /users` and
+//! `DELETE /users/<name>` rewrite. What each user may reach, and how fast,
+//! is set by the `[auth.users]` section of the configuration file. As long
+//! as there is no user, clients connect without authenticating.
+//!
+//! Clients connecting from a `trusted` subnet are not asked for
+//! credentials, a SOCKS5 client among them still gets to authenticate when
+//! it offers no other method.
+//!
+//! With `strict_udp`, UDP ASSOCIATE is refused to clients that neither
+//! authenticated nor connect from localhost or a trusted subnet.
+//!
+//! The credentials generated at startup, which the system proxy is set to
+//! and `nstream share` prints, are accepted for the run without being
+//! saved to the file.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use advanced_random_string::{charset, random_string};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use nstream_core::{
+    ByteRate, DecisionCache, DecisionKey, DecisionStats, IpCidr, Router, Rule, RuleAction,
+    DEFAULT_DECISION_CACHE_CAPACITY,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use socks5::protocol::Address;
+use tokio::time::Instant;
+
+use crate::config::AuthConfig;
+
+/// ULEN and PLEN are single octets
+const MAX_CREDENTIAL_LEN: usize = 255;
+
+/// Token bucket capping the bytes relayed per second, shared by all the
+/// sessions of a user
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: f64,
+    /// Bytes that may be relayed right away, negative when owed, as of when
+    /// it was last refilled
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: ByteRate) -> Self {
+        let rate = rate.bytes_per_sec().max(1) as f64;
+        Self { rate, bucket: Mutex::new((rate, Instant::now())) }
+    }
+
+    /// Account for `len` bytes relayed, waiting until the rate allows them
+    pub(crate) async fn consume(&self, len: u64) {
+        let owed = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (tokens, refilled_at) = &mut *bucket;
+            let now = Instant::now();
+            /* At most a second worth of bytes is saved up */
+            let refill = now.duration_since(*refilled_at).as_secs_f64() * self.rate;
+            *tokens = (*tokens + refill).min(self.rate) - len as f64;
+            *refilled_at = now;
+            -*tokens
+        };
+        if owed > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(owed / self.rate)).await;
+        }
+    }
+}
+
+/// A user as listed by `GET /users`
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct UserStatus {
+    pub(crate) name: String,
+    /// The rules deciding the destinations of the user, none when
+    /// unrestricted
+    pub(crate) rules: Vec<String>,
+    /// e.g. `"1MB/s"`, null when unlimited
+    pub(crate) rate: Option<String>,
+}
+
+/// The body of `POST /users`, which replaces the password of an existing
+/// user
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct UserRequest {
+    pub(crate) name: String,
+    pub(crate) password: String,
+}
+
+#[derive(Debug)]
+struct Policy {
+    router: Router,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+/// The credential store, with the policy of each user
+#[derive(Debug, Default)]
+pub(crate) struct Users {
+    /// Where the hashes are saved, they only live in memory without one
+    file: Option<PathBuf>,
+    hashes: RwLock<BTreeMap<String, String>>,
+    /// Accepted besides `hashes` until the process exits, never saved
+    run_hashes: RwLock<BTreeMap<String, String>>,
+    /// Replaced on reload
+    policies: RwLock<HashMap<String, Policy>>,
+    /// What the rules of the users decided, by user and destination
+    decisions: DecisionCache,
+    /// Subnets whose clients need not authenticate, replaced on reload
+    trusted: RwLock<Vec<IpCidr>>,
+    strict_udp: bool,
+}
+
+fn invalid_input(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/// The `name:hash` lines of `path`, none when it does not exist yet
+fn read_file(path: &Path) -> Result<BTreeMap<String, String>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut hashes = BTreeMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |msg: &str| {
+            Error::new(ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), i + 1, msg))
+        };
+        let (name, hash) = line.split_once(':').ok_or_else(|| invalid("Expected name:hash"))?;
+        PasswordHash::new(hash).map_err(|e| invalid(&e.to_string()))?;
+        hashes.insert(name.to_owned(), hash.to_owned());
+    }
+    Ok(hashes)
+}
+
+/// Replace `path` with the lines of `hashes`, readable by the owner only
+fn write_file(path: &Path, hashes: &BTreeMap<String, String>) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    for (name, hash) in hashes {
+        writeln!(file, "{}:{}", name, hash)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(tmp_path, path)
+}
+
+fn policies(config: &AuthConfig) -> HashMap<String, Policy> {
+    config
+        .users
+        .iter()
+        .map(|(name, policy)| {
+            let router = Router::new(policy.rules.to_owned());
+            let limiter = policy.rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+            (name.to_owned(), Policy { router, limiter })
+        })
+        .collect()
+}
+
+/// Whether `name` and `password` fit in RFC 1929 and in a `name:hash` line
+fn check_credential(name: &str, password: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_CREDENTIAL_LEN {
+        return Err(invalid_input(format!("Name of 1 to {} bytes", MAX_CREDENTIAL_LEN)));
+    }
+    if name.contains([':', '\n', '#']) || name.trim() != name {
+        return Err(invalid_input(format!("Invalid character in name {:?}", name)));
+    }
+    if password.is_empty() || password.len() > MAX_CREDENTIAL_LEN {
+        return Err(invalid_input(format!("Password of 1 to {} bytes", MAX_CREDENTIAL_LEN)));
+    }
+    Ok(())
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::encode_b64(random_string::generate(16, charset::BASE62).as_bytes())
+        .map_err(|e| Error::other(e.to_string()))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| Error::other(e.to_string()))?;
+    Ok(hash.to_string())
+}
+
+impl Users {
+    /// Counting the cached decisions into `decision_stats`
+    pub(crate) fn new(config: &AuthConfig, decision_stats: DecisionStats) -> Result<Self> {
+        let hashes = match &config.file {
+            Some(path) => read_file(path)?,
+            None => BTreeMap::new(),
+        };
+        Ok(Self {
+            file: config.file.to_owned(),
+            hashes: RwLock::new(hashes),
+            run_hashes: RwLock::new(BTreeMap::new()),
+            policies: RwLock::new(policies(config)),
+            decisions: DecisionCache::with_stats(
+                config.decision_cache.unwrap_or(DEFAULT_DECISION_CACHE_CAPACITY),
+                decision_stats,
+            ),
+            trusted: RwLock::new(config.trusted.to_owned()),
+            strict_udp: config.strict_udp,
+        })
+    }
+
+    /// Whether clients must authenticate
+    #[inline]
+    pub(crate) fn required(&self) -> bool {
+        !self.hashes.read().unwrap().is_empty()
+    }
+
+    /// Whether `client` is within a trusted subnet
+    pub(crate) fn trusts(&self, client: IpAddr) -> bool {
+        let client = client.to_canonical();
+        self.trusted.read().unwrap().iter().any(|cidr| cidr.contains(&client))
+    }
+
+    /// Whether `client` must authenticate
+    #[inline]
+    pub(crate) fn required_from(&self, client: IpAddr) -> bool {
+        self.required() && !self.trusts(client)
+    }
+
+    /// Whether `password` is that of `name`, hashing it on a blocking thread
+    pub(crate) async fn verify(&self, name: &str, password: &str) -> bool {
+        let hashes = [&self.hashes, &self.run_hashes]
+            .into_iter()
+            .filter_map(|hashes| hashes.read().unwrap().get(name).cloned())
+            .collect::<Vec<_>>();
+        if hashes.is_empty() {
+            return false;
+        }
+        let password = password.to_owned();
+        tokio::task::spawn_blocking(move || {
+            hashes.iter().any(|hash| {
+                PasswordHash::new(hash).is_ok_and(|hash| {
+                    Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()
+                })
+            })
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    fn save(&self, hashes: BTreeMap<String, String>) -> Result<()> {
+        if let Some(path) = &self.file {
+            write_file(path, &hashes)?;
+        }
+        *self.hashes.write().unwrap() = hashes;
+        Ok(())
+    }
+
+    /// Add `name`, or replace their password
+    pub(crate) fn add(&self, name: &str, password: &str) -> Result<()> {
+        check_credential(name, password)?;
+        let mut hashes = self.hashes.read().unwrap().clone();
+        hashes.insert(name.to_owned(), hash_password(password)?);
+        self.save(hashes)
+    }
+
+    /// Accept `password` for `name` until the process exits, without saving
+    /// it, on top of any password `name` already has
+    pub(crate) fn add_for_run(&self, name: &str, password: &str) -> Result<()> {
+        check_credential(name, password)?;
+        let hash = hash_password(password)?;
+        self.run_hashes.write().unwrap().insert(name.to_owned(), hash);
+        Ok(())
+    }
+
+    /// Remove `name`, returns whether there was such a user
+    pub(crate) fn remove(&self, name: &str) -> Result<bool> {
+        let mut hashes = self.hashes.read().unwrap().clone();
+        if hashes.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save(hashes)?;
+        Ok(true)
+    }
+
+    pub(crate) fn list(&self) -> Vec<UserStatus> {
+        let policies = self.policies.read().unwrap();
+        self.hashes
+            .read()
+            .unwrap()
+            .keys()
+            .map(|name| {
+                let policy = policies.get(name);
+                UserStatus {
+                    name: name.to_owned(),
+                    rules: policy.map_or(vec![], |policy| {
+                        policy.router.rules().iter().map(ToString::to_string).collect()
+                    }),
+                    rate: policy
+                        .and_then(|policy| policy.limiter.as_ref())
+                        .map(|limiter| ByteRate(limiter.rate as u64).to_string()),
+                }
+            })
+            .collect()
+    }
+
+    /// The rule of `name` refusing `addr`, None when the user may connect
+    pub(crate) fn blocked_by(&self, name: &str, addr: &Address) -> Option<Rule> {
+        let policies = self.policies.read().unwrap();
+        let policy = policies.get(name)?;
+        let (domain, ip) = match addr {
+            Address::IP(socket_addr) => (None, Some(socket_addr.ip())),
+            Address::Domain(domain, _) => (Some(domain.as_str()), None),
+        };
+        let key = DecisionKey::new(Some(name), domain, ip, None);
+        let action = self.decisions.decide(key, || policy.router.decide(domain, ip));
+        match action {
+            RuleAction::Reject => policy.router.matched_rule(domain, ip).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Apply the policies and trusted subnets of `config`, none without
+    /// one, forgetting the decisions made with the previous. Sessions keep
+    /// the rate they started with.
+    pub(crate) fn set_policies(&self, config: Option<&AuthConfig>) {
+        *self.policies.write().unwrap() = config.map(policies).unwrap_or_default();
+        *self.trusted.write().unwrap() =
+            config.map(|config| config.trusted.to_owned()).unwrap_or_default();
+        self.decisions.invalidate();
+    }
+
+    /// Whether a UDP association may be granted to `client`, authenticated
+    /// as `user` if at all
+    pub(crate) fn allows_udp(&self, user: Option<&str>, client: IpAddr) -> bool {
+        !self.strict_udp
+            || user.is_some()
+            || client.to_canonical().is_loopback()
+            || self.trusts(client)
+    }
+
+    /// What caps the bandwidth of `name`, if anything
+    #[inline]
+    pub(crate) fn limiter(&self, name: &str) -> Option<Arc<RateLimiter>> {
+        self.policies.read().unwrap().get(name).and_then(|policy| policy.limiter.clone())
+    }
+}
@@ -0,0 +1,164 @@
+// Dashboard of the management API, refreshed every few seconds. The token
+// is kept in sessionStorage and sent as a bearer token with every request.
+
+"use strict";
+
+const REFRESH_INTERVAL_MS = 3000;
+
+const $ = (id) => document.getElementById(id);
+
+function token() {
+  return sessionStorage.getItem("nstream-token") || "";
+}
+
+async function api(method, path, body) {
+  const headers = {};
+  if (token()) {
+    headers["Authorization"] = "Bearer " + token();
+  }
+  if (body !== undefined) {
+    headers["Content-Type"] = "application/json";
+  }
+  const resp = await fetch(path, {
+    method,
+    headers,
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  const json = await resp.json().catch(() => ({}));
+  if (!resp.ok) {
+    throw new Error(json.error || resp.statusText);
+  }
+  return json;
+}
+
+function showError(e) {
+  $("error").hidden = !e;
+  $("error").textContent = e ? e.message : "";
+}
+
+function formatTime(secs) {
+  return new Date(secs * 1000).toLocaleTimeString();
+}
+
+function formatUptime(secs) {
+  const h = Math.floor(secs / 3600);
+  const m = Math.floor((secs % 3600) / 60);
+  return `${h}h ${m}m ${secs % 60}s`;
+}
+
+function cell(row, text) {
+  const td = row.insertCell();
+  td.textContent = text;
+  return td;
+}
+
+function renderStatus(status) {
+  const items = [
+    ["Version", status.version],
+    ["Uptime", formatUptime(status.uptime_secs)],
+    ["Active sessions", status.active_sessions],
+    ["Connections", status.connections],
+    ["UDP associations", status.udp_associations],
+    ["Draining", status.draining ? "yes" : "no"],
+  ];
+  $("status").replaceChildren(
+    ...items.flatMap(([name, value]) => {
+      const dt = document.createElement("dt");
+      const dd = document.createElement("dd");
+      dt.textContent = name;
+      dd.textContent = value;
+      return [dt, dd];
+    })
+  );
+}
+
+function renderProfiles(profiles) {
+  const select = $("profile");
+  const options = [["", "Configured rules"], ...profiles.available.map((name) => [name, name])];
+  select.replaceChildren(
+    ...options.map(([value, label]) => {
+      const option = document.createElement("option");
+      option.value = value;
+      option.textContent = label;
+      return option;
+    })
+  );
+  select.value = profiles.active || "";
+}
+
+function renderSessions(sessions) {
+  const tbody = $("sessions");
+  tbody.replaceChildren();
+  for (const session of sessions) {
+    const row = tbody.insertRow();
+    cell(row, session.peer);
+    cell(row, session.command);
+    cell(row, session.destination_name || session.destination);
+    cell(row, formatTime(session.started_at));
+    const button = document.createElement("button");
+    button.className = "kill";
+    button.textContent = "Kill";
+    button.onclick = () =>
+      api("DELETE", "/conntrack/" + encodeURIComponent(session.peer))
+        .then(refresh)
+        .catch(showError);
+    row.insertCell().appendChild(button);
+  }
+}
+
+function renderRules(rules) {
+  $("rules").replaceChildren(
+    ...rules.map((rule) => {
+      const li = document.createElement("li");
+      li.textContent = rule;
+      return li;
+    })
+  );
+}
+
+function renderLogs(entries) {
+  const pre = $("logs");
+  const atBottom = pre.scrollTop + pre.clientHeight >= pre.scrollHeight - 4;
+  pre.textContent = entries
+    .map((entry) => `${formatTime(entry.at)}  ${entry.message}`)
+    .join("\n");
+  if (atBottom) {
+    pre.scrollTop = pre.scrollHeight;
+  }
+}
+
+async function refresh() {
+  try {
+    const [status, profiles, sessions, rules, logs] = await Promise.all([
+      api("GET", "/status"),
+      api("GET", "/profiles"),
+      api("GET", "/connections"),
+      api("GET", "/rules"),
+      api("GET", "/logs"),
+    ]);
+    renderStatus(status);
+    if (document.activeElement !== $("profile")) {
+      renderProfiles(profiles);
+    }
+    renderSessions(sessions);
+    renderRules(rules);
+    renderLogs(logs);
+    showError(null);
+  } catch (e) {
+    showError(e);
+  }
+}
+
+$("login").onsubmit = (event) => {
+  event.preventDefault();
+  sessionStorage.setItem("nstream-token", $("token").value);
+  refresh();
+};
+
+$("profile").onchange = (event) => {
+  const name = event.target.value || null;
+  api("PUT", "/profiles", { name }).then(refresh).catch(showError);
+};
+
+refresh();
+setInterval(refresh, REFRESH_INTERVAL_MS);
@@ -0,0 +1,86 @@
+//! Throughput of a UDP relay with the per-packet trace off and on
+//!
+//! ```sh
+//! cargo bench -p nstream-core --features trace-log --bench udp_relay | cat > /dev/null
+//! ```
+//!
+//! The trace goes to stdout, through a pipe as when the output is collected,
+//! a terminal being slower still, and the results to stderr. Without the
+//! `trace-log` feature both runs are the same, the trace being compiled out.
+
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nstream_core::trace_println;
+use tokio::net::UdpSocket;
+
+const DATAGRAMS: usize = 20_000;
+const DATAGRAM_LEN: usize = 1200;
+
+/// Forward each datagram of `relay` to `sink`, as the SOCKS5 UDP relay does
+async fn relay(relay: Arc<UdpSocket>, sink: SocketAddr) -> Result<()> {
+    let mut buf = [0u8; u16::MAX as usize];
+    loop {
+        let (len, from_addr) = relay.recv_from(&mut buf).await?;
+        trace_println!(
+            "UDP {} -> {} >>> {}",
+            from_addr,
+            sink,
+            String::from_utf8_lossy(&buf[..len])
+        );
+        relay.send_to(&buf[..len], sink).await?;
+    }
+}
+
+/// Send the datagrams through the relay one at a time, each echoed back by
+/// the sink, returns the bytes relayed per second
+async fn run(trace: bool) -> Result<f64> {
+    nstream_core::set_trace(trace);
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let relay_sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let sink = UdpSocket::bind("127.0.0.1:0").await?;
+    let relay_task = tokio::spawn(relay(relay_sock.clone(), sink.local_addr()?));
+    let client_addr = client.local_addr()?;
+    let sink_task = tokio::spawn(async move {
+        let mut buf = [0u8; u16::MAX as usize];
+        while let Ok(len) = sink.recv(&mut buf).await {
+            /* Straight back to the client, the trace is on the way out only */
+            sink.send_to(&buf[..len], client_addr).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    });
+
+    let payload = "x".repeat(DATAGRAM_LEN);
+    let mut echoed = [0u8; u16::MAX as usize];
+    let started = Instant::now();
+    let mut relayed = 0;
+    for _ in 0..DATAGRAMS {
+        client.send_to(payload.as_bytes(), relay_sock.local_addr()?).await?;
+        /* A datagram lost on the loopback interface is not waited for */
+        if let Ok(len) =
+            tokio::time::timeout(Duration::from_secs(1), client.recv(&mut echoed)).await
+        {
+            relayed += len?;
+        }
+    }
+    let elapsed = started.elapsed();
+    relay_task.abort();
+    sink_task.abort();
+    Ok(relayed as f64 / elapsed.as_secs_f64())
+}
+
+fn main() -> Result<()> {
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    tokio_rt.block_on(async {
+        let off = run(false).await?;
+        let on = run(true).await?;
+        eprintln!("trace off: {:>10.1} KiB/s", off / 1024.0);
+        eprintln!("trace on:  {:>10.1} KiB/s", on / 1024.0);
+        if !cfg!(feature = "trace-log") {
+            eprintln!("(built without the trace-log feature, the trace is compiled out)");
+        }
+        Ok(())
+    })
+}
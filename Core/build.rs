@@ -60,14 +60,16 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("cargo:rerun-if-changed=src/**");
 
     #[cfg(target_os = "macos")]
-    {
+    if std::env::var_os("CARGO_FEATURE_TUN").is_some() {
         let mut build = cc::Build::new();
         build.include("src/darwin_syscall").cpp(false).file("src/darwin_syscall/utun_ifname.c");
         build.compile("darwin_syscall");
     }
 
-    let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(download_maxmind_mmdb())?;
+    if std::env::var_os("CARGO_FEATURE_GEOIP").is_some() {
+        let rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(download_maxmind_mmdb())?;
+    }
 
     Ok(())
 }
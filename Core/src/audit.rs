@@ -0,0 +1,96 @@
+//! One record per connection served by the [Engine](crate::Engine), handed
+//! to an [AuditSink] once the connection closes
+//!
+//! The record is complete when it is handed over: the endpoints, what the
+//! router decided, the bytes relayed both ways, how long it lasted and why
+//! it ended, so that a sink never has to piece a connection together from
+//! several events.
+//!
+//! Those of the connections a rule with `log-sample` matches are sampled by
+//! a [LogSampler](crate::LogSampler), but for denials and errors.
+
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socks5::protocol::{Address, ReplyField};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{RuleAction, TrafficClass};
+
+/// Why a connection ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum CloseReason {
+    /// Relayed until both sides closed
+    Completed,
+    /// Answered with a failure reply, nothing was relayed
+    Refused(ReplyField),
+    /// Reset, for being rejected past the reply or failing to connect after
+    /// it
+    Reset,
+    /// Relaying failed
+    Error(ErrorKind),
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    /// The client, None when served over a stream other than TCP
+    pub peer: Option<SocketAddr>,
+    /// Where the client connected to
+    pub local: Option<SocketAddr>,
+    pub destination: Address,
+    /// The TLS server name or HTTP Host the client asked for, when sniffed
+    /// or found in its first bytes
+    pub sni: Option<String>,
+    /// What the router decided last
+    pub action: RuleAction,
+    pub class: Option<TrafficClass>,
+    /// From the client to the destination
+    pub bytes_sent: u64,
+    /// From the destination to the client
+    pub bytes_received: u64,
+    /// From the request until the connection closed
+    pub duration: Duration,
+    pub reason: CloseReason,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(
+        peer: Option<SocketAddr>,
+        local: Option<SocketAddr>,
+        destination: Address,
+    ) -> Self {
+        Self {
+            peer,
+            local,
+            destination,
+            sni: None,
+            action: RuleAction::Direct,
+            class: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            duration: Duration::ZERO,
+            reason: CloseReason::Completed,
+        }
+    }
+
+    /// Whether it tells of a connection refused or failing, which is never
+    /// sampled out
+    pub fn is_notable(&self) -> bool {
+        self.action == RuleAction::Reject || self.reason != CloseReason::Completed
+    }
+}
+
+/// Where the records of the closed connections go, it is called on the
+/// task of the connection and must not block
+pub trait AuditSink: Debug + Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Records sent to the receiver, dropped once it is gone
+impl AuditSink for UnboundedSender<AuditRecord> {
+    fn record(&self, record: &AuditRecord) {
+        let _ = self.send(record.clone());
+    }
+}
@@ -0,0 +1,200 @@
+//! Classification of flows by the first bytes the client sends
+//!
+//! Those bytes are held against the signatures of a few protocols, enough
+//! for rules such as `PROTOCOL,BITTORRENT,REJECT` without deep packet
+//! inspection. A flow whose client waits for the server to speak first, or
+//! whose first bytes match no signature, stays unclassified.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficClass {
+    Tls,
+    Http,
+    Ssh,
+    BitTorrent,
+    Quic,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; 5] =
+        [Self::Tls, Self::Http, Self::Ssh, Self::BitTorrent, Self::Quic];
+}
+
+impl FromStr for TrafficClass {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "TLS" => Ok(Self::Tls),
+            "HTTP" => Ok(Self::Http),
+            "SSH" => Ok(Self::Ssh),
+            "BITTORRENT" => Ok(Self::BitTorrent),
+            "QUIC" => Ok(Self::Quic),
+            _ => Err(format!("Unknown protocol: {}", s)),
+        }
+    }
+}
+
+impl Display for TrafficClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tls => f.write_str("TLS"),
+            Self::Http => f.write_str("HTTP"),
+            Self::Ssh => f.write_str("SSH"),
+            Self::BitTorrent => f.write_str("BITTORRENT"),
+            Self::Quic => f.write_str("QUIC"),
+        }
+    }
+}
+
+pub(crate) const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"CONNECT ",
+    b"PATCH ",
+    b"TRACE ",
+];
+
+/// The class of a TCP flow whose client sent `first` first
+pub fn classify_stream(first: &[u8]) -> Option<TrafficClass> {
+    match first {
+        /* A handshake record of SSL 3.0 up to TLS 1.3 carrying a ClientHello */
+        [0x16, 0x03, 0x00..=0x04, _, _, 0x01, ..] => Some(TrafficClass::Tls),
+        [0x13, rest @ ..] if rest.starts_with(b"BitTorrent protocol") => {
+            Some(TrafficClass::BitTorrent)
+        }
+        _ if first.starts_with(b"SSH-") => Some(TrafficClass::Ssh),
+        _ if first.starts_with(b"PRI * HTTP/2.0")
+            || HTTP_METHODS.iter().any(|method| first.starts_with(method)) =>
+        {
+            Some(TrafficClass::Http)
+        }
+        _ => None,
+    }
+}
+
+/// Connect requests of UDP trackers start with this protocol ID
+const UDP_TRACKER_PROTOCOL_ID: u64 = 0x41727101980;
+
+/// Clients pad the datagrams carrying their QUIC Initial to this size
+const QUIC_MIN_INITIAL_LEN: usize = 1200;
+
+/// The class of a UDP flow whose client sent the datagram `first` first
+pub fn classify_datagram(first: &[u8]) -> Option<TrafficClass> {
+    match first {
+        /* A long header with the fixed bit, of QUIC v1, v2 or a draft */
+        [0xc0..=0xff, 0, 0, 0, 1, ..]
+        | [0xc0..=0xff, 0x6b, 0x33, 0x43, 0xcf, ..]
+        | [0xc0..=0xff, 0xff, 0, 0, _, ..]
+            if first.len() >= QUIC_MIN_INITIAL_LEN =>
+        {
+            Some(TrafficClass::Quic)
+        }
+        /* A bencoded DHT query, with its type key */
+        _ if first.starts_with(b"d1:") && first.windows(5).any(|w| w == b"1:y1:") => {
+            Some(TrafficClass::BitTorrent)
+        }
+        [a, b, c, d, e, f, g, h, ..]
+            if first.len() == 16
+                && u64::from_be_bytes([*a, *b, *c, *d, *e, *f, *g, *h])
+                    == UDP_TRACKER_PROTOCOL_ID =>
+        {
+            Some(TrafficClass::BitTorrent)
+        }
+        /* The uTP SYN opening a connection: type 4, version 1 and a bare
+         * header */
+        [0x41, 0, ..] if first.len() == 20 => Some(TrafficClass::BitTorrent),
+        _ => None,
+    }
+}
+
+/// Flows counted per class, clones share the same counters
+#[derive(Debug, Clone, Default)]
+pub struct ClassStats {
+    /// In the order of [TrafficClass::ALL], then the unclassified ones
+    counts: Arc<[AtomicU64; TrafficClass::ALL.len() + 1]>,
+}
+
+impl ClassStats {
+    fn index(class: Option<TrafficClass>) -> usize {
+        match class {
+            Some(class) => TrafficClass::ALL.iter().position(|c| *c == class).unwrap(),
+            None => TrafficClass::ALL.len(),
+        }
+    }
+
+    #[inline]
+    pub fn record(&self, class: Option<TrafficClass>) {
+        self.counts[Self::index(class)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Flows of `class`, or unclassified ones for None
+    #[inline]
+    pub fn count(&self, class: Option<TrafficClass>) -> u64 {
+        self.counts[Self::index(class)].load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClassStats, TrafficClass, classify_datagram, classify_stream};
+
+    #[test]
+    fn test_classify_stream() {
+        let client_hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03];
+        assert_eq!(classify_stream(&client_hello), Some(TrafficClass::Tls));
+        assert_eq!(classify_stream(b"GET / HTTP/1.1\r\n"), Some(TrafficClass::Http));
+        assert_eq!(classify_stream(b"PRI * HTTP/2.0\r\n\r\nSM"), Some(TrafficClass::Http));
+        assert_eq!(classify_stream(b"SSH-2.0-OpenSSH_9.6\r\n"), Some(TrafficClass::Ssh));
+        let mut handshake = vec![0x13];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        assert_eq!(classify_stream(&handshake), Some(TrafficClass::BitTorrent));
+
+        /* A TLS record too short to tell, and a method without its space */
+        assert_eq!(classify_stream(&client_hello[..3]), None);
+        assert_eq!(classify_stream(b"GETX"), None);
+        assert_eq!(classify_stream(b""), None);
+    }
+
+    #[test]
+    fn test_classify_datagram() {
+        let mut initial = vec![0xc3, 0, 0, 0, 1];
+        initial.resize(1200, 0);
+        assert_eq!(classify_datagram(&initial), Some(TrafficClass::Quic));
+        /* Not padded as an Initial must be */
+        assert_eq!(classify_datagram(&initial[..100]), None);
+
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(classify_datagram(ping), Some(TrafficClass::BitTorrent));
+        let mut connect = 0x41727101980u64.to_be_bytes().to_vec();
+        connect.extend_from_slice(&[0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(classify_datagram(&connect), Some(TrafficClass::BitTorrent));
+        let mut syn = vec![0x41, 0x00];
+        syn.resize(20, 0);
+        assert_eq!(classify_datagram(&syn), Some(TrafficClass::BitTorrent));
+
+        /* A DNS query */
+        assert_eq!(classify_datagram(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_class_stats() {
+        let stats = ClassStats::default();
+        stats.clone().record(Some(TrafficClass::Quic));
+        stats.record(None);
+        stats.record(None);
+        assert_eq!(stats.count(Some(TrafficClass::Quic)), 1);
+        assert_eq!(stats.count(Some(TrafficClass::Tls)), 0);
+        assert_eq!(stats.count(None), 2);
+        assert_eq!("bittorrent".parse(), Ok(TrafficClass::BitTorrent));
+        assert_eq!(TrafficClass::BitTorrent.to_string(), "BITTORRENT");
+    }
+}
@@ -0,0 +1,379 @@
+//! Resources shared by the whole process, the GeoIP database and the STUN
+//! servers
+//!
+//! The free functions of this crate use [CoreContext::global], which is
+//! built from the bundled database and the default servers on first use
+//! unless another context was installed before. Tests build their own
+//! context instead, pointing at a local STUN server or another database.
+
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "stun")]
+use core::net::{SocketAddrV4, SocketAddrV6};
+use std::future::Future;
+use std::io::{ErrorKind, Result};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+#[cfg(feature = "stun")]
+use crate::RetryPolicy;
+#[cfg(feature = "geoip")]
+use maxminddb::{Reader, geoip2::Country};
+#[cfg(feature = "stun")]
+use stunclient::StunClient;
+#[cfg(feature = "stun")]
+use tokio::net::UdpSocket;
+
+#[cfg(feature = "geoip")]
+pub static GEOIP2_COUNTRY_MMDB_BUF: &[u8] = include_bytes!("../Country.mmdb");
+
+#[cfg(feature = "stun")]
+pub const SOCKET_ADDR_V6_STUN: SocketAddr = SocketAddr::V6(SocketAddrV6::new(
+    Ipv6Addr::new(0x2600, 0x1f16, 0x8c5, 0x101, 0x80b, 0xb58b, 0x828, 0x8df4),
+    3478,
+    0,
+    0,
+));
+
+#[cfg(feature = "stun")]
+pub const SOCKET_ADDR_V4_STUN: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(3, 22, 142, 132), 3478));
+
+/// How long a STUN server is waited for an answer to one query
+#[cfg(feature = "stun")]
+const STUN_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(2);
+
+static GLOBAL: OnceLock<CoreContext> = OnceLock::new();
+
+pub struct CoreContext {
+    /// The reason the database cannot be used otherwise
+    #[cfg(feature = "geoip")]
+    geoip: std::result::Result<Reader<Vec<u8>>, String>,
+    #[cfg(feature = "stun")]
+    stun_v4: SocketAddr,
+    #[cfg(feature = "stun")]
+    stun_v6: SocketAddr,
+    #[cfg(feature = "stun")]
+    stun_retry: RetryPolicy,
+}
+
+impl std::fmt::Debug for CoreContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("CoreContext");
+        #[cfg(feature = "geoip")]
+        debug.field("geoip", &self.geoip.as_ref().map(drop));
+        #[cfg(feature = "stun")]
+        debug
+            .field("stun_v4", &self.stun_v4)
+            .field("stun_v6", &self.stun_v6)
+            .field("stun_retry", &self.stun_retry);
+        debug.finish()
+    }
+}
+
+impl Default for CoreContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "geoip")]
+fn read_geoip_database(buf: Vec<u8>) -> std::result::Result<Reader<Vec<u8>>, String> {
+    if buf.is_empty() {
+        return Err(String::from("Country.mmdb is empty"));
+    }
+    Reader::from_source(buf).map_err(|e| e.to_string())
+}
+
+impl CoreContext {
+    /// With the bundled database and the default STUN servers
+    pub fn new() -> Self {
+        Self {
+            #[cfg(feature = "geoip")]
+            geoip: read_geoip_database(GEOIP2_COUNTRY_MMDB_BUF.to_vec()),
+            #[cfg(feature = "stun")]
+            stun_v4: SOCKET_ADDR_V4_STUN,
+            #[cfg(feature = "stun")]
+            stun_v6: SOCKET_ADDR_V6_STUN,
+            /* Queries or answers are lost now and then, datagrams as they are */
+            #[cfg(feature = "stun")]
+            stun_retry: RetryPolicy::default()
+                .with_max_attempts(3)
+                .with_backoff(Duration::from_millis(100), Duration::from_millis(500))
+                .with_attempt_timeout(STUN_ATTEMPT_TIMEOUT),
+        }
+    }
+
+    /// The context of the free functions of this crate
+    pub fn global() -> &'static CoreContext {
+        GLOBAL.get_or_init(CoreContext::new)
+    }
+
+    /// Make `self` the [CoreContext::global] one, which fails once it has
+    /// been used or installed already
+    /* Given back whole on that failure, once in the life of the process */
+    #[allow(clippy::result_large_err)]
+    pub fn install(self) -> std::result::Result<(), Self> {
+        GLOBAL.set(self)
+    }
+
+    /// Look countries up in the GeoIP2 database `buf` instead
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip_database(mut self, buf: Vec<u8>) -> Self {
+        self.geoip = read_geoip_database(buf);
+        self
+    }
+
+    /// Ask `v4` and `v6` for the external addresses instead
+    #[cfg(feature = "stun")]
+    pub fn with_stun_servers(mut self, v4: SocketAddr, v6: SocketAddr) -> Self {
+        self.stun_v4 = v4;
+        self.stun_v6 = v6;
+        self
+    }
+
+    /// Query the STUN servers as `retry` tells instead, three attempts of
+    /// 2s each by default
+    #[cfg(feature = "stun")]
+    pub fn with_stun_retry(mut self, retry: RetryPolicy) -> Self {
+        self.stun_retry = retry;
+        self
+    }
+
+    /// ISO code of the country where `address` is located
+    #[cfg(feature = "geoip")]
+    pub fn iso_code_of(&self, address: IpAddr) -> Option<String> {
+        let reader = self.geoip.as_ref().ok()?;
+        let lookup_ret = reader.lookup(address).ok()?;
+        let country_ret = lookup_ret.decode::<Country>().ok()??;
+        crate::seeval!(country_ret);
+        country_ret.country.iso_code.map(str::to_string)
+    }
+
+    /// Never known without the `geoip` feature
+    #[cfg(not(feature = "geoip"))]
+    #[inline]
+    pub fn iso_code_of(&self, _address: IpAddr) -> Option<String> {
+        None
+    }
+
+    /// Check that the GeoIP2 country database can be read
+    #[cfg(feature = "geoip")]
+    pub fn probe_geoip_database(&self) -> Result<()> {
+        match &self.geoip {
+            Ok(_) => Ok(()),
+            Err(e) => Err(std::io::Error::new(ErrorKind::InvalidData, e.to_owned())),
+        }
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    #[inline]
+    pub fn probe_geoip_database(&self) -> Result<()> {
+        Err(std::io::Error::new(ErrorKind::Unsupported, "Built without the geoip feature"))
+    }
+
+    /// The IPv4 and IPv6 STUN servers asked, in that order
+    #[cfg(feature = "stun")]
+    #[inline]
+    pub fn stun_servers(&self) -> [SocketAddr; 2] {
+        [self.stun_v4, self.stun_v6]
+    }
+
+    /// The address and port the STUN server of its family sees `udp_sock`
+    /// send from, which others reach it at for as long as NATs on the way
+    /// keep the mapping
+    #[cfg(feature = "stun")]
+    pub async fn reflexive_address(&self, udp_sock: &UdpSocket) -> Result<SocketAddr> {
+        self.query_stun(udp_sock, &self.stun_retry).await
+    }
+
+    #[cfg(feature = "stun")]
+    async fn query_stun(&self, udp_sock: &UdpSocket, retry: &RetryPolicy) -> Result<SocketAddr> {
+        let sockaddr_stun = match udp_sock.local_addr()? {
+            SocketAddr::V4(_) => self.stun_v4,
+            SocketAddr::V6(_) => self.stun_v6,
+        };
+        /* Anything from a lost answer to a garbled one may go right next time,
+         * each attempt with a client of its own as a query consumes it */
+        retry
+            .run_if(
+                |_| true,
+                || async {
+                    StunClient::new(sockaddr_stun)
+                        .query_external_address_async(udp_sock)
+                        .await
+                        .map_err(|e| std::io::Error::other(e.to_string()))
+                },
+            )
+            .await
+    }
+
+    /// The address and port the STUN server sees queries from a new socket
+    /// bound to `ip_unspec` come from
+    #[cfg(feature = "stun")]
+    async fn extip(&self, ip_unspec: IpAddr, retry: &RetryPolicy) -> Result<SocketAddr> {
+        let udp_sock = UdpSocket::bind(SocketAddr::new(ip_unspec, 0)).await?;
+        self.query_stun(&udp_sock, retry).await
+    }
+
+    /// The address and port the STUN server sees IPv6 queries come from
+    #[cfg(feature = "stun")]
+    pub async fn what_is_my_extip_v6addr(&self) -> Result<SocketAddr> {
+        self.extip(IpAddr::V6(Ipv6Addr::UNSPECIFIED), &self.stun_retry).await
+    }
+
+    /// The address and port the STUN server sees IPv4 queries come from
+    #[cfg(feature = "stun")]
+    pub async fn what_is_my_extip_v4addr(&self) -> Result<SocketAddr> {
+        self.extip(IpAddr::V4(Ipv4Addr::UNSPECIFIED), &self.stun_retry).await
+    }
+
+    /// Look the LAN and external addresses of both families up at once,
+    /// each given up on after `timeout`, STUN queries being retried within
+    /// it
+    pub async fn discover_addresses(&self, timeout: Duration) -> Addresses {
+        #[cfg(feature = "stun")]
+        let retry = self.stun_retry.with_budget(timeout);
+        #[cfg(feature = "stun")]
+        let (ext_v4, ext_v6) = (
+            self.extip(IpAddr::V4(Ipv4Addr::UNSPECIFIED), &retry),
+            self.extip(IpAddr::V6(Ipv6Addr::UNSPECIFIED), &retry),
+        );
+        #[cfg(not(feature = "stun"))]
+        let (ext_v4, ext_v6) = (unsupported::<SocketAddr>(), unsupported::<SocketAddr>());
+        let (lan, ext_v4, ext_v6) = tokio::join!(
+            self.discover_lan_addresses(timeout),
+            within(timeout, ext_v4),
+            within(timeout, ext_v6),
+        );
+        Addresses { ext_v4, ext_v6, ..lan }
+    }
+
+    /// Look the LAN addresses of both families up at once, each given up on
+    /// after `timeout`, those STUN tells being left out
+    pub async fn discover_lan_addresses(&self, timeout: Duration) -> Addresses {
+        let (lan_v4, lan_v6) = tokio::join!(
+            within(timeout, crate::what_is_my_lanip_v4addr()),
+            within(timeout, crate::what_is_my_lanip_v6addr()),
+        );
+        Addresses { lan_v4, lan_v6, ..Default::default() }
+    }
+}
+
+/// Addresses of this host, None for those that could not be found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Addresses {
+    pub lan_v4: Option<Ipv4Addr>,
+    pub lan_v6: Option<Ipv6Addr>,
+    /// As seen by the STUN server, the port being that of the query
+    pub ext_v4: Option<SocketAddr>,
+    pub ext_v6: Option<SocketAddr>,
+}
+
+async fn within<T>(timeout: Duration, query: impl Future<Output = Result<T>>) -> Option<T> {
+    tokio::time::timeout(timeout, query).await.ok()?.ok()
+}
+
+#[cfg(not(feature = "stun"))]
+async fn unsupported<T>() -> Result<T> {
+    Err(std::io::Error::new(ErrorKind::Unsupported, "Built without the stun feature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CoreContext;
+
+    use std::time::Duration;
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn test_geoip_database_injected() {
+        let context = CoreContext::new().with_geoip_database(vec![]);
+        assert!(context.probe_geoip_database().is_err());
+        assert_eq!(context.iso_code_of("140.205.135.3".parse().unwrap()), None);
+    }
+
+    /// Answers one binding request with 203.0.113.7:4242 as the mapped address
+    #[cfg(feature = "stun")]
+    async fn serve_stun_once(udp_sock: tokio::net::UdpSocket) -> std::io::Result<()> {
+        serve_stun(udp_sock, 0).await
+    }
+
+    /// Answers the binding request after ignoring the first `ignored` ones
+    #[cfg(feature = "stun")]
+    async fn serve_stun(udp_sock: tokio::net::UdpSocket, ignored: usize) -> std::io::Result<()> {
+        const MAGIC_COOKIE: u32 = 0x2112a442;
+        let mut req = [0u8; 512];
+        for _ in 0..ignored {
+            udp_sock.recv_from(&mut req).await?;
+        }
+        let (len, from_addr) = udp_sock.recv_from(&mut req).await?;
+        assert!(len >= 20);
+        let mut resp = vec![0x01, 0x01, 0, 12];
+        resp.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        resp.extend_from_slice(&req[8..20]); /* Transaction ID */
+        resp.extend_from_slice(&[0, 0x20, 0, 8, 0, 0x01]); /* XOR-MAPPED-ADDRESS, IPv4 */
+        resp.extend_from_slice(&(4242 ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+        resp.extend_from_slice(
+            &(u32::from_be_bytes([203, 0, 113, 7]) ^ MAGIC_COOKIE).to_be_bytes(),
+        );
+        udp_sock.send_to(&resp, from_addr).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "stun")]
+    #[test]
+    fn test_stun_servers_injected() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let udp_sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let stun_addr = udp_sock.local_addr()?;
+            let server = tokio::spawn(serve_stun_once(udp_sock));
+            let context =
+                CoreContext::new().with_stun_servers(stun_addr, "[::1]:9".parse().unwrap());
+            let extip = context.what_is_my_extip_v4addr().await?;
+            assert_eq!(extip, "203.0.113.7:4242".parse().unwrap());
+            server.await?
+        })
+    }
+
+    #[cfg(feature = "stun")]
+    #[test]
+    fn test_stun_retried() -> std::io::Result<()> {
+        use crate::RetryPolicy;
+
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            /* The first query goes unanswered, the second one is */
+            let udp_sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let stun_addr = udp_sock.local_addr()?;
+            let server = tokio::spawn(serve_stun(udp_sock, 1));
+            let retry = RetryPolicy::default()
+                .with_max_attempts(3)
+                .with_backoff(Duration::from_millis(10), Duration::from_millis(10))
+                .with_attempt_timeout(Duration::from_millis(300));
+            let context = CoreContext::new()
+                .with_stun_servers(stun_addr, "[::1]:9".parse().unwrap())
+                .with_stun_retry(retry);
+            let extip = context.what_is_my_extip_v4addr().await?;
+            assert_eq!(extip, "203.0.113.7:4242".parse().unwrap());
+            server.await?
+        })
+    }
+
+    #[cfg(feature = "stun")]
+    #[test]
+    fn test_discover_addresses() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            /* A STUN server that never answers holds nothing else up */
+            let udp_sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let silent_addr = udp_sock.local_addr()?;
+            let context = CoreContext::new().with_stun_servers(silent_addr, silent_addr);
+            let started = std::time::Instant::now();
+            let addrs = context.discover_addresses(Duration::from_millis(200)).await;
+            assert!(started.elapsed() < Duration::from_secs(2));
+            assert_eq!((addrs.ext_v4, addrs.ext_v6), (None, None));
+            Ok(())
+        })
+    }
+}
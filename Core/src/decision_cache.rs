@@ -0,0 +1,201 @@
+//! Router decisions remembered per destination and user
+//!
+//! Repeat connections to a destination then skip matching the rules, and
+//! the GeoIP lookups of `GEOIP` rules along with it. The cache is to be
+//! invalidated whenever the rules it was filled from change, a decision
+//! made with rules since replaced is never kept. [DecisionStats] counts the
+//! hits and misses, to tell whether the capacity suits the destinations
+//! seen.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{RuleAction, TrafficClass};
+
+/// Decisions kept by default, the oldest is dropped past it
+pub const DEFAULT_DECISION_CACHE_CAPACITY: usize = 4096;
+
+/// What a decision depends on besides the rules
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DecisionKey {
+    user: Option<String>,
+    domain: Option<String>,
+    ip: Option<IpAddr>,
+    class: Option<TrafficClass>,
+}
+
+impl DecisionKey {
+    /// The decision for `user`, None when the rules are the same for all
+    pub fn new(
+        user: Option<&str>,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        class: Option<TrafficClass>,
+    ) -> Self {
+        Self {
+            user: user.map(str::to_owned),
+            /* Told apart no more than the rules do */
+            domain: domain.map(str::to_ascii_lowercase),
+            ip: ip.map(|ip| ip.to_canonical()),
+            class,
+        }
+    }
+}
+
+/// Hits and misses of a [DecisionCache], clones share the same counters
+#[derive(Debug, Clone, Default)]
+pub struct DecisionStats {
+    counts: Arc<[AtomicU64; 2]>,
+}
+
+impl DecisionStats {
+    #[inline]
+    pub fn hits(&self) -> u64 {
+        self.counts[0].load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn misses(&self) -> u64 {
+        self.counts[1].load(Ordering::Relaxed)
+    }
+
+    /// The share of the decisions found in the cache, None before any
+    pub fn hit_rate(&self) -> Option<f64> {
+        let (hits, misses) = (self.hits(), self.misses());
+        (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    actions: HashMap<DecisionKey, RuleAction>,
+    /// The keys from the oldest
+    order: VecDeque<DecisionKey>,
+    /// Bumped on each invalidation
+    generation: u64,
+}
+
+#[derive(Debug)]
+pub struct DecisionCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    stats: DecisionStats,
+}
+
+impl Default for DecisionCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_DECISION_CACHE_CAPACITY)
+    }
+}
+
+impl DecisionCache {
+    /// Keeps up to `capacity` decisions, none at all for 0
+    #[inline]
+    pub fn new(capacity: usize) -> Self {
+        Self::with_stats(capacity, DecisionStats::default())
+    }
+
+    /// Same as [DecisionCache::new], counting into `stats`
+    pub fn with_stats(capacity: usize, stats: DecisionStats) -> Self {
+        Self { capacity, entries: Mutex::default(), stats }
+    }
+
+    #[inline]
+    pub fn stats(&self) -> &DecisionStats {
+        &self.stats
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().actions.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The decision for `key`, made by `decide` unless it is cached
+    pub fn decide(&self, key: DecisionKey, decide: impl FnOnce() -> RuleAction) -> RuleAction {
+        let generation = {
+            let entries = self.entries.lock().unwrap();
+            if let Some(action) = entries.actions.get(&key) {
+                self.stats.counts[0].fetch_add(1, Ordering::Relaxed);
+                return *action;
+            }
+            entries.generation
+        };
+        self.stats.counts[1].fetch_add(1, Ordering::Relaxed);
+        /* Without the lock, GeoIP lookups may take a while */
+        let action = decide();
+        let mut entries = self.entries.lock().unwrap();
+        if self.capacity == 0 || entries.generation != generation {
+            return action;
+        }
+        if entries.actions.insert(key.clone(), action).is_none() {
+            entries.order.push_back(key);
+            if entries.order.len() > self.capacity {
+                let oldest = entries.order.pop_front().unwrap();
+                entries.actions.remove(&oldest);
+            }
+        }
+        action
+    }
+
+    /// Forget every decision, for the rules they were made with changed
+    pub fn invalidate(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.actions.clear();
+        entries.order.clear();
+        entries.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecisionCache, DecisionKey};
+    use crate::RuleAction;
+
+    use std::cell::Cell;
+
+    #[test]
+    fn test_decision_cache() {
+        let cache = DecisionCache::new(2);
+        let decided = Cell::new(0);
+        let decide = || {
+            decided.set(decided.get() + 1);
+            RuleAction::Direct
+        };
+        let key = |domain| DecisionKey::new(None, Some(domain), None, None);
+
+        assert_eq!(cache.decide(key("a.example"), decide), RuleAction::Direct);
+        /* Names match whatever their case */
+        assert_eq!(cache.decide(key("A.example"), decide), RuleAction::Direct);
+        assert_eq!(decided.get(), 1);
+        /* Users are told apart */
+        cache.decide(DecisionKey::new(Some("guest"), Some("a.example"), None, None), decide);
+        assert_eq!(decided.get(), 2);
+        assert_eq!((cache.stats().hits(), cache.stats().misses()), (1, 2));
+
+        /* The oldest goes first */
+        cache.decide(key("b.example"), decide);
+        assert_eq!(cache.len(), 2);
+        cache.decide(key("a.example"), decide);
+        assert_eq!(decided.get(), 4);
+
+        cache.invalidate();
+        assert!(cache.is_empty());
+        cache.decide(key("a.example"), || RuleAction::Reject);
+        assert_eq!(cache.decide(key("a.example"), decide), RuleAction::Reject);
+        assert_eq!(cache.stats().hit_rate(), Some(2.0 / 7.0));
+
+        /* A decision made while the rules change is not kept */
+        cache.decide(key("c.example"), || {
+            cache.invalidate();
+            RuleAction::Proxy
+        });
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+//! https://datatracker.ietf.org/doc/html/rfc8305
+
+use crate::{FamilyPreference, IpCidr, RetryPolicy, SocketOptions, keep_meaningful};
+
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::AsFd;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::task::JoinSet;
+
+/// Recommended value of the "Connection Attempt Delay"
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// `IPV6_PREFER_TEMPADDR` of `<netinet6/in6.h>`, not exported by libc
+#[cfg(target_os = "macos")]
+const IPV6_PREFER_TEMPADDR: libc::c_int = 63;
+
+/// Where outbound IPv6 connections originate from, e.g. to avoid exposing
+/// the stable interface identifier of the host through the proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Ipv6Source {
+    /// Whatever the source address selection of the system picks
+    #[default]
+    System,
+    /// Prefer temporary addresses (RFC 8981), which change over time
+    Temporary,
+    /// An address of a local interface within this prefix
+    Prefix(IpCidr),
+}
+
+impl Ipv6Source {
+    /// Set up `socket` before it connects to an IPv6 destination
+    pub fn apply(&self, socket: &TcpSocket) -> Result<()> {
+        match self {
+            Self::System => Ok(()),
+            Self::Temporary => prefer_temporary_addr(socket),
+            Self::Prefix(prefix) => {
+                let addr = local_ipv6_addrs()?
+                    .into_iter()
+                    .find(|addr| prefix.contains(&IpAddr::V6(*addr)))
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::AddrNotAvailable,
+                            format!("No local address within {}", prefix),
+                        )
+                    })?;
+                socket.bind(SocketAddr::new(IpAddr::V6(addr), 0))
+            }
+        }
+    }
+}
+
+impl FromStr for Ipv6Source {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "system" => Ok(Self::System),
+            "temporary" => Ok(Self::Temporary),
+            prefix => {
+                let prefix: IpCidr = prefix.parse().map_err(|e| {
+                    format!("{}, expected \"system\", \"temporary\" or an IPv6 prefix", e)
+                })?;
+                if prefix.addr().is_ipv4() {
+                    return Err(format!("{} is not an IPv6 prefix", prefix));
+                }
+                Ok(Self::Prefix(prefix))
+            }
+        }
+    }
+}
+
+impl Display for Ipv6Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::System => f.write_str("system"),
+            Self::Temporary => f.write_str("temporary"),
+            Self::Prefix(prefix) => write!(f, "{}", prefix),
+        }
+    }
+}
+
+impl TryFrom<String> for Ipv6Source {
+    type Error = String;
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Ipv6Source> for String {
+    fn from(value: Ipv6Source) -> Self {
+        value.to_string()
+    }
+}
+
+fn prefer_temporary_addr(socket: &TcpSocket) -> Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let (optname, val) = (libc::IPV6_ADDR_PREFERENCES, libc::IPV6_PREFER_SRC_TMP);
+    #[cfg(target_os = "macos")]
+    let (optname, val) = (IPV6_PREFER_TEMPADDR, 1);
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    return Err(Error::new(ErrorKind::Unsupported, "Preferring temporary addresses"));
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    {
+        let ret = unsafe {
+            libc::setsockopt(
+                std::os::fd::AsRawFd::as_raw_fd(socket),
+                libc::IPPROTO_IPV6,
+                optname,
+                &val as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
+    }
+}
+
+/// IPv6 addresses of the local interfaces, except link-local ones which
+/// cannot be bound without a scope
+fn local_ipv6_addrs() -> Result<Vec<Ipv6Addr>> {
+    let addrs = crate::interface_addresses()?.into_iter().filter_map(|addr| match addr.ip {
+        IpAddr::V6(ip) if !ip.is_unicast_link_local() => Some(ip),
+        _ => None,
+    });
+    Ok(addrs.collect())
+}
+
+/// `IFNAMSIZ`, the name of an interface and its terminating NUL
+const IF_NAME_SIZE: usize = 16;
+
+/// The name of a network interface, e.g. `en0`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceName([u8; IF_NAME_SIZE]);
+
+impl InterfaceName {
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|b| *b == 0).unwrap_or(IF_NAME_SIZE);
+        std::str::from_utf8(&self.0[..len]).unwrap()
+    }
+
+    /// Index of the interface, which must exist by now
+    #[cfg(target_os = "macos")]
+    fn index(&self) -> Result<std::num::NonZeroU32> {
+        /* Always NUL terminated, the name is at most IF_NAME_SIZE - 1 bytes */
+        let index = unsafe { libc::if_nametoindex(self.0.as_ptr() as *const libc::c_char) };
+        std::num::NonZeroU32::new(index)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No interface {}", self)))
+    }
+}
+
+impl FromStr for InterfaceName {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.is_empty() || s.len() >= IF_NAME_SIZE {
+            return Err(format!("Interface name of 1 to {} bytes", IF_NAME_SIZE - 1));
+        }
+        if s.bytes().any(|b| b == 0 || b == b'/' || b.is_ascii_whitespace()) {
+            return Err(format!("Invalid interface name {:?}", s));
+        }
+        let mut name = [0; IF_NAME_SIZE];
+        name[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self(name))
+    }
+}
+
+impl Display for InterfaceName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::fmt::Debug for InterfaceName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// What outbound connections and datagrams are pinned to, so that they keep
+/// leaving through the physical interface once a tunnel is the default
+/// route instead of looping back into it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum OutboundBind {
+    /// `SO_BINDTODEVICE` on Linux, `IP_BOUND_IF` and `IPV6_BOUND_IF` on
+    /// macOS
+    Interface(InterfaceName),
+    /// A source address, only destinations of its family can be reached
+    Address(IpAddr),
+}
+
+impl OutboundBind {
+    /// Pin `socket` before it connects or sends to an IPv6 destination when
+    /// `ipv6`, or to an IPv4 one
+    pub fn apply<S: AsFd>(&self, socket: &S, ipv6: bool) -> Result<()> {
+        let socket = SockRef::from(socket);
+        match self {
+            Self::Address(ip) if ip.is_ipv6() != ipv6 => Err(Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("{} cannot reach IPv{} destinations", ip, if ipv6 { 6 } else { 4 }),
+            )),
+            Self::Address(ip) => socket.bind(&SocketAddr::new(*ip, 0).into()),
+            Self::Interface(name) => bind_to_interface(&socket, name, ipv6),
+        }
+    }
+}
+
+fn bind_to_interface(socket: &SockRef<'_>, name: &InterfaceName, ipv6: bool) -> Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let _ = ipv6;
+        socket.bind_device(Some(name.as_str().as_bytes()))
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let index = Some(name.index()?);
+        match ipv6 {
+            true => socket.bind_device_by_index_v6(index),
+            false => socket.bind_device_by_index_v4(index),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    {
+        let _ = (socket, ipv6);
+        Err(Error::new(ErrorKind::Unsupported, format!("Binding to interface {}", name)))
+    }
+}
+
+impl FromStr for OutboundBind {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.parse::<IpAddr>() {
+            Ok(ip) => Ok(Self::Address(ip)),
+            Err(_) => s
+                .parse()
+                .map(Self::Interface)
+                .map_err(|e| format!("{}, expected an interface name or a source address", e)),
+        }
+    }
+}
+
+impl Display for OutboundBind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Interface(name) => write!(f, "{}", name),
+            Self::Address(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+impl TryFrom<String> for OutboundBind {
+    type Error = String;
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<OutboundBind> for String {
+    fn from(value: OutboundBind) -> Self {
+        value.to_string()
+    }
+}
+
+/// Options of outbound connections
+#[derive(Debug, Clone, Copy)]
+pub struct DialConfig {
+    pub sockopts: SocketOptions,
+    /// Time to wait for an attempt before starting the next one
+    pub attempt_delay: Duration,
+    /// Try IPv6 addresses first
+    pub prefer_ipv6: bool,
+    pub ipv6_source: Ipv6Source,
+    /// Which family goes first when resolving names, see [crate::resolve]
+    pub family_preference: FamilyPreference,
+    /// Pins connections and relayed datagrams, a source address taking the
+    /// place of `ipv6_source`
+    pub bind: Option<OutboundBind>,
+    /// How failed connections are tried again, they are not by default
+    pub retry: RetryPolicy,
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        Self {
+            sockopts: SocketOptions::default(),
+            attempt_delay: CONNECTION_ATTEMPT_DELAY,
+            prefer_ipv6: true,
+            ipv6_source: Ipv6Source::System,
+            family_preference: FamilyPreference::PreferV6,
+            bind: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl DialConfig {
+    /// Connect to a single address with these options
+    pub async fn connect(&self, addr: SocketAddr) -> Result<TcpStream> {
+        self.outbound_socket(addr)?.connect(addr).await
+    }
+
+    /// A socket for connecting to `addr`, set up and pinned as these options
+    /// ask
+    pub fn outbound_socket(&self, addr: SocketAddr) -> Result<TcpSocket> {
+        let socket = self.sockopts.outbound_socket(addr)?;
+        match self.bind {
+            Some(OutboundBind::Address(_)) => {}
+            _ if addr.is_ipv6() => self.ipv6_source.apply(&socket)?,
+            _ => {}
+        }
+        if let Some(bind) = &self.bind {
+            bind.apply(&socket, addr.is_ipv6())?;
+        }
+        Ok(socket)
+    }
+
+    /// A socket relaying datagrams to destinations, pinned as `bind` asks.
+    /// It is of the family of the source address, IPv4 otherwise.
+    pub fn udp_socket(&self) -> Result<UdpSocket> {
+        let ipv6 = matches!(self.bind, Some(OutboundBind::Address(IpAddr::V6(_))));
+        let domain = if ipv6 { Domain::IPV6 } else { Domain::IPV4 };
+        let socket = Socket::new(domain, Type::DGRAM, None)?;
+        if let Some(bind) = &self.bind {
+            bind.apply(&socket, ipv6)?;
+        }
+        if !matches!(self.bind, Some(OutboundBind::Address(_))) {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+        }
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    }
+}
+
+/// Order `addrs` by alternating address families, starting with the
+/// preferred one, while keeping the relative order within each family.
+pub fn interleave_addrs(addrs: &[SocketAddr], prefer_ipv6: bool) -> Vec<SocketAddr> {
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addrs.iter().copied().partition(|addr| addr.is_ipv6() == prefer_ipv6);
+    if preferred.is_empty() {
+        std::mem::swap(&mut preferred, &mut other);
+    }
+    let mut ret = Vec::with_capacity(addrs.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (first, second) => ret.extend(first.into_iter().chain(second)),
+        }
+    }
+    ret
+}
+
+/// Connect to the first reachable address of `addrs`, racing the address
+/// families with staggered attempts as described in RFC 8305.
+///
+/// A new attempt starts whenever the previous one fails or has not
+/// completed within [DialConfig::attempt_delay]. The first established
+/// connection wins and the others are cancelled. A race lost by all of
+/// them is run again as [DialConfig::retry] asks.
+pub async fn happy_eyeballs_connect(
+    addrs: &[SocketAddr],
+    config: &DialConfig,
+) -> Result<TcpStream> {
+    config.retry.run(|| race_addrs(addrs, config)).await
+}
+
+async fn race_addrs(addrs: &[SocketAddr], config: &DialConfig) -> Result<TcpStream> {
+    let mut pending = interleave_addrs(addrs, config.prefer_ipv6).into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+
+    while pending.peek().is_some() || !attempts.is_empty() {
+        if let Some(addr) = pending.next() {
+            let config = *config;
+            attempts.spawn(async move { config.connect(addr).await });
+        }
+        tokio::select! {
+            Some(ret) = attempts.join_next() => match ret {
+                Ok(Ok(tcp_stream)) => return Ok(tcp_stream),
+                Ok(Err(e)) => keep_meaningful(&mut last_err, e),
+                Err(e) => keep_meaningful(&mut last_err, Error::other(e)),
+            },
+            _ = tokio::time::sleep(config.attempt_delay), if pending.peek().is_some() => {}
+            else => break,
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::InvalidInput, "No address to connect to")))
+}
+
+/// Both ends of a loopback connection, the first connected and the second
+/// accepted, for an outbound relaying a stream of its own to hand out a
+/// [TcpStream] like any other dialer, or a listener to hand a stream it
+/// terminated to the handlers of accepted connections
+pub async fn loopback_pair() -> Result<(TcpStream, TcpStream)> {
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let socket = TcpSocket::new_v4()?;
+    socket.bind((Ipv4Addr::LOCALHOST, 0).into())?;
+    let local_addr = socket.local_addr()?;
+    /* Only the connection made here, should another process connect too */
+    let accept = async {
+        loop {
+            let (accepted, peer) = listener.accept().await?;
+            if peer == local_addr {
+                return Ok::<_, Error>(accepted);
+            }
+        }
+    };
+    tokio::try_join!(socket.connect(listener.local_addr()?), accept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DialConfig, Ipv6Source, OutboundBind, happy_eyeballs_connect, interleave_addrs};
+
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_interleave_addrs() {
+        let addrs: Vec<SocketAddr> = ["1.1.1.1:1", "2.2.2.2:1", "[::1]:1", "[::2]:1", "[::3]:1"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let sorted = interleave_addrs(&addrs, true);
+        assert_eq!(sorted, vec![addrs[2], addrs[0], addrs[3], addrs[1], addrs[4]]);
+        let sorted = interleave_addrs(&addrs, false);
+        assert_eq!(sorted, vec![addrs[0], addrs[2], addrs[1], addrs[3], addrs[4]]);
+        let sorted = interleave_addrs(&addrs[..2], true);
+        assert_eq!(sorted, addrs[..2].to_vec());
+    }
+
+    #[test]
+    fn test_happy_eyeballs_connect() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let refused_addr = {
+                let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+                listener.local_addr()?
+            };
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            let addrs = [refused_addr, listener.local_addr()?];
+
+            let tcp_stream = happy_eyeballs_connect(&addrs, &DialConfig::default()).await?;
+            assert_eq!(tcp_stream.peer_addr()?, listener.local_addr()?);
+
+            assert!(happy_eyeballs_connect(&addrs[..1], &DialConfig::default()).await.is_err());
+            assert!(happy_eyeballs_connect(&[], &DialConfig::default()).await.is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_ipv6_source() -> std::io::Result<()> {
+        assert_eq!("temporary".parse(), Ok(Ipv6Source::Temporary));
+        let prefix = "::1/128".parse::<Ipv6Source>().unwrap();
+        assert_eq!(prefix.to_string(), "::1/128");
+        assert!("10.0.0.0/8".parse::<Ipv6Source>().is_err());
+
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let Ok(listener) = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).await else {
+                /* No IPv6 loopback here */
+                return Ok(());
+            };
+            let config = DialConfig { ipv6_source: prefix, ..Default::default() };
+            let tcp_stream = config.connect(listener.local_addr()?).await?;
+            assert_eq!(tcp_stream.local_addr()?.ip(), Ipv6Addr::LOCALHOST);
+
+            let config =
+                DialConfig { ipv6_source: "2001:db8::/32".parse().unwrap(), ..Default::default() };
+            assert!(config.connect(listener.local_addr()?).await.is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_outbound_bind() -> std::io::Result<()> {
+        let bind = "en0".parse::<OutboundBind>().unwrap();
+        assert!(matches!(bind, OutboundBind::Interface(name) if name.as_str() == "en0"));
+        assert_eq!(bind.to_string(), "en0");
+        assert_eq!("127.0.0.1".parse(), Ok(OutboundBind::Address(Ipv4Addr::LOCALHOST.into())));
+        assert!("".parse::<OutboundBind>().is_err());
+        assert!("an-interface-name".parse::<OutboundBind>().is_err());
+        assert!("10.0.0.0/8".parse::<OutboundBind>().is_err());
+
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            let config = DialConfig { bind: "127.0.0.1".parse().ok(), ..Default::default() };
+            let tcp_stream = config.connect(listener.local_addr()?).await?;
+            assert_eq!(tcp_stream.local_addr()?.ip(), Ipv4Addr::LOCALHOST);
+            assert_eq!(config.udp_socket()?.local_addr()?.ip(), Ipv4Addr::LOCALHOST);
+            let v6_addr = SocketAddr::from((Ipv6Addr::LOCALHOST, listener.local_addr()?.port()));
+            assert!(config.connect(v6_addr).await.is_err());
+
+            let config = DialConfig { bind: "nstream-none0".parse().ok(), ..Default::default() };
+            assert!(config.connect(listener.local_addr()?).await.is_err());
+            assert!(config.udp_socket().is_err());
+            Ok(())
+        })
+    }
+}
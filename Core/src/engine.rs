@@ -0,0 +1,792 @@
+//! In-process SOCKS5 server for embedding in other applications, built with
+//! the `engine-lite` feature
+//!
+//! Only CONNECT without authentication is served. The [Router] picks for
+//! each destination between the [Direct] and [Reject] dialers, and relaying
+//! through an upstream SOCKS5 proxy when one is set. Build with
+//! `default-features = false` to leave out tunnel interfaces, GeoIP, STUN
+//! and the obfuscators.
+//!
+//! Clients are usually accepted on a [TcpListener], [Engine::serve_stream]
+//! serves one over any byte stream instead, e.g. an in-memory duplex in
+//! tests, stdio joined with [tokio::io::join] or an SSH channel, and
+//! [socks5_connect] is the client side.
+//!
+//! With several upstreams, an [UpstreamPool] orders them for each
+//! destination and the next one is tried when connecting to one fails.
+//!
+//! The first bytes of each client are classified once the destination is
+//! connected, a flow that a `PROTOCOL` rule rejects is then reset. Other
+//! `PROTOCOL` actions come too late to pick the dialer and are ignored,
+//! unless the destination is sniffed, see [Engine::sniff].
+//!
+//! With [Engine::audit], an [AuditRecord] of each CONNECT is handed over as
+//! the connection closes, but for those sampled out by the `log-sample` of
+//! the rule they matched.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use socket2::SockRef;
+use socks5::protocol::{
+    Address, AuthMethod, Command, HandshakeRequest, HandshakeResponse, ReplyField, ReplyResponse,
+    TellRequest,
+};
+use socks5::{Socks5Error, exchange_data, exchange_data_timeout, with_deadline};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+use crate::{
+    AuditRecord, AuditSink, ClassStats, CloseReason, DecisionCache, DecisionKey, DecisionStats,
+    DialConfig, Dialer, Direct, LogSampler, Reject, Rejection, ResolveStats, Router, Rule,
+    RuleAction, SelectStrategy, Sniffed, TrafficClass, UpstreamPool, classify_stream,
+    keep_meaningful, sniff, sniff_host,
+};
+
+/// Until when a client may take to send its request, by default
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct Engine {
+    router: RwLock<Router>,
+    /// What the router decided, emptied with each change of the rules
+    decisions: DecisionCache,
+    dial_config: DialConfig,
+    direct: Arc<dyn Dialer>,
+    reject: Arc<dyn Dialer>,
+    /// Where [RuleAction::Proxy] destinations are relayed, they go to the
+    /// direct dialer when unset
+    upstreams: Option<UpstreamPool>,
+    handshake_timeout: Duration,
+    /// How long the host name of IP destinations is sniffed for, if at all
+    sniff_timeout: Option<Duration>,
+    /// How long a flow may stay half-closed, unbounded when unset
+    half_close_timeout: Option<Duration>,
+    resolve_stats: ResolveStats,
+    class_stats: ClassStats,
+    audit: Option<Arc<dyn AuditSink>>,
+    log_sampler: LogSampler,
+}
+
+impl Engine {
+    pub fn new(router: Router, dial_config: DialConfig) -> Self {
+        let resolve_stats = ResolveStats::default();
+        let mut direct = Direct::new(dial_config);
+        direct.resolve_stats(resolve_stats.clone());
+        Self {
+            router: RwLock::new(router),
+            decisions: DecisionCache::default(),
+            dial_config,
+            direct: Arc::new(direct),
+            reject: Arc::new(Reject::default()),
+            upstreams: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            sniff_timeout: None,
+            half_close_timeout: None,
+            resolve_stats,
+            class_stats: ClassStats::default(),
+            audit: None,
+            log_sampler: LogSampler::default(),
+        }
+    }
+
+    /// Dial [RuleAction::Direct] destinations with `direct`, e.g. a
+    /// [Direct] bound to a local address
+    pub fn direct(&mut self, direct: impl Dialer + 'static) -> &mut Self {
+        self.direct = Arc::new(direct);
+        self
+    }
+
+    /// Answer [RuleAction::Reject] destinations with `reject`
+    pub fn reject(&mut self, reject: impl Dialer + 'static) -> &mut Self {
+        self.reject = Arc::new(reject);
+        self
+    }
+
+    /// Relay [RuleAction::Proxy] destinations through the SOCKS5 proxy at
+    /// `upstream`, which must not require authentication
+    pub fn upstream(&mut self, upstream: SocketAddr) -> &mut Self {
+        self.upstreams(UpstreamPool::new(vec![upstream], SelectStrategy::Fallback))
+    }
+
+    /// Relay [RuleAction::Proxy] destinations through the SOCKS5 proxies of
+    /// `upstreams`, none of which may require authentication. Probing them
+    /// with [UpstreamPool::health_check] is up to the caller.
+    pub fn upstreams(&mut self, upstreams: UpstreamPool) -> &mut Self {
+        self.upstreams = Some(upstreams);
+        self
+    }
+
+    pub fn handshake_timeout(&mut self, handshake_timeout: Duration) -> &mut Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// Sniff the TLS server name or HTTP Host of destinations requested by
+    /// IP address, waiting up to `timeout` for the client to send them, and
+    /// match them against the domain rules. The reply is then sent before
+    /// dialing, a destination failing to connect or rejected is reset.
+    pub fn sniff(&mut self, timeout: Duration) -> &mut Self {
+        self.sniff_timeout = Some(timeout);
+        self
+    }
+
+    /// Close flows `timeout` after one side closed its direction, should
+    /// the other not close its own by then. Half-closed flows are relayed
+    /// until both sides close otherwise.
+    pub fn half_close_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.half_close_timeout = Some(timeout);
+        self
+    }
+
+    /// Hand the record of each CONNECT to `audit` as it closes
+    pub fn audit(&mut self, audit: impl AuditSink + 'static) -> &mut Self {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+
+    /// Keep up to `capacity` router decisions, 0 to match the rules for
+    /// every connection
+    pub fn decision_cache(&mut self, capacity: usize) -> &mut Self {
+        self.decisions = DecisionCache::with_stats(capacity, self.decisions.stats().clone());
+        self
+    }
+
+    /// Counters of the names resolved by the default direct dialer
+    #[inline]
+    pub fn resolve_stats(&self) -> &ResolveStats {
+        &self.resolve_stats
+    }
+
+    /// Counters of the relayed flows by their class
+    #[inline]
+    pub fn class_stats(&self) -> &ClassStats {
+        &self.class_stats
+    }
+
+    /// Hits and misses of the router decisions cached
+    #[inline]
+    pub fn decision_stats(&self) -> &DecisionStats {
+        self.decisions.stats()
+    }
+
+    /// Replace the rules, forgetting the decisions made with the previous
+    pub fn set_rules(&self, rules: Vec<Rule>) {
+        self.router.write().unwrap().set_rules(rules);
+        self.decisions.invalidate();
+    }
+
+    /// Accept and serve clients until accepting fails
+    pub async fn serve(self: Arc<Self>, tcp_listener: TcpListener) -> Result<()> {
+        loop {
+            let (tcp_stream, _) = tcp_listener.accept().await?;
+            let engine = self.clone();
+            tokio::spawn(async move { engine.handle(tcp_stream).await });
+        }
+    }
+
+    /// Serve a single client
+    pub async fn handle(&self, tcp_stream: TcpStream) -> Result<()> {
+        let endpoints = (tcp_stream.peer_addr().ok(), tcp_stream.local_addr().ok());
+        /* Closing with a zero linger sends a RST */
+        self.serve_client(tcp_stream, endpoints, |tcp_stream| {
+            SockRef::from(tcp_stream).set_linger(Some(Duration::ZERO))
+        })
+        .await
+    }
+
+    /// Serve a single client over `stream`, which the client side of may be
+    /// driven by [socks5_connect]. Flows that a TCP client would see reset
+    /// are closed instead.
+    pub async fn serve_stream<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.serve_client(stream, (None, None), |_| Ok(())).await
+    }
+
+    /// `endpoints` are the peer and local addresses of the client, if any.
+    /// `reset` aborts the connection, which is closed once dropped.
+    async fn serve_client<S>(
+        &self,
+        mut stream: S,
+        endpoints: (Option<SocketAddr>, Option<SocketAddr>),
+        reset: fn(&S) -> Result<()>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let deadline = Instant::now() + self.handshake_timeout;
+        let hreq = with_deadline(deadline, HandshakeRequest::from(&mut stream)).await?;
+        let hresp =
+            HandshakeResponse::new(hreq.select_method(&[AuthMethod::NoAuthenticationRequired]));
+        hresp.write_to(&mut stream).await?;
+        if hresp.method() == AuthMethod::NoAcceptableMethods {
+            return stream.shutdown().await;
+        }
+
+        let tellreq = match with_deadline(deadline, TellRequest::from(&mut stream)).await {
+            Ok(tellreq) => tellreq,
+            Err(e) => {
+                if let Some(rep) = Socks5Error::of(&e).and_then(Socks5Error::reply_field) {
+                    ReplyResponse::failed(rep).respond_with(&mut stream).await?;
+                    stream.shutdown().await?;
+                }
+                return Err(e);
+            }
+        };
+        if tellreq.cmd() != Command::Connect {
+            let rep_resp = ReplyResponse::failed(ReplyField::CommandNotSupported);
+            rep_resp.respond_with(&mut stream).await?;
+            return stream.shutdown().await;
+        }
+        let started = Instant::now();
+        let mut record = AuditRecord::new(endpoints.0, endpoints.1, tellreq.addr());
+        let ret = self.serve_connect(&mut stream, reset, &mut record).await;
+        if let Some(audit) = &self.audit {
+            record.duration = started.elapsed();
+            if let (Err(e), CloseReason::Completed) = (&ret, &record.reason) {
+                record.reason = CloseReason::Error(e.kind());
+            }
+            if record.is_notable() || self.sampled(&record) {
+                audit.record(&record);
+            }
+        }
+        ret
+    }
+
+    /// Serve the CONNECT to the destination of `record`, filling it in
+    async fn serve_connect<S>(
+        &self,
+        stream: &mut S,
+        reset: fn(&S) -> Result<()>,
+        record: &mut AuditRecord,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let addr = record.destination.clone();
+        if let (Some(sniff_timeout), Address::IP(socket_addr)) = (self.sniff_timeout, &addr) {
+            let ip = socket_addr.ip();
+            return self.handle_sniffed(stream, reset, record, ip, sniff_timeout).await;
+        }
+        record.action = self.decide(&addr, None);
+        let outbound_ret = self.connect(record.action, &addr).await;
+        let rep = match &outbound_ret {
+            Err(e) => match Rejection::of(e) {
+                Some(Rejection::Reset) => {
+                    record.reason = CloseReason::Reset;
+                    return reset(stream);
+                }
+                Some(Rejection::Reply(rep)) => rep.clone(),
+                None => e.into(),
+            },
+            Ok(_) => ReplyField::Succeeded,
+        };
+        let rep_resp = ReplyResponse::new(rep, Address::default());
+        rep_resp.respond_with(stream).await?;
+        match outbound_ret {
+            Ok(mut outbound) if rep_resp.rep() == ReplyField::Succeeded => {
+                let (first, class) = Self::classify(stream, &outbound).await?;
+                self.class_stats.record(class);
+                record.class = class;
+                if let Sniffed::Host(host) = sniff_host(&first) {
+                    record.sni = Some(host);
+                }
+                if class.is_some() && self.decide(&addr, class) == RuleAction::Reject {
+                    /* Too late for a reply, the flow is reset instead */
+                    record.action = RuleAction::Reject;
+                    record.reason = CloseReason::Reset;
+                    return reset(stream);
+                }
+                outbound.write_all(&first).await?;
+                record.bytes_sent = first.len() as u64;
+                self.relay(stream, &mut outbound, record).await
+            }
+            _ => {
+                record.reason = CloseReason::Refused(rep_resp.rep());
+                stream.shutdown().await
+            }
+        }
+    }
+
+    /// Exchange data until both sides close, or the half-close timeout
+    /// passes, adding the bytes relayed to `record`
+    async fn relay<S>(
+        &self,
+        stream: &mut S,
+        outbound: &mut TcpStream,
+        record: &mut AuditRecord,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (sent, received) = match self.half_close_timeout {
+            Some(timeout) => exchange_data_timeout(stream, outbound, timeout).await?,
+            None => exchange_data(stream, outbound).await?,
+        };
+        record.bytes_sent += sent;
+        record.bytes_received += received;
+        Ok(())
+    }
+
+    /// Reply before dialing, so that the client sends the bytes its host
+    /// name is sniffed from, then replay them to the outbound
+    async fn handle_sniffed<S>(
+        &self,
+        stream: &mut S,
+        reset: fn(&S) -> Result<()>,
+        record: &mut AuditRecord,
+        ip: IpAddr,
+        sniff_timeout: Duration,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let rep_resp = ReplyResponse::new(ReplyField::Succeeded, Address::default());
+        rep_resp.respond_with(stream).await?;
+        let (first, host) = sniff(stream, sniff_timeout).await?;
+        let class = classify_stream(&first);
+        self.class_stats.record(class);
+        let action = self.decide_cached(host.as_deref(), Some(ip), class);
+        (record.sni, record.action, record.class) = (host, action, class);
+        match self.connect(action, &record.destination).await {
+            Ok(mut outbound) => {
+                outbound.write_all(&first).await?;
+                record.bytes_sent = first.len() as u64;
+                self.relay(stream, &mut outbound, record).await
+            }
+            Err(_) => {
+                record.reason = CloseReason::Reset;
+                reset(stream)
+            }
+        }
+    }
+
+    /// Read the first bytes of the client to classify the flow, which are
+    /// then up to the caller to relay. The class is None when the
+    /// destination speaks first.
+    async fn classify<S>(
+        stream: &mut S,
+        outbound: &TcpStream,
+    ) -> Result<(Vec<u8>, Option<TrafficClass>)>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0u8; 64];
+        tokio::select! {
+            ret = stream.read(&mut buf) => {
+                buf.truncate(ret?);
+                let class = classify_stream(&buf);
+                Ok((buf, class))
+            }
+            _ = outbound.readable() => Ok((vec![], None)),
+        }
+    }
+
+    fn decide(&self, addr: &Address, class: Option<TrafficClass>) -> RuleAction {
+        match addr {
+            Address::IP(socket_addr) => self.decide_cached(None, Some(socket_addr.ip()), class),
+            Address::Domain(name, _) => self.decide_cached(Some(name), None, class),
+        }
+    }
+
+    /// Whether the `log-sample` of the rule `record` matched picks it
+    fn sampled(&self, record: &AuditRecord) -> bool {
+        let router = self.router.read().unwrap();
+        let rule = match &record.destination {
+            Address::IP(socket_addr) => {
+                router.matched_rule_classified(None, Some(socket_addr.ip()), record.class)
+            }
+            Address::Domain(name, _) => {
+                router.matched_rule_classified(Some(name), None, record.class)
+            }
+        };
+        self.log_sampler.sample(rule)
+    }
+
+    fn decide_cached(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        class: Option<TrafficClass>,
+    ) -> RuleAction {
+        let key = DecisionKey::new(None, domain, ip, class);
+        self.decisions
+            .decide(key, || self.router.read().unwrap().decide_classified(domain, ip, class))
+    }
+
+    async fn connect(&self, action: RuleAction, addr: &Address) -> Result<TcpStream> {
+        match (action, &self.upstreams) {
+            (RuleAction::Proxy, Some(upstreams)) => self.connect_upstreams(upstreams, addr).await,
+            (RuleAction::Reject, _) => self.reject.dial(addr).await,
+            _ => self.direct.dial(addr).await,
+        }
+    }
+
+    /// Try the upstreams in the order of the pool until one connects
+    async fn connect_upstreams(
+        &self,
+        upstreams: &UpstreamPool,
+        addr: &Address,
+    ) -> Result<TcpStream> {
+        let host = match addr {
+            Address::IP(socket_addr) => socket_addr.ip().to_string(),
+            Address::Domain(name, _) => name.to_owned(),
+        };
+        /* Every upstream is tried before a retry */
+        let tcp_stream =
+            self.dial_config.retry.run(|| self.connect_any_upstream(upstreams, &host)).await?;
+        /* Past connecting, failures are the destination's */
+        self.connect_upstream(tcp_stream, addr).await
+    }
+
+    /// A connection to the first upstream of the candidates for `host` to
+    /// accept one
+    async fn connect_any_upstream(
+        &self,
+        upstreams: &UpstreamPool,
+        host: &str,
+    ) -> Result<TcpStream> {
+        let mut last_err = None;
+        for upstream in upstreams.candidates(host) {
+            let start = Instant::now();
+            match self.dial_config.connect(upstream).await {
+                Ok(tcp_stream) => {
+                    upstreams.record_success(upstream, start.elapsed());
+                    return Ok(tcp_stream);
+                }
+                Err(e) => {
+                    upstreams.record_failure(upstream);
+                    keep_meaningful(&mut last_err, e);
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| Error::new(ErrorKind::AddrNotAvailable, "No upstream configured")))
+    }
+
+    async fn connect_upstream(&self, tcp_stream: TcpStream, addr: &Address) -> Result<TcpStream> {
+        socks5_connect(tcp_stream, addr).await
+    }
+}
+
+/// Request a CONNECT to `addr` from the SOCKS5 proxy at the other end of
+/// `stream`, which must not require authentication, and return the stream
+/// once the proxy relays it
+pub async fn socks5_connect<S>(mut stream: S, addr: &Address) -> Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    HandshakeRequest::new(vec![AuthMethod::NoAuthenticationRequired]).write_to(&mut stream).await?;
+    let hresp = HandshakeResponse::from(&mut stream).await?;
+    if hresp.method() != AuthMethod::NoAuthenticationRequired {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Upstream proxy requires authentication",
+        ));
+    }
+    TellRequest::connect(addr.clone()).write_to(&mut stream).await?;
+    let rep_resp = ReplyResponse::from(&mut stream).await?;
+    if rep_resp.rep() != ReplyField::Succeeded {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Upstream proxy replied {:?}", rep_resp.rep()),
+        ));
+    }
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Engine, socks5_connect};
+    use crate::{
+        CloseReason, DialConfig, Router, RuleAction, SelectStrategy, TrafficClass, UpstreamPool,
+    };
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use socks5::protocol::{
+        Address, AuthMethod, HandshakeRequest, HandshakeResponse, ReplyField, ReplyResponse,
+        TellRequest,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn spawn_engine(engine: Engine) -> std::io::Result<SocketAddr> {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = tcp_listener.local_addr()?;
+        tokio::spawn(Arc::new(engine).serve(tcp_listener));
+        Ok(addr)
+    }
+
+    /// An echo server for any number of clients
+    async fn spawn_echo() -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (mut tcp_stream, _) = listener.accept().await?;
+                tokio::spawn(async move {
+                    let (mut r, mut w) = tcp_stream.split();
+                    tokio::io::copy(&mut r, &mut w).await
+                });
+            }
+            #[allow(unreachable_code)]
+            Ok::<_, std::io::Error>(())
+        });
+        Ok(addr)
+    }
+
+    #[test]
+    fn test_engine_connect() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let echo_listener = TcpListener::bind("127.0.0.1:0").await?;
+            let echo_addr = echo_listener.local_addr()?;
+            tokio::spawn(async move {
+                let (mut tcp_stream, _) = echo_listener.accept().await?;
+                let (mut r, mut w) = tcp_stream.split();
+                tokio::io::copy(&mut r, &mut w).await?;
+                Ok::<_, std::io::Error>(())
+            });
+
+            /* The first engine relays everything through the second one */
+            let upstream_addr =
+                spawn_engine(Engine::new(Router::default(), DialConfig::default())).await?;
+            let mut engine = Engine::new(
+                Router::new(vec!["MATCH,PROXY".parse().unwrap()]),
+                DialConfig::default(),
+            );
+            engine.upstream(upstream_addr);
+            let engine_addr = spawn_engine(engine).await?;
+
+            let mut tcp_stream = TcpStream::connect(engine_addr).await?;
+            HandshakeRequest::new(vec![AuthMethod::NoAuthenticationRequired])
+                .write_to(&mut tcp_stream)
+                .await?;
+            let hresp = HandshakeResponse::from(&mut tcp_stream).await?;
+            assert_eq!(hresp.method(), AuthMethod::NoAuthenticationRequired);
+            TellRequest::connect(echo_addr).write_to(&mut tcp_stream).await?;
+            let rep_resp = ReplyResponse::from(&mut tcp_stream).await?;
+            assert_eq!(rep_resp.rep(), ReplyField::Succeeded);
+
+            tcp_stream.write_all(b"ping").await?;
+            let mut buf = [0u8; 4];
+            tcp_stream.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"ping");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_engine_reject() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let router = Router::new(vec!["DOMAIN-SUFFIX,ads.example,REJECT".parse().unwrap()]);
+            let engine_addr = spawn_engine(Engine::new(router, DialConfig::default())).await?;
+
+            let mut tcp_stream = TcpStream::connect(engine_addr).await?;
+            HandshakeRequest::new(vec![AuthMethod::NoAuthenticationRequired])
+                .write_to(&mut tcp_stream)
+                .await?;
+            HandshakeResponse::from(&mut tcp_stream).await?;
+            TellRequest::connect(Address::Domain(String::from("tracker.ads.example"), 443))
+                .write_to(&mut tcp_stream)
+                .await?;
+            let rep_resp = ReplyResponse::from(&mut tcp_stream).await?;
+            assert_eq!(rep_resp.rep(), ReplyField::ConnectionNotAllowedByRuleSet);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_engine_decision_cache() {
+        let router = Router::new(vec!["DOMAIN-SUFFIX,ads.example,REJECT".parse().unwrap()]);
+        let engine = Engine::new(router, DialConfig::default());
+        let addr = Address::Domain(String::from("tracker.ads.example"), 443);
+        assert_eq!(engine.decide(&addr, None), RuleAction::Reject);
+        assert_eq!(engine.decide(&addr, None), RuleAction::Reject);
+        assert_eq!(engine.decision_stats().hits(), 1);
+
+        /* Not decided by the rules replaced */
+        engine.set_rules(vec!["MATCH,PROXY".parse().unwrap()]);
+        assert_eq!(engine.decide(&addr, None), RuleAction::Proxy);
+        assert_eq!(engine.decision_stats().misses(), 2);
+    }
+
+    #[test]
+    fn test_engine_reject_protocol() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let addr = spawn_echo().await?;
+            let router = Router::new(vec![
+                "PROTOCOL,SSH,REJECT".parse().unwrap(),
+                "MATCH,DIRECT".parse().unwrap(),
+            ]);
+            let engine = Engine::new(router, DialConfig::default());
+            let class_stats = engine.class_stats().clone();
+            let engine_addr = spawn_engine(engine).await?;
+
+            for (first, relayed) in [(&b"GET / HTTP/1.1\r\n"[..], true), (b"SSH-2.0-x\r\n", false)]
+            {
+                let mut tcp_stream = TcpStream::connect(engine_addr).await?;
+                HandshakeRequest::new(vec![AuthMethod::NoAuthenticationRequired])
+                    .write_to(&mut tcp_stream)
+                    .await?;
+                HandshakeResponse::from(&mut tcp_stream).await?;
+                TellRequest::connect(addr).write_to(&mut tcp_stream).await?;
+                let rep_resp = ReplyResponse::from(&mut tcp_stream).await?;
+                assert_eq!(rep_resp.rep(), ReplyField::Succeeded);
+
+                tcp_stream.write_all(first).await?;
+                let mut buf = vec![0u8; first.len()];
+                assert_eq!(tcp_stream.read_exact(&mut buf).await.is_ok(), relayed);
+            }
+            assert_eq!(class_stats.count(Some(TrafficClass::Http)), 1);
+            assert_eq!(class_stats.count(Some(TrafficClass::Ssh)), 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_engine_sniff() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let addr = spawn_echo().await?;
+            let router = Router::new(vec![
+                "DOMAIN-SUFFIX,blocked.example,REJECT".parse().unwrap(),
+                "MATCH,DIRECT".parse().unwrap(),
+            ]);
+            let mut engine = Engine::new(router, DialConfig::default());
+            engine.sniff(Duration::from_secs(1));
+            let engine_addr = spawn_engine(engine).await?;
+
+            for (host, relayed) in [("allowed.example", true), ("www.blocked.example", false)] {
+                let mut tcp_stream = TcpStream::connect(engine_addr).await?;
+                HandshakeRequest::new(vec![AuthMethod::NoAuthenticationRequired])
+                    .write_to(&mut tcp_stream)
+                    .await?;
+                HandshakeResponse::from(&mut tcp_stream).await?;
+                TellRequest::connect(addr).write_to(&mut tcp_stream).await?;
+                let rep_resp = ReplyResponse::from(&mut tcp_stream).await?;
+                assert_eq!(rep_resp.rep(), ReplyField::Succeeded);
+
+                /* Sent in two pieces, the engine waits for the whole head */
+                let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+                tcp_stream.write_all(&request.as_bytes()[..10]).await?;
+                tcp_stream.write_all(&request.as_bytes()[10..]).await?;
+                let mut buf = vec![0u8; request.len()];
+                let ret = tcp_stream.read_exact(&mut buf).await;
+                assert_eq!(ret.is_ok(), relayed);
+                if relayed {
+                    assert_eq!(buf, request.as_bytes());
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_engine_upstream_fallback() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let addr = spawn_echo().await?;
+            let upstream_addr =
+                spawn_engine(Engine::new(Router::default(), DialConfig::default())).await?;
+            let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+
+            let pool = UpstreamPool::new(vec![closed, upstream_addr], SelectStrategy::Fallback);
+            let mut engine = Engine::new(
+                Router::new(vec!["MATCH,PROXY".parse().unwrap()]),
+                DialConfig::default(),
+            );
+            engine.upstreams(pool.clone());
+            let engine_addr = spawn_engine(engine).await?;
+
+            let mut tcp_stream = TcpStream::connect(engine_addr).await?;
+            HandshakeRequest::new(vec![AuthMethod::NoAuthenticationRequired])
+                .write_to(&mut tcp_stream)
+                .await?;
+            HandshakeResponse::from(&mut tcp_stream).await?;
+            TellRequest::connect(addr).write_to(&mut tcp_stream).await?;
+            let rep_resp = ReplyResponse::from(&mut tcp_stream).await?;
+            assert_eq!(rep_resp.rep(), ReplyField::Succeeded);
+
+            let health = pool.upstreams().map(|(_, health)| health).collect::<Vec<_>>();
+            assert_eq!((health[0].successes(), health[0].failures()), (0, 1));
+            assert_eq!((health[1].successes(), health[1].failures()), (1, 0));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_engine_audit() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let addr = spawn_echo().await?;
+            let router = Router::new(vec![
+                "DOMAIN-SUFFIX,ads.example,REJECT".parse().unwrap(),
+                "MATCH,DIRECT".parse().unwrap(),
+            ]);
+            let (audit, mut records) = tokio::sync::mpsc::unbounded_channel();
+            let mut engine = Engine::new(router, DialConfig::default());
+            engine.audit(audit);
+            let engine_addr = spawn_engine(engine).await?;
+
+            let tcp_stream = TcpStream::connect(engine_addr).await?;
+            let client = tcp_stream.local_addr()?;
+            let mut stream = socks5_connect(tcp_stream, &Address::IP(addr)).await?;
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: echo.example\r\n\r\n").await?;
+            let mut buf = [0u8; 38];
+            stream.read_exact(&mut buf).await?;
+            stream.shutdown().await?;
+            assert_eq!(stream.read(&mut buf).await?, 0);
+            drop(stream);
+
+            /* In one record once closed */
+            let record = records.recv().await.unwrap();
+            assert_eq!((record.peer, record.local), (Some(client), Some(engine_addr)));
+            assert_eq!(record.destination, Address::IP(addr));
+            assert_eq!(record.sni.as_deref(), Some("echo.example"));
+            assert_eq!(record.class, Some(TrafficClass::Http));
+            assert_eq!((record.bytes_sent, record.bytes_received), (38, 38));
+            assert_eq!(record.reason, CloseReason::Completed);
+
+            let tcp_stream = TcpStream::connect(engine_addr).await?;
+            let addr = Address::Domain(String::from("tracker.ads.example"), 443);
+            assert!(socks5_connect(tcp_stream, &addr).await.is_err());
+            let record = records.recv().await.unwrap();
+            assert_eq!(record.destination, addr);
+            assert_eq!((record.bytes_sent, record.bytes_received), (0, 0));
+            assert_eq!(
+                record.reason,
+                CloseReason::Refused(ReplyField::ConnectionNotAllowedByRuleSet)
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_serve_stream() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let addr = spawn_echo().await?;
+            let engine = Arc::new(Engine::new(Router::default(), DialConfig::default()));
+            let (client, server) = tokio::io::duplex(1024);
+            tokio::spawn(async move { engine.serve_stream(server).await });
+
+            let mut stream = socks5_connect(client, &Address::IP(addr)).await?;
+            stream.write_all(b"ping").await?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"ping");
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,308 @@
+//! Fake-IP, routing by domain when applications resolve names themselves
+//!
+//! Intercepted DNS queries are answered with addresses allocated from a
+//! reserved range such as `198.18.0.0/15`, and the domain each address
+//! stands for is remembered for a while, so that a connection to a fake
+//! address can be made to the domain instead.
+
+use crate::IpCidr;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The benchmarking range of RFC 2544, unused on real networks
+pub const DEFAULT_FAKE_IP_RANGE: &str = "198.18.0.0/15";
+
+/// How long an address is remembered since it was last handed out
+pub const DEFAULT_FAKE_IP_TTL: Duration = Duration::from_secs(600);
+
+/// TTL of the DNS answers, kept short so that applications ask again
+/// rather than hold on to an address that has been given to another domain
+pub const FAKE_IP_ANSWER_TTL: u32 = 1;
+
+/// Candidates looked at before evicting the mapping that expires first
+const MAX_SCAN: u128 = 1024;
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CLASS_IN: u16 = 1;
+
+/// A query answered by [FakeIpPool::answer_query]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeAnswer {
+    /// The name asked about, as it was written in the question
+    pub domain: String,
+    /// The address answered, None for the query types given no address
+    pub ip: Option<IpAddr>,
+    pub response: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Mappings {
+    by_domain: HashMap<String, IpAddr>,
+    by_ip: HashMap<IpAddr, (String, Instant)>,
+    /// Offset within the range of the next address to try
+    next: u128,
+}
+
+impl Mappings {
+    fn unmap(&mut self, ip: &IpAddr) {
+        if let Some((domain, _)) = self.by_ip.remove(ip) {
+            self.by_domain.remove(&domain);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FakeIpPool {
+    range: IpCidr,
+    ttl: Duration,
+    mappings: Mutex<Mappings>,
+}
+
+impl FakeIpPool {
+    /// The network address of `range`, and the broadcast one of IPv4
+    /// ranges, are never handed out
+    pub fn new(range: IpCidr, ttl: Duration) -> Result<Self, String> {
+        let bits = if range.addr().is_ipv4() { 32 } else { 128 };
+        if range.prefix_len() + 2 > bits {
+            return Err(format!("{} is too small for a fake IP range", range));
+        }
+        /* From the network address on, whatever address the range was written with */
+        let network = match range.addr() {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - range.prefix_len() as u32).unwrap_or(0);
+                IpAddr::V4((u32::from(ip) & mask).into())
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - range.prefix_len() as u32).unwrap_or(0);
+                IpAddr::V6((u128::from(ip) & mask).into())
+            }
+        };
+        let range = IpCidr::new(network, range.prefix_len())?;
+        let mappings = Mappings { next: 1, ..Default::default() };
+        Ok(Self { range, ttl, mappings: Mutex::new(mappings) })
+    }
+
+    #[inline]
+    pub fn range(&self) -> IpCidr {
+        self.range
+    }
+
+    #[inline]
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.range.contains(ip)
+    }
+
+    /// Offsets of the addresses handed out, from 1
+    fn last_offset(&self) -> u128 {
+        let (bits, is_ipv4) = match self.range.addr() {
+            IpAddr::V4(_) => (32, true),
+            IpAddr::V6(_) => (128, false),
+        };
+        let host_bits = bits - self.range.prefix_len() as u32;
+        let size = 1u128.checked_shl(host_bits).unwrap_or(0).wrapping_sub(1);
+        if is_ipv4 { size - 1 } else { size }
+    }
+
+    fn nth(&self, offset: u128) -> IpAddr {
+        match self.range.addr() {
+            IpAddr::V4(net) => IpAddr::V4((u32::from(net) + offset as u32).into()),
+            IpAddr::V6(net) => IpAddr::V6((u128::from(net) + offset).into()),
+        }
+    }
+
+    /// The address standing for `domain`, allocated unless it has one
+    /// already, either way it is remembered for the TTL from now on
+    pub fn allocate(&self, domain: &str) -> IpAddr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let now = Instant::now();
+        let mut mappings = self.mappings.lock().unwrap();
+        if let Some(ip) = mappings.by_domain.get(&domain).copied() {
+            mappings.by_ip.insert(ip, (domain, now + self.ttl));
+            return ip;
+        }
+
+        let last_offset = self.last_offset();
+        let mut free = None;
+        for _ in 0..last_offset.min(MAX_SCAN) {
+            let ip = self.nth(mappings.next);
+            mappings.next = if mappings.next >= last_offset { 1 } else { mappings.next + 1 };
+            match mappings.by_ip.get(&ip) {
+                Some((_, expires)) if *expires > now => continue,
+                _ => {
+                    free = Some(ip);
+                    break;
+                }
+            }
+        }
+        /* All taken among those looked at, the one expiring first goes */
+        let ip = free.unwrap_or_else(|| {
+            *mappings.by_ip.iter().min_by_key(|(_, (_, expires))| *expires).unwrap().0
+        });
+        mappings.unmap(&ip);
+        mappings.by_domain.insert(domain.to_owned(), ip);
+        mappings.by_ip.insert(ip, (domain, now + self.ttl));
+        ip
+    }
+
+    /// The domain `ip` stands for, unless it has expired
+    pub fn domain_of(&self, ip: &IpAddr) -> Option<String> {
+        let mappings = self.mappings.lock().unwrap();
+        match mappings.by_ip.get(ip) {
+            Some((domain, expires)) if *expires > Instant::now() => Some(domain.to_owned()),
+            _ => None,
+        }
+    }
+
+    /// Forget the expired mappings
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        let mut mappings = self.mappings.lock().unwrap();
+        let expired = mappings
+            .by_ip
+            .iter()
+            .filter(|(_, (_, expires))| *expires <= now)
+            .map(|(ip, _)| *ip)
+            .collect::<Vec<_>>();
+        for ip in expired.iter() {
+            mappings.unmap(ip);
+        }
+    }
+
+    /// Number of addresses handed out and not expired
+    pub fn len(&self) -> usize {
+        let now = Instant::now();
+        let mappings = self.mappings.lock().unwrap();
+        mappings.by_ip.values().filter(|(_, expires)| *expires > now).count()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The response to the DNS query `query`, with a fake address for A
+    /// queries to an IPv4 range or AAAA queries to an IPv6 one, and no
+    /// address for the other types. None when `query` is not a standard
+    /// query of a single question.
+    #[inline]
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        self.answer_query(query).map(|answer| answer.response)
+    }
+
+    /// Same as [FakeIpPool::answer], along with what was asked and answered
+    pub fn answer_query(&self, query: &[u8]) -> Option<FakeAnswer> {
+        if query.len() < 12 || query[2] & 0xf8 != 0 || query[4..6] != [0, 1] {
+            return None;
+        }
+        let mut labels = vec![];
+        let mut pos = 12;
+        loop {
+            let len = *query.get(pos)? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            /* Compression pointers have no business in the question */
+            if len > 63 {
+                return None;
+            }
+            labels.push(std::str::from_utf8(query.get(pos..pos + len)?).ok()?);
+            pos += len;
+        }
+        let qtype = u16::from_be_bytes(query.get(pos..pos + 2)?.try_into().ok()?);
+        let qclass = u16::from_be_bytes(query.get(pos + 2..pos + 4)?.try_into().ok()?);
+        let question = &query[12..pos + 4];
+
+        let wanted = match self.range.addr() {
+            IpAddr::V4(_) => DNS_TYPE_A,
+            IpAddr::V6(_) => DNS_TYPE_AAAA,
+        };
+        let answered = qtype == wanted && qclass == DNS_CLASS_IN && !labels.is_empty();
+        let mut resp = Vec::with_capacity(query.len() + 28);
+        resp.extend_from_slice(&query[0..2]); /* ID */
+        resp.push(0x80 | 0x04 | (query[2] & 0x01)); /* QR, AA, RD */
+        resp.push(0x80); /* RA, NOERROR */
+        resp.extend_from_slice(&[0, 1, 0, answered as u8, 0, 0, 0, 0]);
+        resp.extend_from_slice(question);
+        let domain = labels.join(".");
+        let ip = answered.then(|| self.allocate(&domain));
+        if let Some(ip) = ip {
+            let rdata = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            resp.extend_from_slice(&[0xc0, 0x0c]); /* The name of the question */
+            resp.extend_from_slice(&wanted.to_be_bytes());
+            resp.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+            resp.extend_from_slice(&FAKE_IP_ANSWER_TTL.to_be_bytes());
+            resp.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            resp.extend_from_slice(&rdata);
+        }
+        Some(FakeAnswer { domain, ip, response: resp })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FakeIpPool;
+
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fake_ip_pool() {
+        let pool =
+            FakeIpPool::new("198.18.0.0/30".parse().unwrap(), Duration::from_secs(60)).unwrap();
+        let example = pool.allocate("Example.COM.");
+        assert_eq!(example, "198.18.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(pool.allocate("example.com"), example);
+        assert_eq!(pool.domain_of(&example).as_deref(), Some("example.com"));
+
+        /* Two addresses to hand out, the one expiring first is taken back */
+        let other = pool.allocate("other.net");
+        assert_eq!(other, "198.18.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(pool.allocate("third.org"), example);
+        assert_eq!(pool.domain_of(&example).as_deref(), Some("third.org"));
+        assert_eq!(pool.len(), 2);
+
+        assert!(FakeIpPool::new("198.18.0.0/31".parse().unwrap(), Duration::ZERO).is_err());
+        let expiring = FakeIpPool::new("fdfe::/120".parse().unwrap(), Duration::ZERO).unwrap();
+        let ip = expiring.allocate("example.com");
+        assert_eq!(expiring.domain_of(&ip), None);
+        expiring.purge_expired();
+        assert!(expiring.is_empty());
+    }
+
+    #[test]
+    fn test_fake_ip_answer() {
+        let pool =
+            FakeIpPool::new("198.18.0.0/15".parse().unwrap(), Duration::from_secs(60)).unwrap();
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&[0, 1, 0, 1]); /* A, IN */
+        let resp = pool.answer(&query).unwrap();
+        assert_eq!(&resp[..4], &[0x12, 0x34, 0x85, 0x80]);
+        assert_eq!(&resp[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&resp[12..query.len()], &query[12..]);
+        assert_eq!(&resp[resp.len() - 4..], &[198, 18, 0, 1]);
+        assert_eq!(pool.domain_of(&"198.18.0.1".parse().unwrap()).as_deref(), Some("example.com"));
+
+        /* AAAA of an IPv4 range, answered with no address */
+        let len = query.len();
+        query[len - 3] = 28;
+        let resp = pool.answer(&query).unwrap();
+        assert_eq!(&resp[6..8], &[0, 0]);
+        assert_eq!(resp.len(), query.len());
+        let answer = pool.answer_query(&query).unwrap();
+        assert_eq!((answer.domain.as_str(), answer.ip), ("example.com", None));
+        query[len - 3] = 1;
+        let answer = pool.answer_query(&query).unwrap();
+        assert_eq!(answer.ip, Some("198.18.0.1".parse().unwrap()));
+
+        assert_eq!(pool.answer(&query[..10]), None);
+    }
+}
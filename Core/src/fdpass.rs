@@ -0,0 +1,111 @@
+//! Passing file descriptors between processes over Unix domain sockets
+//! (`SCM_RIGHTS`)
+
+use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// The maximum number of descriptors sent along with one message
+pub const MAX_FDS_PER_MSG: usize = 16;
+
+/// Send `data` to the peer of `sock`, with `fds` attached to its first byte
+pub fn send_with_fds(sock: &UnixStream, data: &[u8], fds: &[RawFd]) -> Result<usize> {
+    if fds.len() > MAX_FDS_PER_MSG {
+        return Err(Error::new(ErrorKind::InvalidInput, "Too many file descriptors"));
+    }
+    let iov = [IoSlice::new(data)];
+    let fds_len = size_of_val(fds) as u32;
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = iov.as_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = iov.len() as _;
+    if !fds.is_empty() {
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_buf.len() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                libc::CMSG_DATA(cmsg),
+                fds_len as usize,
+            );
+        }
+    }
+
+    let ret = unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, 0) };
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(ret as usize) }
+}
+
+/// Receive into `buf` from the peer of `sock`, along with the descriptors
+/// attached to the received bytes
+pub fn recv_with_fds(sock: &UnixStream, buf: &mut [u8]) -> Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_buf = vec![
+        0u8;
+        unsafe { libc::CMSG_SPACE((MAX_FDS_PER_MSG * size_of::<RawFd>()) as u32) }
+            as usize
+    ];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = iov.as_mut_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = iov.len() as _;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    let ret = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut fds = vec![];
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..data_len / size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "File descriptors truncated"));
+    }
+    Ok((ret as usize, fds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{recv_with_fds, send_with_fds};
+
+    use std::io::{Read, Write};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_pass_fds() -> std::io::Result<()> {
+        let (sender, receiver) = UnixStream::pair()?;
+        let (mut passed, mut kept) = UnixStream::pair()?;
+
+        send_with_fds(&sender, b"hello", &[passed.as_raw_fd()])?;
+        let mut buf = [0u8; 16];
+        let (len, mut fds) = recv_with_fds(&receiver, &mut buf)?;
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(fds.len(), 1);
+
+        passed.write_all(b"!")?;
+        let mut received = UnixStream::from(fds.remove(0));
+        received.write_all(b"?")?;
+        let mut buf = [0u8; 2];
+        kept.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"!?");
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+//! Capture filters, a tcpdump-like language selecting which flows are
+//! captured
+//!
+//! ```plain
+//! expr      = term *( "or" term )
+//! term      = factor *( "and" factor )
+//! factor    = "not" factor / "(" expr ")" / primitive
+//! primitive = [ "src" / "dst" ] ( "host" ADDR / "net" CIDR / "port" NUMBER )
+//!           / "tcp" / "udp"
+//! ```
+//!
+//! `src` is the client and `dst` the destination, either of them matches
+//! when the direction is left out. `&&`, `||` and `!` may be used as well,
+//! e.g. `udp and dst port 53` or `host 10.0.0.2 && !port 22`. The ADDR of
+//! `host` is either an IP address or the domain name a client asked for.
+
+use crate::IpCidr;
+
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowProto {
+    Tcp,
+    Udp,
+}
+
+/// What a [CaptureFilter] is matched against
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flow {
+    pub proto: FlowProto,
+    /// The client
+    pub src: SocketAddr,
+    /// Unknown before the request has been read, and for UDP ASSOCIATE
+    /// until a datagram names it
+    pub dst_domain: Option<String>,
+    pub dst_ip: Option<IpAddr>,
+    pub dst_port: Option<u16>,
+}
+
+impl Flow {
+    #[inline]
+    pub fn new(proto: FlowProto, src: SocketAddr) -> Self {
+        Self { proto, src, dst_domain: None, dst_ip: None, dst_port: None }
+    }
+
+    #[inline]
+    pub fn with_dst_addr(mut self, dst: SocketAddr) -> Self {
+        self.dst_ip = Some(dst.ip());
+        self.dst_port = Some(dst.port());
+        self
+    }
+
+    #[inline]
+    pub fn with_dst_domain(mut self, name: &str, port: u16) -> Self {
+        self.dst_domain = Some(name.to_string());
+        self.dst_port = Some(port);
+        self
+    }
+
+    #[inline]
+    fn dst_known(&self) -> bool {
+        self.dst_port.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    Src,
+    Dst,
+    Either,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Host {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Proto(FlowProto),
+    Host(Dir, Host),
+    Net(Dir, IpCidr),
+    Port(Dir, u16),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/* Three-valued logic, `None` when it depends on a destination not known yet */
+
+fn and(lhs: Option<bool>, rhs: impl FnOnce() -> Option<bool>) -> Option<bool> {
+    match lhs {
+        Some(false) => Some(false),
+        _ => match (lhs, rhs()) {
+            (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        },
+    }
+}
+
+fn or(lhs: Option<bool>, rhs: impl FnOnce() -> Option<bool>) -> Option<bool> {
+    match lhs {
+        Some(true) => Some(true),
+        _ => match (lhs, rhs()) {
+            (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        },
+    }
+}
+
+/// Match the client with `src`, and the destination with `dst`
+fn directed(
+    dir: Dir,
+    flow: &Flow,
+    src: impl FnOnce() -> bool,
+    dst: impl FnOnce() -> Option<bool>,
+) -> Option<bool> {
+    let dst = || if flow.dst_known() { dst() } else { None };
+    match dir {
+        Dir::Src => Some(src()),
+        Dir::Dst => dst(),
+        Dir::Either => or(Some(src()), dst),
+    }
+}
+
+impl Expr {
+    fn eval(&self, flow: &Flow) -> Option<bool> {
+        match self {
+            Self::Proto(proto) => Some(flow.proto == *proto),
+            Self::Host(dir, Host::Ip(ip)) => directed(
+                *dir,
+                flow,
+                || flow.src.ip().to_canonical() == *ip,
+                /* A domain may not have been resolved yet */
+                || flow.dst_ip.map(|dst_ip| dst_ip.to_canonical() == *ip),
+            ),
+            Self::Host(dir, Host::Domain(name)) => directed(
+                *dir,
+                flow,
+                || false,
+                || Some(flow.dst_domain.as_ref().is_some_and(|d| d.eq_ignore_ascii_case(name))),
+            ),
+            Self::Net(dir, cidr) => directed(
+                *dir,
+                flow,
+                || cidr.contains(&flow.src.ip().to_canonical()),
+                || flow.dst_ip.map(|dst_ip| cidr.contains(&dst_ip.to_canonical())),
+            ),
+            Self::Port(dir, port) => directed(
+                *dir,
+                flow,
+                || flow.src.port() == *port,
+                || Some(flow.dst_port == Some(*port)),
+            ),
+            Self::Not(expr) => expr.eval(flow).map(|matched| !matched),
+            Self::And(lhs, rhs) => and(lhs.eval(flow), || rhs.eval(flow)),
+            Self::Or(lhs, rhs) => or(lhs.eval(flow), || rhs.eval(flow)),
+        }
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(s: &'a str) -> Self {
+        let mut tokens = vec![];
+        let mut start = None;
+        for (idx, c) in s.char_indices() {
+            let single = matches!(c, '(' | ')' | '!');
+            if c.is_whitespace() || single {
+                if let Some(start) = start.take() {
+                    tokens.push(&s[start..idx]);
+                }
+                if single {
+                    tokens.push(&s[idx..idx + 1]);
+                }
+            } else if start.is_none() {
+                start = Some(idx);
+            }
+        }
+        if let Some(start) = start {
+            tokens.push(&s[start..]);
+        }
+        Self { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or("Unexpected end of filter")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, alternatives: &[&str]) -> bool {
+        let eaten = self.peek().is_some_and(|token| alternatives.contains(&token));
+        if eaten {
+            self.pos += 1;
+        }
+        eaten
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.term()?;
+        while self.eat(&["or", "||"]) {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.term()?));
+        }
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.factor()?;
+        while self.eat(&["and", "&&"]) {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.factor()?));
+        }
+        Ok(lhs)
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        if self.eat(&["not", "!"]) {
+            return Ok(Expr::Not(Box::new(self.factor()?)));
+        }
+        if self.eat(&["("]) {
+            let expr = self.expr()?;
+            if !self.eat(&[")"]) {
+                return Err(String::from("Missing closing parenthesis"));
+            }
+            return Ok(expr);
+        }
+        self.primitive()
+    }
+
+    fn primitive(&mut self) -> Result<Expr, String> {
+        let dir = match self.peek() {
+            Some("src") => Dir::Src,
+            Some("dst") => Dir::Dst,
+            _ => Dir::Either,
+        };
+        if dir != Dir::Either {
+            self.pos += 1;
+        }
+        let keyword = self.next()?;
+        let expr = match keyword {
+            "tcp" | "udp" if dir != Dir::Either => {
+                return Err(format!("Unexpected {} after a direction", keyword));
+            }
+            "tcp" => Expr::Proto(FlowProto::Tcp),
+            "udp" => Expr::Proto(FlowProto::Udp),
+            "host" => {
+                let value = self.next()?;
+                match value.parse::<IpAddr>() {
+                    Ok(ip) => Expr::Host(dir, Host::Ip(ip.to_canonical())),
+                    Err(_) => Expr::Host(dir, Host::Domain(value.to_string())),
+                }
+            }
+            "net" => Expr::Net(dir, self.next()?.parse()?),
+            "port" => {
+                let value = self.next()?;
+                Expr::Port(dir, value.parse().map_err(|_| format!("Invalid port: {}", value))?)
+            }
+            _ => return Err(format!("Unknown filter primitive: {}", keyword)),
+        };
+        Ok(expr)
+    }
+}
+
+/// A parsed filter expression, see the [module](self) documentation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CaptureFilter {
+    text: String,
+    expr: Expr,
+}
+
+impl CaptureFilter {
+    /// Whether `flow` matches, `None` when that depends on a destination
+    /// that is not known yet
+    #[inline]
+    pub fn eval(&self, flow: &Flow) -> Option<bool> {
+        self.expr.eval(flow)
+    }
+
+    /// Whether `flow` surely matches
+    #[inline]
+    pub fn matches(&self, flow: &Flow) -> bool {
+        self.eval(flow) == Some(true)
+    }
+
+    /// Whether `flow` may still match once its destination is known
+    #[inline]
+    pub fn may_match(&self, flow: &Flow) -> bool {
+        self.eval(flow) != Some(false)
+    }
+}
+
+impl FromStr for CaptureFilter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s);
+        let expr = parser.expr()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected {} in filter: {}", token, s));
+        }
+        Ok(Self { text: s.trim().to_string(), expr })
+    }
+}
+
+impl TryFrom<String> for CaptureFilter {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for CaptureFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl From<CaptureFilter> for String {
+    fn from(value: CaptureFilter) -> Self {
+        value.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaptureFilter, Flow, FlowProto};
+
+    fn filter(s: &str) -> CaptureFilter {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("".parse::<CaptureFilter>().is_err());
+        assert!("host".parse::<CaptureFilter>().is_err());
+        assert!("port http".parse::<CaptureFilter>().is_err());
+        assert!("(tcp or udp".parse::<CaptureFilter>().is_err());
+        assert!("src tcp".parse::<CaptureFilter>().is_err());
+        assert!("tcp udp".parse::<CaptureFilter>().is_err());
+        assert!("net 10.0.0.0/33".parse::<CaptureFilter>().is_err());
+        assert_eq!(filter(" udp and dst port 53 ").to_string(), "udp and dst port 53");
+    }
+
+    #[test]
+    fn test_eval() {
+        let client = "10.0.0.2:50000".parse().unwrap();
+        let pending = Flow::new(FlowProto::Tcp, client);
+        let to_ip = pending.clone().with_dst_addr("1.1.1.1:443".parse().unwrap());
+        let to_domain = pending.clone().with_dst_domain("GitHub.com", 443);
+
+        let f = filter("tcp and dst port 443");
+        assert_eq!(f.eval(&pending), None);
+        assert!(f.may_match(&pending));
+        assert!(f.matches(&to_ip));
+        assert!(!filter("udp && dst port 443").may_match(&pending));
+
+        assert!(filter("src host 10.0.0.2").matches(&pending));
+        assert!(filter("host 10.0.0.2").matches(&pending));
+        assert!(filter("src net 10.0.0.0/8 and not port 22").matches(&to_ip));
+        assert!(filter("host github.com").matches(&to_domain));
+        assert!(!filter("host github.com").may_match(&to_ip));
+        assert_eq!(filter("dst host 1.1.1.1").eval(&to_domain), None);
+
+        let f = filter("!(dst port 80 || dst port 443)");
+        assert!(!f.may_match(&to_ip));
+        assert!(f.matches(&to_ip.clone().with_dst_addr("1.1.1.1:53".parse().unwrap())));
+    }
+}
@@ -0,0 +1,178 @@
+//! Single-packet authorization, keeping a listener closed to all but the
+//! addresses that knocked lately
+//!
+//! A knock is one UDP datagram: a magic, the time it was sent, a random
+//! nonce and an HMAC-SHA256 of them under a key shared with the clients.
+//! The address it came from is let in for a while once it checks out.
+//! Knocks sent more than [MAX_CLOCK_SKEW] away from now are dropped, as are
+//! those whose nonce was seen already, so that a captured one cannot be
+//! replayed from another address. Nothing is ever answered, scanners cannot
+//! tell the knock port from a closed one.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::net::UdpSocket;
+
+const KNOCK_MAGIC: &[u8; 4] = b"NSK1";
+const NONCE_LEN: usize = 16;
+
+/// Of the magic, the time, the nonce and the tag
+pub const KNOCK_LEN: usize = 4 + 8 + NONCE_LEN + 32;
+
+/// How far the clock of a client may be from that of the listener
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// As for fwknop
+pub const DEFAULT_KNOCK_PORT: u16 = 62201;
+
+/// How long an address that knocked may connect for
+pub const DEFAULT_KNOCK_WINDOW: Duration = Duration::from_secs(30);
+
+fn unix_secs(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn sign_knock(key: &hmac::Key, secs: u64, nonce: [u8; NONCE_LEN]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(KNOCK_LEN);
+    packet.extend_from_slice(KNOCK_MAGIC);
+    packet.extend_from_slice(&secs.to_be_bytes());
+    packet.extend_from_slice(&nonce);
+    let tag = hmac::sign(key, &packet);
+    packet.extend_from_slice(tag.as_ref());
+    packet
+}
+
+/// A knock under `key`, sent now
+pub fn knock_packet(key: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).map_err(|_| Error::other("No randomness"))?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    Ok(sign_knock(&key, unix_secs(SystemTime::now()), nonce))
+}
+
+/// Knock on `dest` with `key`, the address it is sent from being the one
+/// let in
+pub async fn send_knock(dest: SocketAddr, key: &[u8]) -> Result<()> {
+    let bind: SocketAddr = match dest {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.send_to(&knock_packet(key)?, dest).await?;
+    Ok(())
+}
+
+/// The addresses let in by their knocks
+#[derive(Debug)]
+pub struct KnockGate {
+    key: hmac::Key,
+    window: Duration,
+    /// Until when each address is let in
+    opened: Mutex<HashMap<IpAddr, Instant>>,
+    /// The nonces of the knocks taken, until they are too old to be anyway
+    nonces: Mutex<HashMap<[u8; NONCE_LEN], Instant>>,
+}
+
+impl KnockGate {
+    /// Letting in for `window` the addresses of the knocks under `key`
+    pub fn new(key: &[u8], window: Duration) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, key),
+            window,
+            opened: Mutex::default(),
+            nonces: Mutex::default(),
+        }
+    }
+
+    /// Let `from` in if `packet` is a valid knock, fails with
+    /// [ErrorKind::InvalidData] for anything else and
+    /// [ErrorKind::PermissionDenied] for a stale or replayed knock
+    pub fn knock(&self, packet: &[u8], from: IpAddr) -> Result<()> {
+        if packet.len() != KNOCK_LEN || !packet.starts_with(KNOCK_MAGIC) {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a knock"));
+        }
+        let (signed, tag) = packet.split_at(KNOCK_LEN - 32);
+        hmac::verify(&self.key, signed, tag)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Knock under another key"))?;
+        let skew = u64::from_be_bytes(signed[4..12].try_into().unwrap())
+            .abs_diff(unix_secs(SystemTime::now()));
+        if skew > MAX_CLOCK_SKEW.as_secs() {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Knock sent {}s away from now", skew),
+            ));
+        }
+        let now = Instant::now();
+        let nonce: [u8; NONCE_LEN] = signed[12..].try_into().unwrap();
+        {
+            let mut nonces = self.nonces.lock().unwrap();
+            nonces.retain(|_, seen| now.duration_since(*seen) <= MAX_CLOCK_SKEW * 2);
+            if nonces.insert(nonce, now).is_some() {
+                return Err(Error::new(ErrorKind::PermissionDenied, "Knock replayed"));
+            }
+        }
+        let mut opened = self.opened.lock().unwrap();
+        opened.retain(|_, until| *until > now);
+        opened.insert(from.to_canonical(), now + self.window);
+        Ok(())
+    }
+
+    /// Whether `ip` knocked within the window
+    pub fn is_open(&self, ip: IpAddr) -> bool {
+        let opened = self.opened.lock().unwrap();
+        opened.get(&ip.to_canonical()).is_some_and(|until| *until > Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KNOCK_LEN, KnockGate, knock_packet, sign_knock, unix_secs};
+
+    use std::io::ErrorKind;
+    use std::net::IpAddr;
+    use std::time::{Duration, SystemTime};
+
+    use ring::hmac;
+
+    #[test]
+    fn test_knock() {
+        let gate = KnockGate::new(b"0123456789abcdef", Duration::from_secs(30));
+        let ip = "203.0.113.7".parse::<IpAddr>().unwrap();
+        assert!(!gate.is_open(ip));
+
+        let packet = knock_packet(b"0123456789abcdef").unwrap();
+        assert_eq!(packet.len(), KNOCK_LEN);
+        gate.knock(&packet, ip).unwrap();
+        assert!(gate.is_open(ip));
+        assert!(gate.is_open("::ffff:203.0.113.7".parse().unwrap()));
+        assert!(!gate.is_open("203.0.113.8".parse().unwrap()));
+
+        /* Replayed from elsewhere */
+        let other = "198.51.100.1".parse().unwrap();
+        assert_eq!(gate.knock(&packet, other).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(!gate.is_open(other));
+
+        let packet = knock_packet(b"fedcba9876543210").unwrap();
+        assert_eq!(gate.knock(&packet, other).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(gate.knock(&[0u8; 3], other).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        /* Sent too long ago */
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"0123456789abcdef");
+        let packet = sign_knock(&key, unix_secs(SystemTime::now()) - 120, [7; 16]);
+        assert_eq!(gate.knock(&packet, other).unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_knock_window() {
+        let gate = KnockGate::new(b"0123456789abcdef", Duration::ZERO);
+        let ip = "203.0.113.7".parse::<IpAddr>().unwrap();
+        gate.knock(&knock_packet(b"0123456789abcdef").unwrap(), ip).unwrap();
+        assert!(!gate.is_open(ip));
+    }
+}
@@ -0,0 +1,263 @@
+//! The addresses of the local interfaces, as `getifaddrs` lists them
+//!
+//! Which local address the system would send from toward a destination is
+//! only one of them, and what it picks toward an IPv4 broadcast address
+//! asked over an IPv6 socket is a mapped IPv4 address rather than any IPv6
+//! one. Listing the interfaces tells them all, global, private or
+//! link-local, the latter along with the index of their interface, without
+//! which they can be neither bound nor reached.
+
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter};
+use std::io::{Error, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+/// How far an address is reachable from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AddressScope {
+    /// Preferred over the others, in this order
+    Global,
+    /// RFC 1918 IPv4 and unique local IPv6 addresses, routed within a site
+    Private,
+    /// 169.254.0.0/16 and fe80::/10, only reachable on the link and through
+    /// the interface they are on
+    LinkLocal,
+    Loopback,
+}
+
+impl AddressScope {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) if ip.is_loopback() => Self::Loopback,
+            IpAddr::V4(ip) if ip.is_link_local() => Self::LinkLocal,
+            IpAddr::V4(ip) if ip.is_private() || is_shared(ip) => Self::Private,
+            IpAddr::V6(ip) if ip.is_loopback() => Self::Loopback,
+            IpAddr::V6(ip) if ip.is_unicast_link_local() => Self::LinkLocal,
+            IpAddr::V6(ip) if ip.is_unique_local() => Self::Private,
+            _ => Self::Global,
+        }
+    }
+}
+
+impl Display for AddressScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Global => "global",
+            Self::Private => "private",
+            Self::LinkLocal => "link-local",
+            Self::Loopback => "loopback",
+        })
+    }
+}
+
+/// 100.64.0.0/10 of carrier-grade NAT, no more reachable from afar than
+/// RFC 1918 addresses
+fn is_shared(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    a == 100 && (b & 0xc0) == 64
+}
+
+/// An address of a local interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    /// Name of the interface, e.g. `en0`
+    pub ifname: String,
+    pub ip: IpAddr,
+    /// Index of the interface for link-local IPv6 addresses, 0 otherwise
+    pub scope_id: u32,
+    /// The interface is up
+    pub up: bool,
+}
+
+impl InterfaceAddress {
+    pub fn scope(&self) -> AddressScope {
+        AddressScope::of(self.ip)
+    }
+
+    /// The address with `port`, the scope of link-local IPv6 addresses set
+    pub fn socket_addr(&self, port: u16) -> SocketAddr {
+        match self.ip {
+            IpAddr::V6(ip) => SocketAddrV6::new(ip, port, 0, self.scope_id).into(),
+            ip => SocketAddr::new(ip, port),
+        }
+    }
+}
+
+/// A link-local IPv6 address as the kernels of BSD descent list it, the
+/// index of its interface within its second 16-bit word, and that index
+fn unembed_scope(mut octets: [u8; 16], scope_id: u32) -> (Ipv6Addr, u32) {
+    let embedded = u16::from_be_bytes([octets[2], octets[3]]) as u32;
+    if Ipv6Addr::from(octets).is_unicast_link_local() && embedded != 0 {
+        octets[2..4].fill(0);
+        return (octets.into(), if scope_id == 0 { embedded } else { scope_id });
+    }
+    (octets.into(), scope_id)
+}
+
+/// The IPv4 and IPv6 addresses of all interfaces, up or not
+pub fn interface_addresses() -> Result<Vec<InterfaceAddress>> {
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut ret = vec![];
+    let mut cursor = ifap;
+    while !cursor.is_null() {
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        let ifname = unsafe { CStr::from_ptr(ifa.ifa_name) }.to_string_lossy().into_owned();
+        let (ip, scope_id) = match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                (IpAddr::V4(u32::from_be(sin.sin_addr.s_addr).into()), 0)
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                let (ip, scope_id) = unembed_scope(sin6.sin6_addr.s6_addr, sin6.sin6_scope_id);
+                /* Never that of an interface, but what a mapping passes for */
+                if ip.to_ipv4_mapped().is_some() {
+                    continue;
+                }
+                let scope_id = if ip.is_unicast_link_local() { scope_id } else { 0 };
+                (IpAddr::V6(ip), scope_id)
+            }
+            _ => continue,
+        };
+        let up = ifa.ifa_flags & libc::IFF_UP as libc::c_uint != 0;
+        ret.push(InterfaceAddress { ifname, ip, scope_id, up });
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    Ok(ret)
+}
+
+/// The addresses a LAN may reach this host at, those of interfaces up and
+/// not loopback, ordered by [AddressScope] then as listed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LanAddresses {
+    pub addrs: Vec<InterfaceAddress>,
+}
+
+impl LanAddresses {
+    pub fn new(addrs: impl IntoIterator<Item = InterfaceAddress>) -> Self {
+        let mut addrs = addrs
+            .into_iter()
+            .filter(|addr| addr.up && addr.scope() != AddressScope::Loopback)
+            .collect::<Vec<_>>();
+        addrs.sort_by_key(InterfaceAddress::scope);
+        Self { addrs }
+    }
+
+    /// Those of the interfaces of this host
+    pub fn enumerate() -> Result<Self> {
+        interface_addresses().map(Self::new)
+    }
+
+    pub fn v4(&self) -> impl Iterator<Item = &InterfaceAddress> {
+        self.addrs.iter().filter(|addr| addr.ip.is_ipv4())
+    }
+
+    pub fn v6(&self) -> impl Iterator<Item = &InterfaceAddress> {
+        self.addrs.iter().filter(|addr| addr.ip.is_ipv6())
+    }
+
+    /// Whether `ip` is one of them
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.addrs.iter().any(|addr| addr.ip == ip)
+    }
+
+    /// The IPv4 address to listen on for the LAN, a private one before a
+    /// global one
+    pub fn best_v4(&self) -> Option<Ipv4Addr> {
+        let best = self.v4().min_by_key(|addr| match addr.scope() {
+            AddressScope::Private => 0,
+            _ => 1,
+        })?;
+        match best.ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    /// The IPv6 address to listen on for the LAN, a global or unique local
+    /// one, link-local ones needing a scope to be of use
+    pub fn best_v6(&self) -> Option<Ipv6Addr> {
+        self.v6().find(|addr| addr.scope() != AddressScope::LinkLocal).and_then(|addr| {
+            match addr.ip {
+                IpAddr::V6(ip) => Some(ip),
+                IpAddr::V4(_) => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(ifname: &str, ip: &str, up: bool) -> InterfaceAddress {
+        let ip = ip.parse().unwrap();
+        let scope_id = if AddressScope::of(ip) == AddressScope::LinkLocal { 4 } else { 0 };
+        InterfaceAddress { ifname: ifname.into(), ip, scope_id, up }
+    }
+
+    #[test]
+    fn test_address_scope() {
+        let scope = |ip: &str| AddressScope::of(ip.parse().unwrap());
+        assert_eq!(scope("192.168.1.2"), AddressScope::Private);
+        assert_eq!(scope("100.100.1.2"), AddressScope::Private);
+        assert_eq!(scope("169.254.3.4"), AddressScope::LinkLocal);
+        assert_eq!(scope("127.0.0.1"), AddressScope::Loopback);
+        assert_eq!(scope("8.8.8.8"), AddressScope::Global);
+        assert_eq!(scope("fd12::1"), AddressScope::Private);
+        assert_eq!(scope("fe80::1"), AddressScope::LinkLocal);
+        assert_eq!(scope("::1"), AddressScope::Loopback);
+        assert_eq!(scope("2001:db8::1"), AddressScope::Global);
+        assert_eq!(scope("::ffff:10.0.0.1"), AddressScope::Private);
+    }
+
+    #[test]
+    fn test_unembed_scope() {
+        let embedded = "fe80:4::1".parse::<Ipv6Addr>().unwrap().octets();
+        assert_eq!(unembed_scope(embedded, 0), ("fe80::1".parse().unwrap(), 4));
+        let global = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+        assert_eq!(unembed_scope(global.octets(), 0), (global, 0));
+    }
+
+    #[test]
+    fn test_lan_addresses() {
+        let lan = LanAddresses::new([
+            addr("lo0", "127.0.0.1", true),
+            addr("en0", "fe80::1", true),
+            addr("en0", "8.8.4.4", true),
+            addr("en0", "2001:db8::2", true),
+            addr("en1", "10.0.0.2", false),
+            addr("en0", "192.168.1.2", true),
+            addr("en0", "fd00::2", true),
+        ]);
+        assert_eq!(lan.addrs.len(), 5);
+        assert_eq!(lan.best_v4(), Some(Ipv4Addr::new(192, 168, 1, 2)));
+        assert_eq!(lan.best_v6(), "2001:db8::2".parse().ok());
+        assert!(!lan.contains("10.0.0.2".parse().unwrap()));
+        let link_local = lan.v6().last().unwrap();
+        assert_eq!(link_local.socket_addr(80).to_string(), "[fe80::1%4]:80");
+
+        let only_link_local = LanAddresses::new([addr("en0", "fe80::1", true)]);
+        assert_eq!(only_link_local.best_v6(), None);
+        assert_eq!(LanAddresses::default().best_v4(), None);
+    }
+
+    #[test]
+    fn test_interface_addresses() -> Result<()> {
+        let addrs = interface_addresses()?;
+        assert!(
+            addrs
+                .iter()
+                .all(|addr| !matches!(addr.ip, IpAddr::V6(ip) if ip.to_ipv4_mapped().is_some()))
+        );
+        assert!(addrs.iter().any(|addr| addr.scope() == AddressScope::Loopback));
+        Ok(())
+    }
+}
@@ -1,40 +1,156 @@
-#[cfg(target_os = "macos")]
+#[cfg(all(feature = "tun", target_os = "macos"))]
 mod utun;
 use tokio::net::UdpSocket;
-#[cfg(target_os = "macos")]
+#[cfg(all(feature = "tun", target_os = "macos"))]
 pub use utun::*;
 
+#[cfg(feature = "tun")]
 mod tun;
+#[cfg(feature = "tun")]
 pub use tun::*;
 
+#[cfg(feature = "tun")]
 mod vtun;
+#[cfg(feature = "tun")]
 pub use vtun::*;
 
+#[cfg(feature = "tun")]
 mod vtun_conf;
+#[cfg(feature = "tun")]
 pub use vtun_conf::*;
 
-use core::error::Error;
+#[cfg(feature = "tun")]
+mod tun_stream;
+#[cfg(feature = "tun")]
+pub use tun_stream::*;
+
+#[cfg(feature = "tun")]
+mod tun_codec;
+#[cfg(feature = "tun")]
+pub use tun_codec::*;
+
+#[cfg(feature = "tun")]
+mod mss;
+#[cfg(feature = "tun")]
+pub use mss::*;
+
+#[cfg(feature = "tun")]
+mod pcap;
+#[cfg(feature = "tun")]
+pub use pcap::*;
+
+mod context;
+pub use context::*;
+
+mod sockopt;
+pub use sockopt::*;
+
+mod pmtu;
+pub use pmtu::*;
+
+pub mod overhead;
+
+mod retry;
+pub use retry::*;
+
+mod lan;
+pub use lan::*;
+
+mod dial;
+pub use dial::*;
+
+mod resolve;
+pub use resolve::*;
+
+mod router;
+pub use router::*;
+
+mod process;
+pub use process::*;
+
+mod decision_cache;
+pub use decision_cache::*;
+
+mod classify;
+pub use classify::*;
+
+mod sniff;
+pub use sniff::*;
+
+mod upstream;
+pub use upstream::*;
+
+mod fakeip;
+pub use fakeip::*;
+
+mod filter;
+pub use filter::*;
+
+mod units;
+pub use units::*;
+
+pub mod fdpass;
+
+#[cfg(feature = "obfs")]
+pub mod obfs;
+
+#[cfg(feature = "obfs")]
+pub mod ws;
+
+#[cfg(feature = "p2p")]
+pub mod punch;
+
+#[cfg(feature = "knock")]
+mod knock;
+#[cfg(feature = "knock")]
+pub use knock::*;
+
+#[cfg(feature = "portmap")]
+mod portmap;
+#[cfg(feature = "portmap")]
+pub use portmap::*;
+
+#[cfg(feature = "wireguard")]
+mod wireguard_noise;
+
+#[cfg(feature = "wireguard")]
+mod wireguard;
+#[cfg(feature = "wireguard")]
+pub use wireguard::*;
+
+#[cfg(feature = "ssh")]
+mod ssh_transport;
+
+#[cfg(feature = "ssh")]
+mod ssh;
+#[cfg(feature = "ssh")]
+pub use ssh::*;
+
+#[cfg(feature = "engine-lite")]
+mod outbound;
+#[cfg(feature = "engine-lite")]
+pub use outbound::*;
+
+#[cfg(feature = "engine-lite")]
+mod audit;
+#[cfg(feature = "engine-lite")]
+pub use audit::*;
+
+#[cfg(feature = "engine-lite")]
+mod engine;
+#[cfg(feature = "engine-lite")]
+pub use engine::*;
+
+#[cfg(feature = "test-util")]
+pub mod testing;
+
 use core::ffi::c_int;
 use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::io::Result;
-use std::net::{SocketAddrV4, SocketAddrV6};
+use std::io::{ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use lazy_static::lazy_static;
 use libc::{F_GETFL, F_SETFD, F_SETFL, FD_CLOEXEC, O_NONBLOCK, fcntl};
-use maxminddb::{Reader, geoip2::Country};
-use stunclient::StunClient;
-
-lazy_static! {
-    pub static ref GEOIP2_COUNTRY_MMDB_BUF: &'static [u8] = include_bytes!("../Country.mmdb");
-    pub static ref SOCKET_ADDR_V6_STUN: SocketAddr = SocketAddr::V6(SocketAddrV6::new(
-        "2600:1f16:8c5:101:80b:b58b:828:8df4".parse::<Ipv6Addr>().unwrap(),
-        3478,
-        0,
-        0
-    ));
-    pub static ref SOCKET_ADDR_V4_STUN: SocketAddr =
-        SocketAddr::V4(SocketAddrV4::new("3.22.142.132".parse::<Ipv4Addr>().unwrap(), 3478));
-}
 
 pub fn set_nonblock(fd: c_int) -> c_int {
     let mut flag: c_int = unsafe { fcntl(fd, F_GETFL, 0) };
@@ -50,30 +166,22 @@ pub fn set_cloexec(fd: c_int) -> c_int {
     unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) }
 }
 
-pub fn check_iso_code(address: IpAddr, iso_code: &str) -> bool {
-    let buf = &GEOIP2_COUNTRY_MMDB_BUF;
-    let from_source_ret = Reader::from_source(buf.to_vec());
-    if from_source_ret.is_err() {
-        return false;
-    }
-    let reader = from_source_ret.unwrap();
-    let lookup_ret = reader.lookup(address);
-    if lookup_ret.is_err() {
-        return false;
-    }
-    let lookup_ret = lookup_ret.unwrap();
-    let decode_country_ret = lookup_ret.decode::<Country>();
-    if decode_country_ret.is_err() {
-        return false;
-    }
+/// ISO code of the country where `address` is located
+#[inline]
+pub fn iso_code_of(address: IpAddr) -> Option<String> {
+    CoreContext::global().iso_code_of(address.to_canonical())
+}
 
-    if let Some(country_ret) = decode_country_ret.unwrap() {
-        seeval!(country_ret);
-        let iso_code_ret = country_ret.country.iso_code;
-        return iso_code_ret == Some(iso_code);
-    }
+/// Check that the GeoIP2 country database can be read
+#[cfg(feature = "geoip")]
+#[inline]
+pub fn probe_geoip_database() -> Result<()> {
+    CoreContext::global().probe_geoip_database()
+}
 
-    false
+#[inline]
+pub fn check_iso_code(address: IpAddr, iso_code: &str) -> bool {
+    iso_code_of(address).as_deref() == Some(iso_code)
 }
 
 #[inline]
@@ -81,50 +189,122 @@ pub fn is_cn_ip(address: IpAddr) -> bool {
     check_iso_code(address, "CN")
 }
 
+/// The ISO 3166-1 codes of the countries of the GeoIP2 database, along with
+/// XK for Kosovo
+const COUNTRY_CODES: [&str; 9] = [
+    "AD AE AF AG AI AL AM AO AQ AR AS AT AU AW AX AZ BA BB BD BE BF BG BH BI BJ BL BM BN BO BQ BR",
+    "BS BT BV BW BY BZ CA CC CD CF CG CH CI CK CL CM CN CO CR CU CV CW CX CY CZ DE DJ DK DM DO DZ",
+    "EC EE EG EH ER ES ET FI FJ FK FM FO FR GA GB GD GE GF GG GH GI GL GM GN GP GQ GR GS GT GU GW",
+    "GY HK HM HN HR HT HU ID IE IL IM IN IO IQ IR IS IT JE JM JO JP KE KG KH KI KM KN KP KR KW KY",
+    "KZ LA LB LC LI LK LR LS LT LU LV LY MA MC MD ME MF MG MH MK ML MM MN MO MP MQ MR MS MT MU MV",
+    "MW MX MY MZ NA NC NE NF NG NI NL NO NP NR NU NZ OM PA PE PF PG PH PK PL PM PN PR PS PT PW PY",
+    "QA RE RO RS RU RW SA SB SC SD SE SG SH SI SJ SK SL SM SN SO SR SS ST SV SX SY SZ TC TD TF TG",
+    "TH TJ TK TL TM TN TO TR TT TV TW TZ UA UG UM US UY UZ VA VC VE VG VI VN VU WF WS XK YE YT ZA",
+    "ZM ZW",
+];
+
+/// Whether some address may be located in the country of `iso_code`, for
+/// `GEOIP` rules to be told from typos
+pub fn is_country_code(iso_code: &str) -> bool {
+    iso_code.len() == 2 && COUNTRY_CODES.iter().any(|codes| codes.split(' ').any(|c| c == iso_code))
+}
+
+/// A global IPv6 address, only the route toward it is looked up
+const ROUTE_PROBE_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+
+/// The local address the system would send from to `sockaddr_broadcast`,
+/// nothing is sent
 async fn try_get_lanip_addr(
     sockaddr_unspec: SocketAddr,
     sockaddr_broadcast: SocketAddr,
-) -> Result<String> {
+) -> Result<IpAddr> {
     let udp_sock = UdpSocket::bind(sockaddr_unspec).await?;
     udp_sock.connect(sockaddr_broadcast).await?;
     let addr = udp_sock.local_addr()?;
-    Ok(addr.ip().to_string())
+    Ok(addr.ip())
 }
 
-#[inline]
-pub async fn what_is_my_lanip_v6addr() -> Result<String> {
+/// The IPv6 address the system sends from toward the Internet, else the
+/// best of [LanAddresses::best_v6], never a link-local or mapped one
+pub async fn what_is_my_lanip_v6addr() -> Result<Ipv6Addr> {
     let sockaddr_unspec = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
-    let sockaddr_broadcast = SocketAddr::new(IpAddr::V6(Ipv4Addr::BROADCAST.to_ipv6_mapped()), 1);
-    return try_get_lanip_addr(sockaddr_unspec, sockaddr_broadcast).await;
+    let sockaddr_probe = SocketAddr::new(IpAddr::V6(ROUTE_PROBE_V6), 53);
+    let lan = LanAddresses::enumerate()?;
+    match try_get_lanip_addr(sockaddr_unspec, sockaddr_probe).await {
+        Ok(IpAddr::V6(ip))
+            if AddressScope::of(ip.into()) < AddressScope::LinkLocal && lan.contains(ip.into()) =>
+        {
+            Ok(ip)
+        }
+        _ => {
+            lan.best_v6().ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No IPv6 address"))
+        }
+    }
 }
 
-#[inline]
-pub async fn what_is_my_lanip_v4addr() -> Result<String> {
+/// The IPv4 address the system sends broadcasts from, else the best of
+/// [LanAddresses::best_v4]
+pub async fn what_is_my_lanip_v4addr() -> Result<Ipv4Addr> {
     let sockaddr_unspec = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
     let sockaddr_broadcast = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 1);
-    return try_get_lanip_addr(sockaddr_unspec, sockaddr_broadcast).await;
+    match try_get_lanip_addr(sockaddr_unspec, sockaddr_broadcast).await {
+        Ok(IpAddr::V4(ip)) if !ip.is_unspecified() => Ok(ip),
+        _ => LanAddresses::enumerate()?
+            .best_v4()
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No IPv4 address")),
+    }
 }
 
-async fn try_get_extip_addr(
-    sockaddr_unspec: SocketAddr,
-    sockaddr_stun: SocketAddr,
-) -> std::result::Result<String, Box<dyn Error>> {
-    let udp_sock = UdpSocket::bind(sockaddr_unspec).await?;
-    let external_addr =
-        StunClient::new(sockaddr_stun).query_external_address_async(&udp_sock).await?;
-    Ok(external_addr.ip().to_string())
+/// The address and port the STUN server sees IPv6 queries come from
+#[cfg(feature = "stun")]
+#[inline]
+pub async fn what_is_my_extip_v6addr() -> Result<SocketAddr> {
+    CoreContext::global().what_is_my_extip_v6addr().await
 }
 
+/// The address and port the STUN server sees IPv4 queries come from
+#[cfg(feature = "stun")]
 #[inline]
-pub async fn what_is_my_extip_v6addr() -> std::result::Result<String, Box<dyn Error>> {
-    let sockaddr_unspec = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
-    return try_get_extip_addr(sockaddr_unspec, *SOCKET_ADDR_V6_STUN).await;
+pub async fn what_is_my_extip_v4addr() -> Result<SocketAddr> {
+    CoreContext::global().what_is_my_extip_v4addr().await
 }
 
+/// See [CoreContext::discover_addresses]
 #[inline]
-pub async fn what_is_my_extip_v4addr() -> std::result::Result<String, Box<dyn Error>> {
-    let sockaddr_unspec = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-    return try_get_extip_addr(sockaddr_unspec, *SOCKET_ADDR_V4_STUN).await;
+pub async fn discover_addresses(timeout: Duration) -> Addresses {
+    CoreContext::global().discover_addresses(timeout).await
+}
+
+/// See [CoreContext::discover_lan_addresses]
+#[inline]
+pub async fn discover_lan_addresses(timeout: Duration) -> Addresses {
+    CoreContext::global().discover_lan_addresses(timeout).await
+}
+
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Turn the output of [trace_println] on or off, it stays off in builds
+/// without the `trace-log` feature
+#[inline]
+pub fn set_trace(enabled: bool) {
+    TRACE.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether [trace_println] prints, known to be false at compile time without
+/// the `trace-log` feature so that the arguments are never even formatted
+#[inline(always)]
+pub fn trace_enabled() -> bool {
+    cfg!(feature = "trace-log") && TRACE.load(Ordering::Relaxed)
+}
+
+/// Per-packet output of the hot paths, see [set_trace]
+#[macro_export]
+macro_rules! trace_println {
+    ($($arg:tt)*) => {
+        if $crate::trace_enabled() {
+            std::println!($($arg)*)
+        }
+    }
 }
 
 #[macro_export(local_inner_macros)]
@@ -156,9 +336,18 @@ macro_rules! seeval {
     };
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "geoip"))]
 mod tests {
 
+    #[test]
+    fn test_is_country_code() {
+        assert!(super::is_country_code("CN"));
+        assert!(super::is_country_code("XK"));
+        assert!(!super::is_country_code("UK"));
+        assert!(!super::is_country_code("C"));
+        assert!(!super::is_country_code("AD AE"));
+    }
+
     #[test]
     fn test_check_iso_code() {
         let check_iso_code_ret = super::check_iso_code("140.205.135.3".parse().unwrap(), "CN");
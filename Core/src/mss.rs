@@ -0,0 +1,193 @@
+//! TCP MSS clamping, for the flows crossing a tunnel interface
+//!
+//! Endpoints announce the segment size of their own link in the MSS option
+//! of their SYN, and rely on path MTU discovery to learn of the tunnel
+//! being narrower. Where the ICMP errors telling so are filtered, large
+//! segments vanish in the tunnel. Lowering the MSS of the SYNs on their way
+//! to what the MTU of the interface carries avoids the question altogether.
+
+use crate::overhead::{Carrier, TransportKind, payload_mtu};
+
+const IPPROTO_TCP: u8 = 6;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+/// Where the TCP header of `packet` starts and the most the MSS may be for
+/// a segment to fit in `mtu`, None when `packet` is not an unfragmented
+/// TCP segment of a SYN
+fn syn_of(packet: &[u8], mtu: u16) -> Option<(usize, u16)> {
+    let (tcp_start, ipv6) = match packet.first()? >> 4 {
+        4 => {
+            let ihl = (*packet.first()? & 0x0f) as usize * 4;
+            let fragment_offset = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x1fff;
+            if *packet.get(9)? != IPPROTO_TCP || fragment_offset != 0 || ihl < 20 {
+                return None;
+            }
+            (ihl, false)
+        }
+        /* Extension headers are left alone, SYNs carry none in practice */
+        6 if *packet.get(6)? == IPPROTO_TCP => (40, true),
+        _ => return None,
+    };
+    let flags = *packet.get(tcp_start + 13)?;
+    let max_mss = payload_mtu(mtu, TransportKind::new(ipv6, Carrier::Tcp));
+    (flags & TCP_FLAG_SYN != 0).then_some((tcp_start, max_mss))
+}
+
+/// Offset within `packet` of the value of the MSS option of the TCP header
+/// at `tcp_start`
+fn mss_option(packet: &[u8], tcp_start: usize) -> Option<usize> {
+    let data_offset = (*packet.get(tcp_start + 12)? >> 4) as usize * 4;
+    let options = packet.get(tcp_start + 20..tcp_start + data_offset)?;
+    let mut pos = 0;
+    while pos < options.len() {
+        match options[pos] {
+            TCP_OPTION_END => return None,
+            TCP_OPTION_NOP => pos += 1,
+            TCP_OPTION_MSS if options.get(pos + 1) == Some(&4) && pos + 4 <= options.len() => {
+                return Some(tcp_start + 20 + pos + 2);
+            }
+            _ => match *options.get(pos + 1)? as usize {
+                len if len >= 2 => pos += len,
+                _ => return None,
+            },
+        }
+    }
+    None
+}
+
+/// The checksum of data whose 16-bit word `old` became `new`, RFC 1624
+fn adjust_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = !checksum as u32 + !old as u32 + new as u32;
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Whether [clamp_mss] would change `packet`
+pub fn needs_mss_clamp(packet: &[u8], mtu: u16) -> bool {
+    syn_of(packet, mtu)
+        .and_then(|(tcp_start, max_mss)| {
+            let at = mss_option(packet, tcp_start)?;
+            Some(u16::from_be_bytes([packet[at], packet[at + 1]]) > max_mss)
+        })
+        .unwrap_or(false)
+}
+
+/// Lower the MSS option of `packet`, if it is the IPv4 or IPv6 packet of
+/// a TCP SYN, to what fits in `mtu`, the checksum being updated along.
+/// Returns whether it was changed.
+pub fn clamp_mss(packet: &mut [u8], mtu: u16) -> bool {
+    let Some((tcp_start, max_mss)) = syn_of(packet, mtu) else {
+        return false;
+    };
+    let Some(at) = mss_option(packet, tcp_start) else {
+        return false;
+    };
+    /* Within the header, which mss_option found the options past */
+    let checksum_at = tcp_start + 16;
+    let mss = u16::from_be_bytes([packet[at], packet[at + 1]]);
+    if mss <= max_mss {
+        return false;
+    }
+    /* Words of the checksum start at even offsets of the segment */
+    let (old, new) = match (at - tcp_start) % 2 {
+        0 => (mss, max_mss),
+        _ => (mss.swap_bytes(), max_mss.swap_bytes()),
+    };
+    let checksum = u16::from_be_bytes([packet[checksum_at], packet[checksum_at + 1]]);
+    let checksum = adjust_checksum(checksum, old, new);
+    packet[at..at + 2].copy_from_slice(&max_mss.to_be_bytes());
+    packet[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clamp_mss, needs_mss_clamp};
+
+    /// One's complement sum of `data` as 16-bit words
+    fn sum(data: &[u8], mut acc: u32) -> u32 {
+        for chunk in data.chunks(2) {
+            acc += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32;
+        }
+        acc
+    }
+
+    /// The checksum of the TCP segment of `packet`, which is right when 0
+    fn tcp_checksum(packet: &[u8]) -> u16 {
+        let (pseudo, segment) = match packet[0] >> 4 {
+            4 => {
+                let len = (packet.len() - 20) as u16;
+                let acc = sum(&packet[12..20], 6 + len as u32);
+                (acc, &packet[20..])
+            }
+            _ => {
+                let len = (packet.len() - 40) as u32;
+                (sum(&packet[8..40], 6 + len), &packet[40..])
+            }
+        };
+        let mut acc = sum(segment, pseudo);
+        while acc > 0xffff {
+            acc = (acc & 0xffff) + (acc >> 16);
+        }
+        !(acc as u16)
+    }
+
+    /// A SYN with the MSS option, `pad` NOPs before it
+    fn syn(ipv6: bool, mss: u16, pad: usize) -> Vec<u8> {
+        let options_len = (pad + 4).div_ceil(4) * 4;
+        let mut tcp = vec![0u8; 20 + options_len];
+        tcp[..4].copy_from_slice(&[0xc0, 0x01, 0x01, 0xbb]);
+        tcp[12] = (((20 + options_len) / 4) << 4) as u8;
+        tcp[13] = 0x02;
+        tcp[20..20 + pad].fill(1);
+        tcp[20 + pad..24 + pad].copy_from_slice(&[2, 4, (mss >> 8) as u8, mss as u8]);
+        let mut packet = match ipv6 {
+            false => {
+                let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 6, 0, 0];
+                ip.extend_from_slice(&[192, 168, 1, 2, 203, 0, 113, 1]);
+                ip
+            }
+            true => {
+                let mut ip = vec![0x60, 0, 0, 0, 0, tcp.len() as u8, 6, 64];
+                ip.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+                ip.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+                ip
+            }
+        };
+        packet.extend_from_slice(&tcp);
+        let checksum_at = packet.len() - tcp.len() + 16;
+        let checksum = tcp_checksum(&packet);
+        packet[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(tcp_checksum(&packet), 0);
+        packet
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        let mut packet = syn(false, 1460, 0);
+        assert!(needs_mss_clamp(&packet, 1400));
+        assert!(clamp_mss(&mut packet, 1400));
+        assert_eq!(&packet[42..44], &1360u16.to_be_bytes());
+        assert_eq!(tcp_checksum(&packet), 0);
+        /* Small enough already */
+        assert!(!clamp_mss(&mut packet, 1500));
+
+        /* At an odd offset of the segment */
+        let mut packet = syn(true, 1440, 1);
+        assert!(clamp_mss(&mut packet, 1280));
+        assert_eq!(&packet[63..65], &1220u16.to_be_bytes());
+        assert_eq!(tcp_checksum(&packet), 0);
+
+        /* Not a SYN */
+        let mut packet = syn(false, 1460, 0);
+        packet[33] = 0x10;
+        assert!(!needs_mss_clamp(&packet, 1400));
+        assert!(!clamp_mss(&mut packet, 1400));
+        assert!(!clamp_mss(&mut [0x45, 0, 0], 1400));
+    }
+}
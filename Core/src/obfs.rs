@@ -0,0 +1,536 @@
+//! Traffic obfuscation of the node-to-node transport, applied to the raw
+//! stream before encryption so that it looks like neither SOCKS nor TLS.
+
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rand::{Rng, RngCore};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Any bidirectional byte stream
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+pub type BoxedFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Which end of the transport a stream is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Wraps a transport stream into one whose bytes on the wire are disguised
+pub trait Obfuscator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Wrap `stream`, performing any handshake needed by the disguise
+    fn obfuscate(&self, stream: BoxedStream, role: Role) -> BoxedFuture<Result<BoxedStream>>;
+}
+
+/// Turns payloads into wire frames and back
+trait FrameCodec: Send + Unpin {
+    fn encode(&mut self, payload: &[u8], out: &mut Vec<u8>);
+
+    /// Decode the first complete frame of `raw` and drain it, `Ok(None)`
+    /// when more bytes are needed
+    fn decode(&mut self, raw: &mut Vec<u8>) -> Result<Option<Vec<u8>>>;
+}
+
+/// A stream carrying payloads encoded by a [FrameCodec]
+struct FramedStream<C: FrameCodec> {
+    inner: BoxedStream,
+    codec: C,
+    /// Bytes read from `inner`, not decoded yet
+    raw: Vec<u8>,
+    /// Decoded bytes not read yet
+    decoded: Vec<u8>,
+    /// Encoded bytes not written to `inner` yet
+    encoded: Vec<u8>,
+}
+
+impl<C: FrameCodec> FramedStream<C> {
+    fn new(inner: BoxedStream, codec: C) -> Self {
+        Self { inner, codec, raw: vec![], decoded: vec![], encoded: vec![] }
+    }
+
+    fn poll_write_encoded(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while !self.encoded.is_empty() {
+            let len = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.encoded))?;
+            if len == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.encoded.drain(..len);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: FrameCodec> AsyncRead for FramedStream<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while this.decoded.is_empty() {
+            if let Some(payload) = this.codec.decode(&mut this.raw)? {
+                this.decoded = payload;
+                continue;
+            }
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                return if this.raw.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.raw.extend_from_slice(chunk_buf.filled());
+        }
+        let len = buf.remaining().min(this.decoded.len());
+        buf.put_slice(&this.decoded[..len]);
+        this.decoded.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: FrameCodec> AsyncWrite for FramedStream<C> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        let mut encoded = std::mem::take(&mut this.encoded);
+        this.codec.encode(buf, &mut encoded);
+        this.encoded = encoded;
+        /* The payload is taken over, the frame is written out on later calls */
+        let _ = this.poll_write_encoded(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// XOR with a repeating key, salted per connection and direction by 16
+/// random bytes sent in clear before the first frame
+#[derive(Debug, Clone)]
+pub struct XorObfuscator {
+    key: Arc<[u8]>,
+}
+
+impl XorObfuscator {
+    pub const SALT_LEN: usize = 16;
+
+    pub fn new(key: &[u8]) -> Self {
+        let key = if key.is_empty() { &[0x5a][..] } else { key };
+        Self { key: key.into() }
+    }
+}
+
+struct XorCodec {
+    key: Arc<[u8]>,
+    read_salt: Option<[u8; XorObfuscator::SALT_LEN]>,
+    read_pos: usize,
+    write_salt: [u8; XorObfuscator::SALT_LEN],
+    write_pos: usize,
+}
+
+impl XorCodec {
+    fn apply(key: &[u8], salt: &[u8], pos: &mut usize, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            *b ^= key[*pos % key.len()] ^ salt[*pos % salt.len()];
+            *pos = pos.wrapping_add(1);
+        }
+    }
+}
+
+impl FrameCodec for XorCodec {
+    fn encode(&mut self, payload: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(payload);
+        Self::apply(&self.key, &self.write_salt, &mut self.write_pos, &mut out[start..]);
+    }
+
+    fn decode(&mut self, raw: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let salt = match self.read_salt {
+            Some(salt) => salt,
+            None if raw.len() < XorObfuscator::SALT_LEN => return Ok(None),
+            None => {
+                let mut salt = [0u8; XorObfuscator::SALT_LEN];
+                salt.copy_from_slice(&raw[..XorObfuscator::SALT_LEN]);
+                raw.drain(..XorObfuscator::SALT_LEN);
+                *self.read_salt.insert(salt)
+            }
+        };
+        if raw.is_empty() {
+            return Ok(None);
+        }
+        let mut payload = std::mem::take(raw);
+        Self::apply(&self.key, &salt, &mut self.read_pos, &mut payload);
+        Ok(Some(payload))
+    }
+}
+
+impl Obfuscator for XorObfuscator {
+    fn name(&self) -> &'static str {
+        "xor"
+    }
+
+    fn obfuscate(&self, stream: BoxedStream, _role: Role) -> BoxedFuture<Result<BoxedStream>> {
+        let mut write_salt = [0u8; Self::SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut write_salt);
+        let codec = XorCodec {
+            key: self.key.clone(),
+            read_salt: None,
+            read_pos: 0,
+            write_salt,
+            write_pos: 0,
+        };
+        Box::pin(async move {
+            let mut framed = FramedStream::new(stream, codec);
+            framed.encoded.extend_from_slice(&write_salt);
+            Ok(Box::new(framed) as BoxedStream)
+        })
+    }
+}
+
+/// Frames of `payload length (u16) | padding length (u8) | payload |
+/// random padding`, so that packet sizes don't give the content away
+#[derive(Debug, Clone, Copy)]
+pub struct PaddingObfuscator {
+    max_padding: u8,
+}
+
+impl PaddingObfuscator {
+    pub fn new(max_padding: u8) -> Self {
+        Self { max_padding }
+    }
+}
+
+impl Default for PaddingObfuscator {
+    fn default() -> Self {
+        Self::new(u8::MAX)
+    }
+}
+
+struct PaddingCodec {
+    max_padding: u8,
+}
+
+impl FrameCodec for PaddingCodec {
+    fn encode(&mut self, payload: &[u8], out: &mut Vec<u8>) {
+        let mut rng = rand::thread_rng();
+        for chunk in payload.chunks(u16::MAX as usize) {
+            let padding_len = rng.gen_range(0..=self.max_padding);
+            out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            out.push(padding_len);
+            out.extend_from_slice(chunk);
+            let start = out.len();
+            out.resize(start + padding_len as usize, 0);
+            rng.fill_bytes(&mut out[start..]);
+        }
+    }
+
+    fn decode(&mut self, raw: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if raw.len() < 3 {
+            return Ok(None);
+        }
+        let payload_len = u16::from_be_bytes([raw[0], raw[1]]) as usize;
+        let frame_len = 3 + payload_len + raw[2] as usize;
+        if raw.len() < frame_len {
+            return Ok(None);
+        }
+        let payload = raw[3..3 + payload_len].to_vec();
+        raw.drain(..frame_len);
+        Ok(Some(payload))
+    }
+}
+
+impl Obfuscator for PaddingObfuscator {
+    fn name(&self) -> &'static str {
+        "padding"
+    }
+
+    fn obfuscate(&self, stream: BoxedStream, _role: Role) -> BoxedFuture<Result<BoxedStream>> {
+        let codec = PaddingCodec { max_padding: self.max_padding };
+        Box::pin(async move { Ok(Box::new(FramedStream::new(stream, codec)) as BoxedStream) })
+    }
+}
+
+/// Looks like a WebSocket connection: an HTTP/1.1 upgrade handshake, then
+/// binary frames, masked from the client as RFC 6455 requires
+#[derive(Debug, Clone)]
+pub struct WebSocketObfuscator {
+    host: String,
+    path: String,
+}
+
+impl WebSocketObfuscator {
+    /// GUID for computing `Sec-WebSocket-Accept`
+    const WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+    const MAX_HEADER_LEN: usize = 8192;
+
+    pub fn new(host: &str, path: &str) -> Self {
+        Self { host: host.to_string(), path: path.to_string() }
+    }
+
+    fn accept_key(key: &str) -> String {
+        let mut sha1 = sha1_smol::Sha1::new();
+        sha1.update(key.as_bytes());
+        sha1.update(Self::WS_GUID.as_bytes());
+        BASE64.encode(sha1.digest().bytes())
+    }
+
+    /// Read an HTTP header block, up to and including the empty line
+    async fn read_http_header(stream: &mut BoxedStream) -> Result<String> {
+        let mut header = vec![];
+        while !header.ends_with(b"\r\n\r\n") {
+            if header.len() >= Self::MAX_HEADER_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "HTTP header too long"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        String::from_utf8(header).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn header_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+        header.lines().skip(1).find_map(|line| {
+            let (key, val) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| val.trim())
+        })
+    }
+
+    async fn client_handshake(&self, stream: &mut BoxedStream) -> Result<()> {
+        let mut key = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = BASE64.encode(key);
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            self.path, self.host, key
+        );
+        stream.write_all(req.as_bytes()).await?;
+        stream.flush().await?;
+
+        let resp = Self::read_http_header(stream).await?;
+        let status_ok = resp.lines().next().is_some_and(|line| line.contains(" 101 "));
+        let accept = Self::header_value(&resp, "Sec-WebSocket-Accept");
+        if !status_ok || accept != Some(Self::accept_key(&key).as_str()) {
+            return Err(Error::new(ErrorKind::InvalidData, "WebSocket handshake rejected"));
+        }
+        Ok(())
+    }
+
+    async fn server_handshake(stream: &mut BoxedStream) -> Result<()> {
+        let req = Self::read_http_header(stream).await?;
+        let upgrade = Self::header_value(&req, "Upgrade");
+        let key = Self::header_value(&req, "Sec-WebSocket-Key");
+        let (Some(upgrade), Some(key)) = (upgrade, key) else {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").await?;
+            return Err(Error::new(ErrorKind::InvalidData, "Not a WebSocket upgrade request"));
+        };
+        if !upgrade.eq_ignore_ascii_case("websocket") {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a WebSocket upgrade request"));
+        }
+        let resp = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            Self::accept_key(key)
+        );
+        stream.write_all(resp.as_bytes()).await?;
+        stream.flush().await
+    }
+}
+
+struct WebSocketCodec {
+    role: Role,
+}
+
+impl WebSocketCodec {
+    const OPCODE_CONTINUATION: u8 = 0x0;
+    const OPCODE_BINARY: u8 = 0x2;
+    const OPCODE_CLOSE: u8 = 0x8;
+}
+
+impl FrameCodec for WebSocketCodec {
+    fn encode(&mut self, payload: &[u8], out: &mut Vec<u8>) {
+        let masked = self.role == Role::Client;
+        let mask_bit = if masked { 0x80 } else { 0 };
+        out.push(0x80 | Self::OPCODE_BINARY);
+        match payload.len() {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if masked {
+            let mask = rand::thread_rng().r#gen::<[u8; 4]>();
+            out.extend_from_slice(&mask);
+            out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            out.extend_from_slice(payload);
+        }
+    }
+
+    fn decode(&mut self, raw: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+        loop {
+            if raw.len() < 2 {
+                return Ok(None);
+            }
+            let opcode = raw[0] & 0x0f;
+            let masked = raw[1] & 0x80 != 0;
+            let (payload_len, mut offset) = match raw[1] & 0x7f {
+                126 if raw.len() < 4 => return Ok(None),
+                126 => (u16::from_be_bytes([raw[2], raw[3]]) as usize, 4),
+                127 if raw.len() < 10 => return Ok(None),
+                127 => {
+                    let mut len = [0u8; 8];
+                    len.copy_from_slice(&raw[2..10]);
+                    (u64::from_be_bytes(len) as usize, 10)
+                }
+                len => (len as usize, 2),
+            };
+            let mask = if masked {
+                if raw.len() < offset + 4 {
+                    return Ok(None);
+                }
+                offset += 4;
+                Some([raw[offset - 4], raw[offset - 3], raw[offset - 2], raw[offset - 1]])
+            } else {
+                None
+            };
+            if raw.len() < offset + payload_len {
+                return Ok(None);
+            }
+            let mut payload = raw[offset..offset + payload_len].to_vec();
+            raw.drain(..offset + payload_len);
+            if let Some(mask) = mask {
+                payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+            }
+            match opcode {
+                Self::OPCODE_BINARY | Self::OPCODE_CONTINUATION => return Ok(Some(payload)),
+                Self::OPCODE_CLOSE => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted, "WebSocket closed"));
+                }
+                /* Text, ping and pong frames carry nothing for us */
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Obfuscator for WebSocketObfuscator {
+    fn name(&self) -> &'static str {
+        "websocket"
+    }
+
+    fn obfuscate(&self, mut stream: BoxedStream, role: Role) -> BoxedFuture<Result<BoxedStream>> {
+        let this = self.clone();
+        Box::pin(async move {
+            match role {
+                Role::Client => this.client_handshake(&mut stream).await?,
+                Role::Server => Self::server_handshake(&mut stream).await?,
+            }
+            Ok(Box::new(FramedStream::new(stream, WebSocketCodec { role })) as BoxedStream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BoxedStream, Obfuscator, PaddingObfuscator, Role, WebSocketObfuscator, XorObfuscator,
+    };
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    /// Send a payload from the client to the server through `obfuscator`,
+    /// returns what went over the wire
+    async fn roundtrip(obfuscator: &dyn Obfuscator) -> std::io::Result<Vec<u8>> {
+        let (client, client_wire) = duplex(64);
+        let (server_wire, server) = duplex(64);
+        let (mut tap_w, mut tap_r) = duplex(1 << 20);
+        let payload = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        /* Relay the wire, recording what the client sends */
+        tokio::spawn(async move {
+            let (mut client_wire_r, mut client_wire_w) = tokio::io::split(client_wire);
+            let (mut server_wire_r, mut server_wire_w) = tokio::io::split(server_wire);
+            let upstream = async move {
+                let mut buf = [0u8; 1024];
+                loop {
+                    let len = client_wire_r.read(&mut buf).await?;
+                    if len == 0 {
+                        break server_wire_w.shutdown().await;
+                    }
+                    tap_w.write_all(&buf[..len]).await?;
+                    server_wire_w.write_all(&buf[..len]).await?;
+                }
+            };
+            let downstream = tokio::io::copy(&mut server_wire_r, &mut client_wire_w);
+            let _ = tokio::join!(upstream, downstream);
+        });
+
+        let (client, server) = tokio::join!(
+            obfuscator.obfuscate(Box::new(client) as BoxedStream, Role::Client),
+            obfuscator.obfuscate(Box::new(server) as BoxedStream, Role::Server),
+        );
+        let (mut client, mut server) = (client?, server?);
+
+        let to_send = payload.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&to_send).await?;
+            client.shutdown().await
+        });
+        let mut received = vec![];
+        server.read_to_end(&mut received).await?;
+        writer.await??;
+        assert_eq!(received, payload);
+
+        let mut on_wire = vec![];
+        tap_r.read_to_end(&mut on_wire).await?;
+        Ok(on_wire)
+    }
+
+    #[test]
+    fn test_obfuscators_roundtrip() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let on_wire = roundtrip(&XorObfuscator::new(b"secret")).await?;
+            assert_eq!(on_wire.len(), 70000 + XorObfuscator::SALT_LEN);
+            assert!(!on_wire.windows(16).any(|w| w == (0..16).collect::<Vec<u8>>()));
+
+            let on_wire = roundtrip(&PaddingObfuscator::default()).await?;
+            assert!(on_wire.len() >= 70000 + 3);
+
+            let on_wire = roundtrip(&WebSocketObfuscator::new("example.com", "/ws")).await?;
+            assert!(on_wire.starts_with(b"GET /ws HTTP/1.1\r\n"));
+            Ok(())
+        })
+    }
+}
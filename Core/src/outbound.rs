@@ -0,0 +1,198 @@
+//! Outbounds, where the connections the [crate::Router] decides on are made
+//!
+//! Every [RuleAction] but [RuleAction::Proxy] has a built-in [Dialer]:
+//! [Direct] connects to the destination and [Reject] refuses to, so that
+//! the server only has to pick a dialer and report what it returned.
+//!
+//! [RuleAction]: crate::RuleAction
+//! [RuleAction::Proxy]: crate::RuleAction::Proxy
+
+use crate::{DialConfig, OutboundBind, ResolveStats, happy_eyeballs_connect, resolve};
+
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+
+use socks5::protocol::{Address, ReplyField};
+use tokio::net::TcpStream;
+
+pub type DialFuture<'a> = Pin<Box<dyn Future<Output = Result<TcpStream>> + Send + 'a>>;
+
+/// Makes the outbound connection of a request
+pub trait Dialer: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Connect to `addr`, errors carrying a [Rejection] tell how to answer
+    /// the client
+    fn dial<'a>(&'a self, addr: &'a Address) -> DialFuture<'a>;
+}
+
+/// Connects to the destination itself
+#[derive(Debug, Clone, Default)]
+pub struct Direct {
+    dial_config: DialConfig,
+    /// Local address connections originate from
+    bind: Option<IpAddr>,
+    resolve_stats: ResolveStats,
+}
+
+impl Direct {
+    pub fn new(dial_config: DialConfig) -> Self {
+        Self { dial_config, bind: None, resolve_stats: ResolveStats::default() }
+    }
+
+    /// Connect from `bind`, so only to destinations of its address family
+    pub fn bind(&mut self, bind: IpAddr) -> &mut Self {
+        self.bind = Some(bind);
+        self
+    }
+
+    /// Share the counters of the names resolved with `resolve_stats`
+    pub fn resolve_stats(&mut self, resolve_stats: ResolveStats) -> &mut Self {
+        self.resolve_stats = resolve_stats;
+        self
+    }
+
+    /// Try `addrs` in turn from the bound address, still through the
+    /// interface the [DialConfig] pins connections to
+    async fn connect_bound(&self, bind: IpAddr, addrs: &[SocketAddr]) -> Result<TcpStream> {
+        let mut last_err = None;
+        for addr in addrs.iter().filter(|addr| addr.is_ipv4() == bind.is_ipv4()) {
+            let socket = self.dial_config.sockopts.outbound_socket(*addr)?;
+            if let Some(pin @ OutboundBind::Interface(_)) = &self.dial_config.bind {
+                pin.apply(&socket, addr.is_ipv6())?;
+            }
+            socket.bind(SocketAddr::new(bind, 0))?;
+            match socket.connect(*addr).await {
+                Ok(tcp_stream) => return Ok(tcp_stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("No destination reachable from {}", bind),
+            )
+        }))
+    }
+
+    async fn connect(&self, addr: &Address) -> Result<TcpStream> {
+        let addrs = match addr {
+            Address::IP(socket_addr) => vec![*socket_addr],
+            Address::Domain(name, port) => {
+                let preference = self.dial_config.family_preference;
+                resolve(name, *port, preference, &self.resolve_stats).await?
+            }
+        };
+        match self.bind {
+            Some(bind) => self.connect_bound(bind, &addrs).await,
+            None => {
+                let config = DialConfig { prefer_ipv6: addrs[0].is_ipv6(), ..self.dial_config };
+                happy_eyeballs_connect(&addrs, &config).await
+            }
+        }
+    }
+}
+
+impl Dialer for Direct {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+
+    fn dial<'a>(&'a self, addr: &'a Address) -> DialFuture<'a> {
+        Box::pin(self.connect(addr))
+    }
+}
+
+/// How a [Reject] answers the client
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    /// Reply with this field
+    Reply(ReplyField),
+    /// Reset the connection without replying
+    Reset,
+}
+
+impl Rejection {
+    /// The rejection `e` carries, if it comes from a [Reject]
+    pub fn of(e: &Error) -> Option<&Rejection> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<Rejection>())
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reply(rep) => write!(f, "Rejected with {:?}", rep),
+            Self::Reset => f.write_str("Rejected with a reset"),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Never connects, by default replying that the rules do not allow it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reject {
+    rejection: Rejection,
+}
+
+impl Default for Reject {
+    fn default() -> Self {
+        Self::new(Rejection::Reply(ReplyField::ConnectionNotAllowedByRuleSet))
+    }
+}
+
+impl Reject {
+    pub fn new(rejection: Rejection) -> Self {
+        Self { rejection }
+    }
+}
+
+impl Dialer for Reject {
+    fn name(&self) -> &'static str {
+        "reject"
+    }
+
+    fn dial<'a>(&'a self, _addr: &'a Address) -> DialFuture<'a> {
+        let rejection = self.rejection.clone();
+        Box::pin(async move { Err(Error::new(ErrorKind::ConnectionAborted, rejection)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dialer, Direct, Reject, Rejection};
+
+    use std::net::{IpAddr, Ipv6Addr};
+
+    use socks5::protocol::{Address, ReplyField};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_builtin_dialers() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = Address::IP(listener.local_addr()?);
+            let tcp_stream = Direct::default().dial(&addr).await?;
+            assert_eq!(tcp_stream.peer_addr()?, listener.local_addr()?);
+
+            /* Bound to IPv6, an IPv4 destination is out of reach */
+            let mut direct = Direct::default();
+            direct.bind(IpAddr::V6(Ipv6Addr::LOCALHOST));
+            assert!(direct.dial(&addr).await.is_err());
+
+            let e = Reject::default().dial(&addr).await.unwrap_err();
+            assert_eq!(
+                Rejection::of(&e),
+                Some(&Rejection::Reply(ReplyField::ConnectionNotAllowedByRuleSet))
+            );
+            let e = Reject::new(Rejection::Reset).dial(&addr).await.unwrap_err();
+            assert_eq!(Rejection::of(&e), Some(&Rejection::Reset));
+            Ok(())
+        })
+    }
+}
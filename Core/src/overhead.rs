@@ -0,0 +1,112 @@
+//! What is left of a link MTU for the payload once a transport wrapped it
+//!
+//! A packet sent over an upstream carries the headers of every layer it
+//! crosses: the IP header of the path, that of UDP or TCP, then whatever the
+//! transport adds on top, the record of TLS, the short header and tag of
+//! QUIC, the data message of WireGuard, and the frame of the multiplexing
+//! when streams share a connection. [payload_mtu] takes them all off, for
+//! the MSS clamping, the path MTU probes and the tunnel interface to agree.
+//!
+//! Optional fields are counted at their usual size, not the largest they
+//! may take: TCP options are left out, QUIC connection IDs are taken as 8
+//! bytes. Where they grow, path MTU discovery makes up for it.
+
+pub const IPV4_HEADER_LEN: u16 = 20;
+pub const IPV6_HEADER_LEN: u16 = 40;
+pub const UDP_HEADER_LEN: u16 = 8;
+/// Without options
+pub const TCP_HEADER_LEN: u16 = 20;
+
+/// The header of a TLS 1.3 record, the inner content type and the AEAD tag
+pub const TLS_RECORD_OVERHEAD: u16 = 5 + 1 + 16;
+
+/// A QUIC short header with a connection ID of 8 bytes and a packet number
+/// of 4, the AEAD tag and the type of the DATAGRAM frame
+pub const QUIC_SHORT_OVERHEAD: u16 = 1 + 8 + 4 + 16 + 1;
+
+/// The header and the tag of a WireGuard data message
+pub const WIREGUARD_OVERHEAD: u16 = 16 + 16;
+
+/// The frame header of a stream multiplexed with others, that of yamux
+pub const MUX_FRAME_OVERHEAD: u16 = 12;
+
+/// What carries the payload over the path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Carrier {
+    Udp,
+    Tcp,
+    /// TLS over TCP
+    Tls,
+    /// QUIC datagrams over UDP
+    Quic,
+    /// WireGuard data messages over UDP
+    WireGuard,
+}
+
+impl Carrier {
+    /// Of the headers above IP
+    pub const fn overhead(self) -> u16 {
+        match self {
+            Self::Udp => UDP_HEADER_LEN,
+            Self::Tcp => TCP_HEADER_LEN,
+            Self::Tls => TCP_HEADER_LEN + TLS_RECORD_OVERHEAD,
+            Self::Quic => UDP_HEADER_LEN + QUIC_SHORT_OVERHEAD,
+            Self::WireGuard => UDP_HEADER_LEN + WIREGUARD_OVERHEAD,
+        }
+    }
+}
+
+/// How a payload is sent over a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportKind {
+    /// The path is over IPv6 rather than IPv4
+    pub ipv6: bool,
+    pub carrier: Carrier,
+    /// In frames of a multiplexed connection
+    pub mux: bool,
+}
+
+impl TransportKind {
+    pub const fn new(ipv6: bool, carrier: Carrier) -> Self {
+        Self { ipv6, carrier, mux: false }
+    }
+
+    pub const fn muxed(mut self) -> Self {
+        self.mux = true;
+        self
+    }
+
+    /// Of all the headers wrapping the payload
+    pub const fn overhead(self) -> u16 {
+        let ip = if self.ipv6 { IPV6_HEADER_LEN } else { IPV4_HEADER_LEN };
+        let mux = if self.mux { MUX_FRAME_OVERHEAD } else { 0 };
+        ip + self.carrier.overhead() + mux
+    }
+}
+
+/// The most a payload sent as `transport` may be for the packet to fit in
+/// `link_mtu`, 0 when the headers alone do not
+pub const fn payload_mtu(link_mtu: u16, transport: TransportKind) -> u16 {
+    link_mtu.saturating_sub(transport.overhead())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_mtu() {
+        /* Those of wg-quick over IPv6 and IPv4 */
+        assert_eq!(payload_mtu(1500, TransportKind::new(true, Carrier::WireGuard)), 1420);
+        assert_eq!(payload_mtu(1500, TransportKind::new(false, Carrier::WireGuard)), 1440);
+        /* The MSS of Ethernet */
+        assert_eq!(payload_mtu(1500, TransportKind::new(false, Carrier::Tcp)), 1460);
+        assert_eq!(payload_mtu(1500, TransportKind::new(true, Carrier::Tcp)), 1440);
+        assert_eq!(payload_mtu(1500, TransportKind::new(false, Carrier::Udp)), 1472);
+        assert_eq!(payload_mtu(1500, TransportKind::new(false, Carrier::Tls)), 1438);
+        assert_eq!(payload_mtu(1280, TransportKind::new(true, Carrier::Quic)), 1202);
+        let muxed = TransportKind::new(false, Carrier::Tls).muxed();
+        assert_eq!(payload_mtu(1500, muxed), 1426);
+        assert_eq!(payload_mtu(40, TransportKind::new(true, Carrier::Quic)), 0);
+    }
+}
@@ -0,0 +1,157 @@
+//! Packet captures of tunnel traffic in the classic pcap format, readable by
+//! tcpdump and Wireshark
+//!
+//! A [TunCapture] is held by every tunnel interface and records nothing
+//! until started, so that it can be switched on and off while the
+//! interface is in use. It is given the packets without the framing of
+//! the interface, so recordings are of raw IP on every system.
+
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes of each packet recorded, longer packets are truncated
+pub const DEFAULT_SNAPLEN: u32 = 65535;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+
+/// What precedes the IP header of recorded packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum LinkType {
+    /// Nothing, the packet starts with the IP header
+    Raw = 101,
+    /// The address family in network byte order
+    Loop = 108,
+}
+
+/// Writes packets to `W` in the classic pcap format, with microsecond
+/// timestamps
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    inner: W,
+    snaplen: u32,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the file header to `inner`
+    pub fn new(mut inner: W, link_type: LinkType, snaplen: u32) -> Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_ne_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MAJOR.to_ne_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MINOR.to_ne_bytes());
+        header.extend_from_slice(&0i32.to_ne_bytes()); /* thiszone */
+        header.extend_from_slice(&0u32.to_ne_bytes()); /* sigfigs */
+        header.extend_from_slice(&snaplen.to_ne_bytes());
+        header.extend_from_slice(&(link_type as u32).to_ne_bytes());
+        inner.write_all(&header)?;
+        Ok(Self { inner, snaplen })
+    }
+
+    #[inline]
+    pub fn write_packet(&mut self, packet: &[u8]) -> Result<()> {
+        self.write_packet_at(SystemTime::now(), packet)
+    }
+
+    pub fn write_packet_at(&mut self, time: SystemTime, packet: &[u8]) -> Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let caplen = packet.len().min(self.snaplen as usize);
+        let mut record = Vec::with_capacity(16 + caplen);
+        record.extend_from_slice(&(since_epoch.as_secs() as u32).to_ne_bytes());
+        record.extend_from_slice(&since_epoch.subsec_micros().to_ne_bytes());
+        record.extend_from_slice(&(caplen as u32).to_ne_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_ne_bytes());
+        record.extend_from_slice(&packet[..caplen]);
+        self.inner.write_all(&record)
+    }
+
+    #[inline]
+    pub fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[derive(Debug)]
+struct Recording {
+    path: PathBuf,
+    writer: PcapWriter<BufWriter<File>>,
+}
+
+/// Tees the packets of a tunnel interface into a pcap file while started,
+/// clones share the same recording
+#[derive(Debug, Clone, Default)]
+pub struct TunCapture {
+    recording: Arc<Mutex<Option<Recording>>>,
+}
+
+impl TunCapture {
+    /// Record into a new file at `path`, replacing the recording in progress
+    pub fn start(&self, path: &Path) -> Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let writer = PcapWriter::new(file, LinkType::Raw, DEFAULT_SNAPLEN)?;
+        let recording = Recording { path: path.to_path_buf(), writer };
+        if let Some(mut previous) = self.recording.lock().unwrap().replace(recording) {
+            previous.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finish the recording in progress, returns the file it went to
+    pub fn stop(&self) -> Result<Option<PathBuf>> {
+        let Some(mut recording) = self.recording.lock().unwrap().take() else {
+            return Ok(None);
+        };
+        recording.writer.flush()?;
+        Ok(Some(recording.path))
+    }
+
+    /// The file being recorded into
+    pub fn path(&self) -> Option<PathBuf> {
+        self.recording.lock().unwrap().as_ref().map(|recording| recording.path.to_owned())
+    }
+
+    /// Record `packet` when started, a failing write stops the recording
+    pub fn tee(&self, packet: &[u8]) {
+        let mut recording = self.recording.lock().unwrap();
+        if let Some(Err(e)) = recording.as_mut().map(|rec| rec.writer.write_packet(packet)) {
+            crate::debug_println!("Packet capture stopped ({:?})", e);
+            recording.take();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkType, PcapWriter};
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_pcap_writer() -> std::io::Result<()> {
+        let mut writer = PcapWriter::new(vec![], LinkType::Raw, 4)?;
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        writer.write_packet_at(time, &[0x45, 0, 0, 20, 0, 0])?;
+        let buf = writer.into_inner();
+
+        let u32_at = |at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), 0xa1b2c3d4);
+        assert_eq!(u32_at(16), 4);
+        assert_eq!(u32_at(20), 101);
+        assert_eq!(u32_at(24), 1_700_000_000);
+        assert_eq!(u32_at(28), 123_456);
+        /* Truncated to the snaplen, along with the original length */
+        assert_eq!(u32_at(32), 4);
+        assert_eq!(u32_at(36), 6);
+        assert_eq!(&buf[40..], &[0x45, 0, 0, 20]);
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! Path MTU discovery toward a host, for sizing a tunnel interface whose
+//! packets end up carried to it
+//!
+//! Probes are UDP datagrams to the host with the don't-fragment bit set,
+//! from the largest of [MTU_PLATEAUS] down, as `tracepath` does. A probe
+//! too large for the local interface fails to send. One too large for a
+//! router on the way brings back an ICMP "fragmentation needed", which
+//! Linux reports on the socket along with the MTU the router told. The
+//! first probe neither refused nor reported within the timeout makes the
+//! path MTU. macOS reports no ICMP errors on UDP sockets, only the local
+//! interface limits the probes there.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::overhead::{Carrier, TransportKind, payload_mtu};
+
+/// MTUs common to links, after RFC 1191, those above Ethernet left out
+pub const MTU_PLATEAUS: [u16; 8] = [1500, 1492, 1480, 1460, 1440, 1400, 1350, 1280];
+
+/// What every IPv6 link carries, the path MTU is assumed no lower
+pub const MIN_PATH_MTU: u16 = 1280;
+
+/// The path MTU toward `dest`, each probe waiting `timeout` for an error
+pub async fn probe_path_mtu(dest: SocketAddr, timeout: Duration) -> Result<u16> {
+    let socket = match dest {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+    };
+    socket.connect(dest).await?;
+    set_dont_fragment(&socket, dest.is_ipv6())?;
+    let transport = TransportKind::new(dest.is_ipv6(), Carrier::Udp);
+    let mut mtu = MTU_PLATEAUS[0];
+    while mtu > MIN_PATH_MTU {
+        let probe = vec![0u8; payload_mtu(mtu, transport) as usize];
+        match send_probe(&socket, &probe, timeout).await {
+            Ok(()) => return Ok(mtu),
+            Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                mtu = next_mtu(&socket, dest.is_ipv6(), mtu);
+            }
+            /* Port unreachable, which the host sent having got the probe */
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => return Ok(mtu),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(MIN_PATH_MTU)
+}
+
+/// Send `probe`, then wait `timeout` for an error about it. An answer, or
+/// none at all, is as good as the probe having passed.
+async fn send_probe(socket: &UdpSocket, probe: &[u8], timeout: Duration) -> Result<()> {
+    socket.send(probe).await?;
+    match tokio::time::timeout(timeout, socket.recv(&mut [0u8; 1])).await {
+        Ok(Err(e)) => Err(e),
+        Ok(Ok(_)) | Err(_) => Ok(()),
+    }
+}
+
+/// The MTU to probe after `mtu` was found too large: what the kernel
+/// learned of the path if lower, else the next plateau
+fn next_mtu(socket: &UdpSocket, ipv6: bool, mtu: u16) -> u16 {
+    known_path_mtu(socket, ipv6).filter(|known| *known < mtu).unwrap_or_else(|| next_plateau(mtu))
+}
+
+fn next_plateau(mtu: u16) -> u16 {
+    MTU_PLATEAUS.iter().copied().find(|plateau| *plateau < mtu).unwrap_or(MIN_PATH_MTU)
+}
+
+/// `IP_MTU` or `IPV6_MTU` of the connected `socket`, Linux only
+#[allow(unused_variables)]
+fn known_path_mtu(socket: &UdpSocket, ipv6: bool) -> Option<u16> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return {
+        let (level, optname) = match ipv6 {
+            false => (libc::IPPROTO_IP, libc::IP_MTU),
+            true => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        };
+        let mut val: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                std::os::fd::AsRawFd::as_raw_fd(socket),
+                level,
+                optname,
+                &mut val as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (ret == 0).then(|| u16::try_from(val).ok()).flatten()
+    };
+    #[allow(unreachable_code)]
+    None
+}
+
+/// Have the datagrams of `socket` sent unfragmented, failing when too
+/// large to
+fn set_dont_fragment(socket: &UdpSocket, ipv6: bool) -> Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let (level, optname, val) = match ipv6 {
+        false => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO),
+        true => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO),
+    };
+    #[cfg(target_os = "macos")]
+    let (level, optname, val) = match ipv6 {
+        false => (libc::IPPROTO_IP, libc::IP_DONTFRAG, 1),
+        true => (libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, 1),
+    };
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    return Err(Error::new(ErrorKind::Unsupported, "No path MTU discovery on this system"));
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    {
+        let ret = unsafe {
+            libc::setsockopt(
+                std::os::fd::AsRawFd::as_raw_fd(socket),
+                level,
+                optname,
+                &val as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MIN_PATH_MTU, MTU_PLATEAUS, next_plateau, probe_path_mtu};
+
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn test_next_plateau() {
+        assert_eq!(next_plateau(1500), 1492);
+        assert_eq!(next_plateau(1450), 1440);
+        assert_eq!(next_plateau(1300), MIN_PATH_MTU);
+    }
+
+    #[test]
+    fn test_probe_path_mtu() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            /* Loopback carries more than any plateau */
+            let peer = UdpSocket::bind("127.0.0.1:0").await?;
+            let mtu = probe_path_mtu(peer.local_addr()?, Duration::from_millis(50)).await?;
+            assert_eq!(mtu, MTU_PLATEAUS[0]);
+            Ok(())
+        })
+    }
+}
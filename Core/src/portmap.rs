@@ -0,0 +1,620 @@
+//! Port mappings on the NAT gateway, so that peers can reach the
+//! node-to-node listener of a host behind NAT.
+//!
+//! NAT-PMP (RFC 6886) is asked first, one datagram to the default gateway
+//! each way. Gateways that do not answer it are looked for over SSDP and
+//! asked through the WANIPConnection service of UPnP IGD instead. Either
+//! way the mapping only lasts its lifetime, [PortMapping::maintain] renews
+//! it halfway through and removes it once told to stop.
+
+use core::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Where gateways listen for NAT-PMP requests
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// Lifetime of the mappings asked for, the one RFC 6886 recommends
+pub const DEFAULT_MAPPING_LIFETIME: Duration = Duration::from_secs(7200);
+
+/// The first NAT-PMP retransmission, doubled each time after
+const NAT_PMP_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const NAT_PMP_ATTEMPTS: u32 = 4;
+
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(2);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// UPnP error code of gateways that only map ports for good
+const ONLY_PERMANENT_LEASES_SUPPORTED: &str = "725";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapProtocol {
+    Tcp,
+    Udp,
+}
+
+impl MapProtocol {
+    fn nat_pmp_opcode(self) -> u8 {
+        match self {
+            Self::Udp => 1,
+            Self::Tcp => 2,
+        }
+    }
+
+    fn igd_name(self) -> &'static str {
+        match self {
+            Self::Tcp => "TCP",
+            Self::Udp => "UDP",
+        }
+    }
+}
+
+/// An `http://` URL of an IGD, whose host is an IPv4 address
+#[derive(Debug, Clone, PartialEq)]
+struct HttpUrl {
+    addr: SocketAddr,
+    path: String,
+}
+
+impl HttpUrl {
+    fn parse(url: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("Unsupported URL {}", url));
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (host, path) = match rest.find('/') {
+            Some(n) => (&rest[..n], &rest[n..]),
+            None => (rest, "/"),
+        };
+        let addr = match host.parse() {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(host.parse().map_err(|_| invalid())?, 80),
+        };
+        Ok(Self { addr, path: path.to_owned() })
+    }
+
+    /// `reference` taken relative to `self`
+    fn join(&self, reference: &str) -> Result<Self> {
+        if reference.starts_with("http://") {
+            return Self::parse(reference);
+        }
+        let path = match reference.starts_with('/') {
+            true => reference.to_owned(),
+            false => format!("/{}", reference),
+        };
+        Ok(Self { addr: self.addr, path })
+    }
+}
+
+/// The WANIPConnection (or WANPPPConnection) service of an IGD
+#[derive(Debug, Clone, PartialEq)]
+struct IgdService {
+    control_url: HttpUrl,
+    service_type: String,
+    /// The address the IGD sees this host from, mappings point at it
+    local_ip: Ipv4Addr,
+}
+
+#[derive(Debug, Clone)]
+enum Gateway {
+    NatPmp(SocketAddr),
+    Igd(IgdService),
+}
+
+/// A port mapped on the gateway to one of this host
+#[derive(Debug)]
+pub struct PortMapping {
+    gateway: Gateway,
+    protocol: MapProtocol,
+    internal_port: u16,
+    external: SocketAddr,
+    /// Zero for mappings that do not expire
+    lifetime: Duration,
+}
+
+impl PortMapping {
+    /// Map a port to `internal_port` on the default gateway, through
+    /// NAT-PMP or otherwise UPnP IGD
+    pub async fn request(
+        protocol: MapProtocol,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<Self> {
+        let nat_pmp_err = match default_gateway() {
+            Ok(gateway) => {
+                let gateway = SocketAddr::new(IpAddr::V4(gateway), NAT_PMP_PORT);
+                match Self::request_nat_pmp(gateway, protocol, internal_port, lifetime).await {
+                    Ok(mapping) => return Ok(mapping),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        crate::debug_println!("NAT-PMP failed: {}, trying UPnP IGD", nat_pmp_err);
+        Self::request_igd(protocol, internal_port, lifetime).await
+    }
+
+    /// Map a port to `internal_port` through the NAT-PMP server `gateway`
+    pub async fn request_nat_pmp(
+        gateway: SocketAddr,
+        protocol: MapProtocol,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<Self> {
+        let external_ip = nat_pmp_external_ip(gateway).await?;
+        let (external_port, lifetime) =
+            nat_pmp_map(gateway, protocol, internal_port, internal_port, lifetime).await?;
+        Ok(Self {
+            gateway: Gateway::NatPmp(gateway),
+            protocol,
+            internal_port,
+            external: SocketAddr::new(IpAddr::V4(external_ip), external_port),
+            lifetime,
+        })
+    }
+
+    /// Map the same port to `internal_port` through the first IGD that
+    /// answers over SSDP
+    pub async fn request_igd(
+        protocol: MapProtocol,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<Self> {
+        let service = discover_igd().await?;
+        let lifetime = igd_add_mapping(&service, protocol, internal_port, lifetime).await?;
+        let external_ip = igd_external_ip(&service).await?;
+        Ok(Self {
+            gateway: Gateway::Igd(service),
+            protocol,
+            internal_port,
+            external: SocketAddr::new(IpAddr::V4(external_ip), internal_port),
+            lifetime,
+        })
+    }
+
+    /// The address peers reach the mapped port at
+    pub fn external(&self) -> SocketAddr {
+        self.external
+    }
+
+    /// How long the gateway keeps the mapping, zero if for good
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Ask the gateway for another lifetime, the external port may change
+    pub async fn renew(&mut self) -> Result<()> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                let (external_port, lifetime) = nat_pmp_map(
+                    *gateway,
+                    self.protocol,
+                    self.internal_port,
+                    self.external.port(),
+                    self.lifetime,
+                )
+                .await?;
+                self.external.set_port(external_port);
+                self.lifetime = lifetime;
+            }
+            Gateway::Igd(service) => {
+                igd_add_mapping(service, self.protocol, self.internal_port, self.lifetime).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the mapping from the gateway
+    pub async fn remove(self) -> Result<()> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                nat_pmp_map(*gateway, self.protocol, self.internal_port, 0, Duration::ZERO)
+                    .await
+                    .map(drop)
+            }
+            Gateway::Igd(service) => {
+                igd_delete_mapping(service, self.protocol, self.external.port()).await
+            }
+        }
+    }
+
+    /// Renew the mapping halfway through each lifetime until `shutdown`
+    /// completes, then remove it
+    pub async fn maintain(mut self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        tokio::pin!(shutdown);
+        loop {
+            if self.lifetime.is_zero() {
+                shutdown.as_mut().await;
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.lifetime / 2) => {
+                    if let Err(e) = self.renew().await {
+                        crate::debug_println!("Renewing the mapping of {} failed: {}", self.external, e);
+                    }
+                }
+                _ = shutdown.as_mut() => break,
+            }
+        }
+        self.remove().await
+    }
+}
+
+/// Send `req` to `gateway` until it answers, as RFC 6886 says
+async fn nat_pmp_exchange(gateway: SocketAddr, req: &[u8], resp_len: usize) -> Result<Vec<u8>> {
+    let udp_sock = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    udp_sock.connect(gateway).await?;
+    let mut resp = vec![0u8; 16];
+    let mut timeout = NAT_PMP_INITIAL_TIMEOUT;
+    for _ in 0..NAT_PMP_ATTEMPTS {
+        udp_sock.send(req).await?;
+        if let Ok(len) = tokio::time::timeout(timeout, udp_sock.recv(&mut resp)).await {
+            let len = len?;
+            /* Answers to other requests are not ours */
+            if len >= resp_len && resp[1] == req[1] | 0x80 {
+                resp.truncate(len);
+                return nat_pmp_result(resp);
+            }
+        }
+        timeout *= 2;
+    }
+    Err(Error::new(ErrorKind::TimedOut, format!("No NAT-PMP answer from {}", gateway)))
+}
+
+fn nat_pmp_result(resp: Vec<u8>) -> Result<Vec<u8>> {
+    let reason = match u16::from_be_bytes([resp[2], resp[3]]) {
+        0 => return Ok(resp),
+        1 => "Unsupported version",
+        2 => "Not authorized",
+        3 => "Network failure",
+        4 => "Out of resources",
+        5 => "Unsupported opcode",
+        _ => "Unknown result code",
+    };
+    Err(Error::other(format!("NAT-PMP refused: {}", reason)))
+}
+
+async fn nat_pmp_external_ip(gateway: SocketAddr) -> Result<Ipv4Addr> {
+    let resp = nat_pmp_exchange(gateway, &[0, 0], 12).await?;
+    Ok(Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]))
+}
+
+/// Returns the external port and lifetime the gateway granted
+async fn nat_pmp_map(
+    gateway: SocketAddr,
+    protocol: MapProtocol,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> Result<(u16, Duration)> {
+    let mut req = vec![0, protocol.nat_pmp_opcode(), 0, 0];
+    req.extend_from_slice(&internal_port.to_be_bytes());
+    req.extend_from_slice(&external_port.to_be_bytes());
+    req.extend_from_slice(&(lifetime.as_secs().min(u32::MAX as u64) as u32).to_be_bytes());
+    let resp = nat_pmp_exchange(gateway, &req, 16).await?;
+    let external_port = u16::from_be_bytes([resp[10], resp[11]]);
+    let lifetime = u32::from_be_bytes([resp[12], resp[13], resp[14], resp[15]]);
+    Ok((external_port, Duration::from_secs(lifetime as u64)))
+}
+
+/// The IPv4 default gateway of this host
+pub fn default_gateway() -> Result<Ipv4Addr> {
+    let not_found = || Error::new(ErrorKind::NotFound, "No IPv4 default gateway");
+    #[cfg(target_os = "macos")]
+    return {
+        let output = std::process::Command::new("route").args(["-n", "get", "default"]).output()?;
+        parse_route_get(&String::from_utf8_lossy(&output.stdout)).ok_or_else(not_found)
+    };
+    #[allow(unreachable_code)]
+    parse_proc_net_route(&std::fs::read_to_string("/proc/net/route")?).ok_or_else(not_found)
+}
+
+/// The gateway line of `route -n get default`
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_route_get(output: &str) -> Option<Ipv4Addr> {
+    output.lines().find_map(|line| line.trim().strip_prefix("gateway:")?.trim().parse().ok())
+}
+
+/// The gateway of the default route in `/proc/net/route`, whose addresses
+/// are hexadecimal in host byte order
+fn parse_proc_net_route(table: &str) -> Option<Ipv4Addr> {
+    const RTF_UP_GATEWAY: u32 = 0x3;
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+        if *fields.get(1)? != "00000000" || flags & RTF_UP_GATEWAY != RTF_UP_GATEWAY {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+/// The location of the first IGD that answers an SSDP search, then its
+/// connection service
+async fn discover_igd() -> Result<IgdService> {
+    let udp_sock = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        SSDP_ADDR
+    );
+    udp_sock.send_to(search.as_bytes(), SocketAddr::V4(SSDP_ADDR)).await?;
+    let location = tokio::time::timeout(SSDP_TIMEOUT, async {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, _) = udp_sock.recv_from(&mut buf).await?;
+            if let Some(location) = header_value(&String::from_utf8_lossy(&buf[..len]), "location")
+            {
+                return Ok::<_, Error>(location);
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::new(ErrorKind::TimedOut, "No IGD answered over SSDP"))??;
+
+    let url = HttpUrl::parse(&location)?;
+    let (local_ip, status, description) = http_request(&url, "GET", &[], "").await?;
+    if status != 200 {
+        return Err(Error::other(format!("IGD description answered with {}", status)));
+    }
+    let (service_type, control_url) = find_connection_service(&description)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "The IGD has no WAN connection service"))?;
+    Ok(IgdService { control_url: url.join(&control_url)?, service_type, local_ip })
+}
+
+/// The value of header `name` of an HTTP-like message
+fn header_value(message: &str, name: &str) -> Option<String> {
+    message.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_owned())
+    })
+}
+
+/// The text of the first `tag` element in `xml`
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let len = xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..start + len].trim())
+}
+
+/// The type and control URL of the WAN connection service in the device
+/// description `xml`
+fn find_connection_service(xml: &str) -> Option<(String, String)> {
+    xml.split("<service>").skip(1).find_map(|service| {
+        let service_type = xml_text(service, "serviceType")?;
+        if !service_type.contains(":WANIPConnection:")
+            && !service_type.contains(":WANPPPConnection:")
+        {
+            return None;
+        }
+        Some((service_type.to_owned(), xml_text(service, "controlURL")?.to_owned()))
+    })
+}
+
+/// Returns the local address of the connection, the status code and body
+async fn http_request(
+    url: &HttpUrl,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(Ipv4Addr, u16, String)> {
+    let exchange = async {
+        let mut tcp_stream = TcpStream::connect(url.addr).await?;
+        let local_ip = match tcp_stream.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED),
+        };
+        let mut req = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            url.path,
+            url.addr,
+            body.len()
+        );
+        for (name, value) in headers {
+            req.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        req.push_str("\r\n");
+        req.push_str(body);
+        tcp_stream.write_all(req.as_bytes()).await?;
+        let mut resp = Vec::new();
+        tcp_stream.read_to_end(&mut resp).await?;
+        Ok::<_, Error>((local_ip, resp))
+    };
+    let (local_ip, resp) = tokio::time::timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, format!("No answer from {}", url.addr)))??;
+
+    let invalid = || Error::new(ErrorKind::InvalidData, "Malformed HTTP response");
+    let split = resp.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&resp[..split]);
+    let status = head.split_whitespace().nth(1).and_then(|s| s.parse().ok()).ok_or_else(invalid)?;
+    let mut body = resp[split + 4..].to_vec();
+    if header_value(&head, "transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+        body = dechunk(&body).ok_or_else(invalid)?;
+    }
+    Ok((local_ip, status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn dechunk(mut chunked: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = chunked.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&chunked[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        let chunk = chunked.get(line_end + 2..line_end + 2 + size)?;
+        body.extend_from_slice(chunk);
+        chunked = chunked.get(line_end + 4 + size..)?;
+    }
+}
+
+/// Call `action` of the service, returning the body of the response
+async fn soap_call(service: &IgdService, action: &str, args: &[(&str, String)]) -> Result<String> {
+    let args: String =
+        args.iter().map(|(name, value)| format!("<{0}>{1}</{0}>", name, value)).collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>\r\n",
+        action, service.service_type, args
+    );
+    let soap_action = format!("\"{}#{}\"", service.service_type, action);
+    let headers =
+        [("Content-Type", "text/xml; charset=\"utf-8\""), ("SOAPAction", soap_action.as_str())];
+    let (_, status, resp) = http_request(&service.control_url, "POST", &headers, &body).await?;
+    if status != 200 {
+        let code = xml_text(&resp, "errorCode").unwrap_or_default();
+        let description = xml_text(&resp, "errorDescription").unwrap_or_default();
+        return Err(Error::other(format!("{} failed: {} {}", action, code, description)));
+    }
+    Ok(resp)
+}
+
+/// Returns the lifetime granted, gateways that only map for good are
+/// asked again without one
+async fn igd_add_mapping(
+    service: &IgdService,
+    protocol: MapProtocol,
+    port: u16,
+    lifetime: Duration,
+) -> Result<Duration> {
+    let args = |lifetime: Duration| {
+        [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol.igd_name().to_owned()),
+            ("NewInternalPort", port.to_string()),
+            ("NewInternalClient", service.local_ip.to_string()),
+            ("NewEnabled", String::from("1")),
+            ("NewPortMappingDescription", String::from("nstream")),
+            ("NewLeaseDuration", lifetime.as_secs().to_string()),
+        ]
+    };
+    match soap_call(service, "AddPortMapping", &args(lifetime)).await {
+        Ok(_) => Ok(lifetime),
+        Err(e)
+            if !lifetime.is_zero() && e.to_string().contains(ONLY_PERMANENT_LEASES_SUPPORTED) =>
+        {
+            soap_call(service, "AddPortMapping", &args(Duration::ZERO)).await?;
+            Ok(Duration::ZERO)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn igd_external_ip(service: &IgdService) -> Result<Ipv4Addr> {
+    let resp = soap_call(service, "GetExternalIPAddress", &[]).await?;
+    xml_text(&resp, "NewExternalIPAddress")
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The IGD reported no external address"))
+}
+
+async fn igd_delete_mapping(service: &IgdService, protocol: MapProtocol, port: u16) -> Result<()> {
+    let args = [
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", port.to_string()),
+        ("NewProtocol", protocol.igd_name().to_owned()),
+    ];
+    soap_call(service, "DeletePortMapping", &args).await.map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        HttpUrl, MapProtocol, PortMapping, dechunk, find_connection_service, parse_proc_net_route,
+        parse_route_get,
+    };
+
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    /// Maps every port to 40000 + port on 203.0.113.9, answering `count`
+    /// requests and returning the lifetimes asked for
+    async fn serve_nat_pmp(udp_sock: UdpSocket, count: usize) -> std::io::Result<Vec<u32>> {
+        let mut lifetimes = Vec::new();
+        let mut req = [0u8; 16];
+        for _ in 0..count {
+            let (len, from_addr) = udp_sock.recv_from(&mut req).await?;
+            let mut resp = vec![0, req[1] | 0x80, 0, 0, 0, 0, 0, 1];
+            match req[1] {
+                0 => resp.extend_from_slice(&[203, 0, 113, 9]),
+                _ => {
+                    assert_eq!(len, 12);
+                    let internal_port = u16::from_be_bytes([req[4], req[5]]);
+                    let lifetime = u32::from_be_bytes([req[8], req[9], req[10], req[11]]);
+                    lifetimes.push(lifetime);
+                    resp.extend_from_slice(&req[4..6]);
+                    resp.extend_from_slice(&(40000 + internal_port).to_be_bytes());
+                    resp.extend_from_slice(&lifetime.min(60).to_be_bytes());
+                }
+            }
+            udp_sock.send_to(&resp, from_addr).await?;
+        }
+        Ok(lifetimes)
+    }
+
+    #[test]
+    fn test_nat_pmp_mapping() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let udp_sock = UdpSocket::bind("127.0.0.1:0").await?;
+            let gateway = udp_sock.local_addr()?;
+            let server = tokio::spawn(serve_nat_pmp(udp_sock, 4));
+
+            let mut mapping = PortMapping::request_nat_pmp(
+                gateway,
+                MapProtocol::Tcp,
+                1080,
+                Duration::from_secs(7200),
+            )
+            .await?;
+            assert_eq!(mapping.external(), "203.0.113.9:41080".parse::<SocketAddr>().unwrap());
+            /* The gateway may grant less than asked for */
+            assert_eq!(mapping.lifetime(), Duration::from_secs(60));
+            mapping.renew().await?;
+            mapping.remove().await?;
+            assert_eq!(server.await??, vec![7200, 60, 0]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_default_gateway_parsers() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                     eth0\t00000000\t0100A8C0\t0003\t0\t0\t0\t00000000\n";
+        let expected = Ipv4Addr::from(u32::from_str_radix("0100A8C0", 16).unwrap().to_ne_bytes());
+        assert_eq!(parse_proc_net_route(table), Some(expected));
+        let without_default: String =
+            table.lines().take(2).map(|line| line.to_owned() + "\n").collect();
+        assert_eq!(parse_proc_net_route(&without_default), None);
+
+        let output = "   route to: default\ndestination: default\n    gateway: 192.168.0.1\n";
+        assert_eq!(parse_route_get(output), Some(Ipv4Addr::new(192, 168, 0, 1)));
+    }
+
+    #[test]
+    fn test_igd_description() {
+        let xml = "<root><device><serviceList>\
+                   <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+                   <controlURL>/l3f</controlURL></service>\
+                   <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                   <controlURL>ctl/IPConn</controlURL></service>\
+                   </serviceList></device></root>";
+        let (service_type, control_url) = find_connection_service(xml).unwrap();
+        assert_eq!(service_type, "urn:schemas-upnp-org:service:WANIPConnection:1");
+        let location = HttpUrl::parse("http://192.168.0.1:5000/rootDesc.xml").unwrap();
+        let control_url = location.join(&control_url).unwrap();
+        assert_eq!(control_url.addr, "192.168.0.1:5000".parse::<SocketAddr>().unwrap());
+        assert_eq!(control_url.path, "/ctl/IPConn");
+
+        assert_eq!(dechunk(b"5\r\nhello\r\n1;ext\r\n!\r\n0\r\n\r\n").unwrap(), b"hello!");
+        assert_eq!(dechunk(b"5\r\nhel"), None);
+    }
+}
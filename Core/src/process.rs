@@ -0,0 +1,241 @@
+//! The process owning the other end of a local TCP connection, for the
+//! rules matching applications rather than destinations
+//!
+//! Linux tells the owner through `/proc`: the socket of the connection in
+//! `/proc/net/tcp`, then the process holding a descriptor of it. macOS asks
+//! `lsof`, and tells the bundle identifier of applications from their
+//! `Info.plist`. Either walks every process, a lookup is only worth it for
+//! connections some rule depends on the process of.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// A process, as matched by the `PROCESS-NAME`, `PROCESS-PATH` and
+/// `BUNDLE-ID` rules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Process {
+    pub pid: u32,
+    /// Of the executable
+    pub path: PathBuf,
+    /// The `CFBundleIdentifier` of the application bundle the executable
+    /// is in, macOS only
+    pub bundle_id: Option<String>,
+}
+
+impl Process {
+    /// The file name of the executable
+    pub fn name(&self) -> Option<&str> {
+        self.path.file_name()?.to_str()
+    }
+}
+
+/// The process whose TCP connection from `client` this process accepted
+/// on `local`. Only a connection between two addresses of this host has an
+/// owner to be found.
+pub fn owner_of(client: SocketAddr, local: SocketAddr) -> Result<Process> {
+    let not_found = || Error::new(ErrorKind::NotFound, format!("No local process owns {}", client));
+    if client.ip().to_canonical() != local.ip().to_canonical() && !client.ip().is_loopback() {
+        return Err(not_found());
+    }
+    #[cfg(target_os = "macos")]
+    return {
+        let pid = lsof_owner(client)?.ok_or_else(not_found)?;
+        let path = proc_pidpath(pid)?;
+        let bundle_id = bundle_of(&path).and_then(bundle_id);
+        Ok(Process { pid, path, bundle_id })
+    };
+    #[cfg(target_os = "linux")]
+    return {
+        let inode = ["/proc/net/tcp", "/proc/net/tcp6"]
+            .iter()
+            .filter_map(|table| std::fs::read_to_string(table).ok())
+            .find_map(|table| socket_inode(&table, client, local))
+            .ok_or_else(not_found)?;
+        let pid = inode_owner(inode)?.ok_or_else(not_found)?;
+        let path = std::fs::read_link(format!("/proc/{}/exe", pid))?;
+        Ok(Process { pid, path, bundle_id: None })
+    };
+    #[allow(unreachable_code)]
+    Err(Error::new(ErrorKind::Unsupported, "No process lookup on this system"))
+}
+
+/// An address of `/proc/net/tcp` or `/proc/net/tcp6`, in hexadecimal with
+/// the address in words of host byte order
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_net_addr(field: &str) -> Option<SocketAddr> {
+    let (ip, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let word =
+        |i: usize| Some(u32::from_str_radix(ip.get(i * 8..(i + 1) * 8)?, 16).ok()?.to_ne_bytes());
+    let ip = match ip.len() {
+        8 => IpAddr::V4(Ipv4Addr::from(word(0)?)),
+        32 => {
+            let mut octets = [0; 16];
+            for (i, chunk) in octets.chunks_mut(4).enumerate() {
+                chunk.copy_from_slice(&word(i)?);
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// The inode of the socket connected from `client` to `local` in `table`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn socket_inode(table: &str, client: SocketAddr, local: SocketAddr) -> Option<u64> {
+    /* IPv4 clients of a dual-stack listener are v4-mapped on one side only */
+    let same = |a: SocketAddr, b: SocketAddr| {
+        a.ip().to_canonical() == b.ip().to_canonical() && a.port() == b.port()
+    };
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (from, to) =
+            (parse_proc_net_addr(fields.get(1)?)?, parse_proc_net_addr(fields.get(2)?)?);
+        (same(from, client) && same(to, local)).then(|| fields.get(9)?.parse().ok())?
+    })
+}
+
+/// The process other than this one with a descriptor of socket `inode`
+#[cfg(target_os = "linux")]
+fn inode_owner(inode: u64) -> Result<Option<u32>> {
+    let target = PathBuf::from(format!("socket:[{}]", inode));
+    for entry in std::fs::read_dir("/proc")?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse::<u32>().ok()) else {
+            continue;
+        };
+        if pid == std::process::id() {
+            continue;
+        }
+        /* Gone since, or of another user without the privileges */
+        let Ok(fds) = std::fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        if fds.flatten().any(|fd| std::fs::read_link(fd.path()).is_ok_and(|link| link == target)) {
+            return Ok(Some(pid));
+        }
+    }
+    Ok(None)
+}
+
+/// The process other than this one with a TCP socket bound to `client`,
+/// the connection accepted here being the one whose peer it is
+#[cfg(target_os = "macos")]
+fn lsof_owner(client: SocketAddr) -> Result<Option<u32>> {
+    let output = std::process::Command::new("lsof")
+        .args(["-nP", "-Fp", &format!("-iTCP@{}", client)])
+        .output()?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_prefix('p')?.parse::<u32>().ok())
+        .find(|pid| *pid != std::process::id()))
+}
+
+#[cfg(target_os = "macos")]
+fn proc_pidpath(pid: u32) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut buf = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len = unsafe {
+        libc::proc_pidpath(
+            pid as libc::c_int,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len() as u32,
+        )
+    };
+    if len <= 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(&buf[..len as usize])))
+}
+
+/// The outermost `.app` directory `path` is in, if any, an application
+/// embedding helpers being told by its own identifier
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn bundle_of(path: &Path) -> Option<&Path> {
+    path.ancestors()
+        .filter(|ancestor| ancestor.extension().is_some_and(|extension| extension == "app"))
+        .last()
+}
+
+/// `CFBundleIdentifier` of `Contents/Info.plist`, which may be a binary
+/// property list, hence `defaults`
+#[cfg(target_os = "macos")]
+fn bundle_id(bundle: &Path) -> Option<String> {
+    let info = bundle.join("Contents").join("Info");
+    let output = std::process::Command::new("defaults")
+        .arg("read")
+        .arg(info)
+        .arg("CFBundleIdentifier")
+        .output()
+        .ok()?;
+    let bundle_id = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    (output.status.success() && !bundle_id.is_empty()).then_some(bundle_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bundle_of, parse_proc_net_addr, socket_inode};
+
+    use std::net::SocketAddr;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_proc_net() {
+        let addr = parse_proc_net_addr("0100007F:1F90").unwrap();
+        assert_eq!(addr, "127.0.0.1:8080".parse::<SocketAddr>().unwrap());
+        let addr = parse_proc_net_addr("00000000000000000000000001000000:0438").unwrap();
+        assert_eq!(addr, "[::1]:1080".parse::<SocketAddr>().unwrap());
+        assert!(parse_proc_net_addr("0100007F").is_none());
+
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+                     0: 0100007F:0438 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1111 1 0 100 0 0 10 0\n\
+                     1: 0100007F:D431 0100007F:0438 01 00000000:00000000 00:00000000 00000000  1000        0 2222 1 0 20 4 30 10 -1\n\
+                     2: 0100007F:0438 0100007F:D431 01 00000000:00000000 00:00000000 00000000     0        0 3333 1 0 20 4 30 10 -1\n";
+        let client = "127.0.0.1:54321".parse().unwrap();
+        let local = "[::ffff:127.0.0.1]:1080".parse().unwrap();
+        /* The socket of the client, not the accepted one */
+        assert_eq!(socket_inode(table, client, local), Some(2222));
+        assert_eq!(socket_inode(table, "127.0.0.1:54322".parse().unwrap(), local), None);
+    }
+
+    #[test]
+    fn test_bundle_of() {
+        let path = Path::new("/Applications/Slack.app/Contents/MacOS/Slack");
+        assert_eq!(bundle_of(path), Some(Path::new("/Applications/Slack.app")));
+        let path = Path::new(
+            "/Applications/Chrome.app/Contents/Frameworks/Helper.app/Contents/MacOS/Helper",
+        );
+        assert_eq!(bundle_of(path), Some(Path::new("/Applications/Chrome.app")));
+        assert_eq!(bundle_of(Path::new("/usr/bin/curl")), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_owner_of() {
+        use super::owner_of;
+        use std::net::{TcpListener, TcpStream};
+        use std::process::{Command, Stdio};
+
+        /* A child process connects, this one accepts */
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = listener.local_addr().unwrap();
+        let mut child = Command::new("bash")
+            .arg("-c")
+            .arg(format!("exec 3<>/dev/tcp/127.0.0.1/{}; read -r _ <&3", local.port()))
+            .stdin(Stdio::null())
+            .spawn();
+        let Ok(child) = child.as_mut() else {
+            return;
+        };
+        let (accepted, client): (TcpStream, _) = listener.accept().unwrap();
+        let owner = owner_of(client, local);
+        drop(accepted);
+        let _ = child.wait();
+        let owner = owner.unwrap();
+        assert_eq!(owner.pid, child.id());
+        assert!(owner.name().is_some());
+        assert_eq!(owner.bundle_id, None);
+    }
+}
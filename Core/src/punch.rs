@@ -0,0 +1,553 @@
+//! Direct datagram channels between two nodes behind NAT
+//!
+//! Each node gathers the candidate addresses of one UDP socket, its local
+//! address and the reflexive one STUN reports, and hands them to the other
+//! along with an ephemeral X25519 key in an [Offer]. Offers go through a
+//! rendezvous server ([serve_rendezvous], [Endpoint::exchange]), which adds
+//! the address it sees each node at, or are pasted by hand as tokens. Both
+//! nodes then probe every candidate of the other at once: the probes each
+//! node sends open the mapping on its own NAT that the probes of the other
+//! come through, and the first candidate that answers becomes the
+//! [SecureChannel].
+//!
+//! Probes and datagrams are sealed with ChaCha20-Poly1305 under keys derived
+//! from the X25519 exchange, so the rendezvous server only has to be trusted
+//! to relay the offers unchanged.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::agreement::{EphemeralPrivateKey, UnparsedPublicKey, X25519, agree_ephemeral};
+use ring::hkdf::{HKDF_SHA256, Salt};
+use ring::rand::SystemRandom;
+use tokio::net::UdpSocket;
+
+use crate::CoreContext;
+
+const TOKEN_PREFIX: &str = "nstream-punch1:";
+
+/// Prefix of the datagrams of the rendezvous protocol
+const RENDEZVOUS_MAGIC: &[u8; 4] = b"NSR1";
+
+/// How often registrations and probes are sent until answered
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the rendezvous server keeps a room after it was last used
+const ROOM_TTL: Duration = Duration::from_secs(60);
+
+const KIND_PROBE: u8 = 1;
+const KIND_ACK: u8 = 2;
+const KIND_DATA: u8 = 3;
+
+/// Kind and counter, in the clear but authenticated
+const HEADER_LEN: usize = 9;
+
+/// What a node tells the other to reach it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+    pub public_key: [u8; 32],
+    pub candidates: Vec<SocketAddr>,
+}
+
+impl Offer {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.public_key.to_vec();
+        buf.push(self.candidates.len() as u8);
+        for candidate in &self.candidates {
+            match candidate.ip() {
+                IpAddr::V4(ip) => {
+                    buf.push(4);
+                    buf.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    buf.push(6);
+                    buf.extend_from_slice(&ip.octets());
+                }
+            }
+            buf.extend_from_slice(&candidate.port().to_be_bytes());
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Malformed offer");
+        let public_key = buf.get(..32).ok_or_else(invalid)?.try_into().unwrap();
+        let count = *buf.get(32).ok_or_else(invalid)?;
+        let mut rest = &buf[33..];
+        let mut candidates = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let ip_len = match rest.first() {
+                Some(4) => 4,
+                Some(6) => 16,
+                _ => return Err(invalid()),
+            };
+            let field = rest.get(1..1 + ip_len + 2).ok_or_else(invalid)?;
+            let ip = match ip_len {
+                4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&field[..4]).unwrap())),
+                _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&field[..16]).unwrap())),
+            };
+            let port = u16::from_be_bytes([field[ip_len], field[ip_len + 1]]);
+            candidates.push(SocketAddr::new(ip, port));
+            rest = &rest[1 + ip_len + 2..];
+        }
+        Ok(Self { public_key, candidates })
+    }
+
+    /// Add `addr` unless it is a candidate already
+    fn add_candidate(&mut self, addr: SocketAddr) {
+        if !self.candidates.contains(&addr) && self.candidates.len() < u8::MAX as usize {
+            self.candidates.push(addr);
+        }
+    }
+}
+
+/// The token to paste on the other node
+impl Display for Offer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", TOKEN_PREFIX, BASE64.encode(self.encode()))
+    }
+}
+
+impl FromStr for Offer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let encoded = s
+            .trim()
+            .strip_prefix(TOKEN_PREFIX)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Not a token: {}", s)))?;
+        let buf = BASE64.decode(encoded).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        Self::decode(&buf)
+    }
+}
+
+/// One UDP socket of this node and the offer to reach it
+#[derive(Debug)]
+pub struct Endpoint {
+    udp_sock: UdpSocket,
+    private_key: EphemeralPrivateKey,
+    offer: Offer,
+}
+
+impl Endpoint {
+    /// Bind a UDP socket to `bind` and gather its candidates, the reflexive
+    /// one from the STUN servers of `context` unless they do not answer
+    /// within `timeout`
+    pub async fn bind(bind: SocketAddr, context: &CoreContext, timeout: Duration) -> Result<Self> {
+        let udp_sock = UdpSocket::bind(bind).await?;
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| Error::other("Failed to generate a key"))?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| Error::other("Failed to compute the public key"))?
+            .as_ref()
+            .try_into()
+            .unwrap();
+        let mut offer = Offer { public_key, candidates: vec![] };
+
+        let local_addr = udp_sock.local_addr()?;
+        let local_ip = match local_addr.ip() {
+            ip if !ip.is_unspecified() => Some(ip),
+            IpAddr::V4(_) => crate::what_is_my_lanip_v4addr().await.ok().map(IpAddr::V4),
+            IpAddr::V6(_) => crate::what_is_my_lanip_v6addr().await.ok().map(IpAddr::V6),
+        };
+        if let Some(local_ip) = local_ip {
+            offer.add_candidate(SocketAddr::new(local_ip, local_addr.port()));
+        }
+        if let Ok(Ok(reflexive)) =
+            tokio::time::timeout(timeout, context.reflexive_address(&udp_sock)).await
+        {
+            offer.add_candidate(reflexive);
+        }
+        Ok(Self { udp_sock, private_key, offer })
+    }
+
+    /// What to hand the other node, e.g. as a token
+    pub fn offer(&self) -> &Offer {
+        &self.offer
+    }
+
+    /// Also offer `addr`, e.g. the external address of a port mapping
+    pub fn add_candidate(&mut self, addr: SocketAddr) {
+        self.offer.add_candidate(addr)
+    }
+
+    /// Swap offers with the node registered in `room` of the rendezvous
+    /// server `server`, waiting up to `timeout` for it to show up
+    pub async fn exchange(
+        &self,
+        server: SocketAddr,
+        room: &str,
+        timeout: Duration,
+    ) -> Result<Offer> {
+        if room.len() > u8::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "Room name too long"));
+        }
+        let mut register = RENDEZVOUS_MAGIC.to_vec();
+        register.push(room.len() as u8);
+        register.extend_from_slice(room.as_bytes());
+        register.extend_from_slice(&self.offer.encode());
+
+        let exchange = async {
+            let mut interval = tokio::time::interval(RETRANSMIT_INTERVAL);
+            let mut buf = vec![0u8; 2048];
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        self.udp_sock.send_to(&register, server).await?;
+                    }
+                    ret = self.udp_sock.recv_from(&mut buf) => {
+                        let (len, from_addr) = ret?;
+                        /* Early probes of the other node are sent again later */
+                        if from_addr != server || !buf[..len].starts_with(RENDEZVOUS_MAGIC) {
+                            continue;
+                        }
+                        return Offer::decode(&buf[RENDEZVOUS_MAGIC.len()..len]);
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(timeout, exchange).await.map_err(|_| {
+            Error::new(ErrorKind::TimedOut, format!("No other node in room {}", room))
+        })?
+    }
+
+    /// Probe the candidates of `remote` until one answers, giving up after
+    /// `timeout`
+    pub async fn punch(self, remote: &Offer, timeout: Duration) -> Result<SecureChannel> {
+        let keys = SessionKeys::agree(self.private_key, &self.offer.public_key, remote)?;
+        let udp_sock = self.udp_sock;
+        let punch = async {
+            let mut interval = tokio::time::interval(RETRANSMIT_INTERVAL);
+            let mut buf = vec![0u8; 2048];
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        for candidate in &remote.candidates {
+                            /* Candidates of the other address family are unreachable */
+                            let _ = udp_sock.send_to(&keys.seal(KIND_PROBE, &[]), candidate).await;
+                        }
+                    }
+                    ret = udp_sock.recv_from(&mut buf) => {
+                        let (len, from_addr) = ret?;
+                        match keys.open(&buf[..len]) {
+                            Some((KIND_PROBE, _)) => {
+                                udp_sock.send_to(&keys.seal(KIND_ACK, &[]), from_addr).await?;
+                            }
+                            Some((KIND_ACK, _)) => {
+                                /* In case our probes were dropped before the
+                                 * other node opened its NAT */
+                                udp_sock.send_to(&keys.seal(KIND_ACK, &[]), from_addr).await?;
+                                return Ok::<_, Error>(from_addr);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        };
+        let peer = tokio::time::timeout(timeout, punch)
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "No candidate answered"))??;
+        udp_sock.connect(peer).await?;
+        Ok(SecureChannel { udp_sock, peer, keys })
+    }
+}
+
+/// Counters seen lately, so that a datagram replayed is dropped
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: u64,
+    /// Bit `n` set when `highest - n` was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, counter: u64) -> bool {
+        if counter == 0 {
+            return false;
+        }
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+            return true;
+        }
+        let offset = self.highest - counter;
+        if offset >= 64 || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+#[derive(Debug)]
+struct SessionKeys {
+    seal: LessSafeKey,
+    open: LessSafeKey,
+    /// Of the last datagram sealed
+    counter: AtomicU64,
+    replay: Mutex<ReplayWindow>,
+}
+
+impl SessionKeys {
+    /// One key per direction, each bound to the public keys of the sender
+    /// and of the receiver
+    fn agree(
+        private_key: EphemeralPrivateKey,
+        public_key: &[u8; 32],
+        remote: &Offer,
+    ) -> Result<Self> {
+        let remote_key = UnparsedPublicKey::new(&X25519, remote.public_key);
+        let prk = agree_ephemeral(private_key, &remote_key, |secret| {
+            Salt::new(HKDF_SHA256, b"nstream punch").extract(secret)
+        })
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid public key"))?;
+        let key = |sender: &[u8], receiver: &[u8]| {
+            let info = [sender, receiver];
+            let okm = prk.expand(&info, &CHACHA20_POLY1305).map_err(|_| Error::other("HKDF"))?;
+            Ok::<_, Error>(LessSafeKey::new(UnboundKey::from(okm)))
+        };
+        Ok(Self {
+            seal: key(public_key, &remote.public_key)?,
+            open: key(&remote.public_key, public_key)?,
+            counter: AtomicU64::new(0),
+            replay: Mutex::default(),
+        })
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn seal(&self, kind: u8, payload: &[u8]) -> Vec<u8> {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut packet = vec![kind];
+        packet.extend_from_slice(&counter.to_be_bytes());
+        let mut sealed = payload.to_vec();
+        self.seal
+            .seal_in_place_append_tag(Self::nonce(counter), Aad::from(&packet[..]), &mut sealed)
+            .expect("Payload too long to seal");
+        packet.extend_from_slice(&sealed);
+        packet
+    }
+
+    /// The kind and payload of `packet`, None when it is forged or replayed
+    fn open(&self, packet: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let (header, sealed) = packet.split_at_checked(HEADER_LEN)?;
+        let counter = u64::from_be_bytes(header[1..].try_into().unwrap());
+        let mut payload = sealed.to_vec();
+        let len = self
+            .open
+            .open_in_place(Self::nonce(counter), Aad::from(header), &mut payload)
+            .ok()?
+            .len();
+        if !self.replay.lock().unwrap().accept(counter) {
+            return None;
+        }
+        payload.truncate(len);
+        Some((header[0], payload))
+    }
+}
+
+/// Sealed datagrams to and from the other node
+#[derive(Debug)]
+pub struct SecureChannel {
+    udp_sock: UdpSocket,
+    peer: SocketAddr,
+    keys: SessionKeys,
+}
+
+impl SecureChannel {
+    /// The candidate of the other node that answered
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub async fn send(&self, payload: &[u8]) -> Result<()> {
+        self.udp_sock.send(&self.keys.seal(KIND_DATA, payload)).await.map(drop)
+    }
+
+    /// The next datagram of the other node, those that are forged or
+    /// replayed are dropped
+    pub async fn recv(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let len = self.udp_sock.recv(&mut buf).await?;
+            match self.keys.open(&buf[..len]) {
+                Some((KIND_DATA, payload)) => return Ok(payload),
+                Some((KIND_PROBE, _)) => {
+                    /* The other node has not seen an answer yet */
+                    self.udp_sock.send(&self.keys.seal(KIND_ACK, &[])).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Room {
+    members: Vec<(SocketAddr, Offer)>,
+    touched: Instant,
+}
+
+/// Pair the nodes registering in the same room on `udp_sock`, handing
+/// each the offer of the other along with the address it was seen at
+pub async fn serve_rendezvous(udp_sock: UdpSocket) -> Result<()> {
+    let mut rooms: HashMap<String, Room> = HashMap::new();
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (len, from_addr) = udp_sock.recv_from(&mut buf).await?;
+        let Some((room, offer)) = parse_register(&buf[..len]) else {
+            continue;
+        };
+        let mut offer = offer;
+        offer.add_candidate(from_addr);
+
+        let now = Instant::now();
+        rooms.retain(|_, room| now.duration_since(room.touched) < ROOM_TTL);
+        let room = rooms.entry(room).or_insert_with(|| Room { members: vec![], touched: now });
+        room.touched = now;
+        match room.members.iter().position(|(addr, _)| *addr == from_addr) {
+            Some(n) => room.members[n].1 = offer,
+            None if room.members.len() < 2 => room.members.push((from_addr, offer)),
+            /* The room is taken */
+            None => continue,
+        }
+        if room.members.len() < 2 {
+            continue;
+        }
+        for (n, (addr, _)) in room.members.iter().enumerate() {
+            let mut reply = RENDEZVOUS_MAGIC.to_vec();
+            reply.extend_from_slice(&room.members[1 - n].1.encode());
+            udp_sock.send_to(&reply, addr).await?;
+        }
+    }
+}
+
+fn parse_register(datagram: &[u8]) -> Option<(String, Offer)> {
+    let rest = datagram.strip_prefix(RENDEZVOUS_MAGIC)?;
+    let (&room_len, rest) = rest.split_first()?;
+    let room = std::str::from_utf8(rest.get(..room_len as usize)?).ok()?;
+    let offer = Offer::decode(&rest[room_len as usize..]).ok()?;
+    Some((room.to_owned(), offer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Endpoint, Offer, ReplayWindow, SessionKeys, serve_rendezvous};
+
+    use std::time::Duration;
+
+    use crate::CoreContext;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// With STUN servers that never answer
+    async fn local_endpoint(silent_addr: std::net::SocketAddr) -> std::io::Result<Endpoint> {
+        let context = CoreContext::new().with_stun_servers(silent_addr, silent_addr);
+        Endpoint::bind("127.0.0.1:0".parse().unwrap(), &context, Duration::from_millis(100)).await
+    }
+
+    #[test]
+    fn test_offer_token() {
+        let offer = Offer {
+            public_key: [7; 32],
+            candidates: vec![
+                "192.0.2.1:4000".parse().unwrap(),
+                "[2001:db8::1]:4001".parse().unwrap(),
+            ],
+        };
+        let token = offer.to_string();
+        assert!(token.starts_with("nstream-punch1:"));
+        assert_eq!(token.parse::<Offer>().unwrap(), offer);
+        assert!("nstream-punch1:AAAA".parse::<Offer>().is_err());
+        assert!("192.0.2.1:4000".parse::<Offer>().is_err());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(!window.accept(0));
+        assert!(window.accept(1));
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        /* Out of order within the window */
+        assert!(window.accept(2));
+        assert!(window.accept(100));
+        assert!(!window.accept(30));
+    }
+
+    #[test]
+    fn test_punch_through_rendezvous() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let server_sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let server_addr = server_sock.local_addr()?;
+            tokio::spawn(serve_rendezvous(server_sock));
+
+            let (alice, bob) = tokio::try_join!(
+                local_endpoint(silent.local_addr()?),
+                local_endpoint(silent.local_addr()?)
+            )?;
+            let (bob_offer, alice_offer) = tokio::try_join!(
+                alice.exchange(server_addr, "room", TIMEOUT),
+                bob.exchange(server_addr, "room", TIMEOUT),
+            )?;
+            assert_eq!(&alice_offer.public_key, &alice.offer().public_key);
+            assert_eq!(&bob_offer.public_key, &bob.offer().public_key);
+
+            let (alice, bob) = tokio::try_join!(
+                alice.punch(&bob_offer, TIMEOUT),
+                bob.punch(&alice_offer, TIMEOUT)
+            )?;
+            alice.send(b"ping").await?;
+            assert_eq!(bob.recv().await?, b"ping");
+            bob.send(b"pong").await?;
+            assert_eq!(alice.recv().await?, b"pong");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_sealed_datagrams() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let alice = local_endpoint(silent.local_addr()?).await?;
+            let bob = local_endpoint(silent.local_addr()?).await?;
+            let (alice_offer, bob_offer) = (alice.offer().clone(), bob.offer().clone());
+            let alice_keys =
+                SessionKeys::agree(alice.private_key, &alice_offer.public_key, &bob_offer)?;
+            let bob_keys =
+                SessionKeys::agree(bob.private_key, &bob_offer.public_key, &alice_offer)?;
+
+            let mut packet = alice_keys.seal(super::KIND_DATA, b"hello");
+            assert_eq!(bob_keys.open(&packet), Some((super::KIND_DATA, b"hello".to_vec())));
+            /* Replayed */
+            assert_eq!(bob_keys.open(&packet), None);
+            /* Sealed for the other direction */
+            assert_eq!(alice_keys.open(&alice_keys.seal(super::KIND_DATA, b"hello")), None);
+            /* Tampered with */
+            packet = alice_keys.seal(super::KIND_DATA, b"hello");
+            *packet.last_mut().unwrap() ^= 1;
+            assert_eq!(bob_keys.open(&packet), None);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,322 @@
+//! Name resolution with the A and AAAA queries in flight at once, as
+//! described in section 3 of RFC 8305
+//!
+//! Each query is a `getaddrinfo` call restricted to one address family, so
+//! that a family whose lookups are slow or failing shows in [ResolveStats]
+//! rather than holding every connection up.
+
+use crate::{DialConfig, happy_eyeballs_connect};
+
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::task::JoinError;
+
+/// Recommended value of the "Resolution Delay", how long the answer of the
+/// other family is waited for once one has come
+pub const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
+
+/// Which address family is tried first when a name has both
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum FamilyPreference {
+    #[default]
+    PreferV6,
+    PreferV4,
+    /// The family whose query is answered first
+    Fastest,
+}
+
+impl FromStr for FamilyPreference {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "prefer_v6" => Ok(Self::PreferV6),
+            "prefer_v4" => Ok(Self::PreferV4),
+            "fastest" => Ok(Self::Fastest),
+            s => Err(format!("{}, expected \"prefer_v6\", \"prefer_v4\" or \"fastest\"", s)),
+        }
+    }
+}
+
+impl Display for FamilyPreference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PreferV6 => f.write_str("prefer_v6"),
+            Self::PreferV4 => f.write_str("prefer_v4"),
+            Self::Fastest => f.write_str("fastest"),
+        }
+    }
+}
+
+impl TryFrom<String> for FamilyPreference {
+    type Error = String;
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<FamilyPreference> for String {
+    fn from(value: FamilyPreference) -> Self {
+        value.to_string()
+    }
+}
+
+/// Counters of the queries for one address family
+#[derive(Debug, Default)]
+pub struct FamilyStats {
+    queries: AtomicU64,
+    failures: AtomicU64,
+    /// Time the queries took altogether, in microseconds
+    latency_us: AtomicU64,
+}
+
+impl FamilyStats {
+    fn record(&self, ret: &Result<Vec<SocketAddr>>, elapsed: Duration) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if ret.as_ref().map_or(true, Vec::is_empty) {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    /// Queries that failed or found no address
+    #[inline]
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn total_latency(&self) -> Duration {
+        Duration::from_micros(self.latency_us.load(Ordering::Relaxed))
+    }
+
+    pub fn mean_latency(&self) -> Duration {
+        match self.queries() {
+            0 => Duration::ZERO,
+            queries => self.total_latency() / queries as u32,
+        }
+    }
+}
+
+/// Per-family counters of [resolve], clones share the same counters
+#[derive(Debug, Clone, Default)]
+pub struct ResolveStats {
+    families: Arc<[FamilyStats; 2]>,
+}
+
+impl ResolveStats {
+    #[inline]
+    pub fn v4(&self) -> &FamilyStats {
+        &self.families[0]
+    }
+
+    #[inline]
+    pub fn v6(&self) -> &FamilyStats {
+        &self.families[1]
+    }
+}
+
+/// `getaddrinfo` restricted to IPv6 or IPv4 addresses
+fn lookup_family(host: &str, port: u16, ipv6: bool) -> Result<Vec<SocketAddr>> {
+    let c_host = CString::new(host)?;
+    let mut hints = unsafe { std::mem::zeroed::<libc::addrinfo>() };
+    hints.ai_family = if ipv6 { libc::AF_INET6 } else { libc::AF_INET };
+    hints.ai_socktype = libc::SOCK_STREAM;
+    let mut res: *mut libc::addrinfo = std::ptr::null_mut();
+    let ret = unsafe { libc::getaddrinfo(c_host.as_ptr(), std::ptr::null(), &hints, &mut res) };
+    if ret != 0 {
+        let msg = unsafe { CStr::from_ptr(libc::gai_strerror(ret)) };
+        let kind = if ret == libc::EAI_NONAME { ErrorKind::NotFound } else { ErrorKind::Other };
+        return Err(Error::new(kind, format!("{}: {}", host, msg.to_string_lossy())));
+    }
+    let mut addrs = vec![];
+    let mut cursor = res;
+    while !cursor.is_null() {
+        let ai = unsafe { &*cursor };
+        if !ai.ai_addr.is_null() {
+            match ai.ai_family {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(ai.ai_addr as *const libc::sockaddr_in) };
+                    let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                    addrs.push(SocketAddr::new(IpAddr::V4(ip), port));
+                }
+                libc::AF_INET6 => {
+                    let sin6 = unsafe { &*(ai.ai_addr as *const libc::sockaddr_in6) };
+                    let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                    addrs.push(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, sin6.sin6_scope_id)));
+                }
+                _ => {}
+            }
+        }
+        cursor = ai.ai_next;
+    }
+    unsafe { libc::freeaddrinfo(res) };
+    Ok(addrs)
+}
+
+#[inline]
+fn joined(ret: std::result::Result<Result<Vec<SocketAddr>>, JoinError>) -> Result<Vec<SocketAddr>> {
+    ret.unwrap_or_else(|e| Err(Error::other(e)))
+}
+
+/// Resolve `host` with the A and AAAA queries in flight at once, returns the
+/// addresses of the family `preference` picks followed by the others
+pub async fn resolve(
+    host: &str,
+    port: u16,
+    preference: FamilyPreference,
+    stats: &ResolveStats,
+) -> Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let query = |ipv6: bool| {
+        let (host, stats) = (host.to_owned(), stats.clone());
+        tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            let ret = lookup_family(&host, port, ipv6);
+            let family_stats = if ipv6 { stats.v6() } else { stats.v4() };
+            family_stats.record(&ret, started.elapsed());
+            ret
+        })
+    };
+    let (mut v4_query, mut v6_query) = (query(false), query(true));
+
+    let (first_is_v6, first) = tokio::select! {
+        ret = &mut v4_query => (false, joined(ret)),
+        ret = &mut v6_query => (true, joined(ret)),
+    };
+    let first_found = first.as_ref().is_ok_and(|addrs| !addrs.is_empty());
+    let second_query = if first_is_v6 { v4_query } else { v6_query };
+    /* The other family is only waited for in full when the first has nothing */
+    let second = match first_found {
+        true => match tokio::time::timeout(RESOLUTION_DELAY, second_query).await {
+            Ok(ret) => joined(ret),
+            Err(_) => Ok(vec![]),
+        },
+        false => joined(second_query.await),
+    };
+
+    let v6_first = match preference {
+        FamilyPreference::PreferV6 => true,
+        FamilyPreference::PreferV4 => false,
+        FamilyPreference::Fastest => first_is_v6 == first_found,
+    };
+    let (preferred, other) = match first_is_v6 == v6_first {
+        true => (first, second),
+        false => (second, first),
+    };
+    let mut addrs = vec![];
+    let mut first_err = None;
+    for ret in [preferred, other] {
+        match ret {
+            Ok(found) => addrs.extend(found),
+            Err(e) => first_err = first_err.or(Some(e)),
+        }
+    }
+    if addrs.is_empty() {
+        return Err(first_err.unwrap_or_else(|| {
+            Error::new(ErrorKind::NotFound, format!("No address for {}", host))
+        }));
+    }
+    Ok(addrs)
+}
+
+/// Resolve `host` and connect to it, the addresses of the family
+/// [DialConfig::family_preference] picks being tried first
+pub async fn connect_host(
+    host: &str,
+    port: u16,
+    config: &DialConfig,
+    stats: &ResolveStats,
+) -> Result<TcpStream> {
+    let addrs = resolve(host, port, config.family_preference, stats).await?;
+    let config = DialConfig { prefer_ipv6: addrs[0].is_ipv6(), ..*config };
+    happy_eyeballs_connect(&addrs, &config).await
+}
+
+/// `getnameinfo` requiring a name, errors when `ip` has no PTR record
+fn lookup_name(ip: IpAddr) -> Result<String> {
+    let socket_addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    let ret = unsafe {
+        libc::getnameinfo(
+            socket_addr.as_ptr() as *const libc::sockaddr,
+            socket_addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if ret != 0 {
+        let msg = unsafe { CStr::from_ptr(libc::gai_strerror(ret)) };
+        let kind = if ret == libc::EAI_NONAME { ErrorKind::NotFound } else { ErrorKind::Other };
+        return Err(Error::new(kind, format!("{}: {}", ip, msg.to_string_lossy())));
+    }
+    Ok(unsafe { CStr::from_ptr(host.as_ptr()) }.to_string_lossy().into_owned())
+}
+
+/// The name the PTR record of `ip` gives, given up on after `timeout`.
+/// The query itself cannot be cancelled and goes on in the background.
+pub async fn reverse_lookup(ip: IpAddr, timeout: Duration) -> Result<String> {
+    let query = tokio::task::spawn_blocking(move || lookup_name(ip));
+    match tokio::time::timeout(timeout, query).await {
+        Ok(ret) => ret.unwrap_or_else(|e| Err(Error::other(e))),
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, format!("{}: Reverse lookup timed out", ip))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FamilyPreference, ResolveStats, resolve};
+
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_family_preference_from_str() {
+        assert_eq!("fastest".parse(), Ok(FamilyPreference::Fastest));
+        assert_eq!("prefer_v4".parse(), Ok(FamilyPreference::PreferV4));
+        assert_eq!(FamilyPreference::default().to_string(), "prefer_v6");
+        assert!("v4".parse::<FamilyPreference>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let stats = ResolveStats::default();
+            /* Literals are not looked up */
+            let addrs = resolve("::1", 80, FamilyPreference::PreferV4, &stats).await?;
+            assert_eq!(addrs, ["[::1]:80".parse::<SocketAddr>().unwrap()]);
+            assert_eq!(stats.v4().queries() + stats.v6().queries(), 0);
+
+            let addrs = resolve("localhost", 80, FamilyPreference::PreferV4, &stats).await?;
+            assert_eq!(addrs[0], "127.0.0.1:80".parse::<SocketAddr>().unwrap());
+            assert_eq!(stats.v4().queries(), 1);
+            assert_eq!(stats.v4().failures(), 0);
+
+            assert!(
+                resolve("nonexistent.invalid", 80, FamilyPreference::Fastest, &stats)
+                    .await
+                    .is_err()
+            );
+            Ok(())
+        })
+    }
+}
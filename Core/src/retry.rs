@@ -0,0 +1,306 @@
+//! Retries with a growing, jittered backoff, for whatever may succeed when
+//! tried again: connections to destinations, STUN queries, links to nodes
+//!
+//! A [RetryPolicy] tells how many attempts are made, how long each may take,
+//! how long is waited between them and how long they may take in all. Which
+//! errors are worth another attempt is up to the caller,
+//! [RetryPolicy::is_retryable] telling so for connections. The attempts are
+//! given up on as soon as a future passed along completes, e.g. on
+//! shutdown.
+
+use std::future::{Future, pending};
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Retries of a failed operation, waiting longer before each of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in all, the first one included, 1 not to retry
+    pub max_attempts: u32,
+    /// Before the first retry, doubled for each next one
+    pub initial_backoff: Duration,
+    /// The cap on the doubled backoff
+    pub max_backoff: Duration,
+    /// How long each attempt may take before failing with
+    /// [ErrorKind::TimedOut], unlimited by default
+    pub attempt_timeout: Option<Duration>,
+    /// How long the attempts and the waits between them may take in all.
+    /// No retry starts past it, and the attempt running then fails with
+    /// [ErrorKind::TimedOut]. Unlimited by default.
+    pub budget: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            attempt_timeout: None,
+            budget: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Make `max_attempts` attempts in all
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Wait `initial` before the first retry, doubled for each next one up
+    /// to `max`
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Give each attempt up after `timeout`
+    pub fn with_attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.attempt_timeout = Some(timeout);
+        self
+    }
+
+    /// Give all attempts up after `budget`
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// How long to wait before retry `retry`, counted from 1: half of the
+    /// doubled backoff, plus up to as much at random so that clients failing
+    /// together do not retry together
+    pub fn backoff(&self, retry: u32) -> Duration {
+        use std::hash::{BuildHasher, Hasher};
+
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        let backoff = self.initial_backoff.saturating_mul(factor).min(self.max_backoff);
+        /* Keyed anew by each RandomState, random enough for a jitter */
+        let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+        backoff / 2 + backoff.mul_f64((random >> 11) as f64 / (1u64 << 53) as f64) / 2
+    }
+
+    /// Whether another attempt may succeed where this one failed with `e`.
+    /// Refusals, by the destination or by the rules, and errors of the
+    /// address or configuration are final.
+    pub fn is_retryable(e: &Error) -> bool {
+        matches!(
+            e.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::ConnectionReset
+                | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable
+                | ErrorKind::NetworkDown
+                | ErrorKind::AddrInUse
+                | ErrorKind::Interrupted
+        )
+    }
+
+    /// `connect` until it succeeds, fails with an error that is not
+    /// retryable or has been tried `max_attempts` times. The error returned
+    /// is the last one telling why, see [keep_meaningful].
+    pub async fn run<T, F, Fut>(&self, connect: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.run_until(pending(), Self::is_retryable, connect).await
+    }
+
+    /// `op` as [RetryPolicy::run] does `connect`, the errors worth another
+    /// attempt being those `is_retryable` tells
+    pub async fn run_if<T, F, Fut>(&self, is_retryable: impl Fn(&Error) -> bool, op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.run_until(pending(), is_retryable, op).await
+    }
+
+    /// `op` as [RetryPolicy::run_if] does, until `cancel` completes, which
+    /// drops the attempt running and fails with [ErrorKind::Interrupted]
+    pub async fn run_until<T, F, Fut>(
+        &self,
+        cancel: impl Future<Output = ()>,
+        is_retryable: impl Fn(&Error) -> bool,
+        mut op: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        tokio::pin!(cancel);
+        let deadline = self.budget.map(|budget| Instant::now() + budget);
+        let mut last_err = None;
+        for attempt in 1..=self.max_attempts.max(1) {
+            if attempt > 1 {
+                let backoff = self.backoff(attempt - 1);
+                if deadline.is_some_and(|deadline| Instant::now() + backoff >= deadline) {
+                    break;
+                }
+                tokio::select! {
+                    _ = &mut cancel => return Err(cancelled()),
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+            }
+            let attempt_deadline = self.attempt_timeout.map(|timeout| Instant::now() + timeout);
+            let attempt_deadline = attempt_deadline.into_iter().chain(deadline).min();
+            let ret = tokio::select! {
+                _ = &mut cancel => return Err(cancelled()),
+                ret = until(attempt_deadline, op()) => ret,
+            };
+            match ret {
+                Ok(ret) => return Ok(ret),
+                Err(e) => {
+                    let retryable = is_retryable(&e);
+                    keep_meaningful(&mut last_err, e);
+                    if !retryable {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+}
+
+fn cancelled() -> Error {
+    Error::new(ErrorKind::Interrupted, "Retries cancelled")
+}
+
+async fn until<T>(deadline: Option<Instant>, op: impl Future<Output = Result<T>>) -> Result<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, op)
+            .await
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "Attempt timed out"))),
+        None => op.await,
+    }
+}
+
+/// Replace `last_err` by `e`, unless `e` tells less than it: an error
+/// without a specific kind, e.g. of a task that panicked, does not hide
+/// one that would have told the client why the connection failed
+pub fn keep_meaningful(last_err: &mut Option<Error>, e: Error) {
+    let vague = |e: &Error| {
+        matches!(e.kind(), ErrorKind::Other | ErrorKind::Interrupted | ErrorKind::WouldBlock)
+    };
+    if last_err.as_ref().is_none_or(|last_err| vague(last_err) || !vague(&e)) {
+        *last_err = Some(e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryPolicy, keep_meaningful};
+
+    use std::io::{Error, ErrorKind};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_retry_policy() -> std::io::Result<()> {
+        let policy = RetryPolicy::default()
+            .with_max_attempts(3)
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(25));
+        for _ in 0..32 {
+            let backoff = policy.backoff(1);
+            assert!(backoff >= Duration::from_millis(5) && backoff <= Duration::from_millis(10));
+            let backoff = policy.backoff(3);
+            assert!(backoff >= Duration::from_micros(12500) && backoff <= policy.max_backoff);
+        }
+        assert!(policy.backoff(u32::MAX) <= policy.max_backoff);
+
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let attempts = AtomicU32::new(0);
+            let fail_with = |kinds: &'static [ErrorKind]| {
+                let attempts = &attempts;
+                attempts.store(0, Ordering::Relaxed);
+                move || async move {
+                    let attempt = attempts.fetch_add(1, Ordering::Relaxed) as usize;
+                    match kinds.get(attempt) {
+                        Some(kind) => Err::<(), _>(Error::from(*kind)),
+                        None => Ok(()),
+                    }
+                }
+            };
+            /* Retried until it succeeds */
+            let kinds = &[ErrorKind::TimedOut, ErrorKind::ConnectionReset];
+            policy.run(fail_with(kinds)).await?;
+            assert_eq!(attempts.load(Ordering::Relaxed), 3);
+            /* Given up on after max_attempts */
+            let kinds = &[ErrorKind::TimedOut; 4];
+            let e = policy.run(fail_with(kinds)).await.unwrap_err();
+            assert_eq!((e.kind(), attempts.load(Ordering::Relaxed)), (ErrorKind::TimedOut, 3));
+            /* A refusal is final */
+            let kinds = &[ErrorKind::ConnectionRefused, ErrorKind::TimedOut];
+            let e = policy.run(fail_with(kinds)).await.unwrap_err();
+            assert_eq!(
+                (e.kind(), attempts.load(Ordering::Relaxed)),
+                (ErrorKind::ConnectionRefused, 1)
+            );
+            /* Unless the caller says otherwise */
+            policy.run_if(|_| true, fail_with(kinds)).await?;
+            assert_eq!(attempts.load(Ordering::Relaxed), 3);
+            /* The last error telling why is the one returned */
+            let kinds =
+                &[ErrorKind::HostUnreachable, ErrorKind::Interrupted, ErrorKind::Interrupted];
+            let e = policy.run(fail_with(kinds)).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::HostUnreachable);
+            /* No retry by default */
+            let e = RetryPolicy::default().run(fail_with(&[ErrorKind::TimedOut; 2])).await;
+            assert_eq!(e.map_err(|e| e.kind()), Err(ErrorKind::TimedOut));
+            assert_eq!(attempts.load(Ordering::Relaxed), 1);
+            Ok::<_, Error>(())
+        })?;
+
+        let mut last_err = None;
+        keep_meaningful(&mut last_err, Error::other("panicked"));
+        keep_meaningful(&mut last_err, Error::from(ErrorKind::ConnectionRefused));
+        keep_meaningful(&mut last_err, Error::other("panicked"));
+        assert_eq!(last_err.map(|e| e.kind()), Some(ErrorKind::ConnectionRefused));
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_deadlines() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let attempts = AtomicU32::new(0);
+            let hang = || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                std::future::pending::<std::io::Result<()>>().await
+            };
+            let policy = RetryPolicy::default()
+                .with_max_attempts(3)
+                .with_backoff(Duration::from_millis(10), Duration::from_millis(10));
+
+            /* Each attempt cut short */
+            let e = policy.with_attempt_timeout(Duration::from_millis(20)).run(hang).await;
+            assert_eq!(e.map_err(|e| e.kind()), Err(ErrorKind::TimedOut));
+            assert_eq!(attempts.swap(0, Ordering::Relaxed), 3);
+
+            /* No retry past the budget */
+            let started = Instant::now();
+            let policy = policy.with_attempt_timeout(Duration::from_millis(50));
+            let e = policy.with_budget(Duration::from_millis(80)).run(hang).await;
+            assert_eq!(e.map_err(|e| e.kind()), Err(ErrorKind::TimedOut));
+            assert_eq!(attempts.swap(0, Ordering::Relaxed), 2);
+            assert!(started.elapsed() < Duration::from_millis(150));
+
+            /* Cancelled, attempt running or not */
+            let cancel = tokio::time::sleep(Duration::from_millis(70));
+            let e = policy.run_until(cancel, |_| true, hang).await;
+            assert_eq!(e.map_err(|e| e.kind()), Err(ErrorKind::Interrupted));
+            assert_eq!(attempts.swap(0, Ordering::Relaxed), 2);
+            Ok(())
+        })
+    }
+}
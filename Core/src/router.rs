@@ -0,0 +1,813 @@
+use crate::{Process, TrafficClass, check_iso_code, is_country_code, iso_code_of};
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+/// What to do with a connection matched by a [Rule]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RuleAction {
+    /// Connect to the destination without going through the proxy
+    Direct,
+    /// Relay the connection through the proxy
+    Proxy,
+    /// Refuse the connection
+    Reject,
+}
+
+impl FromStr for RuleAction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "DIRECT" => Ok(Self::Direct),
+            "PROXY" => Ok(Self::Proxy),
+            "REJECT" => Ok(Self::Reject),
+            _ => Err(format!("Unknown rule action: {}", s)),
+        }
+    }
+}
+
+impl Display for RuleAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Direct => f.write_str("DIRECT"),
+            Self::Proxy => f.write_str("PROXY"),
+            Self::Reject => f.write_str("REJECT"),
+        }
+    }
+}
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, String> {
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_prefix_len {
+            return Err(format!("Invalid prefix length: {}", prefix_len));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    #[inline]
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    #[inline]
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether every address of `other` belongs to the network
+    #[inline]
+    pub fn covers(&self, other: &IpCidr) -> bool {
+        self.prefix_len <= other.prefix_len && self.contains(&other.addr)
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|e| format!("{}: {}", s, e))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|e| format!("{}: {}", s, e))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl TryFrom<String> for IpCidr {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for IpCidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl From<IpCidr> for String {
+    fn from(value: IpCidr) -> Self {
+        value.to_string()
+    }
+}
+
+/// The condition part of a [Rule]
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleMatcher {
+    /// The destination domain is `name`
+    Domain(String),
+    /// The destination domain is `name` or one of its subdomains
+    DomainSuffix(String),
+    /// The destination domain contains `keyword`
+    DomainKeyword(String),
+    /// The destination IP address belongs to the network
+    IpCidr(IpCidr),
+    /// The destination IP address is located in the country with this ISO code
+    GeoIp(String),
+    /// The flow was classified as this protocol by its first bytes
+    Protocol(TrafficClass),
+    /// The file name of the executable of the process opening the flow
+    ProcessName(String),
+    /// The executable of the process opening the flow is this file, or
+    /// within this directory
+    ProcessPath(String),
+    /// The process opening the flow belongs to the application bundle of
+    /// this identifier, on macOS
+    BundleId(String),
+    /// Any destination
+    Match,
+}
+
+/// A routing rule, written as `TYPE,VALUE,ACTION` (or `MATCH,ACTION`):
+///
+/// ```plain
+/// DOMAIN,example.com,DIRECT
+/// DOMAIN-SUFFIX,google.com,PROXY
+/// DOMAIN-KEYWORD,github,PROXY
+/// IP-CIDR,10.0.0.0/8,DIRECT
+/// GEOIP,CN,DIRECT
+/// DOMAIN-SUFFIX,ads.example,REJECT
+/// PROTOCOL,BITTORRENT,REJECT
+/// PROCESS-NAME,curl,DIRECT
+/// PROCESS-PATH,/Applications/Slack.app,PROXY
+/// BUNDLE-ID,com.apple.Safari,REJECT
+/// MATCH,PROXY
+/// ```
+///
+/// `PROTOCOL` rules take TLS, HTTP, SSH, BITTORRENT or QUIC, and only match
+/// once the flow has been classified, see [Rule::matches_classified].
+/// `PROCESS-NAME`, `PROCESS-PATH` and `BUNDLE-ID` rules only match a flow
+/// whose process is known, see [Rule::matches_process].
+///
+/// A rule may end with `log-sample=N` for only 1 in N of the connections it
+/// matches to be logged, e.g. `DOMAIN-SUFFIX,cdn.example,DIRECT,log-sample=100`
+/// for a busy one. Denials and errors are logged all the same, see
+/// [LogSampler].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rule {
+    pub matcher: RuleMatcher,
+    pub action: RuleAction,
+    /// Log 1 in this many of the connections matched, all of them if None
+    pub log_sample: Option<NonZeroU32>,
+}
+
+impl Rule {
+    /// Whether the rule matches a destination, given by its domain name
+    /// and/or IP address
+    #[inline]
+    pub fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>) -> bool {
+        self.matches_classified(domain, ip, None)
+    }
+
+    /// Same as [Rule::matches], for a flow classified as `class` by its
+    /// first bytes
+    #[inline]
+    pub fn matches_classified(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        class: Option<TrafficClass>,
+    ) -> bool {
+        self.matches_process(domain, ip, class, None)
+    }
+
+    /// Same as [Rule::matches_classified], for a flow opened by `process`
+    pub fn matches_process(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        class: Option<TrafficClass>,
+        process: Option<&Process>,
+    ) -> bool {
+        /* IPv4 destinations reached over a dual-stack socket are v4-mapped */
+        let ip = ip.map(|ip| ip.to_canonical());
+        match &self.matcher {
+            RuleMatcher::Domain(name) => domain.is_some_and(|d| d.eq_ignore_ascii_case(name)),
+            RuleMatcher::DomainSuffix(suffix) => domain.is_some_and(|d| {
+                let d = d.to_ascii_lowercase();
+                let suffix = suffix.to_ascii_lowercase();
+                d == suffix || d.ends_with(&format!(".{}", suffix))
+            }),
+            RuleMatcher::DomainKeyword(keyword) => domain
+                .is_some_and(|d| d.to_ascii_lowercase().contains(&keyword.to_ascii_lowercase())),
+            RuleMatcher::IpCidr(cidr) => ip.is_some_and(|ip| cidr.contains(&ip)),
+            RuleMatcher::GeoIp(iso_code) => ip.is_some_and(|ip| check_iso_code(ip, iso_code)),
+            RuleMatcher::Protocol(protocol) => class == Some(*protocol),
+            RuleMatcher::ProcessName(name) => process
+                .and_then(Process::name)
+                .is_some_and(|process_name| process_name.eq_ignore_ascii_case(name)),
+            RuleMatcher::ProcessPath(path) => {
+                process.is_some_and(|process| process.path.starts_with(path))
+            }
+            RuleMatcher::BundleId(bundle_id) => process
+                .and_then(|process| process.bundle_id.as_deref())
+                .is_some_and(|process_bundle_id| process_bundle_id.eq_ignore_ascii_case(bundle_id)),
+            RuleMatcher::Match => true,
+        }
+    }
+
+    /// Whether the rule matches every flow `other` does, which then never
+    /// gets to match after it
+    pub fn covers(&self, other: &Rule) -> bool {
+        use RuleMatcher::{Domain, DomainKeyword, DomainSuffix};
+
+        match (&self.matcher, &other.matcher) {
+            (RuleMatcher::Match, _) => true,
+            (RuleMatcher::IpCidr(cidr), RuleMatcher::IpCidr(other_cidr)) => cidr.covers(other_cidr),
+            (Domain(_) | DomainSuffix(_) | DomainKeyword(_), Domain(name)) => {
+                self.matches(Some(name), None)
+            }
+            /* Subdomains of a domain contain what it does */
+            (DomainSuffix(_) | DomainKeyword(_), DomainSuffix(suffix)) => {
+                self.matches(Some(suffix), None)
+            }
+            (DomainKeyword(_), DomainKeyword(keyword)) => self.matches(Some(keyword), None),
+            (matcher, other_matcher) => matcher == other_matcher,
+        }
+    }
+
+    /// Whether the rule depends on the process opening the flow
+    pub fn is_process_rule(&self) -> bool {
+        matches!(
+            self.matcher,
+            RuleMatcher::ProcessName(_) | RuleMatcher::ProcessPath(_) | RuleMatcher::BundleId(_)
+        )
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',').map(str::trim).collect::<Vec<_>>();
+        let log_sample = match parts.last().copied().and_then(|last| last.split_once('=')) {
+            Some((key, n)) if key.eq_ignore_ascii_case("log-sample") => {
+                parts.pop();
+                Some(n.parse().map_err(|e| format!("Invalid log-sample {}: {}", n, e))?)
+            }
+            _ => None,
+        };
+        let (matcher, action) = match parts.as_slice() {
+            [kind, action] if kind.eq_ignore_ascii_case("MATCH") => (RuleMatcher::Match, action),
+            [kind, value, action] => {
+                let matcher = match kind.to_ascii_uppercase().as_str() {
+                    "DOMAIN" => RuleMatcher::Domain(value.to_string()),
+                    "DOMAIN-SUFFIX" => RuleMatcher::DomainSuffix(value.to_string()),
+                    "DOMAIN-KEYWORD" => RuleMatcher::DomainKeyword(value.to_string()),
+                    "IP-CIDR" | "IP-CIDR6" => RuleMatcher::IpCidr(value.parse()?),
+                    "GEOIP" => RuleMatcher::GeoIp(value.to_ascii_uppercase()),
+                    "PROTOCOL" => RuleMatcher::Protocol(value.parse()?),
+                    "PROCESS-NAME" => RuleMatcher::ProcessName(value.to_string()),
+                    "PROCESS-PATH" => RuleMatcher::ProcessPath(value.to_string()),
+                    "BUNDLE-ID" => RuleMatcher::BundleId(value.to_string()),
+                    _ => return Err(format!("Unknown rule type: {}", kind)),
+                };
+                (matcher, action)
+            }
+            _ => return Err(format!("Malformed rule: {}", s)),
+        };
+        Ok(Self { matcher, action: action.parse()?, log_sample })
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.matcher {
+            RuleMatcher::Domain(name) => write!(f, "DOMAIN,{},{}", name, self.action),
+            RuleMatcher::DomainSuffix(suffix) => {
+                write!(f, "DOMAIN-SUFFIX,{},{}", suffix, self.action)
+            }
+            RuleMatcher::DomainKeyword(keyword) => {
+                write!(f, "DOMAIN-KEYWORD,{},{}", keyword, self.action)
+            }
+            RuleMatcher::IpCidr(cidr) => write!(f, "IP-CIDR,{},{}", cidr, self.action),
+            RuleMatcher::GeoIp(iso_code) => write!(f, "GEOIP,{},{}", iso_code, self.action),
+            RuleMatcher::Protocol(protocol) => {
+                write!(f, "PROTOCOL,{},{}", protocol, self.action)
+            }
+            RuleMatcher::ProcessName(name) => write!(f, "PROCESS-NAME,{},{}", name, self.action),
+            RuleMatcher::ProcessPath(path) => write!(f, "PROCESS-PATH,{},{}", path, self.action),
+            RuleMatcher::BundleId(bundle_id) => {
+                write!(f, "BUNDLE-ID,{},{}", bundle_id, self.action)
+            }
+            RuleMatcher::Match => write!(f, "MATCH,{}", self.action),
+        }?;
+        match self.log_sample {
+            Some(n) => write!(f, ",log-sample={}", n),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<Rule> for String {
+    fn from(value: Rule) -> Self {
+        value.to_string()
+    }
+}
+
+/// The rules of `rules` that never match, each along with the earlier one
+/// covering it, by their indices
+pub fn shadowed_rules(rules: &[Rule]) -> Vec<(usize, usize)> {
+    rules
+        .iter()
+        .enumerate()
+        .filter_map(|(i, rule)| Some((i, rules[..i].iter().position(|prev| prev.covers(rule))?)))
+        .collect()
+}
+
+/// Picks 1 in `log-sample` of the connections each rule matches for
+/// logging, see [Rule::log_sample]. The first connection of a rule is
+/// always picked.
+#[derive(Debug, Default)]
+pub struct LogSampler {
+    /// Connections seen by rule, those with `log-sample` only
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl LogSampler {
+    /// Whether to log the connection `rule` matched, any without `rule`
+    pub fn sample(&self, rule: Option<&Rule>) -> bool {
+        let Some((rule, n)) = rule.and_then(|rule| Some((rule, rule.log_sample?))) else {
+            return true;
+        };
+        let mut seen = self.seen.lock().unwrap();
+        let seen = seen.entry(rule.to_string()).or_default();
+        *seen += 1;
+        (*seen - 1).is_multiple_of(n.get() as u64)
+    }
+}
+
+/// Which countries a [CountryPolicy] acts on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CountryMode {
+    /// Those it lists
+    #[default]
+    Block,
+    /// Those it does not list
+    Allow,
+}
+
+/// Acts on destinations by the country their addresses are located in,
+/// names being resolved first, as `GEOIP` rules only do for destinations
+/// given by address. Addresses the GeoIP database does not locate, private
+/// ones among them, are let through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CountryPolicy {
+    mode: CountryMode,
+    countries: Vec<String>,
+    action: RuleAction,
+}
+
+impl CountryPolicy {
+    /// Fails for a code of no country, or with [RuleAction::Direct], which
+    /// would act on nothing
+    pub fn new(
+        mode: CountryMode,
+        countries: Vec<String>,
+        action: RuleAction,
+    ) -> Result<Self, String> {
+        if action == RuleAction::Direct {
+            return Err(String::from("A country policy either rejects or proxies"));
+        }
+        let countries = countries.iter().map(|c| c.to_ascii_uppercase()).collect::<Vec<_>>();
+        if let Some(iso_code) = countries.iter().find(|c| !is_country_code(c)) {
+            return Err(format!("{} names no country", iso_code));
+        }
+        Ok(Self { mode, countries, action })
+    }
+
+    #[inline]
+    pub fn action(&self) -> RuleAction {
+        self.action
+    }
+
+    /// The rule the policy applies to a destination in the country of
+    /// `iso_code`, as `GEOIP,<iso_code>,<action>`
+    pub fn rule_for_country(&self, iso_code: &str) -> Option<Rule> {
+        let listed = self.countries.iter().any(|c| c.eq_ignore_ascii_case(iso_code));
+        match (self.mode, listed) {
+            (CountryMode::Block, true) | (CountryMode::Allow, false) => Some(Rule {
+                matcher: RuleMatcher::GeoIp(iso_code.to_ascii_uppercase()),
+                action: self.action,
+                log_sample: None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The rule the policy applies to a destination with the addresses
+    /// `ips`, that of the first one it acts on: a name with an address in
+    /// a blocked country is refused whichever it would connect to
+    pub fn rule_for(&self, ips: impl IntoIterator<Item = IpAddr>) -> Option<Rule> {
+        ips.into_iter().find_map(|ip| self.rule_for_country(&iso_code_of(ip)?))
+    }
+}
+
+/// An ordered rule list, the first matching rule wins
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    rules: Vec<Rule>,
+}
+
+impl Router {
+    #[inline]
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules }
+    }
+
+    #[inline]
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules.to_owned()
+    }
+
+    #[inline]
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.rules = rules;
+    }
+
+    /// The first rule matching the destination, if any
+    #[inline]
+    pub fn matched_rule(&self, domain: Option<&str>, ip: Option<IpAddr>) -> Option<&Rule> {
+        self.matched_rule_classified(domain, ip, None)
+    }
+
+    /// Same as [Router::matched_rule], for a flow classified as `class`
+    pub fn matched_rule_classified(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        class: Option<TrafficClass>,
+    ) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches_classified(domain, ip, class))
+    }
+
+    /// Same as [Router::matched_rule], for a flow opened by `process`
+    pub fn matched_rule_process(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        process: Option<&Process>,
+    ) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches_process(domain, ip, None, process))
+    }
+
+    /// The action for the destination, [RuleAction::Proxy] if no rule matches
+    pub fn decide(&self, domain: Option<&str>, ip: Option<IpAddr>) -> RuleAction {
+        self.decide_classified(domain, ip, None)
+    }
+
+    /// Same as [Router::decide], for a flow classified as `class`
+    pub fn decide_classified(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        class: Option<TrafficClass>,
+    ) -> RuleAction {
+        self.matched_rule_classified(domain, ip, class)
+            .map(|rule| rule.action)
+            .unwrap_or(RuleAction::Proxy)
+    }
+
+    /// Same as [Router::decide], for a flow opened by `process`
+    pub fn decide_process(
+        &self,
+        domain: Option<&str>,
+        ip: Option<IpAddr>,
+        process: Option<&Process>,
+    ) -> RuleAction {
+        self.matched_rule_process(domain, ip, process)
+            .map(|rule| rule.action)
+            .unwrap_or(RuleAction::Proxy)
+    }
+
+    /// Whether any rule depends on the class of the flow
+    pub fn has_protocol_rules(&self) -> bool {
+        self.rules.iter().any(|rule| matches!(rule.matcher, RuleMatcher::Protocol(_)))
+    }
+
+    /// Whether any rule depends on the process opening the flow, which is
+    /// only worth looking up then
+    pub fn has_process_rules(&self) -> bool {
+        self.rules.iter().any(Rule::is_process_rule)
+    }
+
+    /// A proxy auto-config script applying the rules, with the SOCKS proxy
+    /// at `socks_addr` for [RuleAction::Proxy]
+    ///
+    /// Browsers have no GeoIP database, only resolve IPv4 addresses in PAC
+    /// scripts and never see the traffic, so `GEOIP`, IPv6 `IP-CIDR`,
+    /// `PROTOCOL` and process rules are left out, the destinations they
+    /// match are sent to the proxy.
+    pub fn to_pac(&self, socks_addr: SocketAddr) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut pac = String::from("function FindProxyForURL(url, host) {\n");
+        let _ = writeln!(
+            pac,
+            "    var proxy = \"SOCKS5 {0}; SOCKS {0}\";\n    host = host.toLowerCase();",
+            socks_addr
+        );
+        let needs_ip = self.rules.iter().any(|rule| match &rule.matcher {
+            RuleMatcher::IpCidr(cidr) => cidr.addr().is_ipv4(),
+            _ => false,
+        });
+        if needs_ip {
+            pac.push_str("    var ip = dnsResolve(host);\n");
+        }
+        for rule in self.rules.iter() {
+            let action = match rule.action {
+                RuleAction::Direct => "\"DIRECT\"",
+                RuleAction::Proxy => "proxy",
+                /* A proxy nothing listens on, the browser fails to connect */
+                RuleAction::Reject => "\"PROXY 127.0.0.1:9\"",
+            };
+            let cond = match &rule.matcher {
+                RuleMatcher::Domain(name) => {
+                    format!("host == {}", quote(&name.to_ascii_lowercase()))
+                }
+                RuleMatcher::DomainSuffix(suffix) => {
+                    let suffix = suffix.to_ascii_lowercase();
+                    format!(
+                        "host == {} || dnsDomainIs(host, {})",
+                        quote(&suffix),
+                        quote(&format!(".{}", suffix))
+                    )
+                }
+                RuleMatcher::DomainKeyword(keyword) => {
+                    format!("host.indexOf({}) >= 0", quote(&keyword.to_ascii_lowercase()))
+                }
+                RuleMatcher::IpCidr(cidr) if cidr.addr().is_ipv4() => {
+                    let mask = u32::MAX.checked_shl(32 - cidr.prefix_len() as u32).unwrap_or(0);
+                    let mask = Ipv4Addr::from(mask);
+                    format!("ip && isInNet(ip, \"{}\", \"{}\")", cidr.addr(), mask)
+                }
+                RuleMatcher::IpCidr(_)
+                | RuleMatcher::GeoIp(_)
+                | RuleMatcher::Protocol(_)
+                | RuleMatcher::ProcessName(_)
+                | RuleMatcher::ProcessPath(_)
+                | RuleMatcher::BundleId(_) => {
+                    let _ = writeln!(pac, "    /* {} is decided by the proxy */", rule);
+                    continue;
+                }
+                RuleMatcher::Match => {
+                    let _ = writeln!(pac, "    return {};", action);
+                    break;
+                }
+            };
+            let _ = writeln!(pac, "    if ({}) return {};", cond, action);
+        }
+        pac.push_str("    return proxy;\n}\n");
+        pac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CountryMode, CountryPolicy, IpCidr, LogSampler, Router, Rule, RuleAction, RuleMatcher,
+        shadowed_rules,
+    };
+    use crate::{Process, TrafficClass};
+
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_rule_from_str() {
+        let rule = "DOMAIN-SUFFIX,google.com,PROXY".parse::<Rule>().unwrap();
+        assert_eq!(rule.matcher, RuleMatcher::DomainSuffix(String::from("google.com")));
+        assert_eq!(rule.action, RuleAction::Proxy);
+        assert_eq!(rule.to_string(), "DOMAIN-SUFFIX,google.com,PROXY");
+
+        let rule = "ip-cidr, 10.0.0.0/8, direct".parse::<Rule>().unwrap();
+        assert_eq!(rule.to_string(), "IP-CIDR,10.0.0.0/8,DIRECT");
+        assert_eq!("MATCH,DIRECT".parse::<Rule>().unwrap().matcher, RuleMatcher::Match);
+        assert_eq!("MATCH,reject".parse::<Rule>().unwrap().action, RuleAction::Reject);
+
+        assert!("DOMAIN,example.com".parse::<Rule>().is_err());
+        assert!("IP-CIDR,10.0.0.0/33,DIRECT".parse::<Rule>().is_err());
+        assert!("PORT,80,DIRECT".parse::<Rule>().is_err());
+
+        let rule = "protocol,BitTorrent,reject".parse::<Rule>().unwrap();
+        assert_eq!(rule.to_string(), "PROTOCOL,BITTORRENT,REJECT");
+        assert!("PROTOCOL,GOPHER,REJECT".parse::<Rule>().is_err());
+
+        let rule = "process-path,/Applications/Slack.app,proxy".parse::<Rule>().unwrap();
+        assert_eq!(rule.to_string(), "PROCESS-PATH,/Applications/Slack.app,PROXY");
+        assert!(rule.is_process_rule());
+        assert!(!"MATCH,PROXY".parse::<Rule>().unwrap().is_process_rule());
+
+        let rule = "domain-suffix,cdn.example,direct,LOG-SAMPLE=100".parse::<Rule>().unwrap();
+        assert_eq!(rule.log_sample.map(|n| n.get()), Some(100));
+        assert_eq!(rule.to_string(), "DOMAIN-SUFFIX,cdn.example,DIRECT,log-sample=100");
+        assert_eq!("MATCH,PROXY,log-sample=2".parse::<Rule>().unwrap().matcher, RuleMatcher::Match);
+        assert!("MATCH,PROXY,log-sample=0".parse::<Rule>().is_err());
+        assert!("DOMAIN,example.com,log-sample=2".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_log_sampler() {
+        let sampler = LogSampler::default();
+        let sampled = "DOMAIN-SUFFIX,cdn.example,DIRECT,log-sample=3".parse::<Rule>().unwrap();
+        let picked = (0..7).filter(|_| sampler.sample(Some(&sampled))).count();
+        assert_eq!(picked, 3);
+
+        /* Counted apart */
+        let other = "DOMAIN-SUFFIX,video.example,DIRECT,log-sample=3".parse::<Rule>().unwrap();
+        assert!(sampler.sample(Some(&other)));
+        assert!(!sampler.sample(Some(&other)));
+
+        let all = "MATCH,PROXY".parse::<Rule>().unwrap();
+        assert!((0..3).all(|_| sampler.sample(Some(&all))));
+        assert!(sampler.sample(None));
+    }
+
+    #[test]
+    fn test_country_policy() {
+        let block =
+            CountryPolicy::new(CountryMode::Block, vec!["cn".into()], RuleAction::Reject).unwrap();
+        assert_eq!(block.rule_for_country("CN").unwrap().to_string(), "GEOIP,CN,REJECT");
+        assert_eq!(block.rule_for_country("US"), None);
+
+        let allow =
+            CountryPolicy::new(CountryMode::Allow, vec!["US".into()], RuleAction::Proxy).unwrap();
+        assert_eq!(allow.rule_for_country("cn").unwrap().to_string(), "GEOIP,CN,PROXY");
+        assert_eq!(allow.rule_for_country("US"), None);
+        /* Private addresses are located nowhere */
+        assert_eq!(allow.rule_for(["192.168.1.1".parse().unwrap()]), None);
+
+        assert!(
+            CountryPolicy::new(CountryMode::Block, vec!["XX".into()], RuleAction::Reject).is_err()
+        );
+        assert!(CountryPolicy::new(CountryMode::Block, vec![], RuleAction::Direct).is_err());
+    }
+
+    #[test]
+    fn test_ip_cidr_contains() {
+        let cidr = "192.168.0.0/16".parse::<IpCidr>().unwrap();
+        assert!(cidr.contains(&"192.168.31.254".parse().unwrap()));
+        assert!(!cidr.contains(&"192.169.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+        let cidr = "0.0.0.0/0".parse::<IpCidr>().unwrap();
+        assert!(cidr.contains(&"8.8.8.8".parse().unwrap()));
+        let cidr = "2001:db8::/32".parse::<IpCidr>().unwrap();
+        assert!(cidr.contains(&"2001:db8:1::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_router_decide() {
+        let router = Router::new(vec![
+            "DOMAIN-SUFFIX,example.com,DIRECT".parse().unwrap(),
+            "IP-CIDR,10.0.0.0/8,DIRECT".parse().unwrap(),
+        ]);
+        assert_eq!(router.decide(Some("www.example.com"), None), RuleAction::Direct);
+        assert_eq!(router.decide(Some("example.com"), None), RuleAction::Direct);
+        assert_eq!(router.decide(Some("badexample.com"), None), RuleAction::Proxy);
+        assert_eq!(router.decide(None, Some("10.1.2.3".parse().unwrap())), RuleAction::Direct);
+        assert_eq!(router.decide(None, Some("11.1.2.3".parse().unwrap())), RuleAction::Proxy);
+        assert!(!router.has_protocol_rules());
+        let mapped = "::ffff:10.1.2.3".parse().unwrap();
+        assert_eq!(router.decide(None, Some(mapped)), RuleAction::Direct);
+
+        let router = Router::new(vec![
+            "PROTOCOL,BITTORRENT,REJECT".parse().unwrap(),
+            "MATCH,DIRECT".parse().unwrap(),
+        ]);
+        assert!(router.has_protocol_rules());
+        /* Unclassified flows skip the PROTOCOL rules */
+        assert_eq!(router.decide(Some("example.com"), None), RuleAction::Direct);
+        let bittorrent = Some(TrafficClass::BitTorrent);
+        assert_eq!(router.decide_classified(None, None, bittorrent), RuleAction::Reject);
+        let tls = Some(TrafficClass::Tls);
+        assert_eq!(router.decide_classified(None, None, tls), RuleAction::Direct);
+    }
+
+    #[test]
+    fn test_router_decide_process() {
+        let router = Router::new(vec![
+            "PROCESS-NAME,curl,DIRECT".parse().unwrap(),
+            "PROCESS-PATH,/Applications/Slack.app,REJECT".parse().unwrap(),
+            "BUNDLE-ID,com.apple.Safari,DIRECT".parse().unwrap(),
+            "DOMAIN-SUFFIX,example.com,REJECT".parse().unwrap(),
+        ]);
+        assert!(router.has_process_rules());
+        let process = |path: &str, bundle_id: Option<&str>| Process {
+            pid: 1,
+            path: PathBuf::from(path),
+            bundle_id: bundle_id.map(str::to_owned),
+        };
+        let curl = process("/usr/bin/curl", None);
+        assert_eq!(
+            router.decide_process(Some("example.com"), None, Some(&curl)),
+            RuleAction::Direct
+        );
+        /* An unknown process skips the process rules */
+        assert_eq!(router.decide_process(Some("example.com"), None, None), RuleAction::Reject);
+        let slack = process("/Applications/Slack.app/Contents/MacOS/Slack", None);
+        assert_eq!(router.decide_process(None, None, Some(&slack)), RuleAction::Reject);
+        let slacker = process("/Applications/Slack.application/Slack", None);
+        assert_eq!(router.decide_process(None, None, Some(&slacker)), RuleAction::Proxy);
+        let safari =
+            process("/Applications/Safari.app/Contents/MacOS/Safari", Some("com.apple.Safari"));
+        assert_eq!(router.decide_process(None, None, Some(&safari)), RuleAction::Direct);
+        assert!(!Router::new(vec!["MATCH,DIRECT".parse().unwrap()]).has_process_rules());
+    }
+
+    #[test]
+    fn test_router_to_pac() {
+        let router = Router::new(vec![
+            "DOMAIN-SUFFIX,Example.com,DIRECT".parse().unwrap(),
+            "DOMAIN-KEYWORD,github,PROXY".parse().unwrap(),
+            "IP-CIDR,10.0.0.0/8,DIRECT".parse().unwrap(),
+            "IP-CIDR6,2001:db8::/32,DIRECT".parse().unwrap(),
+            "GEOIP,CN,DIRECT".parse().unwrap(),
+            "MATCH,DIRECT".parse().unwrap(),
+            "DOMAIN,never.example.org,PROXY".parse().unwrap(),
+        ]);
+        let pac = router.to_pac(SocketAddr::from(([127, 0, 0, 1], 1080)));
+        assert!(pac.contains("var proxy = \"SOCKS5 127.0.0.1:1080; SOCKS 127.0.0.1:1080\";"));
+        assert!(pac.contains(
+            "if (host == \"example.com\" || dnsDomainIs(host, \".example.com\")) return \"DIRECT\";"
+        ));
+        assert!(pac.contains("if (host.indexOf(\"github\") >= 0) return proxy;"));
+        assert!(pac.contains("var ip = dnsResolve(host);"));
+        assert!(
+            pac.contains("if (ip && isInNet(ip, \"10.0.0.0\", \"255.0.0.0\")) return \"DIRECT\";")
+        );
+        assert!(pac.contains("/* IP-CIDR,2001:db8::/32,DIRECT is decided by the proxy */"));
+        assert!(pac.contains("/* GEOIP,CN,DIRECT is decided by the proxy */"));
+        assert!(pac.contains("    return \"DIRECT\";\n"));
+        assert!(!pac.contains("never.example.org"));
+    }
+
+    #[test]
+    fn test_shadowed_rules() {
+        let rules = [
+            "IP-CIDR,10.0.0.0/8,DIRECT",
+            "IP-CIDR,10.1.0.0/16,PROXY",
+            "IP-CIDR,11.0.0.0/8,DIRECT",
+            "DOMAIN-SUFFIX,example.com,DIRECT",
+            "DOMAIN,www.Example.com,PROXY",
+            "DOMAIN-SUFFIX,cdn.example.com,DIRECT",
+            "DOMAIN-KEYWORD,git,PROXY",
+            "DOMAIN-SUFFIX,github.io,DIRECT",
+            "DOMAIN-KEYWORD,github,PROXY",
+            "DOMAIN,example.org,DIRECT",
+            "GEOIP,CN,DIRECT",
+            "GEOIP,CN,PROXY",
+            "MATCH,PROXY",
+            "DOMAIN,example.net,DIRECT",
+        ]
+        .map(|rule| rule.parse::<Rule>().unwrap());
+        assert_eq!(
+            shadowed_rules(&rules),
+            [(1, 0), (4, 3), (5, 3), (7, 6), (8, 6), (11, 10), (13, 12)]
+        );
+        let cidr = "10.0.0.0/8".parse::<IpCidr>().unwrap();
+        assert!(cidr.covers(&"10.0.0.0/8".parse().unwrap()));
+        assert!(!cidr.covers(&"0.0.0.0/0".parse().unwrap()));
+        assert!(!cidr.covers(&"::/0".parse().unwrap()));
+    }
+}
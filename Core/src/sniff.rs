@@ -0,0 +1,247 @@
+//! Host names sniffed from the first bytes of TCP streams
+//!
+//! Destinations known only by their IP address, as with tun mode, can then
+//! still be matched by the domain rules of the [crate::Router]. The bytes
+//! read while sniffing are handed back to be replayed to the outbound
+//! unchanged.
+
+use std::io::Result;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::Instant;
+
+use crate::classify::HTTP_METHODS;
+
+/// Bytes of the largest TLS record, header included, past which a stream
+/// is given up on
+pub const SNIFF_LIMIT: usize = 5 + 16384;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sniffed {
+    /// The server name of a TLS ClientHello or the Host of an HTTP request
+    Host(String),
+    /// The bytes so far begin a ClientHello or request, more are needed
+    Incomplete,
+    /// Neither TLS nor HTTP, or without a host name
+    Unknown,
+}
+
+/// The host name the client asks for in the bytes `first` it sent first
+pub fn sniff_host(first: &[u8]) -> Sniffed {
+    if first.first() == Some(&0x16) {
+        sniff_client_hello(first)
+    } else if HTTP_METHODS.iter().any(|method| first.starts_with(method)) {
+        sniff_http_host(first)
+    } else if HTTP_METHODS.iter().any(|method| method.starts_with(first)) {
+        Sniffed::Incomplete
+    } else {
+        Sniffed::Unknown
+    }
+}
+
+/// Read from `reader` until its host name is sniffed or can't be, for up to
+/// `timeout`. Returns the bytes read, to be replayed, with the host name.
+pub async fn sniff<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    timeout: Duration,
+) -> Result<(Vec<u8>, Option<String>)> {
+    let deadline = Instant::now() + timeout;
+    let mut first = Vec::with_capacity(1024);
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = match tokio::time::timeout_at(deadline, reader.read(&mut buf)).await {
+            Ok(ret) => ret?,
+            Err(_) => return Ok((first, None)),
+        };
+        first.extend_from_slice(&buf[..len]);
+        match sniff_host(&first) {
+            Sniffed::Host(host) => return Ok((first, Some(host))),
+            Sniffed::Incomplete if len != 0 && first.len() < SNIFF_LIMIT => {}
+            _ => return Ok((first, None)),
+        }
+    }
+}
+
+/// Bounds-checked reads over a byte slice, None once past its end
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    /// A vector prefixed with its length of `len_bytes`
+    fn vector(&mut self, len_bytes: usize) -> Option<&'a [u8]> {
+        let len = match len_bytes {
+            1 => self.u8()?,
+            _ => self.u16()?,
+        };
+        self.take(len)
+    }
+}
+
+fn sniff_client_hello(first: &[u8]) -> Sniffed {
+    let record = match first {
+        [0x16, 0x03, _, hi, lo, ..] => {
+            let len = u16::from_be_bytes([*hi, *lo]) as usize;
+            match first[5..].get(..len) {
+                Some(record) => record,
+                None => return Sniffed::Incomplete,
+            }
+        }
+        [0x16] | [0x16, 0x03] | [0x16, 0x03, _] | [0x16, 0x03, _, _] => {
+            return Sniffed::Incomplete;
+        }
+        _ => return Sniffed::Unknown,
+    };
+    /* A ClientHello spread over several records is rare enough to give up on */
+    server_name(record).map(Sniffed::Host).unwrap_or(Sniffed::Unknown)
+}
+
+fn server_name(record: &[u8]) -> Option<String> {
+    let mut cursor = Cursor(record);
+    if cursor.u8()? != 0x01 {
+        return None;
+    }
+    let len = cursor.take(3)?;
+    let mut hello = Cursor(cursor.take(u32::from_be_bytes([0, len[0], len[1], len[2]]) as usize)?);
+    /* The client version and random */
+    hello.take(2 + 32)?;
+    hello.vector(1)?; /* session_id */
+    hello.vector(2)?; /* cipher_suites */
+    hello.vector(1)?; /* compression_methods */
+    let mut extensions = Cursor(hello.vector(2)?);
+    while !extensions.0.is_empty() {
+        let ext_type = extensions.u16()?;
+        let mut ext = Cursor(extensions.vector(2)?);
+        if ext_type != 0 {
+            continue;
+        }
+        let mut names = Cursor(ext.vector(2)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vector(2)?;
+            if name_type == 0 {
+                return host_name(name);
+            }
+        }
+    }
+    None
+}
+
+fn sniff_http_host(first: &[u8]) -> Sniffed {
+    let Some(end) = first.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Sniffed::Incomplete;
+    };
+    let head = String::from_utf8_lossy(&first[..end]);
+    let host = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("host").then(|| value.trim())
+    });
+    let host = host.map(|host| match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    });
+    host.and_then(|host| host_name(host.as_bytes())).map(Sniffed::Host).unwrap_or(Sniffed::Unknown)
+}
+
+/// The name in lowercase, if it looks like a host name
+fn host_name(name: &[u8]) -> Option<String> {
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.iter().all(|b| b.is_ascii_alphanumeric() || b"-._".contains(b));
+    valid.then(|| String::from_utf8_lossy(name).to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sniffed, sniff, sniff_host};
+
+    use std::time::Duration;
+
+    /// A TLS 1.3 ClientHello for `name` with a padding extension before the
+    /// server_name one
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut sni = vec![0, 0];
+        let list_len = (3 + name.len()) as u16;
+        sni.extend_from_slice(&(list_len + 2).to_be_bytes());
+        sni.extend_from_slice(&list_len.to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name.as_bytes());
+        let mut extensions = vec![0x00, 0x15, 0x00, 0x03, 0, 0, 0];
+        extensions.extend_from_slice(&sni);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0x5a; 32]);
+        hello.extend_from_slice(&[0]); /* session_id */
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); /* cipher_suites */
+        hello.extend_from_slice(&[0x01, 0x00]); /* compression_methods */
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_sniff_host() {
+        let hello = client_hello("Www.Example.com");
+        assert_eq!(sniff_host(&hello), Sniffed::Host(String::from("www.example.com")));
+        assert_eq!(sniff_host(&hello[..3]), Sniffed::Incomplete);
+        assert_eq!(sniff_host(&hello[..hello.len() - 1]), Sniffed::Incomplete);
+
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: x\r\nhost: example.org:8080\r\n\r\n";
+        assert_eq!(sniff_host(request), Sniffed::Host(String::from("example.org")));
+        assert_eq!(sniff_host(&request[..20]), Sniffed::Incomplete);
+        assert_eq!(sniff_host(b"PO"), Sniffed::Incomplete);
+        assert_eq!(sniff_host(b"GET / HTTP/1.0\r\n\r\n"), Sniffed::Unknown);
+        assert_eq!(sniff_host(b"SSH-2.0-OpenSSH_9.6\r\n"), Sniffed::Unknown);
+    }
+
+    #[test]
+    fn test_sniff() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let hello = client_hello("example.com");
+            let (mut client, mut server) = tokio::io::duplex(64);
+            let written = hello.clone();
+            tokio::spawn(async move {
+                /* In pieces smaller than the record */
+                for chunk in written.chunks(16) {
+                    tokio::io::AsyncWriteExt::write_all(&mut client, chunk).await?;
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                Ok::<_, std::io::Error>(client)
+            });
+            let (first, host) = sniff(&mut server, Duration::from_secs(1)).await?;
+            assert_eq!(first, hello);
+            assert_eq!(host.as_deref(), Some("example.com"));
+
+            /* The client waits for the server, sniffing gives up in time */
+            let (_client, mut server) = tokio::io::duplex(64);
+            let (first, host) = sniff(&mut server, Duration::from_millis(50)).await?;
+            assert!(first.is_empty() && host.is_none());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,227 @@
+use std::io::Result;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+/// The maximum length of the pending connections queue of a listener
+pub const LISTEN_BACKLOG: u32 = 1024;
+
+/// The largest UDP payload over IPv4: the 16-bit total length less the
+/// IPv4 and UDP headers
+pub const MAX_UDP_PAYLOAD_V4: usize = u16::MAX as usize - 20 - 8;
+
+/// The largest UDP payload over IPv6 without jumbograms: the 16-bit payload
+/// length less the UDP header
+pub const MAX_UDP_PAYLOAD_V6: usize = u16::MAX as usize - 8;
+
+/// Socket tweaks applied to accepted client connections, outbound proxy
+/// connections and listeners.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// `TCP_NODELAY`, disables Nagle's algorithm
+    pub nodelay: Option<bool>,
+    /// Idle time before the first keepalive probe, also used as the
+    /// interval between probes
+    pub keepalive: Option<Duration>,
+    /// `SO_REUSEPORT` for listeners
+    pub reuse_port: Option<bool>,
+    /// TCP Fast Open, the value is the queue length of pending TFO requests
+    /// on listeners. Only honoured on Linux, ignored elsewhere.
+    pub fast_open: Option<u32>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self { nodelay: Some(true), keepalive: None, reuse_port: None, fast_open: None }
+    }
+}
+
+impl SocketOptions {
+    /// Apply the per-connection options to an established stream
+    pub fn apply_to_stream(&self, stream: &TcpStream) -> Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        self.apply_keepalive(&SockRef::from(stream))
+    }
+
+    fn apply_keepalive(&self, sock: &SockRef<'_>) -> Result<()> {
+        if let Some(keepalive) = self.keepalive {
+            let params = TcpKeepalive::new().with_time(keepalive);
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+            let params = params.with_interval(keepalive);
+            sock.set_tcp_keepalive(&params)?;
+        }
+        Ok(())
+    }
+
+    /// Bind a listener on `addr` with these options
+    pub fn bind_listener(&self, addr: SocketAddr) -> Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        if let Some(reuse_port) = self.reuse_port {
+            socket.set_reuse_port(reuse_port)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(qlen) = self.fast_open {
+            set_tcp_fastopen(&socket, libc::TCP_FASTOPEN, qlen as libc::c_int)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG as i32)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Connect to `addr` with these options
+    pub async fn connect(&self, addr: SocketAddr) -> Result<TcpStream> {
+        self.outbound_socket(addr)?.connect(addr).await
+    }
+
+    /// A socket for connecting to `addr` with these options, for callers
+    /// that need to adjust it further before connecting
+    pub fn outbound_socket(&self, addr: SocketAddr) -> Result<TcpSocket> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        self.apply_keepalive(&SockRef::from(&socket))?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.fast_open.is_some() {
+            set_tcp_fastopen(&socket, libc::TCP_FASTOPEN_CONNECT, 1)?;
+        }
+        Ok(socket)
+    }
+}
+
+/// Buffer sizes of the UDP sockets relaying datagrams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpBufferOptions {
+    /// The largest datagram payload relayed, what the receive buffers are
+    /// sized for. Past 65535, only IPv6 jumbograms can carry one.
+    pub datagram_size: usize,
+    /// `SO_RCVBUF`, the system default when unset
+    pub recv_buffer: Option<usize>,
+    /// `SO_SNDBUF`, the system default when unset
+    pub send_buffer: Option<usize>,
+}
+
+impl Default for UdpBufferOptions {
+    fn default() -> Self {
+        Self { datagram_size: u16::MAX as usize, recv_buffer: None, send_buffer: None }
+    }
+}
+
+impl UdpBufferOptions {
+    /// Apply the socket buffer sizes to `socket`. The system may round them,
+    /// Linux doubles them for its bookkeeping.
+    pub fn apply_to_udp(&self, socket: &UdpSocket) -> Result<()> {
+        let sock = SockRef::from(socket);
+        if let Some(size) = self.recv_buffer {
+            sock.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            sock.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// The largest payload sent over a socket of the family of `socket`,
+    /// those past it cannot go out in one datagram
+    pub fn max_payload(&self, socket: &UdpSocket) -> Result<usize> {
+        let max = match socket.local_addr()? {
+            SocketAddr::V4(_) => MAX_UDP_PAYLOAD_V4,
+            SocketAddr::V6(_) if self.datagram_size > u16::MAX as usize => self.datagram_size,
+            SocketAddr::V6(_) => MAX_UDP_PAYLOAD_V6,
+        };
+        Ok(max.min(self.datagram_size))
+    }
+}
+
+/// Close `tcp_stream` with a RST rather than a FIN, telling the peer no
+/// more than a listener being shut down would
+pub fn close_with_reset(tcp_stream: TcpStream) {
+    /* Closing with a zero linger sends a RST */
+    let _ = SockRef::from(&tcp_stream).set_linger(Some(Duration::ZERO));
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_tcp_fastopen<S>(sock: &S, optname: libc::c_int, val: libc::c_int) -> Result<()>
+where
+    S: std::os::fd::AsFd,
+{
+    let ret = unsafe {
+        libc::setsockopt(
+            std::os::fd::AsRawFd::as_raw_fd(&sock.as_fd()),
+            libc::IPPROTO_TCP,
+            optname,
+            &val as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 { Err(std::io::Error::last_os_error()) } else { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_UDP_PAYLOAD_V4, MAX_UDP_PAYLOAD_V6, SocketOptions, UdpBufferOptions};
+
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use socket2::SockRef;
+
+    #[test]
+    fn test_apply_to_accepted_and_outbound() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let sockopts = SocketOptions {
+                keepalive: Some(Duration::from_secs(30)),
+                reuse_port: Some(true),
+                ..Default::default()
+            };
+            let listener = sockopts.bind_listener(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+            let outbound = sockopts.connect(listener.local_addr()?).await?;
+            let (accepted, _) = listener.accept().await?;
+            sockopts.apply_to_stream(&accepted)?;
+
+            for stream in [&outbound, &accepted] {
+                assert!(stream.nodelay()?);
+                assert!(SockRef::from(stream).keepalive()?);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_udp_buffer_options() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let v4 = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            let buffers = UdpBufferOptions {
+                recv_buffer: Some(256 * 1024),
+                send_buffer: Some(128 * 1024),
+                ..Default::default()
+            };
+            buffers.apply_to_udp(&v4)?;
+            assert!(SockRef::from(&v4).recv_buffer_size()? >= 128 * 1024);
+            assert!(SockRef::from(&v4).send_buffer_size()? >= 64 * 1024);
+            assert_eq!(buffers.max_payload(&v4)?, MAX_UDP_PAYLOAD_V4);
+
+            let small = UdpBufferOptions { datagram_size: 1400, ..Default::default() };
+            assert_eq!(small.max_payload(&v4)?, 1400);
+
+            if let Ok(v6) = tokio::net::UdpSocket::bind((std::net::Ipv6Addr::LOCALHOST, 0)).await {
+                assert_eq!(buffers.max_payload(&v6)?, MAX_UDP_PAYLOAD_V6);
+                let jumbo = UdpBufferOptions { datagram_size: 100_000, ..Default::default() };
+                assert_eq!(jumbo.max_payload(&v6)?, 100_000);
+            }
+            Ok(())
+        })
+    }
+}
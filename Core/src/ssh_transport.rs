@@ -0,0 +1,999 @@
+//! The transport layer of SSH (RFC 4253), for either end of a connection
+//!
+//! Keys are exchanged with curve25519-sha256 and packets sealed with
+//! chacha20-poly1305@openssh.com or AES-GCM, which every OpenSSH release of
+//! the last decade offers, along with the strict key exchange of OpenSSH
+//! 9.6 when the peer supports it. Packets are read by a task of their own,
+//! so that waiting for the next one can be cancelled, and which is handed
+//! the keys of those following NEWKEYS as the exchange is processed.
+//! Messages of the layers above sent while keys are exchanged again are
+//! held until the exchange is over.
+//!
+//! The server side is only built for the tests, where the OpenSSH client
+//! is also run against it.
+//!
+//! This is written here rather than taken from russh because only the
+//! client of a single key exchange and two ciphers is needed, and ring,
+//! which the p2p, knock and wireguard features already build with, has
+//! every primitive: russh would add a second crypto stack and its own
+//! runtime of sessions and handlers to drive one channel per connection.
+
+use std::io::{Error, ErrorKind, Result};
+
+use ring::aead::chacha20_poly1305_openssh::{OpeningKey, SealingKey, TAG_LEN};
+use ring::aead::{AES_128_GCM, AES_256_GCM, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest::{Context, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, RsaPublicKeyComponents};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::SshHostKey;
+
+pub(crate) const MSG_DISCONNECT: u8 = 1;
+const MSG_IGNORE: u8 = 2;
+const MSG_UNIMPLEMENTED: u8 = 3;
+const MSG_DEBUG: u8 = 4;
+pub(crate) const MSG_SERVICE_REQUEST: u8 = 5;
+pub(crate) const MSG_SERVICE_ACCEPT: u8 = 6;
+pub(crate) const MSG_EXT_INFO: u8 = 7;
+const MSG_KEXINIT: u8 = 20;
+const MSG_NEWKEYS: u8 = 21;
+const MSG_KEX_ECDH_INIT: u8 = 30;
+const MSG_KEX_ECDH_REPLY: u8 = 31;
+
+/// Reason of a DISCONNECT for leaving
+const DISCONNECT_BY_APPLICATION: u32 = 11;
+
+const VERSION: &str = concat!("SSH-2.0-nstream_", env!("CARGO_PKG_VERSION"));
+/// Lines a server may send before its version, as OpenSSH allows
+const MAX_PRELUDE_LINES: usize = 1024;
+/// Longest line of the version exchange
+const MAX_LINE_LEN: usize = 8192;
+/// Longest packet read, as OpenSSH
+const MAX_PACKET_LEN: usize = 256 * 1024;
+const READ_SIZE: usize = 16 * 1024;
+/// Payload bytes either way after which keys are exchanged again
+const REKEY_BYTES: u64 = 1 << 30;
+
+const KEX_ALGORITHMS: [&str; 2] = ["curve25519-sha256", "curve25519-sha256@libssh.org"];
+const STRICT_KEX_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+const STRICT_KEX_SERVER: &str = "kex-strict-s-v00@openssh.com";
+const HOST_KEY_ALGORITHMS: [&str; 4] =
+    ["ssh-ed25519", "ecdsa-sha2-nistp256", "rsa-sha2-512", "rsa-sha2-256"];
+/// Never used along with AEAD ciphers, but negotiated all the same
+const MAC_ALGORITHMS: [&str; 2] = ["hmac-sha2-256-etm@openssh.com", "hmac-sha2-256"];
+
+pub(crate) fn protocol_error(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn closed() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "SSH connection closed")
+}
+
+/// Appends the encodings of RFC 4251
+pub(crate) trait Encode {
+    fn put_u32(&mut self, value: u32);
+    fn put_bool(&mut self, value: bool);
+    fn put_string(&mut self, value: impl AsRef<[u8]>);
+    /// `value` being an unsigned integer in network byte order
+    fn put_mpint(&mut self, value: &[u8]);
+}
+
+impl Encode for Vec<u8> {
+    fn put_u32(&mut self, value: u32) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_bool(&mut self, value: bool) {
+        self.push(value as u8);
+    }
+
+    fn put_string(&mut self, value: impl AsRef<[u8]>) {
+        let value = value.as_ref();
+        self.put_u32(value.len() as u32);
+        self.extend_from_slice(value);
+    }
+
+    fn put_mpint(&mut self, value: &[u8]) {
+        let value = &value[value.iter().take_while(|b| **b == 0).count()..];
+        let sign_byte = value.first().is_some_and(|b| b & 0x80 != 0);
+        self.put_u32((sign_byte as usize + value.len()) as u32);
+        if sign_byte {
+            self.push(0);
+        }
+        self.extend_from_slice(value);
+    }
+}
+
+/// Reads the fields of a message in turn
+pub(crate) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    #[inline]
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err(protocol_error("Truncated SSH message"));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool> {
+        Ok(self.take(1)?[0] != 0)
+    }
+
+    pub(crate) fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn utf8(&mut self) -> Result<&'a str> {
+        std::str::from_utf8(self.string()?)
+            .map_err(|_| protocol_error("Invalid UTF-8 in SSH message"))
+    }
+
+    pub(crate) fn name_list(&mut self) -> Result<Vec<&'a str>> {
+        Ok(self.utf8()?.split(',').filter(|name| !name.is_empty()).collect())
+    }
+
+    /// A non-negative mpint, without its leading zeros
+    pub(crate) fn mpint(&mut self) -> Result<&'a [u8]> {
+        let value = self.string()?;
+        if value.first().is_some_and(|b| b & 0x80 != 0) {
+            return Err(protocol_error("Negative SSH mpint"));
+        }
+        Ok(&value[value.iter().take_while(|b| **b == 0).count()..])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cipher {
+    ChaCha20Poly1305,
+    Aes256Gcm,
+    Aes128Gcm,
+}
+
+impl Cipher {
+    /// In the order of preference
+    const ALL: [Self; 3] = [Self::ChaCha20Poly1305, Self::Aes256Gcm, Self::Aes128Gcm];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::ChaCha20Poly1305 => "chacha20-poly1305@openssh.com",
+            Self::Aes256Gcm => "aes256-gcm@openssh.com",
+            Self::Aes128Gcm => "aes128-gcm@openssh.com",
+        }
+    }
+
+    fn named(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|cipher| cipher.name() == name)
+    }
+
+    fn key_len(self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 => 64,
+            Self::Aes256Gcm => 32,
+            Self::Aes128Gcm => 16,
+        }
+    }
+
+    fn iv_len(self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 => 0,
+            Self::Aes256Gcm | Self::Aes128Gcm => 12,
+        }
+    }
+
+    fn gcm_key(self, key: &[u8]) -> Box<LessSafeKey> {
+        let algorithm = if self == Self::Aes128Gcm { &AES_128_GCM } else { &AES_256_GCM };
+        Box::new(LessSafeKey::new(UnboundKey::new(algorithm, key).unwrap()))
+    }
+}
+
+/// The nonce of the packet `iv` is for, `iv` then being that of the next
+/// one
+fn next_nonce(iv: &mut [u8; 12]) -> Nonce {
+    let nonce = Nonce::assume_unique_for_key(*iv);
+    let counter = u64::from_be_bytes(iv[4..].try_into().unwrap()).wrapping_add(1);
+    iv[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// Keys of the packets sent
+enum Sealer {
+    Plain,
+    ChaCha(SealingKey),
+    Gcm(Box<LessSafeKey>, [u8; 12]),
+}
+
+impl Sealer {
+    fn new(cipher: Cipher, iv: &[u8], key: &[u8]) -> Self {
+        match cipher {
+            Cipher::ChaCha20Poly1305 => Self::ChaCha(SealingKey::new(key.try_into().unwrap())),
+            _ => Self::Gcm(cipher.gcm_key(key), iv.try_into().unwrap()),
+        }
+    }
+}
+
+/// Keys of the packets received
+enum Opener {
+    Plain,
+    ChaCha(OpeningKey),
+    Gcm(Box<LessSafeKey>, [u8; 12]),
+}
+
+impl Opener {
+    fn new(cipher: Cipher, iv: &[u8], key: &[u8]) -> Self {
+        match cipher {
+            Cipher::ChaCha20Poly1305 => Self::ChaCha(OpeningKey::new(key.try_into().unwrap())),
+            _ => Self::Gcm(cipher.gcm_key(key), iv.try_into().unwrap()),
+        }
+    }
+}
+
+struct PacketWriter {
+    sealer: Sealer,
+    seq: u32,
+    rng: SystemRandom,
+}
+
+impl PacketWriter {
+    fn encode(&mut self, payload: &[u8]) -> Vec<u8> {
+        /* The length is left out of the blocks when it is authenticated */
+        let (block_len, aad_len) = match self.sealer {
+            Sealer::Plain => (8, 0),
+            Sealer::ChaCha(_) => (8, 4),
+            Sealer::Gcm(..) => (16, 4),
+        };
+        let mut padding = block_len - (5 + payload.len() - aad_len) % block_len;
+        if padding < 4 {
+            padding += block_len;
+        }
+        let mut packet = Vec::with_capacity(5 + payload.len() + padding + TAG_LEN);
+        packet.put_u32((1 + payload.len() + padding) as u32);
+        packet.push(padding as u8);
+        packet.extend_from_slice(payload);
+        let start = packet.len();
+        packet.resize(start + padding, 0);
+        self.rng.fill(&mut packet[start..]).unwrap();
+        match &mut self.sealer {
+            Sealer::Plain => {}
+            Sealer::ChaCha(key) => {
+                let mut tag = [0; TAG_LEN];
+                key.seal_in_place(self.seq, &mut packet, &mut tag);
+                packet.extend_from_slice(&tag);
+            }
+            Sealer::Gcm(key, iv) => {
+                let (len, body) = packet.split_at_mut(4);
+                let tag = key.seal_in_place_separate_tag(next_nonce(iv), Aad::from(&*len), body);
+                packet.extend_from_slice(tag.unwrap().as_ref());
+            }
+        }
+        self.seq = self.seq.wrapping_add(1);
+        packet
+    }
+}
+
+struct PacketReader {
+    /// Received and not decoded yet
+    buf: Vec<u8>,
+    opener: Opener,
+    seq: u32,
+}
+
+impl PacketReader {
+    /// The payload of the next packet, None until it is all received
+    fn decode(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(&len) = self.buf.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let len = match &self.opener {
+            Opener::ChaCha(key) => key.decrypt_packet_length(self.seq, len),
+            _ => len,
+        };
+        let len = u32::from_be_bytes(len) as usize;
+        let (aligned, tag_len) = match self.opener {
+            Opener::Plain => ((4 + len).is_multiple_of(8), 0),
+            Opener::ChaCha(_) => (len.is_multiple_of(8), TAG_LEN),
+            Opener::Gcm(..) => (len.is_multiple_of(16), TAG_LEN),
+        };
+        if !aligned || !(8..=MAX_PACKET_LEN).contains(&len) {
+            return Err(protocol_error(format!("Corrupt SSH packet of {} bytes", len)));
+        }
+        if self.buf.len() < 4 + len + tag_len {
+            return Ok(None);
+        }
+        let mut packet: Vec<u8> = self.buf.drain(..4 + len + tag_len).collect();
+        let corrupt = |_| protocol_error("Corrupt SSH packet");
+        let plain: &[u8] = match &mut self.opener {
+            Opener::Plain => &packet[4..],
+            Opener::ChaCha(key) => {
+                let (packet, tag) = packet.split_at_mut(4 + len);
+                key.open_in_place(self.seq, packet, (&*tag).try_into().unwrap()).map_err(corrupt)?
+            }
+            Opener::Gcm(key, iv) => {
+                let (len, body) = packet.split_at_mut(4);
+                key.open_in_place(next_nonce(iv), Aad::from(&*len), body).map_err(corrupt)?
+            }
+        };
+        self.seq = self.seq.wrapping_add(1);
+        let padding = plain[0] as usize;
+        if padding < 4 || padding >= plain.len() {
+            return Err(protocol_error(format!("SSH packet with {} bytes of padding", padding)));
+        }
+        Ok(Some(plain[1..plain.len() - padding].to_vec()))
+    }
+
+    async fn fill(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> Result<()> {
+        self.buf.reserve(READ_SIZE);
+        match stream.read_buf(&mut self.buf).await? {
+            0 => Err(closed()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn read(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+        loop {
+            if let Some(payload) = self.decode()? {
+                return Ok(payload);
+            }
+            self.fill(stream).await?;
+        }
+    }
+
+    /// The version of the peer, the lines before it skipped
+    async fn read_version(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+        for _ in 0..MAX_PRELUDE_LINES {
+            let mut line = loop {
+                if let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+                    break self.buf.drain(..=end).collect::<Vec<_>>();
+                }
+                if self.buf.len() > MAX_LINE_LEN {
+                    return Err(protocol_error("Overlong line before the SSH version"));
+                }
+                self.fill(stream).await?;
+            };
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.starts_with(b"SSH-2.0-") || line.starts_with(b"SSH-1.99-") {
+                return Ok(line);
+            }
+            if line.starts_with(b"SSH-") {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("Unsupported SSH version {}", String::from_utf8_lossy(&line)),
+                ));
+            }
+        }
+        Err(protocol_error("No SSH version from the peer"))
+    }
+}
+
+/// Decode the packets of `stream`, switching keys after each NEWKEYS to
+/// those received from `openers` along with whether sequence numbers
+/// restart
+async fn read_packets(
+    mut stream: OwnedReadHalf,
+    mut reader: PacketReader,
+    packets: mpsc::Sender<Result<Vec<u8>>>,
+    mut openers: mpsc::UnboundedReceiver<(Opener, bool)>,
+) {
+    loop {
+        let ret = reader.read(&mut stream).await;
+        let (failed, newkeys) = match &ret {
+            Ok(payload) => (false, payload.first() == Some(&MSG_NEWKEYS)),
+            Err(_) => (true, false),
+        };
+        if packets.send(ret).await.is_err() || failed {
+            return;
+        }
+        if newkeys {
+            let Some((opener, strict)) = openers.recv().await else {
+                return;
+            };
+            reader.opener = opener;
+            if strict {
+                reader.seq = 0;
+            }
+        }
+    }
+}
+
+/// Verify the `signature` blob of `data` by the public key `key` of the
+/// signature algorithm `algorithm`
+pub(crate) fn verify_signature(
+    algorithm: &str,
+    key: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let mut key_fields = Reader::new(key);
+    let key_type = key_fields.utf8()?;
+    let mut signature = Reader::new(signature);
+    if signature.utf8()? != algorithm {
+        return Err(protocol_error(format!("SSH signature not made with {}", algorithm)));
+    }
+    let signature = signature.string()?;
+    let verified = match algorithm {
+        "ssh-ed25519" if key_type == algorithm => {
+            signature::UnparsedPublicKey::new(&signature::ED25519, key_fields.string()?)
+                .verify(data, signature)
+        }
+        "ecdsa-sha2-nistp256" if key_type == algorithm => {
+            let _curve = key_fields.string()?;
+            let point = key_fields.string()?;
+            let mut scalars = Reader::new(signature);
+            let (r, s) = (scalars.mpint()?, scalars.mpint()?);
+            if r.len() > 32 || s.len() > 32 {
+                return Err(protocol_error("Invalid ECDSA signature"));
+            }
+            let mut fixed = [0; 64];
+            fixed[32 - r.len()..32].copy_from_slice(r);
+            fixed[64 - s.len()..].copy_from_slice(s);
+            signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(data, &fixed)
+        }
+        "rsa-sha2-256" | "rsa-sha2-512" if key_type == "ssh-rsa" => {
+            let e = key_fields.mpint()?;
+            let n = key_fields.mpint()?;
+            let params = match algorithm {
+                "rsa-sha2-256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                _ => &signature::RSA_PKCS1_2048_8192_SHA512,
+            };
+            RsaPublicKeyComponents { n, e }.verify(params, data, signature)
+        }
+        _ => {
+            return Err(protocol_error(format!("SSH key of type {} for {}", key_type, algorithm)));
+        }
+    };
+    verified.map_err(|_| Error::new(ErrorKind::PermissionDenied, "Invalid SSH signature"))
+}
+
+/// The curve25519-sha256 shared secret of `secret` and `public`, encoded
+/// as the mpint hashed
+fn agree(secret: EphemeralPrivateKey, public: &[u8]) -> Result<Vec<u8>> {
+    agreement::agree_ephemeral(secret, &UnparsedPublicKey::new(&X25519, public), |shared| {
+        let mut mpint = vec![];
+        mpint.put_mpint(shared);
+        mpint
+    })
+    .map_err(|_| protocol_error("Invalid curve25519 key of the SSH peer"))
+}
+
+/// The algorithms a KEXINIT lists
+struct KexInit<'a> {
+    kex: Vec<&'a str>,
+    host_key: Vec<&'a str>,
+    /// Client to server first
+    ciphers: [Vec<&'a str>; 2],
+    compression: [Vec<&'a str>; 2],
+    /// Whether a guessed packet of the exchange follows
+    follows: bool,
+}
+
+impl<'a> KexInit<'a> {
+    fn parse(payload: &'a [u8]) -> Result<Self> {
+        let mut fields = Reader::new(payload);
+        /* The message number and the cookie */
+        fields.take(17)?;
+        let kex = fields.name_list()?;
+        let host_key = fields.name_list()?;
+        let ciphers = [fields.name_list()?, fields.name_list()?];
+        let _macs = [fields.name_list()?, fields.name_list()?];
+        let compression = [fields.name_list()?, fields.name_list()?];
+        let _languages = [fields.name_list()?, fields.name_list()?];
+        Ok(Self { kex, host_key, ciphers, compression, follows: fields.bool()? })
+    }
+}
+
+/// The first algorithm of the client the server supports
+fn negotiate<'a>(client: &[&'a str], server: &[&str], what: &str) -> Result<&'a str> {
+    client.iter().find(|name| server.contains(name)).copied().ok_or_else(|| {
+        Error::new(
+            ErrorKind::Unsupported,
+            format!("No {} in common with the SSH peer, which offers {}", what, server.join(",")),
+        )
+    })
+}
+
+/// Which end of the connection
+pub(crate) enum Role {
+    /// Trusting the server with this host key only
+    Client(SshHostKey),
+    /// Only offering ssh-ed25519
+    #[cfg(test)]
+    Server(signature::Ed25519KeyPair),
+}
+
+/// Where the key exchange is at
+enum Kex {
+    Done,
+    /// Our KEXINIT sent
+    Init(Vec<u8>),
+    /// Both KEXINIT sent, waiting for the ECDH message of the peer
+    Exchange {
+        ours: Vec<u8>,
+        theirs: Vec<u8>,
+        host_key_algorithm: &'static str,
+        /// Client to server first
+        ciphers: [Cipher; 2],
+        /// That of the client, once its ECDH_INIT is sent
+        ephemeral: Option<(EphemeralPrivateKey, Vec<u8>)>,
+    },
+    /// Our NEWKEYS sent
+    NewKeys,
+}
+
+pub(crate) struct Transport {
+    role: Role,
+    stream: OwnedWriteHalf,
+    writer: PacketWriter,
+    packets: mpsc::Receiver<Result<Vec<u8>>>,
+    openers: mpsc::UnboundedSender<(Opener, bool)>,
+    reader: JoinHandle<()>,
+    client_version: Vec<u8>,
+    server_version: Vec<u8>,
+    /// The exchange hash of the first exchange
+    session_id: Option<[u8; 32]>,
+    kex: Kex,
+    strict: bool,
+    /// Whether the next packet of the exchange is a wrong guess of the peer
+    ignore_guess: bool,
+    /// Messages sent during an exchange
+    held: Vec<Vec<u8>>,
+    /// Payload bytes either way since the last exchange
+    transferred: u64,
+}
+
+impl Transport {
+    /// Exchange versions and keys over `stream`
+    pub(crate) async fn handshake(stream: TcpStream, role: Role) -> Result<Self> {
+        let (mut read, mut write) = stream.into_split();
+        write.write_all(format!("{}\r\n", VERSION).as_bytes()).await?;
+        let mut reader = PacketReader { buf: vec![], opener: Opener::Plain, seq: 0 };
+        let peer_version = reader.read_version(&mut read).await?;
+        let (client_version, server_version) = match role {
+            Role::Client(_) => (VERSION.as_bytes().to_vec(), peer_version),
+            #[cfg(test)]
+            Role::Server(_) => (peer_version, VERSION.as_bytes().to_vec()),
+        };
+        let (packets_tx, packets) = mpsc::channel(16);
+        let (openers, openers_rx) = mpsc::unbounded_channel();
+        let mut transport = Self {
+            role,
+            stream: write,
+            writer: PacketWriter { sealer: Sealer::Plain, seq: 0, rng: SystemRandom::new() },
+            packets,
+            openers,
+            reader: tokio::spawn(read_packets(read, reader, packets_tx, openers_rx)),
+            client_version,
+            server_version,
+            session_id: None,
+            kex: Kex::Done,
+            strict: false,
+            ignore_guess: false,
+            held: vec![],
+            transferred: 0,
+        };
+        transport.send_kexinit().await?;
+        while transport.session_id.is_none() || !matches!(transport.kex, Kex::Done) {
+            let packet = transport.next_packet().await?;
+            transport.process(packet).await?;
+        }
+        Ok(transport)
+    }
+
+    #[inline]
+    fn is_client(&self) -> bool {
+        matches!(self.role, Role::Client(_))
+    }
+
+    #[inline]
+    pub(crate) fn session_id(&self) -> &[u8] {
+        self.session_id.as_ref().map_or(&[], |id| id)
+    }
+
+    /// The next packet of the peer, which can be cancelled
+    pub(crate) async fn next_packet(&mut self) -> Result<Vec<u8>> {
+        self.packets.recv().await.unwrap_or_else(|| Err(closed()))
+    }
+
+    /// The next message of the layers above, those of the transport being
+    /// handled on the way
+    pub(crate) async fn recv(&mut self) -> Result<Vec<u8>> {
+        loop {
+            let packet = self.next_packet().await?;
+            if let Some(message) = self.process(packet).await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// `packet` if it is a message of the layers above, None once the
+    /// transport handled it otherwise
+    pub(crate) async fn process(&mut self, packet: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let Some(&msg) = packet.first() else {
+            return Err(protocol_error("Empty SSH message"));
+        };
+        self.transferred += packet.len() as u64;
+        match msg {
+            MSG_DISCONNECT => {
+                let mut fields = Reader::new(&packet[1..]);
+                let reason = fields.u32()?;
+                Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    format!("Disconnected by the SSH peer ({}): {}", reason, fields.utf8()?),
+                ))
+            }
+            /* Strict or not, nothing else comes first from the peers around */
+            _ if self.session_id.is_none() && !(MSG_KEXINIT..=49).contains(&msg) => {
+                Err(protocol_error(format!("SSH message {} during the key exchange", msg)))
+            }
+            MSG_IGNORE | MSG_DEBUG | MSG_UNIMPLEMENTED => Ok(None),
+            _ if (MSG_KEX_ECDH_INIT..=49).contains(&msg)
+                && std::mem::take(&mut self.ignore_guess) =>
+            {
+                Ok(None)
+            }
+            MSG_KEXINIT => self.on_kexinit(packet).await.map(|()| None),
+            MSG_KEX_ECDH_INIT | MSG_KEX_ECDH_REPLY => {
+                self.on_ecdh(msg, &packet[1..]).await.map(|()| None)
+            }
+            MSG_NEWKEYS if matches!(self.kex, Kex::NewKeys) => {
+                self.kex = Kex::Done;
+                self.transferred = 0;
+                for message in std::mem::take(&mut self.held) {
+                    self.write(&message).await?;
+                }
+                Ok(None)
+            }
+            _ if (MSG_KEXINIT..=49).contains(&msg) => {
+                Err(protocol_error(format!("Unexpected SSH message {}", msg)))
+            }
+            _ => {
+                self.rekey_if_due().await?;
+                Ok(Some(packet))
+            }
+        }
+    }
+
+    /// Send `message`, held until the keys being exchanged are in use
+    /// unless it belongs to the exchange
+    pub(crate) async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+        if !matches!(self.kex, Kex::Done) && message[0] > 49 {
+            self.held.push(message);
+            return Ok(());
+        }
+        self.transferred += message.len() as u64;
+        self.write(&message).await?;
+        self.rekey_if_due().await
+    }
+
+    /// Say goodbye to the peer
+    pub(crate) async fn disconnect(&mut self) -> Result<()> {
+        let mut message = vec![MSG_DISCONNECT];
+        message.put_u32(DISCONNECT_BY_APPLICATION);
+        message.put_string("");
+        message.put_string("");
+        self.write(&message).await?;
+        self.stream.shutdown().await
+    }
+
+    /// Start exchanging keys again
+    #[cfg(test)]
+    pub(crate) async fn rekey(&mut self) -> Result<()> {
+        self.send_kexinit().await
+    }
+
+    async fn write(&mut self, message: &[u8]) -> Result<()> {
+        let packet = self.writer.encode(message);
+        self.stream.write_all(&packet).await
+    }
+
+    async fn rekey_if_due(&mut self) -> Result<()> {
+        match self.kex {
+            Kex::Done if self.transferred >= REKEY_BYTES => self.send_kexinit().await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn send_kexinit(&mut self) -> Result<()> {
+        let (strict, host_keys) = match self.role {
+            Role::Client(_) => (STRICT_KEX_CLIENT, &HOST_KEY_ALGORITHMS[..]),
+            #[cfg(test)]
+            Role::Server(_) => (STRICT_KEX_SERVER, &HOST_KEY_ALGORITHMS[..1]),
+        };
+        let mut kex = KEX_ALGORITHMS.to_vec();
+        /* Only meaningful in the first exchange */
+        if self.session_id.is_none() {
+            kex.push(strict);
+        }
+        let ciphers = Cipher::ALL.map(Cipher::name).join(",");
+
+        let mut message = vec![MSG_KEXINIT];
+        let mut cookie = [0; 16];
+        self.writer.rng.fill(&mut cookie).unwrap();
+        message.extend_from_slice(&cookie);
+        message.put_string(kex.join(","));
+        message.put_string(host_keys.join(","));
+        message.put_string(&ciphers);
+        message.put_string(&ciphers);
+        message.put_string(MAC_ALGORITHMS.join(","));
+        message.put_string(MAC_ALGORITHMS.join(","));
+        message.put_string("none");
+        message.put_string("none");
+        message.put_string("");
+        message.put_string("");
+        message.put_bool(false);
+        message.put_u32(0);
+        self.write(&message).await?;
+        self.kex = Kex::Init(message);
+        Ok(())
+    }
+
+    async fn on_kexinit(&mut self, theirs: Vec<u8>) -> Result<()> {
+        if matches!(self.kex, Kex::Done) {
+            self.send_kexinit().await?;
+        }
+        let Kex::Init(ours) = std::mem::replace(&mut self.kex, Kex::Done) else {
+            return Err(protocol_error("Unexpected SSH KEXINIT"));
+        };
+        let (host_key_algorithm, ciphers) = {
+            let (our_lists, their_lists) = (KexInit::parse(&ours)?, KexInit::parse(&theirs)?);
+            let (client, server) = match self.is_client() {
+                true => (&our_lists, &their_lists),
+                false => (&their_lists, &our_lists),
+            };
+            let kex = negotiate(&client.kex, &server.kex, "key exchange")?;
+            let host_key = negotiate(&client.host_key, &server.host_key, "host key algorithm")?;
+            let mut ciphers = [Cipher::ChaCha20Poly1305; 2];
+            for (i, cipher) in ciphers.iter_mut().enumerate() {
+                negotiate(&client.compression[i], &server.compression[i], "compression")?;
+                let name = negotiate(&client.ciphers[i], &server.ciphers[i], "cipher")?;
+                *cipher = Cipher::named(name).unwrap();
+            }
+            if self.session_id.is_none() {
+                self.strict = client.kex.contains(&STRICT_KEX_CLIENT)
+                    && server.kex.contains(&STRICT_KEX_SERVER);
+            }
+            self.ignore_guess = their_lists.follows
+                && (their_lists.kex.first() != Some(&kex)
+                    || their_lists.host_key.first() != Some(&host_key));
+            let host_key = HOST_KEY_ALGORITHMS.into_iter().find(|name| *name == host_key);
+            (host_key.unwrap(), ciphers)
+        };
+
+        let ephemeral = match self.is_client() {
+            true => {
+                let secret = EphemeralPrivateKey::generate(&X25519, &self.writer.rng).unwrap();
+                let public = secret.compute_public_key().unwrap().as_ref().to_vec();
+                let mut message = vec![MSG_KEX_ECDH_INIT];
+                message.put_string(&public);
+                self.write(&message).await?;
+                Some((secret, public))
+            }
+            false => None,
+        };
+        self.kex = Kex::Exchange { ours, theirs, host_key_algorithm, ciphers, ephemeral };
+        Ok(())
+    }
+
+    async fn on_ecdh(&mut self, msg: u8, body: &[u8]) -> Result<()> {
+        let Kex::Exchange { ours, theirs, host_key_algorithm, ciphers, ephemeral } =
+            std::mem::replace(&mut self.kex, Kex::Done)
+        else {
+            return Err(protocol_error(format!("Unexpected SSH message {}", msg)));
+        };
+        let mut fields = Reader::new(body);
+        match (&self.role, ephemeral) {
+            (Role::Client(trusted), Some((secret, public))) if msg == MSG_KEX_ECDH_REPLY => {
+                let host_key = fields.string()?;
+                let server_public = fields.string()?;
+                let signature = fields.string()?;
+                let fingerprint = SshHostKey::of(host_key);
+                if fingerprint != *trusted {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("Unexpected host key {} of the SSH server", fingerprint),
+                    ));
+                }
+                let shared = agree(secret, server_public)?;
+                let hash =
+                    self.exchange_hash(&ours, &theirs, host_key, &public, server_public, &shared);
+                verify_signature(host_key_algorithm, host_key, &hash, signature)?;
+                self.new_keys(&shared, &hash, ciphers).await
+            }
+            #[cfg(test)]
+            (Role::Server(key_pair), None) if msg == MSG_KEX_ECDH_INIT => {
+                use ring::signature::KeyPair;
+
+                let client_public = fields.string()?;
+                let secret = EphemeralPrivateKey::generate(&X25519, &self.writer.rng).unwrap();
+                let public = secret.compute_public_key().unwrap();
+                let shared = agree(secret, client_public)?;
+                let mut host_key = vec![];
+                host_key.put_string("ssh-ed25519");
+                host_key.put_string(key_pair.public_key());
+                let hash = self.exchange_hash(
+                    &theirs,
+                    &ours,
+                    &host_key,
+                    client_public,
+                    public.as_ref(),
+                    &shared,
+                );
+                let mut signature = vec![];
+                signature.put_string("ssh-ed25519");
+                signature.put_string(key_pair.sign(&hash));
+                let mut message = vec![MSG_KEX_ECDH_REPLY];
+                message.put_string(&host_key);
+                message.put_string(public);
+                message.put_string(&signature);
+                self.write(&message).await?;
+                self.new_keys(&shared, &hash, ciphers).await
+            }
+            _ => Err(protocol_error(format!("Unexpected SSH message {}", msg))),
+        }
+    }
+
+    /// The hash H of RFC 4253 the keys derive from, signed by the server
+    fn exchange_hash(
+        &self,
+        client_init: &[u8],
+        server_init: &[u8],
+        host_key: &[u8],
+        client_public: &[u8],
+        server_public: &[u8],
+        shared: &[u8],
+    ) -> [u8; 32] {
+        let mut hashed = vec![];
+        hashed.put_string(&self.client_version);
+        hashed.put_string(&self.server_version);
+        hashed.put_string(client_init);
+        hashed.put_string(server_init);
+        hashed.put_string(host_key);
+        hashed.put_string(client_public);
+        hashed.put_string(server_public);
+        hashed.extend_from_slice(shared);
+        ring::digest::digest(&SHA256, &hashed).as_ref().try_into().unwrap()
+    }
+
+    /// `len` bytes of the key of `letter` in RFC 4253
+    fn derive(&self, shared: &[u8], hash: &[u8; 32], letter: u8, len: usize) -> Vec<u8> {
+        let mut context = Context::new(&SHA256);
+        context.update(shared);
+        context.update(hash);
+        context.update(&[letter]);
+        context.update(self.session_id());
+        let mut key = context.finish().as_ref().to_vec();
+        while key.len() < len {
+            let mut context = Context::new(&SHA256);
+            context.update(shared);
+            context.update(hash);
+            context.update(&key);
+            key.extend_from_slice(context.finish().as_ref());
+        }
+        key.truncate(len);
+        key
+    }
+
+    async fn new_keys(
+        &mut self,
+        shared: &[u8],
+        hash: &[u8; 32],
+        ciphers: [Cipher; 2],
+    ) -> Result<()> {
+        if self.session_id.is_none() {
+            self.session_id = Some(*hash);
+        }
+        let [to_server, to_client] = ciphers;
+        let iv_to_server = self.derive(shared, hash, b'A', to_server.iv_len());
+        let iv_to_client = self.derive(shared, hash, b'B', to_client.iv_len());
+        let key_to_server = self.derive(shared, hash, b'C', to_server.key_len());
+        let key_to_client = self.derive(shared, hash, b'D', to_client.key_len());
+        let (sealer, opener) = match self.is_client() {
+            true => (
+                Sealer::new(to_server, &iv_to_server, &key_to_server),
+                Opener::new(to_client, &iv_to_client, &key_to_client),
+            ),
+            false => (
+                Sealer::new(to_client, &iv_to_client, &key_to_client),
+                Opener::new(to_server, &iv_to_server, &key_to_server),
+            ),
+        };
+        self.write(&[MSG_NEWKEYS]).await?;
+        self.writer.sealer = sealer;
+        if self.strict {
+            self.writer.seq = 0;
+        }
+        self.openers.send((opener, self.strict)).map_err(|_| closed())?;
+        self.kex = Kex::NewKeys;
+        Ok(())
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cipher, Encode, Opener, PacketReader, PacketWriter, Reader, Sealer};
+
+    use ring::rand::SystemRandom;
+
+    #[test]
+    fn test_mpint() -> std::io::Result<()> {
+        /* The examples of RFC 4251 */
+        for (value, encoded) in [
+            (&[][..], &[0, 0, 0, 0][..]),
+            (&[0, 0x80], &[0, 0, 0, 2, 0, 0x80]),
+            (
+                &[0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7],
+                &[0, 0, 0, 8, 0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7],
+            ),
+        ] {
+            let mut buf = vec![];
+            buf.put_mpint(value);
+            assert_eq!(buf, encoded);
+            let mut fields = Reader::new(&buf);
+            assert_eq!(fields.mpint()?, &value[value.iter().take_while(|b| **b == 0).count()..]);
+            assert!(fields.u32().is_err());
+        }
+        assert!(Reader::new(&[0, 0, 0, 1, 0xff]).mpint().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_packets() -> std::io::Result<()> {
+        let (iv, key) = ([7; 12], [9; 64]);
+        let pairs = [(Sealer::Plain, Opener::Plain)].into_iter().chain(Cipher::ALL.map(|cipher| {
+            let key = &key[..cipher.key_len()];
+            (Sealer::new(cipher, &iv, key), Opener::new(cipher, &iv, key))
+        }));
+        for (sealer, opener) in pairs {
+            let mut writer = PacketWriter { sealer, seq: 3, rng: SystemRandom::new() };
+            let mut reader = PacketReader { buf: vec![], opener, seq: 3 };
+            let payloads = [&b"\x5e hello"[..], &[0x15], &[0x5e; 1000]];
+            let stream: Vec<u8> =
+                payloads.iter().flat_map(|payload| writer.encode(payload)).collect();
+
+            /* Nothing until the whole packet is there */
+            reader.buf.extend_from_slice(&stream[..10]);
+            assert_eq!(reader.decode()?, None);
+            reader.buf.extend_from_slice(&stream[10..]);
+            for payload in payloads {
+                assert_eq!(reader.decode()?.as_deref(), Some(payload));
+            }
+            assert_eq!(reader.decode()?, None);
+
+            /* Altered on the way */
+            let mut packet = writer.encode(b"\x5e hello");
+            if !matches!(reader.opener, Opener::Plain) {
+                *packet.last_mut().unwrap() ^= 1;
+                reader.buf = packet;
+                assert!(reader.decode().is_err());
+            }
+        }
+        Ok(())
+    }
+}
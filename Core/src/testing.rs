@@ -0,0 +1,445 @@
+//! Test doubles failing on purpose, to exercise retries, failovers and
+//! kill-switches without a network or a tunnel driver
+//!
+//! [FlakyDialer] wraps a [Dialer] and plays a script of [Fault]s, one per
+//! dial: hanging then timing out, refusing, resetting the connection after
+//! some bytes or delivering them in small pieces. [FailingTun] is a tunnel
+//! interface in memory whose operations fail on demand and whose writes can
+//! be cut short.
+
+#[cfg(feature = "tun")]
+use core::ffi::{c_int, c_uint};
+#[cfg(feature = "tun")]
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use socket2::SockRef;
+use socks5::protocol::Address;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::{DialFuture, Dialer, loopback_pair};
+#[cfg(feature = "tun")]
+use crate::{PacketFraming, Tun, VTunConfig};
+
+/// What a [FlakyDialer] does with a dial
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Pass it on to the inner dialer
+    Pass,
+    /// Hang for that long, then fail with [ErrorKind::TimedOut]
+    Timeout(Duration),
+    /// Fail with [ErrorKind::ConnectionRefused] right away
+    Refuse,
+    /// Connect, then reset the connection once the destination sent that
+    /// many bytes, which the caller gets before the reset
+    ResetAfter(usize),
+    /// Connect, relaying the bytes both ways in pieces of at most that many
+    /// as writes cut short would
+    Fragment(usize),
+}
+
+/// A [Dialer] failing as scripted, passing the dials on once the script
+/// has run out
+#[derive(Debug)]
+pub struct FlakyDialer {
+    inner: Box<dyn Dialer>,
+    script: Mutex<VecDeque<Fault>>,
+    /// What the dials do once the script has run out
+    otherwise: Fault,
+    dials: AtomicUsize,
+}
+
+impl FlakyDialer {
+    pub fn new(inner: impl Dialer + 'static) -> Self {
+        Self {
+            inner: Box::new(inner),
+            script: Mutex::new(VecDeque::new()),
+            otherwise: Fault::Pass,
+            dials: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append `fault` to the script, for the dial after those scripted so
+    /// far
+    pub fn then(&mut self, fault: Fault) -> &mut Self {
+        self.script.get_mut().unwrap().push_back(fault);
+        self
+    }
+
+    /// Once the script has run out, dials do `fault` instead of passing
+    pub fn otherwise(&mut self, fault: Fault) -> &mut Self {
+        self.otherwise = fault;
+        self
+    }
+
+    /// How many dials were made, failed ones included
+    #[inline]
+    pub fn dials(&self) -> usize {
+        self.dials.load(Ordering::Relaxed)
+    }
+
+    fn next_fault(&self) -> Fault {
+        self.dials.fetch_add(1, Ordering::Relaxed);
+        self.script.lock().unwrap().pop_front().unwrap_or(self.otherwise)
+    }
+
+    async fn dial_faulty(&self, addr: &Address) -> Result<TcpStream> {
+        let fault = self.next_fault();
+        match fault {
+            Fault::Pass => return self.inner.dial(addr).await,
+            Fault::Timeout(duration) => {
+                tokio::time::sleep(duration).await;
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Dial to {:?} timed out", addr),
+                ));
+            }
+            Fault::Refuse => {
+                return Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!("Dial to {:?} refused", addr),
+                ));
+            }
+            Fault::ResetAfter(_) | Fault::Fragment(_) => {}
+        }
+        let upstream = self.inner.dial(addr).await?;
+        let (caller, ours) = loopback_pair().await?;
+        tokio::spawn(relay(ours, upstream, fault));
+        Ok(caller)
+    }
+}
+
+impl Dialer for FlakyDialer {
+    fn name(&self) -> &'static str {
+        "flaky"
+    }
+
+    fn dial<'a>(&'a self, addr: &'a Address) -> DialFuture<'a> {
+        Box::pin(self.dial_faulty(addr))
+    }
+}
+
+/// Copy `from` to `to` in pieces of at most `piece` bytes, up to `limit`
+/// bytes. Returns whether the limit cut the copy short.
+async fn pump<R, W>(from: &mut R, to: &mut W, piece: usize, limit: usize) -> Result<bool>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut left = limit;
+    loop {
+        if left == 0 {
+            return Ok(true);
+        }
+        let len = buf.len().min(left);
+        let n = from.read(&mut buf[..len]).await?;
+        if n == 0 {
+            to.shutdown().await?;
+            return Ok(false);
+        }
+        for chunk in buf[..n].chunks(piece) {
+            to.write_all(chunk).await?;
+            to.flush().await?;
+        }
+        left -= n;
+    }
+}
+
+/// Relay between the end of the caller and the destination as `fault`
+/// asks, until the destination is done
+async fn relay(mut caller: TcpStream, mut upstream: TcpStream, fault: Fault) -> Result<()> {
+    let (piece, limit) = match fault {
+        Fault::Fragment(piece) => (piece.max(1), usize::MAX),
+        Fault::ResetAfter(limit) => (usize::MAX, limit),
+        _ => (usize::MAX, usize::MAX),
+    };
+    let cut = {
+        let (mut caller_r, mut caller_w) = caller.split();
+        let (mut upstream_r, mut upstream_w) = upstream.split();
+        /* The caller being done writing still lets the destination answer */
+        let outbound = async {
+            pump(&mut caller_r, &mut upstream_w, piece, usize::MAX).await?;
+            std::future::pending::<Result<bool>>().await
+        };
+        tokio::select! {
+            cut = pump(&mut upstream_r, &mut caller_w, piece, limit) => cut?,
+            ret = outbound => ret?,
+        }
+    };
+    if cut {
+        /* Closing with a zero linger sends a RST */
+        SockRef::from(&caller).set_linger(Some(Duration::ZERO))?;
+    }
+    Ok(())
+}
+
+/// An operation of a [FailingTun]
+#[cfg(feature = "tun")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TunOp {
+    Ifname,
+    ConfigWith,
+    Ifindex,
+    Mtu,
+    SetMtu,
+    SetLabel,
+    Unconfigure,
+    ReadPacket,
+    WritePacket,
+}
+
+/// A tunnel interface in memory: packets pushed with
+/// [FailingTun::push_packet] are read and those written are kept
+#[cfg(feature = "tun")]
+#[derive(Debug)]
+pub struct FailingTun {
+    framing: PacketFraming,
+    mtu: Mutex<c_int>,
+    label: Mutex<Option<String>>,
+    /// The operations failing, with the kind of their error
+    failing: Mutex<HashMap<TunOp, ErrorKind>>,
+    /// Longest write taken whole, longer packets are cut short
+    write_limit: Mutex<Option<usize>>,
+    inbound: Mutex<VecDeque<Vec<u8>>>,
+    written: Mutex<Vec<Vec<u8>>>,
+    configured: Mutex<bool>,
+}
+
+#[cfg(feature = "tun")]
+impl FailingTun {
+    /// `op` fails with `kind` from now on, until [FailingTun::heal]ed
+    pub fn fail(&self, op: TunOp, kind: ErrorKind) -> &Self {
+        self.failing.lock().unwrap().insert(op, kind);
+        self
+    }
+
+    pub fn heal(&self, op: TunOp) -> &Self {
+        self.failing.lock().unwrap().remove(&op);
+        self
+    }
+
+    /// Only write the first `limit` bytes of the packets, none to write
+    /// them whole again
+    pub fn truncate_writes(&self, limit: Option<usize>) -> &Self {
+        *self.write_limit.lock().unwrap() = limit;
+        self
+    }
+
+    /// Queue `packet` to be read
+    pub fn push_packet(&self, packet: &[u8]) {
+        self.inbound.lock().unwrap().push_back(packet.to_vec());
+    }
+
+    /// The packets written so far, as much of them as was taken
+    pub fn written(&self) -> Vec<Vec<u8>> {
+        self.written.lock().unwrap().clone()
+    }
+
+    /// Whether it was configured and not unconfigured since
+    pub fn is_configured(&self) -> bool {
+        *self.configured.lock().unwrap()
+    }
+
+    pub fn label(&self) -> Option<String> {
+        self.label.lock().unwrap().clone()
+    }
+
+    fn check(&self, op: TunOp) -> Result<()> {
+        match self.failing.lock().unwrap().get(&op) {
+            Some(kind) => Err(Error::new(*kind, format!("{:?} failed on purpose", op))),
+            None => Ok(()),
+        }
+    }
+
+    /// Read the next packet pushed into `buf`, fails with
+    /// [ErrorKind::WouldBlock] when there is none as [crate::VTun] does
+    pub fn read_packet(&self, buf: &mut [u8]) -> Result<usize> {
+        self.check(TunOp::ReadPacket)?;
+        let packet = self.inbound.lock().unwrap().pop_front().ok_or(ErrorKind::WouldBlock)?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    /// Returns how much of `packet` was taken
+    pub fn write_packet(&self, packet: &[u8]) -> Result<usize> {
+        self.check(TunOp::WritePacket)?;
+        let len =
+            self.write_limit.lock().unwrap().map_or(packet.len(), |limit| limit.min(packet.len()));
+        self.written.lock().unwrap().push(packet[..len].to_vec());
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "tun")]
+impl Tun for FailingTun {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            framing: PacketFraming::Raw,
+            mtu: Mutex::new(1500),
+            label: Mutex::new(None),
+            failing: Mutex::new(HashMap::new()),
+            write_limit: Mutex::new(None),
+            inbound: Mutex::new(VecDeque::new()),
+            written: Mutex::new(vec![]),
+            configured: Mutex::new(false),
+        })
+    }
+
+    #[inline]
+    fn framing(&self) -> PacketFraming {
+        self.framing
+    }
+
+    fn ifname(&self) -> Result<String> {
+        self.check(TunOp::Ifname)?;
+        Ok(String::from("failtun0"))
+    }
+
+    fn config_with(&self, conf: VTunConfig) -> Result<()> {
+        self.check(TunOp::ConfigWith)?;
+        if let Some(mtu) = conf.mtu {
+            self.set_mtu(mtu as c_int)?;
+        }
+        if let Some(label) = conf.label {
+            self.set_label(&label)?;
+        }
+        *self.configured.lock().unwrap() = true;
+        Ok(())
+    }
+
+    fn ifindex(&self) -> Result<c_uint> {
+        self.check(TunOp::Ifindex)?;
+        Ok(u16::MAX as c_uint)
+    }
+
+    fn mtu(&self) -> Result<c_int> {
+        self.check(TunOp::Mtu)?;
+        Ok(*self.mtu.lock().unwrap())
+    }
+
+    fn set_mtu(&self, n: c_int) -> Result<()> {
+        self.check(TunOp::SetMtu)?;
+        *self.mtu.lock().unwrap() = n;
+        Ok(())
+    }
+
+    fn set_label(&self, label: &str) -> Result<()> {
+        self.check(TunOp::SetLabel)?;
+        *self.label.lock().unwrap() = Some(label.to_owned());
+        Ok(())
+    }
+
+    fn unconfigure(&self) -> Result<()> {
+        self.check(TunOp::Unconfigure)?;
+        *self.configured.lock().unwrap() = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, FlakyDialer};
+    use crate::{DialConfig, Dialer, Direct};
+
+    use std::io::ErrorKind;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use socks5::protocol::Address;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn spawn_echo() -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut tcp_stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = tcp_stream.split();
+                    tokio::io::copy(&mut r, &mut w).await
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    #[test]
+    fn test_flaky_dialer() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let addr = Address::IP(spawn_echo().await?);
+            let mut dialer = FlakyDialer::new(Direct::new(DialConfig::default()));
+            dialer
+                .then(Fault::Refuse)
+                .then(Fault::Timeout(Duration::from_millis(10)))
+                .then(Fault::ResetAfter(4))
+                .then(Fault::Fragment(3));
+
+            let e = dialer.dial(&addr).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+            let e = dialer.dial(&addr).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+            let mut tcp_stream = dialer.dial(&addr).await?;
+            tcp_stream.write_all(b"hello world").await?;
+            let mut echoed = [0u8; 4];
+            tcp_stream.read_exact(&mut echoed).await?;
+            assert_eq!(&echoed, b"hell");
+            let e = tcp_stream.read(&mut echoed).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ConnectionReset);
+
+            for _ in 0..2 {
+                let mut tcp_stream = dialer.dial(&addr).await?;
+                tcp_stream.write_all(b"hello world").await?;
+                tcp_stream.shutdown().await?;
+                let mut echoed = vec![];
+                tcp_stream.read_to_end(&mut echoed).await?;
+                assert_eq!(echoed, b"hello world");
+            }
+            assert_eq!(dialer.dials(), 5);
+
+            dialer.otherwise(Fault::Refuse);
+            assert!(dialer.dial(&addr).await.is_err());
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "tun")]
+    #[test]
+    fn test_failing_tun() -> std::io::Result<()> {
+        use super::{FailingTun, TunOp};
+        use crate::{Tun, VTunConfig};
+
+        let tun = FailingTun::new()?;
+        let conf = VTunConfig { mtu: Some(1400), label: Some("test".into()), ..Default::default() };
+        tun.config_with(conf.clone())?;
+        assert!(tun.is_configured());
+        assert_eq!((tun.mtu()?, tun.label()), (1400, Some(String::from("test"))));
+
+        tun.fail(TunOp::SetMtu, ErrorKind::PermissionDenied);
+        assert_eq!(tun.config_with(conf.clone()).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        tun.heal(TunOp::SetMtu);
+        tun.config_with(conf)?;
+
+        let mut buf = [0u8; 64];
+        assert_eq!(tun.read_packet(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        tun.push_packet(&[0x45, 0, 0, 20]);
+        assert_eq!(tun.read_packet(&mut buf)?, 4);
+        assert_eq!(tun.write_packet(&[0x45, 0, 0, 20])?, 4);
+        tun.truncate_writes(Some(2));
+        assert_eq!(tun.write_packet(&[0x45, 0, 0, 20])?, 2);
+        assert_eq!(tun.written(), vec![vec![0x45, 0, 0, 20], vec![0x45, 0]]);
+        tun.fail(TunOp::ReadPacket, ErrorKind::BrokenPipe);
+        assert_eq!(tun.read_packet(&mut buf).unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+        tun.destroy()?;
+        Ok(())
+    }
+}
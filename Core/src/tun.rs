@@ -1,13 +1,116 @@
 use core::ffi::{c_int, c_uint};
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 
-use crate::VTunConfig;
+use crate::{IpVersion, VTunConfig};
+
+/// What precedes the IP header of the packets read from and written to a
+/// tunnel interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketFraming {
+    /// Nothing, as with Linux tun devices opened with `IFF_NO_PI`
+    Raw,
+    /// The address family as a 4-byte integer in network byte order, as
+    /// with macOS utun devices
+    AfPrefixed,
+}
+
+impl PacketFraming {
+    /// That of the tunnel interfaces of this system
+    pub const fn of_tun() -> Self {
+        if cfg!(target_os = "macos") { Self::AfPrefixed } else { Self::Raw }
+    }
+
+    #[inline]
+    pub const fn header_len(self) -> usize {
+        match self {
+            Self::Raw => 0,
+            Self::AfPrefixed => 4,
+        }
+    }
+
+    /// The header to put before `packet`, the family being told by the
+    /// version of its IP header
+    pub fn header(self, packet: &[u8]) -> Result<[u8; 4]> {
+        let version = IpVersion::of(packet)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Not an IP packet"))?;
+        Ok((version.af() as u32).to_be_bytes())
+    }
+
+    /// The IP packet within `frame`, as read from the interface
+    pub fn strip(self, frame: &[u8]) -> Result<&[u8]> {
+        frame
+            .get(self.header_len()..)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Frame shorter than its header"))
+    }
+
+    /// `packet` as written to the interface
+    pub fn frame(self, packet: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Raw => Ok(packet.to_vec()),
+            Self::AfPrefixed => Ok([&self.header(packet)?[..], packet].concat()),
+        }
+    }
+}
 
 pub trait Tun {
-    fn new() -> Self;
+    fn new() -> Result<Self>
+    where
+        Self: Sized;
+    /// What the packets of the interface start with, before their IP header
+    fn framing(&self) -> PacketFraming;
     fn ifname(&self) -> Result<String>;
     fn config_with(&self, conf: VTunConfig) -> Result<()>;
     fn ifindex(&self) -> Result<c_uint>;
     fn mtu(&self) -> Result<c_int>;
     fn set_mtu(&self, n: c_int) -> Result<()>;
+    /// Attach a human readable description to the interface, where the OS
+    /// supports it
+    fn set_label(&self, label: &str) -> Result<()>;
+    /// Remove the IPv4 addresses assigned to the interface, which takes the
+    /// routes the system derived from them along, and bring it down
+    fn unconfigure(&self) -> Result<()>;
+
+    /// Unconfigure the interface and close it, which removes it from the
+    /// system. The fd is closed even if unconfiguring fails.
+    fn destroy(self) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let ret = self.unconfigure();
+        drop(self);
+        ret
+    }
+}
+
+/// Creating a tunnel interface needs root
+#[inline]
+pub fn is_privileged() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Check that this system can create tunnel interfaces, without creating one
+pub fn probe_tun_support() -> Result<()> {
+    #[cfg(target_os = "macos")]
+    return crate::UTun::probe_control();
+    #[cfg(target_os = "linux")]
+    return std::fs::OpenOptions::new().read(true).write(true).open("/dev/net/tun").map(drop);
+    #[allow(unreachable_code)]
+    Err(Error::new(ErrorKind::Unsupported, "No tunnel driver on this system"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PacketFraming;
+
+    #[test]
+    fn test_packet_framing() -> std::io::Result<()> {
+        let packet = [0x60, 0, 0, 0, 0, 0, 59, 64];
+        assert_eq!(PacketFraming::Raw.frame(&packet)?, packet);
+        let frame = PacketFraming::AfPrefixed.frame(&packet)?;
+        assert_eq!(&frame[..4], &(libc::AF_INET6 as u32).to_be_bytes());
+        assert_eq!(PacketFraming::AfPrefixed.strip(&frame)?, packet);
+        assert!(PacketFraming::AfPrefixed.frame(&[0x10, 0]).is_err());
+        assert!(PacketFraming::AfPrefixed.strip(&[0, 0]).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,202 @@
+//! Packets of a tunnel interface framed with tokio_util's codecs
+//!
+//! [TunPacketCodec] decodes and encodes the byte stream of a [TunStream]:
+//! each frame is prefixed with its length as a 2-byte integer in network
+//! byte order, and starts with the header of its [PacketFraming], which the
+//! codec strips and prepends. That of a [TunStream] is
+//! [PacketFraming::Raw], the stream having stripped it already, frames of
+//! macOS utun devices relayed as they are read are
+//! [PacketFraming::AfPrefixed]. Packets come out tagged with their
+//! [IpVersion], and those longer than the MTU are refused on the way in.
+//!
+//! [TunStream]: crate::TunStream
+
+use std::io::{Error, ErrorKind, Result};
+
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{PacketFraming, TUN_STREAM_LEN_PREFIX};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    /// That of the IP header `packet` starts with
+    pub fn of(packet: &[u8]) -> Option<Self> {
+        match packet.first().map(|b| b >> 4) {
+            Some(4) => Some(Self::V4),
+            Some(6) => Some(Self::V6),
+            _ => None,
+        }
+    }
+
+    /// The address family of the AF header
+    #[inline]
+    pub const fn af(self) -> libc::c_int {
+        match self {
+            Self::V4 => libc::AF_INET,
+            Self::V6 => libc::AF_INET6,
+        }
+    }
+}
+
+/// An IP packet, without the framing of the interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunPacket {
+    version: IpVersion,
+    data: Bytes,
+}
+
+impl TunPacket {
+    /// Fails with [ErrorKind::InvalidInput] unless `data` starts with an IP
+    /// header
+    pub fn new(data: impl Into<Bytes>) -> Result<Self> {
+        let data = data.into();
+        let version = IpVersion::of(&data)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Not an IP packet"))?;
+        Ok(Self { version, data })
+    }
+
+    #[inline]
+    pub fn version(&self) -> IpVersion {
+        self.version
+    }
+
+    #[inline]
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    #[inline]
+    pub fn into_data(self) -> Bytes {
+        self.data
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TunPacketCodec {
+    framing: PacketFraming,
+    mtu: usize,
+}
+
+impl TunPacketCodec {
+    /// Frames carry the header of `framing`, packets longer than `mtu` are
+    /// not encoded
+    pub fn new(framing: PacketFraming, mtu: usize) -> Self {
+        Self { framing, mtu }
+    }
+
+    #[inline]
+    pub fn framing(&self) -> PacketFraming {
+        self.framing
+    }
+
+    #[inline]
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+impl Decoder for TunPacketCodec {
+    type Item = TunPacket;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let Some(prefix) = src.first_chunk::<TUN_STREAM_LEN_PREFIX>() else {
+            return Ok(None);
+        };
+        let len = u16::from_be_bytes(*prefix) as usize;
+        if src.len() < TUN_STREAM_LEN_PREFIX + len {
+            src.reserve(TUN_STREAM_LEN_PREFIX + len - src.len());
+            return Ok(None);
+        }
+        src.advance(TUN_STREAM_LEN_PREFIX);
+        let mut frame = src.split_to(len);
+        let packet = self.framing.strip(&frame)?;
+        let version = IpVersion::of(packet)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not an IP packet"))?;
+        if self.framing == PacketFraming::AfPrefixed && frame[..4] != self.framing.header(packet)? {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Address family {:?} of an {:?} packet", &frame[..4], version),
+            ));
+        }
+        frame.advance(self.framing.header_len());
+        Ok(Some(TunPacket { version, data: frame.freeze() }))
+    }
+}
+
+impl Encoder<TunPacket> for TunPacketCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: TunPacket, dst: &mut BytesMut) -> Result<()> {
+        let len = item.data.len();
+        if len > self.mtu {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Packet of {} bytes over the MTU of {}", len, self.mtu),
+            ));
+        }
+        let frame_len = u16::try_from(self.framing.header_len() + len)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Packet of {} bytes", len)))?;
+        dst.reserve(TUN_STREAM_LEN_PREFIX + frame_len as usize);
+        dst.put_u16(frame_len);
+        if self.framing == PacketFraming::AfPrefixed {
+            dst.put_u32(item.version.af() as u32);
+        }
+        dst.put_slice(&item.data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpVersion, TunPacket, TunPacketCodec};
+    use crate::PacketFraming;
+
+    use std::io::ErrorKind;
+
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[test]
+    fn test_tun_packet_codec() -> std::io::Result<()> {
+        let packet = TunPacket::new(vec![0x60, 0, 0, 0, 0, 0, 59, 64])?;
+        assert_eq!(packet.version(), IpVersion::V6);
+        assert!(TunPacket::new(vec![0x10, 0]).is_err());
+
+        for framing in [PacketFraming::Raw, PacketFraming::AfPrefixed] {
+            let mut codec = TunPacketCodec::new(framing, 1500);
+            let mut buf = BytesMut::new();
+            codec.encode(packet.clone(), &mut buf)?;
+            let frame_len = framing.header_len() + packet.data().len();
+            assert_eq!(buf.len(), 2 + frame_len);
+            assert_eq!(&buf[..2], &(frame_len as u16).to_be_bytes());
+            assert_eq!(&buf[2 + framing.header_len()..], &packet.data()[..]);
+
+            /* Nothing until the whole frame is there */
+            let mut partial = buf.split_to(5);
+            assert_eq!(codec.decode(&mut partial)?, None);
+            partial.unsplit(buf);
+            assert_eq!(codec.decode(&mut partial)?, Some(packet.clone()));
+            assert!(partial.is_empty());
+        }
+
+        let mut codec = TunPacketCodec::new(PacketFraming::Raw, 4);
+        let e = codec.encode(packet, &mut BytesMut::new()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert_eq!(e.to_string(), "Packet of 8 bytes over the MTU of 4");
+
+        /* An IPv4 packet said to be IPv6 */
+        let mut codec = TunPacketCodec::new(PacketFraming::AfPrefixed, 1500);
+        let mut buf = BytesMut::from(&[0, 6][..]);
+        buf.extend_from_slice(&(libc::AF_INET6 as u32).to_be_bytes());
+        buf.extend_from_slice(&[0x45, 0]);
+        assert!(codec.decode(&mut buf).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! A tunnel interface as a tokio byte stream
+//!
+//! [TunStream] reads and writes the IP packets of a [VTun] through
+//! [AsyncRead] and [AsyncWrite], so that it composes with the rest of the
+//! async ecosystem. Each packet crosses the stream prefixed with its length
+//! as a 2-byte integer in network byte order, which keeps the packets apart
+//! whatever the size of the reads and writes, e.g. for a length delimited
+//! codec to frame them back.
+
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::VTun;
+
+/// Bytes of the length before each packet
+pub const TUN_STREAM_LEN_PREFIX: usize = 2;
+
+/// Longest packet a 2-byte length can tell
+const MAX_PACKET_LEN: usize = u16::MAX as usize;
+
+#[derive(Debug)]
+pub struct TunStream {
+    tun: AsyncFd<VTun>,
+    /// The packet last read, with its length, and how much of it has been
+    /// handed out
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Bytes written but not yet making up a whole packet
+    write_buf: Vec<u8>,
+}
+
+impl TunStream {
+    /// Switch `tun` to non-blocking mode and register it with the reactor,
+    /// which must be that of the current tokio runtime
+    pub fn new(tun: VTun) -> Result<Self> {
+        if crate::set_nonblock(tun.as_raw_fd()) < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Self { tun: AsyncFd::new(tun)?, read_buf: vec![], read_pos: 0, write_buf: vec![] })
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &VTun {
+        self.tun.get_ref()
+    }
+
+    pub fn into_inner(self) -> VTun {
+        self.tun.into_inner()
+    }
+
+    /// Write the whole packets at the start of `write_buf`
+    fn poll_write_packets(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while let Some(prefix) = self.write_buf.first_chunk::<TUN_STREAM_LEN_PREFIX>() {
+            let len = u16::from_be_bytes(*prefix) as usize;
+            if len == 0 {
+                return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, "Empty packet")));
+            }
+            let end = TUN_STREAM_LEN_PREFIX + len;
+            if self.write_buf.len() < end {
+                break;
+            }
+            let mut guard = ready!(self.tun.poll_write_ready(cx))?;
+            let packet = &self.write_buf[TUN_STREAM_LEN_PREFIX..end];
+            match guard.try_io(|tun| tun.get_ref().write_packet(packet)) {
+                Ok(ret) => {
+                    ret?;
+                    self.write_buf.drain(..end);
+                }
+                Err(_would_block) => continue,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for TunStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while this.read_pos == this.read_buf.len() {
+            let mut guard = ready!(this.tun.poll_read_ready(cx))?;
+            this.read_buf.resize(TUN_STREAM_LEN_PREFIX + MAX_PACKET_LEN, 0);
+            let packet = &mut this.read_buf[TUN_STREAM_LEN_PREFIX..];
+            match guard.try_io(|tun| tun.get_ref().read_packet(packet)) {
+                Ok(ret) => {
+                    let len = ret?;
+                    /* An empty read would look like the end of the stream */
+                    this.read_buf.truncate(if len > 0 { TUN_STREAM_LEN_PREFIX + len } else { 0 });
+                    if len > 0 {
+                        this.read_buf[..TUN_STREAM_LEN_PREFIX]
+                            .copy_from_slice(&(len as u16).to_be_bytes());
+                    }
+                }
+                Err(_would_block) => this.read_buf.clear(),
+            }
+            this.read_pos = 0;
+        }
+        let len = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TunStream {
+    /// Takes `buf` once the whole packets written before it are, the
+    /// interface being ready or not
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_packets(cx))?;
+        this.write_buf.extend_from_slice(buf);
+        /* Pending is fine, the packets go on the next write or flush */
+        if let Poll::Ready(Err(e)) = this.poll_write_packets(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_write_packets(cx)
+    }
+
+    /// A packet partially written is left out
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TUN_STREAM_LEN_PREFIX, TunStream};
+    use crate::VTun;
+
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixDatagram;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_tun_stream() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            /* A datagram socket delivers packets as a tunnel interface
+             * without framing does */
+            let (tun_side, peer) = UnixDatagram::pair()?;
+            let tun = unsafe { VTun::from_raw_fd(tun_side.into_raw_fd()) };
+            let mut stream = TunStream::new(tun)?;
+
+            let packet = [0x45, 0, 0, 20, 1, 2, 3, 4];
+            peer.send(&packet)?;
+            peer.send(&packet[..4])?;
+            let mut prefix = [0u8; TUN_STREAM_LEN_PREFIX];
+            stream.read_exact(&mut prefix).await?;
+            assert_eq!(u16::from_be_bytes(prefix), packet.len() as u16);
+            /* In pieces smaller than the packet */
+            let mut read = [0u8; 8];
+            stream.read_exact(&mut read[..3]).await?;
+            stream.read_exact(&mut read[3..]).await?;
+            assert_eq!(read, packet);
+            let mut next = [0u8; TUN_STREAM_LEN_PREFIX + 4];
+            stream.read_exact(&mut next).await?;
+            assert_eq!(next, [0, 4, 0x45, 0, 0, 20]);
+
+            /* A packet across two writes, then two in one */
+            let mut bytes = (packet.len() as u16).to_be_bytes().to_vec();
+            bytes.extend_from_slice(&packet);
+            stream.write_all(&bytes[..5]).await?;
+            stream.write_all(&bytes[5..]).await?;
+            stream.write_all(&[0, 2, 0x60, 0, 0, 1, 0x45]).await?;
+            stream.flush().await?;
+            let mut recv = [0u8; 64];
+            let len = peer.recv(&mut recv)?;
+            assert_eq!(&recv[..len], packet);
+            let len = peer.recv(&mut recv)?;
+            assert_eq!(&recv[..len], [0x60, 0]);
+            let len = peer.recv(&mut recv)?;
+            assert_eq!(&recv[..len], [0x45]);
+
+            assert!(stream.write_all(&[0, 0]).await.is_err());
+            Ok(())
+        })
+    }
+}
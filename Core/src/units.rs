@@ -0,0 +1,297 @@
+//! Human-friendly quantities in configuration values: sizes such as
+//! `"16KiB"`, rates such as `"10MB/s"` and durations such as `"1h30m"`.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("B", 1),
+    ("K", 1000),
+    ("KB", 1000),
+    ("KIB", 1 << 10),
+    ("M", 1000 * 1000),
+    ("MB", 1000 * 1000),
+    ("MIB", 1 << 20),
+    ("G", 1000 * 1000 * 1000),
+    ("GB", 1000 * 1000 * 1000),
+    ("GIB", 1 << 30),
+    ("T", 1000 * 1000 * 1000 * 1000),
+    ("TB", 1000 * 1000 * 1000 * 1000),
+    ("TIB", 1 << 40),
+];
+
+const DURATION_UNITS: &[(&str, Duration)] = &[
+    ("ns", Duration::from_nanos(1)),
+    ("us", Duration::from_micros(1)),
+    ("ms", Duration::from_millis(1)),
+    ("s", Duration::from_secs(1)),
+    ("m", Duration::from_secs(60)),
+    ("h", Duration::from_secs(60 * 60)),
+    ("d", Duration::from_secs(24 * 60 * 60)),
+];
+
+/// Split `"1.5MB"` into `("1.5", "MB")`
+fn split_number(s: &str) -> (&str, &str) {
+    let end = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    (&s[..end], s[end..].trim_start())
+}
+
+fn parse_number(number: &str, whole: &str) -> Result<f64, String> {
+    if number.is_empty() {
+        return Err(format!("invalid value {:?}, expected a number, e.g. \"10\"", whole));
+    }
+    number.parse::<f64>().map_err(|_| format!("invalid number {:?} in {:?}", number, whole))
+}
+
+/// Parse a byte size, e.g. `"512"`, `"10KB"` or `"1.5 GiB"`. Units are
+/// case-insensitive, K/M/G/T are decimal and KiB/MiB/GiB/TiB are binary.
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, unit) = split_number(s);
+    let number = parse_number(number, s)?;
+    let multiplier = match unit {
+        "" => 1,
+        unit => SIZE_UNITS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or_else(|| {
+                format!("unknown size unit {:?} in {:?}, expected one of B, KB, KiB, MB, MiB, GB, GiB, TB, TiB", unit, s)
+            })?,
+    };
+    let bytes = number * multiplier as f64;
+    if bytes > u64::MAX as f64 {
+        return Err(format!("size {:?} is too large", s));
+    }
+    Ok(bytes.round() as u64)
+}
+
+/// Parse a duration made of one or more `<number><unit>` parts, e.g.
+/// `"250ms"`, `"30s"` or `"1h30m"`, with units ns, us, ms, s, m, h and d
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let whole = s.trim();
+    if whole.is_empty() {
+        return Err(String::from("empty duration, expected e.g. \"30s\""));
+    }
+    let mut rest = whole;
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let (number, after) = split_number(rest);
+        let number = parse_number(number, whole)?;
+        let unit_len = after.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(after.len());
+        let unit = &after[..unit_len];
+        if unit.is_empty() {
+            return Err(format!(
+                "missing unit in duration {:?}, expected one of ns, us, ms, s, m, h, d",
+                whole
+            ));
+        }
+        let (_, unit_duration) =
+            DURATION_UNITS.iter().find(|(name, _)| *name == unit).ok_or_else(|| {
+                format!(
+                    "unknown duration unit {:?} in {:?}, expected one of ns, us, ms, s, m, h, d",
+                    unit, whole
+                )
+            })?;
+        total += Duration::try_from_secs_f64(unit_duration.as_secs_f64() * number)
+            .map_err(|_| format!("duration {:?} is out of range", whole))?;
+        rest = after[unit_len..].trim_start();
+    }
+    Ok(total)
+}
+
+fn format_byte_size(f: &mut Formatter<'_>, bytes: u64) -> std::fmt::Result {
+    const BINARY_UNITS: [(&str, u64); 4] =
+        [("TiB", 1 << 40), ("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+    const DECIMAL_UNITS: [(&str, u64); 4] = [
+        ("TB", 1000 * 1000 * 1000 * 1000),
+        ("GB", 1000 * 1000 * 1000),
+        ("MB", 1000 * 1000),
+        ("KB", 1000),
+    ];
+    for (name, multiplier) in BINARY_UNITS.iter().chain(DECIMAL_UNITS.iter()) {
+        if bytes != 0 && bytes.is_multiple_of(*multiplier) {
+            return write!(f, "{}{}", bytes / multiplier, name);
+        }
+    }
+    write!(f, "{}B", bytes)
+}
+
+/// A number of bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    #[inline]
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_byte_size(s).map(Self)
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        format_byte_size(f, self.0)
+    }
+}
+
+/// A number of bytes per second, written with a `/s` suffix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteRate(pub u64);
+
+impl ByteRate {
+    #[inline]
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteRate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let size = s.trim().strip_suffix("/s").ok_or_else(|| {
+            format!("invalid rate {:?}, expected a size per second, e.g. \"10MB/s\"", s)
+        })?;
+        parse_byte_size(size).map(Self)
+    }
+}
+
+impl Display for ByteRate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        format_byte_size(f, self.0)?;
+        f.write_str("/s")
+    }
+}
+
+/// A [Duration] written like `"30s"`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    #[inline]
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl From<HumanDuration> for Duration {
+    fn from(value: HumanDuration) -> Self {
+        value.0
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_duration(s).map(Self)
+    }
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let nanos = self.0.as_nanos();
+        for (name, unit) in DURATION_UNITS.iter().rev() {
+            if nanos != 0 && nanos.is_multiple_of(unit.as_nanos()) {
+                return write!(f, "{}{}", nanos / unit.as_nanos(), name);
+            }
+        }
+        write!(f, "{}ns", nanos)
+    }
+}
+
+/// Deserializes from a string, or from an integer when `integer` says what
+/// a bare integer means
+struct StrVisitor<T> {
+    expecting: &'static str,
+    integer: Option<fn(u64) -> T>,
+}
+
+impl<T: FromStr<Err = String>> Visitor<'_> for StrVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<T, E> {
+        match self.integer {
+            Some(from_integer) => Ok(from_integer(v)),
+            None => Err(E::invalid_type(de::Unexpected::Unsigned(v), &self)),
+        }
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<T, E> {
+        match u64::try_from(v) {
+            Ok(v) => self.visit_u64(v),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Signed(v), &self)),
+        }
+    }
+}
+
+macro_rules! impl_serde_via_str {
+    ($ty:ty, $expecting:literal, $integer:expr) => {
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer
+                    .deserialize_any(StrVisitor { expecting: $expecting, integer: $integer })
+            }
+        }
+    };
+}
+
+impl_serde_via_str!(ByteSize, "a size such as \"16KiB\" or a number of bytes", Some(ByteSize));
+impl_serde_via_str!(ByteRate, "a rate such as \"10MB/s\"", None);
+impl_serde_via_str!(HumanDuration, "a duration such as \"30s\" or \"1h30m\"", None);
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteRate, ByteSize, HumanDuration, parse_byte_size, parse_duration};
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!(parse_byte_size("512"), Ok(512));
+        assert_eq!(parse_byte_size("10KB"), Ok(10_000));
+        assert_eq!(parse_byte_size("16 KiB"), Ok(16 * 1024));
+        assert_eq!(parse_byte_size("1.5mib"), Ok(1536 * 1024));
+        assert!(parse_byte_size("10XB").unwrap_err().contains("unknown size unit \"XB\""));
+        assert!(parse_byte_size("MB").is_err());
+
+        assert_eq!("10MB/s".parse::<ByteRate>(), Ok(ByteRate(10_000_000)));
+        assert!("10MB".parse::<ByteRate>().is_err());
+        assert_eq!(ByteSize(16 * 1024).to_string(), "16KiB");
+        assert_eq!(ByteRate(10_000_000).to_string(), "10MB/s");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert!(parse_duration("30").unwrap_err().contains("missing unit"));
+        assert!(parse_duration("3 weeks").unwrap_err().contains("unknown duration unit"));
+        assert_eq!(HumanDuration(Duration::from_secs(5400)).to_string(), "90m");
+    }
+}
@@ -0,0 +1,311 @@
+//! Selection between several upstream proxies by their health
+//!
+//! Every connection attempt is recorded passively, and
+//! [UpstreamPool::health_check] adds periodic probes, so that the connect
+//! latency and the failures of each upstream are known. A [SelectStrategy]
+//! then orders the upstreams to try for a destination, those failing
+//! repeatedly coming last.
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::DialConfig;
+
+/// Consecutive failures after which an upstream is unhealthy, until it
+/// connects again
+pub const UNHEALTHY_AFTER: u32 = 3;
+
+/// How the upstreams to try for a destination are ordered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SelectStrategy {
+    /// Lowest connect latency first, unmeasured upstreams last
+    Fastest,
+    /// In the configured order
+    #[default]
+    Fallback,
+    /// Starting from the next upstream on each connection
+    RoundRobin,
+    /// Starting from the upstream the destination host hashes to, which
+    /// stays the same for most hosts when upstreams are added or removed
+    ConsistentHash,
+}
+
+impl FromStr for SelectStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "fastest" => Ok(Self::Fastest),
+            "fallback" => Ok(Self::Fallback),
+            "round_robin" => Ok(Self::RoundRobin),
+            "consistent_hash" => Ok(Self::ConsistentHash),
+            s => Err(format!(
+                "{}, expected \"fastest\", \"fallback\", \"round_robin\" or \"consistent_hash\"",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for SelectStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fastest => f.write_str("fastest"),
+            Self::Fallback => f.write_str("fallback"),
+            Self::RoundRobin => f.write_str("round_robin"),
+            Self::ConsistentHash => f.write_str("consistent_hash"),
+        }
+    }
+}
+
+impl TryFrom<String> for SelectStrategy {
+    type Error = String;
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SelectStrategy> for String {
+    fn from(value: SelectStrategy) -> Self {
+        value.to_string()
+    }
+}
+
+/// Counters of the connections to one upstream
+#[derive(Debug, Default)]
+pub struct UpstreamHealth {
+    successes: AtomicU64,
+    failures: AtomicU64,
+    consecutive_failures: AtomicU32,
+    /// Moving average of the connect latency in microseconds, 0 until the
+    /// first success
+    latency_us: AtomicU64,
+}
+
+impl UpstreamHealth {
+    fn record_success(&self, latency: Duration) {
+        self.successes.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        let sample = (latency.as_micros() as u64).max(1);
+        let _ = self.latency_us.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+            Some(if avg == 0 { sample } else { (avg * 7 + sample) / 8 })
+        });
+    }
+
+    fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn successes(&self) -> u64 {
+        self.successes.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// The average connect latency, None until a connection succeeded
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    #[inline]
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) < UNHEALTHY_AFTER
+    }
+}
+
+/// Upstreams along with their health, clones share the same counters
+#[derive(Debug, Clone)]
+pub struct UpstreamPool {
+    upstreams: Arc<[SocketAddr]>,
+    health: Arc<[UpstreamHealth]>,
+    strategy: SelectStrategy,
+    next: Arc<AtomicUsize>,
+}
+
+impl UpstreamPool {
+    pub fn new(upstreams: Vec<SocketAddr>, strategy: SelectStrategy) -> Self {
+        let health = upstreams.iter().map(|_| UpstreamHealth::default()).collect();
+        Self { upstreams: upstreams.into(), health, strategy, next: Arc::default() }
+    }
+
+    #[inline]
+    pub fn strategy(&self) -> SelectStrategy {
+        self.strategy
+    }
+
+    /// The upstreams with their health, in the configured order
+    pub fn upstreams(&self) -> impl Iterator<Item = (SocketAddr, &UpstreamHealth)> {
+        self.upstreams.iter().copied().zip(self.health.iter())
+    }
+
+    /// The upstreams to try in turn for a connection to `host`, the
+    /// unhealthy ones last
+    pub fn candidates(&self, host: &str) -> Vec<SocketAddr> {
+        let len = self.upstreams.len();
+        let mut order = (0..len).collect::<Vec<_>>();
+        match self.strategy {
+            SelectStrategy::Fallback => {}
+            SelectStrategy::Fastest => order.sort_by_key(|&i| {
+                self.health[i].latency().map_or((1, Duration::ZERO), |latency| (0, latency))
+            }),
+            SelectStrategy::RoundRobin if len > 0 => {
+                order.rotate_left(self.next.fetch_add(1, Ordering::Relaxed) % len)
+            }
+            SelectStrategy::RoundRobin => {}
+            SelectStrategy::ConsistentHash => {
+                /* Rendezvous hashing: the highest weight of host and
+                 * upstream goes first */
+                order.sort_by_key(|&i| {
+                    let mut hasher = DefaultHasher::new();
+                    (host, self.upstreams[i]).hash(&mut hasher);
+                    std::cmp::Reverse(hasher.finish())
+                })
+            }
+        }
+        /* Stable, so the order within both groups is kept */
+        order.sort_by_key(|&i| !self.health[i].is_healthy());
+        order.into_iter().map(|i| self.upstreams[i]).collect()
+    }
+
+    fn health_of(&self, upstream: SocketAddr) -> Option<&UpstreamHealth> {
+        self.upstreams().find(|(addr, _)| *addr == upstream).map(|(_, health)| health)
+    }
+
+    /// Record that connecting to `upstream` took `latency`
+    pub fn record_success(&self, upstream: SocketAddr, latency: Duration) {
+        if let Some(health) = self.health_of(upstream) {
+            health.record_success(latency);
+        }
+    }
+
+    pub fn record_failure(&self, upstream: SocketAddr) {
+        if let Some(health) = self.health_of(upstream) {
+            health.record_failure();
+        }
+    }
+
+    /// Connect to every upstream at once, recording how it went
+    pub async fn probe(&self, dial_config: &DialConfig, timeout: Duration) {
+        let mut probes = JoinSet::new();
+        for (i, upstream) in self.upstreams.iter().copied().enumerate() {
+            let (pool, dial_config) = (self.clone(), *dial_config);
+            probes.spawn(async move {
+                let start = Instant::now();
+                match tokio::time::timeout(timeout, dial_config.connect(upstream)).await {
+                    Ok(Ok(_)) => pool.health[i].record_success(start.elapsed()),
+                    Ok(Err(e)) => {
+                        crate::debug_println!("Probing upstream {} failed: {}", upstream, e);
+                        pool.health[i].record_failure()
+                    }
+                    Err(_) => {
+                        crate::debug_println!("Probing upstream {} timed out", upstream);
+                        pool.health[i].record_failure()
+                    }
+                }
+            });
+        }
+        while probes.join_next().await.is_some() {}
+    }
+
+    /// Probe the upstreams every `interval` until `shutdown` completes
+    pub async fn health_check(
+        &self,
+        dial_config: DialConfig,
+        interval: Duration,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        tokio::pin!(shutdown);
+        loop {
+            self.probe(&dial_config, interval).await;
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.as_mut() => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SelectStrategy, UNHEALTHY_AFTER, UpstreamPool};
+    use crate::DialConfig;
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    fn addrs(ports: &[u16]) -> Vec<SocketAddr> {
+        ports.iter().map(|port| SocketAddr::from(([127, 0, 0, 1], *port))).collect()
+    }
+
+    #[test]
+    fn test_select_strategy() {
+        let upstreams = addrs(&[1, 2, 3]);
+        let pool = UpstreamPool::new(upstreams.clone(), SelectStrategy::Fallback);
+        assert_eq!(pool.candidates("example.com"), upstreams);
+        /* Failing repeatedly moves the first upstream last */
+        for _ in 0..UNHEALTHY_AFTER {
+            pool.record_failure(upstreams[0]);
+        }
+        assert_eq!(pool.candidates("example.com"), addrs(&[2, 3, 1]));
+        pool.record_success(upstreams[0], Duration::from_millis(5));
+        assert_eq!(pool.candidates("example.com"), upstreams);
+
+        let pool = UpstreamPool::new(upstreams.clone(), SelectStrategy::Fastest);
+        pool.record_success(upstreams[0], Duration::from_millis(30));
+        pool.record_success(upstreams[2], Duration::from_millis(10));
+        assert_eq!(pool.candidates("example.com"), addrs(&[3, 1, 2]));
+
+        let pool = UpstreamPool::new(upstreams.clone(), SelectStrategy::RoundRobin);
+        let firsts = (0..3).map(|_| pool.candidates("example.com")[0]).collect::<Vec<_>>();
+        assert_eq!(firsts, upstreams);
+
+        let pool = UpstreamPool::new(upstreams.clone(), SelectStrategy::ConsistentHash);
+        let first = pool.candidates("example.com")[0];
+        assert_eq!(pool.candidates("example.com")[0], first);
+        /* Removing another upstream doesn't move the host */
+        let others = upstreams.iter().copied().filter(|addr| *addr != first).collect::<Vec<_>>();
+        let pool = UpstreamPool::new(vec![first, others[0]], SelectStrategy::ConsistentHash);
+        assert_eq!(pool.candidates("example.com")[0], first);
+
+        assert_eq!("round_robin".parse(), Ok(SelectStrategy::RoundRobin));
+        assert!("random".parse::<SelectStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_probe() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+            let pool =
+                UpstreamPool::new(vec![closed, listener.local_addr()?], SelectStrategy::Fastest);
+            pool.probe(&DialConfig::default(), Duration::from_secs(1)).await;
+            let health = pool.upstreams().map(|(_, health)| health).collect::<Vec<_>>();
+            assert_eq!((health[0].successes(), health[0].failures()), (0, 1));
+            assert_eq!((health[1].successes(), health[1].failures()), (1, 0));
+            assert!(health[1].latency().is_some());
+            assert_eq!(pool.candidates("example.com")[0], listener.local_addr()?);
+            Ok(())
+        })
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{Tun, VTunConfig, debug_println, seeval, set_cloexec, set_nonblock};
+use crate::{PacketFraming, Tun, VTunConfig, debug_println, seeval, set_cloexec, set_nonblock};
 
 use core::ffi::{c_char, c_int, c_uchar, c_uint, c_ulong, c_void};
 use core::mem::{size_of, size_of_val, transmute, zeroed};
@@ -20,11 +20,18 @@ pub const SIOCGIFMTU: c_ulong = 0xc0206933; /* get IF mtu */
 pub const SIOCSIFMTU: c_ulong = 0x80206934; /* set IF mtu */
 pub const SIOCGIFCONF: c_ulong = 0xc00c6924; /* get ifnet list */
 pub const SIOCSIFADDR: c_ulong = 0x8020690c; /* set ifnet address */
+pub const SIOCDIFADDR: c_ulong = 0x80206919; /* delete IF addr */
 pub const SIOCSIFFLAGS: c_ulong = 0x80206910; /* set ifnet flags */
 pub const SIOCGIFFLAGS: c_ulong = 0xc0206911; /* get ifnet flags */
 pub const SIOCSIFNETMASK: c_ulong = 0x80206916; /* set net addr mask */
+pub const SIOCSIFDESC: c_ulong = 0xc0946986; /* set ifnet description */
+pub const SIOCGIFDESC: c_ulong = 0xc0946987; /* get ifnet description */
+/// The maximum length of an interface description
+pub const IF_DESCSIZE: usize = 128;
 /// The maximum number of interfaces
 pub const MAX_IF_NUM: usize = 16;
+/// Units tried by [Tun::new], from `utun0` to `utun254`
+pub const DEFAULT_MAX_UNIT: c_uint = 255;
 
 pub fn new_ctl_info_with(name: &str) -> Result<ctl_info> {
     let cstr_name = CString::new(name).unwrap();
@@ -136,29 +143,49 @@ pub struct ifreq {
 
 impl Copy for ifreq {}
 
+/*
+ * if_descreq: interface description request
+ *    Used with SIOCSIFDESC and SIOCGIFDESC to set and get the human readable
+ *    description of an interface.
+ */
+#[repr(C)]
+#[derive(Clone)]
+#[allow(non_camel_case_types)]
+pub struct if_descreq {
+    pub ifdr_name: [c_char; IFNAMSIZ],
+    pub ifdr_len: u32,
+    pub ifdr_desc: [u8; IF_DESCSIZE],
+}
+
+impl Copy for if_descreq {}
+
 #[derive(Debug)]
 pub struct UTun {
     fd: c_int,
 }
 
 impl UTun {
-    /// Helper functions that tries to open utun device
-    /// return -2 on early initialization failures (utun not supported
-    /// at all (old OS X) and -1 on initlization failure of utun
-    /// device (utun works but utunX is already used
-    pub fn open_utun(utunnum: &c_uint) -> c_int {
-        let ctl_info = new_ctl_info_with(UTUN_CONTROL_NAME).unwrap();
+    /// Open the utun device of unit `utunnum`, which fails with
+    /// [ErrorKind::Unsupported] where utun is not supported at all (old OS X),
+    /// and with the error of `connect` when the unit is already in use
+    pub fn open_utun(utunnum: c_uint) -> Result<c_int> {
+        let ctl_info = new_ctl_info_with(UTUN_CONTROL_NAME)?;
         let mut sc = unsafe { zeroed::<sockaddr_ctl>() };
 
         let fd: c_int = unsafe { socket(PF_SYSTEM, SOCK_DGRAM, SYSPROTO_CONTROL) };
         if fd < 0 {
             debug_println!("Opening utun{} failed (socket(SYSPROTO_CONTROL))", utunnum);
-            return -2;
+            let err = Error::last_os_error();
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("socket(SYSPROTO_CONTROL): {}", err),
+            ));
         }
         if unsafe { ioctl(fd, CTLIOCGINFO, &ctl_info) } == -1 {
             debug_println!("Opening utun{} failed (ioctl(CTLIOCGINFO))", utunnum);
+            let err = Error::last_os_error();
             unsafe { close(fd) };
-            return -2;
+            return Err(Error::new(ErrorKind::Unsupported, format!("ioctl(CTLIOCGINFO): {}", err)));
         }
         seeval!(&ctl_info);
 
@@ -177,30 +204,70 @@ impl UTun {
          * is (sc.sc_unit - 1) */
         if unsafe { connect(fd, sockaddr, sockaddr_ctl_size as socklen_t) } < 0 {
             debug_println!("Opening utun{} failed (connect(AF_SYS_CONTROL))", utunnum);
+            let err = Error::last_os_error();
             unsafe { close(fd) };
-            return -1;
+            return Err(err);
         }
 
         set_nonblock(fd);
         set_cloexec(fd); /* don't pass fd to scripts */
 
-        fd
+        Ok(fd)
+    }
+
+    /// Open the first free unit below `max_unit`, giving up at once where
+    /// utun is not supported. The error tells why each unit failed.
+    pub fn open(max_unit: c_uint) -> Result<Self> {
+        let mut failures: Vec<(c_uint, c_uint, String)> = vec![];
+        for utunnum in 0..max_unit {
+            let reason = match Self::open_utun(utunnum) {
+                Ok(fd) => return Ok(UTun { fd }),
+                Err(e) if e.kind() == ErrorKind::Unsupported => return Err(e),
+                Err(e) => e.to_string(),
+            };
+            /* Runs of units failing alike are reported together */
+            match failures.last_mut() {
+                Some((_, last, last_reason)) if *last_reason == reason => *last = utunnum,
+                _ => failures.push((utunnum, utunnum, reason)),
+            }
+        }
+        let failures = failures
+            .iter()
+            .map(|(first, last, reason)| match first == last {
+                true => format!("utun{}: {}", first, reason),
+                false => format!("utun{}..utun{}: {}", first, last, reason),
+            })
+            .collect::<Vec<_>>();
+        Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("No utun unit below {} could be opened ({})", max_unit, failures.join("; ")),
+        ))
+    }
+
+    /// Look up the utun kernel control without creating an interface, which
+    /// fails where utun is not supported at all
+    pub fn probe_control() -> Result<()> {
+        let ctl_info = new_ctl_info_with(UTUN_CONTROL_NAME)?;
+        let fd: c_int = unsafe { socket(PF_SYSTEM, SOCK_DGRAM, SYSPROTO_CONTROL) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let ret = unsafe { ioctl(fd, CTLIOCGINFO, &ctl_info) };
+        let err = Error::last_os_error();
+        unsafe { close(fd) };
+        if ret == -1 { Err(err) } else { Ok(()) }
     }
 }
 
 impl Tun for UTun {
     #[inline]
-    fn new() -> Self {
-        let mut fd: c_int = c_int::default();
-        for utunnum in 0..255 {
-            fd = Self::open_utun(&utunnum);
-            /* Break if the fd is valid,
-             * or if early initialization failed (-2) */
-            if fd != -1 {
-                break;
-            }
-        }
-        UTun { fd }
+    fn new() -> Result<Self> {
+        Self::open(DEFAULT_MAX_UNIT)
+    }
+
+    #[inline]
+    fn framing(&self) -> PacketFraming {
+        PacketFraming::AfPrefixed
     }
 
     fn ifname(&self) -> Result<String> {
@@ -224,7 +291,7 @@ impl Tun for UTun {
             return Err(Error::last_os_error());
         }
 
-        let VTunConfig { mtu, ipv4_addr, ipv6_addr, netmask } = conf;
+        let VTunConfig { mtu, ipv4_addr, ipv6_addr, netmask, label } = conf;
         let mut ifreq = unsafe { zeroed::<ifreq>() };
         let cstring_ifname = CString::new(self.ifname()?.as_str());
         let self_ifname_c_ptr = cstring_ifname.unwrap().into_raw();
@@ -278,6 +345,13 @@ impl Tun for UTun {
             }
         }
 
+        if let Some(label) = label {
+            /* Descriptions are not supported by every release, not fatal */
+            if let Err(e) = self.set_label(&label) {
+                debug_println!("Setting description of {:?} failed ({:?})", self.ifname(), e);
+            }
+        }
+
         Ok(())
     }
 
@@ -309,6 +383,98 @@ impl Tun for UTun {
         }
         Ok(())
     }
+
+    fn set_label(&self, label: &str) -> Result<()> {
+        let label_bytes = label.as_bytes();
+        if label_bytes.len() >= IF_DESCSIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "`label` too long"));
+        }
+        let mut descreq = unsafe { zeroed::<if_descreq>() };
+        let self_ifname = CString::new(self.ifname()?.as_str()).unwrap();
+        unsafe { strcpy(descreq.ifdr_name.as_mut_ptr(), self_ifname.as_ptr()) };
+        descreq.ifdr_len = label_bytes.len() as u32;
+        descreq.ifdr_desc[..label_bytes.len()].copy_from_slice(label_bytes);
+
+        if unsafe { ioctl(self.fd, SIOCSIFDESC, &mut descreq) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn unconfigure(&self) -> Result<()> {
+        let ifname = self.ifname()?;
+        let ipv4_addrs = Self::ipv4_addrs_of(&ifname)?;
+        let sockfd: c_int = unsafe { socket(AF_INET, SOCK_DGRAM, 0) };
+        if sockfd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut ifreq = unsafe { zeroed::<ifreq>() };
+        let self_ifname = CString::new(ifname.as_str()).unwrap();
+        unsafe { strcpy(ifreq.ifr_name.as_mut_ptr(), self_ifname.as_ptr()) };
+
+        /* The route to the subnet of an address goes away with it */
+        for addr in ipv4_addrs {
+            ifreq.ifr_ifru.ifru_addr = addr;
+            if unsafe { ioctl(sockfd, SIOCDIFADDR, &mut ifreq) } < 0 {
+                let err = Error::last_os_error();
+                unsafe { close(sockfd) };
+                return Err(err);
+            }
+        }
+
+        if unsafe { ioctl(sockfd, SIOCGIFFLAGS, &mut ifreq) } < 0 {
+            let err = Error::last_os_error();
+            unsafe { close(sockfd) };
+            return Err(err);
+        }
+        unsafe { ifreq.ifr_ifru.ifru_flags &= !(IFF_UP as c_short) };
+        let ret = unsafe { ioctl(sockfd, SIOCSIFFLAGS, &mut ifreq) };
+        let err = Error::last_os_error();
+        unsafe { close(sockfd) };
+        if ret < 0 { Err(err) } else { Ok(()) }
+    }
+}
+
+impl UTun {
+    /// The IPv4 addresses assigned to the interface named `ifname`
+    fn ipv4_addrs_of(ifname: &str) -> Result<Vec<sockaddr>> {
+        let mut addrs = vec![];
+        let mut ifap: *mut ifaddrs = core::ptr::null_mut();
+        if unsafe { getifaddrs(&mut ifap) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut ifa = ifap;
+        while !ifa.is_null() {
+            let ifa_ref = unsafe { &*ifa };
+            let ifa_name = unsafe { core::ffi::CStr::from_ptr(ifa_ref.ifa_name) };
+            if ifa_name.to_bytes() == ifname.as_bytes()
+                && !ifa_ref.ifa_addr.is_null()
+                && unsafe { (*ifa_ref.ifa_addr).sa_family } as c_int == AF_INET
+            {
+                addrs.push(unsafe { *ifa_ref.ifa_addr });
+            }
+            ifa = ifa_ref.ifa_next;
+        }
+        unsafe { freeifaddrs(ifap) };
+        Ok(addrs)
+    }
+}
+
+impl UTun {
+    /// The description previously set with [Tun::set_label]
+    pub fn label(&self) -> Result<String> {
+        let mut descreq = unsafe { zeroed::<if_descreq>() };
+        let self_ifname = CString::new(self.ifname()?.as_str()).unwrap();
+        unsafe { strcpy(descreq.ifdr_name.as_mut_ptr(), self_ifname.as_ptr()) };
+        descreq.ifdr_len = IF_DESCSIZE as u32;
+
+        if unsafe { ioctl(self.fd, SIOCGIFDESC, &mut descreq) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        let len = (descreq.ifdr_len as usize).min(IF_DESCSIZE);
+        Ok(String::from_utf8_lossy(&descreq.ifdr_desc[..len]).to_string())
+    }
 }
 
 impl UTun {
@@ -405,6 +571,25 @@ impl AsRawFd for UTun {
     }
 }
 
+impl Drop for UTun {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe { close(self.fd) };
+        }
+    }
+}
+
+#[cfg(unix)]
+use std::os::fd::IntoRawFd;
+#[cfg(unix)]
+impl IntoRawFd for UTun {
+    fn into_raw_fd(self) -> std::os::fd::RawFd {
+        let fd = self.fd;
+        core::mem::forget(self);
+        fd
+    }
+}
+
 #[cfg(unix)]
 use std::os::fd::FromRawFd;
 #[cfg(unix)]
@@ -1,22 +1,133 @@
-use crate::{Tun, UTun};
+#[cfg(target_os = "macos")]
+use crate::UTun;
+use crate::{PacketFraming, Tun, TunCapture};
 
 use core::ffi::{c_int, c_uint};
+#[cfg(target_os = "macos")]
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicU16, Ordering};
 
 #[derive(Debug)]
 pub struct VTun {
     fd: c_int,
+    capture: TunCapture,
+    /// The MTU the MSS of TCP SYNs is clamped to, 0 for none
+    mss_clamp: AtomicU16,
+}
+
+impl VTun {
+    /// Open the first free utun unit below `max_unit`, see [UTun::open]
+    #[cfg(target_os = "macos")]
+    pub fn open(max_unit: c_uint) -> std::io::Result<Self> {
+        let utun = UTun::open(max_unit)?;
+        Ok(VTun {
+            fd: utun.into_raw_fd(),
+            capture: TunCapture::default(),
+            mss_clamp: AtomicU16::new(0),
+        })
+    }
+
+    /// Packets read and written go into it while started
+    #[inline]
+    pub fn capture(&self) -> &TunCapture {
+        &self.capture
+    }
+
+    /// Clamp the MSS of the TCP SYNs read and written to fit in `mtu`,
+    /// see [crate::clamp_mss], or stop with None
+    pub fn clamp_mss_to(&self, mtu: Option<u16>) {
+        self.mss_clamp.store(mtu.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Read an IP packet into `buf`, without the framing of the interface,
+    /// fails with [std::io::ErrorKind::WouldBlock] when there is none
+    pub fn read_packet(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut header = [0u8; 4];
+        let header_len = self.framing().header_len();
+        let iov = [
+            libc::iovec { iov_base: header.as_mut_ptr() as *mut libc::c_void, iov_len: header_len },
+            libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() },
+        ];
+        let n = unsafe { libc::readv(self.fd, iov.as_ptr(), iov.len() as c_int) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let len = (n as usize).checked_sub(header_len).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Frame shorter than its header")
+        })?;
+        match self.mss_clamp.load(Ordering::Relaxed) {
+            0 => {}
+            mtu => {
+                crate::clamp_mss(&mut buf[..len], mtu);
+            }
+        }
+        self.capture.tee(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Write the IP packet `packet`, framed as the interface expects,
+    /// returns the length of `packet`
+    pub fn write_packet(&self, packet: &[u8]) -> std::io::Result<usize> {
+        /* Copied only for the few packets clamped */
+        let clamped;
+        let packet = match self.mss_clamp.load(Ordering::Relaxed) {
+            mtu if mtu != 0 && crate::needs_mss_clamp(packet, mtu) => {
+                let mut copy = packet.to_vec();
+                crate::clamp_mss(&mut copy, mtu);
+                clamped = copy;
+                &clamped[..]
+            }
+            _ => packet,
+        };
+        let framing = self.framing();
+        let header = match framing {
+            PacketFraming::Raw => [0u8; 4],
+            PacketFraming::AfPrefixed => framing.header(packet)?,
+        };
+        let iov = [
+            libc::iovec {
+                iov_base: header.as_ptr() as *mut libc::c_void,
+                iov_len: framing.header_len(),
+            },
+            libc::iovec { iov_base: packet.as_ptr() as *mut libc::c_void, iov_len: packet.len() },
+        ];
+        let n = unsafe { libc::writev(self.fd, iov.as_ptr(), iov.len() as c_int) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        self.capture.tee(packet);
+        Ok((n as usize).saturating_sub(framing.header_len()))
+    }
+
+    /// The utun device behind the fd, which stays open when it is dropped
+    #[cfg(target_os = "macos")]
+    #[inline]
+    fn utun(&self) -> ManuallyDrop<UTun> {
+        ManuallyDrop::new(UTun::from(self.fd))
+    }
 }
 
 impl Tun for VTun {
-    fn new() -> Self {
+    fn new() -> std::io::Result<Self> {
         #[cfg(target_os = "macos")]
-        VTun { fd: super::UTun::new().as_raw_fd() }
+        return UTun::new().map(|utun| VTun {
+            fd: utun.into_raw_fd(),
+            capture: TunCapture::default(),
+            mss_clamp: AtomicU16::new(0),
+        });
+        #[allow(unreachable_code)]
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "No utun on this system"))
+    }
+
+    #[inline]
+    fn framing(&self) -> PacketFraming {
+        PacketFraming::of_tun()
     }
 
     #[inline]
     fn ifname(&self) -> std::io::Result<String> {
         #[cfg(target_os = "macos")]
-        return Into::<UTun>::into(self.fd).ifname();
+        return self.utun().ifname();
         #[allow(unreachable_code)]
         Ok(String::from(""))
     }
@@ -24,7 +135,7 @@ impl Tun for VTun {
     #[inline]
     fn ifindex(&self) -> std::io::Result<c_uint> {
         #[cfg(target_os = "macos")]
-        return Into::<UTun>::into(self.fd).ifindex();
+        return self.utun().ifindex();
         #[allow(unreachable_code)]
         Ok(0)
     }
@@ -32,33 +143,71 @@ impl Tun for VTun {
     #[inline]
     fn mtu(&self) -> std::io::Result<c_int> {
         #[cfg(target_os = "macos")]
-        return Into::<UTun>::into(self.fd).mtu();
+        return self.utun().mtu();
         #[allow(unreachable_code)]
         Ok(0)
     }
 
     #[inline]
+    #[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
     fn set_mtu(&self, n: c_int) -> std::io::Result<()> {
         #[cfg(target_os = "macos")]
-        return Into::<UTun>::into(self.fd).set_mtu(n);
+        return self.utun().set_mtu(n);
         #[allow(unreachable_code)]
         Ok(())
     }
 
     #[inline]
+    #[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
+    fn set_label(&self, label: &str) -> std::io::Result<()> {
+        #[cfg(target_os = "macos")]
+        return self.utun().set_label(label);
+        #[allow(unreachable_code)]
+        Ok(())
+    }
+
+    #[inline]
+    #[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
     fn config_with(&self, conf: crate::VTunConfig) -> std::io::Result<()> {
         #[cfg(target_os = "macos")]
-        return Into::<UTun>::into(self.fd).config_with(conf);
+        return self.utun().config_with(conf);
         #[allow(unreachable_code)]
         Ok(())
     }
+
+    #[inline]
+    fn unconfigure(&self) -> std::io::Result<()> {
+        #[cfg(target_os = "macos")]
+        return self.utun().unconfigure();
+        #[allow(unreachable_code)]
+        Ok(())
+    }
+}
+
+impl Drop for VTun {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe { libc::close(self.fd) };
+        }
+    }
 }
 
+#[cfg(target_os = "macos")]
+use std::os::fd::IntoRawFd;
 #[cfg(unix)]
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, FromRawFd};
 #[cfg(unix)]
 impl AsRawFd for VTun {
     fn as_raw_fd(&self) -> std::os::fd::RawFd {
         self.fd
     }
 }
+
+/// A tunnel interface opened elsewhere, e.g. by the VPN service of the
+/// system, which this then owns
+#[cfg(unix)]
+impl FromRawFd for VTun {
+    unsafe fn from_raw_fd(fd: std::os::fd::RawFd) -> Self {
+        VTun { fd, capture: TunCapture::default(), mss_clamp: AtomicU16::new(0) }
+    }
+}
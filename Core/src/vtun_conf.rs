@@ -1,15 +1,18 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct VTunConfig {
     pub mtu: Option<u16>,
     pub ipv4_addr: Option<Ipv4Addr>,
     pub ipv6_addr: Option<Ipv6Addr>,
     pub netmask: Option<u32>,
+    /// Human readable description of the interface, so it can be told
+    /// apart from other tunnel devices
+    pub label: Option<String>,
 }
 
 impl Default for VTunConfig {
     fn default() -> Self {
-        Self { mtu: None, ipv4_addr: None, ipv6_addr: None, netmask: None }
+        Self { mtu: None, ipv4_addr: None, ipv6_addr: None, netmask: None, label: None }
     }
 }
@@ -0,0 +1,899 @@
+//! Connections through a WireGuard peer, without an interface of the system
+//!
+//! [WireGuard] speaks the protocol in process over a UDP socket of its own,
+//! as the initiator toward a single peer, with the TCP/IP stack of smoltcp
+//! on its end of the tunnel. A connection to a destination within the
+//! `allowed_ips` of the peer is made by the stack from the address of the
+//! tunnel, its packets sealed and sent to the endpoint of the peer, and is
+//! handed out as the end of a loopback [TcpStream] a task relays to the
+//! stack, so that it is used like those of any other dialer.
+//!
+//! Handshakes the peer initiates and cookie replies are ignored: the
+//! initiation is sent again until answered, for up to 90 seconds, and the
+//! session is renewed from this end every two minutes as the protocol
+//! prescribes.
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::poll_fn;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::AnySocket;
+use smoltcp::socket::tcp::{self, RecvError, State};
+use smoltcp::wire::{HardwareAddress, IpCidr as StackCidr};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, copy_bidirectional};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::overhead::{Carrier, TransportKind, payload_mtu};
+use crate::wireguard_noise::{
+    DATA_MIN_LEN, Handshake, Identity, RESPONSE_LEN, Session, TYPE_DATA, TYPE_RESPONSE,
+    receiver_index, tai64n,
+};
+use crate::{IpCidr, MTU_PLATEAUS, loopback_pair};
+
+/// That of wg-quick, which fits the sealed packets in an Ethernet MTU over
+/// IPv6
+pub const DEFAULT_WIREGUARD_MTU: usize =
+    payload_mtu(MTU_PLATEAUS[0], TransportKind::new(true, Carrier::WireGuard)) as usize;
+
+/// Time after an initiation it is sent again
+const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time after which the initiations are given up, until a packet is sent
+const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
+/// Age of a session a new handshake is initiated at
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+/// Age of a session past which it is not used any longer
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+/// Time after a packet was received that a keepalive is sent, unless a
+/// packet was sent since
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Packets held while a session is made, the oldest is dropped past it
+const MAX_QUEUED_PACKETS: usize = 64;
+/// Time a connection through the tunnel has to establish
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time without an answer from the destination a connection is aborted
+/// after
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(60);
+/// Bytes each connection buffers either way
+const SOCKET_BUFFER: usize = 64 * 1024;
+/// First local port of the connections, the rest of the range is cycled
+/// through
+const FIRST_LOCAL_PORT: u16 = 49152;
+
+/// A Curve25519 key in base64, as WireGuard configurations write them
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct WireGuardKey([u8; 32]);
+
+impl WireGuardKey {
+    #[inline]
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// That of this private key
+    pub fn public_key(&self) -> Self {
+        Self(PublicKey::from(&StaticSecret::from(self.0)).to_bytes())
+    }
+}
+
+impl FromStr for WireGuardKey {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bytes = BASE64.decode(s.trim()).map_err(|e| format!("Invalid key: {}", e))?;
+        let bytes = <[u8; 32]>::try_from(bytes)
+            .map_err(|bytes| format!("Invalid key of {} bytes", bytes.len()))?;
+        Ok(Self(bytes))
+    }
+}
+
+impl TryFrom<String> for WireGuardKey {
+    type Error = String;
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for WireGuardKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&BASE64.encode(self.0))
+    }
+}
+
+/// Left out, private keys being of the same type
+impl Debug for WireGuardKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WireGuardKey(..)")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WireGuardPeer {
+    pub public_key: WireGuardKey,
+    pub preshared_key: Option<WireGuardKey>,
+    pub endpoint: SocketAddr,
+    /// Destinations reached through the peer, and sources taken from it
+    pub allowed_ips: Vec<IpCidr>,
+    /// Interval of the keepalives keeping a NAT mapping open, none are sent
+    /// without traffic otherwise
+    pub persistent_keepalive: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WireGuardConfig {
+    pub private_key: WireGuardKey,
+    /// Of this end of the tunnel, one per address family at most
+    pub addresses: Vec<IpAddr>,
+    pub mtu: usize,
+    pub peer: WireGuardPeer,
+}
+
+impl WireGuardConfig {
+    pub fn new(private_key: WireGuardKey, addresses: Vec<IpAddr>, peer: WireGuardPeer) -> Self {
+        Self { private_key, addresses, mtu: DEFAULT_WIREGUARD_MTU, peer }
+    }
+}
+
+/// Packets between the stack and the tunnel
+#[derive(Debug)]
+struct Queue {
+    /// Opened from the peer
+    rx: VecDeque<Vec<u8>>,
+    /// To be sealed for it
+    tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0; len];
+        let ret = f(&mut packet);
+        self.0.push_back(packet);
+        ret
+    }
+}
+
+impl phy::Device for Queue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _: smoltcp::time::Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: smoltcp::time::Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+/// The TCP/IP stack on this end of the tunnel
+struct Stack {
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    device: Queue,
+    /// Sockets of the connections dropped, removed once closed
+    orphans: Vec<SocketHandle>,
+    next_port: u16,
+}
+
+impl Stack {
+    fn new(addresses: &[IpAddr], mtu: usize, random_seed: u64) -> Result<Self> {
+        let mut device = Queue { rx: VecDeque::new(), tx: VecDeque::new(), mtu };
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = random_seed;
+        let mut iface = Interface::new(config, &mut device, smoltcp::time::Instant::now());
+        let v4 = addresses.iter().filter(|ip| ip.is_ipv4()).count();
+        if addresses.is_empty() || v4 > 1 || addresses.len() - v4 > 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The tunnel takes an address of either family or one of each",
+            ));
+        }
+        iface.update_ip_addrs(|addrs| {
+            for ip in addresses {
+                let prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+                addrs.push(StackCidr::new((*ip).into(), prefix_len)).unwrap();
+            }
+        });
+        /* Every destination is behind the peer, the gateway only has to be
+         * one */
+        for ip in addresses {
+            match ip {
+                IpAddr::V4(ip) => iface.routes_mut().add_default_ipv4_route(*ip),
+                IpAddr::V6(ip) => iface.routes_mut().add_default_ipv6_route(*ip),
+            }
+            .unwrap();
+        }
+        Ok(Self {
+            iface,
+            sockets: SocketSet::new(vec![]),
+            device,
+            orphans: vec![],
+            next_port: FIRST_LOCAL_PORT,
+        })
+    }
+
+    /// The packets to send, along with the time until the stack is to be
+    /// polled again
+    fn poll(&mut self) -> (Vec<Vec<u8>>, Option<Duration>) {
+        let now = smoltcp::time::Instant::now();
+        self.iface.poll(now, &mut self.device, &mut self.sockets);
+        self.orphans.retain(|handle| {
+            let state = self.sockets.get::<tcp::Socket>(*handle).state();
+            if matches!(state, State::Closed | State::TimeWait) {
+                self.sockets.remove(*handle);
+                return false;
+            }
+            true
+        });
+        let delay = self.iface.poll_delay(now, &self.sockets).map(Duration::from);
+        (self.device.tx.drain(..).collect(), delay)
+    }
+
+    fn connect(&mut self, addr: SocketAddr) -> Result<SocketHandle> {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER]),
+            tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER]),
+        );
+        socket.set_timeout(Some(SOCKET_TIMEOUT.into()));
+        let port = self.next_port;
+        self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_LOCAL_PORT);
+        socket
+            .connect(self.iface.context(), addr, port)
+            .map_err(|e| Error::new(ErrorKind::AddrNotAvailable, format!("{}: {}", addr, e)))?;
+        Ok(self.sockets.add(socket))
+    }
+}
+
+/// What the tunnel task and the connections share
+struct Shared {
+    stack: Mutex<Stack>,
+    /// Wakes the tunnel task to poll the stack
+    poll: Notify,
+    /// Whether a session with the peer is up
+    established: AtomicBool,
+}
+
+impl Shared {
+    /// Run `f` on the socket of `handle`, and have the stack polled after
+    fn with_socket<R>(&self, handle: SocketHandle, f: impl FnOnce(&mut tcp::Socket) -> R) -> R {
+        let ret = f(self.stack.lock().unwrap().sockets.get_mut(handle));
+        self.poll.notify_one();
+        ret
+    }
+}
+
+/// A connection of the stack, relayed to its loopback [TcpStream]
+struct TunnelStream {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+}
+
+impl TunnelStream {
+    async fn established(&self) -> Result<()> {
+        poll_fn(|cx| {
+            self.shared.with_socket(self.handle, |socket| match socket.state() {
+                State::Established => Poll::Ready(Ok(())),
+                State::Closed => Poll::Ready(Err(Error::from(ErrorKind::ConnectionRefused))),
+                _ => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        self.shared.with_socket(self.handle, |socket| {
+            match socket.recv_slice(buf.initialize_unfilled()) {
+                Ok(0) if buf.remaining() > 0 => {
+                    socket.register_recv_waker(cx.waker());
+                    Poll::Pending
+                }
+                Ok(len) => {
+                    buf.advance(len);
+                    Poll::Ready(Ok(()))
+                }
+                Err(RecvError::Finished) => Poll::Ready(Ok(())),
+                Err(RecvError::InvalidState) => {
+                    Poll::Ready(Err(Error::from(ErrorKind::ConnectionReset)))
+                }
+            }
+        })
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.shared.with_socket(self.handle, |socket| match socket.send_slice(buf) {
+            Ok(0) if !buf.is_empty() => {
+                socket.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+            Ok(len) => Poll::Ready(Ok(len)),
+            Err(_) => Poll::Ready(Err(Error::from(ErrorKind::BrokenPipe))),
+        })
+    }
+
+    /// What is written is sent as the stack is polled
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.shared.with_socket(self.handle, |socket| socket.close());
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TunnelStream {
+    fn drop(&mut self) {
+        let mut stack = self.shared.stack.lock().unwrap();
+        stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        stack.orphans.push(self.handle);
+        drop(stack);
+        self.shared.poll.notify_one();
+    }
+}
+
+/// Hand `stream` out as the end of a loopback connection
+async fn bridge(mut stream: TunnelStream) -> Result<TcpStream> {
+    let (connected, mut accepted) = loopback_pair().await?;
+    tokio::spawn(async move {
+        let _ = copy_bidirectional(&mut accepted, &mut stream).await;
+    });
+    Ok(connected)
+}
+
+/// An initiation sent and not yet answered
+struct PendingHandshake {
+    handshake: Handshake,
+    sent_at: Instant,
+    /// When the first initiation of this attempt was sent
+    since: Instant,
+}
+
+/// The protocol side of the tunnel, run on a task of its own
+struct Tunnel {
+    shared: Arc<Shared>,
+    udp: UdpSocket,
+    identity: Identity,
+    allowed_ips: Vec<IpCidr>,
+    persistent_keepalive: Option<Duration>,
+    rng: SystemRandom,
+    handshake: Option<PendingHandshake>,
+    /// The session packets are sent over, with when it was made
+    current: Option<(Session, Instant)>,
+    /// The one before, still taking the packets the peer sent over it
+    previous: Option<Session>,
+    /// Packets waiting for a session
+    queue: VecDeque<Vec<u8>>,
+    last_sent: Instant,
+    keepalive_due: Option<Instant>,
+}
+
+impl Tunnel {
+    async fn run(mut self) {
+        let mut buf = vec![0; u16::MAX as usize];
+        self.initiate().await;
+        loop {
+            let (packets, delay) = self.shared.stack.lock().unwrap().poll();
+            for packet in packets {
+                self.send_packet(packet).await;
+            }
+            self.on_timers().await;
+
+            let now = Instant::now();
+            let wake_at =
+                [delay.map(|delay| now + delay), self.next_timer()].into_iter().flatten().min();
+            let sleep = async {
+                match wake_at {
+                    Some(at) => tokio::time::sleep_until(at.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                /* Errors are those ICMP reports, and go with the next retry */
+                ret = self.udp.recv(&mut buf) => if let Ok(len) = ret {
+                    self.receive(&buf[..len]).await;
+                },
+                _ = self.shared.poll.notified() => {}
+                _ = sleep => {}
+            }
+        }
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        [
+            self.handshake.as_ref().map(|pending| pending.sent_at + REKEY_TIMEOUT),
+            self.current.as_ref().map(|(_, at)| *at + REJECT_AFTER_TIME),
+            self.keepalive_due,
+            self.persistent_keepalive.map(|interval| self.last_sent + interval),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    async fn on_timers(&mut self) {
+        let now = Instant::now();
+        if let Some(pending) = &self.handshake
+            && now >= pending.sent_at + REKEY_TIMEOUT
+        {
+            let since = pending.since;
+            if now - since >= REKEY_ATTEMPT_TIME {
+                self.handshake = None;
+                self.queue.clear();
+            } else {
+                self.send_initiation(since).await;
+            }
+        }
+        if let Some((_, at)) = &self.current
+            && now - *at >= REJECT_AFTER_TIME
+        {
+            self.current = None;
+            self.previous = None;
+            self.shared.established.store(false, Ordering::Relaxed);
+        }
+        if self.keepalive_due.is_some_and(|due| now >= due)
+            || self.persistent_keepalive.is_some_and(|interval| now >= self.last_sent + interval)
+        {
+            self.keepalive().await;
+        }
+    }
+
+    async fn send(&mut self, msg: &[u8]) {
+        let _ = self.udp.send(msg).await;
+        self.last_sent = Instant::now();
+    }
+
+    /// Start a handshake, unless one is in flight
+    async fn initiate(&mut self) {
+        if self.handshake.is_none() {
+            self.send_initiation(Instant::now()).await;
+        }
+    }
+
+    async fn send_initiation(&mut self, since: Instant) {
+        let mut random = [0; 36];
+        if self.rng.fill(&mut random).is_err() {
+            return;
+        }
+        let index = u32::from_le_bytes(random[32..].try_into().unwrap());
+        let ephemeral = random[..32].try_into().unwrap();
+        /* Fails only for a low order key of the peer, which never answers */
+        let Ok((handshake, msg)) = Handshake::initiate(&self.identity, index, ephemeral, tai64n())
+        else {
+            return;
+        };
+        self.send(&msg).await;
+        self.handshake = Some(PendingHandshake { handshake, sent_at: Instant::now(), since });
+    }
+
+    /// Seal `packet` over the current session, or hold it until there is
+    /// one
+    async fn send_packet(&mut self, packet: Vec<u8>) {
+        let sealed = self
+            .current
+            .as_mut()
+            .filter(|(_, at)| at.elapsed() < REJECT_AFTER_TIME)
+            .and_then(|(session, at)| Some((session.seal(&packet)?, at.elapsed())));
+        match sealed {
+            Some((msg, age)) => {
+                self.send(&msg).await;
+                self.keepalive_due = None;
+                if age >= REKEY_AFTER_TIME {
+                    self.initiate().await;
+                }
+            }
+            None => {
+                if self.queue.len() == MAX_QUEUED_PACKETS {
+                    self.queue.pop_front();
+                }
+                self.queue.push_back(packet);
+                self.initiate().await;
+            }
+        }
+    }
+
+    async fn keepalive(&mut self) {
+        self.keepalive_due = None;
+        match self.current.as_mut().and_then(|(session, _)| session.seal(&[])) {
+            Some(msg) => self.send(&msg).await,
+            None => {
+                self.initiate().await;
+                /* Not again until the interval is over */
+                self.last_sent = Instant::now();
+            }
+        }
+    }
+
+    async fn receive(&mut self, msg: &[u8]) {
+        match msg.first() {
+            Some(&TYPE_RESPONSE) if msg.len() == RESPONSE_LEN => {
+                let Some(pending) = &self.handshake else { return };
+                let Ok(session) = pending.handshake.complete(&self.identity, msg) else { return };
+                self.handshake = None;
+                self.previous = self.current.take().map(|(session, _)| session);
+                self.current = Some((session, Instant::now()));
+                self.shared.established.store(true, Ordering::Relaxed);
+                /* The peer takes the session up once data comes over it */
+                if self.queue.is_empty() {
+                    self.keepalive().await;
+                }
+                for packet in std::mem::take(&mut self.queue) {
+                    self.send_packet(packet).await;
+                }
+            }
+            Some(&TYPE_DATA) if msg.len() >= DATA_MIN_LEN => {
+                let index = receiver_index(msg);
+                let session = match (&mut self.current, &mut self.previous) {
+                    (Some((session, _)), _) if Some(session.local_index()) == index => session,
+                    (_, Some(session)) if Some(session.local_index()) == index => session,
+                    _ => return,
+                };
+                let Ok(packet) = session.open(msg) else { return };
+                if packet.is_empty() {
+                    return;
+                }
+                let Some(packet) = unpad(packet, &self.allowed_ips) else { return };
+                self.keepalive_due.get_or_insert(Instant::now() + KEEPALIVE_TIMEOUT);
+                self.shared.stack.lock().unwrap().device.rx.push_back(packet);
+            }
+            /* Initiations from the peer and cookie replies */
+            _ => {}
+        }
+    }
+}
+
+/// The IP packet `packet` without its padding, unless it is not one from
+/// within `allowed_ips`
+fn unpad(mut packet: Vec<u8>, allowed_ips: &[IpCidr]) -> Option<Vec<u8>> {
+    let (len, src) = match packet[0] >> 4 {
+        4 if packet.len() >= 20 => {
+            let src: [u8; 4] = packet[12..16].try_into().unwrap();
+            (u16::from_be_bytes([packet[2], packet[3]]) as usize, IpAddr::from(src))
+        }
+        6 if packet.len() >= 40 => {
+            let src: [u8; 16] = packet[8..24].try_into().unwrap();
+            (40 + u16::from_be_bytes([packet[4], packet[5]]) as usize, IpAddr::from(src))
+        }
+        _ => return None,
+    };
+    if len > packet.len() || !allowed_ips.iter().any(|cidr| cidr.contains(&src)) {
+        return None;
+    }
+    packet.truncate(len);
+    Some(packet)
+}
+
+/// A tunnel to a WireGuard peer, connecting to the destinations within its
+/// allowed IPs
+pub struct WireGuard {
+    shared: Arc<Shared>,
+    allowed_ips: Vec<IpCidr>,
+    tunnel: JoinHandle<()>,
+    #[cfg(feature = "engine-lite")]
+    resolve_stats: crate::ResolveStats,
+}
+
+impl WireGuard {
+    /// Bind the UDP socket and initiate the handshake, on a task of the
+    /// current tokio runtime
+    pub async fn start(config: WireGuardConfig) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let mut seed = [0; 8];
+        rng.fill(&mut seed).map_err(|_| Error::other("No randomness available"))?;
+        let stack = Stack::new(&config.addresses, config.mtu, u64::from_le_bytes(seed))?;
+        let bind: SocketAddr = match config.peer.endpoint {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let udp = UdpSocket::bind(bind).await?;
+        udp.connect(config.peer.endpoint).await?;
+
+        let shared = Arc::new(Shared {
+            stack: Mutex::new(stack),
+            poll: Notify::new(),
+            established: AtomicBool::new(false),
+        });
+        let peer = &config.peer;
+        let tunnel = Tunnel {
+            shared: shared.clone(),
+            udp,
+            identity: Identity::new(
+                config.private_key.0,
+                peer.public_key.0,
+                peer.preshared_key.map(|key| key.0),
+            ),
+            allowed_ips: peer.allowed_ips.clone(),
+            persistent_keepalive: peer.persistent_keepalive,
+            rng,
+            handshake: None,
+            current: None,
+            previous: None,
+            queue: VecDeque::new(),
+            last_sent: Instant::now(),
+            keepalive_due: None,
+        };
+        Ok(Self {
+            shared,
+            allowed_ips: peer.allowed_ips.clone(),
+            tunnel: tokio::spawn(tunnel.run()),
+            #[cfg(feature = "engine-lite")]
+            resolve_stats: crate::ResolveStats::default(),
+        })
+    }
+
+    /// Count the lookups of the names dialed into `resolve_stats`
+    #[cfg(feature = "engine-lite")]
+    pub fn resolve_stats(&mut self, resolve_stats: crate::ResolveStats) -> &mut Self {
+        self.resolve_stats = resolve_stats;
+        self
+    }
+
+    /// Whether `ip` is reached through the peer
+    pub fn routes(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.allowed_ips.iter().any(|cidr| cidr.contains(&ip))
+    }
+
+    /// Whether a handshake with the peer has completed, and the session it
+    /// made is still in use
+    #[inline]
+    pub fn is_established(&self) -> bool {
+        self.shared.established.load(Ordering::Relaxed)
+    }
+
+    /// Connect to the first of `addrs` within the allowed IPs that accepts,
+    /// in turn
+    pub async fn connect(&self, addrs: &[SocketAddr]) -> Result<TcpStream> {
+        let mut last_err = None;
+        for addr in addrs.iter().filter(|addr| self.routes(addr.ip())) {
+            let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+            match self.connect_one(addr).await {
+                Ok(tcp_stream) => return Ok(tcp_stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::new(ErrorKind::AddrNotAvailable, "No destination within the allowed IPs")
+        }))
+    }
+
+    async fn connect_one(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let handle = self.shared.stack.lock().unwrap().connect(addr)?;
+        self.shared.poll.notify_one();
+        let stream = TunnelStream { shared: self.shared.clone(), handle };
+        match tokio::time::timeout(CONNECT_TIMEOUT, stream.established()).await {
+            Ok(ret) => ret?,
+            Err(_) if !self.is_established() => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "No handshake with the WireGuard peer",
+                ));
+            }
+            Err(_) => return Err(Error::from(ErrorKind::TimedOut)),
+        }
+        bridge(stream).await
+    }
+}
+
+impl Debug for WireGuard {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WireGuard")
+            .field("allowed_ips", &self.allowed_ips)
+            .field("established", &self.is_established())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Stops the tunnel and resets the connections through it
+impl Drop for WireGuard {
+    fn drop(&mut self) {
+        self.tunnel.abort();
+        let mut stack = self.shared.stack.lock().unwrap();
+        for (_, socket) in stack.sockets.iter_mut() {
+            if let Some(socket) = tcp::Socket::downcast_mut(socket) {
+                socket.abort();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "engine-lite")]
+impl crate::Dialer for WireGuard {
+    fn name(&self) -> &'static str {
+        "wireguard"
+    }
+
+    fn dial<'a>(&'a self, addr: &'a socks5::protocol::Address) -> crate::DialFuture<'a> {
+        use socks5::protocol::Address;
+        Box::pin(async move {
+            let addrs = match addr {
+                Address::IP(socket_addr) => vec![*socket_addr],
+                Address::Domain(name, port) => {
+                    let preference = crate::DialConfig::default().family_preference;
+                    crate::resolve(name, *port, preference, &self.resolve_stats).await?
+                }
+            };
+            self.connect(&addrs).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stack, WireGuard, WireGuardConfig, WireGuardKey, WireGuardPeer, unpad};
+    use crate::wireguard_noise::{Session, respond};
+
+    use std::net::{IpAddr, SocketAddr};
+
+    use smoltcp::socket::tcp;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+    use x25519_dalek::StaticSecret;
+
+    #[test]
+    fn test_wireguard_key() {
+        let key: WireGuardKey = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=".parse().unwrap();
+        assert_eq!(key.public_key().to_string(), "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=");
+        assert_eq!(format!("{:?}", key), "WireGuardKey(..)");
+        assert!("AAAA".parse::<WireGuardKey>().is_err());
+    }
+
+    #[test]
+    fn test_unpad() {
+        let cidr = "10.0.0.0/24".parse().unwrap();
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1];
+        packet.resize(32, 0);
+        assert_eq!(unpad(packet.clone(), &[cidr]).map(|packet| packet.len()), Some(20));
+        /* From outside the allowed IPs */
+        packet[14] = 1;
+        assert_eq!(unpad(packet, &[cidr]), None);
+    }
+
+    /// The peer of the tunnel: it answers the initiation and runs a stack
+    /// of its own, echoing what comes to `port`
+    async fn serve_peer(udp: UdpSocket, secret: StaticSecret, addr: IpAddr, port: u16) {
+        let mut stack = Stack::new(&[addr], 1420, 1).unwrap();
+        let listen = |stack: &mut Stack| {
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; 4096]),
+                tcp::SocketBuffer::new(vec![0; 4096]),
+            );
+            socket.listen(port).unwrap();
+            stack.sockets.add(socket)
+        };
+        let mut handle = listen(&mut stack);
+        let mut session: Option<(Session, SocketAddr)> = None;
+        let mut buf = vec![0; 65535];
+        loop {
+            let (packets, delay) = stack.poll();
+            if let Some((session, initiator)) = &mut session {
+                for packet in packets {
+                    udp.send_to(&session.seal(&packet).unwrap(), *initiator).await.unwrap();
+                }
+            }
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            if socket.can_recv() {
+                let mut data = [0; 4096];
+                let len = socket.recv_slice(&mut data).unwrap();
+                socket.send_slice(&data[..len]).unwrap();
+                continue;
+            }
+            if !socket.may_recv() && socket.state() == tcp::State::CloseWait {
+                socket.close();
+                handle = listen(&mut stack);
+                continue;
+            }
+            let delay = delay.unwrap_or(std::time::Duration::from_millis(50));
+            let Ok(Ok((len, initiator))) =
+                tokio::time::timeout(delay, udp.recv_from(&mut buf)).await
+            else {
+                continue;
+            };
+            match buf[0] {
+                1 => {
+                    let (_, response, made) =
+                        respond(&secret, [0; 32], &buf[..len], 7, [4; 32]).unwrap();
+                    udp.send_to(&response, initiator).await.unwrap();
+                    session = Some((made, initiator));
+                }
+                4 => {
+                    let packet = session.as_mut().unwrap().0.open(&buf[..len]).unwrap();
+                    if let Some(packet) = unpad(packet, &["10.7.0.0/24".parse().unwrap()]) {
+                        stack.device.rx.push_back(packet);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_wireguard() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let udp = UdpSocket::bind("127.0.0.1:0").await?;
+            let endpoint = udp.local_addr()?;
+            let secret = StaticSecret::from([8; 32]);
+            let peer_key = WireGuardKey::new(x25519_dalek::PublicKey::from(&secret).to_bytes());
+            tokio::spawn(serve_peer(udp, secret, "10.7.0.1".parse().unwrap(), 7));
+
+            let peer = WireGuardPeer {
+                public_key: peer_key,
+                preshared_key: None,
+                endpoint,
+                allowed_ips: vec!["10.7.0.0/24".parse().unwrap()],
+                persistent_keepalive: None,
+            };
+            let config = WireGuardConfig::new(
+                WireGuardKey::new([2; 32]),
+                vec!["10.7.0.2".parse().unwrap()],
+                peer,
+            );
+            let wireguard = WireGuard::start(config).await?;
+            assert!(wireguard.routes("10.7.0.1".parse().unwrap()));
+            assert!(!wireguard.routes("10.8.0.1".parse().unwrap()));
+
+            let mut tcp_stream = wireguard.connect(&["10.7.0.1:7".parse().unwrap()]).await?;
+            assert!(wireguard.is_established());
+            tcp_stream.write_all(b"through the tunnel").await?;
+            let mut buf = [0; 18];
+            tcp_stream.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"through the tunnel");
+
+            let e = wireguard.connect(&["10.8.0.1:7".parse().unwrap()]).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::AddrNotAvailable);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,544 @@
+//! The handshake and data messages of WireGuard, for the initiator
+//!
+//! The Noise_IKpsk2 handshake makes a [Session] out of the initiation a
+//! [Handshake] sends and the response of the peer, the session then seals
+//! and opens the data messages. BLAKE2s, which the protocol hashes and MACs
+//! with, comes from blake2 and hmac, X25519 and ChaCha20-Poly1305 from
+//! x25519-dalek and ring. The messages are checked in the tests against
+//! those of the whitepaper computed apart, with Python's hashlib and
+//! cryptography.
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use blake2::digest::consts::U16;
+use blake2::digest::{KeyInit, Mac};
+use blake2::{Blake2s256, Blake2sMac, Digest};
+use hmac::SimpleHmac;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+const LABEL_MAC1: &[u8] = b"mac1----";
+
+const TYPE_INITIATION: u8 = 1;
+pub(crate) const TYPE_RESPONSE: u8 = 2;
+pub(crate) const TYPE_DATA: u8 = 4;
+
+const INITIATION_LEN: usize = 148;
+pub(crate) const RESPONSE_LEN: usize = 92;
+/// Type, receiver index and counter, before the sealed packet
+const DATA_HEADER_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+/// That of a keepalive, sealing an empty packet
+pub(crate) const DATA_MIN_LEN: usize = DATA_HEADER_LEN + TAG_LEN;
+
+/// Messages a session seals before a new one is needed
+const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+
+/// Counters behind the greatest one received that are still accepted
+const REPLAY_WINDOW: u64 = 2048;
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut state = Blake2s256::new();
+    for part in parts {
+        Digest::update(&mut state, part);
+    }
+    state.finalize().into()
+}
+
+/// Keyed BLAKE2s of 16 bytes
+fn mac(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut state = <Blake2sMac<U16> as KeyInit>::new_from_slice(key).unwrap();
+    Mac::update(&mut state, data);
+    state.finalize().into_bytes().into()
+}
+
+fn hmac(key: &[u8; 32], parts: &[&[u8]]) -> [u8; 32] {
+    let mut state = <SimpleHmac<Blake2s256> as KeyInit>::new_from_slice(key).unwrap();
+    for part in parts {
+        Mac::update(&mut state, part);
+    }
+    state.finalize().into_bytes().into()
+}
+
+/// The first `N` keys HKDF derives from `key` and `input`
+fn kdf<const N: usize>(key: &[u8; 32], input: &[u8]) -> [[u8; 32]; N] {
+    let prk = hmac(key, &[input]);
+    let mut out = [[0; 32]; N];
+    let mut prev = vec![];
+    for (i, key) in out.iter_mut().enumerate() {
+        *key = hmac(&prk, &[&prev, &[i as u8 + 1]]);
+        prev = key.to_vec();
+    }
+    out
+}
+
+fn aead_key(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).unwrap())
+}
+
+/// The counter in little endian after 4 zero bytes
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn seal(key: &LessSafeKey, counter: u64, aad: &[u8], buf: &mut Vec<u8>) {
+    key.seal_in_place_append_tag(nonce(counter), Aad::from(aad), buf)
+        .expect("Message too long to seal");
+}
+
+fn open(key: &LessSafeKey, counter: u64, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = sealed.to_vec();
+    let len = key
+        .open_in_place(nonce(counter), Aad::from(aad), &mut buf)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Message failed authentication"))?
+        .len();
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Fails for the low order points, which would make the result known
+fn dh(secret: &StaticSecret, public: &[u8; 32]) -> Result<[u8; 32]> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*public));
+    if !shared.was_contributory() {
+        return Err(Error::new(ErrorKind::InvalidData, "Low order public key"));
+    }
+    Ok(shared.to_bytes())
+}
+
+/// The current time as a TAI64N label, which the peer expects to grow
+/// from an initiation to the next
+pub(crate) fn tai64n() -> [u8; 12] {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut label = [0; 12];
+    label[..8].copy_from_slice(&(0x4000_0000_0000_000a + now.as_secs()).to_be_bytes());
+    label[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+    label
+}
+
+/// The receiver index of a response or data message
+pub(crate) fn receiver_index(msg: &[u8]) -> Option<u32> {
+    let index = match msg.first() {
+        Some(&TYPE_RESPONSE) if msg.len() == RESPONSE_LEN => &msg[8..12],
+        Some(&TYPE_DATA) if msg.len() >= DATA_MIN_LEN => &msg[4..8],
+        _ => return None,
+    };
+    Some(u32::from_le_bytes(index.try_into().unwrap()))
+}
+
+/// The keys of both ends, fixed for the life of the tunnel
+pub(crate) struct Identity {
+    secret: StaticSecret,
+    public: [u8; 32],
+    peer: [u8; 32],
+    psk: [u8; 32],
+    /// Of the mac1 of the messages to the peer and of those from it
+    peer_mac1_key: [u8; 32],
+    mac1_key: [u8; 32],
+}
+
+impl Identity {
+    /// Without a `psk`, that of zeros is used as the protocol specifies
+    pub(crate) fn new(private_key: [u8; 32], peer: [u8; 32], psk: Option<[u8; 32]>) -> Self {
+        let secret = StaticSecret::from(private_key);
+        let public = PublicKey::from(&secret).to_bytes();
+        Self {
+            secret,
+            public,
+            peer,
+            psk: psk.unwrap_or_default(),
+            peer_mac1_key: hash(&[LABEL_MAC1, &peer]),
+            mac1_key: hash(&[LABEL_MAC1, &public]),
+        }
+    }
+}
+
+/// The chaining key and hash of a handshake so far
+#[derive(Clone)]
+struct SymmetricState {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+}
+
+impl SymmetricState {
+    fn new(responder: &[u8; 32]) -> Self {
+        let chaining_key = hash(&[CONSTRUCTION]);
+        let hash = hash(&[&hash(&[&chaining_key, IDENTIFIER]), responder]);
+        Self { chaining_key, hash }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = hash(&[&self.hash, data]);
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        [self.chaining_key] = kdf(&self.chaining_key, input);
+    }
+
+    /// Mix `input` in, returning the key to seal the next field with
+    fn mix_key_and_derive(&mut self, input: &[u8]) -> [u8; 32] {
+        let [chaining_key, key] = kdf(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        key
+    }
+
+    fn seal_and_hash(&mut self, key: &[u8; 32], plain: &[u8]) -> Vec<u8> {
+        let mut buf = plain.to_vec();
+        seal(&aead_key(key), 0, &self.hash, &mut buf);
+        self.mix_hash(&buf);
+        buf
+    }
+
+    fn open_and_hash(&mut self, key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>> {
+        let plain = open(&aead_key(key), 0, &self.hash, sealed)?;
+        self.mix_hash(sealed);
+        Ok(plain)
+    }
+
+    /// Mix the preshared key in, the transport keys are derived next
+    fn mix_psk(&mut self, psk: &[u8; 32]) -> [u8; 32] {
+        let [chaining_key, tau, key] = kdf(&self.chaining_key, psk);
+        self.chaining_key = chaining_key;
+        self.mix_hash(&tau);
+        key
+    }
+}
+
+/// An initiation sent and waiting for the response
+pub(crate) struct Handshake {
+    index: u32,
+    ephemeral: StaticSecret,
+    state: SymmetricState,
+}
+
+impl Handshake {
+    /// The initiation of the session of local `index`, from `ephemeral`
+    /// random bytes at `timestamp`
+    pub(crate) fn initiate(
+        identity: &Identity,
+        index: u32,
+        ephemeral: [u8; 32],
+        timestamp: [u8; 12],
+    ) -> Result<(Self, Vec<u8>)> {
+        let ephemeral = StaticSecret::from(ephemeral);
+        let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+        let mut state = SymmetricState::new(&identity.peer);
+        state.mix_key(&ephemeral_public);
+        state.mix_hash(&ephemeral_public);
+        let key = state.mix_key_and_derive(&dh(&ephemeral, &identity.peer)?);
+        let sealed_static = state.seal_and_hash(&key, &identity.public);
+        let key = state.mix_key_and_derive(&dh(&identity.secret, &identity.peer)?);
+        let sealed_timestamp = state.seal_and_hash(&key, &timestamp);
+
+        let mut msg = Vec::with_capacity(INITIATION_LEN);
+        msg.extend_from_slice(&[TYPE_INITIATION, 0, 0, 0]);
+        msg.extend_from_slice(&index.to_le_bytes());
+        msg.extend_from_slice(&ephemeral_public);
+        msg.extend_from_slice(&sealed_static);
+        msg.extend_from_slice(&sealed_timestamp);
+        let mac1 = mac(&identity.peer_mac1_key, &msg);
+        msg.extend_from_slice(&mac1);
+        /* No cookie, mac2 is left zero */
+        msg.resize(INITIATION_LEN, 0);
+        Ok((Self { index, ephemeral, state }, msg))
+    }
+
+    /// The session the response `msg` makes, a forged one leaves the
+    /// handshake waiting for the genuine one
+    pub(crate) fn complete(&self, identity: &Identity, msg: &[u8]) -> Result<Session> {
+        if msg.len() != RESPONSE_LEN
+            || msg[0] != TYPE_RESPONSE
+            || receiver_index(msg) != Some(self.index)
+            || mac(&identity.mac1_key, &msg[..60]) != msg[60..76]
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a response to the initiation"));
+        }
+        let remote_index = u32::from_le_bytes(msg[4..8].try_into().unwrap());
+        let ephemeral: [u8; 32] = msg[12..44].try_into().unwrap();
+        let mut state = self.state.clone();
+        state.mix_key(&ephemeral);
+        state.mix_hash(&ephemeral);
+        state.mix_key(&dh(&self.ephemeral, &ephemeral)?);
+        state.mix_key(&dh(&identity.secret, &ephemeral)?);
+        let key = state.mix_psk(&identity.psk);
+        state.open_and_hash(&key, &msg[44..60])?;
+        let [send, recv] = kdf(&state.chaining_key, &[]);
+        Ok(Session::new(self.index, remote_index, &send, &recv))
+    }
+}
+
+/// Counters received lately, so that none is accepted twice
+struct ReplayWindow {
+    /// Past the greatest counter received
+    next: u64,
+    bits: [u64; (REPLAY_WINDOW / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self { next: 0, bits: [0; (REPLAY_WINDOW / 64) as usize] }
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let slot = counter % REPLAY_WINDOW;
+        ((slot / 64) as usize, 1 << (slot % 64))
+    }
+
+    /// Whether `counter` is neither too old nor seen already
+    fn check(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return counter < REJECT_AFTER_MESSAGES;
+        }
+        let (word, mask) = Self::bit(counter);
+        self.next - counter <= REPLAY_WINDOW && self.bits[word] & mask == 0
+    }
+
+    fn accept(&mut self, counter: u64) {
+        if counter >= self.next {
+            /* The slots of the counters skipped held those a window ago */
+            if counter - self.next >= REPLAY_WINDOW {
+                self.bits = [0; (REPLAY_WINDOW / 64) as usize];
+            } else {
+                for skipped in self.next..counter {
+                    let (word, mask) = Self::bit(skipped);
+                    self.bits[word] &= !mask;
+                }
+            }
+            self.next = counter + 1;
+        }
+        let (word, mask) = Self::bit(counter);
+        self.bits[word] |= mask;
+    }
+}
+
+/// The keys a handshake made, sealing and opening data messages
+pub(crate) struct Session {
+    local_index: u32,
+    remote_index: u32,
+    send: LessSafeKey,
+    recv: LessSafeKey,
+    send_counter: u64,
+    replay: ReplayWindow,
+}
+
+impl Session {
+    fn new(local_index: u32, remote_index: u32, send: &[u8; 32], recv: &[u8; 32]) -> Self {
+        Self {
+            local_index,
+            remote_index,
+            send: aead_key(send),
+            recv: aead_key(recv),
+            send_counter: 0,
+            replay: ReplayWindow::new(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn local_index(&self) -> u32 {
+        self.local_index
+    }
+
+    /// The data message of `packet` padded to 16 bytes, a keepalive when it
+    /// is empty, None once the session has sealed all it may
+    pub(crate) fn seal(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if self.send_counter >= REJECT_AFTER_MESSAGES {
+            return None;
+        }
+        let counter = self.send_counter;
+        self.send_counter += 1;
+        let mut body = Vec::with_capacity(packet.len().next_multiple_of(16) + TAG_LEN);
+        body.extend_from_slice(packet);
+        body.resize(packet.len().next_multiple_of(16), 0);
+        seal(&self.send, counter, &[], &mut body);
+
+        let mut msg = Vec::with_capacity(DATA_HEADER_LEN + body.len());
+        msg.extend_from_slice(&[TYPE_DATA, 0, 0, 0]);
+        msg.extend_from_slice(&self.remote_index.to_le_bytes());
+        msg.extend_from_slice(&counter.to_le_bytes());
+        msg.extend_from_slice(&body);
+        Some(msg)
+    }
+
+    /// The packet of the data message `msg` with its padding, empty for a
+    /// keepalive
+    pub(crate) fn open(&mut self, msg: &[u8]) -> Result<Vec<u8>> {
+        if receiver_index(msg) != Some(self.local_index) || msg[0] != TYPE_DATA {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a data message of the session"));
+        }
+        let counter = u64::from_le_bytes(msg[8..16].try_into().unwrap());
+        if !self.replay.check(counter) {
+            return Err(Error::new(ErrorKind::InvalidData, "Replayed data message"));
+        }
+        let packet = open(&self.recv, counter, &[], &msg[DATA_HEADER_LEN..])?;
+        self.replay.accept(counter);
+        Ok(packet)
+    }
+}
+
+/// The responder side of the handshake, answering `msg` as the peer of
+/// `secret` would with the session of `index`, along with the static key
+/// of the initiator
+#[cfg(test)]
+pub(crate) fn respond(
+    secret: &StaticSecret,
+    psk: [u8; 32],
+    msg: &[u8],
+    index: u32,
+    ephemeral: [u8; 32],
+) -> Result<([u8; 32], Vec<u8>, Session)> {
+    let public = PublicKey::from(secret).to_bytes();
+    if msg.len() != INITIATION_LEN
+        || msg[0] != TYPE_INITIATION
+        || mac(&hash(&[LABEL_MAC1, &public]), &msg[..116]) != msg[116..132]
+    {
+        return Err(Error::new(ErrorKind::InvalidData, "Not an initiation"));
+    }
+    let remote_index = u32::from_le_bytes(msg[4..8].try_into().unwrap());
+    let initiator_ephemeral: [u8; 32] = msg[8..40].try_into().unwrap();
+    let mut state = SymmetricState::new(&public);
+    state.mix_key(&initiator_ephemeral);
+    state.mix_hash(&initiator_ephemeral);
+    let key = state.mix_key_and_derive(&dh(secret, &initiator_ephemeral)?);
+    let initiator: [u8; 32] = state.open_and_hash(&key, &msg[40..88])?.try_into().unwrap();
+    let key = state.mix_key_and_derive(&dh(secret, &initiator)?);
+    state.open_and_hash(&key, &msg[88..116])?;
+
+    let ephemeral = StaticSecret::from(ephemeral);
+    let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+    state.mix_key(&ephemeral_public);
+    state.mix_hash(&ephemeral_public);
+    state.mix_key(&dh(&ephemeral, &initiator_ephemeral)?);
+    state.mix_key(&dh(&ephemeral, &initiator)?);
+    let key = state.mix_psk(&psk);
+    let sealed_empty = state.seal_and_hash(&key, &[]);
+
+    let mut response = Vec::with_capacity(RESPONSE_LEN);
+    response.extend_from_slice(&[TYPE_RESPONSE, 0, 0, 0]);
+    response.extend_from_slice(&index.to_le_bytes());
+    response.extend_from_slice(&remote_index.to_le_bytes());
+    response.extend_from_slice(&ephemeral_public);
+    response.extend_from_slice(&sealed_empty);
+    let mac1 = mac(&hash(&[LABEL_MAC1, &initiator]), &response);
+    response.extend_from_slice(&mac1);
+    response.resize(RESPONSE_LEN, 0);
+    let [recv, send] = kdf(&state.chaining_key, &[]);
+    Ok((initiator, response, Session::new(index, remote_index, &send, &recv)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handshake, Identity, ReplayWindow, hash, mac, respond, tai64n};
+
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_blake2s() {
+        assert_eq!(
+            hex(&hash(&[b"abc"])),
+            "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"
+        );
+        /* Across blocks, in pieces that do not line up with them */
+        let data: Vec<u8> = (0..200).collect();
+        assert_eq!(
+            hex(&hash(&[&data[..63], &data[63..130], &data[130..]])),
+            "6d244e1a06ce4ef578dd0f63aff0936706735119ca9c8d22d86c801414ab9741"
+        );
+        let key: Vec<u8> = (0..32).collect();
+        for (len, digest) in
+            [(64, "f1efa90d2547036841ecd3627fafbc36"), (100, "0b67d33f8b859c3157fbabd9e6e47ed0")]
+        {
+            assert_eq!(hex(&mac(&key, &data[..len])), digest);
+        }
+    }
+
+    /// The messages of a handshake with fixed keys and the first data
+    /// message after, as the whitepaper has them computed apart
+    #[test]
+    fn test_handshake_vectors() -> std::io::Result<()> {
+        let responder = StaticSecret::from([7; 32]);
+        let psk = [9; 32];
+        let identity = Identity::new([3; 32], PublicKey::from(&responder).to_bytes(), Some(psk));
+        let timestamp = [0x40, 0, 0, 0, 0x65, 0x53, 0xf1, 0, 0x07, 0x5b, 0xcd, 0x15];
+
+        let (handshake, initiation) = Handshake::initiate(&identity, 11, [5; 32], timestamp)?;
+        assert_eq!(
+            hex(&initiation),
+            "010000000b00000050a61409b1ddd0325e9b16b700e719e9772c07000b1bd7786e907c653d20495d\
+             eb045b1a1f60c601d0961c716018a5fe4e16c4ac5b814a3014fbee2eed4a99374e24df143550cdb3\
+             339cc4d580224061c4e9f847368af4a8a48f303f4f1ca8df688398cb03da7b074083880e9c68ab5e\
+             f8adf9f3d194d0d32f0af84e00000000000000000000000000000000"
+        );
+        let (_, response, _) = respond(&responder, psk, &initiation, 22, [6; 32])?;
+        assert_eq!(
+            hex(&response),
+            "02000000160000000b000000f5b2d6e60f9477e310c2982daaa6c9136c108a1777c5947e448fa37d\
+             68174557d0fb31442edbc47cd8d9915a05cd4dd91d0f56a0e3790bbddc4ddfc702a24f8600000000\
+             000000000000000000000000"
+        );
+        let mut session = handshake.complete(&identity, &response)?;
+        assert_eq!(
+            hex(&session.seal(b"hello").unwrap()),
+            "04000000160000000000000000000000be2029227f8537ac11d23a169d9cba2b662473b420fa18fd\
+             361836f95d4b5575"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake() -> std::io::Result<()> {
+        let responder = StaticSecret::from([7; 32]);
+        let psk = [9; 32];
+        let identity = Identity::new([3; 32], PublicKey::from(&responder).to_bytes(), Some(psk));
+
+        let (handshake, initiation) = Handshake::initiate(&identity, 11, [5; 32], tai64n())?;
+        let (initiator, response, mut responder_session) =
+            respond(&responder, psk, &initiation, 22, [6; 32])?;
+        assert_eq!(initiator, identity.public);
+
+        /* A response altered or for another index is not taken */
+        let mut forged = response.clone();
+        forged[50] ^= 1;
+        assert!(handshake.complete(&identity, &forged).is_err());
+        let (other, _) = Handshake::initiate(&identity, 12, [5; 32], tai64n())?;
+        assert!(other.complete(&identity, &response).is_err());
+        let mut session = handshake.complete(&identity, &response)?;
+        assert_eq!(session.local_index(), 11);
+
+        let msg = session.seal(b"hello").unwrap();
+        assert_eq!(msg.len(), 16 + 16 + 16);
+        let packet = responder_session.open(&msg)?;
+        assert_eq!(&packet[..5], b"hello");
+        assert!(packet[5..].iter().all(|b| *b == 0));
+        assert!(responder_session.open(&msg).is_err());
+        let keepalive = responder_session.seal(&[]).unwrap();
+        assert_eq!(session.open(&keepalive)?, b"");
+
+        /* Without the same preshared key, the response fails */
+        let (handshake, initiation) = Handshake::initiate(&identity, 13, [5; 32], tai64n())?;
+        let (_, response, _) = respond(&responder, [0; 32], &initiation, 23, [6; 32])?;
+        assert!(handshake.complete(&identity, &response).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+        for counter in [0, 2, 1, 5000, 3000] {
+            assert!(window.check(counter));
+            window.accept(counter);
+            assert!(!window.check(counter));
+        }
+        /* Out of the window, however never seen */
+        assert!(!window.check(2952));
+        assert!(window.check(2953));
+        assert!(window.check(4999));
+    }
+}
@@ -0,0 +1,208 @@
+//! WebSocket transport of the node-to-node stream, for networks where only
+//! HTTP upgrades get through, such as reverse proxies and CDNs.
+//!
+//! Unlike [crate::obfs::WebSocketObfuscator], which only has to look like
+//! WebSocket on the wire, this speaks the protocol in full through
+//! tungstenite: pings are answered, fragmented messages reassembled and
+//! close frames exchanged, so intermediaries that terminate the WebSocket
+//! connection relay it like any other.
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use crate::obfs::{BoxedFuture, BoxedStream, Obfuscator, Role};
+
+fn io_error(e: tungstenite::Error) -> Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            ErrorKind::BrokenPipe.into()
+        }
+        e => Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+/// Carries the stream as binary messages of a WebSocket connection to
+/// `ws://<host><path>`, the server side only accepts upgrades of `path`
+#[derive(Debug, Clone)]
+pub struct WebSocketTransport {
+    host: String,
+    path: String,
+    /// Sent along with the upgrade request, e.g. for a CDN to route on
+    headers: Vec<(String, String)>,
+}
+
+impl WebSocketTransport {
+    pub fn new(host: &str, path: &str) -> Self {
+        Self { host: host.to_string(), path: path.to_string(), headers: vec![] }
+    }
+
+    /// Add a header to the upgrade request
+    pub fn header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    #[inline]
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn client_request(&self) -> Result<Request> {
+        let uri = format!("ws://{}{}", self.host, self.path);
+        let mut req = uri.into_client_request().map_err(io_error)?;
+        for (name, value) in self.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            let value =
+                HeaderValue::from_str(value).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            req.headers_mut().append(name, value);
+        }
+        Ok(req)
+    }
+
+    async fn connect(&self, stream: BoxedStream) -> Result<WebSocketStream<BoxedStream>> {
+        let req = self.client_request()?;
+        let (ws_stream, _) =
+            tokio_tungstenite::client_async(req, stream).await.map_err(io_error)?;
+        Ok(ws_stream)
+    }
+
+    async fn accept(&self, stream: BoxedStream) -> Result<WebSocketStream<BoxedStream>> {
+        let path = self.path.to_owned();
+        /* The signature is that of tungstenite callbacks */
+        #[allow(clippy::result_large_err)]
+        let check_path = move |req: &Request, resp: Response| {
+            if req.uri().path() == path {
+                return Ok(resp);
+            }
+            let mut not_found = ErrorResponse::new(None);
+            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            Err(not_found)
+        };
+        tokio_tungstenite::accept_hdr_async(stream, check_path).await.map_err(io_error)
+    }
+}
+
+impl Obfuscator for WebSocketTransport {
+    fn name(&self) -> &'static str {
+        "ws"
+    }
+
+    fn obfuscate(&self, stream: BoxedStream, role: Role) -> BoxedFuture<Result<BoxedStream>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let ws_stream = match role {
+                Role::Client => this.connect(stream).await?,
+                Role::Server => this.accept(stream).await?,
+            };
+            Ok(Box::new(WsByteStream { inner: ws_stream, decoded: vec![] }) as BoxedStream)
+        })
+    }
+}
+
+/// The bytes carried by the binary messages of a WebSocket connection
+struct WsByteStream {
+    inner: WebSocketStream<BoxedStream>,
+    /// Received bytes not read yet
+    decoded: Vec<u8>,
+}
+
+impl AsyncRead for WsByteStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while this.decoded.is_empty() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.decoded = data,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                /* Pings are answered by tungstenite, text carries nothing for us */
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+        let len = buf.remaining().min(this.decoded.len());
+        buf.put_slice(&this.decoded[..len]);
+        this.decoded.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WsByteStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let mut inner = Pin::new(&mut self.get_mut().inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(io_error)?;
+        inner.start_send(Message::binary(buf)).map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WebSocketTransport;
+    use crate::obfs::{BoxedStream, Obfuscator, Role};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    #[test]
+    fn test_websocket_transport() -> std::io::Result<()> {
+        let tokio_rt = tokio::runtime::Runtime::new()?;
+        tokio_rt.block_on(async {
+            let mut transport = WebSocketTransport::new("cdn.example.com", "/relay");
+            transport.header("X-Forwarded-Host", "node.example.com");
+            let payload = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+            let (client, server) = duplex(4096);
+            let (client, server) = tokio::join!(
+                transport.obfuscate(Box::new(client) as BoxedStream, Role::Client),
+                transport.obfuscate(Box::new(server) as BoxedStream, Role::Server),
+            );
+            let (mut client, mut server) = (client?, server?);
+            let to_send = payload.clone();
+            let writer = tokio::spawn(async move {
+                client.write_all(&to_send).await?;
+                client.shutdown().await
+            });
+            let mut received = vec![];
+            server.read_to_end(&mut received).await?;
+            writer.await??;
+            assert_eq!(received, payload);
+
+            /* Upgrades of other paths are turned away */
+            let (client, server) = duplex(4096);
+            let other = WebSocketTransport::new("cdn.example.com", "/other");
+            let (client, server) = tokio::join!(
+                other.obfuscate(Box::new(client) as BoxedStream, Role::Client),
+                transport.obfuscate(Box::new(server) as BoxedStream, Role::Server),
+            );
+            assert!(client.is_err());
+            assert!(server.is_err());
+            Ok(())
+        })
+    }
+}
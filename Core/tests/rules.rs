@@ -0,0 +1,118 @@
+//! Routing decisions declared by the YAML fixtures in `tests/rules`
+//!
+//! Each fixture holds a ruleset and cases of a destination, optionally the
+//! host name sniffed from the flow and its protocol, with the rule expected
+//! to match and the resulting action. Rulesets from bug reports are shipped
+//! as regression tests by dropping them in as another fixture:
+//!
+//! ```yaml
+//! rules:
+//!   - DOMAIN-SUFFIX,ads.example,REJECT
+//!   - MATCH,PROXY
+//! cases:
+//!   - destination: tracker.ads.example:443
+//!     rule: DOMAIN-SUFFIX,ads.example,REJECT
+//!     action: REJECT
+//!   - destination: 93.184.216.34:443
+//!     sniffed: www.ads.example
+//!     rule: DOMAIN-SUFFIX,ads.example,REJECT
+//!     action: REJECT
+//! ```
+//!
+//! A case whose `rule` is left out expects no rule to match.
+
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use nstream_core::{Router, Rule, RuleAction, TrafficClass};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    rules: Vec<Rule>,
+    cases: Vec<Case>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Case {
+    /// `host:port`, by domain name or IP address
+    destination: String,
+    /// The host name sniffed from the flow to an IP address
+    sniffed: Option<String>,
+    protocol: Option<String>,
+    rule: Option<String>,
+    action: String,
+}
+
+impl Case {
+    /// The domain name and IP address the router is asked about
+    fn destination(&self) -> (Option<String>, Option<IpAddr>) {
+        if let Ok(socket_addr) = self.destination.parse::<SocketAddr>() {
+            return (self.sniffed.clone(), Some(socket_addr.ip()));
+        }
+        let host = match self.destination.rsplit_once(':') {
+            Some((host, _)) => host,
+            None => &self.destination,
+        };
+        match host.parse::<IpAddr>() {
+            Ok(ip) => (self.sniffed.clone(), Some(ip)),
+            Err(_) => (Some(host.to_string()), None),
+        }
+    }
+
+    /// What went wrong, if the router decides otherwise
+    fn check(&self, router: &Router) -> Result<(), String> {
+        let class = self.protocol.as_deref().map(str::parse::<TrafficClass>).transpose()?;
+        let expected_action = self.action.parse::<RuleAction>()?;
+        let expected_rule = self.rule.as_deref().map(str::parse::<Rule>).transpose()?;
+
+        let (domain, ip) = self.destination();
+        let rule = router.matched_rule_classified(domain.as_deref(), ip, class);
+        let action = router.decide_classified(domain.as_deref(), ip, class);
+        if rule != expected_rule.as_ref() || action != expected_action {
+            return Err(format!(
+                "expected {} by {}, got {} by {}",
+                expected_action,
+                expected_rule.map(|rule| rule.to_string()).unwrap_or_else(|| "no rule".into()),
+                action,
+                rule.map(|rule| rule.to_string()).unwrap_or_else(|| "no rule".into()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_rule_fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/rules");
+    let mut paths = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "no fixtures in {}", dir.display());
+
+    /* Every failing case is reported, not only the first */
+    let mut failures = Vec::new();
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let fixture = match serde_yaml::from_str::<Fixture>(&fs::read_to_string(&path).unwrap()) {
+            Ok(fixture) => fixture,
+            Err(e) => {
+                failures.push(format!("{}: {}", name, e));
+                continue;
+            }
+        };
+        let router = Router::new(fixture.rules);
+        for (i, case) in fixture.cases.iter().enumerate() {
+            if let Err(e) = case.check(&router) {
+                failures.push(format!("{} case {} ({}): {}", name, i + 1, case.destination, e));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
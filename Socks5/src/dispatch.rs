@@ -0,0 +1,148 @@
+//! Protocol version dispatch
+//!
+//! Every SOCKS version starts a connection with its version number, so the
+//! first byte is peeked (not consumed) and the connection is handed to the
+//! handler registered for that version, which then parses the whole
+//! request itself. Connections are [TcpStream]s unless a dispatcher is made
+//! for another [Peek]able stream, e.g. a [crate::stream::Peekable] one.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Result;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::stream::Peek;
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Serves connections of one protocol version, `C` is whatever per
+/// connection context the server passes along
+pub trait VersionHandler<C, S = TcpStream>: Send + Sync {
+    fn handle(&self, stream: S, ctx: C) -> HandlerFuture;
+}
+
+impl<C, S, F, Fut> VersionHandler<C, S> for F
+where
+    F: Fn(S, C) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    fn handle(&self, stream: S, ctx: C) -> HandlerFuture {
+        Box::pin(self(stream, ctx))
+    }
+}
+
+/// Peek the version number of a connection without consuming it
+#[inline]
+pub async fn peek_version<S: Peek>(stream: &mut S) -> Result<u8> {
+    stream.peek_byte().await
+}
+
+pub struct Dispatcher<C, S = TcpStream> {
+    handlers: HashMap<u8, Arc<dyn VersionHandler<C, S>>>,
+    fallback: Option<Arc<dyn VersionHandler<C, S>>>,
+    peek_timeout: Option<Duration>,
+}
+
+impl<C, S> Default for Dispatcher<C, S> {
+    fn default() -> Self {
+        Self { handlers: HashMap::new(), fallback: None, peek_timeout: None }
+    }
+}
+
+impl<C, S: Peek> Dispatcher<C, S> {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` for connections starting with `version`,
+    /// replacing the previous handler of that version
+    pub fn register<H>(&mut self, version: u8, handler: H) -> &mut Self
+    where
+        H: VersionHandler<C, S> + 'static,
+    {
+        self.handlers.insert(version, Arc::new(handler));
+        self
+    }
+
+    /// Serves the versions no handler is registered for, such connections
+    /// are dropped when there is none
+    pub fn fallback<H>(&mut self, handler: H) -> &mut Self
+    where
+        H: VersionHandler<C, S> + 'static,
+    {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    /// How long a client may stay silent before sending its version
+    pub fn peek_timeout(&mut self, peek_timeout: Duration) -> &mut Self {
+        self.peek_timeout = Some(peek_timeout);
+        self
+    }
+
+    /// Registered versions in ascending order
+    pub fn versions(&self) -> Vec<u8> {
+        let mut versions = self.handlers.keys().copied().collect::<Vec<_>>();
+        versions.sort();
+        versions
+    }
+
+    pub async fn dispatch(&self, mut stream: S, ctx: C) -> Result<()> {
+        let ver = match self.peek_timeout {
+            Some(peek_timeout) => {
+                crate::with_deadline(Instant::now() + peek_timeout, peek_version(&mut stream))
+                    .await?
+            }
+            None => peek_version(&mut stream).await?,
+        };
+        match self.handlers.get(&ver).or(self.fallback.as_ref()) {
+            Some(handler) => handler.handle(stream, ctx).await,
+            None => {
+                Err(crate::Socks5Error::VersionMismatch { protocol: "socks", version: ver }.into())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dispatch() -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+
+    tokio_rt.block_on(async {
+        let handled = Arc::new(AtomicU8::new(0));
+        let mut dispatcher = Dispatcher::<Arc<AtomicU8>>::new();
+        dispatcher
+            .register(crate::SOCKS_VERSION, |_, handled: Arc<AtomicU8>| async move {
+                handled.store(5, Ordering::SeqCst);
+                Ok(())
+            })
+            .register(crate::socks4::SOCKS4_VERSION, |_, handled: Arc<AtomicU8>| async move {
+                handled.store(4, Ordering::SeqCst);
+                Ok(())
+            });
+        assert_eq!(dispatcher.versions(), [4, 5]);
+
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        for ver in [5u8, 4, 6] {
+            let mut client = TcpStream::connect(tcp_listener.local_addr()?).await?;
+            client.write_all(&[ver, 1, 0]).await?;
+            let (tcp_stream, _) = tcp_listener.accept().await?;
+            let ret = dispatcher.dispatch(tcp_stream, handled.clone()).await;
+            if ver == 6 {
+                assert!(ret.is_err());
+            } else {
+                assert_eq!(handled.load(Ordering::SeqCst), ver);
+            }
+        }
+        Ok(())
+    })
+}
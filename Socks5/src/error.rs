@@ -0,0 +1,110 @@
+//! Why a SOCKS message could not be read, or a request was not served
+//!
+//! The parsers return [std::io::Result] like the streams they read from, a
+//! [Socks5Error] travels inside the [Error] they fail with and is found
+//! back with [Socks5Error::of]. Its [ErrorKind] is kept as it was, so
+//! matching on the kind still works. Servers fail requests their rules
+//! refuse with [Socks5Error::Blocked] the same way, for the reply to tell.
+
+use std::io::{Error, ErrorKind};
+
+use crate::protocol::ReplyField;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Socks5Error {
+    /// A VER other than the one of the message
+    #[error("Unsupported {protocol} version: {version:#04x}")]
+    VersionMismatch { protocol: &'static str, version: u8 },
+    #[error("Unsupported {protocol} command: {cmd:#04x}")]
+    UnsupportedCommand { protocol: &'static str, cmd: u8 },
+    #[error("Unknown address type: {0:#04x}")]
+    BadAddressType(u8),
+    /// A reply code no version defines
+    #[error("Unsupported {protocol} reply: {code:#04x}")]
+    UnsupportedReply { protocol: &'static str, code: u8 },
+    /// The server refused the credentials
+    #[error("Authentication failed")]
+    AuthFailed,
+    /// The stream ended in the middle of a message
+    #[error("Message cut short")]
+    Truncated,
+    /// A rule of the server refuses the destination, as it is written
+    #[error("Blocked by {rule}")]
+    Blocked { rule: String },
+    #[error(transparent)]
+    Io(Error),
+}
+
+impl Socks5Error {
+    /// The error `e` carries, if it comes from parsing a message
+    pub fn of(e: &Error) -> Option<&Socks5Error> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<Socks5Error>())
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::VersionMismatch { .. }
+            | Self::UnsupportedCommand { .. }
+            | Self::BadAddressType(_)
+            | Self::UnsupportedReply { .. } => ErrorKind::Unsupported,
+            Self::AuthFailed | Self::Blocked { .. } => ErrorKind::PermissionDenied,
+            Self::Truncated => ErrorKind::UnexpectedEof,
+            Self::Io(e) => e.kind(),
+        }
+    }
+
+    /// What a server answers the request with, None when the request gets
+    /// no reply as its VER is not understood or it cannot be read at all
+    pub fn reply_field(&self) -> Option<ReplyField> {
+        match self {
+            Self::UnsupportedCommand { .. } => Some(ReplyField::CommandNotSupported),
+            Self::BadAddressType(_) => Some(ReplyField::AddressTypeNotSupported),
+            Self::Blocked { .. } => Some(ReplyField::ConnectionNotAllowedByRuleSet),
+            _ => None,
+        }
+    }
+}
+
+impl From<Socks5Error> for Error {
+    fn from(value: Socks5Error) -> Self {
+        match value {
+            Socks5Error::Io(e) => e,
+            _ => Error::new(value.kind(), value),
+        }
+    }
+}
+
+/// The [Socks5Error] `value` carries, the end of the stream being
+/// [Socks5Error::Truncated]
+impl From<Error> for Socks5Error {
+    fn from(value: Error) -> Self {
+        if Self::of(&value).is_some() {
+            return *value.into_inner().unwrap().downcast::<Socks5Error>().unwrap();
+        }
+        match value.kind() {
+            ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(value),
+        }
+    }
+}
+
+#[test]
+fn test_socks5_error() {
+    let e = Error::from(Socks5Error::UnsupportedCommand { protocol: "socks5", cmd: 9 });
+    assert_eq!(e.kind(), ErrorKind::Unsupported);
+    assert_eq!(e.to_string(), "Unsupported socks5 command: 0x09");
+    assert_eq!(ReplyField::from(&e), ReplyField::CommandNotSupported);
+    assert!(matches!(Socks5Error::from(e), Socks5Error::UnsupportedCommand { cmd: 9, .. }));
+
+    let e = Error::from(ErrorKind::UnexpectedEof);
+    assert!(Socks5Error::of(&e).is_none());
+    assert!(matches!(Socks5Error::from(e), Socks5Error::Truncated));
+    let e = Error::from(Socks5Error::Io(Error::from(ErrorKind::ConnectionRefused)));
+    assert_eq!(ReplyField::from(&e), ReplyField::ConnectionRefused);
+    assert_eq!(Socks5Error::from(e).kind(), ErrorKind::ConnectionRefused);
+
+    let e = Error::from(Socks5Error::Blocked { rule: String::from("MATCH,REJECT") });
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    assert_eq!(e.to_string(), "Blocked by MATCH,REJECT");
+    assert_eq!(ReplyField::from(&e), ReplyField::ConnectionNotAllowedByRuleSet);
+}
@@ -1,21 +1,48 @@
+pub mod dispatch;
+mod error;
 pub mod protocol;
+pub mod socks4;
+#[cfg(feature = "socks6")]
+pub mod socks6;
+pub mod stream;
+pub mod testing;
+pub mod trace;
+pub mod udp_pool;
 
+use std::future::Future;
 #[cfg(debug_assertions)]
 use std::io::Read;
-use std::io::{Error, ErrorKind, Result};
+use std::io::{Error, ErrorKind, IoSlice, Result};
+use std::time::Duration;
 
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite},
-    net::TcpStream,
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{timeout_at, Instant},
 };
 
+pub use error::Socks5Error;
+
 pub const SOCKS_VERSION: u8 = 0x05;
 pub const AUTH_VERSION: u8 = 0x01;
 pub const RSV_RESERVED: u8 = 0x00;
 
+/// Malformed input, as opposed to a well-formed request for something
+/// unsupported
 #[inline]
-pub(crate) fn throw_io_error(msg: &str) -> Error {
-    Error::new(ErrorKind::Unsupported, msg)
+pub(crate) fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Run a handshake step, failing with [ErrorKind::TimedOut] once `deadline`
+/// passes so a client that stops sending cannot hold the connection forever
+pub async fn with_deadline<T, F>(deadline: Instant, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match timeout_at(deadline, fut).await {
+        Ok(ret) => ret,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "Handshake timed out")),
+    }
 }
 
 pub(crate) async fn check_socks_ver<R>(r: &mut R) -> Result<()>
@@ -24,7 +51,7 @@ where
 {
     let ver = r.read_u8().await?;
     if ver != SOCKS_VERSION {
-        Err(throw_io_error(&format!("Unsupported socks version: {:#04x}", ver)))
+        Err(Socks5Error::VersionMismatch { protocol: "socks", version: ver }.into())
     } else {
         Ok(())
     }
@@ -36,7 +63,7 @@ where
 {
     let ver = r.read_u8().await?;
     if ver != AUTH_VERSION {
-        Err(throw_io_error(&format!("Unsupported authentication version: {:#04x}", ver)))
+        Err(Socks5Error::VersionMismatch { protocol: "authentication", version: ver }.into())
     } else {
         Ok(())
     }
@@ -48,24 +75,138 @@ where
 {
     let rsv = r.read_u8().await?;
     if rsv != RSV_RESERVED {
-        Err(throw_io_error(&format!("Unsupported RSV flag: {:#04x}", rsv)))
+        Err(invalid_data(&format!("Unsupported RSV flag: {:#04x}", rsv)))
     } else {
         Ok(())
     }
 }
 
+/// Write all of `bufs`, as few syscalls as the writer allows
+pub(crate) async fn write_all_vectored<W>(w: &mut W, mut bufs: &mut [IoSlice<'_>]) -> Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    while !bufs.is_empty() {
+        let n = w.write_vectored(bufs).await?;
+        if n == 0 {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut bufs, n);
+    }
+    Ok(())
+}
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Copy from `r` to `w` until EOF, which is passed on by shutting down `w`,
+/// adding what is copied to `copied`
+async fn copy_half<R, W>(r: &mut R, w: &mut W, copied: &mut u64) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    loop {
+        let len = r.read(&mut buf).await?;
+        if len == 0 {
+            return w.shutdown().await;
+        }
+        w.write_all(&buf[..len]).await?;
+        /* Within TLS, what is written may wait in a record otherwise */
+        w.flush().await?;
+        *copied += len as u64;
+    }
+}
+
+/// Relay between `from` and `to` until both directions reach EOF, each
+/// passing its EOF on as soon as it reaches it while the other goes on:
+/// a client half-closing after its request still receives the response.
+/// Returns the bytes sent from `from` to `to`, then those back. An error in
+/// either direction ends both.
 #[inline]
 pub async fn exchange_data<F, T>(from: &mut F, to: &mut T) -> Result<(u64, u64)>
 where
     F: AsyncRead + AsyncWrite + Unpin + ?Sized,
     T: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
-    Ok(copy_bidirectional(from, to).await?)
+    exchange(from, to, None).await
 }
 
-pub async fn wait_closed(tcp_stream: &mut TcpStream) -> Result<()> {
+/// [exchange_data], the direction still open once the other reached EOF
+/// being closed after `half_close_timeout`, for peers which never close
+/// their side
+#[inline]
+pub async fn exchange_data_timeout<F, T>(
+    from: &mut F,
+    to: &mut T,
+    half_close_timeout: Duration,
+) -> Result<(u64, u64)>
+where
+    F: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    T: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    exchange(from, to, Some(half_close_timeout)).await
+}
+
+async fn exchange<F, T>(
+    from: &mut F,
+    to: &mut T,
+    half_close_timeout: Option<Duration>,
+) -> Result<(u64, u64)>
+where
+    F: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    T: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (mut from_r, mut from_w) = split(from);
+    let (mut to_r, mut to_w) = split(to);
+    let (mut sent, mut received) = (0u64, 0u64);
+    let timed_out = {
+        let send = copy_half(&mut from_r, &mut to_w, &mut sent);
+        let receive = copy_half(&mut to_r, &mut from_w, &mut received);
+        tokio::pin!(send, receive);
+        let (mut send_done, mut receive_done) = (false, false);
+        /* Set once a direction is done, for the other */
+        let mut deadline = None;
+        loop {
+            let half_closed = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                ret = &mut send, if !send_done => {
+                    ret?;
+                    send_done = true;
+                }
+                ret = &mut receive, if !receive_done => {
+                    ret?;
+                    receive_done = true;
+                }
+                _ = half_closed => break true,
+            }
+            if send_done && receive_done {
+                break false;
+            }
+            deadline = deadline.or(half_close_timeout.map(|timeout| Instant::now() + timeout));
+        }
+    };
+    if timed_out {
+        /* The side not closed yet, that which reached EOF already is */
+        let _ = from_w.shutdown().await;
+        let _ = to_w.shutdown().await;
+    }
+    Ok((sent, received))
+}
+
+/// Wait for the peer of `stream` to close it, dropping whatever it still
+/// sends
+pub async fn wait_closed<R>(stream: &mut R) -> Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
     loop {
-        match tcp_stream.read(&mut [0]).await {
+        match stream.read(&mut [0]).await {
             Ok(0) => break Ok(()),
             Ok(_) => {}
             Err(err) => break Err(err),
@@ -97,5 +238,52 @@ where
     Ok(u16::from_be_bytes([b0, b1]))
 }
 
+#[test]
+fn test_exchange_data_half_close() -> Result<()> {
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    tokio_rt.block_on(async {
+        let (mut client, mut from) = tokio::io::duplex(64);
+        let (mut to, mut server) = tokio::io::duplex(64);
+        let relay = tokio::spawn(async move { exchange_data(&mut from, &mut to).await });
+
+        /* The request, then EOF, which reaches the server */
+        client.write_all(b"request").await?;
+        client.shutdown().await?;
+        let mut request = vec![];
+        server.read_to_end(&mut request).await?;
+        assert_eq!(request, b"request");
+        /* The response still goes back */
+        server.write_all(b"response").await?;
+        server.shutdown().await?;
+        let mut response = vec![];
+        client.read_to_end(&mut response).await?;
+        assert_eq!(response, b"response");
+        assert_eq!(relay.await??, (7, 8));
+        Ok(())
+    })
+}
+
+#[test]
+fn test_exchange_data_timeout() -> Result<()> {
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    tokio_rt.block_on(async {
+        let (mut client, mut from) = tokio::io::duplex(64);
+        let (mut to, mut server) = tokio::io::duplex(64);
+        let relay = tokio::spawn(async move {
+            exchange_data_timeout(&mut from, &mut to, Duration::from_millis(100)).await
+        });
+        client.shutdown().await?;
+        server.write_all(b"partial").await?;
+        /* The server never closes its side */
+        let ret = tokio::time::timeout(Duration::from_secs(5), relay).await;
+        assert_eq!(ret.expect("Relay still open")??, (0, 7));
+        let mut response = vec![];
+        client.read_to_end(&mut response).await?;
+        assert_eq!(response, b"partial");
+        drop(server);
+        Ok(())
+    })
+}
+
 #[cfg(test)]
 mod tests {}
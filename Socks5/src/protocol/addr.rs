@@ -1,13 +1,74 @@
 use super::AddressType;
 
 use core::mem::size_of;
-use std::io::Result;
+use std::io::{IoSlice, Result};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
 use std::str::FromStr;
 use std::vec::IntoIter;
 
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+/// The length of a domain name is sent as a single octet
+pub const MAX_DOMAIN_LEN: usize = u8::MAX as usize;
+/// Longest label allowed by RFC 1035
+const MAX_LABEL_LEN: usize = 63;
+
+/// Convert `name` to its ASCII form (punycode for Unicode labels) and check
+/// that it can be sent as the ADDR of an FQDN address
+pub fn normalize_domain(name: &str) -> Result<String> {
+    let name = if name.is_ascii() {
+        name.to_string()
+    } else {
+        idna::domain_to_ascii(name)
+            .map_err(|e| crate::invalid_data(&format!("Invalid domain name {:?}: {}", name, e)))?
+    };
+    validate_domain(&name)?;
+    Ok(name)
+}
+
+/// Letters, digits, hyphens and underscores in non-empty labels of at most
+/// 63 octets, a trailing dot is allowed
+pub fn validate_domain(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(crate::invalid_data("Empty domain name"));
+    }
+    if name.len() > MAX_DOMAIN_LEN {
+        return Err(crate::invalid_data(&format!(
+            "Domain name longer than {} octets: {}",
+            MAX_DOMAIN_LEN,
+            name.len()
+        )));
+    }
+    let labels = name.strip_suffix('.').unwrap_or(name);
+    for label in labels.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(crate::invalid_data(&format!("Invalid label in domain name {:?}", name)));
+        }
+        if !label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(crate::invalid_data(&format!(
+                "Invalid character in domain name {:?}",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
 
+/// `addr` with an IPv4-mapped IPv6 address, as a dual-stack socket reports
+/// IPv4 peers, turned into the IPv4 address it stands for
+pub fn unmap_socket_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6addr) => match v6addr.ip().to_ipv4_mapped() {
+            Some(v4addr) => SocketAddr::from((v4addr, v6addr.port())),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// IP addresses built from socket addresses and parsed from the wire are
+/// canonical, an IPv4-mapped IPv6 one becomes IPv4, and are always
+/// serialized that way
 #[derive(Debug, Clone, PartialEq)]
 pub enum Address {
     IP(SocketAddr),
@@ -22,7 +83,7 @@ impl From<SocketAddrV4> for Address {
 
 impl From<SocketAddrV6> for Address {
     fn from(v6addr: SocketAddrV6) -> Self {
-        Self::IP(SocketAddr::V6(v6addr))
+        Self::IP(unmap_socket_addr(SocketAddr::V6(v6addr)))
     }
 }
 
@@ -37,7 +98,7 @@ impl From<SocketAddr> for Address {
 
 impl From<(IpAddr, u16)> for Address {
     fn from(pair: (IpAddr, u16)) -> Self {
-        Self::IP(SocketAddr::new(pair.0, pair.1))
+        SocketAddr::new(pair.0, pair.1).into()
     }
 }
 
@@ -57,16 +118,16 @@ impl TryFrom<String> for Address {
     type Error = Box<dyn std::error::Error>;
     fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
         let addr = value;
-        let split_idx = addr.rfind(":").unwrap();
+        let split_idx = addr.rfind(":").ok_or("Missing port")?;
         let addr_splitted = addr.split_at(split_idx);
         let ip_addr_or_domain = addr_splitted.0.replace("[", "").replace("]", "");
         let port = u16::from_str(&(addr_splitted.1)[1..])?;
         if let Ok(ip_v4_addr) = ip_addr_or_domain.parse::<Ipv4Addr>() {
             Ok(Self::IP(SocketAddr::V4(SocketAddrV4::new(ip_v4_addr, port))))
         } else if let Ok(ip_v6_addr) = ip_addr_or_domain.parse::<Ipv6Addr>() {
-            Ok(Self::IP(SocketAddr::V6(SocketAddrV6::new(ip_v6_addr, port, 0, 0))))
+            Ok(SocketAddrV6::new(ip_v6_addr, port, 0, 0).into())
         } else {
-            Ok(Self::Domain(ip_addr_or_domain, port))
+            Ok(Self::domain(&ip_addr_or_domain, port)?)
         }
     }
 }
@@ -90,8 +151,8 @@ impl TryInto<SocketAddr> for Address {
     type Error = std::io::Error;
     fn try_into(self) -> std::result::Result<SocketAddr, Self::Error> {
         let opt_socket_addr = TryInto::<Option<SocketAddr>>::try_into(self)?;
-        let socket_addr = opt_socket_addr.ok_or("Invalid address").unwrap();
-        Ok(socket_addr)
+        opt_socket_addr
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No address resolved"))
     }
 }
 
@@ -144,12 +205,14 @@ impl Address {
                 .into(),
             AddressType::FQDN => {
                 let dnlen = (r.read_u8().await?) as usize;
+                if dnlen == 0 {
+                    return Err(crate::invalid_data("Empty domain name"));
+                }
                 let mut buf = vec![0u8; dnlen];
-                r.read(&mut buf).await?;
-                Address::Domain(
-                    String::from_utf8_lossy(&buf).to_string(),
-                    /* port */ r.read_u16().await?,
-                )
+                r.read_exact(&mut buf).await?;
+                let name = String::from_utf8(buf)
+                    .map_err(|_| crate::invalid_data("Domain name is not UTF-8"))?;
+                Address::domain(&name, /* port */ r.read_u16().await?)?
             }
             AddressType::IPV6 => (
                 Ipv6Addr::new(
@@ -180,10 +243,14 @@ impl Address {
     ///      |  Var |   2  |
     ///      +------+------+
     /// ```
-    pub(crate) fn as_socks_bytes(&self) -> Vec<u8> {
+    ///
+    /// A domain name that does not [validate](Address::validate) is an
+    /// error rather than a corrupt frame.
+    pub(crate) fn as_socks_bytes(&self) -> Result<Vec<u8>> {
+        self.validate()?;
         let mut ret = vec![];
         match self {
-            Self::IP(addr) => match addr.ip() {
+            Self::IP(addr) => match addr.ip().to_canonical() {
                 IpAddr::V4(v4addr) => ret.extend_from_slice(&v4addr.octets()),
                 IpAddr::V6(v6addr) => ret.extend_from_slice(&v6addr.octets()),
             },
@@ -194,7 +261,71 @@ impl Address {
             }
         }
         ret.extend_from_slice(&self.port().to_be_bytes()); /* PORT */
-        ret
+        Ok(ret)
+    }
+
+    /// Write `head`, then [Address::as_socks_bytes], then `tail`, without
+    /// assembling them in a buffer first
+    pub(crate) async fn write_socks_with<W>(
+        &self,
+        w: &mut W,
+        head: &[u8],
+        tail: &[u8],
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        self.validate()?;
+        let mut ip_octets = [0u8; 16];
+        let dnlen;
+        let (addr_bytes, name_bytes): (&[u8], &[u8]) = match self {
+            Self::IP(addr) => match addr.ip().to_canonical() {
+                IpAddr::V4(v4addr) => {
+                    ip_octets[..4].copy_from_slice(&v4addr.octets());
+                    (&ip_octets[..4], &[])
+                }
+                IpAddr::V6(v6addr) => {
+                    ip_octets.copy_from_slice(&v6addr.octets());
+                    (&ip_octets, &[])
+                }
+            },
+            Self::Domain(name, _) => {
+                dnlen = [name.len() as u8];
+                (&dnlen, name.as_bytes())
+            }
+        };
+        let port = self.port().to_be_bytes();
+        let mut bufs = [
+            IoSlice::new(head),
+            IoSlice::new(addr_bytes),
+            IoSlice::new(name_bytes),
+            IoSlice::new(&port),
+            IoSlice::new(tail),
+        ];
+        crate::write_all_vectored(w, &mut bufs).await
+    }
+
+    /// A domain name address, Unicode names are converted to punycode
+    pub fn domain(name: &str, port: u16) -> Result<Self> {
+        Ok(Self::Domain(normalize_domain(name)?, port))
+    }
+
+    /// The same address, an IPv4-mapped IPv6 one as IPv4, for addresses
+    /// built as [Address::IP] directly
+    pub fn to_canonical(&self) -> Self {
+        match self {
+            Self::IP(addr) => Self::IP(unmap_socket_addr(*addr)),
+            Self::Domain(..) => self.clone(),
+        }
+    }
+
+    /// Whether the address can be serialized, [Address::Domain] may have been
+    /// built from anything
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Self::IP(_) => Ok(()),
+            Self::Domain(name, _) => validate_domain(name),
+        }
     }
 
     pub fn port(&self) -> u16 {
@@ -207,7 +338,7 @@ impl Address {
     pub fn len(&self) -> usize {
         let ip_or_domain_size: usize;
         match self {
-            Self::IP(addr) => match addr.ip() {
+            Self::IP(addr) => match addr.ip().to_canonical() {
                 IpAddr::V4(_) => ip_or_domain_size = size_of::<u8>() * 4,
                 IpAddr::V6(_) => ip_or_domain_size = size_of::<u16>() * 8,
             },
@@ -269,14 +400,45 @@ fn test_from_socks_bytes() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_v4_mapped() -> Result<()> {
+    use tokio::io::BufReader;
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    let v4addr: Address = (Ipv4Addr::new(1, 2, 3, 4), 80).into();
+
+    let mapped = "[::ffff:1.2.3.4]:80".parse::<SocketAddr>().unwrap();
+    assert_eq!(Address::from(mapped), v4addr);
+    assert_eq!(Address::try_from(String::from("[::ffff:1.2.3.4]:80")).unwrap(), v4addr);
+    let mapped_bytes = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4, 0x00, 0x50];
+    let mut bufrd = BufReader::new(&mapped_bytes[..]);
+    let addr = tokio_rt.block_on(Address::from_socks_bytes(&mut bufrd, &AddressType::IPV6))?;
+    assert_eq!(addr, v4addr);
+
+    /* Built directly, it is still sent as IPv4 */
+    let addr = Address::IP(mapped);
+    assert_eq!(addr.as_socks_bytes()?, [1, 2, 3, 4, 0x00, 0x50]);
+    assert_eq!(AddressType::from(addr.clone()), AddressType::IPV4);
+    assert_eq!(addr.len(), 6);
+    assert_eq!(addr.to_canonical(), v4addr);
+
+    /* Other IPv6 addresses keep their scope */
+    let scoped = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 0, 2));
+    assert_eq!(Address::from(scoped), Address::IP(scoped));
+    Ok(())
+}
+
 #[test]
 fn test_as_socks_bytes() {
     let ipv4_addr: Address = (Ipv4Addr::LOCALHOST, 80).into();
-    assert_eq!(ipv4_addr.as_socks_bytes(), vec![127, 0, 0, 1, 0x00, 0x50]);
+    assert_eq!(ipv4_addr.as_socks_bytes().unwrap(), vec![127, 0, 0, 1, 0x00, 0x50]);
+
+    /* The length is a single octet, a longer name is not sent cut short */
+    assert!(Address::Domain("a".repeat(256), 80).as_socks_bytes().is_err());
+    assert!(Address::Domain(String::from("git hub.com"), 80).as_socks_bytes().is_err());
 
     let addr_dn: Address = Address::Domain(String::from("github.com"), 443);
     assert_eq!(
-        addr_dn.as_socks_bytes(),
+        addr_dn.as_socks_bytes().unwrap(),
         vec![
             /* dnlen */ 10, /* dnlen */
             103, 105, 116, 104, 117, 98, 46, 99, 111, 109, /* begin port */ 0x01, 0xbb
@@ -287,7 +449,7 @@ fn test_as_socks_bytes() {
         (Ipv6Addr::new(0x2001, 0xdb8, 0x1, 0x0, 0x20c, 0x29ff, 0xfe96, 0x8b55), 8080).into();
 
     assert_eq!(
-        ipv6_addr.as_socks_bytes(),
+        ipv6_addr.as_socks_bytes().unwrap(),
         vec![
             0x20, 0x01, 0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x02, 0x0c, 0x29, 0xff, 0xfe, 0x96,
             0x8b, 0x55, /* begin port */ 0x1f, 0x90
@@ -348,3 +510,38 @@ fn test_try_into_socket_addr() -> Result<()> {
     assert_eq!(socket_addr.port(), 0);
     Ok(())
 }
+
+#[test]
+fn test_domain() -> Result<()> {
+    use std::io::ErrorKind;
+
+    assert_eq!(Address::domain("github.com", 443)?, Address::Domain("github.com".into(), 443));
+    assert_eq!(
+        Address::domain("bücher.example", 80)?,
+        Address::Domain("xn--bcher-kva.example".into(), 80)
+    );
+    assert!(Address::domain("_dmarc.example.com.", 53).is_ok());
+
+    let too_long = vec!["a".repeat(MAX_LABEL_LEN); 4].join(".") + ".com";
+    assert!(too_long.len() > MAX_DOMAIN_LEN);
+    for name in ["", "a..b", "exa mple.com", "a/b", &"a".repeat(MAX_LABEL_LEN + 1), &too_long] {
+        assert_eq!(Address::domain(name, 80).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    let mut written = vec![];
+    let ret =
+        tokio_rt.block_on(Address::Domain(too_long, 80).write_socks_with(&mut written, &[], &[]));
+    assert_eq!(ret.unwrap_err().kind(), ErrorKind::InvalidData);
+    assert!(written.is_empty());
+
+    let mut lossy = [4u8, 0xff, b'a', b'b', b'c', 0x00, 0x50];
+    assert!(tokio_rt
+        .block_on(Address::from_socks_bytes(&mut &lossy[..], &AddressType::FQDN))
+        .is_err());
+    lossy[1] = b'x';
+    let addr = tokio_rt.block_on(Address::from_socks_bytes(&mut &lossy[..], &AddressType::FQDN))?;
+    assert_eq!(addr, Address::Domain("xabc".into(), 80));
+
+    Ok(())
+}
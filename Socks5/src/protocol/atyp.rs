@@ -26,7 +26,7 @@ impl TryFrom<u8> for AddressType {
             0x01 => Ok(Self::IPV4),
             0x03 => Ok(Self::FQDN),
             0x04 => Ok(Self::IPV6),
-            _ => Err(crate::throw_io_error("Unknown address type")),
+            _ => Err(crate::Socks5Error::BadAddressType(value).into()),
         }
     }
 }
@@ -50,7 +50,7 @@ impl Default for AddressType {
 impl From<Address> for AddressType {
     fn from(value: Address) -> Self {
         match value {
-            Address::IP(addr) => match addr.ip() {
+            Address::IP(addr) => match addr.ip().to_canonical() {
                 IpAddr::V4(_) => Self::IPV4,
                 IpAddr::V6(_) => Self::IPV6,
             },
@@ -14,7 +14,10 @@ impl TryFrom<u8> for Command {
             0x01 => Ok(Self::Connect),
             0x02 => Ok(Self::Bind),
             0x03 => Ok(Self::UdpAssociate),
-            _ => Err(crate::throw_io_error("Unknown command")),
+            _ => {
+                Err(crate::Socks5Error::UnsupportedCommand { protocol: "socks5", cmd: value }
+                    .into())
+            }
         }
     }
 }
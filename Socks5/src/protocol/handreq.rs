@@ -2,9 +2,9 @@
 
 use crate::protocol::AuthMethod;
 
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// The client connects to the server, and sends a version
 /// identifier/method selection message:
@@ -36,19 +36,57 @@ impl HandshakeRequest {
         self.methods.to_owned()
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
+    /// Pick the first of the `supported` methods (in order of preference)
+    /// offered by the client, or [AuthMethod::NoAcceptableMethods] if there
+    /// is none, in which case the client MUST close the connection.
+    pub fn select_method(&self, supported: &[AuthMethod]) -> AuthMethod {
+        supported
+            .iter()
+            .find(|m| self.methods.contains(m))
+            .cloned()
+            .unwrap_or(AuthMethod::NoAcceptableMethods)
+    }
+
+    /// Fails with [ErrorKind::InvalidInput] for more than 255 methods, which
+    /// NMETHODS cannot count
+    pub fn as_bytes(&self) -> Result<Vec<u8>> {
         let mut ret = vec![
             /* VER */ crate::SOCKS_VERSION, /* VER */
-            /* NMETHODS */ self.methods.len() as u8, /* NMETHODS */
+            /* NMETHODS */ self.nmethods()?, /* NMETHODS */
         ];
         for m in self.methods.iter() {
             ret.push((*m).to_owned().into());
         }
-        ret
+        Ok(ret)
+    }
+
+    fn nmethods(&self) -> Result<u8> {
+        u8::try_from(self.methods.len()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("More than {} authentication methods: {}", u8::MAX, self.methods.len()),
+            )
+        })
     }
 }
 
 impl HandshakeRequest {
+    /// Same bytes as [HandshakeRequest::as_bytes], written directly to `w`
+    pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        /* VER, NMETHODS and at most 255 METHODS */
+        let mut buf = [0u8; 2 + u8::MAX as usize];
+        let nmethods = self.nmethods()?;
+        buf[0] = crate::SOCKS_VERSION;
+        buf[1] = nmethods;
+        for (b, m) in buf[2..].iter_mut().zip(self.methods.iter()) {
+            *b = m.to_owned().into();
+        }
+        w.write_all(&buf[..2 + nmethods as usize]).await
+    }
+
     pub async fn from<R>(r: &mut R) -> Result<Self>
     where
         R: AsyncRead + Unpin,
@@ -57,12 +95,88 @@ impl HandshakeRequest {
             Err(e)
         } else {
             let nmethods = r.read_u8().await? as usize;
-            let mut methods = Vec::<AuthMethod>::with_capacity(nmethods);
-            for _ in 0..nmethods {
-                methods.push(r.read_u8().await?.into());
+            if nmethods == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "No authentication methods offered",
+                ));
             }
+            /* NMETHODS is a single octet, so this never exceeds 255 bytes */
+            let mut methods_buf = vec![0u8; nmethods];
+            r.read_exact(&mut methods_buf).await?;
+            let methods = methods_buf.into_iter().map(Into::into).collect();
 
             Ok(Self { methods })
         }
     }
 }
+
+#[test]
+fn test_from() -> std::io::Result<()> {
+    use tokio::io::BufReader;
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+
+    let hreqbytes = [5u8, 2, 0x00, 0x02];
+    let mut hreqbufrd = BufReader::new(&hreqbytes[..]);
+    let hreq = tokio_rt.block_on(HandshakeRequest::from(&mut hreqbufrd))?;
+    assert_eq!(
+        hreq.methods(),
+        vec![AuthMethod::NoAuthenticationRequired, AuthMethod::UsernameOrPassword]
+    );
+    assert_eq!(hreq.as_bytes()?, hreqbytes);
+
+    let zerobytes = [5u8, 0];
+    let mut zerobufrd = BufReader::new(&zerobytes[..]);
+    let err = tokio_rt.block_on(HandshakeRequest::from(&mut zerobufrd)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let truncbytes = [5u8, 3, 0x00];
+    let mut truncbufrd = BufReader::new(&truncbytes[..]);
+    let err = tokio_rt.block_on(HandshakeRequest::from(&mut truncbufrd)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+    Ok(())
+}
+
+#[test]
+fn test_select_method() {
+    let hreq = HandshakeRequest::new(vec![AuthMethod::UsernameOrPassword]);
+    assert_eq!(
+        hreq.select_method(&[AuthMethod::NoAuthenticationRequired]),
+        AuthMethod::NoAcceptableMethods
+    );
+    assert_eq!(
+        hreq.select_method(&[AuthMethod::NoAuthenticationRequired, AuthMethod::UsernameOrPassword]),
+        AuthMethod::UsernameOrPassword
+    );
+    /* A server implementing one private method must not accept a different one */
+    let hreq = HandshakeRequest::new(vec![AuthMethod::ReservedForPrivateMethods(0x81)]);
+    assert_eq!(
+        hreq.select_method(&[AuthMethod::ReservedForPrivateMethods(0x80)]),
+        AuthMethod::NoAcceptableMethods
+    );
+    assert_eq!(
+        hreq.select_method(&[AuthMethod::ReservedForPrivateMethods(0x81)]),
+        AuthMethod::ReservedForPrivateMethods(0x81)
+    );
+    assert_eq!(hreq.as_bytes().unwrap(), [5u8, 1, 0x81]);
+}
+
+#[test]
+fn test_too_many_methods() -> std::io::Result<()> {
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+
+    let methods = (0..=u8::MAX).map(AuthMethod::from).collect::<Vec<_>>();
+    let hreq = HandshakeRequest::new(methods[1..].to_vec());
+    assert_eq!(hreq.as_bytes()?.len(), 2 + 255);
+
+    /* NMETHODS is a single octet, 256 methods are not sent cut short */
+    let hreq = HandshakeRequest::new(methods);
+    assert_eq!(hreq.as_bytes().unwrap_err().kind(), ErrorKind::InvalidInput);
+    let mut written = vec![];
+    let err = tokio_rt.block_on(hreq.write_to(&mut written)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(written.is_empty());
+
+    Ok(())
+}
@@ -4,7 +4,7 @@ use crate::protocol::AuthMethod;
 
 use std::io::Result;
 
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// The server selects from one of the methods given in METHODS, and
 /// sends a METHOD selection message:
@@ -39,6 +39,14 @@ impl HandshakeResponse {
     pub fn as_bytes(&self) -> Vec<u8> {
         vec![crate::SOCKS_VERSION, self.method.to_owned().into()]
     }
+
+    /// Same bytes as [HandshakeResponse::as_bytes], written directly to `w`
+    pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        w.write_all(&[crate::SOCKS_VERSION, self.method.to_owned().into()]).await
+    }
 }
 
 impl HandshakeResponse {
@@ -53,3 +61,18 @@ impl HandshakeResponse {
         }
     }
 }
+
+#[test]
+fn test_private_method() -> std::io::Result<()> {
+    use tokio::io::BufReader;
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+
+    let hresp = HandshakeResponse::new(AuthMethod::ReservedForPrivateMethods(0x8a));
+    assert_eq!(hresp.as_bytes(), [crate::SOCKS_VERSION, 0x8a]);
+    let hresp_bytes = hresp.as_bytes();
+    let mut hrespbufrd = BufReader::new(&hresp_bytes[..]);
+    let parsed = tokio_rt.block_on(HandshakeResponse::from(&mut hrespbufrd))?;
+    assert_eq!(parsed.method(), AuthMethod::ReservedForPrivateMethods(0x8a));
+
+    Ok(())
+}
@@ -13,8 +13,10 @@ pub enum AuthMethod {
     NoAuthenticationRequired,
     GSSApi,
     UsernameOrPassword,
-    IANAAssigned,
-    ReservedForPrivateMethods,
+    /// The method octet, `0x03` to `0x7F`
+    IANAAssigned(u8),
+    /// The method octet, `0x80` to `0xFE`
+    ReservedForPrivateMethods(u8),
     NoAcceptableMethods,
 }
 
@@ -24,8 +26,8 @@ impl From<u8> for AuthMethod {
             0x00 => Self::NoAuthenticationRequired,
             0x01 => Self::GSSApi,
             0x02 => Self::UsernameOrPassword,
-            0x03..=0x7f => Self::IANAAssigned,
-            0x80..=0xfe => Self::ReservedForPrivateMethods,
+            0x03..=0x7f => Self::IANAAssigned(value),
+            0x80..=0xfe => Self::ReservedForPrivateMethods(value),
             0xff => Self::NoAcceptableMethods,
         }
     }
@@ -37,9 +39,18 @@ impl Into<u8> for AuthMethod {
             Self::NoAuthenticationRequired => 0x00,
             Self::GSSApi => 0x01,
             Self::UsernameOrPassword => 0x02,
-            Self::IANAAssigned => 0x03,
-            Self::ReservedForPrivateMethods => 0x80,
+            Self::IANAAssigned(value) => value,
+            Self::ReservedForPrivateMethods(value) => value,
             Self::NoAcceptableMethods => 0xff,
         }
     }
 }
+
+#[test]
+fn test_raw_values() {
+    assert_eq!(AuthMethod::from(0x09), AuthMethod::IANAAssigned(0x09));
+    assert_eq!(AuthMethod::from(0x8a), AuthMethod::ReservedForPrivateMethods(0x8a));
+    for value in 0x00..=0xffu8 {
+        assert_eq!(Into::<u8>::into(AuthMethod::from(value)), value);
+    }
+}
@@ -3,6 +3,40 @@
 use std::io::{Error, ErrorKind};
 use tokio::net::{TcpStream, UdpSocket};
 
+#[cfg(unix)]
+use libc::{ECONNREFUSED, EHOSTUNREACH, ENETDOWN, ENETUNREACH, ETIMEDOUT};
+
+/* Those of Winsock, which std reports */
+#[cfg(windows)]
+const ECONNREFUSED: i32 = 10061;
+#[cfg(windows)]
+const EHOSTUNREACH: i32 = 10065;
+#[cfg(windows)]
+const ENETDOWN: i32 = 10050;
+#[cfg(windows)]
+const ENETUNREACH: i32 = 10051;
+#[cfg(windows)]
+const ETIMEDOUT: i32 = 10060;
+
+/// The kind of the OS error `value` carries, which std may have left as
+/// [ErrorKind::Other] or uncategorized on some platforms
+#[cfg(any(unix, windows))]
+fn os_error_kind(value: &Error) -> Option<ErrorKind> {
+    match value.raw_os_error()? {
+        EHOSTUNREACH => Some(ErrorKind::HostUnreachable),
+        ENETUNREACH => Some(ErrorKind::NetworkUnreachable),
+        ENETDOWN => Some(ErrorKind::NetworkDown),
+        ETIMEDOUT => Some(ErrorKind::TimedOut),
+        ECONNREFUSED => Some(ErrorKind::ConnectionRefused),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn os_error_kind(_value: &Error) -> Option<ErrorKind> {
+    None
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ReplyField {
     Succeeded,
@@ -14,7 +48,9 @@ pub enum ReplyField {
     TTLExpired,
     CommandNotSupported,
     AddressTypeNotSupported,
-    Unassigned,
+    /// X'09' to X'FF', the original octet is kept, e.g. to relay the reply
+    /// of an upstream server as is
+    Unassigned(u8),
 }
 
 impl From<u8> for ReplyField {
@@ -29,22 +65,24 @@ impl From<u8> for ReplyField {
             0x06 => Self::TTLExpired,
             0x07 => Self::CommandNotSupported,
             0x08 => Self::AddressTypeNotSupported,
-            0x09..=0xff => Self::Unassigned,
+            0x09..=0xff => Self::Unassigned(value),
         }
     }
 }
 
 impl From<&Error> for ReplyField {
     fn from(value: &Error) -> Self {
-        match value.kind() {
+        if let Some(rep) = crate::Socks5Error::of(value).and_then(|e| e.reply_field()) {
+            return rep;
+        }
+        match os_error_kind(value).unwrap_or(value.kind()) {
             ErrorKind::ConnectionRefused => Self::ConnectionRefused,
-            // ErrorKind::NetworkDown |
             ErrorKind::ConnectionReset | ErrorKind::NotConnected => Self::GeneralSocksServerFailure,
-            // ErrorKind::HostUnreachable => Self::HostUnreachable,
-            // ErrorKind::NetworkUnreachable => Self::NetworkUnreachable,
+            ErrorKind::HostUnreachable => Self::HostUnreachable,
+            ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown => Self::NetworkUnreachable,
             ErrorKind::ConnectionAborted => Self::ConnectionNotAllowedByRuleSet,
             ErrorKind::TimedOut => Self::NetworkUnreachable,
-            ErrorKind::Other | _ => Self::Unassigned,
+            ErrorKind::Other | _ => Self::Unassigned(0x09),
         }
     }
 }
@@ -88,7 +126,7 @@ impl Into<u8> for ReplyField {
             Self::TTLExpired => 0x06,
             Self::CommandNotSupported => 0x07,
             Self::AddressTypeNotSupported => 0x08,
-            Self::Unassigned => 0x09,
+            Self::Unassigned(value) => value,
         }
     }
 }
@@ -98,3 +136,28 @@ impl Default for ReplyField {
         Self::Succeeded
     }
 }
+
+#[cfg(unix)]
+#[test]
+fn test_from_os_error() {
+    let rep = |errno| ReplyField::from(&Error::from_raw_os_error(errno));
+    assert_eq!(rep(libc::EHOSTUNREACH), ReplyField::HostUnreachable);
+    assert_eq!(rep(libc::ENETUNREACH), ReplyField::NetworkUnreachable);
+    assert_eq!(rep(libc::ENETDOWN), ReplyField::NetworkUnreachable);
+    assert_eq!(rep(libc::ETIMEDOUT), ReplyField::NetworkUnreachable);
+    assert_eq!(rep(libc::ECONNREFUSED), ReplyField::ConnectionRefused);
+    /* Without an OS error, by the kind alone */
+    let rep = |kind| ReplyField::from(&Error::from(kind));
+    assert_eq!(rep(ErrorKind::HostUnreachable), ReplyField::HostUnreachable);
+    assert_eq!(rep(ErrorKind::NetworkUnreachable), ReplyField::NetworkUnreachable);
+    assert_eq!(rep(ErrorKind::Other), ReplyField::Unassigned(0x09));
+}
+
+#[test]
+fn test_unassigned() {
+    assert_eq!(ReplyField::from(0x08), ReplyField::AddressTypeNotSupported);
+    assert_eq!(ReplyField::from(0x2a), ReplyField::Unassigned(0x2a));
+    for value in 0x00..=0xffu8 {
+        assert_eq!(Into::<u8>::into(ReplyField::from(value)), value);
+    }
+}
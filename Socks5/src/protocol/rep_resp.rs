@@ -2,7 +2,7 @@
 
 use super::{Address, AddressType, ReplyField};
 
-use tokio::io::{copy, AsyncRead, AsyncReadExt, AsyncWrite, BufReader, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, Result};
 
 /// The SOCKS request information is sent by the client as soon as it has
 /// established a connection to the SOCKS server, and completed the
@@ -25,15 +25,16 @@ pub struct ReplyResponse {
 }
 
 impl ReplyResponse {
-    pub fn as_bytes(&self) -> Vec<u8> {
+    /// Fails for a domain name that does not [validate](Address::validate)
+    pub fn as_bytes(&self) -> Result<Vec<u8>> {
         let mut ret = vec![
             crate::SOCKS_VERSION, /* VER */
             self.rep().into(),    /* REP */
             crate::RSV_RESERVED,  /* RSV */
             self.atyp().into(),   /* ATYP */
         ];
-        ret.extend_from_slice(&self.addr.as_socks_bytes());
-        ret
+        ret.extend_from_slice(&self.addr.as_socks_bytes()?);
+        Ok(ret)
     }
 
     #[inline]
@@ -41,6 +42,18 @@ impl ReplyResponse {
         Self { rep, addr }
     }
 
+    /// A successful reply carrying the BND.ADDR and BND.PORT the server bound
+    #[inline]
+    pub fn succeeded<A: Into<Address>>(addr: A) -> Self {
+        Self::new(ReplyField::Succeeded, addr.into())
+    }
+
+    /// A failure reply, whose address is all zeros
+    #[inline]
+    pub fn failed(rep: ReplyField) -> Self {
+        Self::new(rep, Address::default())
+    }
+
     #[inline]
     pub fn rep(&self) -> ReplyField {
         self.rep.to_owned()
@@ -56,13 +69,24 @@ impl ReplyResponse {
         self.addr.to_owned()
     }
 
+    /// Same bytes as [ReplyResponse::as_bytes], written directly to `w`
+    pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let head =
+            [crate::SOCKS_VERSION, self.rep().into(), crate::RSV_RESERVED, self.atyp().into()];
+        self.addr.write_socks_with(w, &head, &[]).await
+    }
+
+    /// Write the reply, returning the number of bytes written
     pub async fn respond_with<'a, W>(&self, writer: &'a mut W) -> Result<u64>
     where
         W: AsyncWrite + Unpin + ?Sized,
     {
-        let resp_bytes = self.as_bytes();
-        let mut resp_bytes_reader = BufReader::new(resp_bytes.as_slice());
-        copy(&mut resp_bytes_reader, writer).await
+        self.write_to(writer).await?;
+        let dnlen_size = matches!(self.addr, Address::Domain(..)) as usize;
+        Ok((4 + dnlen_size + self.addr.len()) as u64)
     }
 }
 
@@ -82,6 +106,7 @@ impl ReplyResponse {
 
 #[test]
 fn test_from() -> std::io::Result<()> {
+    use tokio::io::BufReader;
     let tokio_rt = tokio::runtime::Runtime::new()?;
 
     let v4reqbytes = [5u8, 1, 0, 1, 127, 0, 0, 1, 0x00, 0x50];
@@ -114,3 +139,20 @@ fn test_from() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_conveniences() {
+    let succeeded = ReplyResponse::succeeded((std::net::Ipv4Addr::LOCALHOST, 1080));
+    assert_eq!(succeeded.rep(), ReplyField::Succeeded);
+    assert_eq!(succeeded.as_bytes().unwrap(), [5u8, 0, 0, 1, 127, 0, 0, 1, 0x04, 0x38]);
+
+    /* Bound on a dual-stack socket, an IPv4 address is reported as such */
+    let mapped = "[::ffff:127.0.0.1]:1080".parse::<std::net::SocketAddr>().unwrap();
+    let succeeded = ReplyResponse::succeeded(mapped);
+    assert_eq!(succeeded.atyp(), AddressType::IPV4);
+    assert_eq!(succeeded.as_bytes().unwrap(), [5u8, 0, 0, 1, 127, 0, 0, 1, 0x04, 0x38]);
+
+    let failed = ReplyResponse::failed(ReplyField::CommandNotSupported);
+    assert_eq!(failed.atyp(), AddressType::IPV4);
+    assert_eq!(failed.as_bytes().unwrap(), [5u8, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+}
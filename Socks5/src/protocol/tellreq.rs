@@ -6,7 +6,7 @@ use crate::protocol::Command;
 
 use std::io::Result;
 
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 
 /// Once the method-dependent subnegotiation has completed, the client
 /// sends the request details.  If the negotiated method includes
@@ -32,22 +32,51 @@ pub struct TellRequest {
 }
 
 impl TellRequest {
-    pub fn as_bytes(&self) -> Vec<u8> {
+    /// Fails for a domain name that does not [validate](Address::validate)
+    pub fn as_bytes(&self) -> Result<Vec<u8>> {
         let mut ret = vec![
             crate::SOCKS_VERSION, /* VER */
             self.cmd().into(),    /* CMD */
             crate::RSV_RESERVED,  /* RSV */
             self.atyp().into(),   /* ATYP */
         ];
-        ret.extend_from_slice(&self.addr.as_socks_bytes());
-        ret
+        ret.extend_from_slice(&self.addr.as_socks_bytes()?);
+        Ok(ret)
     }
 
+    /// Same bytes as [TellRequest::as_bytes], written directly to `w`
+    pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let head =
+            [crate::SOCKS_VERSION, self.cmd().into(), crate::RSV_RESERVED, self.atyp().into()];
+        self.addr.write_socks_with(w, &head, &[]).await
+    }
+
+    /// ATYP is derived from `addr`, so it can never disagree with it
     #[inline]
     pub fn new(cmd: Command, addr: Address) -> Self {
         Self { cmd, addr }
     }
 
+    #[inline]
+    pub fn connect<A: Into<Address>>(addr: A) -> Self {
+        Self::new(Command::Connect, addr.into())
+    }
+
+    #[inline]
+    pub fn bind<A: Into<Address>>(addr: A) -> Self {
+        Self::new(Command::Bind, addr.into())
+    }
+
+    /// `addr` is where the client will send its datagrams from, or the
+    /// default (all zeros) address when it does not know yet
+    #[inline]
+    pub fn udp_associate<A: Into<Address>>(addr: A) -> Self {
+        Self::new(Command::UdpAssociate, addr.into())
+    }
+
     #[inline]
     pub fn cmd(&self) -> Command {
         self.cmd.to_owned()
@@ -117,9 +146,26 @@ fn test_from() -> std::io::Result<()> {
 #[test]
 fn test_as_bytes() {
     let tellreq = TellRequest::new(Command::Connect, Address::default());
-    let tellreq_bytes = tellreq.as_bytes();
+    let tellreq_bytes = tellreq.as_bytes().unwrap();
     let mut vec = vec![5u8, 1, 0, 1];
-    vec.extend_from_slice(&tellreq.addr().as_socks_bytes());
+    vec.extend_from_slice(&tellreq.addr().as_socks_bytes().unwrap());
     assert_eq!(tellreq_bytes, vec);
     assert_eq!(&vec[4..], [0, 0, 0, 0, 0, 0]);
 }
+
+#[test]
+fn test_conveniences() {
+    let v4addr = std::net::SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 80));
+    let connect = TellRequest::connect(v4addr);
+    assert_eq!(connect.cmd(), Command::Connect);
+    assert_eq!(connect.atyp(), AddressType::IPV4);
+    assert_eq!(connect.as_bytes().unwrap(), [5u8, 1, 0, 1, 127, 0, 0, 1, 0x00, 0x50]);
+
+    let bind = TellRequest::bind(Address::Domain(String::from("github.com"), 443));
+    assert_eq!(bind.cmd(), Command::Bind);
+    assert_eq!(bind.atyp(), AddressType::FQDN);
+
+    let udp_associate = TellRequest::udp_associate(Address::default());
+    assert_eq!(udp_associate.cmd(), Command::UdpAssociate);
+    assert_eq!(udp_associate.as_bytes().unwrap(), [5u8, 3, 0, 1, 0, 0, 0, 0, 0, 0]);
+}
@@ -1,14 +1,156 @@
 //! https://datatracker.ietf.org/doc/html/rfc1928
 
 use crate::protocol::AddressType;
+use crate::udp_pool::{PooledUdpSocket, UdpPoolKey, UdpSocketPool};
 
 use super::Address;
 
-use std::net::{IpAddr, SocketAddr};
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::ops::ControlFlow;
 
-use tokio::io::{BufReader, Result};
+use tokio::io::{AsyncWrite, Result};
 use tokio::net::UdpSocket;
 
+/// RSV, FRAG and ATYP
+const UDP_HEAD_LEN: usize = 4;
+
+/// The longest header a datagram of a client may carry, that of a domain
+/// name of 255 bytes
+pub const MAX_UDP_HEAD_LEN: usize = UDP_HEAD_LEN + 1 + 255 + 2;
+
+/// Why a received datagram cannot be relayed
+#[derive(Debug)]
+pub enum UdpParseError {
+    /// Shorter than the header its ATYP calls for
+    Truncated { len: usize, min: usize },
+    /// A nonzero RSV
+    Reserved(u16),
+    /// A fragment, which is not reassembled
+    Fragment(u8),
+    /// An unknown ATYP
+    AddressType(u8),
+    /// A DST.ADDR that is not a valid domain name
+    Domain(std::io::Error),
+    /// Sent by someone else than the client of the association
+    NotFromClient(ExpectedClient),
+}
+
+impl UdpParseError {
+    /// The parse error `e` carries, if it comes from parsing a datagram
+    pub fn of(e: &std::io::Error) -> Option<&UdpParseError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<UdpParseError>())
+    }
+
+    pub fn kind(&self) -> std::io::ErrorKind {
+        use std::io::ErrorKind;
+        match self {
+            Self::Truncated { .. } => ErrorKind::UnexpectedEof,
+            Self::Reserved(_) => ErrorKind::InvalidData,
+            Self::Fragment(_) | Self::AddressType(_) => ErrorKind::Unsupported,
+            Self::Domain(e) => e.kind(),
+            Self::NotFromClient(_) => ErrorKind::PermissionDenied,
+        }
+    }
+}
+
+impl Display for UdpParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated { len, min } => {
+                write!(f, "Datagram of {} bytes, its header takes {}", len, min)
+            }
+            Self::Reserved(rsv) => write!(f, "Unsupported RSV: {:#06x}", rsv),
+            Self::Fragment(frag) => write!(f, "Unsupported FRAG: {:#04x}", frag),
+            Self::AddressType(atyp) => write!(f, "Unknown address type: {:#04x}", atyp),
+            Self::Domain(e) => write!(f, "{}", e),
+            Self::NotFromClient(client) => write!(f, "Not from the associated client {}", client),
+        }
+    }
+}
+
+impl std::error::Error for UdpParseError {}
+
+impl From<UdpParseError> for std::io::Error {
+    fn from(value: UdpParseError) -> Self {
+        std::io::Error::new(value.kind(), value)
+    }
+}
+
+/// How strictly datagrams must come from the announced client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientMatch {
+    /// Any port of the client IP, as many clients bind a fresh UDP port
+    /// after the reply
+    #[default]
+    Ip,
+    /// The client IP and port, a port the request left unknown is learned
+    /// from the first valid datagram
+    IpPort,
+}
+
+/// The client a UDP association was requested for, datagrams from anyone
+/// else must not be relayed.
+///
+/// The DST.ADDR and DST.PORT of a UDP ASSOCIATE request are the address
+/// the client expects to send from, all zeros when it does not know it yet.
+/// An unknown address is taken to be that of the TCP control connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpectedClient {
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+    mode: ClientMatch,
+}
+
+impl ExpectedClient {
+    /// From the DST.ADDR and DST.PORT of the request, and the IP the control
+    /// connection comes from
+    pub fn new(announced: &Address, control_ip: IpAddr, mode: ClientMatch) -> Self {
+        let ip = match announced {
+            Address::IP(addr) if !addr.ip().is_unspecified() => addr.ip(),
+            _ => control_ip,
+        };
+        let port = match mode {
+            ClientMatch::Ip => None,
+            ClientMatch::IpPort => Some(announced.port()).filter(|port| *port != 0),
+        };
+        Self { ip: Some(ip.to_canonical()), port, mode }
+    }
+
+    /// Matches every sender
+    #[inline]
+    pub fn any() -> Self {
+        Self { ip: None, port: None, mode: ClientMatch::Ip }
+    }
+
+    pub fn matches(&self, from_addr: SocketAddr) -> bool {
+        self.ip.is_none_or(|ip| ip == from_addr.ip().to_canonical())
+            && self.port.is_none_or(|port| port == from_addr.port())
+    }
+
+    /// Pin a port that is still unknown to that of a valid datagram from
+    /// `from_addr`, in [ClientMatch::IpPort] mode
+    pub fn learn(&mut self, from_addr: SocketAddr) {
+        if self.mode == ClientMatch::IpPort && self.port.is_none() {
+            self.port = Some(from_addr.port());
+        }
+    }
+}
+
+impl Display for ExpectedClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ip {
+            Some(IpAddr::V6(ip)) => write!(f, "[{}]", ip)?,
+            Some(ip) => write!(f, "{}", ip)?,
+            None => write!(f, "*")?,
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => write!(f, ":*"),
+        }
+    }
+}
+
 /// A UDP-based client MUST send its datagrams to the UDP relay server at
 /// the UDP port indicated by BND.PORT in the reply to the UDP ASSOCIATE
 /// request.  If the selected authentication method provides
@@ -41,32 +183,128 @@ impl UdpPacket {
         Self { frag, addr, data }
     }
 
-    pub async fn from(udp_sock: &UdpSocket) -> Result<(Self, SocketAddr)> {
-        loop {
-            // The buffer is **not** included in the async task and will only exist
-            // on the stack.
-            let mut udp_data = [0u8; u16::MAX as usize];
-            let (len, from_addr) = udp_sock.recv_from(&mut udp_data).await?;
-            let udp_data = &udp_data[..len];
-            if len <= 4 {
-                return Err(crate::throw_io_error(&format!(
-                    "Readied unknown data: {:?}",
-                    udp_data
-                )));
+    /// Parse a single datagram, checking that it is as long as the header
+    /// its ATYP calls for. Fragmentation is not supported, so fragments (a
+    /// nonzero FRAG) are rejected as RFC 1928 requires of an implementation
+    /// that does not reassemble them.
+    pub fn parse(udp_data: &[u8]) -> std::result::Result<Self, UdpParseError> {
+        let truncated = |min: usize| UdpParseError::Truncated { len: udp_data.len(), min };
+        /* Up to the length octet of a name, the shortest address */
+        if udp_data.len() <= UDP_HEAD_LEN {
+            return Err(truncated(UDP_HEAD_LEN + 1));
+        }
+        let rsv = u16::from_be_bytes([udp_data[0], udp_data[1]]);
+        if rsv != 0 {
+            return Err(UdpParseError::Reserved(rsv));
+        }
+        let frag = udp_data[2];
+        if frag != 0 {
+            return Err(UdpParseError::Fragment(frag));
+        }
+        let addr_len = match udp_data[3] {
+            0x01 => 4,
+            0x03 => 1 + udp_data[UDP_HEAD_LEN] as usize,
+            0x04 => 16,
+            atyp => return Err(UdpParseError::AddressType(atyp)),
+        };
+        /* DST.PORT follows DST.ADDR */
+        let head_len = UDP_HEAD_LEN + addr_len + 2;
+        if udp_data.len() < head_len {
+            return Err(truncated(head_len));
+        }
+        let addr = &udp_data[UDP_HEAD_LEN..head_len - 2];
+        let port = u16::from_be_bytes([udp_data[head_len - 2], udp_data[head_len - 1]]);
+        let to_addr = match udp_data[3] {
+            0x01 => (Ipv4Addr::from(<[u8; 4]>::try_from(addr).unwrap()), port).into(),
+            0x04 => {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(addr).unwrap());
+                SocketAddrV6::new(ip, port, 0, 0).into()
             }
-            let _rsv = u16::from_be_bytes([udp_data[0], udp_data[1]]); /* TODO: Check it */
-            let frag = udp_data[2];
-            let atyp: AddressType = udp_data[3].try_into()?;
-            let mut addr_buf = BufReader::new(&udp_data[4..]);
-            if let Ok(to_addr) = Address::from_socks_bytes(&mut addr_buf, &atyp).await {
-                let data = (&udp_data[(4 + to_addr.len())..]).to_vec();
-                return Ok((Self::new(frag, to_addr, data), from_addr));
+            _ => {
+                let name = std::str::from_utf8(&addr[1..]).map_err(|_| {
+                    UdpParseError::Domain(crate::invalid_data("Domain name is not UTF-8"))
+                })?;
+                Address::domain(name, port).map_err(UdpParseError::Domain)?
             }
+        };
+        Ok(Self::new(frag, to_addr, udp_data[head_len..].to_vec()))
+    }
 
-            // Err(crate::throw_io_error(&format!("Readied unknown data: {:?}", udp_data)))
+    /// [UdpPacket::parse] with the error as an [std::io::Error], which
+    /// [UdpParseError::of] gets back
+    pub async fn from_datagram(udp_data: &[u8]) -> Result<Self> {
+        Ok(Self::parse(udp_data)?)
+    }
+
+    /// Receive the next well-formed datagram from `client`. Those that
+    /// cannot be relayed, including datagrams from anyone else, are
+    /// reported to `on_drop`, which either drops them and keeps receiving
+    /// or breaks off with the error. The first datagram received may pin
+    /// the port of `client`, see [ExpectedClient::learn].
+    pub async fn from_client<F>(
+        udp_sock: &UdpSocket,
+        client: &mut ExpectedClient,
+        on_drop: F,
+    ) -> Result<(Self, SocketAddr)>
+    where
+        F: FnMut(&UdpParseError, SocketAddr) -> ControlFlow<()>,
+    {
+        // The buffer is **not** included in the async task and will only exist
+        // on the stack.
+        let mut udp_data = [0u8; u16::MAX as usize];
+        Self::from_client_in(udp_sock, client, &mut udp_data, on_drop).await
+    }
+
+    /// [UdpPacket::from_client] receiving into `buf`, whose length bounds
+    /// the datagrams: the kernel truncates those longer than it. Size it
+    /// with [MAX_UDP_HEAD_LEN] past the largest payload.
+    pub async fn from_client_in<F>(
+        udp_sock: &UdpSocket,
+        client: &mut ExpectedClient,
+        buf: &mut [u8],
+        mut on_drop: F,
+    ) -> Result<(Self, SocketAddr)>
+    where
+        F: FnMut(&UdpParseError, SocketAddr) -> ControlFlow<()>,
+    {
+        loop {
+            let (len, from_addr) = udp_sock.recv_from(buf).await?;
+            let udp_data = &buf[..len];
+            let ret = match client.matches(from_addr) {
+                true => Self::parse(udp_data),
+                false => Err(UdpParseError::NotFromClient(*client)),
+            };
+            match ret {
+                Ok(udp_pack) => {
+                    client.learn(from_addr);
+                    return Ok((udp_pack, from_addr));
+                }
+                Err(e) => {
+                    if on_drop(&e, from_addr).is_break() {
+                        return Err(e.into());
+                    }
+                }
+            }
         }
     }
 
+    /// Receive the next well-formed datagram from anyone, see
+    /// [UdpPacket::from_client] for `on_drop`
+    #[inline]
+    pub async fn from_with<F>(udp_sock: &UdpSocket, on_drop: F) -> Result<(Self, SocketAddr)>
+    where
+        F: FnMut(&UdpParseError, SocketAddr) -> ControlFlow<()>,
+    {
+        Self::from_client(udp_sock, &mut ExpectedClient::any(), on_drop).await
+    }
+
+    /// Receive the next well-formed datagram from anyone, silently
+    /// dropping the others
+    #[inline]
+    pub async fn from(udp_sock: &UdpSocket) -> Result<(Self, SocketAddr)> {
+        Self::from_with(udp_sock, |_, _| ControlFlow::Continue(())).await
+    }
+
     #[inline]
     pub fn frag(&self) -> u8 {
         self.frag.to_owned()
@@ -82,24 +320,39 @@ impl UdpPacket {
         self.data.to_owned()
     }
 
-    pub fn as_socks_bytes(&self) -> Vec<u8> {
+    /// Fails for a domain name that does not [validate](Address::validate)
+    pub fn as_socks_bytes(&self) -> Result<Vec<u8>> {
         let mut ret = vec![];
         ret.extend_from_slice(&[0x00, 0x00]); /* RSV */
         ret.push(self.frag()); /* FRAG */
         let addr = self.addr();
-        let addr_bytes = addr.as_socks_bytes();
+        let addr_bytes = addr.as_socks_bytes()?;
         let atyp = Into::<AddressType>::into(addr);
         ret.push(atyp.into()); /* ATYP */
         ret.extend_from_slice(&addr_bytes); /* DST.ADDR DST.PORT */
         ret.extend_from_slice(&self.data()); /* DATA */
-        ret
+        Ok(ret)
     }
 
-    pub async fn new_exchange(listen_ip: IpAddr) -> Result<(UdpSocket, UdpSocket)> {
-        let zero_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    /// Same bytes as [UdpPacket::as_socks_bytes], written directly to `w`
+    pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let atyp: AddressType = self.addr().into();
+        let head = [0x00, 0x00, self.frag, atyp.into()];
+        self.addr.write_socks_with(w, &head, &self.data).await
+    }
+
+    /// The sockets of a relay: one bound to `listen_ip` for the client, one
+    /// of `pool` toward the destinations
+    pub async fn new_exchange(
+        listen_ip: IpAddr,
+        pool: &UdpSocketPool,
+    ) -> Result<(UdpSocket, PooledUdpSocket)> {
         let from_socket_addr = SocketAddr::from((listen_ip, 0u16));
         let from_udp_sock = UdpSocket::bind(from_socket_addr).await?;
-        let to_udp_sock = UdpSocket::bind(zero_addr).await?;
+        let to_udp_sock = pool.acquire(UdpPoolKey::any(false))?;
         Ok((from_udp_sock, to_udp_sock))
     }
 }
@@ -111,7 +364,7 @@ fn test_as_socks_bytes() {
         28, 0, 1,
     ];
     let udp_pack = UdpPacket::new(0, Address::default(), data);
-    let udp_pack_bytes = udp_pack.as_socks_bytes();
+    let udp_pack_bytes = udp_pack.as_socks_bytes().unwrap();
     assert_eq!(
         udp_pack_bytes,
         vec![
@@ -121,3 +374,97 @@ fn test_as_socks_bytes() {
         ]
     )
 }
+
+#[test]
+fn test_from_datagram() -> std::io::Result<()> {
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+
+    let datagram = [0u8, 0, 0, 1, 127, 0, 0, 1, 0x00, 0x35, /* DATA */ 1, 2, 3];
+    let udp_pack = tokio_rt.block_on(UdpPacket::from_datagram(&datagram))?;
+    assert_eq!(udp_pack.addr(), (std::net::Ipv4Addr::LOCALHOST, 53).into());
+    assert_eq!(udp_pack.data(), [1, 2, 3]);
+
+    let mut dndatagram = vec![0u8, 0, 0, 3, 10];
+    dndatagram.extend_from_slice(b"github.com");
+    dndatagram.extend_from_slice(&[0x00, 0x35, /* DATA */ 1, 2, 3]);
+    let udp_pack = tokio_rt.block_on(UdpPacket::from_datagram(&dndatagram))?;
+    assert_eq!(udp_pack.addr(), Address::Domain(String::from("github.com"), 53));
+    assert_eq!(udp_pack.data(), [1, 2, 3]);
+
+    let nonzero_rsv = [0u8, 1, 0, 1, 127, 0, 0, 1, 0x00, 0x35, 1];
+    let err = tokio_rt.block_on(UdpPacket::from_datagram(&nonzero_rsv)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(matches!(UdpParseError::of(&err), Some(UdpParseError::Reserved(1))));
+
+    let fragment = [0u8, 0, 1, 1, 127, 0, 0, 1, 0x00, 0x35, 1];
+    let err = tokio_rt.block_on(UdpPacket::from_datagram(&fragment)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    assert!(matches!(UdpParseError::of(&err), Some(UdpParseError::Fragment(1))));
+
+    /* The header must be complete whatever the ATYP */
+    for (datagram, min) in [
+        (&[0u8, 0, 0, 1, 127, 0, 0, 1, 0x00][..], 10),
+        (&[0u8, 0, 0, 4, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], 22),
+        (&[0u8, 0, 0, 3, 10, b'g', b'i', b't'], 17),
+        (&[0u8, 0, 0, 1], 5),
+    ] {
+        match UdpPacket::parse(datagram) {
+            Err(UdpParseError::Truncated { len, min: expected }) => {
+                assert_eq!((len, expected), (datagram.len(), min))
+            }
+            ret => panic!("{:?} parsed as {:?}", datagram, ret),
+        }
+    }
+    assert!(matches!(UdpPacket::parse(&[0, 0, 0, 2, 0]), Err(UdpParseError::AddressType(2))));
+    assert!(matches!(UdpPacket::parse(&[0, 0, 0, 3, 0, 0, 0x35]), Err(UdpParseError::Domain(_))));
+
+    /* A v4-mapped destination is taken as IPv4, and replies from one are
+     * headed as IPv4 */
+    let mut mapped = vec![0u8, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 127, 0, 0, 1];
+    mapped.extend_from_slice(&[0x00, 0x35, /* DATA */ 1]);
+    let udp_pack = tokio_rt.block_on(UdpPacket::from_datagram(&mapped))?;
+    assert_eq!(udp_pack.addr(), (std::net::Ipv4Addr::LOCALHOST, 53).into());
+    let back_addr = "[::ffff:127.0.0.1]:53".parse::<SocketAddr>().unwrap();
+    let udp_resp = UdpPacket::new(0, Address::IP(back_addr), vec![1]);
+    assert_eq!(udp_resp.as_socks_bytes()?, [0u8, 0, 0, 1, 127, 0, 0, 1, 0x00, 0x35, 1]);
+
+    Ok(())
+}
+
+#[test]
+fn test_expected_client() {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    let control_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+
+    let announced: Address = (Ipv4Addr::new(192, 168, 1, 2), 5353).into();
+    let client = ExpectedClient::new(&announced, control_ip, ClientMatch::IpPort);
+    assert!(client.matches(SocketAddr::from(([192, 168, 1, 2], 5353))));
+    assert!(!client.matches(SocketAddr::from(([192, 168, 1, 2], 5354))));
+    assert!(!client.matches(SocketAddr::from(([192, 168, 1, 3], 5353))));
+    let mapped = Ipv4Addr::new(192, 168, 1, 2).to_ipv6_mapped();
+    assert!(client.matches(SocketAddr::from((mapped, 5353))));
+    assert_eq!(client.to_string(), "192.168.1.2:5353");
+
+    /* Clients often send from another port than the one announced */
+    let client = ExpectedClient::new(&announced, control_ip, ClientMatch::Ip);
+    assert!(client.matches(SocketAddr::from(([192, 168, 1, 2], 5354))));
+    assert!(!client.matches(SocketAddr::from(([192, 168, 1, 3], 5353))));
+    assert_eq!(client.to_string(), "192.168.1.2:*");
+
+    /* All zeros, the client does not know its address yet */
+    let mut client = ExpectedClient::new(&Address::default(), control_ip, ClientMatch::IpPort);
+    assert!(client.matches(SocketAddr::from(([192, 168, 1, 2], 40000))));
+    assert!(!client.matches(SocketAddr::from(([10, 0, 0, 1], 40000))));
+    assert_eq!(client.to_string(), "192.168.1.2:*");
+    client.learn(SocketAddr::from(([192, 168, 1, 2], 40000)));
+    assert!(!client.matches(SocketAddr::from(([192, 168, 1, 2], 40001))));
+    assert_eq!(client.to_string(), "192.168.1.2:40000");
+
+    let announced: Address = (Ipv6Addr::UNSPECIFIED, 5353).into();
+    let client =
+        ExpectedClient::new(&announced, IpAddr::V6(Ipv6Addr::LOCALHOST), ClientMatch::IpPort);
+    assert!(client.matches(SocketAddr::from((Ipv6Addr::LOCALHOST, 5353))));
+    assert_eq!(client.to_string(), "[::1]:5353");
+
+    assert!(ExpectedClient::any().matches(SocketAddr::from(([10, 0, 0, 1], 1))));
+}
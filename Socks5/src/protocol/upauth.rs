@@ -1,8 +1,8 @@
 //! https://datatracker.ietf.org/doc/html/rfc1929
 
-use std::io::Result;
+use std::io::{IoSlice, Result};
 
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 
 /// Once the SOCKS V5 server has started, and the client has selected the
 /// Username/Password Authentication protocol, the Username/Password
@@ -58,6 +58,23 @@ impl UsernamePasswordAuth {
 }
 
 impl UsernamePasswordAuth {
+    /// Same bytes as [UsernamePasswordAuth::as_bytes], written directly to `w`
+    pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let (usr_bytes, pwd_bytes) = (self.usr.as_bytes(), self.pwd.as_bytes());
+        let head = [crate::AUTH_VERSION, usr_bytes.len() as u8];
+        let plen = [pwd_bytes.len() as u8];
+        let mut bufs = [
+            IoSlice::new(&head),
+            IoSlice::new(usr_bytes),
+            IoSlice::new(&plen),
+            IoSlice::new(pwd_bytes),
+        ];
+        crate::write_all_vectored(w, &mut bufs).await
+    }
+
     pub async fn from<R>(r: &mut R) -> Result<Self>
     where
         R: AsyncRead + Unpin,
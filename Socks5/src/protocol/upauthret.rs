@@ -1,6 +1,6 @@
 //! https://datatracker.ietf.org/doc/html/rfc1929
 
-use std::error::Error;
+use std::io::Result;
 
 use tokio::io::{AsyncRead, AsyncReadExt};
 
@@ -51,15 +51,12 @@ impl UsernamePasswordAuthResult {
 }
 
 impl UsernamePasswordAuthResult {
-    pub async fn from<R>(r: &mut R) -> Result<Self, Box<dyn Error>>
+    pub async fn from<R>(r: &mut R) -> Result<Self>
     where
         R: AsyncRead + Unpin,
     {
-        if let Err(e) = crate::check_auth_ver(r).await {
-            Err(Box::new(e))
-        } else {
-            Ok(r.read_u8().await?.try_into()?) /* STATUS */
-        }
+        crate::check_auth_ver(r).await?;
+        Ok(r.read_u8().await?.into()) /* STATUS */
     }
 }
 
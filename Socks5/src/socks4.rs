@@ -0,0 +1,322 @@
+//! https://www.openssh.com/txt/socks4.protocol
+//! https://www.openssh.com/txt/socks4a.protocol
+
+use crate::protocol::Address;
+
+use std::io::{Error, Result};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+/// The VN of a reply, which is the version of the reply code
+pub const SOCKS4_REPLY_VERSION: u8 = 0x00;
+/// USERID and the SOCKS4a domain name are NULL terminated, anything longer
+/// than this is rejected instead of being buffered without bound
+pub const SOCKS4_MAX_FIELD_LEN: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks4Command {
+    Connect,
+    Bind,
+}
+
+impl TryFrom<u8> for Socks4Command {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x01 => Ok(Self::Connect),
+            0x02 => Ok(Self::Bind),
+            _ => {
+                Err(crate::Socks5Error::UnsupportedCommand { protocol: "socks4", cmd: value }
+                    .into())
+            }
+        }
+    }
+}
+
+impl From<Socks4Command> for u8 {
+    fn from(value: Socks4Command) -> Self {
+        match value {
+            Socks4Command::Connect => 0x01,
+            Socks4Command::Bind => 0x02,
+        }
+    }
+}
+
+async fn read_null_terminated<R>(r: &mut R) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![];
+    loop {
+        match r.read_u8().await? {
+            0x00 => break,
+            b if buf.len() < SOCKS4_MAX_FIELD_LEN => buf.push(b),
+            _ => return Err(crate::invalid_data("Too long socks4 field")),
+        }
+    }
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+/// The client sends a request as follows, USERID is terminated by a NULL:
+///
+/// ```plain
+///      +----+----+----+----+----+----+----+----+----+----+....+----+
+///      | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+///      +----+----+----+----+----+----+----+----+----+----+....+----+
+///      | 1  | 1  |    2    |         4         | Variable     | 1  |
+///      +----+----+----+----+----+----+----+----+----+----+....+----+
+/// ```
+///
+/// With SOCKS4a, a DSTIP of `0.0.0.x` (x nonzero) means the client could
+/// not resolve the destination, and the domain name follows the USERID,
+/// also terminated by a NULL.
+#[derive(Debug, Clone)]
+pub struct Socks4Request {
+    cmd: Socks4Command,
+    addr: Address,
+    userid: String,
+}
+
+impl Socks4Request {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut ret = vec![SOCKS4_VERSION /* VN */, self.cmd.into() /* CD */];
+        ret.extend_from_slice(&self.addr.port().to_be_bytes()); /* DSTPORT */
+        let domain = match &self.addr {
+            Address::IP(SocketAddr::V4(addr)) => {
+                ret.extend_from_slice(&addr.ip().octets());
+                None
+            }
+            Address::IP(SocketAddr::V6(_)) => {
+                /* Not representable, let the server fail to connect to 0.0.0.0 */
+                ret.extend_from_slice(&Ipv4Addr::UNSPECIFIED.octets());
+                None
+            }
+            Address::Domain(name, _) => {
+                ret.extend_from_slice(&[0, 0, 0, 1]);
+                Some(name)
+            }
+        };
+        ret.extend_from_slice(self.userid.as_bytes());
+        ret.push(0x00);
+        if let Some(name) = domain {
+            ret.extend_from_slice(name.as_bytes());
+            ret.push(0x00);
+        }
+        ret
+    }
+
+    #[inline]
+    pub fn new(cmd: Socks4Command, addr: Address, userid: String) -> Self {
+        Self { cmd, addr, userid }
+    }
+
+    #[inline]
+    pub fn cmd(&self) -> Socks4Command {
+        self.cmd
+    }
+
+    #[inline]
+    pub fn addr(&self) -> Address {
+        self.addr.to_owned()
+    }
+
+    #[inline]
+    pub fn userid(&self) -> &str {
+        &self.userid
+    }
+}
+
+impl Socks4Request {
+    pub async fn from<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let ver = r.read_u8().await?;
+        if ver != SOCKS4_VERSION {
+            return Err(
+                crate::Socks5Error::VersionMismatch { protocol: "socks", version: ver }.into()
+            );
+        }
+        let cmd = r.read_u8().await?.try_into()?;
+        let port = r.read_u16().await?;
+        let mut ip = [0u8; 4];
+        r.read_exact(&mut ip).await?;
+        let userid = read_null_terminated(r).await?;
+        let addr = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+            Address::domain(&read_null_terminated(r).await?, port)?
+        } else {
+            (Ipv4Addr::from(ip), port).into()
+        };
+        Ok(Self { cmd, addr, userid })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks4ReplyCode {
+    /// 90: request granted
+    Granted,
+    /// 91: request rejected or failed
+    Rejected,
+    /// 92: request rejected because SOCKS server cannot connect to identd on the client
+    IdentdUnreachable,
+    /// 93: request rejected because the client program and identd report different user-ids
+    UseridMismatch,
+}
+
+impl TryFrom<u8> for Socks4ReplyCode {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            90 => Ok(Self::Granted),
+            91 => Ok(Self::Rejected),
+            92 => Ok(Self::IdentdUnreachable),
+            93 => Ok(Self::UseridMismatch),
+            _ => {
+                Err(crate::Socks5Error::UnsupportedReply { protocol: "socks4", code: value }.into())
+            }
+        }
+    }
+}
+
+impl From<Socks4ReplyCode> for u8 {
+    fn from(value: Socks4ReplyCode) -> Self {
+        match value {
+            Socks4ReplyCode::Granted => 90,
+            Socks4ReplyCode::Rejected => 91,
+            Socks4ReplyCode::IdentdUnreachable => 92,
+            Socks4ReplyCode::UseridMismatch => 93,
+        }
+    }
+}
+
+impl From<&Result<TcpStream>> for Socks4ReplyCode {
+    fn from(value: &Result<TcpStream>) -> Self {
+        match value {
+            Ok(_) => Self::Granted,
+            Err(_) => Self::Rejected,
+        }
+    }
+}
+
+/// The server replies as follows, DSTPORT and DSTIP are only meaningful
+/// in the replies to BIND:
+///
+/// ```plain
+///      +----+----+----+----+----+----+----+----+
+///      | VN | CD | DSTPORT |      DSTIP        |
+///      +----+----+----+----+----+----+----+----+
+///      | 1  | 1  |    2    |         4         |
+///      +----+----+----+----+----+----+----+----+
+/// ```
+#[derive(Debug, Clone)]
+pub struct Socks4Reply {
+    code: Socks4ReplyCode,
+    addr: SocketAddrV4,
+}
+
+impl Socks4Reply {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut ret = vec![SOCKS4_REPLY_VERSION /* VN */, self.code.into() /* CD */];
+        ret.extend_from_slice(&self.addr.port().to_be_bytes());
+        ret.extend_from_slice(&self.addr.ip().octets());
+        ret
+    }
+
+    #[inline]
+    pub fn new(code: Socks4ReplyCode, addr: SocketAddrV4) -> Self {
+        Self { code, addr }
+    }
+
+    #[inline]
+    pub fn code(&self) -> Socks4ReplyCode {
+        self.code
+    }
+
+    #[inline]
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    pub async fn respond_with<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        writer.write_all(&self.as_bytes()).await
+    }
+}
+
+impl Socks4Reply {
+    pub async fn from<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let ver = r.read_u8().await?;
+        if ver != SOCKS4_REPLY_VERSION {
+            return Err(crate::Socks5Error::VersionMismatch {
+                protocol: "socks4 reply",
+                version: ver,
+            }
+            .into());
+        }
+        let code = r.read_u8().await?.try_into()?;
+        let port = r.read_u16().await?;
+        let mut ip = [0u8; 4];
+        r.read_exact(&mut ip).await?;
+        Ok(Self { code, addr: SocketAddrV4::new(ip.into(), port) })
+    }
+}
+
+#[test]
+fn test_from() -> std::io::Result<()> {
+    use tokio::io::BufReader;
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+
+    let v4reqbytes = [4u8, 1, 0x00, 0x50, 127, 0, 0, 1, b'f', b'r', b'e', b'd', 0];
+    let mut v4reqbufrd = BufReader::new(&v4reqbytes[..]);
+    let v4req = tokio_rt.block_on(Socks4Request::from(&mut v4reqbufrd))?;
+    assert_eq!(v4req.cmd(), Socks4Command::Connect);
+    assert_eq!(v4req.addr(), (Ipv4Addr::LOCALHOST, 80).into());
+    assert_eq!(v4req.userid(), "fred");
+
+    let mut v4areqbytes = vec![4u8, 2, 0x01, 0xbb, 0, 0, 0, 1, 0];
+    v4areqbytes.extend_from_slice(b"github.com\0");
+    let mut v4areqbufrd = BufReader::new(&v4areqbytes[..]);
+    let v4areq = tokio_rt.block_on(Socks4Request::from(&mut v4areqbufrd))?;
+    assert_eq!(v4areq.cmd(), Socks4Command::Bind);
+    assert_eq!(v4areq.addr(), Address::Domain(String::from("github.com"), 443));
+    assert_eq!(v4areq.userid(), "");
+    assert_eq!(v4areq.as_bytes(), v4areqbytes);
+
+    let mut longreqbytes = vec![4u8, 1, 0x00, 0x50, 127, 0, 0, 1];
+    longreqbytes.extend_from_slice(&[b'a'; SOCKS4_MAX_FIELD_LEN + 1]);
+    longreqbytes.push(0);
+    let mut longreqbufrd = BufReader::new(&longreqbytes[..]);
+    assert!(tokio_rt.block_on(Socks4Request::from(&mut longreqbufrd)).is_err());
+
+    let mut v5reqbufrd = BufReader::new(&[5u8, 1, 0][..]);
+    assert!(tokio_rt.block_on(Socks4Request::from(&mut v5reqbufrd)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_as_bytes() -> std::io::Result<()> {
+    use tokio::io::BufReader;
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+
+    let reply = Socks4Reply::new(
+        Socks4ReplyCode::Granted,
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8080),
+    );
+    let reply_bytes = reply.as_bytes();
+    assert_eq!(reply_bytes, [0u8, 90, 0x1f, 0x90, 10, 0, 0, 1]);
+    let mut replybufrd = BufReader::new(&reply_bytes[..]);
+    let parsed = tokio_rt.block_on(Socks4Reply::from(&mut replybufrd))?;
+    assert_eq!(parsed.code(), Socks4ReplyCode::Granted);
+    assert_eq!(parsed.addr(), reply.addr());
+
+    Ok(())
+}
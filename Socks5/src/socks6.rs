@@ -0,0 +1,139 @@
+//! https://datatracker.ietf.org/doc/html/draft-olteanu-intarea-socks-6-11
+//!
+//! Experimental, only the request is parsed so far. The draft may still
+//! change, hence the `socks6` feature.
+
+use crate::protocol::Address;
+
+use std::io::Result;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const SOCKS6_VERSION: u8 = 0x06;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks6Command {
+    Noop,
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+impl TryFrom<u8> for Socks6Command {
+    type Error = std::io::Error;
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x00 => Ok(Self::Noop),
+            0x01 => Ok(Self::Connect),
+            0x02 => Ok(Self::Bind),
+            0x03 => Ok(Self::UdpAssociate),
+            _ => {
+                Err(crate::Socks5Error::UnsupportedCommand { protocol: "socks6", cmd: value }
+                    .into())
+            }
+        }
+    }
+}
+
+/// The client starts with a request, options are kept undecoded:
+///
+/// ```plain
+///      +---------+--------------+----------------+
+///      | Version | Command Code | Options Length |
+///      +---------+--------------+----------------+
+///      |  Port   |   Padding    |  Address Type  |
+///      +---------+--------------+----------------+
+///      |     Address      |      Options         |
+///      +------------------+----------------------+
+/// ```
+#[derive(Debug, Clone)]
+pub struct Socks6Request {
+    cmd: Socks6Command,
+    addr: Address,
+    options: Vec<u8>,
+}
+
+impl Socks6Request {
+    #[inline]
+    pub fn cmd(&self) -> Socks6Command {
+        self.cmd
+    }
+
+    #[inline]
+    pub fn addr(&self) -> Address {
+        self.addr.to_owned()
+    }
+
+    #[inline]
+    pub fn options(&self) -> &[u8] {
+        &self.options
+    }
+}
+
+impl Socks6Request {
+    pub async fn from<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let ver = r.read_u8().await?;
+        if ver != SOCKS6_VERSION {
+            return Err(
+                crate::Socks5Error::VersionMismatch { protocol: "socks", version: ver }.into()
+            );
+        }
+        let cmd = r.read_u8().await?.try_into()?;
+        let options_len = r.read_u16().await? as usize;
+        let port = r.read_u16().await?;
+        let _padding = r.read_u8().await?;
+        let addr = match r.read_u8().await? {
+            0x01 => {
+                let mut ip = [0u8; 4];
+                r.read_exact(&mut ip).await?;
+                (Ipv4Addr::from(ip), port).into()
+            }
+            0x03 => {
+                /* Length prefixed, then zero padded to a multiple of 4 octets */
+                let dnlen = r.read_u8().await? as usize;
+                let mut buf = vec![0u8; dnlen + (4 - (dnlen + 1) % 4) % 4];
+                r.read_exact(&mut buf).await?;
+                buf.truncate(dnlen);
+                let name = String::from_utf8(buf)
+                    .map_err(|_| crate::invalid_data("Domain name is not UTF-8"))?;
+                Address::domain(&name, port)?
+            }
+            0x04 => {
+                let mut ip = [0u8; 16];
+                r.read_exact(&mut ip).await?;
+                (Ipv6Addr::from(ip), port).into()
+            }
+            atyp => return Err(crate::Socks5Error::BadAddressType(atyp).into()),
+        };
+        let mut options = vec![0u8; options_len];
+        r.read_exact(&mut options).await?;
+        Ok(Self { cmd, addr, options })
+    }
+}
+
+#[test]
+fn test_from() -> std::io::Result<()> {
+    use tokio::io::BufReader;
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+
+    let v4reqbytes = [6u8, 1, 0, 0, 0x00, 0x50, 0, 1, 127, 0, 0, 1];
+    let mut v4reqbufrd = BufReader::new(&v4reqbytes[..]);
+    let v4req = tokio_rt.block_on(Socks6Request::from(&mut v4reqbufrd))?;
+    assert_eq!(v4req.cmd(), Socks6Command::Connect);
+    assert_eq!(v4req.addr(), (Ipv4Addr::LOCALHOST, 80).into());
+    assert!(v4req.options().is_empty());
+
+    let mut dnreqbytes = vec![6u8, 1, 0, 4, 0x01, 0xbb, 0, 3, 10];
+    dnreqbytes.extend_from_slice(b"github.com\0");
+    dnreqbytes.extend_from_slice(&[1, 2, 3, 4]);
+    let mut dnreqbufrd = BufReader::new(&dnreqbytes[..]);
+    let dnreq = tokio_rt.block_on(Socks6Request::from(&mut dnreqbufrd))?;
+    assert_eq!(dnreq.addr(), Address::Domain(String::from("github.com"), 443));
+    assert_eq!(dnreq.options(), [1, 2, 3, 4]);
+
+    Ok(())
+}
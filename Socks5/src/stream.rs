@@ -0,0 +1,186 @@
+//! The streams a server serves, whatever carries them
+//!
+//! A client may reach the server over plain TCP, within TLS, over a
+//! WebSocket or, in tests, through an in-memory pipe. The handshakes and
+//! relays only read and write, so they take any [ClientStream]; what
+//! differs between transports is how a stream is closed, which is what
+//! [Shutdown] tells, and whether a byte can be looked at before it is
+//! read, which [Peek] does and [Peekable] adds to those which cannot.
+
+use std::future::Future;
+use std::io::{ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+
+/// How a stream is closed. A graceful close is that of
+/// [tokio::io::AsyncWriteExt::shutdown]: a FIN for TCP, a `close_notify`
+/// first within TLS.
+pub trait Shutdown: AsyncWrite + Unpin {
+    /// Close at once as a failure, what is not yet sent dropped: a reset
+    /// for TCP, only dropping the stream for those which have none
+    fn abort(self)
+    where
+        Self: Sized,
+    {
+    }
+}
+
+impl Shutdown for TcpStream {
+    fn abort(self) {
+        /* A zero linger does not block the close, and sends a RST */
+        #[allow(deprecated)]
+        let _ = self.set_linger(Some(Duration::ZERO));
+    }
+}
+
+impl Shutdown for DuplexStream {}
+
+impl<S: Shutdown> Shutdown for Peekable<S> {
+    fn abort(self) {
+        self.inner.abort()
+    }
+}
+
+/// A stream the handlers of a server can serve
+pub trait ClientStream: AsyncRead + AsyncWrite + Shutdown + Unpin + Send + 'static {}
+
+impl<S> ClientStream for S where S: AsyncRead + AsyncWrite + Shutdown + Unpin + Send + 'static {}
+
+/// Look at the first byte left to read without consuming it
+pub trait Peek {
+    /// Fails with [ErrorKind::UnexpectedEof] when the peer sent nothing
+    /// before closing
+    fn peek_byte(&mut self) -> impl Future<Output = Result<u8>> + Send;
+}
+
+impl Peek for TcpStream {
+    async fn peek_byte(&mut self) -> Result<u8> {
+        let mut byte = [0u8; 1];
+        if self.peek(&mut byte).await? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(byte[0])
+    }
+}
+
+/// A stream which cannot peek, given a byte of look-ahead: the peeked byte
+/// is read from the stream and handed out again by the next read
+#[derive(Debug)]
+pub struct Peekable<S> {
+    inner: S,
+    peeked: Option<u8>,
+}
+
+impl<S> Peekable<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, peeked: None }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// The stream, unless a byte peeked is still to be read, which would
+    /// be lost
+    pub fn into_inner(self) -> std::result::Result<S, Self> {
+        match self.peeked {
+            Some(_) => Err(self),
+            None => Ok(self.inner),
+        }
+    }
+}
+
+impl<S> Peek for Peekable<S>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    async fn peek_byte(&mut self) -> Result<u8> {
+        if let Some(byte) = self.peeked {
+            return Ok(byte);
+        }
+        let byte = self.inner.read_u8().await?;
+        self.peeked = Some(byte);
+        Ok(byte)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Peekable<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        match this.peeked.take() {
+            Some(byte) if buf.remaining() > 0 => {
+                buf.put_slice(&[byte]);
+                Poll::Ready(Ok(()))
+            }
+            peeked => {
+                this.peeked = peeked;
+                Pin::new(&mut this.inner).poll_read(cx, buf)
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Peekable<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[test]
+fn test_peekable() -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    tokio_rt.block_on(async {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Peekable::new(server);
+        client.write_all(&[5, 1, 0]).await?;
+        assert_eq!(server.peek_byte().await?, 5);
+        assert_eq!(server.peek_byte().await?, 5);
+        let mut server = server.into_inner().unwrap_err();
+        let mut buf = [0u8; 3];
+        server.read_exact(&mut buf).await?;
+        assert_eq!(buf, [5, 1, 0]);
+        assert!(server.into_inner().is_ok());
+
+        drop(client);
+        let (client, server) = tokio::io::duplex(64);
+        drop(client);
+        let err = Peekable::new(server).peek_byte().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        Ok(())
+    })
+}
@@ -0,0 +1,126 @@
+//! Sessions in memory, for protocol tests without binding a port
+//!
+//! [duplex_pair] connects a client end to a server end as an accepted TCP
+//! connection would, over [tokio::io::duplex]: the protocol messages read
+//! from and write to either, and a [crate::dispatch::Dispatcher] serves the
+//! server end once it is wrapped in a [crate::stream::Peekable]. A peer is
+//! scripted as [Step]s, which [play] runs, and [run_scripted] runs a server
+//! handler against a scripted client, or a client against a scripted server.
+
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+/// What either end buffers before its writes wait for the other to read
+pub const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A client end and a server end connected to each other
+#[inline]
+pub fn duplex_pair() -> (DuplexStream, DuplexStream) {
+    tokio::io::duplex(DUPLEX_BUFFER_SIZE)
+}
+
+/// One step of a scripted peer
+#[derive(Debug, Clone, Copy)]
+pub enum Step<'a> {
+    /// Write these bytes
+    Send(&'a [u8]),
+    /// Read exactly these bytes
+    Expect(&'a [u8]),
+    /// Shut down the writing half, as a client cutting a frame short does
+    Shutdown,
+}
+
+/// Play `steps` on `stream`, then check that the other end closed without
+/// writing anything more. The error names the step that failed, bytes read
+/// other than those expected failing with [ErrorKind::InvalidData].
+pub async fn play<S>(stream: &mut S, steps: &[Step<'_>]) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let at = |i: usize, e: Error| Error::new(e.kind(), format!("step {}: {}", i, e));
+    for (i, step) in steps.iter().enumerate() {
+        match step {
+            Step::Send(bytes) => stream.write_all(bytes).await.map_err(|e| at(i, e))?,
+            Step::Expect(bytes) => {
+                let mut buf = vec![0u8; bytes.len()];
+                stream.read_exact(&mut buf).await.map_err(|e| at(i, e))?;
+                if buf != *bytes {
+                    let e = format!("expected {:?}, read {:?}", bytes, buf);
+                    return Err(at(i, Error::new(ErrorKind::InvalidData, e)));
+                }
+            }
+            Step::Shutdown => stream.shutdown().await.map_err(|e| at(i, e))?,
+        }
+    }
+    let mut rest = Vec::new();
+    stream
+        .read_to_end(&mut rest)
+        .await
+        .map_err(|e| Error::new(e.kind(), format!("reading to the end: {}", e)))?;
+    match rest.is_empty() {
+        true => Ok(()),
+        false => {
+            Err(Error::new(ErrorKind::InvalidData, format!("unexpected trailing bytes {:?}", rest)))
+        }
+    }
+}
+
+/// Run `handler` on one end of a [duplex_pair] while `peer` is played on
+/// the other, until both are done. The end `handler` is given is dropped
+/// when it returns, closing it for [play] to read to the end. Returns how
+/// the script went, then what `handler` returned.
+pub async fn run_scripted<F, Fut>(peer: &[Step<'_>], handler: F) -> (Result<()>, Fut::Output)
+where
+    F: FnOnce(DuplexStream) -> Fut,
+    Fut: Future,
+{
+    let (mut scripted, handled) = duplex_pair();
+    tokio::join!(play(&mut scripted, peer), handler(handled))
+}
+
+#[test]
+fn test_run_scripted() -> Result<()> {
+    use crate::dispatch::Dispatcher;
+    use crate::protocol::{AuthMethod, HandshakeRequest, HandshakeResponse};
+    use crate::stream::Peekable;
+
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    tokio_rt.block_on(async {
+        let greeting = [Step::Send(&[5, 1, 0]), Step::Expect(&[5, 0])];
+        let (played, served) = run_scripted(&greeting, |mut server| async move {
+            let hreq = HandshakeRequest::from(&mut server).await?;
+            let method = hreq.select_method(&[AuthMethod::NoAuthenticationRequired]);
+            HandshakeResponse::new(method).write_to(&mut server).await
+        })
+        .await;
+        played?;
+        served?;
+
+        /* Served by a dispatcher, through the byte it peeks */
+        let mut dispatcher = Dispatcher::<(), Peekable<DuplexStream>>::new();
+        dispatcher.register(crate::SOCKS_VERSION, |mut server, _| async move {
+            let hreq = HandshakeRequest::from(&mut server).await?;
+            let method = hreq.select_method(&[AuthMethod::UsernameOrPassword]);
+            HandshakeResponse::new(method).write_to(&mut server).await
+        });
+        let no_acceptable = [Step::Send(&[5, 1, 0]), Step::Expect(&[5, 0xff])];
+        let (played, served) =
+            run_scripted(&no_acceptable, |server| dispatcher.dispatch(Peekable::new(server), ()))
+                .await;
+        played?;
+        served?;
+
+        /* A server replying other than the script expects */
+        let (played, _) = run_scripted(&greeting, |mut server| async move {
+            let _ = HandshakeRequest::from(&mut server).await;
+            server.write_all(&[5, 2]).await
+        })
+        .await;
+        let err = played.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("step 1:"), "{}", err);
+        Ok(())
+    })
+}
@@ -0,0 +1,464 @@
+//! Protocol trace mode
+//!
+//! When enabled for a connection, every SOCKS message that is sent or
+//! received is logged as a hex dump annotated with the field names and
+//! offsets from RFC 1928 / RFC 1929:
+//!
+//! ```plain
+//! [trace 127.0.0.1:50210] <<< TellRequest (RFC 1928, 10 bytes)
+//!   0000  05                                               VER
+//!   0001  01                                               CMD  Connect
+//!   0002  00                                               RSV
+//!   0003  01                                               ATYP  IPV4
+//!   0004  7f 00 00 01                                      DST.ADDR  127.0.0.1
+//!   0008  00 50                                            DST.PORT  80
+//! ```
+
+use crate::protocol::{
+    Address, AddressType, HandshakeRequest, HandshakeResponse, ReplyResponse, TellRequest,
+    UdpPacket, UsernamePasswordAuth, UsernamePasswordAuthResult,
+};
+use crate::socks4::{Socks4Reply, Socks4Request};
+
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Number of octets rendered on a single dump line
+const BYTES_PER_LINE: usize = 16;
+
+/// A named field of a protocol message
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub len: usize,
+    /// Human readable interpretation of the field value
+    pub note: String,
+    /// Hide the field value in the dump (e.g. passwords)
+    pub masked: bool,
+}
+
+impl TraceField {
+    #[inline]
+    pub fn new(name: &'static str, offset: usize, len: usize) -> Self {
+        Self { name, offset, len, note: String::new(), masked: false }
+    }
+
+    #[inline]
+    pub fn with_note<S: ToString>(mut self, note: S) -> Self {
+        self.note = note.to_string();
+        self
+    }
+
+    #[inline]
+    pub fn masked(mut self) -> Self {
+        self.masked = true;
+        self
+    }
+}
+
+/// Protocol messages that can be rendered as an annotated hex dump
+pub trait Traceable {
+    /// Message name and the RFC it is defined in
+    fn trace_name(&self) -> (&'static str, &'static str);
+    /// Wire representation of the message
+    fn trace_bytes(&self) -> Vec<u8>;
+    /// Field layout of [Traceable::trace_bytes]
+    fn trace_fields(&self) -> Vec<TraceField>;
+}
+
+/// Field layout of `ATYP | ADDR | PORT` starting at `offset`
+fn addr_fields(
+    (addr_name, port_name): (&'static str, &'static str),
+    offset: usize,
+    addr: &Address,
+) -> Vec<TraceField> {
+    let atyp: AddressType = addr.to_owned().into();
+    let mut ret = vec![TraceField::new("ATYP", offset, 1).with_note(format!("{:?}", atyp))];
+    let mut offset = offset + 1;
+    match addr {
+        Address::IP(socket_addr) => {
+            let len = match socket_addr.ip() {
+                IpAddr::V4(_) => 4,
+                IpAddr::V6(_) => 16,
+            };
+            ret.push(TraceField::new(addr_name, offset, len).with_note(socket_addr.ip()));
+            offset += len;
+        }
+        Address::Domain(name, _) => {
+            let len = name.len();
+            ret.push(TraceField::new("DNLEN", offset, 1).with_note(len));
+            ret.push(TraceField::new(addr_name, offset + 1, len).with_note(name));
+            offset += 1 + len;
+        }
+    }
+    ret.push(TraceField::new(port_name, offset, 2).with_note(addr.port()));
+    ret
+}
+
+impl Traceable for HandshakeRequest {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("HandshakeRequest", "RFC 1928")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_bytes().unwrap_or_default()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        let methods = self.methods();
+        vec![
+            TraceField::new("VER", 0, 1),
+            TraceField::new("NMETHODS", 1, 1).with_note(methods.len()),
+            TraceField::new("METHODS", 2, methods.len()).with_note(format!("{:?}", methods)),
+        ]
+    }
+}
+
+impl Traceable for HandshakeResponse {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("HandshakeResponse", "RFC 1928")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_bytes()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        vec![
+            TraceField::new("VER", 0, 1),
+            TraceField::new("METHOD", 1, 1).with_note(format!("{:?}", self.method())),
+        ]
+    }
+}
+
+impl Traceable for UsernamePasswordAuth {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("UsernamePasswordAuth", "RFC 1929")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_bytes()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        let ulen = self.uname().len();
+        let plen = self.passwd().len();
+        vec![
+            TraceField::new("VER", 0, 1),
+            TraceField::new("ULEN", 1, 1).with_note(ulen),
+            TraceField::new("UNAME", 2, ulen).with_note(self.uname()),
+            TraceField::new("PLEN", 2 + ulen, 1).with_note(plen),
+            TraceField::new("PASSWD", 3 + ulen, plen).masked(),
+        ]
+    }
+}
+
+impl Traceable for UsernamePasswordAuthResult {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("UsernamePasswordAuthResult", "RFC 1929")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_bytes()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        vec![
+            TraceField::new("VER", 0, 1),
+            TraceField::new("STATUS", 1, 1).with_note(format!("{:?}", self)),
+        ]
+    }
+}
+
+impl Traceable for TellRequest {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("TellRequest", "RFC 1928")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_bytes().unwrap_or_default()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        let mut ret = vec![
+            TraceField::new("VER", 0, 1),
+            TraceField::new("CMD", 1, 1).with_note(format!("{:?}", self.cmd())),
+            TraceField::new("RSV", 2, 1),
+        ];
+        ret.extend(addr_fields(("DST.ADDR", "DST.PORT"), 3, &self.addr()));
+        ret
+    }
+}
+
+impl Traceable for ReplyResponse {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("ReplyResponse", "RFC 1928")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_bytes().unwrap_or_default()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        let mut ret = vec![
+            TraceField::new("VER", 0, 1),
+            TraceField::new("REP", 1, 1).with_note(format!("{:?}", self.rep())),
+            TraceField::new("RSV", 2, 1),
+        ];
+        ret.extend(addr_fields(("BND.ADDR", "BND.PORT"), 3, &self.addr()));
+        ret
+    }
+}
+
+impl Traceable for Socks4Request {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("Socks4Request", "SOCKS4")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_bytes()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        let userid_len = self.userid().len();
+        let mut ret = vec![
+            TraceField::new("VN", 0, 1),
+            TraceField::new("CD", 1, 1).with_note(format!("{:?}", self.cmd())),
+            TraceField::new("DSTPORT", 2, 2).with_note(self.addr().port()),
+            TraceField::new("DSTIP", 4, 4),
+            TraceField::new("USERID", 8, userid_len).with_note(self.userid()),
+            TraceField::new("NULL", 8 + userid_len, 1),
+        ];
+        if let Address::Domain(name, _) = self.addr() {
+            let (offset, len) = (9 + userid_len, name.len());
+            ret.push(TraceField::new("DOMAIN", offset, len).with_note(name));
+            ret.push(TraceField::new("NULL", offset + len, 1));
+        }
+        ret
+    }
+}
+
+impl Traceable for Socks4Reply {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("Socks4Reply", "SOCKS4")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_bytes()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        vec![
+            TraceField::new("VN", 0, 1),
+            TraceField::new("CD", 1, 1).with_note(format!("{:?}", self.code())),
+            TraceField::new("DSTPORT", 2, 2).with_note(self.addr().port()),
+            TraceField::new("DSTIP", 4, 4).with_note(self.addr().ip()),
+        ]
+    }
+}
+
+impl Traceable for UdpPacket {
+    fn trace_name(&self) -> (&'static str, &'static str) {
+        ("UdpPacket", "RFC 1928")
+    }
+
+    fn trace_bytes(&self) -> Vec<u8> {
+        self.as_socks_bytes().unwrap_or_default()
+    }
+
+    fn trace_fields(&self) -> Vec<TraceField> {
+        let mut ret = vec![
+            TraceField::new("RSV", 0, 2),
+            TraceField::new("FRAG", 2, 1).with_note(self.frag()),
+        ];
+        ret.extend(addr_fields(("DST.ADDR", "DST.PORT"), 3, &self.addr()));
+        let dnlen = match self.addr() {
+            Address::Domain(..) => 1,
+            Address::IP(_) => 0,
+        };
+        let data_offset = 3 + 1 + dnlen + self.addr().len();
+        let data_len = self.data().len();
+        ret.push(TraceField::new("DATA", data_offset, data_len).with_note(data_len));
+        ret
+    }
+}
+
+/// Render `msg` as an annotated hex dump
+pub fn hexdump<T: Traceable + ?Sized>(msg: &T) -> String {
+    let bytes = msg.trace_bytes();
+    let (name, rfc) = msg.trace_name();
+    let mut ret = format!("{} ({}, {} bytes)\n", name, rfc, bytes.len());
+    for field in msg.trace_fields() {
+        let end = (field.offset + field.len).min(bytes.len());
+        let value = &bytes[field.offset.min(end)..end];
+        if value.is_empty() {
+            let _ = writeln!(ret, "  {:04x}  {:<48} {}", field.offset, "", field.name);
+        }
+        for (idx, chunk) in value.chunks(BYTES_PER_LINE).enumerate() {
+            let hex = chunk
+                .iter()
+                .map(|b| if field.masked { String::from("**") } else { format!("{:02x}", b) })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let offset = field.offset + idx * BYTES_PER_LINE;
+            if idx == 0 {
+                let _ = write!(ret, "  {:04x}  {:<48} {}", offset, hex, field.name);
+                if !field.note.is_empty() {
+                    let _ = write!(ret, "  {}", field.note);
+                }
+                ret.push('\n');
+            } else {
+                let _ = writeln!(ret, "  {:04x}  {}", offset, hex);
+            }
+        }
+    }
+    ret
+}
+
+#[derive(Debug, Default)]
+enum TraceState {
+    #[default]
+    Disabled,
+    Enabled,
+    /// Undecided until more is known about the connection, the dumps are
+    /// held back until then
+    Pending(Vec<String>),
+}
+
+/// Per connection protocol tracer
+///
+/// A disabled tracer is cheap to carry around, so callers can create one
+/// for every connection and decide at runtime whether it is enabled.
+/// Clones share whether they are enabled.
+#[derive(Debug, Clone, Default)]
+pub struct Tracer {
+    label: String,
+    state: Arc<Mutex<TraceState>>,
+}
+
+impl Tracer {
+    #[inline]
+    pub fn new<S: ToString>(label: S, enabled: bool) -> Self {
+        let state = if enabled { TraceState::Enabled } else { TraceState::Disabled };
+        Self { label: label.to_string(), state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// A tracer that holds its dumps back until [Tracer::resolve] decides
+    /// whether it is enabled, e.g. once the destination is known
+    #[inline]
+    pub fn pending<S: ToString>(label: S) -> Self {
+        Self { label: label.to_string(), state: Arc::new(Mutex::new(TraceState::Pending(vec![]))) }
+    }
+
+    #[inline]
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), TraceState::Enabled)
+    }
+
+    #[inline]
+    pub fn is_pending(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), TraceState::Pending(_))
+    }
+
+    /// Enable a pending tracer, printing what it held back, or disable it
+    /// and drop that. Does nothing once decided.
+    pub fn resolve(&self, enabled: bool) {
+        let mut state = self.state.lock().unwrap();
+        if let TraceState::Pending(held) = &*state {
+            if enabled {
+                held.iter().for_each(|dump| eprint!("{}", dump));
+                *state = TraceState::Enabled;
+            } else {
+                *state = TraceState::Disabled;
+            }
+        }
+    }
+
+    #[inline]
+    pub fn label(&self) -> String {
+        self.label.to_owned()
+    }
+
+    /// Log a message received from the peer
+    #[inline]
+    pub fn recv<T: Traceable + ?Sized>(&self, msg: &T) {
+        self.log("<<<", msg)
+    }
+
+    /// Log a message sent to the peer
+    #[inline]
+    pub fn send<T: Traceable + ?Sized>(&self, msg: &T) {
+        self.log(">>>", msg)
+    }
+
+    fn log<T: Traceable + ?Sized>(&self, direction: &str, msg: &T) {
+        match &mut *self.state.lock().unwrap() {
+            TraceState::Disabled => {}
+            TraceState::Enabled => {
+                eprint!("[trace {}] {} {}", self.label, direction, hexdump(msg))
+            }
+            TraceState::Pending(held) => {
+                held.push(format!("[trace {}] {} {}", self.label, direction, hexdump(msg)))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_hexdump_tellreq() {
+    use crate::protocol::Command;
+
+    let tellreq = TellRequest::new(Command::Connect, (std::net::Ipv4Addr::LOCALHOST, 80).into());
+    let dump = hexdump(&tellreq);
+    let lines = dump.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "TellRequest (RFC 1928, 10 bytes)");
+    assert!(lines[1].starts_with("  0000  05 ") && lines[1].ends_with("VER"));
+    assert!(lines[2].contains("CMD  Connect"));
+    assert!(lines[4].contains("ATYP  IPV4"));
+    assert!(
+        lines[5].starts_with("  0004  7f 00 00 01 ") && lines[5].contains("DST.ADDR  127.0.0.1")
+    );
+    assert!(lines[6].starts_with("  0008  00 50 ") && lines[6].contains("DST.PORT  80"));
+}
+
+#[test]
+fn test_hexdump_masks_passwd() {
+    let auth = UsernamePasswordAuth::new("usr", "secret");
+    let dump = hexdump(&auth);
+    assert!(dump.contains("UNAME  usr"));
+    assert!(dump.contains("** ** ** ** ** **"));
+    assert!(!dump.contains("73 65 63 72 65 74"));
+}
+
+#[test]
+fn test_hexdump_fields_cover_message() {
+    let udp_pack = UdpPacket::new(0, Address::Domain(String::from("github.com"), 443), vec![1; 20]);
+    let fields = udp_pack.trace_fields();
+    let covered: usize = fields.iter().map(|f| f.len).sum();
+    assert_eq!(covered, udp_pack.trace_bytes().len());
+    assert_eq!(fields.last().unwrap().name, "DATA");
+}
+
+#[test]
+fn test_pending_tracer() {
+    let tracer = Tracer::pending("127.0.0.1:50210");
+    let clone = tracer.clone();
+    assert!(tracer.is_pending() && !tracer.enabled());
+    clone.resolve(true);
+    assert!(tracer.enabled() && !tracer.is_pending());
+    /* Decided once and for all */
+    tracer.resolve(false);
+    assert!(clone.enabled());
+
+    let tracer = Tracer::pending("127.0.0.1:50211");
+    tracer.resolve(false);
+    assert!(!tracer.enabled() && !tracer.is_pending());
+    assert!(!Tracer::new("127.0.0.1:50212", false).is_pending());
+}
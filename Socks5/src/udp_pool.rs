@@ -0,0 +1,311 @@
+//! Outbound UDP sockets shared over time by the relays of UDP associations
+//!
+//! Binding a fresh socket per association takes an ephemeral port each
+//! time, and clients opening associations in bursts, as DNS over SOCKS5
+//! does, run the host out of them. A socket given back to the pool once the
+//! last of its leases drops is handed to the next association bound the
+//! same way, until it has waited [DEFAULT_IDLE_TIMEOUT] unused.
+//!
+//! A lease is exclusive while held: replies on a socket go to the one relay
+//! holding it. Datagrams still queued when it is leased again are dropped,
+//! late replies to the previous holder may yet reach the next one, which
+//! [PooledUdpSocket::recv_from_peer] drops along with anything else not
+//! coming from where the lease sent to.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+
+/// Time a socket given back stays in the pool unused before it is closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sockets kept for each key, those given back past it are closed
+pub const DEFAULT_MAX_IDLE: usize = 64;
+
+/// How the sockets of a pool entry are bound
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UdpPoolKey {
+    /// The interface they are pinned to, if any
+    pub interface: Option<String>,
+    /// The address they are bound to, unspecified for any of its family
+    pub local_ip: IpAddr,
+}
+
+impl UdpPoolKey {
+    /// Bound to any address of a family, pinned to no interface
+    pub fn any(ipv6: bool) -> Self {
+        let local_ip = match ipv6 {
+            true => Ipv6Addr::UNSPECIFIED.into(),
+            false => Ipv4Addr::UNSPECIFIED.into(),
+        };
+        Self { interface: None, local_ip }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.local_ip.is_ipv6()
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    idle: Mutex<HashMap<UdpPoolKey, Vec<(UdpSocket, Instant)>>>,
+    idle_timeout: Duration,
+    max_idle: usize,
+}
+
+impl Shared {
+    /// Close the sockets of `idle` unused for longer than the idle timeout
+    fn reclaim(&self, idle: &mut HashMap<UdpPoolKey, Vec<(UdpSocket, Instant)>>) {
+        let now = Instant::now();
+        for sockets in idle.values_mut() {
+            sockets.retain(|(_, since)| now.duration_since(*since) < self.idle_timeout);
+        }
+        idle.retain(|_, sockets| !sockets.is_empty());
+    }
+
+    fn give_back(&self, key: UdpPoolKey, socket: UdpSocket) {
+        drain(&socket);
+        let mut idle = self.idle.lock().unwrap();
+        self.reclaim(&mut idle);
+        let sockets = idle.entry(key).or_default();
+        if sockets.len() < self.max_idle {
+            sockets.push((socket, Instant::now()));
+        }
+    }
+}
+
+/// Drop the datagrams queued on `socket`
+fn drain(socket: &UdpSocket) {
+    let mut buf = [0u8; 1];
+    loop {
+        match socket.try_recv_from(&mut buf) {
+            Ok(_) => {}
+            /* An ICMP error of a datagram sent before, nothing was read */
+            Err(e) if e.kind() != ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+    }
+}
+
+/// Outbound UDP sockets, cheap to clone, clones sharing the sockets
+#[derive(Debug, Clone)]
+pub struct UdpSocketPool {
+    shared: Arc<Shared>,
+}
+
+impl Default for UdpSocketPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_IDLE)
+    }
+}
+
+impl UdpSocketPool {
+    pub fn new(idle_timeout: Duration, max_idle: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared { idle: Mutex::new(HashMap::new()), idle_timeout, max_idle }),
+        }
+    }
+
+    /// A socket bound as `key` tells, one given back if any, else one `bind`
+    /// makes
+    pub fn acquire_with<F>(&self, key: UdpPoolKey, bind: F) -> Result<PooledUdpSocket>
+    where
+        F: FnOnce() -> Result<UdpSocket>,
+    {
+        let reused = {
+            let mut idle = self.shared.idle.lock().unwrap();
+            self.shared.reclaim(&mut idle);
+            idle.get_mut(&key).and_then(Vec::pop)
+        };
+        let socket = match reused {
+            Some((socket, _)) => {
+                drain(&socket);
+                socket
+            }
+            None => bind()?,
+        };
+        let lease = Lease {
+            socket: Some(socket),
+            key,
+            peers: Mutex::new(HashSet::new()),
+            shared: self.shared.clone(),
+        };
+        Ok(PooledUdpSocket(Arc::new(lease)))
+    }
+
+    /// [UdpSocketPool::acquire_with] binding to `key.local_ip`, which only
+    /// does for keys without an interface
+    pub fn acquire(&self, key: UdpPoolKey) -> Result<PooledUdpSocket> {
+        let local_addr = SocketAddr::new(key.local_ip, 0);
+        self.acquire_with(key, || {
+            let socket = std::net::UdpSocket::bind(local_addr)?;
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)
+        })
+    }
+
+    /// Sockets waiting in the pool, all keys together
+    pub fn idle_count(&self) -> usize {
+        let mut idle = self.shared.idle.lock().unwrap();
+        self.shared.reclaim(&mut idle);
+        idle.values().map(Vec::len).sum()
+    }
+}
+
+#[derive(Debug)]
+struct Lease {
+    /// Taken back on drop
+    socket: Option<UdpSocket>,
+    key: UdpPoolKey,
+    /// Where datagrams were sent to during this lease
+    peers: Mutex<HashSet<SocketAddr>>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            self.shared.give_back(self.key.clone(), socket);
+        }
+    }
+}
+
+/// A socket of a [UdpSocketPool], given back once the last clone drops
+#[derive(Debug, Clone)]
+pub struct PooledUdpSocket(Arc<Lease>);
+
+impl Deref for PooledUdpSocket {
+    type Target = UdpSocket;
+
+    fn deref(&self) -> &UdpSocket {
+        self.0.socket.as_ref().unwrap()
+    }
+}
+
+impl PooledUdpSocket {
+    /// [UdpSocket::send_to], which makes `target` a peer of this lease
+    pub async fn send_to_peer(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
+        self.0.peers.lock().unwrap().insert(canonical(target));
+        self.send_to(buf, target).await
+    }
+
+    /// [UdpSocket::recv_from] of the next datagram from a peer of this lease,
+    /// see [PooledUdpSocket::send_to_peer]. Those from anywhere else, e.g.
+    /// late replies to the previous holder, are dropped, `on_foreign` being
+    /// told where each came from.
+    pub async fn recv_from_peer<F>(
+        &self,
+        buf: &mut [u8],
+        mut on_foreign: F,
+    ) -> Result<(usize, SocketAddr)>
+    where
+        F: FnMut(SocketAddr),
+    {
+        loop {
+            let (len, from) = self.recv_from(buf).await?;
+            match self.0.peers.lock().unwrap().contains(&canonical(from)) {
+                true => return Ok((len, from)),
+                false => on_foreign(from),
+            }
+        }
+    }
+}
+
+/// `addr` with a v4-mapped IP as IPv4, as a dual-stack socket reports it
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[test]
+fn test_udp_socket_pool() -> Result<()> {
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    let _guard = tokio_rt.enter();
+    let pool = UdpSocketPool::new(Duration::from_millis(200), 2);
+    let key = UdpPoolKey { interface: None, local_ip: Ipv4Addr::LOCALHOST.into() };
+
+    let first = pool.acquire(key.clone())?;
+    let port = first.local_addr()?.port();
+    /* Not shared while leased */
+    let second = pool.acquire(key.clone())?;
+    assert_ne!(second.local_addr()?.port(), port);
+    let clone = first.clone();
+    drop(first);
+    assert_eq!(pool.idle_count(), 0);
+    drop(clone);
+    assert_eq!(pool.idle_count(), 1);
+    assert_eq!(pool.acquire(key.clone())?.local_addr()?.port(), port);
+
+    /* Keys apart, and no more than max_idle kept of each */
+    let any = pool.acquire(UdpPoolKey::any(false))?;
+    assert_eq!(any.local_addr()?.ip(), Ipv4Addr::UNSPECIFIED);
+    let third = pool.acquire(key.clone())?;
+    let fourth = pool.acquire(key.clone())?;
+    drop((second, third, fourth, any));
+    assert_eq!(pool.idle_count(), 3);
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(pool.idle_count(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_udp_socket_pool_drains() -> Result<()> {
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    let pool = UdpSocketPool::default();
+    let key = UdpPoolKey { interface: None, local_ip: Ipv4Addr::LOCALHOST.into() };
+    tokio_rt.block_on(async {
+        let socket = pool.acquire(key.clone())?;
+        let to = socket.local_addr()?;
+        let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        peer.send_to(b"stale", to).await?;
+        socket.readable().await?;
+        drop(socket);
+
+        let socket = pool.acquire(key)?;
+        assert_eq!(socket.local_addr()?, to);
+        peer.send_to(b"fresh", to).await?;
+        let mut buf = [0u8; 8];
+        let (len, _) = socket.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len], b"fresh");
+        Ok(())
+    })
+}
+
+#[test]
+fn test_udp_socket_pool_peers() -> Result<()> {
+    let tokio_rt = tokio::runtime::Runtime::new()?;
+    let pool = UdpSocketPool::default();
+    let key = UdpPoolKey { interface: None, local_ip: Ipv4Addr::LOCALHOST.into() };
+    tokio_rt.block_on(async {
+        let first_dst = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let second_dst = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut buf = [0u8; 8];
+
+        /* A first association asks first_dst, which has yet to answer */
+        let socket = pool.acquire(key.clone())?;
+        let to = socket.local_addr()?;
+        socket.send_to_peer(b"query", first_dst.local_addr()?).await?;
+        let (_, from) = first_dst.recv_from(&mut buf).await?;
+        assert_eq!(from, to);
+        drop(socket);
+
+        /* The next one on the same socket asks second_dst, the late answer
+         * of first_dst is not taken for one of its replies */
+        let socket = pool.acquire(key)?;
+        assert_eq!(socket.local_addr()?, to);
+        socket.send_to_peer(b"query", second_dst.local_addr()?).await?;
+        first_dst.send_to(b"late", to).await?;
+        let (_, from) = second_dst.recv_from(&mut buf).await?;
+        second_dst.send_to(b"reply", from).await?;
+        let mut foreign = vec![];
+        let (len, from) = socket.recv_from_peer(&mut buf, |from| foreign.push(from)).await?;
+        assert_eq!((&buf[..len], from), (&b"reply"[..], second_dst.local_addr()?));
+        assert_eq!(foreign, [first_dst.local_addr()?]);
+        Ok(())
+    })
+}
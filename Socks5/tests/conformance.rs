@@ -0,0 +1,500 @@
+//! Conformance of the protocol messages to RFC 1928 and RFC 1929, checked
+//! on whole sessions: a server put together from the server side messages
+//! is run against scripted clients, and a client put together from the
+//! client side ones against scripted servers. Both ends talk over an
+//! in-memory duplex stream, see [socks5::testing], so no socket is needed.
+
+use std::future::Future;
+use std::io::{ErrorKind, Result};
+use std::net::Ipv4Addr;
+
+use socks5::protocol::{
+    Address, AuthMethod, HandshakeRequest, HandshakeResponse, ReplyField, ReplyResponse,
+    TellRequest, UdpPacket, UsernamePasswordAuth, UsernamePasswordAuthResult,
+};
+use socks5::testing::{duplex_pair, run_scripted, Step};
+use socks5::Socks5Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+const CREDENTIALS: (&str, &str) = ("user", "pass");
+
+fn block_on<F: Future>(fut: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(fut)
+}
+
+/// The server side of a session: negotiate a method, with `credentials`
+/// required when given, then read the request and reply that it succeeded.
+/// As the server of the CLI does, a request that cannot be parsed closes
+/// the connection, with a reply only for an unknown CMD or ATYP.
+async fn serve<S>(mut stream: S, credentials: Option<(&str, &str)>) -> Result<TellRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let supported = match credentials {
+        Some(_) => AuthMethod::UsernameOrPassword,
+        None => AuthMethod::NoAuthenticationRequired,
+    };
+    let method = HandshakeRequest::from(&mut stream).await?.select_method(&[supported]);
+    HandshakeResponse::new(method.clone()).write_to(&mut stream).await?;
+    if method == AuthMethod::NoAcceptableMethods {
+        return Err(ErrorKind::PermissionDenied.into());
+    }
+    if let Some((usr, pwd)) = credentials {
+        let auth = UsernamePasswordAuth::from(&mut stream).await?;
+        let accepted = auth.uname() == usr && auth.passwd() == pwd;
+        let status = match accepted {
+            true => UsernamePasswordAuthResult::Succeeded,
+            false => UsernamePasswordAuthResult::Failure,
+        };
+        stream.write_all(&status.as_bytes()).await?;
+        if !accepted {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+    }
+    let tellreq = match TellRequest::from(&mut stream).await {
+        Ok(tellreq) => tellreq,
+        Err(e) => {
+            if let Some(rep) = Socks5Error::of(&e).and_then(Socks5Error::reply_field) {
+                ReplyResponse::failed(rep).write_to(&mut stream).await?;
+            }
+            return Err(e);
+        }
+    };
+    ReplyResponse::succeeded((Ipv4Addr::UNSPECIFIED, 0)).write_to(&mut stream).await?;
+    Ok(tellreq)
+}
+
+/// The client side of a session: offer the methods the `credentials`
+/// allow, authenticate if the server asks to, then request a CONNECT to
+/// `addr` and read the reply
+async fn connect<S>(
+    mut stream: S,
+    addr: Address,
+    credentials: Option<(&str, &str)>,
+) -> Result<ReplyResponse>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut methods = vec![AuthMethod::NoAuthenticationRequired];
+    if credentials.is_some() {
+        methods.push(AuthMethod::UsernameOrPassword);
+    }
+    HandshakeRequest::new(methods.clone()).write_to(&mut stream).await?;
+    let method = HandshakeResponse::from(&mut stream).await?.method();
+    match (method, credentials) {
+        (AuthMethod::NoAuthenticationRequired, _) => {}
+        (AuthMethod::UsernameOrPassword, Some((usr, pwd))) => {
+            UsernamePasswordAuth::new(usr, pwd).write_to(&mut stream).await?;
+            if UsernamePasswordAuthResult::from(&mut stream).await?
+                != UsernamePasswordAuthResult::Succeeded
+            {
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+        }
+        (AuthMethod::NoAcceptableMethods, _) => return Err(ErrorKind::PermissionDenied.into()),
+        (method, _) => {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Server selected a method not offered: {:?}", method),
+            ))
+        }
+    }
+    TellRequest::connect(addr).write_to(&mut stream).await?;
+    ReplyResponse::from(&mut stream).await
+}
+
+struct ServerCase {
+    name: &'static str,
+    credentials: Option<(&'static str, &'static str)>,
+    client: &'static [Step<'static>],
+    /// The error the server fails with, None if it serves the request
+    error: Option<ErrorKind>,
+}
+
+struct ClientCase {
+    name: &'static str,
+    credentials: Option<(&'static str, &'static str)>,
+    server: &'static [Step<'static>],
+    /// The error the client fails with, None if it reads a reply
+    error: Option<ErrorKind>,
+}
+
+const SUCCEEDED: &[u8] = &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+const SERVER_CASES: &[ServerCase] = &[
+    ServerCase {
+        name: "connect to an IPv4 address",
+        credentials: None,
+        client: &[
+            Step::Send(&[5, 1, 0]),
+            Step::Expect(&[5, 0]),
+            Step::Send(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]),
+            Step::Expect(SUCCEEDED),
+        ],
+        error: None,
+    },
+    ServerCase {
+        name: "no acceptable methods",
+        credentials: None,
+        client: &[Step::Send(&[5, 1, 2]), Step::Expect(&[5, 0xff])],
+        error: Some(ErrorKind::PermissionDenied),
+    },
+    ServerCase {
+        name: "no methods offered",
+        credentials: None,
+        client: &[Step::Send(&[5, 0])],
+        error: Some(ErrorKind::InvalidData),
+    },
+    ServerCase {
+        name: "SOCKS4 greeting",
+        credentials: None,
+        client: &[Step::Send(&[4, 1, 0])],
+        error: Some(ErrorKind::Unsupported),
+    },
+    ServerCase {
+        name: "greeting cut short",
+        credentials: None,
+        client: &[Step::Send(&[5, 2, 0]), Step::Shutdown],
+        error: Some(ErrorKind::UnexpectedEof),
+    },
+    ServerCase {
+        name: "username and password accepted",
+        credentials: Some(CREDENTIALS),
+        client: &[
+            Step::Send(&[5, 2, 0, 2]),
+            Step::Expect(&[5, 2]),
+            Step::Send(&[1, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b's']),
+            Step::Expect(&[1, 0]),
+            Step::Send(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]),
+            Step::Expect(SUCCEEDED),
+        ],
+        error: None,
+    },
+    ServerCase {
+        name: "wrong password",
+        credentials: Some(CREDENTIALS),
+        client: &[
+            Step::Send(&[5, 1, 2]),
+            Step::Expect(&[5, 2]),
+            Step::Send(&[1, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b'z']),
+            Step::Expect(&[1, 1]),
+        ],
+        error: Some(ErrorKind::PermissionDenied),
+    },
+    ServerCase {
+        name: "wrong subnegotiation version",
+        credentials: Some(CREDENTIALS),
+        client: &[
+            Step::Send(&[5, 1, 2]),
+            Step::Expect(&[5, 2]),
+            Step::Send(&[5, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b's']),
+        ],
+        error: Some(ErrorKind::Unsupported),
+    },
+    ServerCase {
+        name: "password cut short",
+        credentials: Some(CREDENTIALS),
+        client: &[
+            Step::Send(&[5, 1, 2]),
+            Step::Expect(&[5, 2]),
+            Step::Send(&[1, 4, b'u', b's', b'e', b'r', 4, b'p']),
+            Step::Shutdown,
+        ],
+        error: Some(ErrorKind::UnexpectedEof),
+    },
+    ServerCase {
+        name: "FQDN of zero length",
+        credentials: None,
+        client: &[
+            Step::Send(&[5, 1, 0]),
+            Step::Expect(&[5, 0]),
+            Step::Send(&[5, 1, 0, 3, 0, 0, 80]),
+        ],
+        error: Some(ErrorKind::InvalidData),
+    },
+    ServerCase {
+        name: "FQDN label longer than 63 octets",
+        credentials: None,
+        client: &[
+            Step::Send(&[5, 1, 0]),
+            Step::Expect(&[5, 0]),
+            Step::Send(&[5, 1, 0, 3, 64]),
+            Step::Send(&[b'a'; 64]),
+            Step::Send(&[0, 80]),
+        ],
+        error: Some(ErrorKind::InvalidData),
+    },
+    ServerCase {
+        name: "FQDN shorter than its length",
+        credentials: None,
+        client: &[
+            Step::Send(&[5, 1, 0]),
+            Step::Expect(&[5, 0]),
+            Step::Send(&[5, 1, 0, 3, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e']),
+            Step::Shutdown,
+        ],
+        error: Some(ErrorKind::UnexpectedEof),
+    },
+    ServerCase {
+        name: "nonzero RSV",
+        credentials: None,
+        client: &[
+            Step::Send(&[5, 1, 0]),
+            Step::Expect(&[5, 0]),
+            Step::Send(&[5, 1, 1, 1, 127, 0, 0, 1, 0, 80]),
+        ],
+        error: Some(ErrorKind::InvalidData),
+    },
+    ServerCase {
+        name: "unknown command",
+        credentials: None,
+        client: &[
+            Step::Send(&[5, 1, 0]),
+            Step::Expect(&[5, 0]),
+            Step::Send(&[5, 9, 0, 1, 127, 0, 0, 1, 0, 80]),
+            Step::Expect(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]),
+        ],
+        error: Some(ErrorKind::Unsupported),
+    },
+    ServerCase {
+        name: "unknown address type",
+        credentials: None,
+        client: &[
+            Step::Send(&[5, 1, 0]),
+            Step::Expect(&[5, 0]),
+            Step::Send(&[5, 1, 0, 2, 127, 0, 0, 1, 0, 80]),
+            Step::Expect(&[5, 8, 0, 1, 0, 0, 0, 0, 0, 0]),
+        ],
+        error: Some(ErrorKind::Unsupported),
+    },
+    ServerCase {
+        name: "request cut short",
+        credentials: None,
+        client: &[
+            Step::Send(&[5, 1, 0]),
+            Step::Expect(&[5, 0]),
+            Step::Send(&[5, 1, 0, 4, 0x20, 0x01, 0x0d, 0xb8]),
+            Step::Shutdown,
+        ],
+        error: Some(ErrorKind::UnexpectedEof),
+    },
+];
+
+const CLIENT_CASES: &[ClientCase] = &[
+    ClientCase {
+        name: "connect without authentication",
+        credentials: None,
+        server: &[
+            Step::Expect(&[5, 1, 0]),
+            Step::Send(&[5, 0]),
+            Step::Expect(&[5, 1, 0, 3, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e']),
+            Step::Expect(&[b'.', b'c', b'o', b'm', 1, 0xbb]),
+            Step::Send(SUCCEEDED),
+        ],
+        error: None,
+    },
+    ClientCase {
+        name: "connect with username and password",
+        credentials: Some(CREDENTIALS),
+        server: &[
+            Step::Expect(&[5, 2, 0, 2]),
+            Step::Send(&[5, 2]),
+            Step::Expect(&[1, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b's']),
+            Step::Send(&[1, 0]),
+            Step::Expect(&[5, 1, 0, 3, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e']),
+            Step::Expect(&[b'.', b'c', b'o', b'm', 1, 0xbb]),
+            Step::Send(SUCCEEDED),
+        ],
+        error: None,
+    },
+    ClientCase {
+        name: "no acceptable methods",
+        credentials: None,
+        server: &[Step::Expect(&[5, 1, 0]), Step::Send(&[5, 0xff])],
+        error: Some(ErrorKind::PermissionDenied),
+    },
+    ClientCase {
+        name: "method not offered",
+        credentials: None,
+        server: &[Step::Expect(&[5, 1, 0]), Step::Send(&[5, 2])],
+        error: Some(ErrorKind::InvalidData),
+    },
+    ClientCase {
+        name: "authentication failed",
+        credentials: Some(CREDENTIALS),
+        server: &[
+            Step::Expect(&[5, 2, 0, 2]),
+            Step::Send(&[5, 2]),
+            Step::Expect(&[1, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b's']),
+            Step::Send(&[1, 1]),
+        ],
+        error: Some(ErrorKind::PermissionDenied),
+    },
+    ClientCase {
+        name: "wrong version in the method selection",
+        credentials: None,
+        server: &[Step::Expect(&[5, 1, 0]), Step::Send(&[4, 0])],
+        error: Some(ErrorKind::Unsupported),
+    },
+    ClientCase {
+        name: "reply cut short",
+        credentials: None,
+        server: &[
+            Step::Expect(&[5, 1, 0]),
+            Step::Send(&[5, 0]),
+            Step::Expect(&[5, 1, 0, 3, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e']),
+            Step::Expect(&[b'.', b'c', b'o', b'm', 1, 0xbb]),
+            Step::Send(&[5, 0, 0, 1, 0, 0]),
+            Step::Shutdown,
+        ],
+        error: Some(ErrorKind::UnexpectedEof),
+    },
+    ClientCase {
+        name: "reply with nonzero RSV",
+        credentials: None,
+        server: &[
+            Step::Expect(&[5, 1, 0]),
+            Step::Send(&[5, 0]),
+            Step::Expect(&[5, 1, 0, 3, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e']),
+            Step::Expect(&[b'.', b'c', b'o', b'm', 1, 0xbb]),
+            Step::Send(&[5, 0, 1, 1, 0, 0, 0, 0, 0, 0]),
+        ],
+        error: Some(ErrorKind::InvalidData),
+    },
+];
+
+/// Run every case, reporting all that fail at once
+fn check<C>(
+    cases: &[C],
+    run: impl Fn(&C) -> std::result::Result<(), String>,
+    name: fn(&C) -> &str,
+) {
+    let failures = cases
+        .iter()
+        .filter_map(|case| run(case).err().map(|e| format!("{}: {}", name(case), e)))
+        .collect::<Vec<_>>();
+    assert!(failures.is_empty(), "{} failed:\n{}", failures.len(), failures.join("\n"));
+}
+
+fn outcome<T>(ret: &Result<T>, expected: Option<ErrorKind>) -> std::result::Result<(), String> {
+    match (ret, expected) {
+        (Ok(_), None) => Ok(()),
+        (Err(e), Some(kind)) if e.kind() == kind => Ok(()),
+        (Ok(_), Some(kind)) => Err(format!("succeeded, expected {:?}", kind)),
+        (Err(e), _) => Err(format!("failed with {:?} ({}), expected {:?}", e.kind(), e, expected)),
+    }
+}
+
+#[test]
+fn test_server_sessions() {
+    check(
+        SERVER_CASES,
+        |case| {
+            block_on(async {
+                let (played, served) =
+                    run_scripted(case.client, |server| serve(server, case.credentials)).await;
+                played.map_err(|e| e.to_string())?;
+                outcome(&served, case.error)
+            })
+        },
+        |case| case.name,
+    );
+}
+
+#[test]
+fn test_client_sessions() {
+    check(
+        CLIENT_CASES,
+        |case| {
+            block_on(async {
+                let addr = Address::domain("example.com", 443).unwrap();
+                let (played, replied) =
+                    run_scripted(case.server, |client| connect(client, addr, case.credentials))
+                        .await;
+                played.map_err(|e| e.to_string())?;
+                outcome(&replied, case.error)
+            })
+        },
+        |case| case.name,
+    );
+}
+
+#[test]
+fn test_client_against_server() {
+    block_on(async {
+        for credentials in [None, Some(CREDENTIALS)] {
+            let (client, server) = duplex_pair();
+            let addr = Address::domain("example.com", 443).unwrap();
+            let (replied, served) = tokio::join!(
+                connect(client, addr.clone(), credentials),
+                serve(server, credentials)
+            );
+            assert_eq!(replied.unwrap().rep(), ReplyField::Succeeded);
+            assert_eq!(served.unwrap().addr(), addr);
+        }
+    });
+}
+
+#[test]
+fn test_oversized_domain() {
+    /* The length is a single octet, so a longer name cannot be sent */
+    let label = "a".repeat(63);
+    let longest = [&*label, &label, &label, &"a".repeat(63)].join(".");
+    assert_eq!(longest.len(), 255);
+    assert!(Address::domain(&longest, 80).is_ok());
+    let err = Address::domain(&format!("a{}", longest), 80).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_unsendable_domain() {
+    fn rejected<T>(ret: Result<T>) {
+        assert_eq!(ret.map(|_| ()).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    /* Built directly, a name that cannot be sent fails every serializer
+     * rather than going out as a corrupt frame */
+    for name in ["a".repeat(256), String::from("git hub.com")] {
+        let addr = Address::Domain(name, 80);
+
+        let tellreq = TellRequest::connect(addr.clone());
+        rejected(tellreq.as_bytes());
+        rejected(block_on(tellreq.write_to(&mut vec![])));
+
+        let rep_resp = ReplyResponse::succeeded(addr.clone());
+        rejected(rep_resp.as_bytes());
+        rejected(block_on(rep_resp.write_to(&mut vec![])));
+        rejected(block_on(rep_resp.respond_with(&mut vec![])));
+
+        let udp_pack = UdpPacket::new(0, addr, vec![0xab]);
+        rejected(udp_pack.as_socks_bytes());
+        rejected(block_on(udp_pack.write_to(&mut vec![])));
+    }
+}
+
+#[test]
+fn test_udp_datagrams() {
+    let cases: &[(&str, &[u8], Option<ErrorKind>)] = &[
+        ("IPv4 destination", &[0, 0, 0, 1, 127, 0, 0, 1, 0, 53, 0xab], None),
+        ("FQDN destination", &[0, 0, 0, 3, 1, b'a', 0, 53, 0xab], None),
+        ("nonzero RSV", &[0, 1, 0, 1, 127, 0, 0, 1, 0, 53, 0xab], Some(ErrorKind::InvalidData)),
+        ("fragment", &[0, 0, 1, 1, 127, 0, 0, 1, 0, 53, 0xab], Some(ErrorKind::Unsupported)),
+        ("unknown address type", &[0, 0, 0, 2, 127, 0, 0, 1, 0, 53], Some(ErrorKind::Unsupported)),
+        ("FQDN of zero length", &[0, 0, 0, 3, 0, 0, 53, 0xab], Some(ErrorKind::InvalidData)),
+        ("header only", &[0, 0, 0, 1], Some(ErrorKind::UnexpectedEof)),
+        ("address cut short", &[0, 0, 0, 1, 127, 0], Some(ErrorKind::UnexpectedEof)),
+    ];
+    check(
+        cases,
+        |(_, datagram, error)| {
+            let parsed = block_on(UdpPacket::from_datagram(datagram));
+            if let Ok(packet) = &parsed {
+                if packet.data() != [0xab] {
+                    return Err(format!("parsed DATA {:?}", packet.data()));
+                }
+            }
+            outcome(&parsed, *error)
+        },
+        |(name, _, _)| name,
+    );
+}
@@ -0,0 +1,187 @@
+//! Property tests of the protocol parsers: arbitrary input must never
+//! panic, serialized messages must parse back unchanged, and truncated
+//! ones must fail with [ErrorKind::UnexpectedEof].
+
+use std::future::Future;
+use std::io::{ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use proptest::prelude::*;
+use socks5::protocol::{
+    Address, AuthMethod, Command, HandshakeRequest, HandshakeResponse, ReplyField, ReplyResponse,
+    TellRequest, UdpPacket, UsernamePasswordAuth, UsernamePasswordAuthResult,
+};
+use socks5::socks4::{Socks4Command, Socks4Reply, Socks4Request};
+
+fn block_on<F: Future>(fut: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(fut)
+}
+
+/// Malformed input is either unsupported, invalid or cut short
+fn assert_rejected_cleanly<T>(ret: Result<T>) {
+    if let Err(e) = ret {
+        assert!(
+            matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof | ErrorKind::InvalidData | ErrorKind::Unsupported
+            ),
+            "unexpected error kind: {:?}",
+            e
+        );
+    }
+}
+
+fn assert_truncated<T: std::fmt::Debug>(ret: Result<T>) {
+    assert_eq!(ret.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+fn address() -> impl Strategy<Value = Address> {
+    prop_oneof![
+        (any::<[u8; 4]>(), any::<u16>()).prop_map(|(ip, port)| (Ipv4Addr::from(ip), port).into()),
+        (any::<[u8; 16]>(), any::<u16>()).prop_map(|(ip, port)| (Ipv6Addr::from(ip), port).into()),
+        ("[a-z0-9-]{1,63}(\\.[a-z0-9-]{1,63}){0,3}", any::<u16>())
+            .prop_map(|(name, port)| Address::Domain(name, port)),
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![Just(Command::Connect), Just(Command::Bind), Just(Command::UdpAssociate)]
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+        block_on(async {
+            assert_rejected_cleanly(HandshakeRequest::from(&mut &bytes[..]).await);
+            assert_rejected_cleanly(HandshakeResponse::from(&mut &bytes[..]).await);
+            assert_rejected_cleanly(UsernamePasswordAuth::from(&mut &bytes[..]).await);
+            assert_rejected_cleanly(UsernamePasswordAuthResult::from(&mut &bytes[..]).await);
+            assert_rejected_cleanly(TellRequest::from(&mut &bytes[..]).await);
+            assert_rejected_cleanly(ReplyResponse::from(&mut &bytes[..]).await);
+            assert_rejected_cleanly(UdpPacket::from_datagram(&bytes).await);
+            assert_rejected_cleanly(Socks4Request::from(&mut &bytes[..]).await);
+            assert_rejected_cleanly(Socks4Reply::from(&mut &bytes[..]).await);
+        });
+    }
+
+    #[test]
+    fn tell_request_roundtrip(cmd in command(), addr in address(), cut in any::<prop::sample::Index>()) {
+        let tellreq = TellRequest::new(cmd.clone(), addr.clone());
+        let bytes = tellreq.as_bytes().unwrap();
+        block_on(async {
+            let mut written = vec![];
+            tellreq.write_to(&mut written).await.unwrap();
+            assert_eq!(written, bytes);
+
+            let parsed = TellRequest::from(&mut &bytes[..]).await.unwrap();
+            assert_eq!(parsed.cmd(), cmd);
+            assert_eq!(parsed.addr(), addr);
+            assert_truncated(TellRequest::from(&mut &bytes[..cut.index(bytes.len())]).await);
+        });
+    }
+
+    #[test]
+    fn reply_response_roundtrip(rep in any::<u8>(), addr in address(), cut in any::<prop::sample::Index>()) {
+        let rep_resp = ReplyResponse::new(ReplyField::from(rep), addr.clone());
+        let bytes = rep_resp.as_bytes().unwrap();
+        block_on(async {
+            let mut written = vec![];
+            assert_eq!(rep_resp.respond_with(&mut written).await.unwrap(), bytes.len() as u64);
+            assert_eq!(written, bytes);
+
+            let parsed = ReplyResponse::from(&mut &bytes[..]).await.unwrap();
+            assert_eq!(parsed.rep(), ReplyField::from(rep));
+            assert_eq!(parsed.addr(), addr);
+            assert_truncated(ReplyResponse::from(&mut &bytes[..cut.index(bytes.len())]).await);
+        });
+    }
+
+    #[test]
+    fn udp_packet_roundtrip(
+        addr in address(),
+        data in proptest::collection::vec(any::<u8>(), 0..64),
+    ) {
+        let udp_pack = UdpPacket::new(0, addr.clone(), data.clone());
+        let bytes = udp_pack.as_socks_bytes().unwrap();
+        block_on(async {
+            let mut written = vec![];
+            udp_pack.write_to(&mut written).await.unwrap();
+            assert_eq!(written, bytes);
+            let parsed = UdpPacket::from_datagram(&bytes).await.unwrap();
+            assert_eq!(parsed.addr(), addr);
+            assert_eq!(parsed.data(), data);
+        });
+    }
+
+    #[test]
+    fn handshake_request_roundtrip(
+        methods in proptest::collection::vec(any::<u8>().prop_map(AuthMethod::from), 1..=255),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let hreq = HandshakeRequest::new(methods.clone());
+        let bytes = hreq.as_bytes().unwrap();
+        block_on(async {
+            let mut written = vec![];
+            hreq.write_to(&mut written).await.unwrap();
+            assert_eq!(written, bytes);
+
+            let parsed = HandshakeRequest::from(&mut &bytes[..]).await.unwrap();
+            assert_eq!(parsed.methods(), methods);
+            assert_eq!(HandshakeRequest::new(parsed.methods()).as_bytes().unwrap(), bytes);
+            assert_truncated(HandshakeRequest::from(&mut &bytes[..cut.index(bytes.len())]).await);
+        });
+    }
+
+    #[test]
+    fn username_password_auth_roundtrip(
+        usr in "[ -~]{0,255}",
+        pwd in "[ -~]{0,255}",
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let upauth = UsernamePasswordAuth::new(&usr, &pwd);
+        let bytes = upauth.as_bytes();
+        block_on(async {
+            let mut written = vec![];
+            upauth.write_to(&mut written).await.unwrap();
+            assert_eq!(written, bytes);
+
+            let parsed = UsernamePasswordAuth::from(&mut &bytes[..]).await.unwrap();
+            assert_eq!(parsed.uname(), usr);
+            assert_eq!(parsed.passwd(), pwd);
+            assert_truncated(UsernamePasswordAuth::from(&mut &bytes[..cut.index(bytes.len())]).await);
+        });
+    }
+
+    #[test]
+    fn socks4_request_roundtrip(
+        bind in any::<bool>(),
+        addr in address().prop_filter("SOCKS4 has no IPv6, and 0.0.0.x means SOCKS4a", |addr| {
+            match addr {
+                Address::IP(socket_addr) => match socket_addr.ip() {
+                    std::net::IpAddr::V4(ip) => ip.octets()[..3] != [0, 0, 0],
+                    std::net::IpAddr::V6(_) => false,
+                },
+                Address::Domain(..) => true,
+            }
+        }),
+        userid in "[a-z]{0,32}",
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let cmd = if bind { Socks4Command::Bind } else { Socks4Command::Connect };
+        let bytes = Socks4Request::new(cmd, addr.clone(), userid.clone()).as_bytes();
+        block_on(async {
+            let req = Socks4Request::from(&mut &bytes[..]).await.unwrap();
+            assert_eq!(req.cmd(), cmd);
+            assert_eq!(req.addr(), addr);
+            assert_eq!(req.userid(), userid);
+            assert_truncated(Socks4Request::from(&mut &bytes[..cut.index(bytes.len())]).await);
+        });
+    }
+}
+
+#[test]
+fn empty_domain_is_invalid() {
+    let bytes = [5u8, 1, 0, 3, 0, 0x00, 0x50];
+    let err = block_on(TellRequest::from(&mut &bytes[..])).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}